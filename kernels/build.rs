@@ -19,6 +19,10 @@ fn main() -> Result<()> {
     println!("cargo:rerun-if-changed=src/pagedattention.cu");
     println!("cargo:rerun-if-changed=src/copy_blocks_kernel.cu");
     println!("cargo:rerun-if-changed=src/reshape_and_cache_kernel.cu");
+    println!("cargo:rerun-if-changed=src/rejection_sampler_kernel.cu");
+    println!("cargo:rerun-if-changed=src/sampler_kernel.cu");
+    println!("cargo:rerun-if-changed=src/fused_kernels.cu");
+    println!("cargo:rerun-if-changed=src/custom_all_reduce.cu");
     let builder = bindgen_cuda::Builder::default();
     println!("cargo:info={builder:?}");
     builder.build_lib("libpagedattention.a");
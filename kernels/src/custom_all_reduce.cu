@@ -0,0 +1,199 @@
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <stdint.h>
+#include <string.h>
+
+#include "cuda_compat.h"
+
+#include <algorithm>
+
+#define CUSTOM_ALL_REDUCE_MAX_RANKS 8
+#define CUSTOM_ALL_REDUCE_MAX_BLOCKS 36
+#define CUSTOM_ALL_REDUCE_THREADS 512
+
+namespace vllm {
+
+__device__ __forceinline__ float ar_to_float(float v) { return v; }
+__device__ __forceinline__ float ar_to_float(__half v) { return __half2float(v); }
+__device__ __forceinline__ float ar_to_float(__nv_bfloat16 v) { return __bfloat162float(v); }
+
+template<typename T>
+__device__ __forceinline__ T ar_from_float(float v);
+template<>
+__device__ __forceinline__ float ar_from_float<float>(float v) { return v; }
+template<>
+__device__ __forceinline__ __half ar_from_float<__half>(float v) { return __float2half(v); }
+template<>
+__device__ __forceinline__ __nv_bfloat16 ar_from_float<__nv_bfloat16>(float v) {
+  return __float2bfloat16(v);
+}
+
+// The flags a block of every rank sets in the signal of each of them at the start, middle (two
+// shot only) and end of an all-reduce. The buffer of the signal is zeroed when allocated.
+struct Signal {
+  uint32_t flags[3][CUSTOM_ALL_REDUCE_MAX_BLOCKS][CUSTOM_ALL_REDUCE_MAX_RANKS];
+};
+
+// The buffers or signals of the ranks, mapped in the address space of this one.
+struct RankPtrs {
+  void* ptrs[CUSTOM_ALL_REDUCE_MAX_RANKS];
+};
+
+// Waits for the same block of every rank to reach the barrier `stage` of the all-reduce `flag`.
+// The writes of the block before it are visible to the other ranks after it.
+__device__ void block_barrier(
+  const RankPtrs& signals,
+  const int stage,
+  const int rank,
+  const int world_size,
+  const uint32_t flag) {
+  __threadfence_system();
+  __syncthreads();
+  if (threadIdx.x < world_size) {
+    Signal* peer = reinterpret_cast<Signal*>(signals.ptrs[threadIdx.x]);
+    Signal* own = reinterpret_cast<Signal*>(signals.ptrs[rank]);
+    *reinterpret_cast<volatile uint32_t*>(&peer->flags[stage][blockIdx.x][rank]) = flag;
+    while (*reinterpret_cast<volatile uint32_t*>(&own->flags[stage][blockIdx.x][threadIdx.x]) != flag) {
+    }
+  }
+  __syncthreads();
+}
+
+// Every rank sums the whole buffers of all of them, for small messages: a single round trip.
+// The ranks are summed in order, so that all of them get the same result to the bit.
+template<typename T>
+__global__ void one_shot_all_reduce_kernel(
+  const RankPtrs buffers,
+  const RankPtrs signals,
+  T* __restrict__ out,
+  const int rank,
+  const int world_size,
+  const int64_t n,
+  const uint32_t flag) {
+  block_barrier(signals, 0, rank, world_size, flag);
+  for (int64_t i = (int64_t) blockIdx.x * blockDim.x + threadIdx.x; i < n;
+       i += (int64_t) gridDim.x * blockDim.x) {
+    float sum = 0.f;
+    for (int r = 0; r < world_size; ++r) {
+      sum += ar_to_float(reinterpret_cast<const T*>(buffers.ptrs[r])[i]);
+    }
+    out[i] = ar_from_float<T>(sum);
+  }
+  // No rank overwrites its buffer with the next message while the others still read it.
+  block_barrier(signals, 2, rank, world_size, flag);
+}
+
+// Every rank sums its part of the buffers of all of them into its own (reduce-scatter), then
+// gathers the parts of the others (all-gather): each element crosses the links twice instead of
+// once per rank, for larger messages. A rank's part of its buffer is read by no other rank
+// before the middle barrier, so it is summed in place.
+template<typename T>
+__global__ void two_shot_all_reduce_kernel(
+  const RankPtrs buffers,
+  const RankPtrs signals,
+  T* __restrict__ out,
+  const int rank,
+  const int world_size,
+  const int64_t n,
+  const uint32_t flag) {
+  const int64_t part = (n + world_size - 1) / world_size;
+  const int64_t start = (int64_t) blockIdx.x * blockDim.x + threadIdx.x;
+  const int64_t stride = (int64_t) gridDim.x * blockDim.x;
+
+  block_barrier(signals, 0, rank, world_size, flag);
+  T* own = reinterpret_cast<T*>(buffers.ptrs[rank]);
+  const int64_t begin = rank * part;
+  const int64_t end = min(n, begin + part);
+  for (int64_t i = begin + start; i < end; i += stride) {
+    float sum = 0.f;
+    for (int r = 0; r < world_size; ++r) {
+      sum += ar_to_float(reinterpret_cast<const T*>(buffers.ptrs[r])[i]);
+    }
+    own[i] = ar_from_float<T>(sum);
+  }
+  // The same block of each rank summed the elements this block gathers from it.
+  block_barrier(signals, 1, rank, world_size, flag);
+  for (int r = 0; r < world_size; ++r) {
+    const int64_t begin = r * part;
+    const int64_t end = min(n, begin + part);
+    for (int64_t i = begin + start; i < end; i += stride) {
+      out[i] = reinterpret_cast<const T*>(buffers.ptrs[r])[i];
+    }
+  }
+  block_barrier(signals, 2, rank, world_size, flag);
+}
+
+} // namespace vllm
+
+#define DISPATCH_ALL_REDUCE_DTYPE(dtype, CALL) \
+  if (dtype == 0) {                            \
+    CALL(__half);                              \
+  } else if (dtype == 1) {                     \
+    CALL(__nv_bfloat16);                       \
+  } else if (dtype == 2) {                     \
+    CALL(float);                               \
+  }
+
+extern "C" int32_t ipc_get_mem_handle(void* ptr, uint8_t* handle) {
+  cudaIpcMemHandle_t h;
+  const cudaError_t err = cudaIpcGetMemHandle(&h, ptr);
+  if (err == cudaSuccess) {
+    memcpy(handle, &h, sizeof(h));
+  }
+  return err;
+}
+
+extern "C" int32_t ipc_open_mem_handle(const uint8_t* handle, void** ptr) {
+  cudaIpcMemHandle_t h;
+  memcpy(&h, handle, sizeof(h));
+  return cudaIpcOpenMemHandle(ptr, h, cudaIpcMemLazyEnablePeerAccess);
+}
+
+extern "C" int32_t ipc_close_mem_handle(void* ptr) {
+  return cudaIpcCloseMemHandle(ptr);
+}
+
+extern "C" void custom_all_reduce(
+  const void* input,      // [n]
+  void* out,              // [n]
+  void* const* buffers,   // [world_size], of at least n elements each
+  void* const* signals,   // [world_size]
+  int32_t rank,
+  int32_t world_size,
+  int64_t n,
+  uint32_t flag,
+  int32_t two_shot,
+
+  uint32_t dtype          // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  const cudaStream_t stream = 0;
+  const size_t elem_size = dtype == 2 ? 4 : 2;
+  // The other ranks read the buffer once their kernel started, after the copy.
+  cudaMemcpyAsync(buffers[rank], input, n * elem_size, cudaMemcpyDeviceToDevice, stream);
+
+  vllm::RankPtrs buffer_ptrs;
+  vllm::RankPtrs signal_ptrs;
+  for (int r = 0; r < world_size; ++r) {
+    buffer_ptrs.ptrs[r] = buffers[r];
+    signal_ptrs.ptrs[r] = signals[r];
+  }
+  // The same grid on every rank, a function of the message only.
+  const int64_t elems = two_shot ? (n + world_size - 1) / world_size : n;
+  const int64_t blocks = (elems + CUSTOM_ALL_REDUCE_THREADS - 1) / CUSTOM_ALL_REDUCE_THREADS;
+  dim3 grid(std::max<int64_t>(1, std::min<int64_t>(CUSTOM_ALL_REDUCE_MAX_BLOCKS, blocks)));
+  dim3 block(CUSTOM_ALL_REDUCE_THREADS);
+
+#define CALL_CUSTOM_ALL_REDUCE(T)                                              \
+  if (two_shot) {                                                              \
+    vllm::two_shot_all_reduce_kernel<T><<<grid, block, 0, stream>>>(           \
+      buffer_ptrs, signal_ptrs, reinterpret_cast<T*>(out), rank, world_size,   \
+      n, flag);                                                                \
+  } else {                                                                     \
+    vllm::one_shot_all_reduce_kernel<T><<<grid, block, 0, stream>>>(           \
+      buffer_ptrs, signal_ptrs, reinterpret_cast<T*>(out), rank, world_size,   \
+      n, flag);                                                                \
+  }
+
+  DISPATCH_ALL_REDUCE_DTYPE(dtype, CALL_CUSTOM_ALL_REDUCE)
+}
@@ -67,4 +67,98 @@ extern "C" {
 
         dtype: u32,
     );
+
+    pub fn rejection_sample(
+        probs: *const f32,
+        draft_tokens: *const u32,
+        num_draft_tokens: *const u32,
+        uniform: *const f32,
+        out: *mut u32,
+
+        num_seqs: c_int,
+        vocab_size: c_int,
+        max_num_draft_tokens: c_int,
+        greedy: c_int,
+    );
+
+    pub fn sample_tokens(
+        logits: *const f32,
+        uniform: *const f32,
+        out: *mut u32,
+
+        num_rows: c_int,
+        vocab_size: c_int,
+        inv_temperature: f32,
+        top_k: c_int,
+        top_p: f32,
+    );
+
+    pub fn rms_norm(
+        out: *const c_void,
+        x: *const c_void,
+        weight: *const c_void,
+        eps: f32,
+
+        num_tokens: c_int,
+        hidden_size: c_int,
+
+        dtype: u32,
+    );
+
+    pub fn fused_add_rms_norm(
+        out: *const c_void,
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        eps: f32,
+
+        num_tokens: c_int,
+        hidden_size: c_int,
+
+        dtype: u32,
+    );
+
+    pub fn silu_and_mul(
+        out: *const c_void,
+        gate: *const c_void,
+        up: *const c_void,
+
+        n: i64,
+
+        dtype: u32,
+    );
+
+    pub fn rotary_embedding(
+        x: *const c_void,
+        cos: *const c_void,
+        sin: *const c_void,
+        offsets: *const u32,
+
+        batch: c_int,
+        num_heads: c_int,
+        seq_len: c_int,
+        head_size: c_int,
+
+        dtype: u32,
+    );
+
+    pub fn ipc_get_mem_handle(ptr: *mut c_void, handle: *mut u8) -> c_int;
+
+    pub fn ipc_open_mem_handle(handle: *const u8, ptr: *mut *mut c_void) -> c_int;
+
+    pub fn ipc_close_mem_handle(ptr: *mut c_void) -> c_int;
+
+    pub fn custom_all_reduce(
+        input: *const c_void,
+        out: *mut c_void,
+        buffers: *const *mut c_void,
+        signals: *const *mut c_void,
+        rank: c_int,
+        world_size: c_int,
+        n: i64,
+        flag: u32,
+        two_shot: c_int,
+
+        dtype: u32,
+    );
 }
@@ -0,0 +1,271 @@
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <stdint.h>
+
+#include "cuda_compat.h"
+
+#include <algorithm>
+
+#define FUSED_MAX_THREADS 1024
+
+namespace vllm {
+
+__device__ __forceinline__ float to_float(float v) { return v; }
+__device__ __forceinline__ float to_float(__half v) { return __half2float(v); }
+__device__ __forceinline__ float to_float(__nv_bfloat16 v) { return __bfloat162float(v); }
+
+template<typename T>
+__device__ __forceinline__ T from_float(float v);
+template<>
+__device__ __forceinline__ float from_float<float>(float v) { return v; }
+template<>
+__device__ __forceinline__ __half from_float<__half>(float v) { return __float2half(v); }
+template<>
+__device__ __forceinline__ __nv_bfloat16 from_float<__nv_bfloat16>(float v) {
+  return __float2bfloat16(v);
+}
+
+// Sum of `v` over the threads of the block, returned to all of them. The block size is a
+// multiple of the warp size.
+__device__ float block_sum(float v) {
+  __shared__ float warp_sums[32];
+  __shared__ float total;
+  const int lane = threadIdx.x % 32;
+  const int warp = threadIdx.x / 32;
+  for (int mask = 16; mask > 0; mask >>= 1) {
+    v += VLLM_SHFL_XOR_SYNC(v, mask);
+  }
+  if (lane == 0) {
+    warp_sums[warp] = v;
+  }
+  __syncthreads();
+  if (warp == 0) {
+    v = lane < blockDim.x / 32 ? warp_sums[lane] : 0.f;
+    for (int mask = 16; mask > 0; mask >>= 1) {
+      v += VLLM_SHFL_XOR_SYNC(v, mask);
+    }
+    if (lane == 0) {
+      total = v;
+    }
+  }
+  __syncthreads();
+  return total;
+}
+
+// One block per row of `hidden_size` elements.
+template<typename scalar_t>
+__global__ void rms_norm_kernel(
+  scalar_t* __restrict__ out,           // [num_tokens, hidden_size]
+  const scalar_t* __restrict__ x,       // [num_tokens, hidden_size]
+  const scalar_t* __restrict__ weight,  // [hidden_size]
+  const float eps,
+  const int hidden_size) {
+  const scalar_t* row = x + (int64_t)blockIdx.x * hidden_size;
+  float sum2 = 0.f;
+  for (int i = threadIdx.x; i < hidden_size; i += blockDim.x) {
+    const float v = to_float(row[i]);
+    sum2 += v * v;
+  }
+  const float scale = rsqrtf(block_sum(sum2) / hidden_size + eps);
+  scalar_t* out_row = out + (int64_t)blockIdx.x * hidden_size;
+  for (int i = threadIdx.x; i < hidden_size; i += blockDim.x) {
+    out_row[i] = from_float<scalar_t>(to_float(row[i]) * scale * to_float(weight[i]));
+  }
+}
+
+// Adds the residual and normalizes the sum in one pass. The sum is rounded to `scalar_t` before
+// it is normalized, as the unfused ops would, and both come out: the norm in the first half of
+// `out` and the sum, the residual of the next layer, in the second.
+template<typename scalar_t>
+__global__ void fused_add_rms_norm_kernel(
+  scalar_t* __restrict__ out,             // [2, num_tokens, hidden_size]
+  const scalar_t* __restrict__ x,         // [num_tokens, hidden_size]
+  const scalar_t* __restrict__ residual,  // [num_tokens, hidden_size]
+  const scalar_t* __restrict__ weight,    // [hidden_size]
+  const float eps,
+  const int num_tokens,
+  const int hidden_size) {
+  const int64_t offset = (int64_t)blockIdx.x * hidden_size;
+  scalar_t* normed = out + offset;
+  scalar_t* sum = out + (int64_t)num_tokens * hidden_size + offset;
+  float sum2 = 0.f;
+  for (int i = threadIdx.x; i < hidden_size; i += blockDim.x) {
+    const scalar_t s = from_float<scalar_t>(to_float(x[offset + i]) + to_float(residual[offset + i]));
+    sum[i] = s;
+    const float v = to_float(s);
+    sum2 += v * v;
+  }
+  const float scale = rsqrtf(block_sum(sum2) / hidden_size + eps);
+  // Each thread reads back only the elements it wrote.
+  for (int i = threadIdx.x; i < hidden_size; i += blockDim.x) {
+    normed[i] = from_float<scalar_t>(to_float(sum[i]) * scale * to_float(weight[i]));
+  }
+}
+
+template<typename scalar_t>
+__global__ void silu_and_mul_kernel(
+  scalar_t* __restrict__ out,         // [n]
+  const scalar_t* __restrict__ gate,  // [n]
+  const scalar_t* __restrict__ up,    // [n]
+  const int64_t n) {
+  for (int64_t i = (int64_t)blockIdx.x * blockDim.x + threadIdx.x; i < n;
+       i += (int64_t)gridDim.x * blockDim.x) {
+    const float g = to_float(gate[i]);
+    out[i] = from_float<scalar_t>(g / (1.f + __expf(-g)) * to_float(up[i]));
+  }
+}
+
+// Rotates the halves of each head in place, the non-interleaved rope: the token at `t` of batch
+// entry `b` sits at position `offsets[b] + t`. One block per head of a token.
+template<typename scalar_t>
+__global__ void rotary_embedding_kernel(
+  scalar_t* __restrict__ x,            // [batch, num_heads, seq_len, head_size]
+  const scalar_t* __restrict__ cos,    // [max_position, head_size / 2]
+  const scalar_t* __restrict__ sin,    // [max_position, head_size / 2]
+  const uint32_t* __restrict__ offsets,  // [batch]
+  const int num_heads,
+  const int seq_len,
+  const int head_size) {
+  const int t = blockIdx.x % seq_len;
+  const int b = blockIdx.x / (seq_len * num_heads);
+  const int half = head_size / 2;
+  const int64_t pos = (int64_t)offsets[b] + t;
+  scalar_t* head = x + (int64_t)blockIdx.x * head_size;
+  for (int i = threadIdx.x; i < half; i += blockDim.x) {
+    const float c = to_float(cos[pos * half + i]);
+    const float s = to_float(sin[pos * half + i]);
+    const float x1 = to_float(head[i]);
+    const float x2 = to_float(head[i + half]);
+    head[i] = from_float<scalar_t>(x1 * c - x2 * s);
+    head[i + half] = from_float<scalar_t>(x1 * s + x2 * c);
+  }
+}
+
+} // namespace vllm
+
+// Threads of a block over a row of `n` elements: a whole number of warps.
+static int row_threads(int n) {
+  return std::min((n + 31) / 32 * 32, FUSED_MAX_THREADS);
+}
+
+#define DISPATCH_DTYPE(dtype, CALL)   \
+  if (dtype == 0) {                   \
+    CALL(__half);                     \
+  } else if (dtype == 1) {            \
+    CALL(__nv_bfloat16);              \
+  } else if (dtype == 2) {            \
+    CALL(float);                      \
+  }
+
+extern "C" void rms_norm(
+  void *out,           // [num_tokens, hidden_size]
+  const void *x,       // [num_tokens, hidden_size]
+  const void *weight,  // [hidden_size]
+  float eps,
+
+  int32_t num_tokens,
+  int32_t hidden_size,
+
+  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  dim3 grid(num_tokens);
+  dim3 block(row_threads(hidden_size));
+  const cudaStream_t stream = 0;
+
+#define CALL_RMS_NORM(T)                                         \
+  vllm::rms_norm_kernel<T><<<grid, block, 0, stream>>>(          \
+    reinterpret_cast<T*>(out),                                   \
+    reinterpret_cast<const T*>(x),                               \
+    reinterpret_cast<const T*>(weight),                          \
+    eps,                                                         \
+    hidden_size);
+
+  DISPATCH_DTYPE(dtype, CALL_RMS_NORM)
+}
+
+extern "C" void fused_add_rms_norm(
+  void *out,             // [2, num_tokens, hidden_size]
+  const void *x,         // [num_tokens, hidden_size]
+  const void *residual,  // [num_tokens, hidden_size]
+  const void *weight,    // [hidden_size]
+  float eps,
+
+  int32_t num_tokens,
+  int32_t hidden_size,
+
+  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  dim3 grid(num_tokens);
+  dim3 block(row_threads(hidden_size));
+  const cudaStream_t stream = 0;
+
+#define CALL_FUSED_ADD_RMS_NORM(T)                                   \
+  vllm::fused_add_rms_norm_kernel<T><<<grid, block, 0, stream>>>(    \
+    reinterpret_cast<T*>(out),                                       \
+    reinterpret_cast<const T*>(x),                                   \
+    reinterpret_cast<const T*>(residual),                            \
+    reinterpret_cast<const T*>(weight),                              \
+    eps,                                                             \
+    num_tokens,                                                      \
+    hidden_size);
+
+  DISPATCH_DTYPE(dtype, CALL_FUSED_ADD_RMS_NORM)
+}
+
+extern "C" void silu_and_mul(
+  void *out,         // [n]
+  const void *gate,  // [n]
+  const void *up,    // [n]
+
+  int64_t n,
+
+  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  const int threads = 256;
+  dim3 grid(std::min<int64_t>((n + threads - 1) / threads, 65535));
+  dim3 block(threads);
+  const cudaStream_t stream = 0;
+
+#define CALL_SILU_AND_MUL(T)                                     \
+  vllm::silu_and_mul_kernel<T><<<grid, block, 0, stream>>>(      \
+    reinterpret_cast<T*>(out),                                   \
+    reinterpret_cast<const T*>(gate),                            \
+    reinterpret_cast<const T*>(up),                              \
+    n);
+
+  DISPATCH_DTYPE(dtype, CALL_SILU_AND_MUL)
+}
+
+extern "C" void rotary_embedding(
+  void *x,                  // [batch, num_heads, seq_len, head_size]
+  const void *cos,          // [max_position, head_size / 2]
+  const void *sin,          // [max_position, head_size / 2]
+  const uint32_t *offsets,  // [batch]
+
+  int32_t batch,
+  int32_t num_heads,
+  int32_t seq_len,
+  int32_t head_size,
+
+  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  dim3 grid(batch * num_heads * seq_len);
+  dim3 block(std::min(head_size / 2, 512));
+  const cudaStream_t stream = 0;
+
+#define CALL_ROTARY_EMBEDDING(T)                                     \
+  vllm::rotary_embedding_kernel<T><<<grid, block, 0, stream>>>(      \
+    reinterpret_cast<T*>(x),                                         \
+    reinterpret_cast<const T*>(cos),                                 \
+    reinterpret_cast<const T*>(sin),                                 \
+    offsets,                                                         \
+    num_heads,                                                       \
+    seq_len,                                                         \
+    head_size);
+
+  DISPATCH_DTYPE(dtype, CALL_ROTARY_EMBEDDING)
+}
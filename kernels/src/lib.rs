@@ -1,6 +1,9 @@
 pub const COPY_BLOCKS_KERNEL: &str =
     include_str!(concat!(env!("OUT_DIR"), "/copy_blocks_kernel.ptx"));
+pub const FUSED_KERNELS: &str = include_str!(concat!(env!("OUT_DIR"), "/fused_kernels.ptx"));
 pub const PAGEDATTENTION: &str = include_str!(concat!(env!("OUT_DIR"), "/pagedattention.ptx"));
+pub const REJECTION_SAMPLER_KERNEL: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/rejection_sampler_kernel.ptx"));
 pub const RESHAPE_AND_CACHE_KERNEL: &str =
     include_str!(concat!(env!("OUT_DIR"), "/reshape_and_cache_kernel.ptx"));
 pub mod ffi;
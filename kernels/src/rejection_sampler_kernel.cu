@@ -0,0 +1,188 @@
+#include <stdint.h>
+
+#include "cuda_compat.h"
+
+#define REJECTION_SAMPLER_THREADS 256
+
+namespace vllm {
+
+// Index of the largest of `probs`, the lowest one among equal values.
+__device__ int block_argmax(
+  const float* __restrict__ probs,
+  const int vocab_size,
+  float* s_val,
+  int* s_idx) {
+  float best = 0.f;
+  int best_idx = -1;
+  for (int i = threadIdx.x; i < vocab_size; i += blockDim.x) {
+    const float p = probs[i];
+    if (best_idx < 0 || p > best) {
+      best = p;
+      best_idx = i;
+    }
+  }
+  s_val[threadIdx.x] = best;
+  s_idx[threadIdx.x] = best_idx;
+  __syncthreads();
+  for (int stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+    if (threadIdx.x < stride) {
+      const float v = s_val[threadIdx.x + stride];
+      const int j = s_idx[threadIdx.x + stride];
+      const int i = s_idx[threadIdx.x];
+      if (j >= 0 && (i < 0 || v > s_val[threadIdx.x] || (v == s_val[threadIdx.x] && j < i))) {
+        s_val[threadIdx.x] = v;
+        s_idx[threadIdx.x] = j;
+      }
+    }
+    __syncthreads();
+  }
+  const int result = s_idx[0];
+  __syncthreads();
+  return result;
+}
+
+// Token sampled from `probs` without `excluded` (-1 for none) by inverse transform sampling of
+// `u` in [0, 1): the first token whose cumulative probability exceeds `u` times the total. The
+// last token with a probability is taken if rounding leaves `u` above them all, and `vocab_size`
+// if no token has one.
+__device__ int block_sample(
+  const float* __restrict__ probs,
+  const int vocab_size,
+  const int excluded,
+  const float u,
+  float* s_val,
+  int* s_idx) {
+  float total = 0.f;
+  for (int i = threadIdx.x; i < vocab_size; i += blockDim.x) {
+    total += i == excluded ? 0.f : probs[i];
+  }
+  s_val[threadIdx.x] = total;
+  __syncthreads();
+  for (int stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+    if (threadIdx.x < stride) {
+      s_val[threadIdx.x] += s_val[threadIdx.x + stride];
+    }
+    __syncthreads();
+  }
+  const float target = u * s_val[0];
+  __syncthreads();
+
+  // s_idx[0] is the sampled token, s_idx[1] the last token with a probability.
+  if (threadIdx.x == 0) {
+    s_idx[0] = vocab_size;
+    s_idx[1] = -1;
+  }
+  float offset = 0.f;
+  for (int start = 0; start < vocab_size; start += blockDim.x) {
+    const int i = start + threadIdx.x;
+    const float p = (i < vocab_size && i != excluded) ? probs[i] : 0.f;
+    __syncthreads();
+    s_val[threadIdx.x] = p;
+    __syncthreads();
+    // Inclusive scan of the probabilities of this tile.
+    for (int stride = 1; stride < blockDim.x; stride <<= 1) {
+      const float add = threadIdx.x >= stride ? s_val[threadIdx.x - stride] : 0.f;
+      __syncthreads();
+      s_val[threadIdx.x] += add;
+      __syncthreads();
+    }
+    if (p > 0.f) {
+      atomicMax(&s_idx[1], i);
+      if (offset + s_val[threadIdx.x] > target) {
+        atomicMin(&s_idx[0], i);
+      }
+    }
+    offset += s_val[blockDim.x - 1];
+    __syncthreads();
+    if (s_idx[0] < vocab_size) {
+      break;
+    }
+  }
+  const int result = s_idx[0] < vocab_size ? s_idx[0] : (s_idx[1] >= 0 ? s_idx[1] : vocab_size);
+  __syncthreads();
+  return result;
+}
+
+// One thread block per sequence, whose rows are its last token followed by its draft tokens.
+// The draft tokens are proposed with certainty (prompt lookup), so the one of a row is accepted
+// with its probability, and a rejected one is replaced by a token sampled from the row without
+// it. Once all are accepted, the token following them is sampled from the last row. Greedy
+// verification accepts the draft tokens that are the argmax of their row.
+__global__ void rejection_sample_kernel(
+  const float* __restrict__ probs,                // [num_rows, vocab_size]
+  const uint32_t* __restrict__ draft_tokens,      // [num_seqs, max_num_draft_tokens]
+  const uint32_t* __restrict__ num_draft_tokens,  // [num_seqs]
+  const float* __restrict__ uniform,              // [num_rows, 2]
+  uint32_t* __restrict__ out,                     // [num_seqs, max_num_draft_tokens + 2]
+  const int vocab_size,
+  const int max_num_draft_tokens,
+  const bool greedy) {
+  __shared__ float s_val[REJECTION_SAMPLER_THREADS];
+  __shared__ int s_idx[REJECTION_SAMPLER_THREADS];
+
+  const int seq_idx = blockIdx.x;
+  int64_t first_row = 0;
+  for (int i = 0; i < seq_idx; ++i) {
+    first_row += num_draft_tokens[i] + 1;
+  }
+  const int num_draft = num_draft_tokens[seq_idx];
+  const uint32_t* seq_draft_tokens = draft_tokens + seq_idx * max_num_draft_tokens;
+  uint32_t* seq_out = out + seq_idx * (max_num_draft_tokens + 2);
+
+  int num_out = 0;
+  for (int r = 0; r <= num_draft; ++r) {
+    const int64_t row = first_row + r;
+    const float* row_probs = probs + row * vocab_size;
+    const float* row_uniform = uniform + row * 2;
+    const int draft = r < num_draft ? (int) seq_draft_tokens[r] : -1;
+    int token;
+    bool accepted = false;
+    if (greedy) {
+      token = block_argmax(row_probs, vocab_size, s_val, s_idx);
+      accepted = draft >= 0 && token == draft;
+    } else {
+      accepted = draft >= 0 && draft < vocab_size && row_uniform[0] < row_probs[draft];
+      token = accepted ? draft : block_sample(row_probs, vocab_size, draft, row_uniform[1], s_val, s_idx);
+    }
+    if (threadIdx.x == 0) {
+      seq_out[1 + num_out] = token;
+    }
+    num_out += 1;
+    if (!accepted) {
+      break;
+    }
+  }
+  if (threadIdx.x == 0) {
+    seq_out[0] = num_out;
+  }
+}
+
+} // namespace vllm
+
+extern "C" void rejection_sample(
+  const float* probs,                // [num_rows, vocab_size]
+  const uint32_t* draft_tokens,      // [num_seqs, max_num_draft_tokens]
+  const uint32_t* num_draft_tokens,  // [num_seqs]
+  const float* uniform,              // [num_rows, 2]
+  uint32_t* out,                     // [num_seqs, max_num_draft_tokens + 2]
+
+  int32_t num_seqs,
+  int32_t vocab_size,
+  int32_t max_num_draft_tokens,
+  int32_t greedy
+  )
+{
+  dim3 grid(num_seqs);
+  dim3 block(REJECTION_SAMPLER_THREADS);
+  const cudaStream_t stream = 0;
+
+  vllm::rejection_sample_kernel<<<grid, block, 0, stream>>>(
+    probs,
+    draft_tokens,
+    num_draft_tokens,
+    uniform,
+    out,
+    vocab_size,
+    max_num_draft_tokens,
+    greedy != 0);
+}
@@ -0,0 +1,198 @@
+#include <stdint.h>
+
+#include "cuda_compat.h"
+
+#define SAMPLER_THREADS 1024
+
+namespace vllm {
+
+// Key of `x` whose unsigned order is the order of the floats, so that a threshold on the logits
+// can be searched bit by bit.
+__device__ __forceinline__ uint32_t float_key(const float x) {
+  const uint32_t bits = __float_as_uint(x);
+  return (bits & 0x80000000u) ? ~bits : (bits | 0x80000000u);
+}
+
+__device__ float block_sum(float value, float* s_val) {
+  s_val[threadIdx.x] = value;
+  __syncthreads();
+  for (int stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+    if (threadIdx.x < stride) {
+      s_val[threadIdx.x] += s_val[threadIdx.x + stride];
+    }
+    __syncthreads();
+  }
+  const float result = s_val[0];
+  __syncthreads();
+  return result;
+}
+
+__device__ float block_max(float value, float* s_val) {
+  s_val[threadIdx.x] = value;
+  __syncthreads();
+  for (int stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+    if (threadIdx.x < stride) {
+      s_val[threadIdx.x] = fmaxf(s_val[threadIdx.x], s_val[threadIdx.x + stride]);
+    }
+    __syncthreads();
+  }
+  const float result = s_val[0];
+  __syncthreads();
+  return result;
+}
+
+// Number of the `vocab_size` scaled logits of the row whose key is at least `threshold`.
+__device__ float count_from(
+  const float* __restrict__ logits,
+  const int vocab_size,
+  const float inv_temperature,
+  const uint32_t threshold,
+  float* s_val) {
+  float count = 0.f;
+  for (int i = threadIdx.x; i < vocab_size; i += blockDim.x) {
+    count += float_key(logits[i] * inv_temperature) >= threshold ? 1.f : 0.f;
+  }
+  return block_sum(count, s_val);
+}
+
+// Unnormalized probability mass of the tokens whose key is at least `threshold`.
+__device__ float mass_from(
+  const float* __restrict__ logits,
+  const int vocab_size,
+  const float inv_temperature,
+  const float max,
+  const uint32_t threshold,
+  float* s_val) {
+  float mass = 0.f;
+  for (int i = threadIdx.x; i < vocab_size; i += blockDim.x) {
+    const float x = logits[i] * inv_temperature;
+    mass += float_key(x) >= threshold ? __expf(x - max) : 0.f;
+  }
+  return block_sum(mass, s_val);
+}
+
+// One thread block per row. The logits are scaled by the temperature and turned into
+// probabilities by a softmax, top-k keeps the tokens at least as likely as the k-th one, and
+// top-p the most likely of them making up `top_p` of the probability mass. Both are thresholds on
+// the logits, found by a binary search over their keys, so the vocabulary is never sorted and
+// the tokens tied with the last one kept are kept as well. The token is then sampled among the
+// kept ones by inverse transform sampling of the row's draw, in the order of the vocabulary.
+__global__ void sample_tokens_kernel(
+  const float* __restrict__ logits,   // [num_rows, vocab_size]
+  const float* __restrict__ uniform,  // [num_rows]
+  uint32_t* __restrict__ out,         // [num_rows]
+  const int vocab_size,
+  const float inv_temperature,
+  const int top_k,
+  const float top_p) {
+  __shared__ float s_val[SAMPLER_THREADS];
+  __shared__ int s_idx[2];
+
+  const float* row_logits = logits + (int64_t) blockIdx.x * vocab_size;
+
+  float local_max = -INFINITY;
+  for (int i = threadIdx.x; i < vocab_size; i += blockDim.x) {
+    local_max = fmaxf(local_max, row_logits[i] * inv_temperature);
+  }
+  const float max = block_max(local_max, s_val);
+
+  // The largest threshold keeping at least `top_k` tokens.
+  uint32_t lo = 0;
+  if (top_k > 0 && top_k < vocab_size) {
+    uint32_t hi = 0xffffffffu;
+    while (lo < hi) {
+      const uint32_t mid = lo + (uint32_t) (((uint64_t) hi - lo + 1) / 2);
+      if (count_from(row_logits, vocab_size, inv_temperature, mid, s_val) >= (float) top_k) {
+        lo = mid;
+      } else {
+        hi = mid - 1;
+      }
+    }
+  }
+  // The largest threshold above it keeping `top_p` of the mass of the whole vocabulary, or all
+  // the top-k tokens if they have less.
+  if (top_p > 0.f && top_p < 1.f) {
+    const float z = mass_from(row_logits, vocab_size, inv_temperature, max, 0, s_val);
+    const float target = top_p * z;
+    uint32_t hi = 0xffffffffu;
+    while (lo < hi) {
+      const uint32_t mid = lo + (uint32_t) (((uint64_t) hi - lo + 1) / 2);
+      if (mass_from(row_logits, vocab_size, inv_temperature, max, mid, s_val) >= target) {
+        lo = mid;
+      } else {
+        hi = mid - 1;
+      }
+    }
+  }
+  const uint32_t threshold = lo;
+
+  const float kept = mass_from(row_logits, vocab_size, inv_temperature, max, threshold, s_val);
+  const float target = uniform[blockIdx.x] * kept;
+
+  // s_idx[0] is the sampled token, s_idx[1] the last kept token.
+  if (threadIdx.x == 0) {
+    s_idx[0] = vocab_size;
+    s_idx[1] = -1;
+  }
+  float offset = 0.f;
+  for (int start = 0; start < vocab_size; start += blockDim.x) {
+    const int i = start + threadIdx.x;
+    float p = 0.f;
+    if (i < vocab_size) {
+      const float x = row_logits[i] * inv_temperature;
+      p = float_key(x) >= threshold ? __expf(x - max) : 0.f;
+    }
+    __syncthreads();
+    s_val[threadIdx.x] = p;
+    __syncthreads();
+    // Inclusive scan of the probabilities of this tile.
+    for (int stride = 1; stride < blockDim.x; stride <<= 1) {
+      const float add = threadIdx.x >= stride ? s_val[threadIdx.x - stride] : 0.f;
+      __syncthreads();
+      s_val[threadIdx.x] += add;
+      __syncthreads();
+    }
+    if (p > 0.f) {
+      atomicMax(&s_idx[1], i);
+      if (offset + s_val[threadIdx.x] > target) {
+        atomicMin(&s_idx[0], i);
+      }
+    }
+    offset += s_val[blockDim.x - 1];
+    __syncthreads();
+    if (s_idx[0] < vocab_size) {
+      break;
+    }
+  }
+  if (threadIdx.x == 0) {
+    out[blockIdx.x] = s_idx[0] < vocab_size ? s_idx[0] : (s_idx[1] >= 0 ? s_idx[1] : vocab_size);
+  }
+}
+
+} // namespace vllm
+
+extern "C" void sample_tokens(
+  const float* logits,   // [num_rows, vocab_size]
+  const float* uniform,  // [num_rows]
+  uint32_t* out,         // [num_rows]
+
+  int32_t num_rows,
+  int32_t vocab_size,
+  float inv_temperature,
+  int32_t top_k,
+  float top_p
+  )
+{
+  dim3 grid(num_rows);
+  dim3 block(SAMPLER_THREADS);
+  const cudaStream_t stream = 0;
+
+  vllm::sample_tokens_kernel<<<grid, block, 0, stream>>>(
+    logits,
+    uniform,
+    out,
+    vocab_size,
+    inv_temperature,
+    top_k,
+    top_p);
+}
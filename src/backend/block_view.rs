@@ -0,0 +1,72 @@
+//! Addressing of the blocks of a KV cache tensor. Key and value caches are contiguous tensors
+//! whose first dimension is the block, every block op goes through a `BlockView` so that the
+//! element range of a block is computed and bounds checked in one place.
+use candle::{Layout, Result};
+use candle_core as candle;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockView {
+    /// Offset of the first block in the storage, in elements.
+    start: usize,
+    num_blocks: usize,
+    /// Number of elements of one block.
+    block_numel: usize,
+}
+
+impl BlockView {
+    pub(crate) fn new(cache_l: &Layout) -> Result<Self> {
+        let Some((start, end)) = cache_l.contiguous_offsets() else {
+            candle::bail!("kv cache must be contiguous ({cache_l:?})")
+        };
+        let num_blocks = match cache_l.dims().first() {
+            Some(&num_blocks) if num_blocks > 0 => num_blocks,
+            _ => candle::bail!("kv cache must have at least one block ({cache_l:?})"),
+        };
+        Ok(Self {
+            start,
+            num_blocks,
+            block_numel: (end - start) / num_blocks,
+        })
+    }
+
+    pub(crate) fn block_numel(&self) -> usize {
+        self.block_numel
+    }
+
+    /// Offset of the first block in the storage, in elements.
+    pub(crate) fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Element range of `block` in the storage.
+    pub(crate) fn block(&self, block: usize) -> Result<Range<usize>> {
+        if block >= self.num_blocks {
+            candle::bail!(
+                "block {block} is out of range for a kv cache of {} blocks",
+                self.num_blocks
+            )
+        }
+        let start = self.start + block * self.block_numel;
+        Ok(start..start + self.block_numel)
+    }
+
+    /// Pairs of (source, destination) element ranges for copying blocks from `self` to `dst`.
+    pub(crate) fn copy_ranges(
+        &self,
+        dst: &BlockView,
+        block_mapping: &[(usize, usize)],
+    ) -> Result<Vec<(Range<usize>, Range<usize>)>> {
+        if self.block_numel != dst.block_numel {
+            candle::bail!(
+                "kv cache blocks have different sizes, {} (src) and {} (dst) elements",
+                self.block_numel,
+                dst.block_numel
+            )
+        }
+        block_mapping
+            .iter()
+            .map(|&(src_block, dst_block)| Ok((self.block(src_block)?, dst.block(dst_block)?)))
+            .collect()
+    }
+}
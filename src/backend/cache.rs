@@ -1,151 +1,134 @@
-use std::{collections::HashMap, iter::zip, ptr::NonNull};
+use std::{collections::HashMap, iter::zip, ops::Range, ptr::NonNull};
 
-use crate::{
-    backend::{get_or_load_func, Conjoined},
-    openai::responses::APIError,
-    try_api,
-};
-use candle_core::cuda_backend::CudaStorageSlice;
+use super::block_view::BlockView;
+use super::cache_error::{check_caches, kernel_u32, CacheOpError};
+use super::cpu::{copy_blocks_cpu, swap_blocks_cpu};
+use crate::backend::{get_or_load_func, Conjoined};
+use candle_core::cuda_backend::{CudaStorageSlice, WrapErr};
 use candle_core::{
-    cuda_backend::cudarc::driver::{CudaSlice, DevicePtr, LaunchAsync, LaunchConfig},
-    Device, IndexOp, Storage, Tensor,
+    cuda_backend::cudarc::driver::{CudaSlice, DevicePtr, DeviceRepr, LaunchAsync, LaunchConfig},
+    CpuStorage, CudaDevice, CudaStorage, Device, InplaceOp2, Layout, Storage, Tensor,
 };
 
 use super::COPY_BLOCKS_KERNEL_NAME;
 use kernels::COPY_BLOCKS_KERNEL;
 
+/// Device address of the first block of a cache on a cuda device.
+fn cuda_blocks_ptr(cache: &Tensor) -> candle_core::Result<(u64, BlockView)> {
+    let (storage, layout) = cache.storage_and_layout();
+    let view = BlockView::new(layout)?;
+    let Storage::Cuda(storage) = &*storage else {
+        candle_core::bail!("kv cache must be a cuda tensor")
+    };
+    let ptr = match &storage.slice {
+        CudaStorageSlice::BF16(slice) => *slice.slice(view.start()..).device_ptr(),
+        CudaStorageSlice::F16(slice) => *slice.slice(view.start()..).device_ptr(),
+        CudaStorageSlice::F32(slice) => *slice.slice(view.start()..).device_ptr(),
+        _ => candle_core::bail!("only f32, f16 and bf16 input data type supported!"),
+    };
+    Ok((ptr, view))
+}
+
+/// Copies block `src` to each block of `dsts` in every key and value cache, for every entry of
+/// `block_mapping`. The caches are checked up front: one value cache per key cache, all
+/// contiguous on the same cpu or cuda device with the same dtype, and blocks in range.
+///
 /// # Safety
 /// Unsafe due to passing pointers
 pub unsafe fn copy_blocks(
     key_caches: Vec<&mut Tensor>,
     value_caches: Vec<&mut Tensor>,
     block_mapping: HashMap<usize, Vec<usize>>,
-) -> Result<(), APIError> {
-    let cache_dev = key_caches.first().unwrap().device();
-    let Device::Cuda(dev) = cache_dev else {
-        panic!("Expected the key caches to be on a CUDA device.")
-    };
-    if !cache_dev.same_device(value_caches.first().unwrap().device()) {
-        return Err(APIError::new(format!(
-            "`key` and `value` caches have different devices, got {:?} and {:?} respectively.",
-            cache_dev,
-            value_caches.first().unwrap().device()
-        )));
-    }
-    if key_caches.first().unwrap().dtype() != value_caches.first().unwrap().dtype() {
-        return Err(APIError::new(format!(
-            "Key and value caches have different types, got {:?} and {:?}.",
-            key_caches.first().unwrap().dtype(),
-            value_caches.first().unwrap().dtype()
-        )));
+) -> Result<(), CacheOpError> {
+    if key_caches.len() != value_caches.len() {
+        return Err(CacheOpError::LayerMismatch {
+            keys: key_caches.len(),
+            values: value_caches.len(),
+        });
     }
-    let num_layers: u32 = key_caches.len().try_into().unwrap();
-    if num_layers == 0 {
+    let caches = key_caches.iter().chain(&value_caches).map(|cache| &**cache);
+    let Some((cache_dev, dtype)) = check_caches(caches)? else {
         return Ok(());
-    }
+    };
+    let num_layers = kernel_u32("layers", key_caches.len())?;
+    let block_pairs = block_mapping
+        .iter()
+        .flat_map(|(src, dsts)| dsts.iter().map(move |dst| (*src, *dst)))
+        .collect::<Vec<_>>();
+    let dev = match cache_dev {
+        Device::Cuda(dev) => dev,
+        _ => {
+            for cache in key_caches.iter().chain(&value_caches) {
+                copy_blocks_cpu(cache, &block_pairs).map_err(CacheOpError::Blocks)?;
+            }
+            return Ok(());
+        }
+    };
 
     let mut key_cache_ptrs = Vec::new();
     key_cache_ptrs.reserve_exact(num_layers as usize);
     let mut value_cache_ptrs = Vec::new();
     value_cache_ptrs.reserve_exact(num_layers as usize);
+    let mut numel_per_block = None;
     for (key_cache, value_cache) in zip(&key_caches, &value_caches) {
-        try_api!(key_cache.to_device(cache_dev));
-        try_api!(value_cache.to_device(cache_dev));
-
-        let key_offset: u64 = key_cache
-            .storage_and_layout()
-            .1
-            .start_offset()
-            .try_into()
-            .unwrap();
-        let Storage::Cuda(key_storage) = &*key_cache.storage_and_layout().0 else {
-            unreachable!()
-        };
-
-        // let key_ptr = *try_api!(key_storage.as_cuda_slice::<u8>()).device_ptr();
-
-        let value_offset: u64 = value_cache
-            .storage_and_layout()
-            .1
-            .start_offset()
-            .try_into()
-            .unwrap();
-        let Storage::Cuda(value_storage) = &*value_cache.storage_and_layout().0 else {
-            unreachable!()
-        };
-        // let value_ptr = *try_api!(value_storage.as_cuda_slice::<u8>()).device_ptr();
-
-        let (key_ptr, value_ptr) = match (&key_storage.slice, &value_storage.slice) {
-            (CudaStorageSlice::BF16(slice_key), CudaStorageSlice::BF16(slice_value)) => {
-                let ptr_key = *slice_key.slice(0..).device_ptr();
-                let ptr_value = *slice_value.slice(0..).device_ptr();
-                (ptr_key, ptr_value)
-            }
-            (CudaStorageSlice::F16(slice_key), CudaStorageSlice::F16(slice_value)) => {
-                let ptr_key = *slice_key.slice(0..).device_ptr();
-                let ptr_value = *slice_value.slice(0..).device_ptr();
-                (ptr_key, ptr_value)
+        let (key_ptr, key_view) = cuda_blocks_ptr(key_cache).map_err(CacheOpError::Blocks)?;
+        let (value_ptr, value_view) = cuda_blocks_ptr(value_cache).map_err(CacheOpError::Blocks)?;
+        // The kernel indexes all caches with the block numbers and block size of the first.
+        for view in [key_view, value_view] {
+            view.copy_ranges(&view, &block_pairs)
+                .map_err(CacheOpError::Blocks)?;
+            if *numel_per_block.get_or_insert(view.block_numel()) != view.block_numel() {
+                return Err(CacheOpError::Blocks(candle_core::Error::Msg(
+                    "all key and value caches must have blocks of the same size".to_string(),
+                )));
             }
-            (CudaStorageSlice::F32(slice_key), CudaStorageSlice::F32(slice_value)) => {
-                let ptr_key = *slice_key.slice(0..).device_ptr();
-                let ptr_value = *slice_value.slice(0..).device_ptr();
-                (ptr_key, ptr_value)
-            }
-            _ => {
-                return Err(APIError::from(
-                    "only f32, f16 and bf16 input data type supported!",
-                ));
-            }
-        };
-        key_cache_ptrs.push(key_ptr + key_offset);
-        value_cache_ptrs.push(value_ptr + value_offset);
+        }
+        key_cache_ptrs.push(key_ptr);
+        value_cache_ptrs.push(value_ptr);
     }
+    let Some(numel_per_block) = numel_per_block.filter(|_| !block_pairs.is_empty()) else {
+        return Ok(());
+    };
 
     let mut block_mapping_vec: Vec<i64> = Vec::new();
-    for (src_block_number, dst_blocks) in block_mapping {
-        for dst_block_number in dst_blocks {
-            block_mapping_vec.push(src_block_number.try_into().unwrap());
-            block_mapping_vec.push(dst_block_number.try_into().unwrap());
-        }
+    for (src_block_number, dst_block_number) in block_pairs {
+        // Block numbers are in range of the caches, far below i64::MAX.
+        block_mapping_vec.push(src_block_number as i64);
+        block_mapping_vec.push(dst_block_number as i64);
     }
-    let num_pairs: u32 = (block_mapping_vec.len() / 2).try_into().unwrap();
+    let num_pairs = kernel_u32("block pairs", block_mapping_vec.len() / 2)?;
+    let numel_per_block = kernel_u32("elements per block", numel_per_block)?;
+    // The vectors are not empty, their pointers are not null.
     let block_mapping_ptr = Conjoined::new(
-        NonNull::new(block_mapping_vec.as_mut_ptr()).unwrap(),
+        NonNull::from(&mut block_mapping_vec[0]),
         &mut block_mapping_vec,
     );
-
-    let key_cache_ptr = Conjoined::new(
-        NonNull::new(key_cache_ptrs.as_mut_ptr()).unwrap(),
-        &mut key_cache_ptrs,
-    );
+    let key_cache_ptr = Conjoined::new(NonNull::from(&mut key_cache_ptrs[0]), &mut key_cache_ptrs);
     let value_cache_ptr = Conjoined::new(
-        NonNull::new(value_cache_ptrs.as_mut_ptr()).unwrap(),
+        NonNull::from(&mut value_cache_ptrs[0]),
         &mut value_cache_ptrs,
     );
 
-    let numel_per_block: u32 = try_api!(key_caches.first().unwrap().i(0))
-        .shape()
-        .dims()
-        .iter()
-        .product::<usize>()
-        .try_into()
-        .unwrap();
     let launch_conf = LaunchConfig {
         grid_dim: (num_layers, num_pairs, 1u32),
         block_dim: (numel_per_block.min(1024), 1u32, 1u32),
         shared_mem_bytes: 0,
     };
-    let stream = try_api!(dev.fork_default_stream());
+    let stream = dev
+        .fork_default_stream()
+        .w()
+        .map_err(CacheOpError::Device)?;
 
-    let kernel = try_api!(get_or_load_func(
+    let kernel = get_or_load_func(
         COPY_BLOCKS_KERNEL,
         COPY_BLOCKS_KERNEL_NAME,
-        key_caches.first().unwrap().dtype(),
+        dtype,
         None,
         &dev,
-    ));
+    )
+    .map_err(|e| CacheOpError::Device(candle_core::Error::Msg(e.to_string())))?;
 
-    try_api!(unsafe {
+    unsafe {
         kernel.launch_on_stream(
             &stream,
             launch_conf,
@@ -156,84 +139,126 @@ pub unsafe fn copy_blocks(
                 numel_per_block as i32,
             ),
         )
-    });
+    }
+    .w()
+    .map_err(CacheOpError::Device)?;
 
     Ok(())
 }
 
-pub fn swap_blocks(
-    src: Tensor,
-    dst: &mut Tensor,
-    block_mapping: HashMap<usize, usize>,
-) -> Result<(), APIError> {
-    let block_size_in_bytes = src.dtype().size_in_bytes() * src.dims()[0];
-    match (src.device(), dst.device()) {
-        (Device::Cuda(src_dev), Device::Cuda(dst_dev)) => {
-            if src_dev.ordinal() != dst_dev.ordinal() {
-                return Err(APIError::new(format!("Tensors must be on the same device to copy, got ordinals {} (src) and {} (dst).", src_dev.ordinal(), dst_dev.ordinal())))
+/// Copies whole blocks from `src` to `dst`, two caches on the same cuda device.
+struct CudaBlockSwap {
+    block_mapping: Vec<(usize, usize)>,
+}
+
+fn copy_cuda_block_ranges<T: DeviceRepr>(
+    dev: &CudaDevice,
+    dst: &mut CudaSlice<T>,
+    src: &CudaSlice<T>,
+    ranges: &[(Range<usize>, Range<usize>)],
+) -> candle_core::Result<()> {
+    for (src_range, dst_range) in ranges {
+        let src_block = src.slice(src_range.clone());
+        let mut dst_block = dst.slice_mut(dst_range.clone());
+        dev.dtod_copy(&src_block, &mut dst_block).w()?;
+    }
+    Ok(())
+}
+
+impl InplaceOp2 for CudaBlockSwap {
+    fn name(&self) -> &'static str {
+        "swap-blocks"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &mut CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> candle_core::Result<()> {
+        candle_core::bail!("swap-blocks on cpu is handled by swap_blocks_cpu")
+    }
+
+    fn cuda_fwd(
+        &self,
+        dst: &mut CudaStorage,
+        dst_l: &Layout,
+        src: &CudaStorage,
+        src_l: &Layout,
+    ) -> candle_core::Result<()> {
+        let ranges =
+            BlockView::new(src_l)?.copy_ranges(&BlockView::new(dst_l)?, &self.block_mapping)?;
+        let dev = dst.device.clone();
+        match (&mut dst.slice, &src.slice) {
+            (CudaStorageSlice::BF16(dst), CudaStorageSlice::BF16(src)) => {
+                copy_cuda_block_ranges(&dev, dst, src, &ranges)
             }
-            let (src_storage, src_layout) = src.storage_and_layout();
-            let (dst_storage, dst_layout) = dst.storage_and_layout();
-            assert!(matches!(&*src_storage, Storage::Cuda(_)));
-            assert!(matches!(&*dst_storage, Storage::Cuda(_)));
-            let Storage::Cuda(src_storage) = &*src_storage else { unreachable!() };
-            let Storage::Cuda(dst_storage) = &*dst_storage else { unreachable!() };
-            let (src_ptr, dst_ptr) = match (&src_storage.slice, &dst_storage.slice) {
-                (CudaStorageSlice::BF16(slice_src), CudaStorageSlice::BF16(slice_dst)) => {
-                    let ptr_src = *slice_src.slice(src_layout.start_offset()..).device_ptr();
-                    let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
-                    (ptr_src, ptr_dst)
-                }
-                (CudaStorageSlice::F16(slice_src), CudaStorageSlice::F16(slice_dst)) => {
-                    let ptr_src = *slice_src.slice(src_layout.start_offset()..).device_ptr();
-                    let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
-                    (ptr_src, ptr_dst)
-                }
-                (CudaStorageSlice::F32(slice_src), CudaStorageSlice::F32(slice_dst)) => {
-                    let ptr_src = *slice_src.slice(src_layout.start_offset()..).device_ptr();
-                    let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
-                    (ptr_src, ptr_dst)
-                }
-                _ => {
-                    return Err(APIError::from("only f32, f16 and bf16 input data type supported!"));
-                }
-            };
-            // let src_ptr = src_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(src_layout.start_offset()).unwrap();
-            // let dst_ptr = dst_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(dst_layout.start_offset()).unwrap();
-
-            for (src_block_number, dst_block_number) in block_mapping {
-                let src_offset: u64 = (src_block_number * block_size_in_bytes).try_into().unwrap();
-                let dst_offset: u64 = (dst_block_number * block_size_in_bytes).try_into().unwrap();
-                // u8s because we copy by bytes
-                let src_slice: CudaSlice<u8> = unsafe { src_dev.upgrade_device_ptr(src_ptr+src_offset, block_size_in_bytes) };
-                let mut dst_slice = unsafe { dst_dev.upgrade_device_ptr(dst_ptr+dst_offset, block_size_in_bytes) };
-
-                try_api!(src_dev.dtod_copy(&src_slice, &mut dst_slice));
+            (CudaStorageSlice::F16(dst), CudaStorageSlice::F16(src)) => {
+                copy_cuda_block_ranges(&dev, dst, src, &ranges)
             }
-        }
-        (Device::Cpu, Device::Cuda(dst_dev)) => {
-            let (src_storage, _src_layout) = src.storage_and_layout();
-            let (dst_storage, dst_layout) = dst.storage_and_layout();
-            assert!(matches!(&*src_storage, Storage::Cpu(_)));
-            assert!(matches!(&*dst_storage, Storage::Cuda(_)));
-            let Storage::Cpu(src_storage) = &*src_storage else { unreachable!() };
-            let Storage::Cuda(dst_storage) = &*dst_storage else { unreachable!() };
-            let dst_ptr = dst_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(dst_layout.start_offset()).unwrap();
-            let src_slice = try_api!(src_storage.as_slice());
-
-            for (src_block_number, dst_block_number) in block_mapping {
-                let src_offset = src_block_number * block_size_in_bytes;
-                let dst_offset: u64 = (dst_block_number * block_size_in_bytes).try_into().unwrap();
-                // u8s because we copy by bytes
-                let mut dst_slice: CudaSlice<u8> = unsafe { dst_dev.upgrade_device_ptr(dst_ptr+dst_offset, block_size_in_bytes) };
-
-                try_api!(dst_dev.htod_sync_copy_into(&src_slice[src_offset..src_offset+block_size_in_bytes], &mut dst_slice));
+            (CudaStorageSlice::F32(dst), CudaStorageSlice::F32(src)) => {
+                copy_cuda_block_ranges(&dev, dst, src, &ranges)
             }
+            _ => candle_core::bail!(
+                "swap_blocks is only supported between f32, f16 or bf16 caches of the same dtype"
+            ),
         }
-        (src, dst) => {
-            return Err(APIError::new(format!("Tensors must be on either the GPU or CPU to swap,, got {src:?} (src) and {dst:?} (dst).")))
-        }
+    }
+}
+
+/// Copies block `src_block` of `src` to block `dst_block` of `dst` for every entry of
+/// `block_mapping`. When the caches are on different devices, only the blocks to copy are moved
+/// to the device of `dst`. Both caches need the same dtype and `dst` has to be contiguous, on the
+/// cpu or on a cuda device. A `src` that is not contiguous, e.g. sliced out of a larger tensor, is
+/// copied to a contiguous one first.
+pub fn swap_blocks(
+    src: Tensor,
+    dst: &mut Tensor,
+    block_mapping: HashMap<usize, usize>,
+) -> Result<(), CacheOpError> {
+    check_caches([&*dst])?;
+    if src.dtype() != dst.dtype() {
+        return Err(CacheOpError::DtypeMismatch {
+            expected: dst.dtype(),
+            found: src.dtype(),
+        });
+    }
+    let src = if src.is_contiguous() {
+        src
+    } else {
+        src.contiguous().map_err(CacheOpError::Device)?
+    };
+    let mut block_mapping = block_mapping.into_iter().collect::<Vec<_>>();
+    let src_view = BlockView::new(src.layout()).map_err(CacheOpError::Blocks)?;
+    let dst_view = BlockView::new(dst.layout()).map_err(CacheOpError::Blocks)?;
+    src_view
+        .copy_ranges(&dst_view, &block_mapping)
+        .map_err(CacheOpError::Blocks)?;
+    if block_mapping.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    let src = if src.device().same_device(dst.device()) {
+        src
+    } else {
+        let src_blocks = block_mapping
+            .iter()
+            .map(|&(src_block, _)| src_block as u32)
+            .collect::<Vec<_>>();
+        for (i, (src_block, _)) in block_mapping.iter_mut().enumerate() {
+            *src_block = i;
+        }
+        Tensor::new(src_blocks, src.device())
+            .and_then(|src_blocks| src.index_select(&src_blocks, 0))
+            .and_then(|src_blocks| src_blocks.to_device(dst.device()))
+            .map_err(CacheOpError::Device)?
+    };
+
+    match dst.device() {
+        Device::Cpu => swap_blocks_cpu(&src, dst, &block_mapping).map_err(CacheOpError::Blocks),
+        _ => dst
+            .inplace_op2(&src, &CudaBlockSwap { block_mapping })
+            .map_err(CacheOpError::Device),
+    }
 }
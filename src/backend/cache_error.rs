@@ -0,0 +1,146 @@
+//! Errors of the block ops of the KV cache, and the checks run on the caches before any block is
+//! copied, so that a bad cache is reported instead of reaching a kernel.
+use candle::cuda_backend::{cudarc::driver::sys::CUdevice_attribute, WrapErr};
+use candle::{DType, Device, DeviceLocation, Tensor};
+use candle_core as candle;
+use derive_more::Display;
+
+/// Dtypes of the caches the block ops support.
+pub const CACHE_DTYPES: [DType; 3] = [DType::F16, DType::BF16, DType::F32];
+
+#[derive(Debug, Display)]
+pub enum CacheOpError {
+    #[display(fmt = "got {} key caches for {} value caches", keys, values)]
+    LayerMismatch { keys: usize, values: usize },
+    #[display(
+        fmt = "kv caches are on different devices, {:?} and {:?}",
+        expected,
+        found
+    )]
+    DeviceMismatch {
+        expected: DeviceLocation,
+        found: DeviceLocation,
+    },
+    #[display(
+        fmt = "kv caches have different dtypes, {:?} and {:?}",
+        expected,
+        found
+    )]
+    DtypeMismatch { expected: DType, found: DType },
+    #[display(
+        fmt = "kv caches of dtype {:?} are not supported, only f16, bf16 and f32",
+        _0
+    )]
+    UnsupportedDtype(DType),
+    #[display(fmt = "block ops are not supported on {:?}", _0)]
+    UnsupportedDevice(DeviceLocation),
+    /// The bf16 kernels of the cache need an Ampere or newer GPU.
+    #[display(
+        fmt = "bf16 kv caches need a GPU of compute capability 8.0 or newer, {:?} has {}.{}, serve the model with --dtype f16",
+        location,
+        major,
+        minor
+    )]
+    Bf16Unsupported {
+        location: DeviceLocation,
+        major: i32,
+        minor: i32,
+    },
+    /// More layers, block pairs or elements per block than a kernel launch can address.
+    #[display(fmt = "too many {} for the copy kernel: {}", what, count)]
+    KernelLimit { what: &'static str, count: usize },
+    /// A cache written in place whose blocks are not ranges of its storage, e.g. a transposed
+    /// one. Copying it to a contiguous tensor would leave the cache itself unchanged.
+    #[display(
+        fmt = "kv cache of shape {:?} and stride {:?} is not contiguous",
+        dims,
+        stride
+    )]
+    NotContiguous {
+        dims: Vec<usize>,
+        stride: Vec<usize>,
+    },
+    /// A block out of range or blocks of different sizes.
+    #[display(fmt = "invalid blocks: {}", _0)]
+    Blocks(candle::Error),
+    /// The device failed to copy the blocks.
+    #[display(fmt = "block copy failed: {}", _0)]
+    Device(candle::Error),
+}
+
+impl std::error::Error for CacheOpError {}
+
+/// Checks that the cache ops run in `dtype` on `device`, before a cache is allocated: the bf16
+/// kernels of paged attention trap on GPUs older than Ampere.
+pub fn check_cache_dtype(device: &Device, dtype: DType) -> Result<(), CacheOpError> {
+    if !CACHE_DTYPES.contains(&dtype) {
+        return Err(CacheOpError::UnsupportedDtype(dtype));
+    }
+    let Device::Cuda(dev) = device else {
+        return Ok(());
+    };
+    if dtype != DType::BF16 {
+        return Ok(());
+    }
+    let attribute = |attribute| dev.attribute(attribute).w().map_err(CacheOpError::Device);
+    let major = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)?;
+    let minor = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)?;
+    if major < 8 {
+        return Err(CacheOpError::Bf16Unsupported {
+            location: device.location(),
+            major,
+            minor,
+        });
+    }
+    Ok(())
+}
+
+/// Device and dtype shared by `caches`, which the block ops have to support and write in place.
+/// `None` if there is no cache.
+pub(crate) fn check_caches<'a>(
+    caches: impl IntoIterator<Item = &'a Tensor>,
+) -> Result<Option<(Device, DType)>, CacheOpError> {
+    let mut caches = caches.into_iter();
+    let Some(first) = caches.next() else {
+        return Ok(None);
+    };
+    check_contiguous(first)?;
+    let (device, dtype) = (first.device(), first.dtype());
+    if !matches!(device, Device::Cpu | Device::Cuda(_)) {
+        return Err(CacheOpError::UnsupportedDevice(device.location()));
+    }
+    if !CACHE_DTYPES.contains(&dtype) {
+        return Err(CacheOpError::UnsupportedDtype(dtype));
+    }
+    for cache in caches {
+        check_contiguous(cache)?;
+        if !cache.device().same_device(device) {
+            return Err(CacheOpError::DeviceMismatch {
+                expected: device.location(),
+                found: cache.device().location(),
+            });
+        }
+        if cache.dtype() != dtype {
+            return Err(CacheOpError::DtypeMismatch {
+                expected: dtype,
+                found: cache.dtype(),
+            });
+        }
+    }
+    Ok(Some((device.clone(), dtype)))
+}
+
+fn check_contiguous(cache: &Tensor) -> Result<(), CacheOpError> {
+    if cache.is_contiguous() {
+        return Ok(());
+    }
+    Err(CacheOpError::NotContiguous {
+        dims: cache.dims().to_vec(),
+        stride: cache.stride().to_vec(),
+    })
+}
+
+/// `value` as the u32 of a kernel launch parameter.
+pub(crate) fn kernel_u32(what: &'static str, value: usize) -> Result<u32, CacheOpError> {
+    u32::try_from(value).map_err(|_| CacheOpError::KernelLimit { what, count: value })
+}
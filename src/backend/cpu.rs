@@ -0,0 +1,440 @@
+//! Reference CPU implementations of the paged attention ops, used when the model and its KV cache
+//! live in host memory. They address the caches through `KvCacheLayout`, as the CUDA kernels
+//! lay them out.
+use super::block_view::BlockView;
+use super::kv_layout::KvCacheLayout;
+use candle::{
+    CpuStorage, InplaceOp1, InplaceOp2, Layout, Result, Shape, Storage, Tensor, WithDType,
+};
+use candle_core as candle;
+use std::ops::Range;
+
+fn cpu_slice<'a, T: WithDType>(
+    storage: &'a Storage,
+    layout: &Layout,
+    name: &str,
+) -> Result<&'a [T]> {
+    let storage = match storage {
+        Storage::Cpu(storage) => storage,
+        _ => candle::bail!("{name} must be a cpu tensor"),
+    };
+    match layout.contiguous_offsets() {
+        Some((start, end)) => Ok(&storage.as_slice::<T>()?[start..end]),
+        None => candle::bail!("{name} must be contiguous"),
+    }
+}
+
+/// Writes `src` of shape `(num_tokens, num_heads, head_size)` into the key cache (`key` is set)
+/// or the value cache at the slots given by `slot_mapping`.
+struct CacheUpdate {
+    src: Tensor,
+    slot_mapping: Tensor,
+    layout: KvCacheLayout,
+    key: bool,
+}
+
+impl CacheUpdate {
+    fn update<T: WithDType>(&self, cache: &mut [T], cache_l: &Layout) -> Result<()> {
+        let (num_tokens, num_heads, head_size) = self.src.dims3()?;
+        if (num_heads, head_size) != (self.layout.num_heads(), self.layout.head_size()) {
+            candle::bail!(
+                "{num_heads} heads of size {head_size} do not fit a kv cache of {:?}",
+                self.layout
+            )
+        }
+        let block_size = self.layout.block_size();
+        let src = self.src.flatten_all()?.to_vec1::<T>()?;
+        let slots = self.slot_mapping.to_vec1::<i64>()?;
+        if slots.len() != num_tokens {
+            candle::bail!(
+                "slot_mapping has {} entries for {num_tokens} tokens",
+                slots.len()
+            )
+        }
+        let view = BlockView::new(cache_l)?;
+
+        for (token, slot) in slots.into_iter().enumerate() {
+            // Padding tokens have no slot.
+            if slot < 0 {
+                continue;
+            }
+            let (block, offset) = (slot as usize / block_size, slot as usize % block_size);
+            let cache_block = &mut cache[view.block(block)?];
+            for head in 0..num_heads {
+                for d in 0..head_size {
+                    let idx = if self.key {
+                        self.layout.key_index(0, head, d, offset)
+                    } else {
+                        self.layout.value_index(0, head, d, offset)
+                    };
+                    cache_block[idx] = src[(token * num_heads + head) * head_size + d];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InplaceOp1 for CacheUpdate {
+    fn name(&self) -> &'static str {
+        "reshape-and-cache"
+    }
+
+    fn cpu_fwd(&self, cache: &mut CpuStorage, cache_l: &Layout) -> Result<()> {
+        match cache {
+            CpuStorage::F32(cache) => self.update(cache, cache_l),
+            CpuStorage::F16(cache) => self.update(cache, cache_l),
+            CpuStorage::BF16(cache) => self.update(cache, cache_l),
+            _ => candle::bail!("reshape_and_cache is only supported for f32, f16 and bf16"),
+        }
+    }
+}
+
+pub(crate) fn reshape_and_cache_cpu(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let layout = KvCacheLayout::of_caches(key_cache.shape(), value_cache.shape())?;
+    let slot_mapping = slot_mapping.flatten_all()?;
+    key_cache.inplace_op1(&CacheUpdate {
+        src: key.clone(),
+        slot_mapping: slot_mapping.clone(),
+        layout,
+        key: true,
+    })?;
+    value_cache.inplace_op1(&CacheUpdate {
+        src: value.clone(),
+        slot_mapping,
+        layout,
+        key: false,
+    })
+}
+
+/// Copies whole blocks from `src` to `dst`, two caches of the same shape.
+struct BlockSwap {
+    block_mapping: Vec<(usize, usize)>,
+}
+
+impl InplaceOp2 for BlockSwap {
+    fn name(&self) -> &'static str {
+        "swap-blocks"
+    }
+
+    fn cpu_fwd(
+        &self,
+        dst: &mut CpuStorage,
+        dst_l: &Layout,
+        src: &CpuStorage,
+        src_l: &Layout,
+    ) -> Result<()> {
+        let ranges =
+            BlockView::new(src_l)?.copy_ranges(&BlockView::new(dst_l)?, &self.block_mapping)?;
+        match (dst, src) {
+            (CpuStorage::F32(dst), CpuStorage::F32(src)) => copy_block_ranges(dst, src, &ranges),
+            (CpuStorage::F16(dst), CpuStorage::F16(src)) => copy_block_ranges(dst, src, &ranges),
+            (CpuStorage::BF16(dst), CpuStorage::BF16(src)) => copy_block_ranges(dst, src, &ranges),
+            _ => candle::bail!(
+                "swap_blocks is only supported between f32, f16 or bf16 caches of the same dtype"
+            ),
+        }
+        Ok(())
+    }
+}
+
+fn copy_block_ranges<T: Copy>(dst: &mut [T], src: &[T], ranges: &[(Range<usize>, Range<usize>)]) {
+    for (src_range, dst_range) in ranges {
+        dst[dst_range.clone()].copy_from_slice(&src[src_range.clone()]);
+    }
+}
+
+pub(crate) fn swap_blocks_cpu(
+    src: &Tensor,
+    dst: &Tensor,
+    block_mapping: &[(usize, usize)],
+) -> Result<()> {
+    dst.inplace_op2(
+        src,
+        &BlockSwap {
+            block_mapping: block_mapping.to_vec(),
+        },
+    )
+}
+
+/// Copies whole blocks to other blocks of the same cache.
+struct BlockCopy {
+    block_mapping: Vec<(usize, usize)>,
+}
+
+impl InplaceOp1 for BlockCopy {
+    fn name(&self) -> &'static str {
+        "copy-blocks"
+    }
+
+    fn cpu_fwd(&self, cache: &mut CpuStorage, cache_l: &Layout) -> Result<()> {
+        let view = BlockView::new(cache_l)?;
+        let ranges = view.copy_ranges(&view, &self.block_mapping)?;
+        match cache {
+            CpuStorage::F32(cache) => copy_blocks_within(cache, &ranges),
+            CpuStorage::F16(cache) => copy_blocks_within(cache, &ranges),
+            CpuStorage::BF16(cache) => copy_blocks_within(cache, &ranges),
+            _ => candle::bail!("copy_blocks is only supported for f32, f16 and bf16"),
+        }
+        Ok(())
+    }
+}
+
+fn copy_blocks_within<T: Copy>(cache: &mut [T], ranges: &[(Range<usize>, Range<usize>)]) {
+    for (src_range, dst_range) in ranges {
+        cache.copy_within(src_range.clone(), dst_range.start);
+    }
+}
+
+pub(crate) fn copy_blocks_cpu(cache: &Tensor, block_mapping: &[(usize, usize)]) -> Result<()> {
+    cache.inplace_op1(&BlockCopy {
+        block_mapping: block_mapping.to_vec(),
+    })
+}
+
+/// Decoding attention of one query token per sequence over the tokens already in the cache.
+/// `q` has shape `(num_seqs, num_heads, head_size)`, as does the result.
+pub(crate) fn paged_attention_cpu<T: WithDType>(
+    q: &[T],
+    q_l: &Layout,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+) -> Result<(CpuStorage, Shape)> {
+    let (num_seqs, num_heads, head_size) = q_l.shape().dims3()?;
+    let q = match q_l.contiguous_offsets() {
+        Some((start, end)) => &q[start..end],
+        None => candle::bail!("q must be contiguous"),
+    };
+    let layout = KvCacheLayout::of_caches(key_cache.shape(), value_cache.shape())?;
+    let (num_kv_heads, block_size) = (layout.num_heads(), layout.block_size());
+    if layout.head_size() != head_size {
+        candle::bail!(
+            "head size {head_size} of q does not match the kv cache of {:?}",
+            layout
+        )
+    }
+    if num_heads % num_kv_heads != 0 {
+        candle::bail!("number of kv heads {num_kv_heads} must divide number of heads {num_heads}")
+    }
+    let num_queries_per_kv = num_heads / num_kv_heads;
+    let block_tables = block_tables.to_vec2::<u32>()?;
+    let context_lens = context_lens.to_vec1::<u32>()?;
+
+    let (kc, kc_l) = key_cache.storage_and_layout();
+    let kc = cpu_slice::<T>(&kc, kc_l, "key_cache")?;
+    let (vc, vc_l) = value_cache.storage_and_layout();
+    let vc = cpu_slice::<T>(&vc, vc_l, "value_cache")?;
+
+    let mut out = vec![T::zero(); num_seqs * num_heads * head_size];
+    for seq in 0..num_seqs {
+        let context_len = context_lens[seq] as usize;
+        let slots = (0..context_len)
+            .map(|pos| {
+                (
+                    block_tables[seq][pos / block_size] as usize,
+                    pos % block_size,
+                )
+            })
+            .collect::<Vec<_>>();
+        for head in 0..num_heads {
+            let kv_head = head / num_queries_per_kv;
+            let q_head = &q[(seq * num_heads + head) * head_size..][..head_size];
+
+            let mut scores = slots
+                .iter()
+                .map(|&(block, offset)| {
+                    let dot = (0..head_size)
+                        .map(|d| {
+                            let k = kc[layout.key_index(block, kv_head, d, offset)];
+                            q_head[d].to_f64() * k.to_f64()
+                        })
+                        .sum::<f64>();
+                    dot * f64::from(softmax_scale)
+                })
+                .collect::<Vec<_>>();
+            let max = scores.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            scores.iter_mut().for_each(|s| *s = (*s - max).exp());
+            let sum = scores.iter().sum::<f64>();
+
+            let out_head = &mut out[(seq * num_heads + head) * head_size..][..head_size];
+            for (d, o) in out_head.iter_mut().enumerate() {
+                let acc = slots
+                    .iter()
+                    .zip(&scores)
+                    .map(|(&(block, offset), p)| {
+                        let v = vc[layout.value_index(block, kv_head, d, offset)];
+                        p * v.to_f64()
+                    })
+                    .sum::<f64>();
+                *o = T::from_f64(acc / sum);
+            }
+        }
+    }
+    Ok((
+        T::to_cpu_storage_owned(out),
+        Shape::from((num_seqs, num_heads, head_size)),
+    ))
+}
+
+/// Index of the largest of `probs`, the lowest one among equal values.
+fn argmax(probs: &[f32]) -> usize {
+    let mut best = 0;
+    for (i, &p) in probs.iter().enumerate() {
+        if p > probs[best] {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Token sampled from `probs` without `excluded` by inverse transform sampling of `u` in [0, 1):
+/// the first token whose cumulative probability exceeds `u` times the total. The last token with
+/// a probability is taken if rounding leaves `u` above them all, and `probs.len()` if no token
+/// has one.
+fn sample(probs: &[f32], excluded: Option<usize>, u: f32) -> usize {
+    let prob = |i: usize| if Some(i) == excluded { 0. } else { probs[i] };
+    let total = (0..probs.len()).map(prob).sum::<f32>();
+    let target = u * total;
+    let mut cumsum = 0.;
+    let mut last = None;
+    for i in 0..probs.len() {
+        let p = prob(i);
+        if p > 0. {
+            cumsum += p;
+            last = Some(i);
+            if cumsum > target {
+                return i;
+            }
+        }
+    }
+    last.unwrap_or(probs.len())
+}
+
+/// Verification of the draft tokens of each sequence, as the rejection sampling kernel does it.
+/// `probs` has a row for the last token of each sequence followed by one for each of its draft
+/// tokens, and the result a row per sequence of its number of tokens and the tokens themselves.
+pub(crate) fn rejection_sample_cpu(
+    probs: &[f32],
+    probs_l: &Layout,
+    draft_tokens: &Tensor,
+    num_draft_tokens: &Tensor,
+    uniform: &Tensor,
+    greedy: bool,
+) -> Result<(CpuStorage, Shape)> {
+    let (num_rows, vocab_size) = probs_l.shape().dims2()?;
+    let probs = match probs_l.contiguous_offsets() {
+        Some((start, end)) => &probs[start..end],
+        None => candle::bail!("probs must be contiguous"),
+    };
+    let draft_tokens = draft_tokens.to_vec2::<u32>()?;
+    let num_draft_tokens = num_draft_tokens.to_vec1::<u32>()?;
+    let uniform = uniform.to_vec2::<f32>()?;
+    let (num_seqs, max_num_draft_tokens) = (draft_tokens.len(), draft_tokens[0].len());
+    if num_draft_tokens
+        .iter()
+        .map(|&n| n as usize + 1)
+        .sum::<usize>()
+        != num_rows
+    {
+        candle::bail!("probs has {num_rows} rows for {num_seqs} sequences and their draft tokens")
+    }
+
+    let mut out = vec![0u32; num_seqs * (max_num_draft_tokens + 2)];
+    let mut first_row = 0;
+    for (seq, seq_out) in out.chunks_mut(max_num_draft_tokens + 2).enumerate() {
+        let num_draft = num_draft_tokens[seq] as usize;
+        let seq_draft_tokens = &draft_tokens[seq][..num_draft];
+        let mut num_out = 0;
+        for r in 0..=num_draft {
+            let row = first_row + r;
+            let row_probs = &probs[row * vocab_size..][..vocab_size];
+            let draft = seq_draft_tokens.get(r).map(|&token| token as usize);
+            let (token, accepted) = if greedy {
+                let token = argmax(row_probs);
+                (token, draft == Some(token))
+            } else {
+                match draft.filter(|&d| d < vocab_size && uniform[row][0] < row_probs[d]) {
+                    Some(draft) => (draft, true),
+                    None => (sample(row_probs, draft, uniform[row][1]), false),
+                }
+            };
+            seq_out[1 + num_out] = token as u32;
+            num_out += 1;
+            if !accepted {
+                break;
+            }
+        }
+        seq_out[0] = num_out as u32;
+        first_row += num_draft + 1;
+    }
+    Ok((
+        CpuStorage::U32(out),
+        Shape::from((num_seqs, max_num_draft_tokens + 2)),
+    ))
+}
+
+/// Tokens sampled from each row of `logits` as the sampling kernel does it: with the logits
+/// scaled by `inv_temperature`, among the tokens at least as likely as the `top_k`-th one and
+/// then the most likely of them making up `top_p` of the probability mass, all of those tied
+/// with the last one kept included. `top_k` of 0 and `top_p` outside (0, 1) keep every token.
+pub(crate) fn sample_tokens_cpu(
+    logits: &[f32],
+    logits_l: &Layout,
+    uniform: &[f32],
+    inv_temperature: f32,
+    top_k: usize,
+    top_p: f32,
+) -> Result<(CpuStorage, Shape)> {
+    let (num_rows, vocab_size) = logits_l.shape().dims2()?;
+    let logits = match logits_l.contiguous_offsets() {
+        Some((start, end)) => &logits[start..end],
+        None => candle::bail!("logits must be contiguous"),
+    };
+    if uniform.len() != num_rows {
+        candle::bail!("{} uniform draws for {num_rows} rows", uniform.len())
+    }
+
+    let out = logits
+        .chunks(vocab_size)
+        .zip(uniform)
+        .map(|(row, &u)| {
+            let x = row.iter().map(|&l| l * inv_temperature).collect::<Vec<_>>();
+            let max = x.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            let probs = x.iter().map(|&x| (x - max).exp()).collect::<Vec<_>>();
+            let mut order = (0..vocab_size).collect::<Vec<_>>();
+            order.sort_by(|&i, &j| x[j].total_cmp(&x[i]));
+
+            let mut threshold = f32::NEG_INFINITY;
+            if top_k > 0 && top_k < vocab_size {
+                threshold = x[order[top_k - 1]];
+            }
+            if top_p > 0. && top_p < 1. {
+                let target = top_p * probs.iter().sum::<f32>();
+                let mut mass = 0.;
+                for &i in order.iter().take_while(|&&i| x[i] >= threshold) {
+                    mass += probs[i];
+                    if mass >= target {
+                        threshold = x[i];
+                        break;
+                    }
+                }
+            }
+            let kept = x
+                .iter()
+                .zip(&probs)
+                .map(|(&x, &p)| if x >= threshold { p } else { 0. })
+                .collect::<Vec<_>>();
+            sample(&kept, None, u) as u32
+        })
+        .collect::<Vec<_>>();
+    Ok((CpuStorage::U32(out), Shape::from(num_rows)))
+}
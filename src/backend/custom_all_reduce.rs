@@ -0,0 +1,273 @@
+//! A peer-to-peer all-reduce over the GPUs of a node, for the partial outputs of the ranks of a
+//! sharded model. The messages of a decode step are a few rows of the hidden size, whose NCCL
+//! all-reduce costs its latency more than the transfer: here each rank copies its message to a
+//! buffer every other rank maps with CUDA IPC, and one kernel reads and sums them over NVLink or
+//! PCIe. Larger messages, and ranks on several nodes or without peer access, go through NCCL.
+use candle::backend::BackendStorage;
+use candle::cuda_backend::cudarc::driver::{CudaSlice, DevicePtr, DeviceRepr};
+use candle::cuda_backend::{CudaDType, WrapErr};
+use candle::{CpuStorage, CudaStorage, DType, Device, Layout, Result, Shape, Tensor};
+use candle_core as candle;
+use half::{bf16, f16};
+use kernels::ffi;
+use std::ffi::{c_int, c_void};
+
+/// Largest message summed by the custom all-reduce, in bytes, the size of the buffer of a rank.
+pub const CUSTOM_ALL_REDUCE_MAX_SIZE: usize = 8 << 20;
+/// Largest message summed in one shot by more than two ranks.
+const ONE_SHOT_MAX_SIZE: usize = 512 << 10;
+/// Most ranks the kernels address.
+pub const CUSTOM_ALL_REDUCE_MAX_RANKS: usize = 8;
+/// Size of the flags of the barriers of the kernels, see `Signal`.
+const SIGNAL_SIZE: usize = 3 * 36 * CUSTOM_ALL_REDUCE_MAX_RANKS * 4;
+const IPC_HANDLE_LEN: usize = 64;
+/// Length of the handles of a rank: the boot id of its node, then the IPC handles of its buffer
+/// and of its signal.
+pub const CUSTOM_ALL_REDUCE_HANDLES_LEN: usize = 3 * IPC_HANDLE_LEN;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllReduceAlgorithm {
+    /// Every rank reads and sums the whole messages of the others.
+    OneShot,
+    /// Every rank sums a part of the messages, then gathers the parts of the others.
+    TwoShot,
+}
+
+impl AllReduceAlgorithm {
+    /// How the custom all-reduce sums a message of `size` bytes over `world_size` ranks, `None`
+    /// when NCCL does it better. Two ranks read as much in one shot as in two.
+    pub fn select(size: usize, world_size: usize) -> Option<Self> {
+        if !(2..=CUSTOM_ALL_REDUCE_MAX_RANKS).contains(&world_size)
+            || size == 0
+            || size > CUSTOM_ALL_REDUCE_MAX_SIZE
+        {
+            None
+        } else if world_size == 2 || size <= ONE_SHOT_MAX_SIZE {
+            Some(Self::OneShot)
+        } else {
+            Some(Self::TwoShot)
+        }
+    }
+}
+
+fn check_cuda(err: c_int, what: &str) -> Result<()> {
+    if err != 0 {
+        candle::bail!("failed to {what} (CUDA error {err})")
+    }
+    Ok(())
+}
+
+/// Identifies the node, and its boot: ranks on different nodes cannot map each other's memory.
+fn boot_id() -> Result<String> {
+    let id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id")?;
+    Ok(id.trim().to_string())
+}
+
+/// The buffer and signal of a rank of the custom all-reduce, and those of the other ranks once
+/// they are mapped with `open`.
+pub struct CustomAllReduce {
+    rank: usize,
+    world_size: usize,
+    buffer: CudaSlice<u8>,
+    signal: CudaSlice<u8>,
+    buffers: Vec<*mut c_void>,
+    signals: Vec<*mut c_void>,
+    /// Id of the last all-reduce, which the ranks run in the same order.
+    flag: u32,
+}
+
+// SAFETY: the pointers are device addresses of the buffers of the ranks, which live as long as
+// their communicator and may be used from any thread. It is not `Sync`: `all_reduce` takes it
+// mutably, so that the all-reduces of a rank run one at a time, in the order of their flags.
+unsafe impl Send for CustomAllReduce {}
+
+impl CustomAllReduce {
+    /// The buffer and signal of `rank` among `world_size` ranks, on the GPU `device`.
+    pub fn new(device: &Device, rank: usize, world_size: usize) -> Result<Self> {
+        let Device::Cuda(dev) = device else {
+            candle::bail!("the custom all-reduce runs on CUDA devices only")
+        };
+        if !(2..=CUSTOM_ALL_REDUCE_MAX_RANKS).contains(&world_size) || rank >= world_size {
+            candle::bail!(
+                "the custom all-reduce is for 2 to {CUSTOM_ALL_REDUCE_MAX_RANKS} ranks, not \
+                 rank {rank} of {world_size}"
+            )
+        }
+        // The flags start at 0, the first all-reduce is 1.
+        let buffer = dev.alloc_zeros::<u8>(CUSTOM_ALL_REDUCE_MAX_SIZE).w()?;
+        let signal = dev.alloc_zeros::<u8>(SIGNAL_SIZE).w()?;
+        dev.synchronize().w()?;
+        Ok(Self {
+            rank,
+            world_size,
+            buffer,
+            signal,
+            buffers: Vec::new(),
+            signals: Vec::new(),
+            flag: 0,
+        })
+    }
+
+    /// The handles the other ranks map the buffer and signal of this one with, see `open`.
+    pub fn handles(&self) -> Result<Vec<u8>> {
+        let mut handles = vec![0u8; CUSTOM_ALL_REDUCE_HANDLES_LEN];
+        let boot_id = boot_id()?;
+        let boot_id = &boot_id.as_bytes()[..boot_id.len().min(IPC_HANDLE_LEN)];
+        handles[..boot_id.len()].copy_from_slice(boot_id);
+        let ptrs = [*self.buffer.device_ptr(), *self.signal.device_ptr()];
+        for (ptr, handle) in ptrs
+            .into_iter()
+            .zip(handles.chunks_mut(IPC_HANDLE_LEN).skip(1))
+        {
+            let err = unsafe { ffi::ipc_get_mem_handle(ptr as *mut c_void, handle.as_mut_ptr()) };
+            check_cuda(err, "get an IPC handle")?;
+        }
+        Ok(handles)
+    }
+
+    /// Map the buffers and signals of the other ranks, from the `handles` of every rank in
+    /// order. Fails unless they all are on the node of this one, with peer access to its GPU.
+    pub fn open(&mut self, handles: &[u8]) -> Result<()> {
+        if handles.len() != self.world_size * CUSTOM_ALL_REDUCE_HANDLES_LEN {
+            candle::bail!(
+                "{} bytes of handles for {} ranks",
+                handles.len(),
+                self.world_size
+            )
+        }
+        let own = &handles[self.rank * CUSTOM_ALL_REDUCE_HANDLES_LEN..][..IPC_HANDLE_LEN];
+        if own.iter().all(|&b| b == 0) {
+            candle::bail!("the node of rank {} is unknown", self.rank)
+        }
+        for (rank, handle) in handles.chunks(CUSTOM_ALL_REDUCE_HANDLES_LEN).enumerate() {
+            if &handle[..IPC_HANDLE_LEN] != own {
+                candle::bail!("rank {rank} is not on the node of rank {}", self.rank)
+            }
+        }
+
+        self.close();
+        // The buffers, then the signals of the ranks.
+        let mut mapped = [Vec::new(), Vec::new()];
+        let mut result = Ok(());
+        'ranks: for (rank, handle) in handles.chunks(CUSTOM_ALL_REDUCE_HANDLES_LEN).enumerate() {
+            if rank == self.rank {
+                mapped[0].push(*self.buffer.device_ptr() as *mut c_void);
+                mapped[1].push(*self.signal.device_ptr() as *mut c_void);
+                continue;
+            }
+            for (ptrs, handle) in mapped.iter_mut().zip(handle.chunks(IPC_HANDLE_LEN).skip(1)) {
+                let mut ptr = std::ptr::null_mut();
+                let err = unsafe { ffi::ipc_open_mem_handle(handle.as_ptr(), &mut ptr) };
+                ptrs.push(ptr);
+                result = check_cuda(err, &format!("map the memory of rank {rank}"));
+                if result.is_err() {
+                    break 'ranks;
+                }
+            }
+        }
+        let [buffers, signals] = mapped;
+        self.buffers = buffers;
+        self.signals = signals;
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    /// Unmap the memory of the other ranks.
+    fn close(&mut self) {
+        for ptrs in [&mut self.buffers, &mut self.signals] {
+            for (rank, ptr) in ptrs.drain(..).enumerate() {
+                if rank != self.rank && !ptr.is_null() {
+                    unsafe { ffi::ipc_close_mem_handle(ptr) };
+                }
+            }
+        }
+    }
+
+    /// Sum of `x` over the ranks, `None` if NCCL has to sum it: when the other ranks are not
+    /// mapped, for a large message or a dtype the kernels do not sum.
+    pub fn all_reduce(&mut self, x: &Tensor) -> Result<Option<Tensor>> {
+        if self.buffers.len() != self.world_size
+            || !matches!(x.dtype(), DType::F16 | DType::BF16 | DType::F32)
+        {
+            return Ok(None);
+        }
+        let size = x.elem_count() * x.dtype().size_in_bytes();
+        let Some(algorithm) = AllReduceAlgorithm::select(size, self.world_size) else {
+            return Ok(None);
+        };
+        self.flag = self.flag.wrapping_add(1);
+        let op = AllReduceOp {
+            comm: self,
+            flag: self.flag,
+            two_shot: algorithm == AllReduceAlgorithm::TwoShot,
+        };
+        x.contiguous()?.apply_op1_no_bwd(&op).map(Some)
+    }
+}
+
+impl Drop for CustomAllReduce {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+struct AllReduceOp<'a> {
+    comm: &'a CustomAllReduce,
+    flag: u32,
+    two_shot: bool,
+}
+
+impl AllReduceOp<'_> {
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        s: &CudaStorage,
+        l: &Layout,
+        dtype: u32,
+    ) -> Result<(CudaStorage, Shape)> {
+        let elem_count = l.shape().elem_count();
+        let dev = s.device();
+        let x = s.as_cuda_slice::<T>()?.slice(l.start_offset()..);
+        // SAFETY: the all-reduce writes every element.
+        let out = unsafe { dev.alloc::<T>(elem_count) }.w()?;
+        unsafe {
+            ffi::custom_all_reduce(
+                *x.device_ptr() as *const c_void,
+                *out.device_ptr() as *mut c_void,
+                self.comm.buffers.as_ptr(),
+                self.comm.signals.as_ptr(),
+                self.comm.rank as c_int,
+                self.comm.world_size as c_int,
+                elem_count as i64,
+                self.flag,
+                self.two_shot as c_int,
+                dtype,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, l.shape().clone()))
+    }
+}
+
+impl candle::CustomOp1 for AllReduceOp<'_> {
+    fn name(&self) -> &'static str {
+        "custom-all-reduce"
+    }
+
+    fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("the custom all-reduce runs on CUDA devices only")
+    }
+
+    fn cuda_fwd(&self, s: &CudaStorage, l: &Layout) -> Result<(CudaStorage, Shape)> {
+        if !l.is_contiguous() {
+            candle::bail!("custom all-reduce of a non contiguous tensor")
+        }
+        match s.dtype() {
+            DType::F16 => self.cuda_fwd_t::<f16>(s, l, 0),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(s, l, 1),
+            DType::F32 => self.cuda_fwd_t::<f32>(s, l, 2),
+            dtype => candle::bail!("custom all-reduce of {dtype:?} is not supported"),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Memory of the GPU as the driver reports it, to size the KV cache after the weights are
+//! loaded instead of from a fixed budget.
+use candle::cuda_backend::{cudarc::driver::result::mem_get_info, WrapErr};
+use candle::Device;
+use candle_core as candle;
+
+/// Free and total memory of `device` in bytes, `None` for devices whose memory is not
+/// measured, such as the CPU.
+pub fn device_memory(device: &Device) -> candle::Result<Option<(usize, usize)>> {
+    let Device::Cuda(dev) = device else {
+        return Ok(None);
+    };
+    // The driver reports the memory of the device of the current context.
+    dev.bind_to_thread().w()?;
+    Ok(Some(mem_get_info().w()?))
+}
@@ -0,0 +1,422 @@
+use candle::backend::BackendStorage;
+use candle::cuda_backend::cudarc::driver::{DevicePtr, DeviceRepr};
+use candle::cuda_backend::{CudaDType, WrapErr};
+use candle::{CpuStorage, CudaStorage, DType, Layout, Result, Shape, Storage, Tensor};
+use candle_core as candle;
+use half::{bf16, f16};
+use kernels::ffi;
+use std::ffi::{c_int, c_void};
+
+fn kernel_dtype(dtype: DType) -> Result<u32> {
+    match dtype {
+        DType::F16 => Ok(0),
+        DType::BF16 => Ok(1),
+        DType::F32 => Ok(2),
+        dtype => {
+            candle::bail!("fused kernels are only supported for f32, f16 and bf16 ({dtype:?})")
+        }
+    }
+}
+
+/// Rows of `hidden_size` elements of `l`, which the kernels read in order.
+fn rows(name: &str, l: &Layout) -> Result<(usize, usize)> {
+    if !l.is_contiguous() {
+        candle::bail!("{name} expects contiguous tensors ({l:?})")
+    }
+    let hidden_size = *l.dims().last().unwrap_or(&0);
+    if hidden_size == 0 {
+        candle::bail!("{name} expects a non-empty last dimension ({l:?})")
+    }
+    Ok((l.shape().elem_count() / hidden_size, hidden_size))
+}
+
+fn check_weight(name: &str, w_l: &Layout, hidden_size: usize) -> Result<()> {
+    if !w_l.is_contiguous() || w_l.shape().dims1()? != hidden_size {
+        candle::bail!("{name} expects a weight of {hidden_size} elements ({w_l:?})")
+    }
+    Ok(())
+}
+
+fn cuda_slice_ptr<T: CudaDType + DeviceRepr>(s: &CudaStorage, l: &Layout) -> Result<*const c_void> {
+    let s = s.as_cuda_slice::<T>()?.slice(l.start_offset()..);
+    Ok(*s.device_ptr() as *const c_void)
+}
+
+struct RmsNorm {
+    eps: f32,
+}
+
+impl RmsNorm {
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        x: &CudaStorage,
+        x_l: &Layout,
+        w: &CudaStorage,
+        w_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        let (num_tokens, hidden_size) = rows("rms-norm", x_l)?;
+        check_weight("rms-norm", w_l, hidden_size)?;
+        let dev = x.device();
+        let out = unsafe { dev.alloc::<T>(x_l.shape().elem_count()) }.w()?;
+        unsafe {
+            ffi::rms_norm(
+                *out.device_ptr() as *const c_void,
+                cuda_slice_ptr::<T>(x, x_l)?,
+                cuda_slice_ptr::<T>(w, w_l)?,
+                self.eps,
+                num_tokens as c_int,
+                hidden_size as c_int,
+                kernel_dtype(x.dtype())?,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, x_l.shape().clone()))
+    }
+}
+
+impl candle::CustomOp2 for RmsNorm {
+    fn name(&self) -> &'static str {
+        "rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        // `rms_norm` runs the candle ops on the host.
+        candle::bail!("rms-norm has no host kernel")
+    }
+
+    fn cuda_fwd(
+        &self,
+        x: &CudaStorage,
+        x_l: &Layout,
+        w: &CudaStorage,
+        w_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        match x.dtype() {
+            DType::F32 => self.cuda_fwd_t::<f32>(x, x_l, w, w_l),
+            DType::F16 => self.cuda_fwd_t::<f16>(x, x_l, w, w_l),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(x, x_l, w, w_l),
+            dt => candle::bail!("rms-norm is only supported for f32/f16/bf16 ({dt:?})"),
+        }
+    }
+}
+
+struct FusedAddRmsNorm {
+    eps: f32,
+}
+
+impl FusedAddRmsNorm {
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        x: &CudaStorage,
+        x_l: &Layout,
+        r: &CudaStorage,
+        r_l: &Layout,
+        w: &CudaStorage,
+        w_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        let (num_tokens, hidden_size) = rows("fused-add-rms-norm", x_l)?;
+        rows("fused-add-rms-norm", r_l)?;
+        if x_l.shape() != r_l.shape() {
+            candle::bail!(
+                "shape mismatch x {:?} and residual {:?}",
+                x_l.shape(),
+                r_l.shape()
+            )
+        }
+        check_weight("fused-add-rms-norm", w_l, hidden_size)?;
+        let dev = x.device();
+        let mut out_dims = vec![2];
+        out_dims.extend_from_slice(x_l.dims());
+        let out_shape = Shape::from(out_dims);
+        let out = unsafe { dev.alloc::<T>(out_shape.elem_count()) }.w()?;
+        unsafe {
+            ffi::fused_add_rms_norm(
+                *out.device_ptr() as *const c_void,
+                cuda_slice_ptr::<T>(x, x_l)?,
+                cuda_slice_ptr::<T>(r, r_l)?,
+                cuda_slice_ptr::<T>(w, w_l)?,
+                self.eps,
+                num_tokens as c_int,
+                hidden_size as c_int,
+                kernel_dtype(x.dtype())?,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, out_shape))
+    }
+}
+
+impl candle::CustomOp3 for FusedAddRmsNorm {
+    fn name(&self) -> &'static str {
+        "fused-add-rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        // `fused_add_rms_norm` runs the candle ops on the host.
+        candle::bail!("fused-add-rms-norm has no host kernel")
+    }
+
+    fn cuda_fwd(
+        &self,
+        x: &CudaStorage,
+        x_l: &Layout,
+        r: &CudaStorage,
+        r_l: &Layout,
+        w: &CudaStorage,
+        w_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        match x.dtype() {
+            DType::F32 => self.cuda_fwd_t::<f32>(x, x_l, r, r_l, w, w_l),
+            DType::F16 => self.cuda_fwd_t::<f16>(x, x_l, r, r_l, w, w_l),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(x, x_l, r, r_l, w, w_l),
+            dt => candle::bail!("fused-add-rms-norm is only supported for f32/f16/bf16 ({dt:?})"),
+        }
+    }
+}
+
+struct SiluAndMul;
+
+impl SiluAndMul {
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        gate: &CudaStorage,
+        gate_l: &Layout,
+        up: &CudaStorage,
+        up_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        if !gate_l.is_contiguous() || !up_l.is_contiguous() {
+            candle::bail!("silu-and-mul expects contiguous tensors")
+        }
+        if gate_l.shape() != up_l.shape() {
+            candle::bail!(
+                "shape mismatch gate {:?} and up {:?}",
+                gate_l.shape(),
+                up_l.shape()
+            )
+        }
+        let dev = gate.device();
+        let n = gate_l.shape().elem_count();
+        let out = unsafe { dev.alloc::<T>(n) }.w()?;
+        unsafe {
+            ffi::silu_and_mul(
+                *out.device_ptr() as *const c_void,
+                cuda_slice_ptr::<T>(gate, gate_l)?,
+                cuda_slice_ptr::<T>(up, up_l)?,
+                n as i64,
+                kernel_dtype(gate.dtype())?,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, gate_l.shape().clone()))
+    }
+}
+
+impl candle::CustomOp2 for SiluAndMul {
+    fn name(&self) -> &'static str {
+        "silu-and-mul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        // `silu_and_mul` runs the candle ops on the host.
+        candle::bail!("silu-and-mul has no host kernel")
+    }
+
+    fn cuda_fwd(
+        &self,
+        gate: &CudaStorage,
+        gate_l: &Layout,
+        up: &CudaStorage,
+        up_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        match gate.dtype() {
+            DType::F32 => self.cuda_fwd_t::<f32>(gate, gate_l, up, up_l),
+            DType::F16 => self.cuda_fwd_t::<f16>(gate, gate_l, up, up_l),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(gate, gate_l, up, up_l),
+            dt => candle::bail!("silu-and-mul is only supported for f32/f16/bf16 ({dt:?})"),
+        }
+    }
+}
+
+/// RMS normalization of the last dimension of `x`, scaled by `weight`, in one kernel on CUDA.
+/// The host runs `candle_nn::ops::rms_norm`.
+pub fn rms_norm(x: &Tensor, weight: &Tensor, eps: f32) -> Result<Tensor> {
+    let x = x.contiguous()?;
+    if !x.device().is_cuda() {
+        return candle_nn::ops::rms_norm(&x, weight, eps);
+    }
+    x.apply_op2_no_bwd(weight, &RmsNorm { eps })
+}
+
+/// Adds `residual` to `x` and normalizes the sum in one kernel on CUDA, the step between the
+/// attention and the MLP of a decoder layer.
+///
+/// Returns the normalized sum and the sum itself, the residual of what follows.
+pub fn fused_add_rms_norm(
+    x: &Tensor,
+    residual: &Tensor,
+    weight: &Tensor,
+    eps: f32,
+) -> Result<(Tensor, Tensor)> {
+    if !x.device().is_cuda() {
+        let sum = (x + residual)?;
+        return Ok((rms_norm(&sum, weight, eps)?, sum));
+    }
+    let out = x.contiguous()?.apply_op3_no_bwd(
+        &residual.contiguous()?,
+        weight,
+        &FusedAddRmsNorm { eps },
+    )?;
+    Ok((out.get(0)?, out.get(1)?))
+}
+
+/// `silu(gate) * up` in one kernel on CUDA, the activation of gated MLPs.
+pub fn silu_and_mul(gate: &Tensor, up: &Tensor) -> Result<Tensor> {
+    if !gate.device().is_cuda() {
+        return candle_nn::ops::silu(gate)? * up;
+    }
+    gate.contiguous()?
+        .apply_op2_no_bwd(&up.contiguous()?, &SiluAndMul)
+}
+
+fn rotate_in_place<T: CudaDType + DeviceRepr>(
+    x: &Tensor,
+    cos: &Tensor,
+    sin: &Tensor,
+    offsets: &Tensor,
+) -> Result<()> {
+    let (batch, num_heads, seq_len, head_size) = x.dims4()?;
+    let (x, x_l) = x.storage_and_layout();
+    let x = match &*x {
+        Storage::Cuda(x) => x,
+        _ => candle::bail!("rotary-embedding expects cuda tensors"),
+    };
+    let (c, c_l) = cos.storage_and_layout();
+    let c = match &*c {
+        Storage::Cuda(c) => c,
+        _ => candle::bail!("cos must be a cuda tensor"),
+    };
+    let (s, s_l) = sin.storage_and_layout();
+    let s = match &*s {
+        Storage::Cuda(s) => s,
+        _ => candle::bail!("sin must be a cuda tensor"),
+    };
+    let (o, o_l) = offsets.storage_and_layout();
+    let o = match &*o {
+        Storage::Cuda(o) => o,
+        _ => candle::bail!("offsets must be a cuda tensor"),
+    };
+    if !(x_l.is_contiguous() && c_l.is_contiguous() && s_l.is_contiguous()) {
+        candle::bail!("rotary-embedding expects contiguous tensors")
+    }
+    let o = o.as_cuda_slice::<u32>()?.slice(o_l.start_offset()..);
+
+    unsafe {
+        ffi::rotary_embedding(
+            cuda_slice_ptr::<T>(x, x_l)?,
+            cuda_slice_ptr::<T>(c, c_l)?,
+            cuda_slice_ptr::<T>(s, s_l)?,
+            *o.device_ptr() as *const u32,
+            batch as c_int,
+            num_heads as c_int,
+            seq_len as c_int,
+            head_size as c_int,
+            kernel_dtype(x.dtype())?,
+        )
+    }
+    Ok(())
+}
+
+/// Applies the rotary embedding to `q` and `k`, both of shape `(batch, num_heads, seq_len,
+/// head_size)`, the tokens of each batch entry starting at the first of its `input_positions`.
+/// `cos` and `sin` hold a row of `head_size / 2` angles per position.
+///
+/// On CUDA one kernel per tensor rotates them in place, so `q` and `k` must not share storage
+/// with tensors still in use. The host runs `candle_nn::rotary_emb::rope` on each batch entry.
+pub fn rotary_embedding(
+    q: &Tensor,
+    k: &Tensor,
+    cos: &Tensor,
+    sin: &Tensor,
+    input_positions: &[Vec<usize>],
+) -> Result<(Tensor, Tensor)> {
+    let (batch, _, seq_len, head_size) = q.dims4()?;
+    let (max_position, half) = cos.dims2()?;
+    let (k_batch, _, k_seq_len, k_head_size) = k.dims4()?;
+    if (k_batch, k_seq_len, k_head_size) != (batch, seq_len, head_size) {
+        candle::bail!("shape mismatch q {:?} and k {:?}", q.shape(), k.shape())
+    }
+    if half * 2 != head_size || sin.dims2()? != (max_position, half) {
+        candle::bail!(
+            "cos {:?} and sin {:?} do not rotate heads of size {head_size}",
+            cos.shape(),
+            sin.shape()
+        )
+    }
+    if cos.dtype() != q.dtype() || sin.dtype() != q.dtype() || k.dtype() != q.dtype() {
+        candle::bail!("rotary-embedding expects cos, sin, q and k of the same dtype")
+    }
+    if input_positions.len() != batch {
+        candle::bail!(
+            "{} input positions for a batch of {batch}",
+            input_positions.len()
+        )
+    }
+    let offsets = input_positions
+        .iter()
+        .map(|positions| positions.first().copied().unwrap_or(0))
+        .collect::<Vec<_>>();
+    if offsets
+        .iter()
+        .any(|&offset| offset + seq_len > max_position)
+    {
+        candle::bail!("rotary-embedding positions beyond the {max_position} of cos and sin")
+    }
+
+    if !q.device().is_cuda() {
+        let rotate = |x: &Tensor| -> Result<Tensor> {
+            let embeds = offsets
+                .iter()
+                .enumerate()
+                .map(|(b, &offset)| {
+                    let cos = cos.narrow(0, offset, seq_len)?;
+                    let sin = sin.narrow(0, offset, seq_len)?;
+                    candle_nn::rotary_emb::rope(&x.narrow(0, b, 1)?.contiguous()?, &cos, &sin)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::cat(&embeds, 0)
+        };
+        return Ok((rotate(q)?, rotate(k)?));
+    }
+
+    let (q, k) = (q.contiguous()?, k.contiguous()?);
+    let offsets = Tensor::from_iter(offsets.iter().map(|&o| o as u32), q.device())?;
+    for x in [&q, &k] {
+        match x.dtype() {
+            DType::F32 => rotate_in_place::<f32>(x, cos, sin, &offsets)?,
+            DType::F16 => rotate_in_place::<f16>(x, cos, sin, &offsets)?,
+            DType::BF16 => rotate_in_place::<bf16>(x, cos, sin, &offsets)?,
+            dt => candle::bail!("rotary-embedding is only supported for f32/f16/bf16 ({dt:?})"),
+        }
+    }
+    Ok((q, k))
+}
@@ -0,0 +1,112 @@
+//! Memory layout of the paged KV cache, shared by the CUDA kernels and the CPU ops.
+//!
+//! Keys are stored as `(num_blocks, num_heads, head_size / x, block_size, x)`, where the `x`
+//! elements packed last are 16 bytes. A thread of the attention kernel loads them with a single
+//! vectorized access, and the threads reading the same `x` elements of consecutive tokens of a
+//! block access consecutive memory. Values are stored as `(num_blocks, num_heads, head_size,
+//! block_size)`, so that one element of a head for all the tokens of a block is contiguous.
+use candle::{DType, Result, Shape};
+use candle_core as candle;
+
+/// Bytes of the elements of a key the kernels load at once.
+const KEY_PACK_BYTES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvCacheLayout {
+    num_heads: usize,
+    head_size: usize,
+    block_size: usize,
+    /// Elements of a key packed together.
+    x: usize,
+}
+
+impl KvCacheLayout {
+    /// Layout of the cache of `num_heads` heads of `head_size` in blocks of `block_size` tokens,
+    /// for elements of `dtype`.
+    pub fn new(
+        num_heads: usize,
+        head_size: usize,
+        block_size: usize,
+        dtype: DType,
+    ) -> Result<Self> {
+        let x = (KEY_PACK_BYTES / dtype.size_in_bytes()).max(1);
+        if head_size % x != 0 {
+            candle::bail!("head size {head_size} is not a multiple of {x} {dtype:?} elements")
+        }
+        Ok(Self {
+            num_heads,
+            head_size,
+            block_size,
+            x,
+        })
+    }
+
+    /// Layout of a key cache and a value cache of the given shapes, which have to agree.
+    pub fn of_caches(key_cache: &Shape, value_cache: &Shape) -> Result<Self> {
+        let (num_blocks, num_heads, packed_head_size, block_size, x) = key_cache.dims5()?;
+        let layout = Self {
+            num_heads,
+            head_size: packed_head_size * x,
+            block_size,
+            x,
+        };
+        if value_cache.dims4()? != layout.value_cache_shape(num_blocks) {
+            candle::bail!(
+                "value cache of shape {value_cache:?} does not match the key cache of shape \
+                {key_cache:?}, expected {:?}",
+                layout.value_cache_shape(num_blocks)
+            )
+        }
+        Ok(layout)
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.num_heads
+    }
+
+    pub fn head_size(&self) -> usize {
+        self.head_size
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Elements of a key packed together, 16 bytes of them.
+    pub fn x(&self) -> usize {
+        self.x
+    }
+
+    /// Whether `x` is what the kernels pack for elements of `dtype`.
+    pub fn packs(&self, dtype: DType) -> bool {
+        self.x == (KEY_PACK_BYTES / dtype.size_in_bytes()).max(1)
+    }
+
+    pub fn key_cache_shape(&self, num_blocks: usize) -> (usize, usize, usize, usize, usize) {
+        (
+            num_blocks,
+            self.num_heads,
+            self.head_size / self.x,
+            self.block_size,
+            self.x,
+        )
+    }
+
+    pub fn value_cache_shape(&self, num_blocks: usize) -> (usize, usize, usize, usize) {
+        (num_blocks, self.num_heads, self.head_size, self.block_size)
+    }
+
+    /// Position in the key cache of element `d` of `head` for the token at `offset` of `block`.
+    pub fn key_index(&self, block: usize, head: usize, d: usize, offset: usize) -> usize {
+        let packed_head_size = self.head_size / self.x;
+        (((block * self.num_heads + head) * packed_head_size + d / self.x) * self.block_size
+            + offset)
+            * self.x
+            + d % self.x
+    }
+
+    /// Position in the value cache of element `d` of `head` for the token at `offset` of `block`.
+    pub fn value_index(&self, block: usize, head: usize, d: usize, offset: usize) -> usize {
+        ((block * self.num_heads + head) * self.head_size + d) * self.block_size + offset
+    }
+}
@@ -1,5 +1,15 @@
+mod block_view;
 mod cache;
+mod cache_error;
+mod cpu;
+mod custom_all_reduce;
+mod device_memory;
+mod fused;
+mod kv_layout;
+mod numa;
 mod paged_attention;
+mod rejection_sampler;
+mod sampler;
 
 const COPY_BLOCKS_KERNEL_NAME: &str = "copy_blocks_kernel";
 
@@ -58,11 +68,19 @@ unsafe impl<'a, T, R> DeviceRepr for Conjoined<'a, T, R> {
 }
 
 pub use cache::*;
+pub use cache_error::{check_cache_dtype, CacheOpError, CACHE_DTYPES};
 use candle_core::{
     cuda_backend::cudarc::driver::{CudaFunction, DeviceRepr},
     CudaDevice, DType,
 };
+pub use custom_all_reduce::*;
+pub use device_memory::device_memory;
+pub use fused::*;
+pub use kv_layout::KvCacheLayout;
+pub use numa::{bind_thread, device_numa_node, node_cpus, parse_cpu_list, pci_numa_node, MAX_CPUS};
 pub use paged_attention::*;
+pub use rejection_sampler::*;
+pub use sampler::*;
 pub use std::ops::Deref;
 use std::{
     marker::PhantomData,
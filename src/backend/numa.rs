@@ -0,0 +1,117 @@
+//! NUMA topology of the host, to keep the copies between a GPU and host memory on the socket the
+//! GPU hangs off. The node of a GPU is the one sysfs reports for its PCI device, and threads are
+//! bound to the CPUs of a node with `sched_setaffinity`.
+use std::ffi::{c_char, c_int, CStr};
+use std::io;
+
+use candle::cuda_backend::{
+    cudarc::driver::{result::device, sys},
+    WrapErr,
+};
+use candle::{Device, DeviceLocation};
+use candle_core as candle;
+
+/// Highest CPU an affinity mask can hold, plus one.
+#[cfg(target_os = "linux")]
+pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+pub const MAX_CPUS: usize = 1024;
+
+/// NUMA node of `device`, `None` for the CPU and for GPUs whose node is not known, e.g. on hosts
+/// with a single node.
+pub fn device_numa_node(device: &Device) -> candle::Result<Option<usize>> {
+    let DeviceLocation::Cuda { gpu_id } = device.location() else {
+        return Ok(None);
+    };
+    let dev = device::get(gpu_id as c_int).w()?;
+    let mut bus_id = [0 as c_char; 32];
+    // SAFETY: the driver writes a nul-terminated id of at most `bus_id.len()` bytes.
+    let bus_id = unsafe {
+        sys::cuDeviceGetPCIBusId(bus_id.as_mut_ptr(), bus_id.len() as c_int, dev)
+            .result()
+            .w()?;
+        CStr::from_ptr(bus_id.as_ptr())
+    };
+    Ok(pci_numa_node(&bus_id.to_string_lossy()))
+}
+
+/// NUMA node of the PCI device `bus_id` (e.g. `0000:3b:00.0`), as sysfs reports it.
+pub fn pci_numa_node(bus_id: &str) -> Option<usize> {
+    // sysfs names devices with a domain of 4 digits, the driver may report 8.
+    let bus_id = bus_id.to_lowercase();
+    let bus_id = match bus_id.split_once(':') {
+        Some((domain, rest)) if domain.len() > 4 => {
+            format!("{}:{rest}", &domain[domain.len() - 4..])
+        }
+        _ => bus_id,
+    };
+    let node = std::fs::read_to_string(format!("/sys/bus/pci/devices/{bus_id}/numa_node")).ok()?;
+    // -1 when the platform does not tell.
+    node.trim().parse().ok()
+}
+
+/// CPUs of the NUMA node `node`, `None` if there is no such node.
+pub fn node_cpus(node: usize) -> Option<Vec<usize>> {
+    let list =
+        std::fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist")).ok()?;
+    parse_cpu_list(&list)
+}
+
+/// CPUs of a list such as `0-3,8,10-11`, as sysfs and numactl write them. `None` if the list is
+/// malformed or empty.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<usize>().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Run the calling thread on `cpus` only, its new allocations then come from their node. The
+/// CUDA context of `device` is made current on it, for the copies it issues.
+pub fn bind_thread(cpus: &[usize], device: &Device) -> io::Result<()> {
+    set_affinity(cpus)?;
+    if let Device::Cuda(dev) = device {
+        dev.bind_to_thread().map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    if let Some(cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("CPU {cpu} is past the {MAX_CPUS} CPUs of an affinity mask"),
+        ));
+    }
+    // SAFETY: the set is zeroed before the CPUs, all below `CPU_SETSIZE`, are added to it.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "threads can only be bound to CPUs on Linux",
+    ))
+}
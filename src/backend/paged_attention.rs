@@ -1,4 +1,8 @@
 // use candle_core::{cuda_backend::cudarc::driver::CudaFunction, DType, Tensor};
+use super::block_view::BlockView;
+use super::cpu::{paged_attention_cpu, reshape_and_cache_cpu};
+use super::kv_layout::KvCacheLayout;
+use crate::scheduler::cache_engine::SUPPORTED_BLOCK_SIZES;
 use candle::backend::BackendStorage;
 use candle::cuda_backend::cudarc::driver::DevicePtr;
 use candle::cuda_backend::WrapErr;
@@ -86,6 +90,9 @@ impl PagedAttention {
                 (value_cache: {vc_l:?})"
             )
         }
+        // The kernel addresses the caches as contiguous blocks.
+        BlockView::new(kc_l)?;
+        BlockView::new(vc_l)?;
 
         // Get cuda slices for all tensors
         let q = q.as_cuda_slice::<T>()?;
@@ -122,20 +129,16 @@ impl PagedAttention {
             )
         }
 
-        let (num_blocks, num_kv_heads, head_size_kc, block_size, x) = kc_l.shape().dims5()?;
-        if head_size_kc != head_size / x {
+        let layout = KvCacheLayout::of_caches(kc_l.shape(), vc_l.shape())?;
+        let (num_kv_heads, block_size) = (layout.num_heads(), layout.block_size());
+        if !SUPPORTED_BLOCK_SIZES.contains(&block_size) {
             candle::bail!(
-                "shape mismatch value_cache {:?}, expected {:?}",
-                vc_l.shape(),
-                (num_blocks, num_heads, head_size / x, block_size, x)
-            )
+                "`block_size` must be one of {SUPPORTED_BLOCK_SIZES:?}, got {block_size}"
+            );
         }
-
-        if (num_blocks, num_kv_heads, head_size, block_size) != vc_l.shape().dims4()? {
+        if layout.head_size() != head_size || !layout.packs(dtype) {
             candle::bail!(
-                "shape mismatch key_cache {:?} and value_cache {:?}",
-                kc_l.shape(),
-                vc_l.shape()
+                "kv cache {layout:?} does not hold heads of size {head_size} in {dtype:?}"
             )
         }
 
@@ -237,8 +240,20 @@ impl candle::CustomOp1 for PagedAttention {
         "paged-attention"
     }
 
-    fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
-        candle::bail!("no cpu support for paged-attention")
+    fn cpu_fwd(&self, q: &CpuStorage, q_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        let (kc, vc, bt, cl) = (
+            &self.key_cache,
+            &self.value_cache,
+            &self.block_tables,
+            &self.context_lens,
+        );
+        let scale = self.softmax_scale;
+        match q {
+            CpuStorage::F32(q) => paged_attention_cpu(q, q_l, kc, vc, bt, cl, scale),
+            CpuStorage::F16(q) => paged_attention_cpu(q, q_l, kc, vc, bt, cl, scale),
+            CpuStorage::BF16(q) => paged_attention_cpu(q, q_l, kc, vc, bt, cl, scale),
+            _ => candle::bail!("paged-attention is only supported for f32/f16/bf16"),
+        }
     }
 
     fn cuda_fwd(&self, q: &CudaStorage, q_l: &Layout) -> Result<(CudaStorage, Shape)> {
@@ -261,13 +276,17 @@ impl candle::CustomOp1 for PagedAttention {
 ///
 /// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
-/// with `x` being the size of an element in bytes.
+/// with `x` elements being 16 bytes, see `KvCacheLayout`.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`.
 /// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
 /// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
 /// * `max_context_len` - Max of `context_len`
 /// * `softmax_scale` - scaling factor
 ///
+/// The caches may hold another dtype than `q`, which is then attended in the dtype of the
+/// caches, and the result cast back. The kernels take a single dtype, so each call casts the
+/// query of the step, not the cache.
+///
 /// The resulting tensor has dimensions `(num_sequences, num_heads_q, head_size)`.
 pub fn paged_attention(
     q: &Tensor,
@@ -286,7 +305,13 @@ pub fn paged_attention(
         context_lens: context_lens.clone(),
         max_context_len,
     };
-    q.apply_op1(op)
+    let dtype = q.dtype();
+    if dtype == key_cache.dtype() {
+        return q.apply_op1(op);
+    }
+    q.to_dtype(key_cache.dtype())?
+        .apply_op1(op)?
+        .to_dtype(dtype)
 }
 
 fn update_cache<
@@ -359,6 +384,9 @@ fn update_cache<
                 (value_cache: {vc_l:?})"
         )
     }
+    // The kernel addresses the caches as contiguous blocks.
+    BlockView::new(kc_l)?;
+    BlockView::new(vc_l)?;
 
     // Get cuda slices for all tensors
     let k = k.as_cuda_slice::<T>()?;
@@ -379,22 +407,13 @@ fn update_cache<
         candle::bail!("shape mismatch k {:?} and v {:?}", k_l.shape(), v_l.shape())
     }
 
-    let (num_blocks, num_heads_kc, head_size_kc, block_size, x) = kc_l.shape().dims5()?;
-    if num_heads_kc != num_heads || head_size_kc != head_size / x {
+    let layout = KvCacheLayout::of_caches(kc_l.shape(), vc_l.shape())?;
+    if (layout.num_heads(), layout.head_size()) != (num_heads, head_size) || !layout.packs(dtype) {
         candle::bail!(
-            "shape mismatch value_cache {:?}, expected {:?}",
-            vc_l.shape(),
-            (num_blocks, num_heads, head_size / x, block_size, x)
-        )
-    }
-
-    if (num_blocks, num_heads, head_size, block_size) != vc_l.shape().dims4()? {
-        candle::bail!(
-            "shape mismatch key_cache {:?} and value_cache {:?}",
-            kc_l.shape(),
-            vc_l.shape()
+            "kv cache {layout:?} does not hold {num_heads} heads of size {head_size} in {dtype:?}"
         )
     }
+    let (block_size, x) = (layout.block_size(), layout.x());
 
     if (num_tokens) != s_l.shape().dims1()? {
         candle::bail!(
@@ -440,9 +459,12 @@ fn update_cache<
 /// * `key` - Key tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
-/// with `x` being the size of an element in bytes.
+/// with `x` elements being 16 bytes, see `KvCacheLayout`.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+///
+/// Keys and values are cast to the dtype of the caches, the kernels are the ones of that dtype.
+/// Only the tokens of the step are cast.
 pub fn reshape_and_cache(
     key: &Tensor,
     value: &Tensor,
@@ -450,6 +472,13 @@ pub fn reshape_and_cache(
     value_cache: &Tensor,
     slot_mapping: &Tensor,
 ) -> Result<()> {
+    let (key, value) = (
+        &key.to_dtype(key_cache.dtype())?,
+        &value.to_dtype(value_cache.dtype())?,
+    );
+    if key.device().is_cpu() {
+        return reshape_and_cache_cpu(key, value, key_cache, value_cache, slot_mapping);
+    }
     match key.dtype() {
         DType::F16 => update_cache::<f16>(key, value, key_cache, value_cache, slot_mapping),
         DType::BF16 => update_cache::<bf16>(key, value, key_cache, value_cache, slot_mapping),
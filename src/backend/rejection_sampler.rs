@@ -0,0 +1,174 @@
+use super::cpu::rejection_sample_cpu;
+use candle::backend::BackendStorage;
+use candle::cuda_backend::cudarc::driver::DevicePtr;
+use candle::cuda_backend::WrapErr;
+use candle::{CpuStorage, CudaStorage, DType, Layout, Result, Shape, Storage, Tensor};
+use candle_core as candle;
+use kernels::ffi::rejection_sample as rejection_sample_kernel;
+use std::ffi::c_int;
+
+struct RejectionSample {
+    draft_tokens: Tensor,
+    num_draft_tokens: Tensor,
+    uniform: Tensor,
+    greedy: bool,
+}
+
+impl candle::CustomOp1 for RejectionSample {
+    fn name(&self) -> &'static str {
+        "rejection-sample"
+    }
+
+    fn cpu_fwd(&self, probs: &CpuStorage, probs_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        match probs {
+            CpuStorage::F32(probs) => rejection_sample_cpu(
+                probs,
+                probs_l,
+                &self.draft_tokens,
+                &self.num_draft_tokens,
+                &self.uniform,
+                self.greedy,
+            ),
+            _ => candle::bail!("rejection-sample is only supported for f32 probs"),
+        }
+    }
+
+    fn cuda_fwd(&self, probs: &CudaStorage, probs_l: &Layout) -> Result<(CudaStorage, Shape)> {
+        if probs.dtype() != DType::F32 {
+            candle::bail!("rejection-sample is only supported for f32 probs")
+        }
+        let dev = probs.device();
+
+        let (dt, dt_l) = self.draft_tokens.storage_and_layout();
+        let dt = match &*dt {
+            Storage::Cuda(dt) => dt,
+            _ => candle::bail!("draft_tokens must be a cuda tensor"),
+        };
+        let (nd, nd_l) = self.num_draft_tokens.storage_and_layout();
+        let nd = match &*nd {
+            Storage::Cuda(nd) => nd,
+            _ => candle::bail!("num_draft_tokens must be a cuda tensor"),
+        };
+        let (u, u_l) = self.uniform.storage_and_layout();
+        let u = match &*u {
+            Storage::Cuda(u) => u,
+            _ => candle::bail!("uniform must be a cuda tensor"),
+        };
+        if !(probs_l.is_contiguous()
+            && dt_l.is_contiguous()
+            && nd_l.is_contiguous()
+            && u_l.is_contiguous())
+        {
+            candle::bail!("rejection-sample expects contiguous tensors")
+        }
+
+        let probs = probs.as_cuda_slice::<f32>()?;
+        let dt = dt.as_cuda_slice::<u32>()?;
+        let nd = nd.as_cuda_slice::<u32>()?;
+        let u = u.as_cuda_slice::<f32>()?;
+
+        let probs = probs.slice(probs_l.start_offset()..);
+        let dt = dt.slice(dt_l.start_offset()..);
+        let nd = nd.slice(nd_l.start_offset()..);
+        let u = u.slice(u_l.start_offset()..);
+
+        let (_, vocab_size) = probs_l.shape().dims2()?;
+        let (num_seqs, max_num_draft_tokens) = dt_l.shape().dims2()?;
+        let out_shape = Shape::from((num_seqs, max_num_draft_tokens + 2));
+        let out = dev.alloc_zeros::<u32>(out_shape.elem_count()).w()?;
+
+        unsafe {
+            rejection_sample_kernel(
+                *probs.device_ptr() as *const f32,
+                *dt.device_ptr() as *const u32,
+                *nd.device_ptr() as *const u32,
+                *u.device_ptr() as *const f32,
+                *out.device_ptr() as *mut u32,
+                num_seqs as c_int,
+                vocab_size as c_int,
+                max_num_draft_tokens as c_int,
+                self.greedy as c_int,
+            )
+        }
+
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, out_shape))
+    }
+}
+
+/// Verify the draft tokens of a batch of sequences in one kernel launch, by rejection sampling.
+///
+/// Draft tokens are proposed with certainty, as prompt lookup does, so each is accepted with its
+/// probability under the model. The first rejected one is replaced by a token sampled from the
+/// probabilities without it, and once all of them are accepted a bonus token is sampled from the
+/// last row. Greedy verification accepts the draft tokens that are the argmax of their row, and
+/// takes the argmax as the replacement or bonus token.
+///
+/// # Arguments
+///
+/// * `probs` - f32 tensor of shape `(num_rows, vocab_size)`, the probabilities of the token
+/// following the last token of each sequence and then each of its draft tokens, in order. Logits
+/// are enough for greedy verification.
+/// * `draft_tokens` - The draft tokens of each sequence.
+/// * `uniform` - Two draws in [0, 1) for each row, to accept its draft token and to sample a token.
+/// * `greedy` - Whether to verify greedily.
+///
+/// Only the tokens of each sequence come back to the host: its accepted draft tokens followed by
+/// the replacement or bonus token.
+pub fn rejection_sample(
+    probs: &Tensor,
+    draft_tokens: &[Vec<u32>],
+    uniform: &[f32],
+    greedy: bool,
+) -> Result<Vec<Vec<u32>>> {
+    let (num_rows, vocab_size) = probs.dims2()?;
+    let num_seqs = draft_tokens.len();
+    if num_seqs == 0 {
+        candle::bail!("rejection-sample needs at least one sequence")
+    }
+    if draft_tokens
+        .iter()
+        .map(|tokens| tokens.len() + 1)
+        .sum::<usize>()
+        != num_rows
+    {
+        candle::bail!("probs has {num_rows} rows for {num_seqs} sequences and their draft tokens")
+    }
+    if uniform.len() != 2 * num_rows {
+        candle::bail!(
+            "{} uniform draws for {num_rows} rows, expected two per row",
+            uniform.len()
+        )
+    }
+    let max_num_draft_tokens = draft_tokens.iter().map(Vec::len).max().unwrap_or(0).max(1);
+    let padded = draft_tokens
+        .iter()
+        .flat_map(|tokens| {
+            let padding = max_num_draft_tokens - tokens.len();
+            tokens
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(0).take(padding))
+        })
+        .collect::<Vec<_>>();
+    let device = probs.device();
+    let op = RejectionSample {
+        draft_tokens: Tensor::from_vec(padded, (num_seqs, max_num_draft_tokens), device)?,
+        num_draft_tokens: Tensor::from_iter(
+            draft_tokens.iter().map(|tokens| tokens.len() as u32),
+            device,
+        )?,
+        uniform: Tensor::from_slice(uniform, (num_rows, 2), device)?,
+        greedy,
+    };
+    let out = probs.contiguous()?.apply_op1(op)?.to_vec2::<u32>()?;
+    out.into_iter()
+        .map(|row| {
+            let tokens = row[1..][..row[0] as usize].to_vec();
+            if tokens.iter().any(|&token| token as usize >= vocab_size) {
+                candle::bail!("rejection-sample found no token with a probability")
+            }
+            Ok(tokens)
+        })
+        .collect()
+}
@@ -0,0 +1,129 @@
+use super::cpu::sample_tokens_cpu;
+use candle::backend::BackendStorage;
+use candle::cuda_backend::cudarc::driver::DevicePtr;
+use candle::cuda_backend::WrapErr;
+use candle::{CpuStorage, CudaStorage, DType, Layout, Result, Shape, Storage, Tensor};
+use candle_core as candle;
+use kernels::ffi::sample_tokens as sample_tokens_kernel;
+use std::ffi::c_int;
+
+struct SampleTokens {
+    uniform: Tensor,
+    inv_temperature: f32,
+    top_k: usize,
+    top_p: f32,
+}
+
+impl candle::CustomOp1 for SampleTokens {
+    fn name(&self) -> &'static str {
+        "sample-tokens"
+    }
+
+    fn cpu_fwd(&self, logits: &CpuStorage, logits_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        match logits {
+            CpuStorage::F32(logits) => sample_tokens_cpu(
+                logits,
+                logits_l,
+                &self.uniform.to_vec1::<f32>()?,
+                self.inv_temperature,
+                self.top_k,
+                self.top_p,
+            ),
+            _ => candle::bail!("sample-tokens is only supported for f32 logits"),
+        }
+    }
+
+    fn cuda_fwd(&self, logits: &CudaStorage, logits_l: &Layout) -> Result<(CudaStorage, Shape)> {
+        if logits.dtype() != DType::F32 {
+            candle::bail!("sample-tokens is only supported for f32 logits")
+        }
+        let dev = logits.device();
+
+        let (u, u_l) = self.uniform.storage_and_layout();
+        let u = match &*u {
+            Storage::Cuda(u) => u,
+            _ => candle::bail!("uniform must be a cuda tensor"),
+        };
+        if !(logits_l.is_contiguous() && u_l.is_contiguous()) {
+            candle::bail!("sample-tokens expects contiguous tensors")
+        }
+
+        let logits = logits.as_cuda_slice::<f32>()?;
+        let u = u.as_cuda_slice::<f32>()?;
+        let logits = logits.slice(logits_l.start_offset()..);
+        let u = u.slice(u_l.start_offset()..);
+
+        let (num_rows, vocab_size) = logits_l.shape().dims2()?;
+        let out = dev.alloc_zeros::<u32>(num_rows).w()?;
+
+        unsafe {
+            sample_tokens_kernel(
+                *logits.device_ptr() as *const f32,
+                *u.device_ptr() as *const f32,
+                *out.device_ptr() as *mut u32,
+                num_rows as c_int,
+                vocab_size as c_int,
+                self.inv_temperature,
+                self.top_k as c_int,
+                self.top_p,
+            )
+        }
+
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, Shape::from(num_rows)))
+    }
+}
+
+/// Sample a token from each row of a batch of logits in one kernel launch.
+///
+/// The temperature scaling, top-k and top-p filtering and the softmax all run on the device over
+/// the whole vocabulary, which at large batch sizes costs more than any other part of a decode
+/// step but attention. Top-k keeps the tokens at least as likely as the `top_k`-th one and top-p
+/// the most likely of them making up `top_p` of the probability mass, as the host sampler does,
+/// except that tokens tied with the last one kept are all kept.
+///
+/// # Arguments
+///
+/// * `logits` - Tensor of shape `(num_rows, vocab_size)`.
+/// * `temperature` - The temperature, above 0: greedy rows take the argmax instead.
+/// * `top_k` - The number of most likely tokens to sample from, if set.
+/// * `top_p` - The probability mass of the most likely tokens to sample from, if set.
+/// * `uniform` - A draw in [0, 1) for each row.
+///
+/// Only the tokens come back to the host.
+pub fn sample_tokens(
+    logits: &Tensor,
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    uniform: &[f32],
+) -> Result<Vec<u32>> {
+    let (num_rows, vocab_size) = logits.dims2()?;
+    if temperature <= 0. {
+        candle::bail!("sample-tokens needs a temperature above 0, not {temperature}")
+    }
+    if uniform.len() != num_rows {
+        candle::bail!(
+            "{} uniform draws for {num_rows} rows, expected one per row",
+            uniform.len()
+        )
+    }
+    if num_rows == 0 {
+        return Ok(Vec::new());
+    }
+    let op = SampleTokens {
+        uniform: Tensor::from_slice(uniform, num_rows, logits.device())?,
+        inv_temperature: 1. / temperature,
+        top_k: top_k.unwrap_or(0).min(vocab_size),
+        top_p: top_p.unwrap_or(1.),
+    };
+    let tokens = logits
+        .to_dtype(DType::F32)?
+        .contiguous()?
+        .apply_op1(op)?
+        .to_vec1::<u32>()?;
+    if tokens.iter().any(|&token| token as usize >= vocab_size) {
+        candle::bail!("sample-tokens found no token with a probability")
+    }
+    Ok(tokens)
+}
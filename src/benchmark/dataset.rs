@@ -0,0 +1,102 @@
+use crate::{openai::responses::APIError, try_api};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{path::Path, time::Duration};
+use tokenizers::{Encoding, Tokenizer, TruncationDirection};
+
+#[derive(Deserialize)]
+struct ShareGPTTurn {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct ShareGPTConversation {
+    conversations: Vec<ShareGPTTurn>,
+}
+
+/// A benchmark request: the tokenized prompt and how many tokens to generate for it.
+#[derive(Clone, Debug)]
+pub struct BenchmarkRequest {
+    pub prompt: Encoding,
+    pub output_len: usize,
+}
+
+/// Extract (prompt, completion) pairs from a ShareGPT json dump, i.e. the first two turns of
+/// every conversation that has them.
+pub fn parse_sharegpt(data: &str) -> Result<Vec<(String, String)>, APIError> {
+    let conversations: Vec<ShareGPTConversation> = try_api!(serde_json::from_str(data));
+    Ok(conversations
+        .into_iter()
+        .filter(|conversation| conversation.conversations.len() >= 2)
+        .map(|conversation| {
+            let mut turns = conversation.conversations.into_iter();
+            let prompt = turns.next().unwrap().value;
+            let completion = turns.next().unwrap().value;
+            (prompt, completion)
+        })
+        .collect())
+}
+
+/// Sample `num_prompts` requests from a ShareGPT dataset. The output length of each request is
+/// the token count of the recorded completion.
+pub fn load_sharegpt_dataset(
+    path: &Path,
+    tokenizer: &Tokenizer,
+    num_prompts: usize,
+    max_model_len: usize,
+    seed: u64,
+) -> Result<Vec<BenchmarkRequest>, APIError> {
+    let mut pairs = parse_sharegpt(&try_api!(std::fs::read_to_string(path)))?;
+    pairs.shuffle(&mut StdRng::seed_from_u64(seed));
+
+    let mut requests = Vec::new();
+    for (prompt, completion) in pairs {
+        if requests.len() == num_prompts {
+            break;
+        }
+        let prompt = try_api!(tokenizer.encode(prompt, false));
+        let output_len = try_api!(tokenizer.encode(completion, false)).len();
+        // Same pruning as vLLM: skip very short sequences and those that do not fit the model.
+        if prompt.len() < 4 || output_len < 4 || prompt.len() + output_len > max_model_len {
+            continue;
+        }
+        requests.push(BenchmarkRequest { prompt, output_len });
+    }
+    if requests.len() < num_prompts {
+        println!(
+            "Only {} of the requested {} prompts fit the model, benchmarking with those.",
+            requests.len(),
+            num_prompts
+        );
+    }
+    Ok(requests)
+}
+
+/// `num_prompts` identical requests with a prompt of `prompt_len` tokens.
+pub fn synthetic_requests(
+    tokenizer: &Tokenizer,
+    num_prompts: usize,
+    prompt_len: usize,
+    output_len: usize,
+) -> Result<Vec<BenchmarkRequest>, APIError> {
+    let mut prompt = try_api!(tokenizer.encode("hello ".repeat(prompt_len), false));
+    prompt.truncate(prompt_len, 0, TruncationDirection::Right);
+    Ok(vec![BenchmarkRequest { prompt, output_len }; num_prompts])
+}
+
+/// Arrival offsets of `num_requests` requests following a Poisson process of `request_rate`
+/// requests per second. With an infinite rate every request arrives at once.
+pub fn poisson_arrivals(num_requests: usize, request_rate: f64, seed: u64) -> Vec<Duration> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut elapsed = 0f64;
+    (0..num_requests)
+        .map(|_| {
+            let arrival = Duration::from_secs_f64(elapsed);
+            if request_rate.is_finite() {
+                // Exponentially distributed inter-arrival time.
+                elapsed += -(1.0 - rng.gen::<f64>()).ln() / request_rate;
+            }
+            arrival
+        })
+        .collect()
+}
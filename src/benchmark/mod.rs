@@ -0,0 +1,285 @@
+//! Offline benchmark modelled on vLLM's `benchmark_throughput`/`benchmark_serving`: replay a set of
+//! requests against the engine at a given request rate and report throughput, time to first token
+//! (TTFT), time per output token (TPOT) and KV cache usage.
+use crate::openai::{
+    pipelines::llm_engine::LLMEngine,
+    responses::APIError,
+    sampling_params::{EarlyStoppingCondition, SamplingParams},
+    streaming::ChatResponse,
+    PipelineConfig,
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::Mutex;
+
+pub mod dataset;
+
+pub use dataset::{
+    load_sharegpt_dataset, parse_sharegpt, poisson_arrivals, synthetic_requests, BenchmarkRequest,
+};
+
+const KV_USAGE_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Mean and percentiles of a metric.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Percentiles {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl Percentiles {
+    /// Percentiles are interpolated linearly between the closest ranks, as numpy does.
+    pub fn new(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let at = |q: f64| {
+            let rank = q * (sorted.len() - 1) as f64;
+            let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+            sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+        };
+        Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+        }
+    }
+}
+
+/// Timings of a single request, measured from the moment it arrived.
+#[derive(Clone, Debug)]
+pub struct RequestMetrics {
+    pub prompt_len: usize,
+    pub output_len: usize,
+    pub ttft: Duration,
+    pub latency: Duration,
+}
+
+impl RequestMetrics {
+    /// Average decoding time of the tokens after the first one.
+    pub fn tpot(&self) -> Option<Duration> {
+        if self.output_len > 1 {
+            Some((self.latency - self.ttft) / (self.output_len - 1) as u32)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchmarkReport {
+    pub num_requests: usize,
+    pub duration: Duration,
+    pub prompt_tokens: usize,
+    pub output_tokens: usize,
+    pub ttft_ms: Percentiles,
+    pub tpot_ms: Percentiles,
+    pub latency_ms: Percentiles,
+    /// Fraction of GPU KV cache blocks in use, sampled while the benchmark runs.
+    pub mean_kv_usage: f64,
+    pub peak_kv_usage: f64,
+}
+
+impl BenchmarkReport {
+    pub fn new(metrics: &[RequestMetrics], duration: Duration, kv_usage: &[f64]) -> Self {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        Self {
+            num_requests: metrics.len(),
+            duration,
+            prompt_tokens: metrics.iter().map(|m| m.prompt_len).sum(),
+            output_tokens: metrics.iter().map(|m| m.output_len).sum(),
+            ttft_ms: Percentiles::new(&metrics.iter().map(|m| ms(m.ttft)).collect::<Vec<_>>()),
+            tpot_ms: Percentiles::new(
+                &metrics
+                    .iter()
+                    .filter_map(|m| m.tpot().map(ms))
+                    .collect::<Vec<_>>(),
+            ),
+            latency_ms: Percentiles::new(
+                &metrics.iter().map(|m| ms(m.latency)).collect::<Vec<_>>(),
+            ),
+            mean_kv_usage: if kv_usage.is_empty() {
+                0.0
+            } else {
+                kv_usage.iter().sum::<f64>() / kv_usage.len() as f64
+            },
+            peak_kv_usage: kv_usage.iter().cloned().fold(0.0, f64::max),
+        }
+    }
+
+    pub fn request_throughput(&self) -> f64 {
+        self.num_requests as f64 / self.duration.as_secs_f64()
+    }
+
+    pub fn output_throughput(&self) -> f64 {
+        self.output_tokens as f64 / self.duration.as_secs_f64()
+    }
+
+    pub fn token_throughput(&self) -> f64 {
+        (self.prompt_tokens + self.output_tokens) as f64 / self.duration.as_secs_f64()
+    }
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Successful requests:          {}", self.num_requests)?;
+        writeln!(
+            f,
+            "Benchmark duration (s):       {:.2}",
+            self.duration.as_secs_f64()
+        )?;
+        writeln!(f, "Total input tokens:           {}", self.prompt_tokens)?;
+        writeln!(f, "Total generated tokens:       {}", self.output_tokens)?;
+        writeln!(
+            f,
+            "Request throughput (req/s):   {:.2}",
+            self.request_throughput()
+        )?;
+        writeln!(
+            f,
+            "Output throughput (tok/s):    {:.2}",
+            self.output_throughput()
+        )?;
+        writeln!(
+            f,
+            "Total throughput (tok/s):     {:.2}",
+            self.token_throughput()
+        )?;
+        for (name, p) in [
+            ("TTFT", &self.ttft_ms),
+            ("TPOT", &self.tpot_ms),
+            ("E2E latency", &self.latency_ms),
+        ] {
+            writeln!(
+                f,
+                "{name} (ms): mean {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}",
+                p.mean, p.p50, p.p90, p.p99
+            )?;
+        }
+        write!(
+            f,
+            "KV cache usage: mean {:.1}%, peak {:.1}%",
+            self.mean_kv_usage * 100.0,
+            self.peak_kv_usage * 100.0
+        )
+    }
+}
+
+/// Submit `requests` to the engine following Poisson arrivals at `request_rate` requests per
+/// second (`f64::INFINITY` submits them all at once) and wait for all of them to finish.
+/// Generation ignores EOS so that every request produces exactly its `output_len` tokens.
+pub async fn run_benchmark(
+    engine: Arc<Mutex<LLMEngine>>,
+    pipeline_config: &PipelineConfig,
+    requests: Vec<BenchmarkRequest>,
+    request_rate: f64,
+    seed: u64,
+) -> Result<BenchmarkReport, APIError> {
+    let scheduler_trace = engine.lock().await.scheduler_trace.clone();
+    let arrivals = poisson_arrivals(requests.len(), request_rate, seed);
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let kv_sampler = {
+        let finished = finished.clone();
+        tokio::spawn(async move {
+            let mut kv_usage = Vec::new();
+            while !finished.load(Ordering::Relaxed) {
+                {
+                    let snapshot = scheduler_trace.read().unwrap_or_else(|e| e.into_inner());
+                    if snapshot.num_gpu_blocks > 0 {
+                        kv_usage.push(
+                            1.0 - snapshot.num_free_gpu_blocks as f64
+                                / snapshot.num_gpu_blocks as f64,
+                        );
+                    }
+                }
+                tokio::time::sleep(KV_USAGE_SAMPLE_INTERVAL).await;
+            }
+            kv_usage
+        })
+    };
+
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for (i, (request, arrival)) in requests.into_iter().zip(arrivals).enumerate() {
+        let sampling_params = SamplingParams::new(
+            1,
+            None,
+            0.0,
+            0.0,
+            pipeline_config.penalty,
+            pipeline_config.temperature,
+            1.0,
+            -1,
+            false,
+            1.0,
+            EarlyStoppingCondition::UnlikelyBetterCandidates,
+            None,
+            vec![],
+            true,
+            request.output_len,
+            None,
+            None,
+            true,
+        )?;
+        let engine = engine.clone();
+        // Each request waits for the engine on its own, so that a busy engine shows up as
+        // queueing delay in its TTFT instead of delaying the arrival of the next requests.
+        handles.push(tokio::spawn(async move {
+            tokio::time::sleep_until((start + arrival).into()).await;
+            let arrived = Instant::now();
+            let prompt_len = request.prompt.len();
+            let (response_tx, rx) = flume::unbounded();
+            {
+                let mut engine = engine.lock().await;
+                engine.add_request(
+                    request.prompt,
+                    format!("benchmark-{i}"),
+                    SystemTime::now(),
+                    sampling_params,
+                    false,
+                    Some(response_tx),
+                    None,
+                );
+                engine.notify.notify_one();
+            }
+
+            let mut first_token = None;
+            let mut output_len = 0;
+            while let Ok(ChatResponse::Chunk(chunk)) = rx.recv_async().await {
+                if chunk.choices.iter().any(|c| c.delta.content.is_some()) {
+                    first_token.get_or_insert_with(Instant::now);
+                    output_len += 1;
+                }
+            }
+            let latency = arrived.elapsed();
+            RequestMetrics {
+                prompt_len,
+                output_len,
+                ttft: first_token.map_or(latency, |t| t - arrived),
+                latency,
+            }
+        }));
+    }
+
+    let mut metrics = Vec::new();
+    for handle in handles {
+        metrics.push(handle.await.map_err(APIError::from)?);
+    }
+    let duration = start.elapsed();
+    finished.store(true, Ordering::Relaxed);
+    let kv_usage = kv_sampler.await.map_err(APIError::from)?;
+
+    Ok(BenchmarkReport::new(&metrics, duration, &kv_usage))
+}
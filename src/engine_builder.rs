@@ -0,0 +1,428 @@
+//! Start an engine from a model id alone, for library users who want to generate text without
+//! assembling the pipeline, the scheduler config and the cache config the way the server does:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), candle_vllm::openai::responses::APIError> {
+//! use candle_core::DType;
+//! use candle_vllm::engine_builder::EngineBuilder;
+//!
+//! let (engine, pipeline_config) = EngineBuilder::new("meta-llama/Meta-Llama-3.1-8B-Instruct")
+//!     .dtype(DType::BF16)
+//!     .max_model_len(8192)
+//!     .gpu_memory_utilization(0.9)
+//!     .build()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
+
+use candle_core::{DType, Device};
+use tokio::sync::{Mutex, Notify};
+
+use crate::{
+    backend::device_memory,
+    detect_model, get_cache_config, get_dtype, get_model_loader, get_model_paths,
+    openai::{
+        fingerprint::weights_digest,
+        pipelines::{
+            integrity::{verify_weights, WeightManifest},
+            llm_engine::LLMEngine,
+            weights::DEFAULT_WEIGHT_BUFFER_MEM,
+            worker::WorkerConfig,
+        },
+        responses::APIError,
+        sampling_params::{EarlyStoppingCondition, SamplingParams},
+        PipelineConfig,
+    },
+    scheduler::{cache_engine::SwapAffinity, SchedulerConfig},
+    try_api, ModelSelected, SIZE_IN_MB,
+};
+
+/// Share of the GPU memory the engine takes by default, weights and KV cache.
+pub const DEFAULT_GPU_MEMORY_UTILIZATION: f64 = 0.9;
+/// GPU memory left out of the KV cache for the activations of a step (MB).
+pub const DEFAULT_ACTIVATION_MEM: usize = 2048;
+/// KV cache of devices whose memory is not measured, such as the CPU, and swap space (MB).
+pub const DEFAULT_KVCACHE_MEM: usize = 4096;
+/// Prompt of the request run through the engine before it is handed out.
+const WARMUP_PROMPT: &str = "Hello, world!";
+
+/// Builder of an `LLMEngine` and the `PipelineConfig` of its model, with the defaults of the
+/// server. The model is detected from the `config.json` of `model_id` unless given with
+/// `model`.
+#[derive(Debug)]
+pub struct EngineBuilder {
+    model_id: String,
+    model: Option<ModelSelected>,
+    weight_path: Option<String>,
+    hf_token: Option<String>,
+    hf_token_path: Option<String>,
+    dtype: Option<DType>,
+    kv_cache_dtype: Option<DType>,
+    cpu: bool,
+    max_model_len: Option<usize>,
+    gpu_memory_utilization: f64,
+    activation_mem: usize,
+    kvcache_mem_gpu: Option<usize>,
+    kvcache_mem_cpu: usize,
+    swap_affinity: Option<SwapAffinity>,
+    prefill_chunk_size: Option<usize>,
+    control_vectors: Vec<(String, PathBuf)>,
+    block_size: usize,
+    max_num_seqs: usize,
+    weight_buffer_mem: usize,
+    warmup: bool,
+    verify_weights: bool,
+    weights_manifest: Option<PathBuf>,
+}
+
+impl EngineBuilder {
+    pub fn new(model_id: impl Into<String>) -> Self {
+        Self {
+            model_id: model_id.into(),
+            model: None,
+            weight_path: None,
+            hf_token: None,
+            hf_token_path: None,
+            dtype: None,
+            kv_cache_dtype: None,
+            cpu: false,
+            max_model_len: None,
+            gpu_memory_utilization: DEFAULT_GPU_MEMORY_UTILIZATION,
+            activation_mem: DEFAULT_ACTIVATION_MEM,
+            kvcache_mem_gpu: None,
+            kvcache_mem_cpu: DEFAULT_KVCACHE_MEM,
+            swap_affinity: None,
+            prefill_chunk_size: None,
+            control_vectors: Vec::new(),
+            block_size: 32,
+            max_num_seqs: 256,
+            weight_buffer_mem: DEFAULT_WEIGHT_BUFFER_MEM,
+            warmup: true,
+            verify_weights: false,
+            weights_manifest: None,
+        }
+    }
+
+    /// Load the model as `model` instead of detecting it, e.g. with its sampling defaults.
+    pub fn model(mut self, model: ModelSelected) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Load the weights from this folder instead of the hub.
+    pub fn weight_path(mut self, weight_path: impl Into<String>) -> Self {
+        self.weight_path = Some(weight_path.into());
+        self
+    }
+
+    pub fn hf_token(mut self, hf_token: impl Into<String>) -> Self {
+        self.hf_token = Some(hf_token.into());
+        self
+    }
+
+    pub fn hf_token_path(mut self, hf_token_path: impl Into<String>) -> Self {
+        self.hf_token_path = Some(hf_token_path.into());
+        self
+    }
+
+    /// Dtype the model is served in, bf16 by default.
+    pub fn dtype(mut self, dtype: DType) -> Self {
+        self.dtype = Some(dtype);
+        self
+    }
+
+    /// Dtype of the KV cache, the one the model is served in by default.
+    pub fn kv_cache_dtype(mut self, kv_cache_dtype: DType) -> Self {
+        self.kv_cache_dtype = Some(kv_cache_dtype);
+        self
+    }
+
+    pub fn cpu(mut self, cpu: bool) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Tokens (prompt and generated) of a request, at most the max length of the model which
+    /// is the default.
+    pub fn max_model_len(mut self, max_model_len: usize) -> Self {
+        self.max_model_len = Some(max_model_len);
+        self
+    }
+
+    /// Share of the memory of the GPU the weights, the activations and the KV cache take, the
+    /// KV cache gets what the other two leave. Ignored with `kvcache_mem`.
+    pub fn gpu_memory_utilization(mut self, gpu_memory_utilization: f64) -> Self {
+        self.gpu_memory_utilization = gpu_memory_utilization;
+        self
+    }
+
+    /// GPU memory left out of the KV cache for the activations of a step (MB).
+    pub fn activation_mem(mut self, activation_mem: usize) -> Self {
+        self.activation_mem = activation_mem;
+        self
+    }
+
+    /// Size the GPU KV cache at `kvcache_mem` MB instead of from the free GPU memory.
+    pub fn kvcache_mem(mut self, kvcache_mem: usize) -> Self {
+        self.kvcache_mem_gpu = Some(kvcache_mem);
+        self
+    }
+
+    /// CPU KV cache preempted sequences are swapped out to (MB).
+    pub fn swap_space_mem(mut self, swap_space_mem: usize) -> Self {
+        self.kvcache_mem_cpu = swap_space_mem;
+        self
+    }
+
+    /// Run the swaps to the CPU KV cache on these CPUs, e.g. the ones local to the GPU.
+    pub fn swap_affinity(mut self, swap_affinity: SwapAffinity) -> Self {
+        self.swap_affinity = Some(swap_affinity);
+        self
+    }
+
+    /// Attend prompts `prefill_chunk_size` tokens at a time during prefill, for long prompts.
+    pub fn prefill_chunk_size(mut self, prefill_chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(prefill_chunk_size);
+        self
+    }
+
+    /// Let requests steer the model with the control vector of the GGUF or safetensors file
+    /// `path`, by `name`.
+    pub fn control_vector(mut self, name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.control_vectors.push((name.into(), path.into()));
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn max_num_seqs(mut self, max_num_seqs: usize) -> Self {
+        self.max_num_seqs = max_num_seqs;
+        self
+    }
+
+    /// Host memory the weights are streamed to the GPU through while they load (MB).
+    pub fn weight_buffer_mem(mut self, weight_buffer_mem: usize) -> Self {
+        self.weight_buffer_mem = weight_buffer_mem;
+        self
+    }
+
+    /// Run a request through the engine before returning it, so that the first real request
+    /// does not pay for loading the kernels. On by default.
+    pub fn warmup(mut self, warmup: bool) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Check the size and the SHA-256 of the weights against the hub before loading them.
+    pub fn verify_weights(mut self, verify_weights: bool) -> Self {
+        self.verify_weights = verify_weights;
+        self
+    }
+
+    /// Check the SHA-256 of the weights against the manifest at `path` (a JSON object of file
+    /// names to digests, or the output of `sha256sum`) before loading them.
+    pub fn weights_manifest(mut self, path: impl Into<PathBuf>) -> Self {
+        self.weights_manifest = Some(path.into());
+        self
+    }
+
+    /// Load the model, size the KV cache from the GPU memory left by the weights and warm the
+    /// engine up. Its loop runs on the Tokio runtime this is awaited on.
+    pub async fn build(self) -> Result<(Arc<Mutex<LLMEngine>>, PipelineConfig), APIError> {
+        if !(self.gpu_memory_utilization > 0. && self.gpu_memory_utilization <= 1.) {
+            return Err(APIError::new(format!(
+                "gpu_memory_utilization must be in (0, 1], got {}.",
+                self.gpu_memory_utilization
+            )));
+        }
+        if self.max_num_seqs == 0 {
+            return Err(APIError::new_str(
+                "At least one sequence has to run at once.",
+            ));
+        }
+        let model = match self.model {
+            Some(model) => model,
+            None => detect_model(
+                Some(self.model_id.clone()),
+                self.weight_path.as_ref(),
+                self.hf_token.clone(),
+                self.hf_token_path.clone(),
+            )?,
+        };
+        let (loader, model_id) = get_model_loader(model, Some(self.model_id));
+        let paths = get_model_paths(
+            &*loader,
+            model_id,
+            self.weight_path.as_ref(),
+            self.hf_token,
+            self.hf_token_path,
+        )?;
+        if self.verify_weights || self.weights_manifest.is_some() {
+            let manifest = self
+                .weights_manifest
+                .as_deref()
+                .map(WeightManifest::load)
+                .transpose()?;
+            verify_weights(paths.get_weight_filenames(), manifest.as_ref())?;
+        }
+        let weights = paths
+            .get_weight_filenames()
+            .iter()
+            .chain(paths.get_lora_adapter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let weights_digest = weights_digest(&weights);
+        let dtype = get_dtype(self.dtype.map(|dtype| dtype.as_str()), &*paths)?;
+        let device = candle_examples::device(self.cpu).map_err(APIError::from)?;
+        let (pipeline, mut pipeline_config) =
+            loader.load_model(paths, dtype, device, self.weight_buffer_mem)?;
+
+        if let Some(max_model_len) = self.max_model_len {
+            if max_model_len == 0 || max_model_len > pipeline_config.max_model_len {
+                return Err(APIError::new(format!(
+                    "max_model_len must be between 1 and the max length of the model {}, got \
+                     {max_model_len}.",
+                    pipeline_config.max_model_len
+                )));
+            }
+            pipeline_config.max_model_len = max_model_len;
+            pipeline_config.default_max_tokens =
+                pipeline_config.default_max_tokens.min(max_model_len);
+        }
+
+        let mut config = pipeline.get_model_config();
+        if let Some(kv_cache_dtype) = self.kv_cache_dtype {
+            config.kv_cache_dtype = kv_cache_dtype;
+        }
+        let kvcache_mem_gpu = match self.kvcache_mem_gpu {
+            Some(kvcache_mem) => kvcache_mem,
+            None => profile_kvcache_mem(
+                pipeline.device(),
+                self.gpu_memory_utilization,
+                self.activation_mem,
+            )?,
+        };
+        let mut cache_config = get_cache_config(
+            &config,
+            self.block_size,
+            kvcache_mem_gpu,
+            self.kvcache_mem_cpu,
+            None,
+            None,
+            None,
+        )?;
+        cache_config.num_state_slots = config.recurrent_state.as_ref().map(|_| self.max_num_seqs);
+        cache_config.swap_affinity = self.swap_affinity;
+        cache_config.verify_args()?;
+        let worker_config = WorkerConfig {
+            prefill_chunk_size: self.prefill_chunk_size,
+            control_vectors: self.control_vectors,
+        };
+        let cache_len = cache_config.num_gpu_blocks.unwrap() * self.block_size;
+        if cache_len < pipeline_config.max_model_len {
+            return Err(APIError::new(format!(
+                "The KV cache of {kvcache_mem_gpu} MB holds {cache_len} tokens, less than a \
+                 sequence of max_model_len {} tokens. Raise gpu_memory_utilization or lower \
+                 max_model_len.",
+                pipeline_config.max_model_len
+            )));
+        }
+        println!("Cache config {:?}", cache_config);
+
+        let engine = LLMEngine::new(
+            pipeline,
+            SchedulerConfig {
+                max_num_seqs: self.max_num_seqs,
+                max_num_prefill_tokens: None,
+                long_prefill_token_threshold: None,
+                max_long_prefills: 1,
+                prompt_lookup: None,
+                batch_invariant: false,
+                max_sessions: 0,
+                compaction_blocks: None,
+            },
+            cache_config,
+            worker_config,
+            Arc::new(Notify::new()),
+            Arc::new(Notify::new()),
+        )?;
+        engine.lock().await.set_weights_digest(weights_digest);
+        if self.warmup {
+            warm_up(&engine).await?;
+        }
+        Ok((engine, pipeline_config))
+    }
+}
+
+/// GPU memory for the KV cache (MB): `gpu_memory_utilization` of the memory of `device`, less
+/// the memory already in use, the loaded weights among it, and `activation_mem`.
+fn profile_kvcache_mem(
+    device: &Device,
+    gpu_memory_utilization: f64,
+    activation_mem: usize,
+) -> Result<usize, APIError> {
+    let Some((free, total)) = try_api!(device_memory(device)) else {
+        return Ok(DEFAULT_KVCACHE_MEM);
+    };
+    let budget = (total as f64 * gpu_memory_utilization) as usize / SIZE_IN_MB;
+    let used = (total - free) / SIZE_IN_MB;
+    budget
+        .checked_sub(used + activation_mem)
+        .filter(|&kvcache_mem| kvcache_mem > 0)
+        .ok_or_else(|| {
+            APIError::new(format!(
+                "{used} MB of the GPU are in use after loading the weights, {activation_mem} MB \
+                 are left for the activations: nothing is left for the KV cache in the {budget} \
+                 MB of gpu_memory_utilization {gpu_memory_utilization}."
+            ))
+        })
+}
+
+/// Generate a token for a short prompt, loading the kernels of a prefill and of the sampler.
+async fn warm_up(engine: &Arc<Mutex<LLMEngine>>) -> Result<(), APIError> {
+    let mut engine = engine.lock().await;
+    let prompt = engine
+        .get_pipeline()
+        .tokenizer()
+        .tokenizer()
+        .encode(WARMUP_PROMPT, false)
+        .map_err(APIError::from)?;
+    let sampling_params = SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        vec![],
+        true,
+        1,
+        None,
+        None,
+        true,
+    )?;
+    engine.add_request(
+        prompt,
+        "warmup".to_string(),
+        SystemTime::now(),
+        sampling_params,
+        false,
+        None,
+        None,
+    );
+    while engine.has_unfinished_requests() {
+        engine.step()?;
+    }
+    Ok(())
+}
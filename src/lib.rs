@@ -1,11 +1,17 @@
 #![warn(clippy::cast_lossless)]
-use candle::Result;
+use candle::{DType, Result};
 use candle_core as candle;
 use clap::Subcommand;
+use openai::models::Config;
 use openai::pipelines::{
-    pipeline::{DefaultLoader, SpecificConfig},
-    ModelLoader,
+    pipeline::{download_config, DefaultLoader, DefaultModelPaths, SpecificConfig},
+    ModelLoader, ModelPaths,
 };
+use openai::responses::APIError;
+use scheduler::cache_engine::{host_memory_mb, verify_swap_space, AttentionSinks, CacheConfig};
+use std::{path::Path, time::Duration};
+
+const SIZE_IN_MB: usize = 1024 * 1024;
 
 #[derive(Debug, Subcommand)]
 pub enum ModelSelected {
@@ -164,6 +170,56 @@ pub enum ModelSelected {
         #[arg(long)]
         max_gen_tokens: Option<usize>,
     },
+
+    /// Select the LLaVA vision-language model (default llava-1.5-7b-hf).
+    Llava {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+    },
+
+    /// Select a T5 encoder-decoder model (default flan-t5-large), e.g. FLAN-T5 or MADLAD-400
+    /// translation, served through `/v1/completions`.
+    T5 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+    },
+
+    /// Select a Whisper speech recognition model (default whisper-large-v3), served through
+    /// `/v1/audio/transcriptions` and `/v1/audio/translations`.
+    Whisper {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+    },
 }
 
 impl ToString for ModelSelected {
@@ -227,6 +283,24 @@ impl ToString for ModelSelected {
                 penalty: _,
                 max_gen_tokens: _,
             } => "stablelm".to_string(),
+            ModelSelected::Llava {
+                repeat_last_n: _,
+                temperature: _,
+                penalty: _,
+                max_gen_tokens: _,
+            } => "llava".to_string(),
+            ModelSelected::T5 {
+                repeat_last_n: _,
+                temperature: _,
+                penalty: _,
+                max_gen_tokens: _,
+            } => "t5".to_string(),
+            ModelSelected::Whisper {
+                repeat_last_n: _,
+                temperature: _,
+                penalty: _,
+                max_gen_tokens: _,
+            } => "whisper".to_string(),
         }
     }
 }
@@ -449,6 +523,78 @@ pub fn get_model_loader(
                 "stabilityai/stablelm-zephyr-3b".to_string()
             },
         ),
+
+        ModelSelected::Llava {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                ),
+                "llava".to_string(),
+            )),
+            if model_id.is_some() {
+                model_id.unwrap()
+            } else {
+                "llava-hf/llava-1.5-7b-hf".to_string()
+            },
+        ),
+
+        ModelSelected::T5 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                ),
+                "t5".to_string(),
+            )),
+            if model_id.is_some() {
+                model_id.unwrap()
+            } else {
+                "google/flan-t5-large".to_string()
+            },
+        ),
+
+        ModelSelected::Whisper {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                ),
+                "whisper".to_string(),
+            )),
+            if model_id.is_some() {
+                model_id.unwrap()
+            } else {
+                "openai/whisper-large-v3".to_string()
+            },
+        ),
     }
 }
 
@@ -473,7 +619,329 @@ pub fn hub_load_local_safetensors(
     Ok(safetensors_files)
 }
 
+/// Locate the model files, either in the local `weight_path` folder or by downloading `model_id`
+/// from the hub.
+pub fn get_model_paths(
+    loader: &dyn ModelLoader,
+    model_id: String,
+    weight_path: Option<&String>,
+    hf_token: Option<String>,
+    hf_token_path: Option<String>,
+) -> std::result::Result<Box<dyn ModelPaths>, APIError> {
+    match weight_path {
+        Some(path) => Ok(Box::new(DefaultModelPaths {
+            tokenizer_filename: (path.to_owned() + "tokenizer.json").into(),
+            config_filename: (path.to_owned() + "config.json").into(),
+            generation_config_filename: Some(path.to_owned() + "generation_config.json")
+                .filter(|path| Path::new(path).exists())
+                .map(Into::into),
+            filenames: if Path::new(&(path.to_owned() + "model.safetensors.index.json")).exists() {
+                hub_load_local_safetensors(path, "model.safetensors.index.json")
+                    .map_err(APIError::from)?
+            } else {
+                //a single weight file case
+                let mut safetensors_files = Vec::<std::path::PathBuf>::new();
+                safetensors_files.insert(0, (path.to_owned() + "model.safetensors").into());
+                safetensors_files
+            },
+        })),
+        _ => loader.download_model(model_id, None, hf_token, hf_token_path),
+    }
+}
+
+/// Resolve the dtype the model is served in, `auto` takes the one of the checkpoint. Weights
+/// stored in another dtype are cast while they are loaded.
+pub fn get_dtype(
+    dtype: Option<&str>,
+    paths: &dyn ModelPaths,
+) -> std::result::Result<DType, APIError> {
+    get_config_dtype(dtype, paths.get_config_filename())
+}
+
+/// Resolve the dtype of the KV cache of a model served in `dtype`, the same one by default and
+/// with `auto`. The keys and values are cast to it as they are written to the cache.
+pub fn get_kv_cache_dtype(
+    kv_cache_dtype: Option<&str>,
+    dtype: DType,
+) -> std::result::Result<DType, APIError> {
+    match kv_cache_dtype {
+        Some("f16") => Ok(DType::F16),
+        Some("bf16") => Ok(DType::BF16),
+        Some("f32") => Ok(DType::F32),
+        Some("auto") | None => Ok(dtype),
+        // An fp8 cache needs per-tensor scales and kernels reading and writing e4m3, which
+        // neither the paged attention kernels nor candle have.
+        Some("fp8") => Err(APIError::new_str(
+            "fp8 KV caches are not supported yet, use f16 or bf16 to shrink the cache",
+        )),
+        Some(kv_cache_dtype) => Err(APIError::new(format!(
+            "Unsupported KV cache dtype {kv_cache_dtype}"
+        ))),
+    }
+}
+
+/// `get_dtype` for the checkpoint of `config_path`, before any other file of it is downloaded.
+pub fn get_config_dtype(
+    dtype: Option<&str>,
+    config_path: &Path,
+) -> std::result::Result<DType, APIError> {
+    match dtype {
+        Some("f16") => Ok(DType::F16),
+        Some("bf16") => Ok(DType::BF16),
+        Some("f32") => Ok(DType::F32),
+        Some("auto") => Ok(get_checkpoint_dtype(config_path)?.unwrap_or(DType::BF16)),
+        Some(dtype) => Err(APIError::new(format!("Unsupported dtype {dtype}"))),
+        None => Ok(DType::BF16),
+    }
+}
+
+/// The `torch_dtype` a checkpoint was saved in according to its `config.json`, looking into the
+/// `text_config` of multimodal checkpoints.
+pub fn get_checkpoint_dtype(config_path: &Path) -> std::result::Result<Option<DType>, APIError> {
+    let config = std::fs::read(config_path).map_err(APIError::from)?;
+    let config: serde_json::Value = serde_json::from_slice(&config).map_err(APIError::from)?;
+    let torch_dtype = config
+        .get("torch_dtype")
+        .or_else(|| config.get("text_config")?.get("torch_dtype"));
+    match torch_dtype.and_then(|dtype| dtype.as_str()) {
+        Some("float16" | "half") => Ok(Some(DType::F16)),
+        Some("bfloat16") => Ok(Some(DType::BF16)),
+        Some("float32" | "float") => Ok(Some(DType::F32)),
+        Some(dtype) => Err(APIError::new(format!(
+            "Unsupported checkpoint torch_dtype {dtype}"
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// The `architectures` of `config.json` each model is loaded for. Llama checkpoints with the
+/// 128k vocabulary of Llama 3 are loaded as `llama3`.
+pub const SUPPORTED_ARCHITECTURES: &[(&str, &str)] = &[
+    ("LlamaForCausalLM", "llama"),
+    ("PhiForCausalLM", "phi2"),
+    ("Phi3ForCausalLM", "phi3"),
+    ("Qwen2ForCausalLM", "qwen2"),
+    ("GemmaForCausalLM", "gemma"),
+    ("MistralForCausalLM", "mistral"),
+    ("YiForCausalLM", "yi"),
+    ("StableLmForCausalLM", "stablelm"),
+    ("StableLMEpochForCausalLM", "stablelm"),
+    ("LlavaForConditionalGeneration", "llava"),
+    ("T5ForConditionalGeneration", "t5"),
+    ("WhisperForConditionalGeneration", "whisper"),
+];
+
+const LLAMA3_VOCAB_SIZE: u64 = 128000;
+
+impl ModelSelected {
+    /// The model of the given name (see `to_string`) with the defaults of the checkpoint.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "llama" => ModelSelected::Llama {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "llama3" => ModelSelected::Llama3 {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "phi2" => ModelSelected::Phi2 {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "phi3" => ModelSelected::Phi3 {
+                repeat_last_n: None,
+                temperature: None,
+                top_p: None,
+                top_k: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "qwen2" => ModelSelected::Qwen2 {
+                repeat_last_n: None,
+                temperature: None,
+                top_p: None,
+                top_k: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "gemma" => ModelSelected::Gemma {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "mistral" => ModelSelected::Mistral {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "yi" => ModelSelected::Yi {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "stablelm" => ModelSelected::StableLM {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "llava" => ModelSelected::Llava {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "t5" => ModelSelected::T5 {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            "whisper" => ModelSelected::Whisper {
+                repeat_last_n: None,
+                temperature: None,
+                penalty: None,
+                max_gen_tokens: None,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// The model to load a checkpoint with, from the `architectures` of its `config.json`.
+pub fn get_model_architecture(config_path: &Path) -> std::result::Result<ModelSelected, APIError> {
+    let config = std::fs::read(config_path).map_err(APIError::from)?;
+    let config: serde_json::Value = serde_json::from_slice(&config).map_err(APIError::from)?;
+    let architectures = config
+        .get("architectures")
+        .and_then(|architectures| architectures.as_array())
+        .map(|architectures| {
+            architectures
+                .iter()
+                .filter_map(|architecture| architecture.as_str())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let name = architectures.iter().find_map(|architecture| {
+        SUPPORTED_ARCHITECTURES
+            .iter()
+            .find(|(supported, _)| supported == architecture)
+            .map(|(_, name)| *name)
+    });
+    let name = match name {
+        Some("llama")
+            if config
+                .get("vocab_size")
+                .and_then(|vocab_size| vocab_size.as_u64())
+                .is_some_and(|vocab_size| vocab_size >= LLAMA3_VOCAB_SIZE) =>
+        {
+            "llama3"
+        }
+        Some(name) => name,
+        None => {
+            let supported = SUPPORTED_ARCHITECTURES
+                .iter()
+                .map(|(architecture, _)| *architecture)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(APIError::new(format!(
+                "Unsupported architectures {architectures:?} in {}, select the model explicitly or use one of the supported architectures: {supported}",
+                config_path.display()
+            )));
+        }
+    };
+    Ok(ModelSelected::from_name(name).expect("supported architectures map to models"))
+}
+
+/// Detect the model from the `config.json` in the local `weight_path` folder or downloaded for
+/// `model_id` from the hub.
+pub fn detect_model(
+    model_id: Option<String>,
+    weight_path: Option<&String>,
+    hf_token: Option<String>,
+    hf_token_path: Option<String>,
+) -> std::result::Result<ModelSelected, APIError> {
+    let config_filename = match (weight_path, model_id) {
+        (Some(path), _) => (path.to_owned() + "config.json").into(),
+        (None, Some(model_id)) => download_config(model_id, None, hf_token, hf_token_path)?,
+        (None, None) => return Err(APIError::new_str(
+            "Select a model, or pass `weight_path` or `model_id` to detect it from its config.json",
+        )),
+    };
+    let model = get_model_architecture(&config_filename)?;
+    println!(
+        "Detected {} model from {}",
+        model.to_string(),
+        config_filename.display()
+    );
+    Ok(model)
+}
+
+/// Number of KV cache blocks of `block_size` tokens that fit in `mem` MB.
+pub fn num_kvcache_blocks(config: &Config, block_size: usize, mem: usize) -> usize {
+    mem * SIZE_IN_MB / (block_size * kvcache_bytes_per_token(config))
+}
+
+/// Bytes of the keys and values of one token over all layers.
+pub fn kvcache_bytes_per_token(config: &Config) -> usize {
+    2 * config.num_hidden_layers
+        * config.num_key_value_heads
+        * config.get_head_size()
+        * config.kv_cache_dtype.size_in_bytes()
+}
+
+/// Split the GPU and CPU memory budgets (in MB) for the KV cache into blocks of `block_size` tokens.
+/// The CPU cache, the swap space preempted sequences are swapped out to, has to fit in host memory.
+/// With `kvcache_growth_mem` the GPU cache is allocated lazily in chunks of that many MB, and
+/// shrunk back to one chunk after `kvcache_idle_shrink` without requests.
+pub fn get_cache_config(
+    config: &Config,
+    block_size: usize,
+    kvcache_mem_gpu: usize,
+    kvcache_mem_cpu: usize,
+    kvcache_growth_mem: Option<usize>,
+    kvcache_idle_shrink: Option<Duration>,
+    attention_sinks: Option<AttentionSinks>,
+) -> std::result::Result<CacheConfig, APIError> {
+    if let Some(host_memory) = host_memory_mb() {
+        verify_swap_space(kvcache_mem_cpu, host_memory)?;
+    }
+    let num_blocks = |mem: usize| num_kvcache_blocks(config, block_size, mem);
+    let cache_config = CacheConfig {
+        block_size,
+        num_gpu_blocks: Some(num_blocks(kvcache_mem_gpu)),
+        num_cpu_blocks: Some(num_blocks(kvcache_mem_cpu)),
+        fully_init: true,
+        dtype: config.kv_cache_dtype,
+        gpu_growth_blocks: kvcache_growth_mem.map(num_blocks),
+        idle_shrink_after: kvcache_idle_shrink,
+        attention_sinks,
+        num_state_slots: None,
+        swap_affinity: None,
+    };
+    cache_config.verify_args()?;
+    Ok(cache_config)
+}
+
 pub mod backend;
+pub mod benchmark;
+pub mod engine_builder;
+pub mod logging;
 pub mod openai;
 pub mod paged_attention;
+pub mod planning;
+#[cfg(feature = "python")]
+pub mod python;
 pub mod scheduler;
+pub mod telemetry;
+pub mod tls;
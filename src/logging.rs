@@ -0,0 +1,88 @@
+//! Logging of the request lifecycle. Requests emit `tracing` events when they are received,
+//! queued, start their prefill, produce their first token and finish, with the request id, token
+//! counts and durations as fields. The binary installs a subscriber printing them as text lines,
+//! or as JSON objects for log aggregation systems, whose filter can be changed at runtime.
+use clap::ValueEnum;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::EnvFilter;
+
+use crate::openai::responses::APIError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+type ReloadFilter = dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync;
+
+/// Changes the filter of the installed subscriber while the server runs.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    reload: Arc<ReloadFilter>,
+    directives: Arc<Mutex<String>>,
+}
+
+impl LogFilterHandle {
+    /// Directives of the current filter, e.g. `info,candle_vllm=debug`.
+    pub fn directives(&self) -> String {
+        self.directives
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace the filter by `directives`, in the syntax of `RUST_LOG`.
+    pub fn set_directives(&self, directives: &str) -> Result<(), APIError> {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| APIError::new(format!("Invalid log filter `{directives}`: {e}")))?;
+        (self.reload)(filter)
+            .map_err(|e| APIError::new(format!("Failed to change the log filter: {e}")))?;
+        *self.directives.lock().unwrap_or_else(|e| e.into_inner()) = directives.to_string();
+        Ok(())
+    }
+}
+
+/// Install the global subscriber. `RUST_LOG` takes precedence over the default filter, which
+/// logs at info level, and at debug level for this crate when `verbose`. The filter can be
+/// changed later through the returned handle.
+pub fn init_logging(format: LogFormat, verbose: bool) -> Result<LogFilterHandle, APIError> {
+    let default_filter = if verbose {
+        "info,candle_vllm=debug"
+    } else {
+        "info"
+    };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
+    let directives = Arc::new(Mutex::new(filter.to_string()));
+    // The type of the handle depends on the formatter, only the reload function is kept.
+    let (reload, installed): (Arc<ReloadFilter>, _) = match format {
+        LogFormat::Text => {
+            let builder = tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            (
+                Arc::new(move |filter| handle.reload(filter).map_err(|e| e.to_string())),
+                builder.try_init(),
+            )
+        }
+        LogFormat::Json => {
+            let builder = tracing_subscriber::fmt()
+                .json()
+                .flatten_event(true)
+                .with_env_filter(filter)
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            (
+                Arc::new(move |filter| handle.reload(filter).map_err(|e| e.to_string())),
+                builder.try_init(),
+            )
+        }
+    };
+    installed.map_err(|e| APIError::new(format!("Failed to install the logger: {e}")))?;
+    Ok(LogFilterHandle { reload, directives })
+}
@@ -1,28 +1,217 @@
 use axum::{
+    extract::DefaultBodyLimit,
     http::{self, Method},
-    routing::post,
+    routing::{delete, get, post},
     Router,
 };
 use candle_core::{DType, Device};
 use candle_examples;
-use candle_vllm::openai::openai_server::chat_completions;
-use candle_vllm::openai::pipelines::llm_engine::LLMEngine;
-use candle_vllm::openai::pipelines::pipeline::DefaultModelPaths;
+use candle_vllm::benchmark::{load_sharegpt_dataset, run_benchmark, synthetic_requests};
+use candle_vllm::logging::{init_logging, LogFilterHandle, LogFormat};
+use candle_vllm::openai::batches::BatchStore;
+use candle_vllm::openai::control_vectors::parse_control_vector_arg;
+use candle_vllm::openai::fingerprint::weights_digest;
+use candle_vllm::openai::logit_dump::LogitDumpDir;
+use candle_vllm::openai::models::Config;
+use candle_vllm::openai::moderation::RegexModerator;
+use candle_vllm::openai::openai_server::{
+    abort_request, audio_transcriptions, audio_translations, cache_stats, cancel_batch,
+    chat_completions, completions, create_batch, debug_scheduler, detect_watermark,
+    get_admin_config, get_batch, get_file, get_file_content, list_batches, list_requests,
+    post_admin_config, recovered_requests, sleep, upload_file, wake,
+};
+use candle_vllm::openai::pipelines::{
+    executor::MultiprocExecutor,
+    integrity::{verify_weights, WeightManifest},
+    llm_engine::LLMEngine,
+    lora::with_lora_merge,
+    pipeline::{download_config, load_config},
+    tensor_parallel::check_tensor_parallel,
+    weights::DEFAULT_WEIGHT_BUFFER_MEM,
+    worker::{Worker, WorkerConfig},
+    worker_process::{
+        serve_engine, worker_secret, EngineConnection, WorkerProcess, WORKER_DEVICE_ENV,
+        WORKER_ENV, WORKER_RANK_ENV,
+    },
+    ModelLoader, ModelPaths,
+};
+use candle_vllm::openai::response_cache::{ResponseCache, DEFAULT_RESPONSE_CACHE_TTL_SECS};
 use candle_vllm::openai::responses::APIError;
-use candle_vllm::openai::OpenAIServerData;
-use candle_vllm::scheduler::cache_engine::CacheConfig;
-use candle_vllm::scheduler::SchedulerConfig;
-use candle_vllm::{get_model_loader, hub_load_local_safetensors, ModelSelected};
-use clap::Parser;
+use candle_vllm::openai::sampling_params::{
+    EarlyStoppingCondition, SamplingParams, MAX_TOP_LOGPROBS,
+};
+use candle_vllm::openai::streaming::ChatResponse;
+use candle_vllm::openai::tokenizer_pool::TokenizerPool;
+use candle_vllm::openai::watermark::{Watermark, WatermarkConfig};
+use candle_vllm::openai::{OpenAIServerData, PipelineConfig};
+use candle_vllm::planning::{self, PlanConfig, Quantization};
+use candle_vllm::scheduler::{
+    cache_engine::{AttentionSinks, CacheConfig, SwapAffinity, SUPPORTED_BLOCK_SIZES},
+    policy::get_policy,
+    prompt_lookup::PromptLookupConfig,
+    request_log::RequestLog,
+    SchedulerConfig,
+};
+use candle_vllm::telemetry::{OtlpExporter, TelemetryConfig};
+use candle_vllm::tls::{self, TlsFiles};
+use candle_vllm::{
+    detect_model, get_cache_config, get_config_dtype, get_dtype, get_kv_cache_dtype,
+    get_model_loader, get_model_paths, ModelSelected,
+};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-const SIZE_IN_MB: usize = 1024 * 1024;
-use candle_vllm::openai::models::Config;
-use std::path::Path;
-use tokio::sync::Notify;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, Notify};
 use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Max size of the audio files of transcription requests, as in the OpenAI API.
+const AUDIO_BODY_LIMIT: usize = 25 * 1024 * 1024;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Format of the logs, json prints one object per line for log aggregation systems
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start the OpenAI compatible server.
+    Serve {
+        /// Port to serve on (localhost:port)
+        #[arg(long)]
+        port: u16,
+
+        /// Set verbose mode (log all requests and their prompts, RUST_LOG takes precedence)
+        #[arg(long)]
+        verbose: bool,
+
+        /// Record conversation (default false, the client need to record chat history)
+        #[arg(long)]
+        record_conversation: bool,
+
+        /// Number of threads encoding the prompts of requests
+        #[arg(long, default_value_t = 2)]
+        tokenizer_threads: usize,
+
+        /// Bearer token of the admin API changing the configuration at runtime, disabled without
+        /// one (CANDLE_VLLM_ADMIN_TOKEN keeps it out of the command line)
+        #[arg(long)]
+        admin_token: Option<String>,
+
+        /// Requests of a batch (`/v1/batches`) in flight at once, they are scheduled behind the
+        /// interactive requests
+        #[arg(long, default_value_t = 4)]
+        max_concurrent_batch_requests: usize,
+
+        #[command(flatten)]
+        request_log: RequestLogArgs,
+
+        #[command(flatten)]
+        watermark: WatermarkArgs,
+
+        #[command(flatten)]
+        moderation: ModerationArgs,
+
+        #[command(flatten)]
+        admission: AdmissionArgs,
+
+        #[command(flatten)]
+        response_cache: ResponseCacheArgs,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+
+        #[command(flatten)]
+        telemetry: TelemetryArgs,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+
+        /// Model to load (detected from the architectures of its config.json if not specified)
+        #[command(subcommand)]
+        model: Option<ModelSelected>,
+    },
+
+    /// Generate a completion for the prompt in a file and print it as it is decoded.
+    Generate {
+        /// File containing the prompt
+        #[arg(long)]
+        prompt_file: PathBuf,
+
+        /// Maximum number of tokens to generate (default: the model's default)
+        #[arg(long)]
+        max_tokens: Option<usize>,
+
+        /// Use the file content as is instead of wrapping it in the model's chat template
+        #[arg(long)]
+        raw: bool,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+
+        /// Model to load (detected from the architectures of its config.json if not specified)
+        #[command(subcommand)]
+        model: Option<ModelSelected>,
+    },
+
+    /// Measure throughput, TTFT, TPOT and KV cache usage on a replayed or synthetic workload.
+    Benchmark {
+        #[command(flatten)]
+        benchmark: BenchmarkArgs,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+
+        /// Model to load (detected from the architectures of its config.json if not specified)
+        #[command(subcommand)]
+        model: Option<ModelSelected>,
+    },
+
+    /// Compute the context length and the number of sequences a GPU can serve, from the config
+    /// of the model alone.
+    Plan {
+        #[command(flatten)]
+        plan: PlanArgs,
+
+        #[command(flatten)]
+        model_args: ModelArgs,
+
+        /// The folder name that contains the config.json of the model, path must include last "/"
+        #[arg(long)]
+        weight_path: Option<String>,
+
+        /// Model to plan for (detected from the architectures of its config.json if not specified)
+        #[command(subcommand)]
+        model: Option<ModelSelected>,
+    },
+
+    /// Download a model from the hub into the local cache.
+    Download {
+        /// Git revision of the model repository
+        #[arg(long)]
+        revision: Option<String>,
+
+        #[command(flatten)]
+        model_args: ModelArgs,
+
+        /// Model to load (detected from the architectures of its config.json if not specified)
+        #[command(subcommand)]
+        model: Option<ModelSelected>,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct ModelArgs {
     /// Huggingface token environment variable (optional). If not specified, load using hf_token_path.
     #[arg(long)]
     hf_token: Option<String>,
@@ -32,40 +221,310 @@ struct Args {
     #[arg(long)]
     hf_token_path: Option<String>,
 
-    /// Port to serve on (localhost:port)
+    /// if weight_path is passed, it will ignore the model_id
     #[arg(long)]
-    port: u16,
+    model_id: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct RequestLogArgs {
+    /// Write-ahead log of the accepted requests, the ones a crash left unfinished are reported
+    /// at the next start and listed by `/recovered_requests`
+    #[arg(long)]
+    request_log: Option<PathBuf>,
+
+    /// Queue the idempotent requests (greedy or beam search, without images) left unfinished in
+    /// the request log again instead of reporting them as lost
+    #[arg(long, requires = "request_log")]
+    replay_requests: bool,
+}
+
+#[derive(ClapArgs, Debug)]
+struct AdmissionArgs {
+    /// Name of the served model, requests for other models get a 404 (repeat it for aliases).
+    /// Requests may name any model without it
+    #[arg(long)]
+    served_model_name: Vec<String>,
+
+    /// Refuse new requests with a 429 while this many requests wait to be scheduled
+    #[arg(long)]
+    max_waiting_requests: Option<usize>,
+
+    /// Cap the tokens of requests without `max_tokens` at what the free blocks of the KV cache
+    /// hold besides their prompt, on top of the context length of the model
+    #[arg(long)]
+    cap_max_tokens_to_free_blocks: bool,
+
+    /// Refuse chat and completion requests with fields the server does not know, instead of
+    /// ignoring them
+    #[arg(long)]
+    reject_unknown_fields: bool,
+
+    /// Most `top_logprobs` a request may ask for, for research on the distributions of the model
+    #[arg(long, default_value_t = MAX_TOP_LOGPROBS)]
+    max_logprobs: usize,
 
-    /// Set verbose mode (print all requests)
+    /// Directory requests with `dump_logits` write the logits of their tokens to, as
+    /// `<request id>.jsonl`. Requests cannot dump logits without it
     #[arg(long)]
-    verbose: bool,
+    logit_dump_dir: Option<PathBuf>,
 
-    #[clap(subcommand)]
-    command: ModelSelected,
+    /// Size of the logit dump of a request past which it stops (MiB)
+    #[arg(long, default_value_t = 1024)]
+    max_logit_dump_mb: u64,
+
+    /// API keys (`Authorization: Bearer <key>`) each getting a fair share of the server with
+    /// `--scheduling-policy fair`, requests with other keys or none share one tenant
+    #[arg(long, value_delimiter = ',')]
+    api_keys: Vec<String>,
+
+    /// File of API keys like `--api-keys`, one per line, keeping them out of the command line
+    #[arg(long)]
+    api_key_file: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ResponseCacheArgs {
+    /// Serve this many responses of greedy (temperature 0), non-streamed requests again to the
+    /// identical requests without running the model. Off when 0
+    #[arg(long, default_value_t = 0)]
+    response_cache_size: usize,
+
+    /// Time a response is served from the cache (seconds)
+    #[arg(long, default_value_t = DEFAULT_RESPONSE_CACHE_TTL_SECS)]
+    response_cache_ttl_secs: u64,
+}
+
+#[derive(ClapArgs, Debug)]
+struct TlsArgs {
+    /// PEM certificate chain to serve HTTPS with, reloaded with the key on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct TelemetryArgs {
+    /// OpenTelemetry collector to export the metrics of the engine and the traces of the requests
+    /// to over OTLP/HTTP, e.g. http://localhost:4318 (OTEL_EXPORTER_OTLP_ENDPOINT when not given)
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Share of the requests traced, from 0 to 1
+    #[arg(long, default_value_t = 1.0)]
+    otlp_trace_sample_ratio: f64,
+
+    /// Time between two exports to the collector (seconds)
+    #[arg(long, default_value_t = 10)]
+    otlp_export_interval_secs: u64,
+
+    /// `service.name` of the exported signals (OTEL_SERVICE_NAME when not given)
+    #[arg(long)]
+    otlp_service_name: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ModerationArgs {
+    /// Halt a choice with the `content_filter` finish reason when its text matches, given as
+    /// category=regex, e.g. url=https?://\S+ (repeatable)
+    #[arg(long = "content-filter")]
+    content_filters: Vec<String>,
+
+    /// Report the matches in the text of a choice without halting it, given as category=regex
+    /// (repeatable)
+    #[arg(long = "content-flag")]
+    content_flags: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct WatermarkArgs {
+    /// Secret key watermarking the generated text, which `/v1/watermark/detect` tests for
+    /// (CANDLE_VLLM_WATERMARK_KEY keeps it out of the command line)
+    #[arg(long)]
+    watermark_key: Option<u64>,
+
+    /// Fraction of the vocabulary favored after each token of watermarked text
+    #[arg(long, default_value_t = 0.25)]
+    watermark_gamma: f64,
+
+    /// Bias added to the logits of the favored tokens of watermarked text
+    #[arg(long, default_value_t = 2.0)]
+    watermark_delta: f32,
+}
+
+#[derive(ClapArgs, Debug)]
+struct BenchmarkArgs {
+    /// ShareGPT json dataset to sample prompts from (synthetic prompts if not specified)
+    #[arg(long)]
+    dataset: Option<PathBuf>,
+
+    /// Number of requests
+    #[arg(long, default_value_t = 16)]
+    num_requests: usize,
+
+    /// Prompt length of each synthetic request in tokens
+    #[arg(long, default_value_t = 128)]
+    prompt_len: usize,
+
+    /// Number of tokens generated for each synthetic request
+    #[arg(long, default_value_t = 128)]
+    max_tokens: usize,
+
+    /// Requests per second, arriving as a Poisson process (inf sends all requests at once)
+    #[arg(long, default_value_t = f64::INFINITY)]
+    request_rate: f64,
+
+    /// Seed for sampling the dataset and the arrival times
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(ClapArgs, Debug)]
+struct PlanArgs {
+    /// Memory of the GPU (MB)
+    #[arg(long)]
+    gpu_mem: usize,
+
+    /// GPU memory left for the activations and the CUDA context (MB)
+    #[arg(long, default_value_t = 2048)]
+    reserved_mem: usize,
+
+    /// Tokens (prompt and generated) of each sequence (default: the max model length)
+    #[arg(long)]
+    context_len: Option<usize>,
+
+    /// Quantization of the weights of the layers
+    #[arg(long, value_enum)]
+    quantization: Option<Quantization>,
 
     /// Maximum number of sequences to allow
     #[arg(long, default_value_t = 256)]
     max_num_seqs: usize,
 
-    /// Size of a block
-    #[arg(long, default_value_t = 32)]
+    /// Size of a KV cache block in tokens
+    #[arg(long, default_value_t = 32, value_parser = parse_block_size)]
     block_size: usize,
 
-    /// if weight_path is passed, it will ignore the model_id
-    #[arg(long)]
-    model_id: Option<String>,
+    /// Dtype the model is served in (default bf16), auto uses the dtype of the checkpoint
+    #[arg(long, value_parser = PossibleValuesParser::new(["auto", "f16", "bf16", "f32"]))]
+    dtype: Option<String>,
+
+    /// Dtype of the KV cache, auto (the default) uses the dtype the model is served in. fp8 is
+    /// not supported yet
+    #[arg(long, value_parser = PossibleValuesParser::new(["auto", "f16", "bf16", "f32", "fp8"]))]
+    kv_cache_dtype: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct EngineArgs {
+    #[command(flatten)]
+    model_args: ModelArgs,
 
     /// The folder name that contains safetensor weights and json files
     /// (same structure as huggingface online), path must include last "/"
     #[arg(long)]
     weight_path: Option<String>,
 
+    /// Folder of a PEFT LoRA adapter (adapter_config.json and adapter_model.safetensors) to merge
+    /// into the weights while they load, serving the adapted model without runtime LoRA overhead
+    #[arg(long)]
+    lora_merge: Option<PathBuf>,
+
+    /// Check the size of the safetensors shards and their SHA-256 against the hub before loading
+    /// them, failing on truncated or corrupted downloads
     #[arg(long)]
+    verify_weights: bool,
+
+    /// Check the SHA-256 of the shards against this manifest instead of the hub: a JSON object
+    /// of file names to digests, or the output of `sha256sum`. Implies `verify_weights`
+    #[arg(long)]
+    weights_manifest: Option<PathBuf>,
+
+    /// Maximum number of sequences to allow
+    #[arg(long, default_value_t = 256)]
+    max_num_seqs: usize,
+
+    /// Maximum number of prompt tokens prefilled in one step (a longer prompt is prefilled alone)
+    #[arg(long)]
+    max_num_prefill_tokens: Option<usize>,
+
+    /// Prompts longer than this many tokens are long prefills, limited by max_long_prefills and
+    /// never prefilled in two steps in a row while other sequences are decoding
+    #[arg(long)]
+    long_prefill_token_threshold: Option<usize>,
+
+    /// Maximum number of long prefills in one step
+    #[arg(long, default_value_t = 1)]
+    max_long_prefills: usize,
+
+    /// Speculate up to this many tokens per step by prompt lookup: the tokens that followed an
+    /// earlier occurrence of the last n-gram of a sequence are verified in one forward pass
+    #[arg(long)]
+    num_speculative_tokens: Option<usize>,
+
+    /// Longest n-gram looked up for prompt lookup speculation
+    #[arg(long, default_value_t = 4)]
+    prompt_lookup_max: usize,
+
+    /// Shortest n-gram looked up for prompt lookup speculation
+    #[arg(long, default_value_t = 1)]
+    prompt_lookup_min: usize,
+
+    /// Propose the continuations of up to this many earlier occurrences of the n-gram at once,
+    /// as a tree whose branches are all verified in one forward pass
+    #[arg(long, default_value_t = 1)]
+    prompt_lookup_branches: usize,
+
+    /// Propose fewer tokens for the requests whose recent proposals were mostly rejected, up to
+    /// num_speculative_tokens, so that the model does not verify tokens unlikely to be accepted
+    #[arg(long, default_value_t = false, requires = "num_speculative_tokens")]
+    adaptive_speculation: bool,
+
+    /// Run each sequence through the model on its own, so that its output is bit-identical
+    /// whichever requests it is batched with, at the cost of throughput
+    #[arg(long, default_value_t = false)]
+    batch_invariant: bool,
+
+    /// Order the requests are scheduled and preempted in: fcfs, priority by the `priority` of
+    /// the requests (lower first) and then fcfs, or fair for a fair share of the API keys
+    #[arg(long, default_value = "fcfs", value_parser = PossibleValuesParser::new(["fcfs", "priority", "fair"]))]
+    scheduling_policy: String,
+
+    /// Keep the KV cache of the last turn of up to this many conversations, for requests with a
+    /// `conversation_id` to only prefill the new tokens of their prompt
+    #[arg(long, default_value_t = 0)]
+    max_sessions: usize,
+
+    /// Move up to this many KV cache blocks per decode step to keep the free blocks in one range
+    /// for long prompts, at the cost of copying them
+    #[arg(long)]
+    compaction_blocks: Option<usize>,
+
+    /// Size of a KV cache block in tokens (one the paged attention kernels are compiled for)
+    #[arg(long, default_value_t = 32, value_parser = parse_block_size)]
+    block_size: usize,
+
+    /// Dtype the model is served in (default bf16), auto uses the dtype of the checkpoint.
+    /// Weights stored in another dtype are cast while loading.
+    #[arg(long, value_parser = PossibleValuesParser::new(["auto", "f16", "bf16", "f32"]))]
     dtype: Option<String>,
 
+    /// Dtype of the KV cache, auto (the default) uses the dtype the model is served in. The keys
+    /// and values are cast to it as they are written, e.g. a f16 cache holds twice the tokens of
+    /// a f32 one. fp8 is not supported yet.
+    #[arg(long, value_parser = PossibleValuesParser::new(["auto", "f16", "bf16", "f32", "fp8"]))]
+    kv_cache_dtype: Option<String>,
+
     #[arg(long, default_value_t = false)]
     cpu: bool,
 
+    /// Host memory the weights are staged in on their way to the device (MB)
+    #[arg(long, default_value_t = DEFAULT_WEIGHT_BUFFER_MEM)]
+    weight_buffer_mem: usize,
+
     /// Available GPU memory for kvcache (MB)
     #[arg(long, default_value_t = 4096)]
     kvcache_mem_gpu: usize,
@@ -74,102 +533,697 @@ struct Args {
     #[arg(long, default_value_t = 4096)]
     kvcache_mem_cpu: usize,
 
-    /// Record conversation (default false, the client need to record chat history)
+    /// CPU kvcache sequences preempted by swapping are moved to (GiB), in place of
+    /// kvcache_mem_cpu. It has to fit in host memory, 0 aborts them instead
+    #[arg(long, conflicts_with = "kvcache_mem_cpu")]
+    swap_space: Option<f64>,
+
+    /// Allocate the GPU kvcache lazily in chunks of this size (MB), up to kvcache_mem_gpu
     #[arg(long)]
-    record_conversation: bool,
+    kvcache_growth_mem: Option<usize>,
+
+    /// Shrink the GPU kvcache back to one chunk after this many seconds without requests
+    /// (requires kvcache_growth_mem)
+    #[arg(long)]
+    kvcache_idle_shrink_secs: Option<u64>,
+
+    /// Keep the KV cache of the first blocks of every sequence as attention sinks, and of a
+    /// window of recent blocks, evicting the blocks in between for unbounded generation
+    #[arg(long, requires = "attention_window_blocks")]
+    attention_sink_blocks: Option<usize>,
+
+    /// Number of recent blocks kept with attention sinks (requires attention_sink_blocks)
+    #[arg(long, requires = "attention_sink_blocks")]
+    attention_window_blocks: Option<usize>,
+
+    /// CPUs the swaps of the kvcache between the GPU and the CPU run on, and the CPU kvcache is
+    /// allocated from: `auto` for the NUMA node of the GPU, `node:<node>` or a list of CPUs
+    /// (e.g. 0-15,32-47). Unbound by default
+    #[arg(long)]
+    swap_affinity: Option<String>,
+
+    /// Attend prompts this many tokens at a time during prefill, so that long prompts are
+    /// bounded by the kvcache rather than by the memory of their attention scores
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// Control vector requests may steer the model with, as name=path to a GGUF or safetensors
+    /// file of direction.{layer} tensors (repeat it for more vectors)
+    #[arg(long = "control-vector")]
+    control_vectors: Vec<String>,
+
+    /// Run the model in a worker process on each of these GPUs (e.g. 0,1) instead of in the
+    /// server process, a crash of CUDA then fails the requests in flight instead of the server
+    #[arg(long, value_delimiter = ',')]
+    worker_devices: Vec<usize>,
+
+    /// NUMA node each worker process is pinned to with numactl, in the order of worker_devices
+    #[arg(long, value_delimiter = ',', requires = "worker_devices")]
+    worker_numa_nodes: Vec<usize>,
+
+    /// Shard the model over the worker processes instead of running a replica of it in each of
+    /// them, for models larger than a GPU (llama models, builds with the nccl feature)
+    #[arg(long, requires = "worker_devices")]
+    tensor_parallel: bool,
+
+    /// Number of nodes the model is sharded over, each running the same command with its
+    /// worker_devices and its node_rank
+    #[arg(long, default_value_t = 1, requires_all = ["tensor_parallel", "master_addr"])]
+    num_nodes: usize,
+
+    /// Rank of this node: node 0 runs the server, the others only their worker processes
+    #[arg(long, default_value_t = 0, requires = "master_addr")]
+    node_rank: usize,
+
+    /// Address (host:port) node 0 accepts the worker processes of the other nodes on
+    #[arg(long)]
+    master_addr: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), APIError> {
-    let args = Args::parse();
-    let (loader, model_id) = get_model_loader(args.command, args.model_id.clone());
-    if args.model_id.is_none() {
+/// The selected model, or the one detected from the `config.json` of the checkpoint.
+fn select_model(
+    model: Option<ModelSelected>,
+    args: &ModelArgs,
+    weight_path: Option<&String>,
+) -> Result<ModelSelected, APIError> {
+    match model {
+        Some(model) => Ok(model),
+        None => detect_model(
+            args.model_id.clone(),
+            weight_path,
+            args.hf_token.clone(),
+            args.hf_token_path.clone(),
+        ),
+    }
+}
+
+/// Resolve the selected model to its loader, files and dtype.
+fn resolve_model(
+    model: Option<ModelSelected>,
+    args: &EngineArgs,
+) -> Result<(Box<dyn ModelLoader>, Box<dyn ModelPaths>, DType), APIError> {
+    let model_args = &args.model_args;
+    let model = select_model(model, model_args, args.weight_path.as_ref())?;
+    let (loader, model_id) = get_model_loader(model, model_args.model_id.clone());
+    if model_args.model_id.is_none() {
         println!("No model id specified, using the default model or specified in the weight_path!");
     }
 
-    let paths = match &args.weight_path {
-        Some(path) => Box::new(DefaultModelPaths {
-            tokenizer_filename: (path.to_owned() + "tokenizer.json").into(),
-            config_filename: (path.to_owned() + "config.json").into(),
-            filenames: if Path::new(&(path.to_owned() + "model.safetensors.index.json")).exists() {
-                hub_load_local_safetensors(path, "model.safetensors.index.json").unwrap()
-            } else {
-                //a single weight file case
-                let mut safetensors_files = Vec::<std::path::PathBuf>::new();
-                safetensors_files.insert(0, (path.to_owned() + "model.safetensors").into());
-                safetensors_files
-            },
+    let paths = get_model_paths(
+        &*loader,
+        model_id,
+        args.weight_path.as_ref(),
+        model_args.hf_token.clone(),
+        model_args.hf_token_path.clone(),
+    )?;
+    let paths = match &args.lora_merge {
+        Some(adapter) => with_lora_merge(paths, adapter.clone()),
+        None => paths,
+    };
+    let dtype = get_dtype(args.dtype.as_deref(), &*paths)?;
+    Ok((loader, paths, dtype))
+}
+
+fn cache_config(args: &EngineArgs, config: &Config) -> Result<CacheConfig, APIError> {
+    let kvcache_mem_cpu = match args.swap_space {
+        Some(swap_space) if !swap_space.is_finite() || swap_space < 0. => {
+            return Err(APIError::new(format!(
+                "The swap space must be a positive number of GiB, got {swap_space}."
+            )))
+        }
+        Some(swap_space) => (swap_space * 1024.) as usize,
+        None => args.kvcache_mem_cpu,
+    };
+    // The blocks are sized for the dtype of the cache, not the one of the model.
+    let config = Config {
+        kv_cache_dtype: get_kv_cache_dtype(args.kv_cache_dtype.as_deref(), config.kv_cache_dtype)?,
+        ..config.clone()
+    };
+    let mut cache_config = get_cache_config(
+        &config,
+        args.block_size,
+        args.kvcache_mem_gpu,
+        kvcache_mem_cpu,
+        args.kvcache_growth_mem,
+        args.kvcache_idle_shrink_secs.map(Duration::from_secs),
+        args.attention_sink_blocks
+            .zip(args.attention_window_blocks)
+            .map(|(sink_blocks, window_blocks)| AttentionSinks {
+                sink_blocks,
+                window_blocks,
+            }),
+    )?;
+    // Every running sequence of a hybrid model holds a slot of recurrent states.
+    cache_config.num_state_slots = config.recurrent_state.as_ref().map(|_| args.max_num_seqs);
+    cache_config.swap_affinity = args
+        .swap_affinity
+        .as_deref()
+        .map(SwapAffinity::from_str)
+        .transpose()?;
+    cache_config.verify_args()?;
+    Ok(cache_config)
+}
+
+/// A `--block-size` the paged attention kernels are compiled for.
+fn parse_block_size(arg: &str) -> Result<usize, String> {
+    let block_size = arg.parse::<usize>().map_err(|err| err.to_string())?;
+    if !SUPPORTED_BLOCK_SIZES.contains(&block_size) {
+        return Err(format!("must be one of {SUPPORTED_BLOCK_SIZES:?}"));
+    }
+    Ok(block_size)
+}
+
+/// API keys of `--api-keys` and `--api-key-file`.
+fn api_keys(admission: &AdmissionArgs) -> Result<HashSet<String>, APIError> {
+    let mut api_keys: HashSet<String> = admission.api_keys.iter().cloned().collect();
+    if let Some(path) = &admission.api_key_file {
+        let keys = std::fs::read_to_string(path).map_err(|e| {
+            APIError::new(format!("Cannot read the API keys {}: {e}", path.display()))
+        })?;
+        api_keys.extend(
+            keys.lines()
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string),
+        );
+    }
+    if api_keys.iter().any(|key| key.is_empty()) {
+        return Err(APIError::new_str("An API key cannot be empty."));
+    }
+    Ok(api_keys)
+}
+
+/// How the engine and its worker processes run the model, from the same command line.
+fn worker_config(args: &EngineArgs) -> Result<WorkerConfig, APIError> {
+    let worker_config = WorkerConfig {
+        prefill_chunk_size: args.prefill_chunk_size,
+        control_vectors: args
+            .control_vectors
+            .iter()
+            .map(|arg| parse_control_vector_arg(arg))
+            .collect::<Result<_, _>>()?,
+    };
+    worker_config.verify_args()?;
+    Ok(worker_config)
+}
+
+/// Load the selected model and start an engine for it.
+async fn load_engine(
+    model: Option<ModelSelected>,
+    args: EngineArgs,
+) -> Result<(Arc<Mutex<LLMEngine>>, PipelineConfig), APIError> {
+    let (loader, paths, dtype) = resolve_model(model, &args)?;
+    // Workers load the same files, they are checked once here.
+    if args.verify_weights || args.weights_manifest.is_some() {
+        let manifest = args
+            .weights_manifest
+            .as_deref()
+            .map(WeightManifest::load)
+            .transpose()?;
+        verify_weights(paths.get_weight_filenames(), manifest.as_ref())?;
+    }
+    let weights = paths
+        .get_weight_filenames()
+        .iter()
+        .chain(paths.get_lora_adapter())
+        .cloned()
+        .collect::<Vec<_>>();
+    let weights_digest = weights_digest(&weights);
+    let policy = get_policy(&args.scheduling_policy).unwrap();
+    let scheduler_config = SchedulerConfig {
+        max_num_seqs: args.max_num_seqs,
+        max_num_prefill_tokens: args.max_num_prefill_tokens,
+        long_prefill_token_threshold: args.long_prefill_token_threshold,
+        max_long_prefills: args.max_long_prefills,
+        prompt_lookup: args.num_speculative_tokens.map(|num_speculative_tokens| {
+            PromptLookupConfig {
+                num_speculative_tokens,
+                max_ngram: args.prompt_lookup_max,
+                min_ngram: args.prompt_lookup_min,
+                num_branches: args.prompt_lookup_branches,
+                adaptive: args.adaptive_speculation,
+            }
         }),
-        _ => loader.download_model(model_id, None, args.hf_token, args.hf_token_path)?,
-    };
-
-    let dtype = match args.dtype.as_deref() {
-        Some("f16") => DType::F16,
-        Some("bf16") => DType::BF16,
-        Some("f32") => DType::F32,
-        Some(dtype) => panic!("Unsupported dtype {dtype}"),
-        None => DType::BF16,
-    };
-
-    let device = candle_examples::device(args.cpu).unwrap();
-    let model = loader.load_model(paths, dtype, device)?;
-    let config: Config = model.0.get_model_config();
-    let dsize = config.kv_cache_dtype.size_in_bytes();
-    let num_gpu_blocks = args.kvcache_mem_gpu * SIZE_IN_MB
-        / dsize
-        / args.block_size
-        / config.num_key_value_heads
-        / config.get_head_size()
-        / config.num_hidden_layers
-        / 2;
-    let num_cpu_blocks = args.kvcache_mem_cpu * SIZE_IN_MB
-        / dsize
-        / args.block_size
-        / config.num_key_value_heads
-        / config.get_head_size()
-        / config.num_hidden_layers
-        / 2;
-    let cache_config = CacheConfig {
-        block_size: args.block_size,
-        num_gpu_blocks: Some(num_gpu_blocks),
-        num_cpu_blocks: Some(num_cpu_blocks),
-        fully_init: true,
-        dtype: config.kv_cache_dtype,
+        batch_invariant: args.batch_invariant,
+        max_sessions: args.max_sessions,
+        compaction_blocks: args.compaction_blocks,
     };
+
+    if args.worker_devices.is_empty() {
+        let device = candle_examples::device(args.cpu).map_err(APIError::from)?;
+        let (pipeline, pipeline_config) =
+            loader.load_model(paths, dtype, device, args.weight_buffer_mem)?;
+        let cache_config = cache_config(&args, &pipeline.get_model_config())?;
+        println!("Cache config {:?}", cache_config);
+        let llm_engine = LLMEngine::new(
+            pipeline,
+            scheduler_config,
+            cache_config,
+            worker_config(&args)?,
+            Arc::new(Notify::new()),
+            Arc::new(Notify::new()),
+        )?;
+        let mut engine = llm_engine.lock().await;
+        engine.set_scheduling_policy(policy);
+        engine.set_weights_digest(weights_digest);
+        drop(engine);
+        return Ok((llm_engine, pipeline_config));
+    }
+
+    if !args.worker_numa_nodes.is_empty()
+        && args.worker_numa_nodes.len() != args.worker_devices.len()
+    {
+        return Err(APIError::new(format!(
+            "{} NUMA nodes were given for {} worker devices.",
+            args.worker_numa_nodes.len(),
+            args.worker_devices.len()
+        )));
+    }
+    // The server only tokenizes and samples, the workers load the weights.
+    let (pipeline, pipeline_config) = loader.load_model_without_weights(paths, dtype)?;
+    let cache_config = cache_config(&args, &pipeline.get_model_config())?;
     println!("Cache config {:?}", cache_config);
-    let finish_notify = Arc::new(Notify::new());
-    let llm_engine = LLMEngine::new(
-        model.0,
-        SchedulerConfig {
-            max_num_seqs: args.max_num_seqs,
-        },
+    // Checked before the workers load the model with it.
+    let worker_config = worker_config(&args)?;
+    worker_config.verify_model(&*pipeline)?;
+    if args.num_nodes == 0 {
+        return Err(APIError::new_str("At least one node is needed."));
+    }
+    let num_local_workers = args.worker_devices.len();
+    let world_size = num_local_workers * args.num_nodes;
+    if args.tensor_parallel {
+        check_tensor_parallel(pipeline.name(), &pipeline.get_model_config(), world_size)?;
+    }
+    // Bound before the local workers load, the other nodes retry until it is.
+    let listener = match (&args.master_addr, args.num_nodes) {
+        (Some(addr), num_nodes) if num_nodes > 1 => {
+            let secret = worker_secret()?;
+            let listener = std::net::TcpListener::bind(addr)
+                .map_err(|err| APIError::new(format!("Cannot listen on {addr}: {err}")))?;
+            Some((listener, secret))
+        }
+        _ => None,
+    };
+    let mut workers = Vec::new();
+    for (rank, ordinal) in args.worker_devices.iter().enumerate() {
+        let command = worker_command(&args, rank)?;
+        workers.push(WorkerProcess::spawn(rank, command)?);
+        println!("Worker process {rank} started on GPU {ordinal}");
+    }
+    if let Some((listener, secret)) = listener {
+        println!(
+            "Waiting for the worker processes of {} other nodes",
+            args.num_nodes - 1
+        );
+        workers.extend(WorkerProcess::accept_remote(
+            &listener,
+            num_local_workers..world_size,
+            &secret,
+        )?);
+    }
+    let llm_engine = LLMEngine::with_executor(
+        Box::new(MultiprocExecutor::new(
+            pipeline,
+            workers,
+            args.tensor_parallel,
+        )?),
+        scheduler_config,
         cache_config,
+        worker_config,
+        Arc::new(Notify::new()),
         Arc::new(Notify::new()),
-        finish_notify.clone(),
     )?;
+    let mut engine = llm_engine.lock().await;
+    engine.set_scheduling_policy(policy);
+    engine.set_weights_digest(weights_digest);
+    drop(engine);
+    Ok((llm_engine, pipeline_config))
+}
+
+/// The command running the worker process of the local GPU `index`: this same command line,
+/// which it loads the model from, pinned to the NUMA node of the GPU if one was given.
+fn worker_command(args: &EngineArgs, index: usize) -> Result<std::process::Command, APIError> {
+    let exe = std::env::current_exe().map_err(APIError::from)?;
+    let mut command = match args.worker_numa_nodes.get(index) {
+        Some(node) => {
+            let mut command = std::process::Command::new("numactl");
+            command
+                .arg(format!("--cpunodebind={node}"))
+                .arg(format!("--membind={node}"))
+                .arg(&exe);
+            command
+        }
+        None => std::process::Command::new(&exe),
+    };
+    command
+        .args(std::env::args_os().skip(1))
+        .env(WORKER_DEVICE_ENV, args.worker_devices[index].to_string());
+    Ok(command)
+}
+
+/// Run the worker processes of a node other than node 0, which connect to the engine at
+/// `master_addr`, until they exit.
+fn run_node(args: &EngineArgs) -> Result<(), APIError> {
+    if args.node_rank >= args.num_nodes {
+        return Err(APIError::new(format!(
+            "The node rank {} is out of the {} nodes.",
+            args.node_rank, args.num_nodes
+        )));
+    }
+    let Some(master_addr) = &args.master_addr else {
+        return Err(APIError::new_str("The other nodes need the master_addr."));
+    };
+    // Checked before the workers are spawned, which connect with it.
+    worker_secret()?;
+    let mut children = Vec::new();
+    for (index, ordinal) in args.worker_devices.iter().enumerate() {
+        let rank = args.node_rank * args.worker_devices.len() + index;
+        let mut command = worker_command(args, index)?;
+        command
+            .env(WORKER_ENV, format!("tcp://{master_addr}"))
+            .env(WORKER_RANK_ENV, rank.to_string());
+        children.push((rank, command.spawn().map_err(APIError::from)?));
+        println!("Worker process {rank} started on GPU {ordinal}, engine at {master_addr}");
+    }
+    let mut failed = None;
+    for (rank, mut child) in children {
+        let status = child.wait().map_err(APIError::from)?;
+        if !status.success() {
+            failed.get_or_insert(APIError::new(format!(
+                "Worker process {rank} exited: {status}."
+            )));
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Engine arguments of the commands loading the model.
+fn engine_args(command: &Command) -> Option<&EngineArgs> {
+    match command {
+        Command::Serve { engine, .. }
+        | Command::Generate { engine, .. }
+        | Command::Benchmark { engine, .. } => Some(engine),
+        Command::Download { .. } | Command::Plan { .. } => None,
+    }
+}
+
+/// Run the model, or a shard of it, on the GPU `ordinal` for the engine this process connects
+/// to.
+fn run_worker(command: Command, ordinal: usize) -> Result<(), APIError> {
+    let (engine, model) = match command {
+        Command::Serve { engine, model, .. }
+        | Command::Generate { engine, model, .. }
+        | Command::Benchmark { engine, model, .. } => (engine, model),
+        Command::Download { .. } | Command::Plan { .. } => {
+            return Err(APIError::new_str(
+                "Only commands loading the model run worker processes.",
+            ))
+        }
+    };
+    let mut connection = EngineConnection::connect()?;
+    let (loader, paths, dtype) = resolve_model(model, &engine)?;
+    let device = Device::new_cuda(ordinal).map_err(APIError::from)?;
+    let (pipeline, _) = if engine.tensor_parallel {
+        let parallel = connection.tensor_parallel(&device)?;
+        loader.load_model_shard(paths, dtype, device, engine.weight_buffer_mem, parallel)?
+    } else {
+        loader.load_model(paths, dtype, device, engine.weight_buffer_mem)?
+    };
+    let cache_config = cache_config(&engine, &pipeline.get_model_config())?;
+    let worker_config = worker_config(&engine)?;
+    serve_engine(
+        connection,
+        Worker::new(pipeline, &cache_config, &worker_config)?,
+    )
+}
+
+fn default_sampling_params(
+    pipeline_config: &PipelineConfig,
+    max_tokens: usize,
+) -> Result<SamplingParams, APIError> {
+    SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        pipeline_config.penalty,
+        pipeline_config.temperature,
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        vec![],
+        false,
+        max_tokens,
+        None,
+        None,
+        true,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    port: u16,
+    record_conversation: bool,
+    tokenizer_threads: usize,
+    admin_token: Option<String>,
+    max_concurrent_batch_requests: usize,
+    log_filter: LogFilterHandle,
+    request_log: RequestLogArgs,
+    watermark: WatermarkArgs,
+    moderation: ModerationArgs,
+    admission: AdmissionArgs,
+    response_cache: ResponseCacheArgs,
+    tls: TlsArgs,
+    telemetry: TelemetryArgs,
+    engine: EngineArgs,
+    model: Option<ModelSelected>,
+) -> Result<(), APIError> {
+    let admin_token = admin_token.or_else(|| std::env::var("CANDLE_VLLM_ADMIN_TOKEN").ok());
+    if admin_token.as_ref().is_some_and(|token| token.is_empty()) {
+        return Err(APIError::new_str("The admin token cannot be empty."));
+    }
+    let api_keys = api_keys(&admission)?;
+    if engine.scheduling_policy == "fair" && api_keys.is_empty() {
+        tracing::warn!(
+            "Without --api-keys every request shares one tenant of the fair scheduling policy."
+        );
+    }
+    if max_concurrent_batch_requests == 0 {
+        return Err(APIError::new_str(
+            "At least one request of a batch has to be in flight at once.",
+        ));
+    }
+    let logit_dumps = match admission.logit_dump_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir).map_err(|e| {
+                APIError::new(format!(
+                    "Cannot create the logit dump directory {}: {e}",
+                    dir.display()
+                ))
+            })?;
+            Some(LogitDumpDir {
+                dir,
+                max_bytes: admission.max_logit_dump_mb << 20,
+            })
+        }
+        None => None,
+    };
+    let watermark_key = match (
+        watermark.watermark_key,
+        std::env::var("CANDLE_VLLM_WATERMARK_KEY"),
+    ) {
+        (Some(key), _) => Some(key),
+        (None, Ok(key)) => Some(
+            key.parse::<u64>()
+                .map_err(|e| APIError::new(format!("Invalid CANDLE_VLLM_WATERMARK_KEY: {e}")))?,
+        ),
+        (None, Err(_)) => None,
+    };
+    let watermark = watermark_key
+        .map(|key| {
+            Watermark::new(WatermarkConfig {
+                key,
+                gamma: watermark.watermark_gamma,
+                delta: watermark.watermark_delta,
+            })
+        })
+        .transpose()?;
+    let moderators = moderation
+        .content_filters
+        .iter()
+        .map(|spec| RegexModerator::parse(spec, true))
+        .chain(
+            moderation
+                .content_flags
+                .iter()
+                .map(|spec| RegexModerator::parse(spec, false)),
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+    let tls_files = match (tls.tls_cert, tls.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+        _ => None,
+    };
+    // A bad certificate fails before the model loads.
+    let tls_config = match &tls_files {
+        Some(files) => Some(files.load().await?),
+        None => None,
+    };
+    let otlp_exporter = telemetry
+        .otlp_endpoint
+        .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+        .map(|endpoint| {
+            let service_name = telemetry
+                .otlp_service_name
+                .or_else(|| std::env::var("OTEL_SERVICE_NAME").ok())
+                .unwrap_or_else(|| "candle-vllm".to_string());
+            OtlpExporter::new(TelemetryConfig {
+                endpoint,
+                trace_sample_ratio: telemetry.otlp_trace_sample_ratio,
+                export_interval: Duration::from_secs(telemetry.otlp_export_interval_secs),
+                resource: vec![
+                    ("service.name".to_string(), service_name),
+                    (
+                        "service.version".to_string(),
+                        env!("CARGO_PKG_VERSION").to_string(),
+                    ),
+                ],
+            })
+        })
+        .transpose()?;
+    let (llm_engine, pipeline_config) = load_engine(model, engine).await?;
+    let (
+        finish_notify,
+        scheduler_trace,
+        scheduler_limits,
+        aborted_requests,
+        tokenizer,
+        control_vectors,
+        admissions,
+    ) = {
+        let mut engine = llm_engine.lock().await;
+        if let Some(watermark) = &watermark {
+            engine.add_logits_processor(Arc::new(watermark.clone()));
+        }
+        for moderator in moderators {
+            engine.add_moderator(Arc::new(moderator));
+        }
+        if let Some(exporter) = otlp_exporter {
+            let exporter = Arc::new(exporter.with_resource(
+                "candle_vllm.system_fingerprint",
+                engine.system_fingerprint_id().to_string(),
+            ));
+            engine.add_observer(exporter.clone());
+            exporter.spawn();
+        }
+        if let Some(path) = &request_log.request_log {
+            let (log, unfinished) = RequestLog::open(path)?;
+            engine.set_request_log(log);
+            if !unfinished.is_empty() {
+                println!(
+                    "{} requests were left unfinished in {}.",
+                    unfinished.len(),
+                    path.display()
+                );
+                engine.recover(unfinished, request_log.replay_requests);
+                engine.notify.notify_one();
+            }
+        }
+        (
+            engine.finish_notify.clone(),
+            engine.scheduler_trace.clone(),
+            engine.scheduler_limits.clone(),
+            engine.aborted_requests.clone(),
+            engine.get_pipeline().tokenizer().tokenizer().clone(),
+            engine.control_vector_names(),
+            engine.admission_queue(),
+        )
+    };
 
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
+        pipeline_config: std::sync::RwLock::new(pipeline_config),
         model: llm_engine,
-        record_conversation: args.record_conversation,
+        record_conversation,
         device: Device::Cpu,
-        finish_notify: finish_notify.clone(),
+        finish_notify,
+        scheduler_trace,
+        tokenizer_pool: TokenizerPool::new(tokenizer, tokenizer_threads)?,
+        scheduler_limits,
+        aborted_requests,
+        admin_token,
+        log_filter: Some(log_filter),
+        batches: BatchStore::new(max_concurrent_batch_requests),
+        watermark,
+        served_model_names: admission.served_model_name,
+        control_vectors,
+        max_waiting_requests: admission.max_waiting_requests,
+        cap_max_tokens_to_free_blocks: admission.cap_max_tokens_to_free_blocks,
+        reject_unknown_fields: admission.reject_unknown_fields,
+        max_logprobs: admission.max_logprobs,
+        logit_dumps,
+        admissions,
+        api_keys,
+        response_cache: (response_cache.response_cache_size > 0).then(|| {
+            ResponseCache::new(
+                response_cache.response_cache_size,
+                Duration::from_secs(response_cache.response_cache_ttl_secs),
+            )
+        }),
     };
 
-    println!("Server started at http://127.0.0.1:{}.", args.port);
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    println!("Server started at {scheme}://127.0.0.1:{}.", port);
 
     let allow_origin = AllowOrigin::any();
     let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
         .allow_headers([http::header::CONTENT_TYPE])
         .allow_origin(allow_origin);
 
     let app = Router::new()
         .layer(cors_layer)
         .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/completions", post(completions))
+        // Audio files are larger than the default limit of request bodies.
+        .route(
+            "/v1/audio/transcriptions",
+            post(audio_transcriptions).layer(DefaultBodyLimit::max(AUDIO_BODY_LIMIT)),
+        )
+        .route(
+            "/v1/audio/translations",
+            post(audio_translations).layer(DefaultBodyLimit::max(AUDIO_BODY_LIMIT)),
+        )
+        .route("/debug/scheduler", get(debug_scheduler))
+        .route("/cache_stats", get(cache_stats))
+        .route("/sleep", post(sleep))
+        .route("/wake", post(wake))
+        .route("/recovered_requests", get(recovered_requests))
+        .route("/v1/files", post(upload_file))
+        .route("/v1/files/:file_id", get(get_file))
+        .route("/v1/files/:file_id/content", get(get_file_content))
+        .route("/v1/batches", post(create_batch).get(list_batches))
+        .route("/v1/batches/:batch_id", get(get_batch))
+        .route("/v1/batches/:batch_id/cancel", post(cancel_batch))
+        .route("/v1/watermark/detect", post(detect_watermark))
+        .route("/v1/requests", get(list_requests))
+        .route("/v1/requests/:request_id", delete(abort_request))
+        .route(
+            "/admin/config",
+            get(get_admin_config).post(post_admin_config),
+        )
         .with_state(Arc::new(server_data));
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", args.port))
+    if let (Some(files), Some(config)) = (tls_files, tls_config) {
+        tls::reload_on_sighup(files, config.clone())?;
+        return tls::serve(([127, 0, 0, 1], port).into(), app, config).await;
+    }
+    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port))
         .await
         .map_err(|e| APIError::new(e.to_string()))?;
     axum::serve(listener, app)
@@ -178,3 +1232,238 @@ async fn main() -> Result<(), APIError> {
 
     Ok(())
 }
+
+async fn generate(
+    prompt_file: PathBuf,
+    max_tokens: Option<usize>,
+    raw: bool,
+    engine: EngineArgs,
+    model: Option<ModelSelected>,
+) -> Result<(), APIError> {
+    let prompt = std::fs::read_to_string(&prompt_file).map_err(APIError::from)?;
+    let (llm_engine, pipeline_config) = load_engine(model, engine).await?;
+    let mut engine = llm_engine.lock().await;
+
+    let prompt = if raw {
+        prompt
+    } else {
+        let conversation = engine.get_mut_pipeline().get_conversation(false);
+        conversation.append_message("user".to_string(), prompt);
+        conversation.get_prompt()
+    };
+    let token_ids = engine
+        .get_pipeline()
+        .tokenizer()
+        .tokenizer()
+        .encode(prompt, false)
+        .map_err(APIError::from)?;
+    // Without `max_tokens`, generation stops at the end of the context.
+    let max_tokens = max_tokens.unwrap_or(
+        pipeline_config.default_max_tokens.min(
+            pipeline_config
+                .max_model_len
+                .saturating_sub(token_ids.len())
+                .max(1),
+        ),
+    );
+    let sampling_params = default_sampling_params(&pipeline_config, max_tokens)?;
+
+    let (response_tx, rx) = flume::unbounded();
+    engine.add_request(
+        token_ids,
+        "generate-0".to_string(),
+        SystemTime::now(),
+        sampling_params,
+        false,
+        Some(response_tx),
+        None,
+    );
+    let printer = std::thread::spawn(move || {
+        while let Ok(ChatResponse::Chunk(chunk)) = rx.recv() {
+            if let Some(content) = chunk.choices.first().and_then(|c| c.delta.content.as_ref()) {
+                print!("{content}");
+                let _ = std::io::stdout().flush();
+            }
+        }
+        println!();
+    });
+    engine.generate_once()?;
+    drop(engine);
+    let _ = printer.join();
+    Ok(())
+}
+
+async fn benchmark(
+    args: BenchmarkArgs,
+    engine: EngineArgs,
+    model: Option<ModelSelected>,
+) -> Result<(), APIError> {
+    let (llm_engine, pipeline_config) = load_engine(model, engine).await?;
+    let requests = {
+        let engine = llm_engine.lock().await;
+        let tokenizer = engine.get_pipeline().tokenizer().tokenizer();
+        match &args.dataset {
+            Some(path) => load_sharegpt_dataset(
+                path,
+                tokenizer,
+                args.num_requests,
+                pipeline_config.max_model_len,
+                args.seed,
+            )?,
+            None => synthetic_requests(
+                tokenizer,
+                args.num_requests,
+                args.prompt_len,
+                args.max_tokens,
+            )?,
+        }
+    };
+    let report = run_benchmark(
+        llm_engine,
+        &pipeline_config,
+        requests,
+        args.request_rate,
+        args.seed,
+    )
+    .await?;
+    println!("\r\n{report}");
+    Ok(())
+}
+
+fn download(
+    revision: Option<String>,
+    args: ModelArgs,
+    model: Option<ModelSelected>,
+) -> Result<(), APIError> {
+    let model = select_model(model, &args, None)?;
+    let (loader, model_id) = get_model_loader(model, args.model_id);
+    let paths = loader.download_model(model_id, revision, args.hf_token, args.hf_token_path)?;
+    println!("Config: {}", paths.get_config_filename().display());
+    println!("Tokenizer: {}", paths.get_tokenizer_filename().display());
+    for filename in paths.get_weight_filenames() {
+        println!("Weights: {}", filename.display());
+    }
+    Ok(())
+}
+
+fn plan(
+    args: PlanArgs,
+    model_args: ModelArgs,
+    weight_path: Option<String>,
+    model: Option<ModelSelected>,
+) -> Result<(), APIError> {
+    let model = select_model(model, &model_args, weight_path.as_ref())?;
+    let name = model.to_string();
+    let config_filename = match &weight_path {
+        Some(path) => (path.to_owned() + "config.json").into(),
+        None => {
+            let (_, model_id) = get_model_loader(model, model_args.model_id);
+            download_config(
+                model_id,
+                None,
+                model_args.hf_token,
+                model_args.hf_token_path,
+            )?
+        }
+    };
+    let dtype = get_config_dtype(args.dtype.as_deref(), &config_filename)?;
+    let mut config = load_config(&name, &config_filename, dtype)?.config;
+    config.kv_cache_dtype = get_kv_cache_dtype(args.kv_cache_dtype.as_deref(), dtype)?;
+    let plan = planning::plan(
+        &config,
+        &PlanConfig {
+            dtype,
+            quantization: args.quantization,
+            gpu_mem: args.gpu_mem,
+            reserved_mem: args.reserved_mem,
+            block_size: args.block_size,
+            max_num_seqs: args.max_num_seqs,
+            context_len: args.context_len,
+        },
+    )?;
+    println!("{plan}");
+    Ok(())
+}
+
+async fn run(command: Command, log_filter: LogFilterHandle) -> Result<(), APIError> {
+    match command {
+        Command::Serve {
+            port,
+            verbose: _,
+            record_conversation,
+            tokenizer_threads,
+            admin_token,
+            max_concurrent_batch_requests,
+            request_log,
+            watermark,
+            moderation,
+            admission,
+            response_cache,
+            tls,
+            telemetry,
+            engine,
+            model,
+        } => {
+            serve(
+                port,
+                record_conversation,
+                tokenizer_threads,
+                admin_token,
+                max_concurrent_batch_requests,
+                log_filter,
+                request_log,
+                watermark,
+                moderation,
+                admission,
+                response_cache,
+                tls,
+                telemetry,
+                engine,
+                model,
+            )
+            .await
+        }
+        Command::Generate {
+            prompt_file,
+            max_tokens,
+            raw,
+            engine,
+            model,
+        } => generate(prompt_file, max_tokens, raw, engine, model).await,
+        Command::Benchmark {
+            benchmark: args,
+            engine,
+            model,
+        } => benchmark(args, engine, model).await,
+        Command::Download {
+            revision,
+            model_args,
+            model,
+        } => download(revision, model_args, model),
+        Command::Plan {
+            plan: args,
+            model_args,
+            weight_path,
+            model,
+        } => plan(args, model_args, weight_path, model),
+    }
+}
+
+fn main() -> Result<(), APIError> {
+    let args = Args::parse();
+    let verbose = matches!(args.command, Command::Serve { verbose: true, .. });
+    let log_filter = init_logging(args.log_format, verbose)?;
+    if let Ok(ordinal) = std::env::var(WORKER_DEVICE_ENV) {
+        let ordinal = ordinal.parse::<usize>().map_err(APIError::from)?;
+        return run_worker(args.command, ordinal);
+    }
+    if let Some(engine) = engine_args(&args.command).filter(|engine| engine.node_rank > 0) {
+        return run_node(engine);
+    }
+    let runtime = tokio::runtime::Runtime::new().map_err(APIError::from)?;
+    let result = runtime.block_on(run(args.command, log_filter));
+    // The engine loop occupies a blocking thread for the lifetime of the process, waiting for it
+    // would never return once `generate` or `benchmark` are done.
+    runtime.shutdown_background();
+    result
+}
@@ -0,0 +1,235 @@
+//! Preprocessing of audio inputs for speech recognition models. Audio is decoded from WAV, mixed
+//! down to mono and resampled to 16 kHz, then cut in windows of 30 seconds, each of which is
+//! turned into the log-mel spectrogram Whisper was trained on and transcribed by a request of
+//! its own.
+use super::responses::APIError;
+use crate::try_api;
+use candle_core::{DType, Device, Tensor};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+pub const SAMPLE_RATE: usize = 16000;
+pub const N_FFT: usize = 400;
+pub const HOP_LENGTH: usize = 160;
+/// Length of the windows transcribed at once, in seconds.
+pub const CHUNK_LENGTH: usize = 30;
+pub const N_SAMPLES: usize = SAMPLE_RATE * CHUNK_LENGTH;
+/// Frames of the spectrogram of a window.
+pub const N_FRAMES: usize = N_SAMPLES / HOP_LENGTH;
+
+#[derive(Debug, Clone)]
+pub struct AudioProcessor {
+    pub num_mel_bins: usize,
+    /// `(num_mel_bins, N_FFT / 2 + 1)` filters of the frequencies of the spectrogram.
+    mel_filters: Vec<f32>,
+}
+
+impl AudioProcessor {
+    pub fn new(num_mel_bins: usize) -> Self {
+        Self {
+            num_mel_bins,
+            mel_filters: mel_filters(num_mel_bins),
+        }
+    }
+
+    /// Samples of a WAV file, mixed down to mono and resampled to `SAMPLE_RATE`. Integer PCM of
+    /// 8 to 32 bits and 32-bit float samples are supported, compressed formats are not.
+    pub fn decode_wav(bytes: &[u8]) -> Result<Vec<f32>, APIError> {
+        if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(APIError::new_str(
+                "Only WAV audio files are supported, convert the audio first.",
+            ));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut format = None;
+        let mut data = None;
+        let mut i = 12;
+        while i + 8 <= bytes.len() {
+            let size = u32_at(i + 4) as usize;
+            let body = &bytes[i + 8..(i + 8 + size).min(bytes.len())];
+            match &bytes[i..i + 4] {
+                b"fmt " if body.len() >= 16 => {
+                    let mut tag = u16_at(i + 8);
+                    // WAVE_FORMAT_EXTENSIBLE, the format is the start of the sub format GUID.
+                    if tag == 0xFFFE && body.len() >= 26 {
+                        tag = u16_at(i + 8 + 24);
+                    }
+                    let channels = u16_at(i + 10) as usize;
+                    let sample_rate = u32_at(i + 12) as usize;
+                    let bits = u16_at(i + 22) as usize;
+                    format = Some((tag, channels, sample_rate, bits));
+                }
+                b"data" => data = Some(body),
+                _ => {}
+            }
+            // Chunks are padded to an even size.
+            i += 8 + size + size % 2;
+        }
+        let (Some((tag, channels, sample_rate, bits)), Some(data)) = (format, data) else {
+            return Err(APIError::new_str("The WAV file has no format or no data."));
+        };
+        if channels == 0 || sample_rate == 0 {
+            return Err(APIError::new_str("The WAV file has no channel."));
+        }
+        let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+            (1, 8) => |b| (b[0] as f32 - 128.) / 128.,
+            (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.,
+            (1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.,
+            (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.,
+            (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            _ => {
+                return Err(APIError::new(format!(
+                    "WAV files of format {tag} with {bits} bit samples are not supported."
+                )))
+            }
+        };
+        let frame_size = channels * bits / 8;
+        let samples = data
+            .chunks_exact(frame_size)
+            .map(|frame| {
+                let sum: f32 = frame.chunks_exact(bits / 8).map(decode).sum();
+                sum / channels as f32
+            })
+            .collect::<Vec<_>>();
+        Ok(resample(&samples, sample_rate, SAMPLE_RATE))
+    }
+
+    /// Windows of `CHUNK_LENGTH` seconds of `samples`, the last one may be shorter.
+    pub fn chunks(samples: &[f32]) -> impl Iterator<Item = &[f32]> {
+        samples.chunks(N_SAMPLES)
+    }
+
+    /// Log-mel spectrogram of at most `CHUNK_LENGTH` seconds of `samples`, padded with silence,
+    /// of shape `(1, num_mel_bins, N_FRAMES)`.
+    pub fn preprocess(
+        &self,
+        samples: &[f32],
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Tensor, APIError> {
+        let mel = self.log_mel_spectrogram(samples);
+        let mel = try_api!(Tensor::from_vec(
+            mel,
+            (1, self.num_mel_bins, N_FRAMES),
+            device
+        ));
+        Ok(try_api!(mel.to_dtype(dtype)))
+    }
+
+    /// The spectrogram of the Whisper reference implementation: power spectrum of a centered
+    /// STFT with a Hann window, mel filters, log10 clamped to 8 below its maximum, rescaled.
+    fn log_mel_spectrogram(&self, samples: &[f32]) -> Vec<f32> {
+        let mut audio = samples[..samples.len().min(N_SAMPLES)].to_vec();
+        audio.resize(N_SAMPLES, 0.);
+        // Frames are centered on their sample, the audio is reflected at both ends.
+        let pad = N_FFT / 2;
+        let mut padded = Vec::with_capacity(N_SAMPLES + 2 * pad);
+        padded.extend((1..=pad).rev().map(|i| audio[i]));
+        padded.extend(&audio);
+        padded.extend((N_SAMPLES - 1 - pad..N_SAMPLES - 1).rev().map(|i| audio[i]));
+
+        let window = (0..N_FFT)
+            .map(|n| 0.5 - 0.5 * (2. * PI * n as f32 / N_FFT as f32).cos())
+            .collect::<Vec<_>>();
+        let (cos, sin): (Vec<f32>, Vec<f32>) = (0..N_FFT)
+            .map(|n| {
+                let angle = 2. * PI * n as f32 / N_FFT as f32;
+                (angle.cos(), angle.sin())
+            })
+            .unzip();
+        let num_freqs = N_FFT / 2 + 1;
+        let power = (0..N_FRAMES)
+            .into_par_iter()
+            .flat_map_iter(|frame| {
+                let frame = &padded[frame * HOP_LENGTH..frame * HOP_LENGTH + N_FFT];
+                let frame = zip_mul(frame, &window);
+                let (cos, sin) = (&cos, &sin);
+                (0..num_freqs).map(move |k| {
+                    let (mut re, mut im) = (0f32, 0f32);
+                    for (n, x) in frame.iter().enumerate() {
+                        let i = (k * n) % N_FFT;
+                        re += x * cos[i];
+                        im -= x * sin[i];
+                    }
+                    re * re + im * im
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut mel = vec![0f32; self.num_mel_bins * N_FRAMES];
+        for (m, filter) in self.mel_filters.chunks_exact(num_freqs).enumerate() {
+            for (frame, power) in power.chunks_exact(num_freqs).enumerate() {
+                let energy: f32 = filter.iter().zip(power).map(|(f, p)| f * p).sum();
+                mel[m * N_FRAMES + frame] = energy.max(1e-10).log10();
+            }
+        }
+        let max = mel.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        mel.iter().map(|&x| (x.max(max - 8.) + 4.) / 4.).collect()
+    }
+}
+
+fn zip_mul(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter().zip(b).map(|(a, b)| a * b).collect()
+}
+
+/// Linear interpolation of `samples` from `from` to `to` Hz.
+fn resample(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let len = samples.len() * to / from;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * from as f64 / to as f64;
+            let j = position as usize;
+            let frac = (position - j as f64) as f32;
+            let next = samples.get(j + 1).copied().unwrap_or(samples[j]);
+            samples[j] * (1. - frac) + next * frac
+        })
+        .collect()
+}
+
+/// Mel scale of librosa (Slaney), linear below 1 kHz and logarithmic above.
+fn hz_to_mel(hz: f64) -> f64 {
+    let f_sp = 200. / 3.;
+    let log_step = 6.4f64.ln() / 27.;
+    if hz >= 1000. {
+        1000. / f_sp + (hz / 1000.).ln() / log_step
+    } else {
+        hz / f_sp
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    let f_sp = 200. / 3.;
+    let log_step = 6.4f64.ln() / 27.;
+    let min_log_mel = 1000. / f_sp;
+    if mel >= min_log_mel {
+        1000. * (log_step * (mel - min_log_mel)).exp()
+    } else {
+        mel * f_sp
+    }
+}
+
+/// The triangular filters of `librosa.filters.mel(sr=16000, n_fft=400, n_mels=num_mel_bins)`,
+/// normalized to a constant energy per band, which Whisper was trained with.
+fn mel_filters(num_mel_bins: usize) -> Vec<f32> {
+    let num_freqs = N_FFT / 2 + 1;
+    let max_mel = hz_to_mel(SAMPLE_RATE as f64 / 2.);
+    let edges = (0..num_mel_bins + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (num_mel_bins + 1) as f64))
+        .collect::<Vec<_>>();
+    let mut filters = vec![0f32; num_mel_bins * num_freqs];
+    for m in 0..num_mel_bins {
+        let (lower, center, upper) = (edges[m], edges[m + 1], edges[m + 2]);
+        let norm = 2. / (upper - lower);
+        for k in 0..num_freqs {
+            let hz = (k * SAMPLE_RATE) as f64 / N_FFT as f64;
+            let weight = ((hz - lower) / (center - lower)).min((upper - hz) / (upper - center));
+            filters[m * num_freqs + k] = (weight.max(0.) * norm) as f32;
+        }
+    }
+    filters
+}
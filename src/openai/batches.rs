@@ -0,0 +1,312 @@
+//! Offline batches in the shape of the OpenAI Batch API. The requests of an uploaded JSONL file
+//! run at low priority, behind the interactive requests, and their responses are written to an
+//! output file. Files and batches are kept in memory for the lifetime of the server.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::utils::get_created_time_secs;
+
+/// Endpoints the requests of a batch can be sent to.
+pub const BATCH_ENDPOINTS: [&str; 2] = ["/v1/chat/completions", "/v1/completions"];
+/// The only completion window of the OpenAI Batch API, it is not enforced.
+pub const COMPLETION_WINDOW: &str = "24h";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    pub bytes: usize,
+    pub created_at: u64,
+    pub filename: String,
+    /// `batch` for the input of a batch, `batch_output` for its output and error files.
+    pub purpose: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchRequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+/// Why the input file of a batch was rejected, `line` counts from 1.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchError {
+    pub code: String,
+    pub message: String,
+    pub line: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchErrors {
+    pub object: String,
+    pub data: Vec<BatchError>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub id: String,
+    pub object: String,
+    pub endpoint: String,
+    pub errors: Option<BatchErrors>,
+    pub input_file_id: String,
+    pub completion_window: String,
+    pub status: BatchStatus,
+    /// Responses of the requests that succeeded, once the batch is done.
+    pub output_file_id: Option<String>,
+    /// Responses of the requests that failed, if any.
+    pub error_file_id: Option<String>,
+    pub created_at: u64,
+    pub in_progress_at: Option<u64>,
+    pub finalizing_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub cancelling_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    pub request_counts: BatchRequestCounts,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchList {
+    pub object: String,
+    pub data: Vec<Batch>,
+}
+
+/// A line of the input file of a batch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchInput {
+    pub custom_id: String,
+    pub method: String,
+    pub url: String,
+    pub body: serde_json::Value,
+}
+
+/// A line of the output or error file of a batch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchOutput {
+    pub id: String,
+    pub custom_id: String,
+    pub response: BatchOutputResponse,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchOutputResponse {
+    pub status_code: u16,
+    pub request_id: String,
+    pub body: serde_json::Value,
+}
+
+/// Parse the input file of a batch whose requests go to `endpoint`. Every invalid line is
+/// reported, the batch fails if there is any.
+pub fn parse_batch_input(
+    content: &[u8],
+    endpoint: &str,
+) -> Result<Vec<BatchInput>, Vec<BatchError>> {
+    let error = |code: &str, message: String, line: usize| BatchError {
+        code: code.to_string(),
+        message,
+        line: Some(line),
+    };
+    let content = match std::str::from_utf8(content) {
+        Ok(content) => content,
+        Err(e) => {
+            return Err(vec![BatchError {
+                code: "invalid_json_line".to_string(),
+                message: format!("The input file is not UTF-8: {e}"),
+                line: None,
+            }])
+        }
+    };
+    let mut inputs = Vec::new();
+    let mut errors = Vec::new();
+    let mut custom_ids = HashSet::new();
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let input: BatchInput = match serde_json::from_str(line) {
+            Ok(input) => input,
+            Err(e) => {
+                errors.push(error("invalid_json_line", e.to_string(), line_number));
+                continue;
+            }
+        };
+        if input.method != "POST" {
+            errors.push(error(
+                "invalid_method",
+                format!("Requests must use POST, got `{}`.", input.method),
+                line_number,
+            ));
+        } else if input.url != endpoint {
+            errors.push(error(
+                "invalid_url",
+                format!(
+                    "Requests must be sent to `{endpoint}` like the batch, got `{}`.",
+                    input.url
+                ),
+                line_number,
+            ));
+        } else if !custom_ids.insert(input.custom_id.clone()) {
+            errors.push(error(
+                "duplicate_custom_id",
+                format!(
+                    "The custom_id `{}` is used more than once.",
+                    input.custom_id
+                ),
+                line_number,
+            ));
+        } else {
+            inputs.push(input);
+        }
+    }
+    if inputs.is_empty() && errors.is_empty() {
+        errors.push(BatchError {
+            code: "empty_file".to_string(),
+            message: "The input file holds no request.".to_string(),
+            line: None,
+        });
+    }
+    if errors.is_empty() {
+        Ok(inputs)
+    } else {
+        Err(errors)
+    }
+}
+
+struct StoredFile {
+    object: FileObject,
+    content: Bytes,
+}
+
+/// Files and batches of the server.
+pub struct BatchStore {
+    /// Requests of a batch in flight at once.
+    pub max_concurrent_requests: usize,
+    files: Mutex<HashMap<String, StoredFile>>,
+    batches: Mutex<Vec<Batch>>,
+}
+
+impl BatchStore {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        Self {
+            max_concurrent_requests,
+            files: Mutex::new(HashMap::new()),
+            batches: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add_file(&self, filename: String, purpose: String, content: Bytes) -> FileObject {
+        let object = FileObject {
+            id: format!("file-{}", Uuid::new_v4()),
+            object: "file".to_string(),
+            bytes: content.len(),
+            created_at: get_created_time_secs(),
+            filename,
+            purpose,
+        };
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.insert(
+            object.id.clone(),
+            StoredFile {
+                object: object.clone(),
+                content,
+            },
+        );
+        object
+    }
+
+    pub fn file(&self, file_id: &str) -> Option<FileObject> {
+        let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.get(file_id).map(|file| file.object.clone())
+    }
+
+    pub fn file_content(&self, file_id: &str) -> Option<Bytes> {
+        let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.get(file_id).map(|file| file.content.clone())
+    }
+
+    /// A new batch, validating the input file `input_file_id`.
+    pub fn add_batch(
+        &self,
+        input_file_id: String,
+        endpoint: String,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Batch {
+        let batch = Batch {
+            id: format!("batch_{}", Uuid::new_v4()),
+            object: "batch".to_string(),
+            endpoint,
+            errors: None,
+            input_file_id,
+            completion_window: COMPLETION_WINDOW.to_string(),
+            status: BatchStatus::Validating,
+            output_file_id: None,
+            error_file_id: None,
+            created_at: get_created_time_secs(),
+            in_progress_at: None,
+            finalizing_at: None,
+            completed_at: None,
+            failed_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: BatchRequestCounts::default(),
+            metadata,
+        };
+        let mut batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+        batches.push(batch.clone());
+        batch
+    }
+
+    pub fn batch(&self, batch_id: &str) -> Option<Batch> {
+        let batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+        batches.iter().find(|batch| batch.id == batch_id).cloned()
+    }
+
+    /// All the batches, the most recent first.
+    pub fn batches(&self) -> Vec<Batch> {
+        let batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+        batches.iter().rev().cloned().collect()
+    }
+
+    /// Apply `update` to the batch `batch_id` and return it.
+    pub fn update_batch(&self, batch_id: &str, update: impl FnOnce(&mut Batch)) -> Option<Batch> {
+        let mut batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+        let batch = batches.iter_mut().find(|batch| batch.id == batch_id)?;
+        update(batch);
+        Some(batch.clone())
+    }
+
+    /// Stop sending the requests of the batch `batch_id`, the ones in flight still finish. A
+    /// batch that is done is left as it is.
+    pub fn cancel_batch(&self, batch_id: &str) -> Option<Batch> {
+        self.update_batch(batch_id, |batch| {
+            if matches!(
+                batch.status,
+                BatchStatus::Validating | BatchStatus::InProgress
+            ) {
+                batch.status = BatchStatus::Cancelling;
+                batch.cancelling_at = Some(get_created_time_secs());
+            }
+        })
+    }
+}
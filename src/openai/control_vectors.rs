@@ -0,0 +1,214 @@
+//! Control vectors, which steer the style of the output without fine-tuning the model: a
+//! direction added to the hidden states after chosen decoder layers, scaled by the strength a
+//! request gives it. They are read from the GGUF files of llama.cpp and repeng, or from
+//! safetensors, in which the tensor `direction.{i}` is added to the output of layer `i`.
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use super::{models::Config, responses::APIError};
+use crate::try_api;
+
+const DIRECTION_PREFIX: &str = "direction.";
+
+/// A control vector of the server a request steers with, by its name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControlVectorStrength {
+    pub name: String,
+    /// Scale of the directions, negative to steer the other way.
+    #[serde(default = "default_strength")]
+    pub strength: f32,
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+/// Name and file of a `--control-vector name=path` argument.
+pub fn parse_control_vector_arg(arg: &str) -> Result<(String, PathBuf), APIError> {
+    match arg.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(APIError::new(format!(
+            "A control vector is given as name=path, got {arg}."
+        ))),
+    }
+}
+
+/// The directions of a control vector by layer, `(hidden_size,)` f32 tensors on the CPU.
+#[derive(Clone, Debug)]
+pub struct ControlVector {
+    directions: HashMap<usize, Tensor>,
+}
+
+impl ControlVector {
+    /// Read the directions of a `.gguf` file, or else of a safetensors file.
+    pub fn load(path: &Path) -> Result<Self, APIError> {
+        let tensors = if path.extension().is_some_and(|ext| ext == "gguf") {
+            read_gguf(path)?
+        } else {
+            try_api!(candle_core::safetensors::load(path, &Device::Cpu))
+        };
+        let mut directions = HashMap::new();
+        for (name, tensor) in tensors {
+            let Some(layer) = name.strip_prefix(DIRECTION_PREFIX) else {
+                continue;
+            };
+            let layer = layer.parse::<usize>().map_err(|_| {
+                APIError::new(format!(
+                    "{}: {name} is not the direction of a layer.",
+                    path.display()
+                ))
+            })?;
+            let direction = try_api!(try_api!(tensor.to_dtype(DType::F32)).flatten_all());
+            directions.insert(layer, direction);
+        }
+        if directions.is_empty() {
+            return Err(APIError::new(format!(
+                "{} holds no {DIRECTION_PREFIX}{{layer}} tensor.",
+                path.display()
+            )));
+        }
+        Ok(Self { directions })
+    }
+
+    /// Layers the control vector steers.
+    pub fn layers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.directions.keys().copied()
+    }
+
+    pub fn direction(&self, layer: usize) -> Option<&Tensor> {
+        self.directions.get(&layer)
+    }
+
+    /// Fails unless every direction is for a layer of the model, of its hidden size.
+    fn check(&self, name: &str, config: &Config) -> Result<(), APIError> {
+        for (&layer, direction) in &self.directions {
+            if layer >= config.num_hidden_layers {
+                return Err(APIError::new(format!(
+                    "Control vector {name} steers layer {layer}, the model has {} layers.",
+                    config.num_hidden_layers
+                )));
+            }
+            if direction.elem_count() != config.hidden_size {
+                return Err(APIError::new(format!(
+                    "The direction of control vector {name} for layer {layer} is of size {}, the \
+                     hidden size of the model is {}.",
+                    direction.elem_count(),
+                    config.hidden_size
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_gguf(path: &Path) -> Result<HashMap<String, Tensor>, APIError> {
+    let mut file = try_api!(File::open(path));
+    let content = try_api!(gguf_file::Content::read(&mut file));
+    let mut tensors = HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let tensor = try_api!(content.tensor(&mut file, name, &Device::Cpu));
+        tensors.insert(name.clone(), try_api!(tensor.dequantize(&Device::Cpu)));
+    }
+    Ok(tensors)
+}
+
+/// The control vectors of the server by name, which a worker adds to the hidden states of the
+/// sequences steering with them.
+#[derive(Clone, Debug, Default)]
+pub struct ControlVectors {
+    vectors: HashMap<String, ControlVector>,
+    hidden_size: usize,
+}
+
+impl ControlVectors {
+    /// Read the control vectors `files`, by name, for the model of `config`.
+    pub fn load(files: &[(String, PathBuf)], config: &Config) -> Result<Self, APIError> {
+        let mut vectors = HashMap::new();
+        for (name, path) in files {
+            let vector = ControlVector::load(path)?;
+            vector.check(name, config)?;
+            vectors.insert(name.clone(), vector);
+        }
+        Ok(Self {
+            vectors,
+            hidden_size: config.hidden_size,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /// What is added to the hidden states of a step whose sequences steer with `strengths`,
+    /// each over `num_rows` rows: one row per prompt during prefill, added to all of its tokens,
+    /// else one row per token. `None` when none of them steers.
+    pub fn steering(
+        &self,
+        seqs: &[(&[ControlVectorStrength], usize)],
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Option<Steering>, APIError> {
+        let mut layers = seqs
+            .iter()
+            .flat_map(|(strengths, _)| *strengths)
+            .filter_map(|strength| self.vectors.get(&strength.name))
+            .flat_map(ControlVector::layers)
+            .collect::<Vec<_>>();
+        if layers.is_empty() {
+            return Ok(None);
+        }
+        layers.sort_unstable();
+        layers.dedup();
+
+        let mut steering = HashMap::new();
+        for layer in layers {
+            let mut rows = Vec::new();
+            for (strengths, num_rows) in seqs {
+                let mut row = try_api!(Tensor::zeros(self.hidden_size, DType::F32, &Device::Cpu));
+                for strength in *strengths {
+                    let direction = self
+                        .vectors
+                        .get(&strength.name)
+                        .and_then(|vector| vector.direction(layer));
+                    if let Some(direction) = direction {
+                        let scaled = try_api!(direction.affine(strength.strength as f64, 0.));
+                        row = try_api!(row.add(&scaled));
+                    }
+                }
+                rows.extend(std::iter::repeat(row).take(*num_rows));
+            }
+            let rows = try_api!(Tensor::stack(&rows, 0));
+            let rows = try_api!(rows.unsqueeze(1));
+            steering.insert(
+                layer,
+                try_api!(try_api!(rows.to_dtype(dtype)).to_device(device)),
+            );
+        }
+        Ok(Some(Steering { layers: steering }))
+    }
+}
+
+/// The `(num_rows, 1, hidden_size)` additions to the hidden states of a step after each steered
+/// layer, see `ControlVectors::steering`.
+#[derive(Clone, Debug)]
+pub struct Steering {
+    layers: HashMap<usize, Tensor>,
+}
+
+impl Steering {
+    /// `xs`, the output of `layer`, steered.
+    pub fn apply(&self, layer: usize, xs: Tensor) -> candle_core::Result<Tensor> {
+        match self.layers.get(&layer) {
+            Some(steering) => xs.broadcast_add(steering),
+            None => Ok(xs),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Fill-in-the-middle prompts for code models. The `prompt` and `suffix` of a completion request
+//! are wrapped, in prefix-suffix-middle order, in the sentinel tokens the model was trained with,
+//! and the model generates the code that goes between them.
+use tokenizers::Tokenizer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FimTemplate {
+    StarCoder,
+    CodeLlama,
+    DeepSeekCoder,
+}
+
+impl FimTemplate {
+    /// Prefix, suffix and middle sentinel tokens, as they appear in the vocabulary.
+    pub fn sentinels(&self) -> [&'static str; 3] {
+        match self {
+            Self::StarCoder => ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+            Self::CodeLlama => ["▁<PRE>", "▁<SUF>", "▁<MID>"],
+            Self::DeepSeekCoder => ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+        }
+    }
+
+    /// Token ending the middle part, for models that do not end it with their eos token.
+    pub fn end_token(&self) -> Option<&'static str> {
+        match self {
+            Self::CodeLlama => Some("▁<EOT>"),
+            Self::StarCoder | Self::DeepSeekCoder => None,
+        }
+    }
+
+    /// The template whose sentinel tokens are all in the vocabulary of `tokenizer`, if any.
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        [Self::StarCoder, Self::CodeLlama, Self::DeepSeekCoder]
+            .into_iter()
+            .find(|template| {
+                template
+                    .sentinels()
+                    .iter()
+                    .all(|token| tokenizer.token_to_id(token).is_some())
+            })
+    }
+
+    /// Prompt asking for the code between `prefix` and `suffix`. Prompts are encoded without
+    /// special tokens, so the bos token is part of the prompt for the models trained with one.
+    pub fn prompt(&self, prefix: &str, suffix: &str) -> String {
+        let [pre, suf, mid] = self.sentinels();
+        match self {
+            Self::StarCoder => format!("{pre}{prefix}{suf}{suffix}{mid}"),
+            // The sentinels of CodeLlama carry the space in front of them, the prefix is
+            // separated from `<PRE>` by one more.
+            Self::CodeLlama => format!("<s>{pre} {prefix}{suf}{suffix}{mid}"),
+            Self::DeepSeekCoder => {
+                format!("<｜begin▁of▁sentence｜>{pre}{prefix}{suf}{suffix}{mid}")
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! `system_fingerprint` of the responses, a digest of what of the serving stack the output of a
+//! request depends on besides its sampling parameters: the weights, the dtypes they and the KV
+//! cache are run in, the tensor parallel layout and the kernels of the build. Outputs which
+//! differ under the same fingerprint come from the batching, not from a change of the server.
+use std::{fmt, path::PathBuf};
+
+use candle_core::DType;
+use sha2::{Digest, Sha256};
+
+use super::pipelines::integrity::hub_digest;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SystemFingerprint {
+    /// Name of the model architecture.
+    pub model: String,
+    /// Digest of the weight files, see `weights_digest`. `None` when the files the model was
+    /// loaded from are not known.
+    pub weights: Option<String>,
+    pub dtype: DType,
+    pub kv_cache_dtype: DType,
+    /// Ranks the model is sharded over, 1 for a model run whole.
+    pub tensor_parallel_size: usize,
+    /// Version of the server and the kernels it was built with.
+    pub kernels: String,
+}
+
+impl SystemFingerprint {
+    /// The `system_fingerprint` of the responses, `fp_` and the first hex digits of the
+    /// SHA-256 of the fingerprint, as OpenAI formats it.
+    pub fn id(&self) -> String {
+        let digest = format!("{:x}", Sha256::digest(self.to_string().as_bytes()));
+        format!("fp_{}", &digest[..10])
+    }
+}
+
+impl fmt::Display for SystemFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "model={} weights={} dtype={} kv_cache_dtype={} tensor_parallel={} kernels={}",
+            self.model,
+            self.weights.as_deref().unwrap_or("unknown"),
+            self.dtype.as_str(),
+            self.kv_cache_dtype.as_str(),
+            self.tensor_parallel_size,
+            self.kernels
+        )
+    }
+}
+
+/// Version of the server and the kernels it runs: the compiled backends, and flash attention
+/// when the model uses it.
+pub fn kernels(use_flash_attn: bool) -> String {
+    let backends = [
+        ("cuda", cfg!(feature = "cuda")),
+        ("cudnn", cfg!(feature = "cudnn")),
+        ("mkl", cfg!(feature = "mkl")),
+        ("accelerate", cfg!(feature = "accelerate")),
+        ("nccl", cfg!(feature = "nccl")),
+        ("flash-attn", use_flash_attn),
+    ];
+    let backends = backends
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    if backends.is_empty() {
+        format!("{}+cpu", env!("CARGO_PKG_VERSION"))
+    } else {
+        format!("{}+{}", env!("CARGO_PKG_VERSION"), backends.join(","))
+    }
+}
+
+/// Digest of the weight files `files`, a LoRA adapter among them. Files of the hub are known by
+/// their SHA-256, the others by their name, size and modification time, so that the weights
+/// are not read a second time.
+pub fn weights_digest(files: &[PathBuf]) -> String {
+    let mut files = files.to_vec();
+    files.sort();
+    files.dedup();
+    let mut hasher = Sha256::new();
+    for file in &files {
+        let digest = hub_digest(file).unwrap_or_else(|| {
+            let metadata = std::fs::metadata(file).ok();
+            let modified = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_secs());
+            format!(
+                "{}:{}:{modified}",
+                file.file_name().unwrap_or_default().to_string_lossy(),
+                metadata.map_or(0, |metadata| metadata.len())
+            )
+        });
+        hasher.update(digest.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
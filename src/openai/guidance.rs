@@ -0,0 +1,18 @@
+//! Classifier-free guidance: the logits of a choice are pushed away from the logits of the same
+//! tokens following a negative prompt, `negative + scale * (logits - negative)` over the log
+//! probabilities, so that the output follows what the prompt adds over the negative prompt.
+use candle_core::{DType, Result, Tensor, D};
+
+/// Guidance scale of the requests with a negative prompt and no `guidance_scale`.
+pub const DEFAULT_GUIDANCE_SCALE: f32 = 1.5;
+
+/// Guided logits of the rows of `logits`, given the logits `negative_logits` of the negative
+/// prompt. The log probabilities are combined rather than the logits, which the two prompts
+/// shift differently.
+pub fn guide_logits(logits: &Tensor, negative_logits: &Tensor, scale: f32) -> Result<Tensor> {
+    let dtype = logits.dtype();
+    let logits = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let negative = candle_nn::ops::log_softmax(&negative_logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let guided = ((&logits - &negative)? * scale as f64)?;
+    (negative + guided)?.to_dtype(dtype)
+}
@@ -0,0 +1,74 @@
+//! Guided choice decoding, for classification style requests whose output has to be one of a
+//! fixed set of strings. The tokenized choices are kept in a trie, and at every step only the
+//! tokens continuing one of them can be sampled.
+use std::collections::BTreeMap;
+use tokenizers::Tokenizer;
+
+use super::responses::APIError;
+
+#[derive(Debug, Default)]
+struct ChoiceNode {
+    children: BTreeMap<u32, usize>,
+    /// Whether the tokens leading to the node are a whole choice.
+    is_choice: bool,
+}
+
+#[derive(Debug)]
+pub struct ChoiceTrie {
+    /// The root is the first node.
+    nodes: Vec<ChoiceNode>,
+}
+
+impl ChoiceTrie {
+    pub fn new(choices: &[Vec<u32>]) -> Self {
+        let mut nodes = vec![ChoiceNode::default()];
+        for choice in choices {
+            let mut node = 0;
+            for token in choice {
+                node = match nodes[node].children.get(token) {
+                    Some(&child) => child,
+                    None => {
+                        nodes.push(ChoiceNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(*token, child);
+                        child
+                    }
+                };
+            }
+            nodes[node].is_choice = true;
+        }
+        Self { nodes }
+    }
+
+    /// Trie of `choices` as `tokenizer` encodes them, without special tokens.
+    pub fn from_choices(tokenizer: &Tokenizer, choices: &[String]) -> Result<Self, APIError> {
+        let choices = choices
+            .iter()
+            .map(|choice| {
+                tokenizer
+                    .encode(choice.as_str(), false)
+                    .map(|encoding| encoding.get_ids().to_vec())
+                    .map_err(APIError::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(&choices))
+    }
+
+    fn node(&self, generated: &[u32]) -> Option<&ChoiceNode> {
+        generated.iter().try_fold(&self.nodes[0], |node, token| {
+            node.children.get(token).map(|&child| &self.nodes[child])
+        })
+    }
+
+    /// Tokens continuing a choice after `generated`, in ascending order.
+    pub fn next_tokens(&self, generated: &[u32]) -> Vec<u32> {
+        self.node(generated)
+            .map(|node| node.children.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `generated` is a whole choice.
+    pub fn is_choice(&self, generated: &[u32]) -> bool {
+        self.node(generated).is_some_and(|node| node.is_choice)
+    }
+}
@@ -0,0 +1,134 @@
+//! Hooks for the applications embedding the engine, to build their own streaming transport,
+//! moderate the output as it is generated or cache it token by token, without going through the
+//! responses of the server, or to process the logits a token is sampled from.
+use std::{fmt, sync::Arc, time::Duration};
+
+use candle_core::Tensor;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    responses::{APIError, ChatChoice, ChatCompletionUsageResponse},
+    sampling_params::Logprobs,
+};
+use crate::scheduler::sequence::SequenceGroupMetrics;
+
+/// A token sampled for a choice of a request, before it is streamed and added to the choice.
+#[derive(Clone, Copy, Debug)]
+pub struct TokenEvent<'a> {
+    pub request_id: &'a str,
+    /// Index of the choice within the request.
+    pub index: usize,
+    pub logprobs: &'a Logprobs,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenAction {
+    Continue,
+    /// Drop the token and finish the choice with this finish reason, e.g. `content_filter`.
+    Stop(String),
+}
+
+/// A scheduler step run by the engine.
+#[derive(Clone, Debug)]
+pub struct StepEvent {
+    /// Whether the step prefilled prompts, it decoded otherwise.
+    pub is_prompt: bool,
+    pub request_ids: Vec<String>,
+    pub num_seqs: usize,
+    /// Tokens sampled in the step, more than one per seq with speculative decoding.
+    pub num_tokens: usize,
+    pub duration: Duration,
+}
+
+/// Observer of the requests run by the engine, added with `LLMEngine::add_observer`. It is called
+/// by the engine loop while it holds the engine, so it has to return quickly and must not lock
+/// the engine. Every method does nothing by default.
+pub trait EngineObserver: Send + Sync {
+    /// Called for every sampled token in the order of the choices. The observers added after
+    /// one that stops the choice do not see the token.
+    fn on_token(&self, _token: &TokenEvent) -> TokenAction {
+        TokenAction::Continue
+    }
+
+    fn on_step(&self, _step: &StepEvent) {}
+
+    /// Called once a request finished, with its response.
+    fn on_finish(
+        &self,
+        _request_id: &str,
+        _choices: &[ChatChoice],
+        _usage: &ChatCompletionUsageResponse,
+    ) {
+    }
+
+    /// Called after `on_finish` with when the request went through each stage of its life.
+    fn on_request_metrics(&self, _request_id: &str, _metrics: &SequenceGroupMetrics) {}
+
+    /// Called for the requests aborted as the engine failed.
+    fn on_abort(&self, _request_id: &str, _error: &APIError) {}
+}
+
+/// The seq whose next token is sampled from the logits given to a `LogitsProcessor`.
+#[derive(Clone, Copy, Debug)]
+pub struct LogitsContext<'a> {
+    pub request_id: &'a str,
+    pub prompt: &'a [u32],
+    /// Tokens generated so far by the seq.
+    pub generated: &'a [u32],
+}
+
+/// Processor of the logits of every seq before its next token is sampled, e.g. to constrain the
+/// output to a language or to watermark it. Processors run after the penalties and the
+/// constraints of the request (token healing, `min_tokens`, guided choice and JSON mode), the
+/// ones added with `LLMEngine::add_logits_processor` first and then the ones of the request, in
+/// the order they were added. They run on the host while the engine holds the batch, for each
+/// seq in parallel.
+pub trait LogitsProcessor: Send + Sync {
+    /// `logits` over the vocabulary, in the dtype of the model or in f32. The logits it returns
+    /// are the ones of the next processor, a processor that fails is skipped.
+    fn process(&self, context: &LogitsContext, logits: &Tensor) -> candle_core::Result<Tensor>;
+}
+
+/// Logits processors of a request, set in `SamplingParams::logits_processors`. They are not
+/// serialized, a request logged or sent over the wire runs without them.
+#[derive(Clone, Default)]
+pub struct LogitsProcessors(pub Vec<Arc<dyn LogitsProcessor>>);
+
+impl fmt::Debug for LogitsProcessors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LogitsProcessors({})", self.0.len())
+    }
+}
+
+/// Text of a choice given to a `Moderator`, after a token was sampled and before it is streamed.
+#[derive(Clone, Copy, Debug)]
+pub struct ModerationContext<'a> {
+    pub request_id: &'a str,
+    /// Index of the choice within the request.
+    pub index: usize,
+    /// Text generated so far by the choice, with the text of the new token at the end.
+    pub text: &'a str,
+    /// Text of the new token.
+    pub new_text: &'a str,
+}
+
+/// Text a `Moderator` matched, reported in the `moderation` of the choice and of the chunk of the
+/// token it matched on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModerationMatch {
+    /// What was matched, e.g. `url` or `profanity`.
+    pub category: String,
+    pub text: String,
+    /// Whether the choice was halted with the `content_filter` finish reason, without the token
+    /// of the match. Matches that do not halt are only reported.
+    pub halt: bool,
+}
+
+/// Moderator of the text generated by every choice, added with `LLMEngine::add_moderator`, e.g.
+/// a regex filter or a small classifier. It runs after the observers, on the host while the
+/// engine holds the batch, so it has to return quickly.
+pub trait Moderator: Send + Sync {
+    /// The match of the text ending with the new token, if any. A moderator sees the text once
+    /// per token, it should only report what the new token completes.
+    fn moderate(&self, context: &ModerationContext) -> Option<ModerationMatch>;
+}
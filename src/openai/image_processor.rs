@@ -0,0 +1,119 @@
+//! Preprocessing of `image_url` inputs for vision-language models. Images are prepared the way the
+//! CLIP image processor of HF transformers does it (resize the shortest edge, center crop, rescale
+//! and normalize), and every image placeholder token of the prompt is expanded to one token per
+//! image feature, so that the prompt length seen by the scheduler includes the image.
+use super::responses::APIError;
+use crate::try_api;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use candle_core::{DType, Device, Tensor};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+pub const OPENAI_CLIP_MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+pub const OPENAI_CLIP_STD: [f32; 3] = [0.26862954, 0.2613026, 0.2757771];
+
+#[derive(Debug, Clone)]
+pub struct ImageProcessor {
+    /// Side of the square the image is cropped to.
+    pub image_size: usize,
+    pub image_mean: [f32; 3],
+    pub image_std: [f32; 3],
+    /// Placeholder inserted in the prompt in place of each image, e.g. `<image>`.
+    pub image_token: String,
+    pub image_token_id: u32,
+    /// Number of embeddings the vision encoder produces for one image.
+    pub num_image_tokens: usize,
+}
+
+impl ImageProcessor {
+    pub fn new(
+        image_size: usize,
+        image_token: String,
+        image_token_id: u32,
+        num_image_tokens: usize,
+    ) -> Self {
+        Self {
+            image_size,
+            image_mean: OPENAI_CLIP_MEAN,
+            image_std: OPENAI_CLIP_STD,
+            image_token,
+            image_token_id,
+            num_image_tokens,
+        }
+    }
+
+    /// Decode an image given as a `data:image/<format>;base64,<data>` URL. Remote URLs are not
+    /// fetched, the server never makes requests on behalf of its clients.
+    pub fn load_image_url(url: &str) -> Result<DynamicImage, APIError> {
+        let data = url
+            .strip_prefix("data:")
+            .and_then(|url| url.split_once(";base64,"))
+            .map(|(_, data)| data)
+            .ok_or(APIError::new_str(
+                "Only base64 encoded `data:image/...;base64,` image URLs are supported.",
+            ))?;
+        let bytes = try_api!(STANDARD.decode(data.trim()));
+        Ok(try_api!(image::load_from_memory(&bytes)))
+    }
+
+    /// Pixel values of `image`, of shape `(3, image_size, image_size)`.
+    pub fn preprocess(
+        &self,
+        image: &DynamicImage,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Tensor, APIError> {
+        let size = self.image_size as u32;
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(APIError::new_str("Image is empty."));
+        }
+        let scale = size as f32 / width.min(height) as f32;
+        let resized_width = ((width as f32 * scale).round() as u32).max(size);
+        let resized_height = ((height as f32 * scale).round() as u32).max(size);
+        let image = image
+            .resize_exact(resized_width, resized_height, FilterType::CatmullRom)
+            .crop_imm(
+                (resized_width - size) / 2,
+                (resized_height - size) / 2,
+                size,
+                size,
+            )
+            .to_rgb8();
+
+        self.normalize(image.into_raw(), dtype, device)
+            .map_err(APIError::from)
+    }
+
+    fn normalize(
+        &self,
+        rgb: Vec<u8>,
+        dtype: DType,
+        device: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let shape = (self.image_size, self.image_size, 3);
+        let pixels = Tensor::from_vec(rgb, shape, &Device::Cpu)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?;
+        let mean = Tensor::from_slice(&self.image_mean, (3, 1, 1), &Device::Cpu)?;
+        let std = Tensor::from_slice(&self.image_std, (3, 1, 1), &Device::Cpu)?;
+        (pixels / 255.0)?
+            .broadcast_sub(&mean)?
+            .broadcast_div(&std)?
+            .to_dtype(dtype)?
+            .to_device(device)
+    }
+
+    /// Replace every image placeholder in `token_ids` with `num_image_tokens` placeholders, one
+    /// for each embedding the image is encoded into.
+    pub fn expand_image_tokens(&self, token_ids: &[u32]) -> Vec<u32> {
+        let mut expanded = Vec::with_capacity(token_ids.len());
+        for &id in token_ids {
+            if id == self.image_token_id {
+                expanded.resize(expanded.len() + self.num_image_tokens, id);
+            } else {
+                expanded.push(id);
+            }
+        }
+        expanded
+    }
+}
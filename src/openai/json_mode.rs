@@ -0,0 +1,261 @@
+//! JSON mode, for `response_format: {"type": "json_object"}` requests that want a JSON object
+//! without giving a schema. A pushdown automaton over the characters of the output tracks where
+//! in the object it is, and at every step only the tokens whose text keeps the output a prefix of
+//! a valid object can be sampled. The output ends once the object is closed.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Container {
+    Object,
+    Array,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Escape {
+    None,
+    Backslash,
+    /// Hex digits left in a `\u` escape.
+    Unicode(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    Minus,
+    Zero,
+    Integer,
+    Point,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl Number {
+    /// Whether the number can end here.
+    fn is_complete(self) -> bool {
+        matches!(
+            self,
+            Number::Zero | Number::Integer | Number::Fraction | Number::ExponentDigits
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lex {
+    /// Before the object, which has to come first.
+    Start,
+    /// After `:` in an object or `,` in an array.
+    Value,
+    /// After `{`, a key or `}`.
+    ObjectStart,
+    /// After `,` in an object.
+    Key,
+    /// After a key.
+    Colon,
+    /// After `[`, a value or `]`.
+    ArrayStart,
+    /// After a value, `,` or the bracket closing its container.
+    AfterValue,
+    /// In a string, which is a key or a value.
+    String {
+        key: bool,
+        escape: Escape,
+    },
+    Number(Number),
+    /// The rest of `true`, `false` or `null`.
+    Literal(&'static [u8]),
+    /// After the object.
+    Done,
+}
+
+/// Where the output is in the JSON object.
+#[derive(Clone, Debug)]
+pub struct JsonState {
+    /// Containers the output is in, innermost last.
+    stack: Vec<Container>,
+    lex: Lex,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self {
+            stack: Vec::new(),
+            lex: Lex::Start,
+        }
+    }
+}
+
+impl JsonState {
+    /// Feed `text` to the automaton, `false` if the output is then no longer the prefix of an
+    /// object, in which case the state is left somewhere in `text`.
+    pub fn advance(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.push(c))
+    }
+
+    /// Whether the object was closed.
+    pub fn is_complete(&self) -> bool {
+        self.lex == Lex::Done
+    }
+
+    /// Tokens whose text keeps the output the prefix of an object, given the text of each token
+    /// of the vocabulary. Tokens without text are left out, they would not make progress.
+    pub fn allowed_tokens(&self, vocab: &[String]) -> Vec<u32> {
+        vocab
+            .iter()
+            .enumerate()
+            .filter(|(_, text)| !text.is_empty() && self.clone().advance(text))
+            .map(|(token, _)| token as u32)
+            .collect()
+    }
+
+    fn push(&mut self, c: char) -> bool {
+        let is_whitespace = matches!(c, ' ' | '\t' | '\n' | '\r');
+        match self.lex {
+            Lex::Start => c == '{' && self.open(Container::Object),
+            Lex::Value => is_whitespace || self.start_value(c),
+            Lex::ArrayStart => match c {
+                ']' => self.close(),
+                _ => is_whitespace || self.start_value(c),
+            },
+            Lex::ObjectStart => match c {
+                '"' => self.start_key(),
+                '}' => self.close(),
+                _ => is_whitespace,
+            },
+            Lex::Key => match c {
+                '"' => self.start_key(),
+                _ => is_whitespace,
+            },
+            Lex::Colon => match c {
+                ':' => {
+                    self.lex = Lex::Value;
+                    true
+                }
+                _ => is_whitespace,
+            },
+            Lex::AfterValue => match (c, self.stack.last()) {
+                (',', Some(Container::Object)) => {
+                    self.lex = Lex::Key;
+                    true
+                }
+                (',', Some(Container::Array)) => {
+                    self.lex = Lex::Value;
+                    true
+                }
+                ('}', Some(Container::Object)) | (']', Some(Container::Array)) => self.close(),
+                _ => is_whitespace,
+            },
+            Lex::String { key, escape } => {
+                let escape = match (escape, c) {
+                    (Escape::None, '"') => {
+                        if key {
+                            self.lex = Lex::Colon;
+                        } else {
+                            self.end_value();
+                        }
+                        return true;
+                    }
+                    (Escape::None, '\\') => Escape::Backslash,
+                    // Control characters have to be escaped.
+                    (Escape::None, c) if c < ' ' => return false,
+                    (Escape::None, _) => Escape::None,
+                    (Escape::Backslash, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => {
+                        Escape::None
+                    }
+                    (Escape::Backslash, 'u') => Escape::Unicode(4),
+                    (Escape::Unicode(left), c) if c.is_ascii_hexdigit() => match left {
+                        1 => Escape::None,
+                        _ => Escape::Unicode(left - 1),
+                    },
+                    _ => return false,
+                };
+                self.lex = Lex::String { key, escape };
+                true
+            }
+            Lex::Number(number) => {
+                let next = match (number, c) {
+                    (Number::Minus, '0') => Number::Zero,
+                    (Number::Minus | Number::Integer, '0'..='9') => Number::Integer,
+                    (Number::Zero | Number::Integer, '.') => Number::Point,
+                    (Number::Point | Number::Fraction, '0'..='9') => Number::Fraction,
+                    (Number::Zero | Number::Integer | Number::Fraction, 'e' | 'E') => {
+                        Number::Exponent
+                    }
+                    (Number::Exponent, '+' | '-') => Number::ExponentSign,
+                    (
+                        Number::Exponent | Number::ExponentSign | Number::ExponentDigits,
+                        '0'..='9',
+                    ) => Number::ExponentDigits,
+                    // A number only ends with the character following it.
+                    _ if number.is_complete() => {
+                        self.end_value();
+                        return self.push(c);
+                    }
+                    _ => return false,
+                };
+                self.lex = Lex::Number(next);
+                true
+            }
+            Lex::Literal(rest) => {
+                if !c.is_ascii() || c as u8 != rest[0] {
+                    return false;
+                }
+                match &rest[1..] {
+                    [] => self.end_value(),
+                    rest => self.lex = Lex::Literal(rest),
+                }
+                true
+            }
+            Lex::Done => false,
+        }
+    }
+
+    fn start_value(&mut self, c: char) -> bool {
+        self.lex = match c {
+            '{' => return self.open(Container::Object),
+            '[' => return self.open(Container::Array),
+            '"' => Lex::String {
+                key: false,
+                escape: Escape::None,
+            },
+            '-' => Lex::Number(Number::Minus),
+            '0' => Lex::Number(Number::Zero),
+            '1'..='9' => Lex::Number(Number::Integer),
+            't' => Lex::Literal(b"rue"),
+            'f' => Lex::Literal(b"alse"),
+            'n' => Lex::Literal(b"ull"),
+            _ => return false,
+        };
+        true
+    }
+
+    fn start_key(&mut self) -> bool {
+        self.lex = Lex::String {
+            key: true,
+            escape: Escape::None,
+        };
+        true
+    }
+
+    fn open(&mut self, container: Container) -> bool {
+        self.stack.push(container);
+        self.lex = match container {
+            Container::Object => Lex::ObjectStart,
+            Container::Array => Lex::ArrayStart,
+        };
+        true
+    }
+
+    fn close(&mut self) -> bool {
+        self.stack.pop();
+        self.end_value();
+        true
+    }
+
+    fn end_value(&mut self) {
+        self.lex = if self.stack.is_empty() {
+            Lex::Done
+        } else {
+            Lex::AfterValue
+        };
+    }
+}
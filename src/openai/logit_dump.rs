@@ -0,0 +1,143 @@
+//! Logit dumps for research: the logits of the model at every token a request generates, the
+//! whole vocabulary or its most likely tokens, written to a JSONL file next to the response for
+//! distillation and analysis. A line is written per token as it is sampled, so that a dump never
+//! sits in memory, and a dump stops at a size limit instead of filling the disk.
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use candle_core::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::responses::APIError;
+
+/// Dumps of a server, `--logit-dump-dir`, which requests ask for with `dump_logits`.
+#[derive(Clone, Debug)]
+pub struct LogitDumpDir {
+    pub dir: PathBuf,
+    /// Size of the dump of a request past which it stops.
+    pub max_bytes: u64,
+}
+
+impl LogitDumpDir {
+    /// Dump of the request `request_id`, in `<dir>/<request_id>.jsonl`.
+    pub fn params(&self, request_id: &str, top: usize) -> LogitDumpParams {
+        LogitDumpParams {
+            path: self.dir.join(format!("{request_id}.jsonl")),
+            top,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+/// Logit dump of a request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogitDumpParams {
+    pub path: PathBuf,
+    /// Most likely tokens whose logits are dumped, every token of the vocabulary when it holds
+    /// no more than this.
+    pub top: usize,
+    pub max_bytes: u64,
+}
+
+/// A line of a dump, the logits of the model before the token of `choice` at `position` was
+/// sampled from them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogitDumpLine {
+    pub choice: usize,
+    /// Tokens the choice generated before this one.
+    pub position: usize,
+    pub token: u32,
+    /// Ids of the dumped logits, left out when they are the whole vocabulary in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ids: Option<Vec<u32>>,
+    pub logits: Vec<f32>,
+}
+
+/// The file a request dumps its logits to, shared by its choices.
+pub struct LogitDump {
+    top: usize,
+    max_bytes: u64,
+    /// `None` once the dump stopped.
+    writer: Mutex<Option<(BufWriter<File>, u64)>>,
+}
+
+impl LogitDump {
+    pub fn create(params: &LogitDumpParams) -> Result<Self, APIError> {
+        let file = File::create(&params.path).map_err(|err| {
+            APIError::new(format!(
+                "Cannot create the logit dump {}: {err}",
+                params.path.display()
+            ))
+        })?;
+        Ok(Self {
+            top: params.top,
+            max_bytes: params.max_bytes,
+            writer: Mutex::new(Some((BufWriter::new(file), 0))),
+        })
+    }
+
+    /// Dump `logits`, the row of the model the token of `choice` at `position` is sampled from.
+    /// The line is flushed, for the dump to be read as it is written.
+    pub fn write(
+        &self,
+        choice: usize,
+        position: usize,
+        token: u32,
+        logits: &Tensor,
+    ) -> Result<(), APIError> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((file, written)) = writer.as_mut() else {
+            return Ok(());
+        };
+        let logits = logits
+            .to_dtype(DType::F32)
+            .and_then(|logits| logits.to_vec1::<f32>())
+            .map_err(APIError::from)?;
+        let line = if self.top < logits.len() {
+            let mut top = (0..).zip(logits).collect::<Vec<(u32, f32)>>();
+            top.select_nth_unstable_by(self.top - 1, |(_, a), (_, b)| b.total_cmp(a));
+            top.truncate(self.top);
+            top.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            let (token_ids, logits) = top.into_iter().unzip();
+            LogitDumpLine {
+                choice,
+                position,
+                token,
+                token_ids: Some(token_ids),
+                logits,
+            }
+        } else {
+            LogitDumpLine {
+                choice,
+                position,
+                token,
+                token_ids: None,
+                logits,
+            }
+        };
+        let mut line = serde_json::to_vec(&line).map_err(APIError::from)?;
+        line.push(b'\n');
+        if *written + line.len() as u64 > self.max_bytes {
+            // The last line tells a dump cut short from one of a request that finished early.
+            let _ = file
+                .write_all(b"{\"truncated\":true}\n")
+                .and_then(|()| file.flush());
+            warn!(
+                max_bytes = self.max_bytes,
+                "logit dump truncated at its size limit"
+            );
+            *writer = None;
+            return Ok(());
+        }
+        file.write_all(&line)
+            .and_then(|()| file.flush())
+            .map_err(APIError::from)?;
+        *written += line.len() as u64;
+        Ok(())
+    }
+}
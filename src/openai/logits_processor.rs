@@ -1,6 +1,6 @@
 use crate::candle::D;
 use crate::candle::{DType, Error, Result, Tensor};
-use rand::{distributions::Distribution, SeedableRng};
+use rand::{distributions::Distribution, Rng, SeedableRng};
 use std::sync::Arc;
 use std::sync::Mutex;
 #[derive(Clone, PartialEq, Debug)]
@@ -12,6 +12,64 @@ pub enum Sampling {
     TopKThenTopP { k: usize, p: f64, temperature: f64 },
 }
 
+impl Sampling {
+    /// Greedy below a temperature of 0, else random sampling among the top `top_k` tokens and
+    /// the nucleus of `top_p` if set.
+    pub fn new(temperature: f64, top_k: Option<usize>, top_p: Option<f64>) -> Self {
+        if temperature <= 0. {
+            return Self::ArgMax;
+        }
+        match (top_k, top_p) {
+            (None, None) => Self::All { temperature },
+            (Some(k), None) => Self::TopK { k, temperature },
+            (None, Some(p)) => Self::TopP { p, temperature },
+            (Some(k), Some(p)) => Self::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+/// Logits of the last dimension with every token but `allowed` set to minus infinity.
+pub fn mask_logits(logits: &Tensor, allowed: &[u32]) -> Result<Tensor> {
+    add_token_mask(logits, allowed, f32::NEG_INFINITY, 0.0)
+}
+
+/// Logits of the last dimension with the `suppressed` tokens set to minus infinity.
+pub fn suppress_logits(logits: &Tensor, suppressed: &[u32]) -> Result<Tensor> {
+    add_token_mask(logits, suppressed, 0.0, f32::NEG_INFINITY)
+}
+
+/// Add `value` to the logits of `tokens` and `rest` to all others, in f32.
+fn add_token_mask(logits: &Tensor, tokens: &[u32], rest: f32, value: f32) -> Result<Tensor> {
+    let vocab_size = logits.dim(D::Minus1)?;
+    let mut mask = vec![rest; vocab_size];
+    for &token in tokens {
+        if let Some(mask) = mask.get_mut(token as usize) {
+            *mask = value;
+        }
+    }
+    let mask = Tensor::from_vec(mask, vocab_size, logits.device())?;
+    logits.to_dtype(DType::F32)?.broadcast_add(&mask)
+}
+
+/// Log probability of `token` under `logits`, along with the `top_n` most likely tokens and
+/// theirs, the most likely first.
+pub fn token_logprobs(logits: &Tensor, token: u32, top_n: usize) -> Result<(f32, Vec<(u32, f32)>)> {
+    let logprobs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let logprobs = logprobs.to_vec1::<f32>()?;
+    let logprob = logprobs
+        .get(token as usize)
+        .copied()
+        .unwrap_or(f32::NEG_INFINITY);
+    let mut top = (0..).zip(logprobs).collect::<Vec<(u32, f32)>>();
+    let top_n = top_n.min(top.len());
+    if top_n > 0 {
+        top.select_nth_unstable_by(top_n - 1, |(_, a), (_, b)| b.total_cmp(a));
+    }
+    top.truncate(top_n);
+    top.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    Ok((logprob, top))
+}
+
 pub struct LogitsProcessor {
     rng: Arc<Mutex<rand::rngs::StdRng>>,
     sampling: Sampling,
@@ -38,6 +96,16 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
+    pub fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    /// `n` draws in [0, 1) from the random generator of the sampler.
+    pub fn uniform(&self, n: usize) -> Vec<f32> {
+        let mut rng = self.rng.lock().unwrap();
+        (0..n).map(|_| rng.gen::<f32>()).collect()
+    }
+
     fn sample_argmax(&self, logits: Tensor) -> Result<u32> {
         // let logits_v: Vec<f32> = logits.to_vec1()?;
         // Use gpu kernel
@@ -118,7 +186,21 @@ impl LogitsProcessor {
         self.sample_f(logits, |_| {})
     }
 
+    /// Sample with `sampling` instead of the sampling of the processor, from its generator.
+    pub fn sample_with(&self, logits: &Tensor, sampling: &Sampling) -> Result<u32> {
+        self.sample_f_with(logits, sampling, |_| {})
+    }
+
     pub fn sample_f(&self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
+        self.sample_f_with(logits, &self.sampling, f)
+    }
+
+    fn sample_f_with(
+        &self,
+        logits: &Tensor,
+        sampling: &Sampling,
+        f: impl FnOnce(&mut [f32]),
+    ) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
         let prs = |temperature: f64| -> Result<Vec<f32>> {
             let logits = (&logits / temperature)?;
@@ -128,7 +210,7 @@ impl LogitsProcessor {
             Ok(prs)
         };
 
-        let next_token = match &self.sampling {
+        let next_token = match sampling {
             Sampling::ArgMax => self.sample_argmax(logits)?,
             Sampling::All { temperature } => {
                 let prs = prs(*temperature)?;
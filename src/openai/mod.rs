@@ -1,9 +1,17 @@
 use candle_core::Device;
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
 use tokenizers::{EncodeInput, Encoding, Tokenizer};
 use tokio::sync::{Mutex, Notify};
 
-use self::{pipelines::llm_engine::LLMEngine, responses::APIError};
+use self::{
+    batches::BatchStore, logit_dump::LogitDumpDir, pipelines::admission::AdmissionQueue,
+    pipelines::llm_engine::LLMEngine, response_cache::ResponseCache, responses::APIError,
+    tokenizer_pool::TokenizerPool, watermark::Watermark,
+};
+use crate::logging::LogFilterHandle;
+use crate::scheduler::{SchedulerLimits, SchedulerSnapshot};
 
 pub mod requests;
 pub mod responses;
@@ -38,19 +46,109 @@ pub struct PipelineConfig {
     pub penalty: f32,
     pub repeat_last_n: usize,
     pub temperature: f32,
+    /// Default `top_p` and `top_k` of the requests.
+    pub top_p: f32,
+    pub top_k: isize,
+}
+
+impl PipelineConfig {
+    /// Default `top_p` and `top_k` of a request sampling at `temperature`, greedy requests take
+    /// neither.
+    pub fn top_p_top_k(&self, temperature: f32) -> (f32, isize) {
+        if temperature < sampling_params::SAMPLING_EPS {
+            (1.0, -1)
+        } else {
+            (self.top_p, self.top_k)
+        }
+    }
 }
 
 pub struct OpenAIServerData {
     pub model: Arc<Mutex<LLMEngine>>,
-    pub pipeline_config: PipelineConfig,
+    /// Changed through `POST /admin/config`, each request reads it once.
+    pub pipeline_config: std::sync::RwLock<PipelineConfig>,
     pub record_conversation: bool,
     pub device: Device,
     pub finish_notify: Arc<Notify>,
+    pub scheduler_trace: Arc<std::sync::RwLock<SchedulerSnapshot>>,
+    /// Encodes the prompts of requests without holding the engine.
+    pub tokenizer_pool: TokenizerPool,
+    pub scheduler_limits: Arc<std::sync::RwLock<SchedulerLimits>>,
+    /// Requests aborted through `DELETE /v1/requests/{id}`, see `LLMEngine::aborted_requests`.
+    pub aborted_requests: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// Bearer token of the `/admin` endpoints, which are disabled without one.
+    pub admin_token: Option<String>,
+    pub log_filter: Option<LogFilterHandle>,
+    /// Files and batches of the `/v1/files` and `/v1/batches` endpoints.
+    pub batches: BatchStore,
+    /// Watermark of the generated text, tested by `/v1/watermark/detect`.
+    pub watermark: Option<Watermark>,
+    /// Model names requests may ask for, any name is served when empty.
+    pub served_model_names: Vec<String>,
+    /// Names of the control vectors requests may steer with.
+    pub control_vectors: Vec<String>,
+    /// Requests waiting to be scheduled past which new ones are refused with a 429.
+    pub max_waiting_requests: Option<usize>,
+    /// Cap the requests without `max_tokens` at the tokens the free blocks of the KV cache hold
+    /// besides their prompt, so that they finish without being preempted.
+    pub cap_max_tokens_to_free_blocks: bool,
+    /// Responses of the deterministic requests, served again to identical requests.
+    pub response_cache: Option<ResponseCache>,
+    /// Refuse the requests with fields the server does not know, instead of ignoring them.
+    pub reject_unknown_fields: bool,
+    /// Requests prepared without the engine and taken in at its next step, see `AdmissionQueue`.
+    pub admissions: AdmissionQueue,
+    /// Top logprobs a request may ask for, `MAX_TOP_LOGPROBS` unless raised for research.
+    pub max_logprobs: usize,
+    /// Where the logits requests ask for with `dump_logits` go, dumps are refused without it.
+    pub logit_dumps: Option<LogitDumpDir>,
+    /// API keys identifying the tenants of the fair scheduling policy, requests with other keys
+    /// share the tenant of those without one.
+    pub api_keys: HashSet<String>,
+}
+
+impl OpenAIServerData {
+    pub fn pipeline_config(&self) -> PipelineConfig {
+        self.pipeline_config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Tenant of a request sent with `api_key`, for fair scheduling. Only the configured keys
+    /// are tenants of their own, not to give a share to every key a client makes up. The key is
+    /// hashed, not to be kept in the scheduler where snapshots and logs could show it.
+    pub fn tenant(&self, api_key: Option<&str>) -> Option<String> {
+        let key = api_key.filter(|key| self.api_keys.contains(*key))?;
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(format!("key-{:016x}", hasher.finish()))
+    }
 }
 
+pub mod audio_processor;
+pub mod batches;
+pub mod control_vectors;
 pub mod conversation;
+pub mod fim;
+pub mod fingerprint;
+pub mod guidance;
+pub mod guided_choice;
+pub mod hooks;
+pub mod image_processor;
+pub mod json_mode;
+pub mod logit_dump;
 pub mod logits_processor;
 pub mod models;
+pub mod moderation;
 pub mod openai_server;
 pub mod pipelines;
+pub mod response_cache;
+pub mod special_tokens;
+pub mod stop_checker;
+pub mod tokenizer_pool;
+pub mod tool_calls;
+pub mod transcription;
 pub mod utils;
+pub mod validation;
+pub mod watermark;
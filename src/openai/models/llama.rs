@@ -1,11 +1,13 @@
 use super::Config;
+use crate::backend::{rotary_embedding, silu_and_mul};
 use crate::openai::models::linear::{linear_no_bias as linear, Linear};
+use crate::openai::models::rms_norm::RmsNorm;
+use crate::openai::pipelines::tensor_parallel::TensorParallel;
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use candle::{DType, Device, IndexOp, Result, Tensor};
 use candle_core as candle;
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
-use candle_transformers::models::with_tracing::RmsNorm;
 
 pub const MAX_SEQ_LEN: usize = 4096;
 use crate::openai::models::TokenID;
@@ -56,6 +58,8 @@ impl LlamaConfig {
             kv_cache_dtype,
             use_qkv_bias: None,
             custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse: None,
         }
     }
 }
@@ -97,27 +101,24 @@ struct CausalSelfAttention {
     span_rot: tracing::Span,
     attn: PagedAttention,
     cos_sin_cache: Cache,
+    parallel: TensorParallel,
 }
 
 impl CausalSelfAttention {
-    fn apply_rotary_emb(&self, x: &Tensor, input_positions: &Vec<Vec<usize>>) -> Result<Tensor> {
+    fn apply_rotary_emb(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        input_positions: &Vec<Vec<usize>>,
+    ) -> Result<(Tensor, Tensor)> {
         let _enter = self.span_rot.enter();
-        let (b_sz, _, seq_len, _hidden_size) = x.dims4()?;
-        let mut embeds = Vec::new();
-        for (b, seqlen_offset) in zip(0..b_sz, input_positions) {
-            let cos = self
-                .cos_sin_cache
-                .cos
-                .narrow(0, seqlen_offset[0], seq_len)?;
-            let sin = self
-                .cos_sin_cache
-                .sin
-                .narrow(0, seqlen_offset[0], seq_len)?;
-            let x_b = x.narrow(0, b, 1)?;
-            let embed = candle_nn::rotary_emb::rope(&x_b, &cos, &sin).unwrap();
-            embeds.push(embed);
-        }
-        Tensor::cat(&embeds, 0)
+        rotary_embedding(
+            q,
+            k,
+            &self.cos_sin_cache.cos,
+            &self.cos_sin_cache.sin,
+            input_positions,
+        )
     }
 
     fn forward(
@@ -129,7 +130,9 @@ impl CausalSelfAttention {
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let (b_sz, seq_len, _) = x.dims3()?;
+        // The heads of this rank only, with tensor parallelism.
+        let hidden_size = self.num_attention_heads * self.head_dim;
         let q = self.q_proj.forward(x)?;
         let k = self.k_proj.forward(x)?;
         let v = self.v_proj.forward(x)?;
@@ -156,8 +159,7 @@ impl CausalSelfAttention {
             (q, k, v)
         };
 
-        let q = self.apply_rotary_emb(&q, input_positions)?;
-        let k = self.apply_rotary_emb(&k, input_positions)?;
+        let (q, k) = self.apply_rotary_emb(&q, &k, input_positions)?;
 
         let y = self.attn.forward(
             &q,
@@ -169,46 +171,56 @@ impl CausalSelfAttention {
             input_metadata,
         )?;
 
-        let y = if attention_mask.is_some() {
+        let y = if seq_len > 1 {
             y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?
         } else {
             y.reshape(&[b_sz, seq_len, hidden_size])?
         };
         let y = self.o_proj.forward(&y)?;
-        Ok(y)
+        self.parallel.all_reduce(&y)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+        parallel: &TensorParallel,
+    ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "attn");
         let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
+        let world_size = parallel.shard.world_size;
+        let num_attention_heads = cfg.num_attention_heads / world_size;
+        let num_key_value_heads = cfg.num_key_value_heads / world_size;
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         let size_in = cfg.hidden_size;
-        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
-        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
+        let size_q = head_dim * num_attention_heads;
+        let size_kv = head_dim * num_key_value_heads;
         let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
         let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
         let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
         let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
-        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         Ok(Self {
             q_proj,
             k_proj,
             v_proj,
             o_proj,
-            num_attention_heads: cfg.num_attention_heads,
-            num_key_value_heads: cfg.num_key_value_heads,
+            num_attention_heads,
+            num_key_value_heads,
             head_dim: head_dim,
             span,
             span_rot,
             attn: PagedAttention::new(
-                cfg.num_attention_heads,
+                num_attention_heads,
                 head_dim,
                 1. / ((head_dim as f32).sqrt()),
-                Some(cfg.num_key_value_heads),
+                Some(num_key_value_heads),
                 None,
                 vb.device().clone(),
                 None,
             )?,
             cos_sin_cache: Cache::new(dtype, &cfg, device)?,
+            parallel: parallel.clone(),
         })
     }
 }
@@ -219,19 +231,20 @@ struct Mlp {
     c_fc2: Linear,
     c_proj: Linear,
     span: tracing::Span,
+    parallel: TensorParallel,
 }
 
 impl Mlp {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let x = (candle_nn::ops::silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
-        self.c_proj.forward(&x)
+        let x = silu_and_mul(&self.c_fc1.forward(x)?, &self.c_fc2.forward(x)?)?;
+        self.parallel.all_reduce(&self.c_proj.forward(&x)?)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, parallel: &TensorParallel) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
-        let i_size = cfg.intermediate_size;
+        let i_size = cfg.intermediate_size / parallel.shard.world_size;
         let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
         let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
         let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
@@ -240,6 +253,7 @@ impl Mlp {
             c_fc2,
             c_proj,
             span,
+            parallel: parallel.clone(),
         })
     }
 }
@@ -264,19 +278,23 @@ impl Block {
         let _enter = self.span.enter();
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self
+        let x = self
             .attn
-            .forward(&x, attention_mask, input_positions, cache, input_metadata)?
-            + residual)?;
-        let residual = &x;
-        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
-        Ok(x)
+            .forward(&x, attention_mask, input_positions, cache, input_metadata)?;
+        let (x, residual) = self.rms_2.forward_residual(&x, residual)?;
+        self.mlp.forward(&x)? + residual
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+        parallel: &TensorParallel,
+    ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "block");
-        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg, dtype, device)?;
-        let mlp = Mlp::load(vb.pp("mlp"), cfg)?;
+        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg, dtype, device, parallel)?;
+        let mlp = Mlp::load(vb.pp("mlp"), cfg, parallel)?;
         let rms_1 = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
         let rms_2 = RmsNorm::new(
             cfg.hidden_size,
@@ -321,16 +339,35 @@ impl Llama {
         kv_caches: Option<&Vec<(Tensor, Tensor)>>,
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor> {
-        let (_b_sz, seq_len) = x.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
+        let x = self.embed(x)?;
+        self.forward_embeds(x, input_positions, kv_caches, input_metadata)
+    }
+
+    /// Token embeddings of `x`, of shape `(batch, seq_len, hidden_size)`.
+    pub fn embed(&self, x: &Tensor) -> Result<Tensor> {
+        self.wte.forward(x)
+    }
+
+    /// Run the decoder on input embeddings instead of token ids, so that multimodal models can
+    /// splice other features (e.g. image patches) into the sequence.
+    pub fn forward_embeds(
+        &mut self,
+        mut x: Tensor,
+        input_positions: &Vec<Vec<usize>>,
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len, _) = x.dims3()?;
+        let attention_mask = if input_metadata.needs_prompt_mask(seq_len) {
             let mask = self.prepare_decoder_attention_mask(_b_sz, seq_len)?;
             Some(mask)
+        } else {
+            None
         };
-        let mut x = self.wte.forward(x)?;
         if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), block) in zip(kv_caches.iter(), &mut self.blocks) {
+            for (i, ((k_cache, v_cache), block)) in
+                zip(kv_caches.iter(), &mut self.blocks).enumerate()
+            {
                 x = block.forward(
                     &x,
                     attention_mask.as_ref(),
@@ -338,9 +375,10 @@ impl Llama {
                     Some((k_cache, v_cache)),
                     input_metadata,
                 )?;
+                x = input_metadata.steer(i, x)?;
             }
         } else {
-            for block in &mut self.blocks {
+            for (i, block) in self.blocks.iter_mut().enumerate() {
                 x = block.forward(
                     &x,
                     attention_mask.as_ref(),
@@ -348,6 +386,7 @@ impl Llama {
                     None,
                     input_metadata,
                 )?;
+                x = input_metadata.steer(i, x)?;
             }
         }
         let x = self.ln_f.forward(&x)?;
@@ -357,11 +396,31 @@ impl Llama {
     }
 
     pub fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
+        Self::load_shard(vb, cfg, dtype, device, &TensorParallel::default())
+    }
+
+    /// Load the shard of `parallel` of the model, whose sharded weights `vb` holds.
+    pub fn load_shard(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+        parallel: &TensorParallel,
+    ) -> Result<Self> {
         let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
         let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
         let ln_f = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
         let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(vb.pp(&format!("model.layers.{i}")), cfg, dtype, device).unwrap())
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    dtype,
+                    device,
+                    parallel,
+                )
+                .unwrap()
+            })
             .collect();
 
         Ok(Self {
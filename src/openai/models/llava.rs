@@ -0,0 +1,308 @@
+use super::llama::{Llama, LlamaConfig};
+use super::{Config, TokenID};
+use crate::openai::models::linear::{linear, Linear};
+use crate::paged_attention::input_metadata::InputMetadata;
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_core as candle;
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::clip::{
+    text_model::Activation as ClipActivation,
+    vision_model::{ClipVisionConfig, ClipVisionTransformer},
+};
+use either::Either;
+
+/// Language model part of a LLaVA checkpoint in the HF transformers format. Fields missing from
+/// the config take the defaults of transformers' `LlamaConfig`, which llava-hf checkpoints rely on.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct LLaVATextConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub bos_token_id: u32,
+    pub eos_token_id: u32,
+    pub max_position_embeddings: usize,
+}
+
+impl Default for LLaVATextConfig {
+    fn default() -> Self {
+        Self {
+            hidden_size: 4096,
+            intermediate_size: 11008,
+            vocab_size: 32000,
+            num_hidden_layers: 32,
+            num_attention_heads: 32,
+            num_key_value_heads: None,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10_000.0,
+            bos_token_id: 1,
+            eos_token_id: 2,
+            max_position_embeddings: 2048,
+        }
+    }
+}
+
+/// CLIP vision tower of a LLaVA checkpoint, defaulting to CLIP ViT-L/14 at 336px.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct LLaVAVisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub projection_dim: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+}
+
+impl Default for LLaVAVisionConfig {
+    fn default() -> Self {
+        Self {
+            hidden_size: 1024,
+            intermediate_size: 4096,
+            num_hidden_layers: 24,
+            num_attention_heads: 16,
+            projection_dim: 768,
+            image_size: 336,
+            patch_size: 14,
+        }
+    }
+}
+
+impl LLaVAVisionConfig {
+    fn to_clip_config(&self) -> ClipVisionConfig {
+        ClipVisionConfig {
+            embed_dim: self.hidden_size,
+            activation: ClipActivation::QuickGelu,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            projection_dim: self.projection_dim,
+            num_channels: 3,
+            image_size: self.image_size,
+            patch_size: self.patch_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LLaVAConfig {
+    #[serde(default)]
+    pub text_config: LLaVATextConfig,
+    #[serde(default)]
+    pub vision_config: LLaVAVisionConfig,
+    pub image_token_index: u32,
+    /// Layer of the vision tower the image features are taken from, counting the embeddings as
+    /// layer 0 and negative values from the end.
+    #[serde(default = "default_vision_feature_layer")]
+    pub vision_feature_layer: isize,
+    /// `default` drops the CLS token from the image features, `full` keeps it.
+    #[serde(default = "default_vision_feature_select_strategy")]
+    pub vision_feature_select_strategy: String,
+}
+
+fn default_vision_feature_layer() -> isize {
+    -2
+}
+
+fn default_vision_feature_select_strategy() -> String {
+    "default".to_string()
+}
+
+impl LLaVAConfig {
+    pub fn into_config(self, use_flash_attn: bool, kv_cache_dtype: DType) -> Config {
+        let text = self.text_config;
+        LlamaConfig {
+            hidden_size: text.hidden_size,
+            intermediate_size: text.intermediate_size,
+            vocab_size: text.vocab_size,
+            num_hidden_layers: text.num_hidden_layers,
+            num_attention_heads: text.num_attention_heads,
+            num_key_value_heads: text.num_key_value_heads,
+            rms_norm_eps: text.rms_norm_eps,
+            rope_theta: text.rope_theta,
+            bos_token_id: TokenID(Either::Left(Some(text.bos_token_id))),
+            eos_token_id: TokenID(Either::Left(Some(text.eos_token_id))),
+            max_position_embeddings: Some(text.max_position_embeddings),
+        }
+        .into_config(use_flash_attn, kv_cache_dtype)
+    }
+
+    /// Number of embeddings each image is encoded into.
+    pub fn num_image_tokens(&self) -> usize {
+        let patches_per_side = self.vision_config.image_size / self.vision_config.patch_size;
+        let num_patches = patches_per_side * patches_per_side;
+        if self.vision_feature_select_strategy == "full" {
+            num_patches + 1
+        } else {
+            num_patches
+        }
+    }
+}
+
+/// Two layer MLP mapping image features into the embedding space of the language model.
+struct MultiModalProjector {
+    linear_1: Linear,
+    act: Activation,
+    linear_2: Linear,
+}
+
+impl MultiModalProjector {
+    fn load(vb: VarBuilder, vision_hidden_size: usize, text_hidden_size: usize) -> Result<Self> {
+        Ok(Self {
+            linear_1: linear(vision_hidden_size, text_hidden_size, vb.pp("linear_1"))?,
+            act: Activation::Gelu,
+            linear_2: linear(text_hidden_size, text_hidden_size, vb.pp("linear_2"))?,
+        })
+    }
+}
+
+impl Module for MultiModalProjector {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.linear_1)?
+            .apply(&self.act)?
+            .apply(&self.linear_2)
+    }
+}
+
+/// LLaVA: a CLIP vision tower whose features are projected into the token embeddings of a llama
+/// decoder. Image features replace the image placeholder tokens of the prompt during prefill.
+pub struct LLaVA {
+    vision_tower: ClipVisionTransformer,
+    projector: MultiModalProjector,
+    llama: Llama,
+    image_token_id: u32,
+    vision_feature_layer: isize,
+    select_cls_token: bool,
+}
+
+impl LLaVA {
+    pub fn load(
+        vb: VarBuilder,
+        llava_cfg: &LLaVAConfig,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let vision_tower = ClipVisionTransformer::new(
+            vb.pp("vision_tower.vision_model"),
+            &llava_cfg.vision_config.to_clip_config(),
+        )?;
+        let projector = MultiModalProjector::load(
+            vb.pp("multi_modal_projector"),
+            llava_cfg.vision_config.hidden_size,
+            cfg.hidden_size,
+        )?;
+        let llama = Llama::load(vb.pp("language_model"), cfg, dtype, device)?;
+        Ok(Self {
+            vision_tower,
+            projector,
+            llama,
+            image_token_id: llava_cfg.image_token_index,
+            vision_feature_layer: llava_cfg.vision_feature_layer,
+            select_cls_token: llava_cfg.vision_feature_select_strategy == "full",
+        })
+    }
+
+    /// Encode `pixel_values` of shape `(num_images, 3, image_size, image_size)` into image
+    /// features of shape `(num_images, num_image_tokens, hidden_size)`.
+    pub fn encode_images(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.vision_tower.output_hidden_states(pixel_values)?;
+        // One entry per encoder layer, followed by the pooled output.
+        let num_layers = hidden_states.len() as isize - 1;
+        let layer = if self.vision_feature_layer < 0 {
+            num_layers + self.vision_feature_layer
+        } else {
+            self.vision_feature_layer - 1
+        };
+        if layer < 0 || layer >= num_layers {
+            candle::bail!(
+                "vision_feature_layer {} is out of range for {num_layers} layers",
+                self.vision_feature_layer
+            )
+        }
+        let features = &hidden_states[layer as usize];
+        let features = if self.select_cls_token {
+            features.clone()
+        } else {
+            features.i((.., 1..))?
+        };
+        self.projector.forward(&features)
+    }
+
+    /// `image_features` holds the output of `encode_images` for each sequence of the batch that
+    /// has images. It is only given during prefill.
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        input_positions: &Vec<Vec<usize>>,
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+        image_features: &[Option<Tensor>],
+    ) -> Result<Tensor> {
+        let mut embeds = self.llama.embed(x)?;
+        if image_features.iter().any(|f| f.is_some()) {
+            embeds = merge_image_features(&embeds, x, image_features, self.image_token_id)?;
+        }
+        self.llama
+            .forward_embeds(embeds, input_positions, kv_caches, input_metadata)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        self.llama.get_config()
+    }
+}
+
+/// Replace, in order, the embeddings of the image placeholder tokens of each sequence of
+/// `input_ids` with the features of its images.
+pub fn merge_image_features(
+    embeds: &Tensor,
+    input_ids: &Tensor,
+    image_features: &[Option<Tensor>],
+    image_token_id: u32,
+) -> Result<Tensor> {
+    let (_, seq_len, hidden_size) = embeds.dims3()?;
+    let input_ids = input_ids.to_dtype(DType::I64)?.to_vec2::<i64>()?;
+    let image_token_id = i64::from(image_token_id);
+    let mut merged = Vec::with_capacity(input_ids.len());
+    for (i, ids) in input_ids.iter().enumerate() {
+        let seq_embeds = embeds.i(i)?;
+        let Some(features) = image_features.get(i).and_then(|f| f.as_ref()) else {
+            merged.push(seq_embeds);
+            continue;
+        };
+        let features = features
+            .reshape(((), hidden_size))?
+            .to_dtype(seq_embeds.dtype())?;
+        let num_features = features.dim(0)?;
+        let num_image_tokens = ids.iter().filter(|&&id| id == image_token_id).count();
+        if num_image_tokens != num_features {
+            candle::bail!(
+                "sequence has {num_image_tokens} image tokens for {num_features} image features"
+            )
+        }
+
+        // Gather from the token embeddings followed by the image features.
+        let mut next_feature = seq_len;
+        let index = ids
+            .iter()
+            .enumerate()
+            .map(|(pos, &id)| {
+                if id == image_token_id {
+                    next_feature += 1;
+                    (next_feature - 1) as u32
+                } else {
+                    pos as u32
+                }
+            })
+            .collect::<Vec<_>>();
+        let index = Tensor::from_vec(index, seq_len, embeds.device())?;
+        merged.push(Tensor::cat(&[&seq_embeds, &features], 0)?.index_select(&index, 0)?);
+    }
+    Tensor::stack(&merged, 0)
+}
@@ -1,10 +1,11 @@
 use super::Config;
+use crate::backend::{rotary_embedding, silu_and_mul};
 use crate::openai::models::linear::{linear_no_bias, Linear};
+use crate::openai::models::rms_norm::RmsNorm;
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
-use candle_transformers::models::with_tracing::RmsNorm;
 use either::Either;
 use std::iter::zip;
 use std::sync::Arc;
@@ -53,6 +54,8 @@ impl MistralConfig {
             kv_cache_dtype,
             use_qkv_bias: None,
             custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse: None,
         }
     }
 }
@@ -91,23 +94,7 @@ impl RotaryEmbedding {
         k: &Tensor,
         input_positions: &Vec<Vec<usize>>,
     ) -> Result<(Tensor, Tensor)> {
-        let (b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let mut q_embeds = Vec::new();
-        let mut k_embeds = Vec::new();
-        for (b, seqlen_offset) in zip(0..b_sz, input_positions) {
-            let cos = self.cos.narrow(0, seqlen_offset[0], seq_len)?;
-            let sin = self.sin.narrow(0, seqlen_offset[0], seq_len)?;
-            let x_q = q.narrow(0, b, 1)?;
-            let x_k = k.narrow(0, b, 1)?;
-            let q_embed = candle_nn::rotary_emb::rope(&x_q, &cos, &sin).unwrap();
-            let k_embed = candle_nn::rotary_emb::rope(&x_k, &cos, &sin).unwrap();
-            q_embeds.push(q_embed);
-            k_embeds.push(k_embed);
-        }
-        Ok((
-            Tensor::cat(&q_embeds, 0).unwrap(),
-            Tensor::cat(&k_embeds, 0).unwrap(),
-        ))
+        rotary_embedding(q, k, &self.cos, &self.sin, input_positions)
     }
 }
 
@@ -138,9 +125,14 @@ impl MLP {
 
 impl Module for MLP {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
-        let rhs = xs.apply(&self.up_proj)?;
-        (lhs * rhs)?.apply(&self.down_proj)
+        let gate = xs.apply(&self.gate_proj)?;
+        let up = xs.apply(&self.up_proj)?;
+        let xs = if matches!(self.act_fn, Activation::Silu) {
+            silu_and_mul(&gate, &up)?
+        } else {
+            (gate.apply(&self.act_fn)? * up)?
+        };
+        xs.apply(&self.down_proj)
     }
 }
 
@@ -182,7 +174,8 @@ impl Attention {
                 head_dim,
                 1. / ((head_dim as f32).sqrt()),
                 Some(cfg.num_key_value_heads),
-                None,
+                // The window of the mask of the prompt, for prompts attended without it.
+                cfg.sliding_window,
                 vb.device().clone(),
                 None,
             )?,
@@ -241,7 +234,7 @@ impl Attention {
             input_metadata,
         )?;
 
-        let y = if attention_mask.is_some() {
+        let y = if seq_len > 1 {
             y.transpose(1, 2)?
                 .reshape(&[b_sz, seq_len, self.hidden_size])?
         } else {
@@ -291,10 +284,10 @@ impl DecoderLayer {
         let xs =
             self.self_attn
                 .forward(&xs, attention_mask, input_positions, cache, input_metadata)?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
-        residual + xs
+        let (xs, residual) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
+        residual + xs.apply(&self.mlp)?
     }
 }
 
@@ -361,32 +354,36 @@ impl Mistral {
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
+        let attention_mask = if input_metadata.needs_prompt_mask(seq_len) {
             let mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
             Some(mask)
+        } else {
+            None
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+            for (i, ((k_cache, v_cache), layer)) in
+                zip(kv_caches.iter(), self.layers.iter_mut()).enumerate()
+            {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     Some((k_cache, v_cache)),
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         } else {
-            for layer in self.layers.iter_mut() {
+            for (i, layer) in self.layers.iter_mut().enumerate() {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     None,
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         }
         let logits = xs
@@ -1,12 +1,18 @@
 pub mod gemma;
 pub mod linear;
 pub mod llama;
+pub mod llava;
 pub mod mistral;
 pub mod phi2;
 pub mod phi3;
 pub mod qwen2;
+pub mod rms_norm;
 pub mod stable_lm;
+pub mod t5;
+pub mod whisper;
 pub mod yi;
+use crate::paged_attention::block_sparse::BlockSparseConfig;
+use crate::scheduler::state_cache::RecurrentStateConfig;
 use candle_core::DType;
 use either::Either;
 use serde::Deserialize;
@@ -45,6 +51,10 @@ pub struct Config {
     pub kv_cache_dtype: DType,
     pub use_qkv_bias: Option<bool>,
     pub custom_stop_tokens: Option<Vec<String>>,
+    /// The recurrent layers of a hybrid model, `None` when every layer attends the KV cache.
+    pub recurrent_state: Option<RecurrentStateConfig>,
+    /// The sparse attention of models like Phi-3-small, `None` when every layer attends densely.
+    pub block_sparse: Option<BlockSparseConfig>,
 }
 
 impl Config {
@@ -57,6 +57,8 @@ impl Phi2Config {
             kv_cache_dtype,
             use_qkv_bias: None,
             custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse: None,
         }
     }
 }
@@ -249,7 +251,7 @@ impl Attention {
             input_metadata,
         )?;
 
-        let y = if attention_mask.is_some() {
+        let y = if seq_len > 1 {
             y.transpose(1, 2)?
                 .reshape(&[b_size, seq_len, self.hidden_size])?
         } else {
@@ -353,31 +355,35 @@ impl Phi2 {
     ) -> Result<Tensor> {
         let (b_size, seq_len) = xs.dims2()?;
         let mut xs = xs.apply(&self.embed_tokens)?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
+        let attention_mask = if input_metadata.needs_prompt_mask(seq_len) {
             let mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
             Some(mask)
+        } else {
+            None
         };
         if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+            for (i, ((k_cache, v_cache), layer)) in
+                zip(kv_caches.iter(), self.layers.iter_mut()).enumerate()
+            {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     Some((k_cache, v_cache)),
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         } else {
-            for layer in self.layers.iter_mut() {
+            for (i, layer) in self.layers.iter_mut().enumerate() {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     None,
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         }
         xs.apply(&self.final_layernorm)?
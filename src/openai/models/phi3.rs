@@ -1,13 +1,14 @@
 // This implementation is based on:
 // https://huggingface.co/microsoft/Phi-3-mini-4k-instruct/blob/main/modeling_phi3.py
 use super::{Config, RopeScaling};
+use crate::backend::silu_and_mul;
 use crate::openai::models::linear::{linear_no_bias as linear, Linear};
+use crate::openai::models::rms_norm::RmsNorm;
 use crate::paged_attention::input_metadata::InputMetadata;
-use crate::paged_attention::PagedAttention;
+use crate::paged_attention::{block_sparse::BlockSparseConfig, PagedAttention};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_core as candle;
 use candle_nn::VarBuilder;
-use candle_transformers::models::with_tracing::RmsNorm;
 use either::Either;
 use std::collections::HashMap;
 use std::iter::zip;
@@ -30,10 +31,33 @@ pub struct PhiConfig {
     pub max_position_embeddings: usize,
     pub original_max_position_embeddings: Option<usize>,
     pub sliding_window: Option<usize>,
+    /// The block-sparse attention of Phi-3-small.
+    pub blocksparse_block_size: Option<usize>,
+    pub blocksparse_num_local_blocks: Option<usize>,
+    pub blocksparse_vert_stride: Option<usize>,
+    #[serde(default)]
+    pub blocksparse_homo_head_pattern: bool,
+    pub dense_attention_every_n_layers: Option<usize>,
 }
 
 impl PhiConfig {
     pub fn into_config(self, use_flash_attn: bool, kv_cache_dtype: DType) -> Config {
+        let block_sparse = match (
+            self.blocksparse_block_size,
+            self.blocksparse_num_local_blocks,
+            self.blocksparse_vert_stride,
+        ) {
+            (Some(block_size), Some(num_local_blocks), Some(vert_stride)) => {
+                Some(BlockSparseConfig {
+                    block_size,
+                    num_local_blocks,
+                    vert_stride,
+                    homo_head_pattern: self.blocksparse_homo_head_pattern,
+                    dense_every_n_layers: self.dense_attention_every_n_layers,
+                })
+            }
+            _ => None,
+        };
         Config {
             hidden_size: self.hidden_size,
             intermediate_size: self.intermediate_size,
@@ -58,6 +82,8 @@ impl PhiConfig {
             kv_cache_dtype,
             use_qkv_bias: None,
             custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse,
         }
     }
 }
@@ -230,7 +256,12 @@ struct Attention {
 }
 
 impl Attention {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        layer_idx: usize,
+    ) -> Result<Self> {
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
@@ -253,7 +284,8 @@ impl Attention {
                 None,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_layer(layer_idx),
         })
     }
 
@@ -317,7 +349,7 @@ impl Attention {
             input_metadata,
         )?;
 
-        let y = if attention_mask.is_some() {
+        let y = if seq_len > 1 {
             y.transpose(1, 2)?
                 .reshape(&[b_sz, seq_len, self.hidden_size])?
         } else {
@@ -356,7 +388,11 @@ impl Module for Mlp {
         let up_states = xs.apply(&self.gate_up_proj)?;
         let gate = up_states.narrow(D::Minus1, 0, self.i_size)?;
         let up_states = up_states.narrow(D::Minus1, self.i_size, self.i_size)?;
-        let up_states = (up_states * gate.apply(&self.act_fn))?;
+        let up_states = if matches!(self.act_fn, candle_nn::Activation::Silu) {
+            silu_and_mul(&gate, &up_states)?
+        } else {
+            (up_states * gate.apply(&self.act_fn))?
+        };
         up_states.apply(&self.down_proj)
     }
 }
@@ -369,8 +405,13 @@ struct DecoderLayer {
 }
 
 impl DecoderLayer {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        layer_idx: usize,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"), layer_idx)?;
         let mlp = Mlp::new(cfg, vb.pp("mlp"))?;
         let input_layernorm =
             RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
@@ -400,10 +441,10 @@ impl DecoderLayer {
         let xs =
             self.self_attn
                 .forward(&xs, attention_mask, input_positions, cache, input_metadata)?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
-        residual + xs
+        let (xs, residual) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
+        residual + xs.apply(&self.mlp)?
     }
 }
 
@@ -426,7 +467,7 @@ impl Phi {
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let vb_l = vb_m.pp("layers");
         for layer_idx in 0..cfg.num_hidden_layers {
-            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx), layer_idx)?;
             layers.push(layer)
         }
         let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
@@ -459,33 +500,37 @@ impl Phi {
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
+        let attention_mask = if input_metadata.needs_prompt_mask(seq_len) {
             let mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
             Some(mask)
+        } else {
+            None
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
 
         if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+            for (i, ((k_cache, v_cache), layer)) in
+                zip(kv_caches.iter(), self.layers.iter_mut()).enumerate()
+            {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     Some((k_cache, v_cache)),
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         } else {
-            for layer in self.layers.iter_mut() {
+            for (i, layer) in self.layers.iter_mut().enumerate() {
                 xs = layer.forward(
                     &xs,
                     attention_mask.as_ref(),
                     input_positions,
                     None,
                     input_metadata,
-                )?
+                )?;
+                xs = input_metadata.steer(i, xs)?;
             }
         }
         xs.i((.., seq_len - 1, ..))?
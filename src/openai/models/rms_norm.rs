@@ -0,0 +1,38 @@
+//! RMS normalization layer
+//!
+//! This layer normalizes the last dimension of its input by its root mean square and scales it by
+//! a learned weight, `y = x / sqrt(mean(x^2) + eps) * w`. It runs the fused kernels of the backend
+//! on CUDA, including the residual addition that precedes the norm in decoder layers.
+use crate::backend::{fused_add_rms_norm, rms_norm};
+use crate::candle::{Module, Result, Tensor};
+use candle_nn::VarBuilder;
+
+#[derive(Clone, Debug)]
+pub struct RmsNorm {
+    weight: Tensor,
+    eps: f32,
+}
+
+impl RmsNorm {
+    pub fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        Ok(Self::from_weight(vb.get(size, "weight")?, eps))
+    }
+
+    pub fn from_weight(weight: Tensor, eps: f64) -> Self {
+        Self {
+            weight,
+            eps: eps as f32,
+        }
+    }
+
+    /// Normalizes `x + residual`, returning the norm and the sum, the residual of what follows.
+    pub fn forward_residual(&self, x: &Tensor, residual: &Tensor) -> Result<(Tensor, Tensor)> {
+        fused_add_rms_norm(x, residual, &self.weight, self.eps)
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        rms_norm(x, &self.weight, self.eps)
+    }
+}
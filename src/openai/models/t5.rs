@@ -0,0 +1,623 @@
+//! T5 encoder-decoder (FLAN-T5, MADLAD-400), served through the completions API.
+//!
+//! A sequence holds the prompt followed by the generated tokens, so that the scheduler and the
+//! block engine handle it like the sequence of a decoder-only model. The prompt is the input of
+//! the encoder, which runs once, at the prompt step of the sequence. The keys and values of the
+//! cross-attention are computed from its output and kept in a region of their own, by sequence,
+//! until the sequence is freed. The decoder starts at the last slot of the prompt: decoder token
+//! `i` has the KV cache slot of token `prompt_len - 1 + i` of the sequence, which leaves the
+//! other slots of the prompt unused.
+//!
+//! T5 has no position embeddings but a bias of the attention scores by relative position, which
+//! the paged attention kernel does not apply, so the decoder attends over the keys and values it
+//! gathers from the blocks of the sequence.
+use super::Config;
+use crate::openai::models::linear::{linear_no_bias as linear, Linear};
+use crate::openai::models::TokenID;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::{gather_kv_cache, write_kv_cache};
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_core as candle;
+use candle_nn::{embedding, Activation, Embedding, VarBuilder};
+use candle_transformers::models::with_tracing::RmsNorm;
+use either::Either;
+use std::collections::HashMap;
+use std::ops::Range;
+
+pub const MAX_SEQ_LEN: usize = 512;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct T5Config {
+    pub vocab_size: usize,
+    pub d_model: usize,
+    pub d_kv: usize,
+    pub d_ff: usize,
+    pub num_layers: usize,
+    pub num_decoder_layers: Option<usize>,
+    pub num_heads: usize,
+    pub relative_attention_num_buckets: usize,
+    #[serde(default = "default_relative_attention_max_distance")]
+    pub relative_attention_max_distance: usize,
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "default_feed_forward_proj")]
+    pub feed_forward_proj: String,
+    #[serde(default = "default_tie_word_embeddings")]
+    pub tie_word_embeddings: bool,
+    pub pad_token_id: u32,
+    pub eos_token_id: u32,
+    pub decoder_start_token_id: Option<u32>,
+    pub n_positions: Option<usize>,
+}
+
+fn default_relative_attention_max_distance() -> usize {
+    128
+}
+
+fn default_feed_forward_proj() -> String {
+    "relu".to_string()
+}
+
+fn default_tie_word_embeddings() -> bool {
+    true
+}
+
+impl T5Config {
+    /// The KV cache holds the self-attention of the decoder. Its head size is taken as
+    /// `hidden_size / num_attention_heads`, so `hidden_size` is the inner dimension of the
+    /// attention, which differs from `d_model` for some checkpoints (e.g. flan-t5-small).
+    pub fn into_config(self, use_flash_attn: bool, kv_cache_dtype: DType) -> Config {
+        let hidden_act = self.activation().ok().map(|(_, activation)| activation);
+        Config {
+            hidden_size: self.num_heads * self.d_kv,
+            intermediate_size: self.d_ff,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_decoder_layers(),
+            num_attention_heads: self.num_heads,
+            num_key_value_heads: self.num_heads,
+            rms_norm_eps: self.layer_norm_epsilon,
+            rope_theta: 0.,
+            use_flash_attn,
+            bos_token_id: TokenID(Either::Left(Some(self.decoder_start_token_id()))),
+            eos_token_id: TokenID(Either::Left(Some(self.eos_token_id))),
+            max_seq_len: self.n_positions.unwrap_or(MAX_SEQ_LEN),
+            sliding_window: None,
+            hidden_act,
+            tie_word_embeddings: self.tie_word_embeddings,
+            rope_scaling: None,
+            original_max_position_embeddings: None,
+            attention_bias: false,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse: None,
+        }
+    }
+
+    fn num_decoder_layers(&self) -> usize {
+        self.num_decoder_layers.unwrap_or(self.num_layers)
+    }
+
+    fn decoder_start_token_id(&self) -> u32 {
+        self.decoder_start_token_id.unwrap_or(self.pad_token_id)
+    }
+
+    /// Whether the feed forward is gated, and its activation.
+    fn activation(&self) -> Result<(bool, Activation)> {
+        let (gated, name) = match self.feed_forward_proj.strip_prefix("gated-") {
+            Some(name) => (true, name),
+            None => (false, self.feed_forward_proj.as_str()),
+        };
+        let activation = match name {
+            // `gated-gelu` is the tanh approximation.
+            "gelu" if gated => Activation::NewGelu,
+            "gelu" => Activation::Gelu,
+            "gelu_new" => Activation::NewGelu,
+            "relu" => Activation::Relu,
+            "silu" => Activation::Silu,
+            _ => candle::bail!(
+                "Unsupported feed_forward_proj `{}`.",
+                self.feed_forward_proj
+            ),
+        };
+        Ok((gated, activation))
+    }
+}
+
+/// Bias of the attention scores by bucketed relative position, of the first layer of a stack
+/// and shared by the others.
+struct RelativePositionBias {
+    embedding: Embedding,
+    num_buckets: usize,
+    max_distance: usize,
+    /// Encoder tokens attend both ways, decoder tokens only to the tokens before them.
+    bidirectional: bool,
+}
+
+impl RelativePositionBias {
+    fn bucket(&self, query: usize, key: usize) -> u32 {
+        let (num_buckets, offset, distance) = if self.bidirectional {
+            let num_buckets = self.num_buckets / 2;
+            let offset = if key > query { num_buckets } else { 0 };
+            (num_buckets, offset, key.abs_diff(query))
+        } else {
+            (self.num_buckets, 0, query.saturating_sub(key))
+        };
+        let max_exact = num_buckets / 2;
+        let bucket = if distance < max_exact {
+            distance
+        } else {
+            let large = (distance as f32 / max_exact as f32).ln()
+                / (self.max_distance as f32 / max_exact as f32).ln()
+                * (num_buckets - max_exact) as f32;
+            (max_exact + large as usize).min(num_buckets - 1)
+        };
+        (offset + bucket) as u32
+    }
+
+    /// Bias of the queries at `queries` attending to the keys at `0..num_keys`, of shape
+    /// `(num_heads, queries.len(), num_keys)`. Decoder queries get `-inf` for the keys after them.
+    fn forward(&self, queries: Range<usize>, num_keys: usize, dtype: DType) -> Result<Tensor> {
+        let device = self.embedding.embeddings().device();
+        let num_queries = queries.len();
+        let mut buckets = Vec::with_capacity(num_queries * num_keys);
+        let mut mask = Vec::with_capacity(num_queries * num_keys);
+        for query in queries {
+            for key in 0..num_keys {
+                buckets.push(self.bucket(query, key));
+                let masked = !self.bidirectional && key > query;
+                mask.push(if masked { f32::NEG_INFINITY } else { 0. });
+            }
+        }
+        let buckets = Tensor::from_vec(buckets, (num_queries, num_keys), device)?;
+        let bias = self.embedding.forward(&buckets)?.permute((2, 0, 1))?;
+        let mask = Tensor::from_vec(mask, (num_queries, num_keys), device)?.to_dtype(dtype)?;
+        bias.to_dtype(dtype)?.broadcast_add(&mask)?.contiguous()
+    }
+
+    fn load(vb: VarBuilder, cfg: &T5Config, bidirectional: bool) -> Result<Self> {
+        Ok(Self {
+            embedding: embedding(cfg.relative_attention_num_buckets, cfg.num_heads, vb)?,
+            num_buckets: cfg.relative_attention_num_buckets,
+            max_distance: cfg.relative_attention_max_distance,
+            bidirectional,
+        })
+    }
+}
+
+/// Multi-head attention without scaling of the scores, T5 folds it into the weights.
+struct T5Attention {
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    o: Linear,
+    num_heads: usize,
+    d_kv: usize,
+}
+
+impl T5Attention {
+    /// `(num_tokens, inner_dim)` to `(num_tokens, num_heads, d_kv)`.
+    fn split_heads(&self, x: &Tensor) -> Result<Tensor> {
+        let (num_tokens, _) = x.dims2()?;
+        x.reshape((num_tokens, self.num_heads, self.d_kv))
+    }
+
+    /// Attention of `q` over `k` and `v`, all of shape `(num_tokens, num_heads, d_kv)`, returns
+    /// `(q_len, inner_dim)`.
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, bias: Option<&Tensor>) -> Result<Tensor> {
+        let q_len = q.dim(0)?;
+        let q = q.transpose(0, 1)?.contiguous()?;
+        let k = k.transpose(0, 1)?.contiguous()?;
+        let v = v.transpose(0, 1)?.contiguous()?;
+        let scores = q.matmul(&k.t()?)?;
+        let scores = match bias {
+            Some(bias) => scores.broadcast_add(bias)?,
+            None => scores,
+        };
+        let weights = candle_nn::ops::softmax_last_dim(&scores.to_dtype(DType::F32)?)?
+            .to_dtype(scores.dtype())?;
+        weights
+            .matmul(&v)?
+            .transpose(0, 1)?
+            .reshape((q_len, self.num_heads * self.d_kv))
+    }
+
+    fn load(vb: VarBuilder, cfg: &T5Config) -> Result<Self> {
+        let inner_dim = cfg.num_heads * cfg.d_kv;
+        Ok(Self {
+            q: linear(cfg.d_model, inner_dim, vb.pp("q"))?,
+            k: linear(cfg.d_model, inner_dim, vb.pp("k"))?,
+            v: linear(cfg.d_model, inner_dim, vb.pp("v"))?,
+            o: linear(inner_dim, cfg.d_model, vb.pp("o"))?,
+            num_heads: cfg.num_heads,
+            d_kv: cfg.d_kv,
+        })
+    }
+}
+
+struct DenseActDense {
+    wi: Linear,
+    /// Linear gated by the activation of `wi`, for the `gated-*` feed forwards.
+    wi_gate: Option<Linear>,
+    wo: Linear,
+    act: Activation,
+}
+
+impl DenseActDense {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let hidden = self.act.forward(&self.wi.forward(x)?)?;
+        let hidden = match &self.wi_gate {
+            Some(wi_gate) => (hidden * wi_gate.forward(x)?)?,
+            None => hidden,
+        };
+        self.wo.forward(&hidden)
+    }
+
+    fn load(vb: VarBuilder, cfg: &T5Config) -> Result<Self> {
+        let (gated, act) = cfg.activation()?;
+        let (wi, wi_gate) = if gated {
+            (
+                linear(cfg.d_model, cfg.d_ff, vb.pp("wi_0"))?,
+                Some(linear(cfg.d_model, cfg.d_ff, vb.pp("wi_1"))?),
+            )
+        } else {
+            (linear(cfg.d_model, cfg.d_ff, vb.pp("wi"))?, None)
+        };
+        Ok(Self {
+            wi,
+            wi_gate,
+            wo: linear(cfg.d_ff, cfg.d_model, vb.pp("wo"))?,
+            act,
+        })
+    }
+}
+
+struct EncoderBlock {
+    self_attn: T5Attention,
+    self_attn_norm: RmsNorm,
+    ff: DenseActDense,
+    ff_norm: RmsNorm,
+}
+
+impl EncoderBlock {
+    /// `x` is the `(num_tokens, d_model)` hidden states of a single prompt.
+    fn forward(&self, x: &Tensor, bias: &Tensor) -> Result<Tensor> {
+        let h = self.self_attn_norm.forward(x)?;
+        let q = self.self_attn.split_heads(&self.self_attn.q.forward(&h)?)?;
+        let k = self.self_attn.split_heads(&self.self_attn.k.forward(&h)?)?;
+        let v = self.self_attn.split_heads(&self.self_attn.v.forward(&h)?)?;
+        let h = self.self_attn.attend(&q, &k, &v, Some(bias))?;
+        let x = (x + self.self_attn.o.forward(&h)?)?;
+        let h = self.ff.forward(&self.ff_norm.forward(&x)?)?;
+        x + h
+    }
+
+    fn load(vb: VarBuilder, cfg: &T5Config) -> Result<Self> {
+        let eps = cfg.layer_norm_epsilon;
+        Ok(Self {
+            self_attn: T5Attention::load(vb.pp("layer.0.SelfAttention"), cfg)?,
+            self_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp("layer.0.layer_norm"))?,
+            ff: DenseActDense::load(vb.pp("layer.1.DenseReluDense"), cfg)?,
+            ff_norm: RmsNorm::new(cfg.d_model, eps, vb.pp("layer.1.layer_norm"))?,
+        })
+    }
+}
+
+/// Decoder tokens of one sequence within the hidden states of a step.
+struct DecoderRow {
+    seq_id: usize,
+    /// Rows of the tokens in the hidden states.
+    tokens: Range<usize>,
+    /// Bias of the self-attention of the tokens, over the whole decoder context.
+    bias: Tensor,
+    /// Blocks of the sequence and the cache indices of the decoder context, during decoding.
+    /// At the prompt step the tokens attend to each other only.
+    context: Option<(Vec<u32>, Range<usize>)>,
+}
+
+struct DecoderBlock {
+    self_attn: T5Attention,
+    self_attn_norm: RmsNorm,
+    cross_attn: T5Attention,
+    cross_attn_norm: RmsNorm,
+    ff: DenseActDense,
+    ff_norm: RmsNorm,
+}
+
+impl DecoderBlock {
+    fn forward(
+        &self,
+        x: &Tensor,
+        rows: &[DecoderRow],
+        slot_mapping: &Tensor,
+        cache: &(Tensor, Tensor),
+        cross_kv: &[&(Tensor, Tensor)],
+    ) -> Result<Tensor> {
+        let (key_cache, value_cache) = cache;
+        let h = self.self_attn_norm.forward(x)?;
+        let q = self.self_attn.split_heads(&self.self_attn.q.forward(&h)?)?;
+        let k = self.self_attn.split_heads(&self.self_attn.k.forward(&h)?)?;
+        let v = self.self_attn.split_heads(&self.self_attn.v.forward(&h)?)?;
+        write_kv_cache(&k, &v, key_cache, value_cache, slot_mapping)?;
+        let mut outputs = Vec::with_capacity(rows.len());
+        for row in rows {
+            let len = row.tokens.len();
+            let q = q.narrow(0, row.tokens.start, len)?;
+            let (k, v) = match &row.context {
+                Some((block_table, context)) => gather_kv_cache(
+                    key_cache,
+                    value_cache,
+                    block_table,
+                    context.start,
+                    context.len(),
+                    q.dtype(),
+                )?,
+                None => (
+                    k.narrow(0, row.tokens.start, len)?,
+                    v.narrow(0, row.tokens.start, len)?,
+                ),
+            };
+            outputs.push(self.self_attn.attend(&q, &k, &v, Some(&row.bias))?);
+        }
+        let x = (x + self.self_attn.o.forward(&Tensor::cat(&outputs, 0)?)?)?;
+
+        let h = self.cross_attn_norm.forward(&x)?;
+        let q = self
+            .cross_attn
+            .split_heads(&self.cross_attn.q.forward(&h)?)?;
+        let mut outputs = Vec::with_capacity(rows.len());
+        for (row, (k, v)) in rows.iter().zip(cross_kv) {
+            let q = q.narrow(0, row.tokens.start, row.tokens.len())?;
+            outputs.push(self.cross_attn.attend(&q, k, v, None)?);
+        }
+        let x = (&x + self.cross_attn.o.forward(&Tensor::cat(&outputs, 0)?)?)?;
+
+        let h = self.ff.forward(&self.ff_norm.forward(&x)?)?;
+        x + h
+    }
+
+    /// Keys and values of the cross-attention over `encoder_output`.
+    fn cross_kv(&self, encoder_output: &Tensor) -> Result<(Tensor, Tensor)> {
+        let k = self.cross_attn.k.forward(encoder_output)?;
+        let v = self.cross_attn.v.forward(encoder_output)?;
+        Ok((
+            self.cross_attn.split_heads(&k)?,
+            self.cross_attn.split_heads(&v)?,
+        ))
+    }
+
+    fn load(vb: VarBuilder, cfg: &T5Config) -> Result<Self> {
+        let eps = cfg.layer_norm_epsilon;
+        Ok(Self {
+            self_attn: T5Attention::load(vb.pp("layer.0.SelfAttention"), cfg)?,
+            self_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp("layer.0.layer_norm"))?,
+            cross_attn: T5Attention::load(vb.pp("layer.1.EncDecAttention"), cfg)?,
+            cross_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp("layer.1.layer_norm"))?,
+            ff: DenseActDense::load(vb.pp("layer.2.DenseReluDense"), cfg)?,
+            ff_norm: RmsNorm::new(cfg.d_model, eps, vb.pp("layer.2.layer_norm"))?,
+        })
+    }
+}
+
+/// Encoder output of a sequence, as the keys and values of the cross-attention of each decoder
+/// layer.
+struct EncoderOutput {
+    /// Number of tokens of the sequence that are the prompt.
+    prompt_len: usize,
+    cross_kv: Vec<(Tensor, Tensor)>,
+}
+
+pub struct T5 {
+    shared: Embedding,
+    encoder: Vec<EncoderBlock>,
+    encoder_bias: RelativePositionBias,
+    encoder_norm: RmsNorm,
+    decoder: Vec<DecoderBlock>,
+    decoder_bias: RelativePositionBias,
+    decoder_norm: RmsNorm,
+    /// `None` when tied to the shared embedding.
+    lm_head: Option<Linear>,
+    d_model: usize,
+    eos_token_id: u32,
+    decoder_start_token_id: u32,
+    /// Cross-attention region of the KV cache, by sequence id.
+    encoder_outputs: HashMap<usize, EncoderOutput>,
+    cfg: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl T5 {
+    /// Encode `prompt`, which is ended by EOS as in training, into the `(num_tokens, d_model)`
+    /// hidden states the decoder attends to.
+    fn encode(&self, prompt: &[u32]) -> Result<Tensor> {
+        let mut ids = prompt.to_vec();
+        if ids.last() != Some(&self.eos_token_id) {
+            ids.push(self.eos_token_id);
+        }
+        let len = ids.len();
+        let bias = self.encoder_bias.forward(0..len, len, self.dtype)?;
+        let mut x = self
+            .shared
+            .forward(&Tensor::from_vec(ids, len, &self.device)?)?;
+        for block in &self.encoder {
+            x = block.forward(&x, &bias)?;
+        }
+        self.encoder_norm.forward(&x)
+    }
+
+    /// Run the encoder on the prompt of `seq_id` at its prompt step. A sequence preempted by
+    /// recompute keeps its encoder output, its decoder tokens are the generated ones.
+    fn encoder_output(&mut self, seq_id: usize, prompt: &[u32]) -> Result<&EncoderOutput> {
+        if !self.encoder_outputs.contains_key(&seq_id) {
+            if prompt.is_empty() {
+                candle::bail!("T5 needs a prompt of at least one token.");
+            }
+            let encoded = self.encode(prompt)?;
+            let cross_kv = self
+                .decoder
+                .iter()
+                .map(|block| block.cross_kv(&encoded))
+                .collect::<Result<Vec<_>>>()?;
+            let output = EncoderOutput {
+                prompt_len: prompt.len(),
+                cross_kv,
+            };
+            self.encoder_outputs.insert(seq_id, output);
+        }
+        Ok(&self.encoder_outputs[&seq_id])
+    }
+
+    /// Logits of the next token of every sequence of the step. At the prompt step, `x` holds the
+    /// whole sequences, the prompt is encoded and the decoder runs from its start token.
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor> {
+        let Some(kv_caches) = kv_caches else {
+            candle::bail!("The T5 decoder needs the KV cache.")
+        };
+        let input_ids = x.to_dtype(DType::U32)?.to_vec2::<u32>()?;
+        let slot_mapping = input_metadata.slot_mapping.to_vec2::<i64>()?;
+        let mut decoder_ids = Vec::new();
+        let mut slots = Vec::new();
+        let mut rows = Vec::new();
+        if input_metadata.is_prompt {
+            for ((&seq_id, ids), (&seq_len, seq_slots)) in input_metadata
+                .seq_ids
+                .iter()
+                .zip(&input_ids)
+                .zip(input_metadata.prompt_lens.iter().zip(&slot_mapping))
+            {
+                let prompt_len = self.encoder_output(seq_id, &ids[..seq_len])?.prompt_len;
+                let start = decoder_ids.len();
+                decoder_ids.push(self.decoder_start_token_id);
+                decoder_ids.extend(&ids[prompt_len..seq_len]);
+                slots.extend(&seq_slots[prompt_len - 1..seq_len]);
+                let len = decoder_ids.len() - start;
+                rows.push(DecoderRow {
+                    seq_id,
+                    tokens: start..start + len,
+                    bias: self.decoder_bias.forward(0..len, len, self.dtype)?,
+                    context: None,
+                });
+            }
+        } else {
+            let (Some(block_tables), Some(context_lens)) =
+                (&input_metadata.block_tables, &input_metadata.context_lens)
+            else {
+                candle::bail!("Decoding needs the block tables and context lengths.")
+            };
+            let block_tables = block_tables.to_vec2::<u32>()?;
+            let context_lens = context_lens.to_vec1::<u32>()?;
+            for (row, &seq_id) in input_metadata.seq_ids.iter().enumerate() {
+                let Some(output) = self.encoder_outputs.get(&seq_id) else {
+                    candle::bail!("No encoder output for sequence {seq_id}.")
+                };
+                let context = output.prompt_len - 1..context_lens[row] as usize;
+                let position = context.len() - 1;
+                decoder_ids.push(input_ids[row][0]);
+                slots.push(slot_mapping[row][0]);
+                rows.push(DecoderRow {
+                    seq_id,
+                    tokens: row..row + 1,
+                    bias: self.decoder_bias.forward(
+                        position..position + 1,
+                        context.len(),
+                        self.dtype,
+                    )?,
+                    context: Some((block_tables[row].clone(), context)),
+                });
+            }
+        }
+
+        let cross_kv = rows
+            .iter()
+            .map(|row| &self.encoder_outputs[&row.seq_id].cross_kv)
+            .collect::<Vec<_>>();
+        let num_tokens = decoder_ids.len();
+        let slot_mapping = Tensor::from_vec(slots, num_tokens, &self.device)?;
+        let mut x =
+            self.shared
+                .forward(&Tensor::from_vec(decoder_ids, num_tokens, &self.device)?)?;
+        for (i, (block, cache)) in self.decoder.iter().zip(kv_caches).enumerate() {
+            let layer_cross_kv = cross_kv.iter().map(|kv| &kv[i]).collect::<Vec<_>>();
+            x = block.forward(&x, &rows, &slot_mapping, cache, &layer_cross_kv)?;
+        }
+        let last_tokens = rows
+            .iter()
+            .map(|row| row.tokens.end as u32 - 1)
+            .collect::<Vec<_>>();
+        let last_tokens = Tensor::from_vec(last_tokens, rows.len(), &self.device)?;
+        let x = self
+            .decoder_norm
+            .forward(&x.index_select(&last_tokens, 0)?)?;
+        let logits = match &self.lm_head {
+            Some(lm_head) => lm_head.forward(&x)?,
+            // The tied embedding is scaled as in the original implementation.
+            None => {
+                (x * (self.d_model as f64).powf(-0.5))?.matmul(&self.shared.embeddings().t()?)?
+            }
+        };
+        logits.to_dtype(DType::F32)
+    }
+
+    /// Drop the encoder output of finished sequences.
+    pub fn free_sequences(&mut self, seq_ids: &[usize]) {
+        for seq_id in seq_ids {
+            self.encoder_outputs.remove(seq_id);
+        }
+    }
+
+    pub fn new(
+        vb: VarBuilder,
+        t5_cfg: &T5Config,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let eps = t5_cfg.layer_norm_epsilon;
+        let shared = embedding(t5_cfg.vocab_size, t5_cfg.d_model, vb.pp("shared"))?;
+        let encoder = (0..t5_cfg.num_layers)
+            .map(|i| EncoderBlock::load(vb.pp(format!("encoder.block.{i}")), t5_cfg))
+            .collect::<Result<Vec<_>>>()?;
+        let decoder = (0..t5_cfg.num_decoder_layers())
+            .map(|i| DecoderBlock::load(vb.pp(format!("decoder.block.{i}")), t5_cfg))
+            .collect::<Result<Vec<_>>>()?;
+        let bias_path = "block.0.layer.0.SelfAttention.relative_attention_bias";
+        let lm_head = if t5_cfg.tie_word_embeddings {
+            None
+        } else {
+            Some(linear(t5_cfg.d_model, t5_cfg.vocab_size, vb.pp("lm_head"))?)
+        };
+        Ok(Self {
+            shared,
+            encoder,
+            encoder_bias: RelativePositionBias::load(vb.pp("encoder").pp(bias_path), t5_cfg, true)?,
+            encoder_norm: RmsNorm::new(t5_cfg.d_model, eps, vb.pp("encoder.final_layer_norm"))?,
+            decoder,
+            decoder_bias: RelativePositionBias::load(
+                vb.pp("decoder").pp(bias_path),
+                t5_cfg,
+                false,
+            )?,
+            decoder_norm: RmsNorm::new(t5_cfg.d_model, eps, vb.pp("decoder.final_layer_norm"))?,
+            lm_head,
+            d_model: t5_cfg.d_model,
+            eos_token_id: t5_cfg.eos_token_id,
+            decoder_start_token_id: t5_cfg.decoder_start_token_id(),
+            encoder_outputs: HashMap::new(),
+            cfg: cfg.clone(),
+            dtype,
+            device: device.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
@@ -0,0 +1,469 @@
+//! Whisper speech recognition, served through `/v1/audio/transcriptions` and
+//! `/v1/audio/translations`.
+//!
+//! The log-mel spectrogram of up to 30 seconds of audio goes through the engine as the pixel
+//! values of the request, and the audio encoder runs on it at the prompt step, as the vision
+//! encoder of LLaVA does on images. The prompt is the start of the decoder output
+//! (`<|startoftranscript|>`, the language, the task and `<|notimestamps|>`), so the scheduler and
+//! the block engine handle the sequence like the sequence of a decoder-only model, and the
+//! self-attention of the decoder goes through the paged KV cache. The keys and values of the
+//! cross-attention are computed from the encoder output and kept by sequence until the sequence
+//! is freed.
+use super::Config;
+use crate::openai::models::linear::{linear, linear_no_bias, Linear};
+use crate::openai::models::TokenID;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_core as candle;
+use candle_nn::{
+    conv1d, embedding, layer_norm, Activation, Conv1d, Conv1dConfig, Embedding, LayerNorm,
+    VarBuilder,
+};
+use either::Either;
+use std::collections::HashMap;
+use std::iter::zip;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct WhisperConfig {
+    pub vocab_size: usize,
+    pub num_mel_bins: usize,
+    pub d_model: usize,
+    pub encoder_layers: usize,
+    pub encoder_attention_heads: usize,
+    pub encoder_ffn_dim: usize,
+    pub decoder_layers: usize,
+    pub decoder_attention_heads: usize,
+    pub decoder_ffn_dim: usize,
+    /// Number of frames of the encoder output, half of the frames of the spectrogram.
+    pub max_source_positions: usize,
+    pub max_target_positions: usize,
+    pub decoder_start_token_id: u32,
+    pub eos_token_id: u32,
+}
+
+impl WhisperConfig {
+    /// The KV cache holds the self-attention of the decoder.
+    pub fn into_config(self, use_flash_attn: bool, kv_cache_dtype: DType) -> Config {
+        Config {
+            hidden_size: self.d_model,
+            intermediate_size: self.decoder_ffn_dim,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.decoder_layers,
+            num_attention_heads: self.decoder_attention_heads,
+            num_key_value_heads: self.decoder_attention_heads,
+            rms_norm_eps: 1e-5,
+            rope_theta: 0.,
+            use_flash_attn,
+            bos_token_id: TokenID(Either::Left(Some(self.decoder_start_token_id))),
+            eos_token_id: TokenID(Either::Left(Some(self.eos_token_id))),
+            max_seq_len: self.max_target_positions,
+            sliding_window: None,
+            hidden_act: Some(Activation::Gelu),
+            tie_word_embeddings: true,
+            rope_scaling: None,
+            original_max_position_embeddings: None,
+            attention_bias: true,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: Some(true),
+            custom_stop_tokens: None,
+            recurrent_state: None,
+            block_sparse: None,
+        }
+    }
+}
+
+/// Multi-head attention, the keys are projected without bias.
+struct Attention {
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    o: Linear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    /// `(num_tokens, d_model)` to `(num_tokens, num_heads, head_dim)`.
+    fn split_heads(&self, x: &Tensor) -> Result<Tensor> {
+        let (num_tokens, _) = x.dims2()?;
+        x.reshape((num_tokens, self.num_heads, self.head_dim))
+    }
+
+    /// Attention of `q` over `k` and `v`, all of shape `(num_tokens, num_heads, head_dim)`,
+    /// returns `(q_len, d_model)`.
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+        let q_len = q.dim(0)?;
+        let q = q.transpose(0, 1)?.contiguous()?;
+        let k = k.transpose(0, 1)?.contiguous()?;
+        let v = v.transpose(0, 1)?.contiguous()?;
+        let scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let weights = candle_nn::ops::softmax_last_dim(&scores.to_dtype(DType::F32)?)?
+            .to_dtype(scores.dtype())?;
+        weights
+            .matmul(&v)?
+            .transpose(0, 1)?
+            .reshape((q_len, self.num_heads * self.head_dim))
+    }
+
+    /// Keys and values over `x`, of shape `(num_tokens, d_model)`.
+    fn kv(&self, x: &Tensor) -> Result<(Tensor, Tensor)> {
+        Ok((
+            self.split_heads(&self.k.forward(x)?)?,
+            self.split_heads(&self.v.forward(x)?)?,
+        ))
+    }
+
+    fn load(vb: VarBuilder, d_model: usize, num_heads: usize) -> Result<Self> {
+        Ok(Self {
+            q: linear(d_model, d_model, vb.pp("q_proj"))?,
+            k: linear_no_bias(d_model, d_model, vb.pp("k_proj"))?,
+            v: linear(d_model, d_model, vb.pp("v_proj"))?,
+            o: linear(d_model, d_model, vb.pp("out_proj"))?,
+            num_heads,
+            head_dim: d_model / num_heads,
+        })
+    }
+}
+
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.fc2.forward(&self.fc1.forward(x)?.gelu_erf()?)
+    }
+
+    fn load(vb: VarBuilder, d_model: usize, ffn_dim: usize) -> Result<Self> {
+        Ok(Self {
+            fc1: linear(d_model, ffn_dim, vb.pp("fc1"))?,
+            fc2: linear(ffn_dim, d_model, vb.pp("fc2"))?,
+        })
+    }
+}
+
+struct EncoderLayer {
+    self_attn: Attention,
+    self_attn_norm: LayerNorm,
+    mlp: Mlp,
+    final_norm: LayerNorm,
+}
+
+impl EncoderLayer {
+    /// `x` is the `(num_frames, d_model)` hidden states of one audio.
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let h = self.self_attn_norm.forward(x)?;
+        let q = self.self_attn.split_heads(&self.self_attn.q.forward(&h)?)?;
+        let (k, v) = self.self_attn.kv(&h)?;
+        let h = self.self_attn.attend(&q, &k, &v)?;
+        let x = (x + self.self_attn.o.forward(&h)?)?;
+        let h = self.mlp.forward(&self.final_norm.forward(&x)?)?;
+        x + h
+    }
+
+    fn load(vb: VarBuilder, cfg: &WhisperConfig) -> Result<Self> {
+        let d_model = cfg.d_model;
+        Ok(Self {
+            self_attn: Attention::load(vb.pp("self_attn"), d_model, cfg.encoder_attention_heads)?,
+            self_attn_norm: layer_norm(d_model, 1e-5, vb.pp("self_attn_layer_norm"))?,
+            mlp: Mlp::load(vb.clone(), d_model, cfg.encoder_ffn_dim)?,
+            final_norm: layer_norm(d_model, 1e-5, vb.pp("final_layer_norm"))?,
+        })
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    attn: PagedAttention,
+    self_attn_norm: LayerNorm,
+    cross_attn: Attention,
+    cross_attn_norm: LayerNorm,
+    mlp: Mlp,
+    final_norm: LayerNorm,
+}
+
+impl DecoderLayer {
+    /// `x` is the `(batch, seq_len, d_model)` hidden states of the step, each sequence of which
+    /// attends to the encoder output of its audio through `cross_kv`.
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        attention_mask: Option<&Tensor>,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+        cross_kv: &[&(Tensor, Tensor)],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, d_model) = x.dims3()?;
+        let (num_heads, head_dim) = (self.self_attn.num_heads, self.self_attn.head_dim);
+        let h = self.self_attn_norm.forward(x)?;
+        let q = self.self_attn.q.forward(&h)?;
+        let k = self.self_attn.k.forward(&h)?;
+        let v = self.self_attn.v.forward(&h)?;
+        let (q, k, v) = if seq_len == 1 {
+            (
+                q.reshape((b_sz, num_heads, seq_len, head_dim))?,
+                k.reshape((b_sz, num_heads, seq_len, head_dim))?,
+                v.reshape((b_sz, num_heads, seq_len, head_dim))?,
+            )
+        } else {
+            let split = |x: Tensor| {
+                x.reshape((b_sz, seq_len, num_heads, head_dim))?
+                    .transpose(1, 2)?
+                    .contiguous()
+            };
+            (split(q)?, split(k)?, split(v)?)
+        };
+        let y = self.attn.forward(
+            &q,
+            &k,
+            &v,
+            attention_mask,
+            cache.map(|(k_, _)| k_.clone()),
+            cache.map(|(_, v_)| v_.clone()),
+            input_metadata,
+        )?;
+        let y = if attention_mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, d_model))?
+        } else {
+            y.reshape((b_sz, seq_len, d_model))?
+        };
+        let x = (x + self.self_attn.o.forward(&y)?)?;
+
+        let h = self.cross_attn_norm.forward(&x)?;
+        let mut outputs = Vec::with_capacity(b_sz);
+        for (b, (k, v)) in cross_kv.iter().enumerate() {
+            let h = h.i(b)?;
+            let q = self
+                .cross_attn
+                .split_heads(&self.cross_attn.q.forward(&h)?)?;
+            outputs.push(self.cross_attn.attend(&q, k, v)?);
+        }
+        let x = (&x + self.cross_attn.o.forward(&Tensor::stack(&outputs, 0)?)?)?;
+
+        let h = self.mlp.forward(&self.final_norm.forward(&x)?)?;
+        x + h
+    }
+
+    fn load(vb: VarBuilder, cfg: &WhisperConfig) -> Result<Self> {
+        let d_model = cfg.d_model;
+        let num_heads = cfg.decoder_attention_heads;
+        let head_dim = d_model / num_heads;
+        Ok(Self {
+            self_attn: Attention::load(vb.pp("self_attn"), d_model, num_heads)?,
+            attn: PagedAttention::new(
+                num_heads,
+                head_dim,
+                1. / ((head_dim as f32).sqrt()),
+                Some(num_heads),
+                None,
+                vb.device().clone(),
+                None,
+            )?,
+            self_attn_norm: layer_norm(d_model, 1e-5, vb.pp("self_attn_layer_norm"))?,
+            cross_attn: Attention::load(vb.pp("encoder_attn"), d_model, num_heads)?,
+            cross_attn_norm: layer_norm(d_model, 1e-5, vb.pp("encoder_attn_layer_norm"))?,
+            mlp: Mlp::load(vb.clone(), d_model, cfg.decoder_ffn_dim)?,
+            final_norm: layer_norm(d_model, 1e-5, vb.pp("final_layer_norm"))?,
+        })
+    }
+}
+
+pub struct Whisper {
+    conv1: Conv1d,
+    conv2: Conv1d,
+    /// `(max_source_positions, d_model)`.
+    encoder_positions: Tensor,
+    encoder: Vec<EncoderLayer>,
+    encoder_norm: LayerNorm,
+    embed_tokens: Embedding,
+    decoder_positions: Embedding,
+    decoder: Vec<DecoderLayer>,
+    decoder_norm: LayerNorm,
+    /// Keys and values of the cross-attention of each decoder layer, by sequence id.
+    cross_kv: HashMap<usize, Vec<(Tensor, Tensor)>>,
+    cfg: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl Whisper {
+    /// Encode the `(num_audios, num_mel_bins, num_frames)` log-mel spectrograms of
+    /// `input_features` into the `(num_audios, max_source_positions, d_model)` hidden states the
+    /// decoder attends to.
+    pub fn encode(&self, input_features: &Tensor) -> Result<Tensor> {
+        let x = self.conv1.forward(input_features)?.gelu_erf()?;
+        let x = self.conv2.forward(&x)?.gelu_erf()?.transpose(1, 2)?;
+        let num_positions = x.dim(1)?;
+        let positions = self.encoder_positions.narrow(0, 0, num_positions)?;
+        let x = x.broadcast_add(&positions)?;
+        let mut outputs = Vec::new();
+        for i in 0..x.dim(0)? {
+            let mut h = x.i(i)?.contiguous()?;
+            for layer in &self.encoder {
+                h = layer.forward(&h)?;
+            }
+            outputs.push(self.encoder_norm.forward(&h)?);
+        }
+        Tensor::stack(&outputs, 0)
+    }
+
+    fn prepare_decoder_attention_mask(&self, b_size: usize, tgt_len: usize) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, tgt_len))?
+            .contiguous()?
+            .to_dtype(self.dtype)
+    }
+
+    /// Logits of the next token of every sequence of the step. At the prompt step, the encoder
+    /// output of the audio of each sequence is in `audio_features`.
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        input_positions: &[Vec<usize>],
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+        audio_features: &[Option<Tensor>],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if input_metadata.is_prompt {
+            for (&seq_id, features) in zip(&input_metadata.seq_ids, audio_features) {
+                let Some(features) = features else {
+                    candle::bail!(
+                        "Whisper transcribes audio, send it to `/v1/audio/transcriptions`."
+                    )
+                };
+                let features = features.squeeze(0)?;
+                let cross_kv = self
+                    .decoder
+                    .iter()
+                    .map(|layer| layer.cross_attn.kv(&features))
+                    .collect::<Result<Vec<_>>>()?;
+                self.cross_kv.insert(seq_id, cross_kv);
+            }
+        } else if input_metadata.seq_ids.len() != b_sz {
+            candle::bail!("Whisper decodes one token per sequence and step.")
+        }
+        let cross_kv = input_metadata
+            .seq_ids
+            .iter()
+            .map(|seq_id| match self.cross_kv.get(seq_id) {
+                Some(cross_kv) => Ok(cross_kv),
+                None => candle::bail!("No encoder output for sequence {seq_id}."),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let positions = input_positions
+            .iter()
+            .flat_map(|positions| {
+                let mut positions = positions.iter().map(|&p| p as u32).collect::<Vec<_>>();
+                positions.resize(seq_len, 0);
+                positions
+            })
+            .collect::<Vec<_>>();
+        let positions = Tensor::from_vec(positions, (b_sz, seq_len), &self.device)?;
+        let mut x =
+            (self.embed_tokens.forward(x)? + self.decoder_positions.forward(&positions)?)?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            Some(self.prepare_decoder_attention_mask(b_sz, seq_len)?)
+        };
+        for (i, layer) in self.decoder.iter_mut().enumerate() {
+            let layer_cross_kv = cross_kv.iter().map(|kv| &kv[i]).collect::<Vec<_>>();
+            x = layer.forward(
+                &x,
+                attention_mask.as_ref(),
+                kv_caches.map(|caches| (&caches[i].0, &caches[i].1)),
+                input_metadata,
+                &layer_cross_kv,
+            )?;
+        }
+        // Prompts are padded to the longest one, their last tokens are sampled.
+        let last_tokens = (0..b_sz)
+            .map(|b| {
+                let len = input_metadata.prompt_lens.get(b).copied().unwrap_or(1);
+                x.i((b, len - 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let x = self
+            .decoder_norm
+            .forward(&Tensor::stack(&last_tokens, 0)?)?;
+        let logits = x.matmul(&self.embed_tokens.embeddings().t()?)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    /// Drop the cross-attention of finished sequences.
+    pub fn free_sequences(&mut self, seq_ids: &[usize]) {
+        for seq_id in seq_ids {
+            self.cross_kv.remove(seq_id);
+        }
+    }
+
+    pub fn new(
+        vb: VarBuilder,
+        whisper_cfg: &WhisperConfig,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let d_model = whisper_cfg.d_model;
+        let encoder_vb = vb.pp("model.encoder");
+        let decoder_vb = vb.pp("model.decoder");
+        let conv = |padding, stride| Conv1dConfig {
+            padding,
+            stride,
+            ..Default::default()
+        };
+        let conv1 = conv1d(
+            whisper_cfg.num_mel_bins,
+            d_model,
+            3,
+            conv(1, 1),
+            encoder_vb.pp("conv1"),
+        )?;
+        let conv2 = conv1d(d_model, d_model, 3, conv(1, 2), encoder_vb.pp("conv2"))?;
+        let encoder = (0..whisper_cfg.encoder_layers)
+            .map(|i| EncoderLayer::load(encoder_vb.pp(format!("layers.{i}")), whisper_cfg))
+            .collect::<Result<Vec<_>>>()?;
+        let decoder = (0..whisper_cfg.decoder_layers)
+            .map(|i| DecoderLayer::load(decoder_vb.pp(format!("layers.{i}")), whisper_cfg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            conv1,
+            conv2,
+            encoder_positions: encoder_vb.get(
+                (whisper_cfg.max_source_positions, d_model),
+                "embed_positions.weight",
+            )?,
+            encoder,
+            encoder_norm: layer_norm(d_model, 1e-5, encoder_vb.pp("layer_norm"))?,
+            embed_tokens: embedding(
+                whisper_cfg.vocab_size,
+                d_model,
+                decoder_vb.pp("embed_tokens"),
+            )?,
+            decoder_positions: embedding(
+                whisper_cfg.max_target_positions,
+                d_model,
+                decoder_vb.pp("embed_positions"),
+            )?,
+            decoder,
+            decoder_norm: layer_norm(d_model, 1e-5, decoder_vb.pp("layer_norm"))?,
+            cross_kv: HashMap::new(),
+            cfg: cfg.clone(),
+            dtype,
+            device: device.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
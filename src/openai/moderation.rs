@@ -0,0 +1,63 @@
+//! Regex filters of the generated text, the `Moderator` the server can be started with.
+use regex::Regex;
+
+use super::{
+    hooks::{ModerationContext, ModerationMatch, Moderator},
+    responses::APIError,
+};
+
+/// Reports the matches of a regex in the generated text, e.g. URLs or a list of words, and halts
+/// the choice on them if `halt` is set.
+#[derive(Clone, Debug)]
+pub struct RegexModerator {
+    category: String,
+    regex: Regex,
+    halt: bool,
+}
+
+impl RegexModerator {
+    pub fn new(category: &str, pattern: &str, halt: bool) -> Result<Self, APIError> {
+        if category.is_empty() {
+            return Err(APIError::new(format!(
+                "The moderation pattern {pattern} needs a category."
+            )));
+        }
+        let regex = Regex::new(pattern)
+            .map_err(|e| APIError::new(format!("Invalid moderation pattern {pattern}: {e}")))?;
+        Ok(Self {
+            category: category.to_string(),
+            regex,
+            halt,
+        })
+    }
+
+    /// The moderator of `category=pattern`, as given on the command line.
+    pub fn parse(spec: &str, halt: bool) -> Result<Self, APIError> {
+        let (category, pattern) = spec.split_once('=').ok_or_else(|| {
+            APIError::new(format!(
+                "A moderation pattern is given as category=pattern, got {spec}."
+            ))
+        })?;
+        Self::new(category, pattern, halt)
+    }
+}
+
+impl Moderator for RegexModerator {
+    fn moderate(&self, context: &ModerationContext) -> Option<ModerationMatch> {
+        let new_start = context.text.len() - context.new_text.len();
+        // A match growing with the new token, e.g. a URL, was reported when it first matched.
+        let reported = self
+            .regex
+            .find_iter(&context.text[..new_start])
+            .map(|m| m.start())
+            .collect::<Vec<_>>();
+        self.regex
+            .find_iter(context.text)
+            .find(|m| m.end() > new_start && !m.is_empty() && !reported.contains(&m.start()))
+            .map(|m| ModerationMatch {
+                category: self.category.clone(),
+                text: m.as_str().to_string(),
+                halt: self.halt,
+            })
+    }
+}
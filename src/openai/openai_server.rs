@@ -1,105 +1,369 @@
-use super::requests::ChatCompletionRequest;
-use super::requests::Messages;
-use super::responses::{APIError, ChatCompletionResponse, ChatResponder};
-use super::sampling_params::{EarlyStoppingCondition, SamplingParams};
-use super::streaming::{Streamer, StreamingStatus};
+use super::audio_processor::{AudioProcessor, CHUNK_LENGTH, SAMPLE_RATE};
+use super::batches::{
+    parse_batch_input, BatchErrors, BatchInput, BatchList, BatchOutput, BatchOutputResponse,
+    BatchStatus, BATCH_ENDPOINTS, COMPLETION_WINDOW,
+};
+use super::fim::FimTemplate;
+use super::image_processor::ImageProcessor;
+use super::pipelines::llm_engine::SleepLevel;
+use super::requests::{
+    AdminConfigUpdate, ChatCompletionRequest, CompletionRequest, CreateBatchRequest,
+    FileUploadQuery, SleepQuery, StreamOptions, WatermarkDetectRequest,
+};
+use super::requests::{ContentPart, MessageContent, Messages, ResponseFormat, Tool};
+use super::response_cache::{CachedResponse, ResponseCache};
+use super::responses::{
+    APIError, ActiveRequestList, AdminConfig, AdminResponder, AudioResponder, BatchResponder,
+    ChatChoice, ChatCompletionResponse, ChatCompletionUsageResponse, ChatResponder,
+    CompletionChoice, CompletionResponse, RecoveredRequestStatus, SleepResponder, SleepStatus,
+    TranscriptionResponse, TranscriptionVerboseResponse, WatermarkDetectResponse,
+    WatermarkResponder,
+};
+use super::sampling_params::{EarlyStoppingCondition, SamplingParams, SAMPLING_EPS};
+use super::streaming::{ChatResponse, Streamer};
+use super::tool_calls::tools_prompt;
+use super::transcription::{
+    format_srt, format_vtt, segments_text, TranscriptionFormat, TranscriptionSegment,
+};
+use super::utils::{get_created_time_secs, get_time_millis};
+use super::validation::{validate_chat_request, validate_completion_request};
+use super::watermark::DEFAULT_Z_THRESHOLD;
 use super::OpenAIServerData;
+use crate::scheduler::{sequence::SequenceGroupMetrics, CacheStats, SchedulerSnapshot};
+use crate::try_api;
 use axum::response::sse::KeepAlive;
 use axum::{
-    extract::{Json, State},
-    response::Sse,
+    body::Bytes,
+    extract::{rejection::JsonRejection, Json, Multipart, Path, Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Sse},
 };
+use candle_core::Tensor;
+use either::Either;
 use flume;
+use futures::StreamExt;
 use std::env;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokenizers::Encoding;
 use tokio::time::Duration;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
-// fn verify_model(data: &OpenAIServerData<'_>, model_name: &String) -> Result<(), APIError> {
-//     let current_name = {
-//         let model = data.model.lock().unwrap();
-//         model.get_pipeline().name().to_string()
-//     };
-//     if &current_name != model_name {
-//         Err(APIError::new(format!(
-//             "Model name `{model_name}` is invalid."
-//         )))
-//     } else {
-//         Ok(())
-//     }
-// }
-
-// Get prompt, roles
+
+/// Default interval of the keep-alive comments of streams, `KEEP_ALIVE_INTERVAL` overrides it.
+const KEEP_ALIVE_INTERVAL_MS: u64 = 10_000;
+
+// Requests have to name one of the served models, when the server was given their names.
+fn verify_model(data: &OpenAIServerData, model_name: &str) -> Result<(), APIError> {
+    let names = &data.served_model_names;
+    if names.is_empty() || names.iter().any(|name| name == model_name) {
+        return Ok(());
+    }
+    Err(APIError::new(format!(
+        "The model `{model_name}` does not exist, the server serves {}.",
+        names.join(", ")
+    )))
+}
+
+/// Why a request is refused before it is tokenized.
+enum Refusal {
+    Sleeping(APIError),
+    Overloaded(APIError),
+}
+
+impl From<Refusal> for ChatResponder {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Sleeping(e) => ChatResponder::Unavailable(e),
+            Refusal::Overloaded(e) => ChatResponder::Overloaded(e),
+        }
+    }
+}
+
+impl From<Refusal> for AudioResponder {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Sleeping(e) => AudioResponder::Unavailable(e),
+            Refusal::Overloaded(e) => AudioResponder::Overloaded(e),
+        }
+    }
+}
+
+// Refuse requests while the engine sleeps or too many requests wait to be scheduled, so that
+// clients retry later instead of queueing behind them.
+async fn admit(data: &OpenAIServerData) -> Result<(), Refusal> {
+    let model = data.model.lock().await;
+    model.check_awake().map_err(Refusal::Sleeping)?;
+    match data.max_waiting_requests {
+        Some(max) if model.num_waiting_requests() >= max => {
+            Err(Refusal::Overloaded(APIError::new(format!(
+                "The server is overloaded, {max} requests are waiting to be scheduled."
+            ))))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Get prompt, roles and the URLs of the images, each of which is replaced by an image
+// placeholder in the prompt.
 async fn get_gen_prompt(
     data: &OpenAIServerData,
     request: &ChatCompletionRequest,
-) -> Result<String, APIError> {
+) -> Result<(String, Vec<String>), APIError> {
     let mut model = data.model.lock().await;
+    let model_name = model.get_pipeline().name().to_string();
+    if model.get_pipeline().is_encoder_decoder() {
+        return Err(APIError::new(format!(
+            "Model `{model_name}` is an encoder-decoder model, use `/v1/completions`."
+        )));
+    }
+    if model.get_pipeline().audio_processor().is_some() {
+        return Err(APIError::new(format!(
+            "Model `{model_name}` transcribes audio, use `/v1/audio/transcriptions`."
+        )));
+    }
+    let image_token = model
+        .get_pipeline()
+        .image_processor()
+        .map(|image_processor| image_processor.image_token.clone());
     let conversation = model
         .get_mut_pipeline()
         .get_conversation(data.record_conversation);
 
+    let mut image_urls = Vec::new();
     match &request.messages {
         Messages::Literal(msg) => {
-            return Ok(msg.clone());
+            return Ok((msg.clone(), image_urls));
         }
         Messages::Map(messages) => {
+            let mut system_message = None;
             for message in messages {
-                let role = message
-                    .get("role")
-                    .ok_or(APIError::new("Message key `role` not found.".to_string()))?;
-                let content = message
-                    .get("content")
-                    .ok_or(APIError::new(
-                        "Message key `content` not found.".to_string(),
-                    ))?
-                    .clone();
+                let role = match message.get("role") {
+                    Some(MessageContent::Text(role)) => role,
+                    _ => return Err(APIError::new("Message key `role` not found.".to_string())),
+                };
+                let content = match message.get("content").ok_or(APIError::new(
+                    "Message key `content` not found.".to_string(),
+                ))? {
+                    MessageContent::Text(text) => text.clone(),
+                    MessageContent::Parts(parts) => parts
+                        .iter()
+                        .map(|part| match part {
+                            ContentPart::Text { text } => Ok(text.clone()),
+                            ContentPart::ImageUrl { image_url } => {
+                                let image_token = image_token.clone().ok_or(APIError::new(
+                                    format!("Model `{model_name}` does not accept image inputs."),
+                                ))?;
+                                image_urls.push(image_url.url.clone());
+                                Ok(image_token)
+                            }
+                        })
+                        .collect::<Result<Vec<_>, APIError>>()?
+                        .join("\n"),
+                };
 
                 if role == "system" {
-                    conversation.set_system_message(content);
+                    system_message = Some(content);
                 } else {
                     conversation.append_message(role.to_string(), content)
                 }
             }
+            // The functions are described to the model after the system message.
+            if let Some(tools) = request.tools.as_ref().filter(|tools| !tools.is_empty()) {
+                let tools = tools_prompt(tools);
+                system_message = Some(match system_message {
+                    Some(system_message) => format!("{system_message}\n\n{tools}"),
+                    None => tools,
+                });
+            }
+            if let Some(system_message) = system_message {
+                conversation.set_system_message(system_message);
+            }
         }
     }
 
-    Ok(conversation.get_prompt())
+    Ok((conversation.get_prompt(), image_urls))
+}
+
+// Decode and preprocess the images of a request into pixel values of shape
+// (num_images, 3, height, width).
+async fn get_pixel_values(
+    data: &OpenAIServerData,
+    image_urls: &[String],
+) -> Result<Option<Tensor>, APIError> {
+    if image_urls.is_empty() {
+        return Ok(None);
+    }
+    let (image_processor, dtype, device) = {
+        let model = data.model.lock().await;
+        let pipeline = model.get_pipeline();
+        let image_processor = pipeline
+            .image_processor()
+            .cloned()
+            .ok_or(APIError::new(format!(
+                "Model `{}` does not accept image inputs.",
+                pipeline.name()
+            )))?;
+        (
+            image_processor,
+            pipeline.get_dtype(),
+            pipeline.device().clone(),
+        )
+    };
+    let images = image_urls
+        .iter()
+        .map(|url| {
+            let image = ImageProcessor::load_image_url(url)?;
+            image_processor.preprocess(&image, dtype, &device)
+        })
+        .collect::<Result<Vec<_>, APIError>>()?;
+    Ok(Some(try_api!(Tensor::stack(&images, 0))))
 }
 
+/// Encoding of `prompt`, its length counting image features, and the tokens a choice may
+/// generate after it: `max_tokens`, or without it as many as the context of the model (and, with
+/// `cap_max_tokens_to_free_blocks`, the free blocks of the KV cache) leaves after the prompt, up
+/// to the default of the server.
 async fn check_length(
-    request: &ChatCompletionRequest,
+    max_tokens: Option<usize>,
     prompt: String,
+    num_images: usize,
     data: &OpenAIServerData,
-) -> Result<Encoding, APIError> {
-    let token_ids = {
+) -> Result<(Encoding, usize, usize), APIError> {
+    // Encoding is done on the tokenizer pool, the engine keeps running meanwhile.
+    let token_ids = data.tokenizer_pool.encode(prompt).await?;
+    let (prompt_len, attention_sinks, free_tokens) = {
         let model = data.model.lock().await;
-        model
-            .get_pipeline()
-            .tokenizer()
-            .tokenizer()
-            .encode(prompt, false)
-            .map_err(APIError::from)?
+        // Every image placeholder stands for one token per image feature.
+        let prompt_len = match model.get_pipeline().image_processor() {
+            Some(image_processor) if num_images > 0 => {
+                let num_placeholders = token_ids
+                    .get_ids()
+                    .iter()
+                    .filter(|&&id| id == image_processor.image_token_id)
+                    .count();
+                if num_placeholders != num_images {
+                    return Err(APIError::new(format!(
+                        "The messages contain {num_placeholders} image placeholders for {num_images} images."
+                    )));
+                }
+                image_processor
+                    .expand_image_tokens(token_ids.get_ids())
+                    .len()
+            }
+            _ => token_ids.len(),
+        };
+        let attention_sinks = model.get_cache_config().attention_sinks.is_some();
+        let free_tokens = data
+            .cap_max_tokens_to_free_blocks
+            .then(|| model.num_free_gpu_tokens());
+        (prompt_len, attention_sinks, free_tokens)
     };
 
-    let max_gen_tokens = request
-        .max_tokens
-        .unwrap_or(data.pipeline_config.default_max_tokens);
+    let pipeline_config = data.pipeline_config();
+    let max_gen_tokens = match max_tokens {
+        Some(max_tokens) => max_tokens,
+        None => {
+            let mut cap = pipeline_config.default_max_tokens;
+            if !attention_sinks {
+                cap = cap.min(pipeline_config.max_model_len.saturating_sub(prompt_len));
+            }
+            // A prompt the free blocks do not hold waits for them, it still generates a token.
+            if let Some(free_tokens) = free_tokens {
+                cap = cap.min(free_tokens.saturating_sub(prompt_len).max(1));
+            }
+            cap
+        }
+    };
+    // With attention sinks, generating evicts the KV cache of older tokens and positions stay
+    // within the cache, only the prompt has to fit the context.
+    let requested_len = if attention_sinks {
+        prompt_len
+    } else {
+        prompt_len + max_gen_tokens
+    };
 
-    if token_ids.len() + max_gen_tokens > data.pipeline_config.max_model_len {
+    if requested_len > pipeline_config.max_model_len || max_gen_tokens == 0 {
         Err(APIError::new(format!(
             "This model's maximum context length is {} tokens. \
             However, you requested {} tokens ({} in the messages, \
             {} in the completion). \nPlease clear the chat history or reduce the length of the \
             messages.",
-            data.pipeline_config.max_model_len,
-            max_gen_tokens + token_ids.len(),
-            token_ids.len(),
+            pipeline_config.max_model_len,
+            max_gen_tokens + prompt_len,
+            prompt_len,
             max_gen_tokens
         )))
     } else {
-        Ok(token_ids)
+        Ok((token_ids, prompt_len, max_gen_tokens))
+    }
+}
+
+// Tokens of the negative prompt of a request. Its sequences generate as many tokens as the
+// choices, they have to fit the context as well.
+async fn get_negative_prompt(
+    data: &OpenAIServerData,
+    negative_prompt: &Option<String>,
+    max_tokens: Option<usize>,
+) -> Result<Option<Vec<u32>>, APIError> {
+    let Some(negative_prompt) = negative_prompt else {
+        return Ok(None);
+    };
+    if data.model.lock().await.get_pipeline().is_encoder_decoder() {
+        return Err(APIError::new_str(
+            "negative_prompt is not supported for encoder-decoder models.",
+        ));
+    }
+    let (token_ids, _, _) = check_length(max_tokens, negative_prompt.clone(), 0, data).await?;
+    Ok(Some(token_ids.get_ids().to_vec()))
+}
+
+/// Key of the response cache of a request to `endpoint`, `None` unless the server caches
+/// responses and the request is greedy (temperature 0), not streamed and does not dump its
+/// logits, which only running the model writes.
+fn response_cache_key(
+    data: &OpenAIServerData,
+    endpoint: &str,
+    request: &impl serde::Serialize,
+    stream: Option<bool>,
+    temperature: Option<f32>,
+    dump_logits: Option<usize>,
+) -> Option<String> {
+    data.response_cache.as_ref()?;
+    let temperature = temperature.unwrap_or(data.pipeline_config().temperature);
+    if stream.unwrap_or(false) || temperature >= SAMPLING_EPS || dump_logits.is_some() {
+        return None;
+    }
+    ResponseCache::key(endpoint, request)
+}
+
+fn cached_response(data: &OpenAIServerData, key: &Option<String>) -> Option<CachedResponse> {
+    let response = data.response_cache.as_ref()?.get(key.as_ref()?)?;
+    info!("response served from the cache");
+    Some(response)
+}
+
+fn cache_response(
+    data: &OpenAIServerData,
+    key: Option<String>,
+    response: impl FnOnce() -> CachedResponse,
+) {
+    if let (Some(cache), Some(key)) = (&data.response_cache, key) {
+        cache.insert(key, response());
+    }
+}
+
+// Options of the stream of a request, `None` when the request is not streamed.
+fn get_stream_options(
+    stream: Option<bool>,
+    stream_options: &Option<StreamOptions>,
+) -> Result<Option<StreamOptions>, APIError> {
+    match (stream.unwrap_or(false), stream_options) {
+        (true, options) => Ok(Some(options.clone().unwrap_or_default())),
+        (false, Some(_)) => Err(APIError::new_str(
+            "`stream_options` is only allowed when streaming.",
+        )),
+        (false, None) => Ok(None),
     }
 }
 
@@ -112,13 +376,50 @@ async fn check_length(
 )]
 pub async fn chat_completions(
     State(data): State<Arc<OpenAIServerData>>,
-    request: Json<ChatCompletionRequest>,
+    headers: HeaderMap,
+    request: Result<Json<ChatCompletionRequest>, JsonRejection>,
 ) -> ChatResponder {
-    // let model_name = &request.model;
-    // let res = verify_model(&data, model_name);
-    // if res.is_err() {
-    //     return Either::Left(Err(res.err().unwrap()));
-    // }
+    match request {
+        Ok(request) => {
+            let tenant = data.tenant(bearer_token(&headers));
+            chat_completion(data, request.0, false, tenant).await
+        }
+        Err(rejection) => ChatResponder::ValidationError(APIError::new(rejection.body_text())),
+    }
+}
+
+/// Requests of `low_priority` are scheduled behind the others, batches throttle them instead of
+/// refusing them when the server is overloaded.
+async fn chat_completion(
+    data: Arc<OpenAIServerData>,
+    request: ChatCompletionRequest,
+    low_priority: bool,
+    tenant: Option<String>,
+) -> ChatResponder {
+    if let Err(e) = verify_model(&data, &request.model) {
+        return ChatResponder::ModelNotFound(e);
+    }
+    if let Err(e) = validate_chat_request(&request, data.reject_unknown_fields, data.max_logprobs) {
+        return ChatResponder::ValidationError(e);
+    }
+    let cache_key = response_cache_key(
+        &data,
+        "chat",
+        &request,
+        request.stream,
+        request.temperature,
+        request.dump_logits,
+    );
+    if let Some(CachedResponse::Chat(mut response)) = cached_response(&data, &cache_key) {
+        response.id = format!("cmpl-{}", Uuid::new_v4());
+        response.created = get_created_time_secs();
+        return ChatResponder::Completion(response, None);
+    }
+    if !low_priority {
+        if let Err(refusal) = admit(&data).await {
+            return refusal.into();
+        }
+    }
 
     if request.logit_bias.as_ref().is_some()
         && request.logit_bias.as_ref().is_some_and(|x| !x.is_empty())
@@ -132,18 +433,39 @@ pub async fn chat_completions(
     if prompt.is_err() {
         return ChatResponder::ValidationError(prompt.err().unwrap());
     }
-    let prompt = prompt.unwrap();
+    let (prompt, image_urls) = prompt.unwrap();
 
-    let token_ids = check_length(&request, prompt.clone(), &data).await;
+    let token_ids = check_length(request.max_tokens, prompt.clone(), image_urls.len(), &data).await;
     if token_ids.is_err() {
         return ChatResponder::ValidationError(token_ids.err().unwrap());
     }
-    let token_ids: Encoding = token_ids.unwrap();
+    let (token_ids, prompt_len, max_tokens): (Encoding, usize, usize) = token_ids.unwrap();
+
+    let stream_options = match get_stream_options(request.stream, &request.stream_options) {
+        Ok(stream_options) => stream_options,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
 
-    println!("\n\n\nPrompt {:?}", prompt);
+    let pixel_values = get_pixel_values(&data, &image_urls).await;
+    if pixel_values.is_err() {
+        return ChatResponder::ValidationError(pixel_values.err().unwrap());
+    }
+    let pixel_values = pixel_values.unwrap();
+
+    let logprobs = request.logprobs.unwrap_or(false);
+    if request.top_logprobs.is_some() && !logprobs {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "`top_logprobs` needs `logprobs` to be true.",
+        ));
+    }
 
     let request_id = format!("cmpl-{}", Uuid::new_v4());
+    info!(%request_id, prompt_tokens = token_ids.len(), "request received");
+    debug!(%request_id, %prompt, "prompt");
 
+    let pipeline_config = data.pipeline_config();
+    let temperature = request.temperature.unwrap_or(pipeline_config.temperature);
+    let (top_p, top_k) = pipeline_config.top_p_top_k(temperature);
     let sampling_params = SamplingParams::new(
         request.n.unwrap_or(1),
         request.best_of,
@@ -151,21 +473,17 @@ pub async fn chat_completions(
         request.frequency_penalty.unwrap_or(0.0),
         request
             .repetition_penalty
-            .unwrap_or(data.pipeline_config.penalty),
-        request
-            .temperature
-            .unwrap_or(data.pipeline_config.temperature),
-        request.top_p.unwrap_or(1.0),
-        request.top_k.unwrap_or(-1),
+            .unwrap_or(pipeline_config.penalty),
+        temperature,
+        request.top_p.unwrap_or(top_p),
+        request.top_k.unwrap_or(top_k),
         request.use_beam_search.unwrap_or(false),
-        1.0,
+        request.length_penalty.unwrap_or(1.0),
         EarlyStoppingCondition::UnlikelyBetterCandidates,
         request.stop.clone(),
         request.stop_token_ids.clone().unwrap_or_default(),
         request.ignore_eos.unwrap_or(false),
-        request
-            .max_tokens
-            .unwrap_or(data.pipeline_config.default_max_tokens),
+        max_tokens,
         None,
         None,
         request.skip_special_tokens.unwrap_or(true),
@@ -173,76 +491,1401 @@ pub async fn chat_completions(
     if sampling_params.is_err() {
         return ChatResponder::ValidationError(sampling_params.err().unwrap());
     }
-    let sampling_params = sampling_params.unwrap();
+    let mut sampling_params = sampling_params.unwrap();
+    if let Err(e) = sampling_params.set_logprobs(
+        logprobs.then(|| request.top_logprobs.unwrap_or(0)),
+        data.max_logprobs,
+    ) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_timeout(request.timeout) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_min_tokens(request.min_tokens) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_sampling_schedule(request.sampling_schedule.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.token_healing = request.token_healing.unwrap_or(false);
+    sampling_params.spaces_between_special_tokens =
+        request.spaces_between_special_tokens.unwrap_or(true);
+    sampling_params.low_priority = low_priority;
+    sampling_params.tenant = tenant;
+    if let Err(e) = sampling_params.set_guided_choice(request.guided_choice.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_conversation_id(request.conversation_id.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_kv_cache_retention(request.kv_cache_retention) {
+        return ChatResponder::ValidationError(e);
+    }
+    if request.negative_prompt.is_some() && !image_urls.is_empty() {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "negative_prompt is not supported for messages with images.",
+        ));
+    }
+    let negative_prompt_ids =
+        match get_negative_prompt(&data, &request.negative_prompt, request.max_tokens).await {
+            Ok(negative_prompt_ids) => negative_prompt_ids,
+            Err(e) => return ChatResponder::ValidationError(e),
+        };
+    if let Err(e) = sampling_params.set_guidance(negative_prompt_ids, request.guidance_scale) {
+        return ChatResponder::ValidationError(e);
+    }
+    let json_mode = request.response_format == Some(ResponseFormat::JsonObject);
+    if let Err(e) = sampling_params.set_json_mode(json_mode) {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.return_tokens = request.return_tokens.unwrap_or(false);
+    if let Err(e) =
+        sampling_params.set_control_vectors(request.control_vectors.clone(), &data.control_vectors)
+    {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.priority = request.priority.unwrap_or(0);
+    if let Err(e) =
+        sampling_params.set_logit_dump(request.dump_logits, data.logit_dumps.as_ref(), &request_id)
+    {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.tools = request
+        .tools
+        .iter()
+        .flatten()
+        .map(|Tool::Function { function }| function.name.clone())
+        .collect();
+    let prompt_token_ids = prompt_token_ids(&sampling_params, &token_ids);
+    // Streamed choices are sent as they are generated, before the best `n` could be picked.
+    if stream_options.is_some() && sampling_params.best_of != sampling_params.n {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "`best_of` must be equal to `n` when streaming.",
+        ));
+    }
+
+    let generated = generate(
+        data.clone(),
+        request_id.clone(),
+        token_ids,
+        sampling_params,
+        prompt_len,
+        prompt_token_ids.clone(),
+        logprobs,
+        stream_options,
+        pixel_values,
+        false,
+    )
+    .await;
+    match generated {
+        Ok(Either::Left(streamer)) => ChatResponder::Streamer(streamer),
+        Ok(Either::Right((choices, usage, metrics, system_fingerprint))) => {
+            let response = ChatCompletionResponse {
+                id: request_id,
+                choices,
+                created: usage.created,
+                model: request.model.clone(),
+                object: "chat.completion",
+                system_fingerprint: Some(system_fingerprint),
+                usage,
+                prompt_token_ids,
+            };
+            cache_response(&data, cache_key, || CachedResponse::Chat(response.clone()));
+            ChatResponder::Completion(response, metrics)
+        }
+        // Errors about a parameter of the request are the ones of its client.
+        Err(e) if e.param().is_some() => ChatResponder::ValidationError(e),
+        Err(e) => ChatResponder::ModelError(e),
+    }
+}
+
+/// Token ids of the prompt, returned with the response of the requests with `return_tokens`.
+fn prompt_token_ids(sampling_params: &SamplingParams, token_ids: &Encoding) -> Option<Vec<usize>> {
+    sampling_params
+        .return_tokens
+        .then(|| token_ids.get_ids().iter().map(|&id| id as usize).collect())
+}
+
+/// Choices, usage, metrics and system fingerprint of a request which was not streamed.
+type CompletedRequest = (
+    Vec<ChatChoice>,
+    ChatCompletionUsageResponse,
+    Option<SequenceGroupMetrics>,
+    String,
+);
 
+// Send a request to the inference engine. Streamed requests, the ones with `stream` options, return
+// the stream of their chunks, the others wait until the request finished and return its choices,
+// usage and metrics.
+#[allow(clippy::too_many_arguments)]
+async fn generate(
+    data: Arc<OpenAIServerData>,
+    request_id: String,
+    token_ids: Encoding,
+    sampling_params: SamplingParams,
+    prompt_len: usize,
+    prompt_token_ids: Option<Vec<usize>>,
+    logprobs: bool,
+    stream: Option<StreamOptions>,
+    pixel_values: Option<Tensor>,
+    text_completion: bool,
+) -> Result<Either<Sse<Streamer>, CompletedRequest>, APIError> {
+    data.model.lock().await.check_awake()?;
     let (response_tx, rx) = flume::unbounded();
     // println!("{:?}", sampling_params);
 
-    if request.stream.is_some_and(|x| x) {
+    if let Some(stream_options) = stream {
+        // Prepared off the engine, which takes the request in at its next step.
+        let admissions = data.admissions.clone();
         let _ = tokio::task::spawn_blocking(move || {
-            tokio::runtime::Handle::current().block_on(async move {
-                {
-                    //send completion request to inference engine
-                    let mut model = data.model.lock().await;
-                    model.add_request(
-                        token_ids,
-                        request_id.clone(),
-                        SystemTime::now(),
-                        sampling_params,
-                        request.logprobs.unwrap_or(false),
-                        Some(response_tx),
-                    );
-                    model.notify.notify_one();
-                }
-            });
+            admissions.submit(
+                token_ids,
+                request_id,
+                SystemTime::now(),
+                sampling_params,
+                logprobs,
+                Some(response_tx),
+                pixel_values,
+            );
         });
-        ChatResponder::Streamer(
-            Sse::new(Streamer {
-                rx,
-                status: StreamingStatus::Uninitilized,
-            })
+        Ok(Either::Left(
+            Sse::new(
+                Streamer::new(rx, text_completion, prompt_len, stream_options)
+                    .with_prompt_token_ids(prompt_token_ids),
+            )
+            // Comments sent while no chunk comes (long prefills, preemption) keep proxies from
+            // closing the connection.
             .keep_alive(
                 KeepAlive::new()
                     .interval(Duration::from_millis(
                         env::var("KEEP_ALIVE_INTERVAL")
-                            .map(|val| val.parse::<u64>().unwrap_or(100))
-                            .unwrap_or(100),
+                            .map(|val| val.parse::<u64>().unwrap_or(KEEP_ALIVE_INTERVAL_MS))
+                            .unwrap_or(KEEP_ALIVE_INTERVAL_MS),
                     ))
                     .text("keep-alive-text"),
             ),
-        )
+        ))
     } else {
         //send completion request to inference engine
-        let mut model = data.model.lock().await;
-        model.add_request(
-            token_ids,
-            request_id.clone(),
-            SystemTime::now(),
-            sampling_params,
-            request.logprobs.unwrap_or(false),
-            Some(response_tx),
+        let admissions = data.admissions.clone();
+        let id = request_id.clone();
+        try_api!(
+            tokio::task::spawn_blocking(move || {
+                admissions.submit(
+                    token_ids,
+                    id,
+                    SystemTime::now(),
+                    sampling_params,
+                    logprobs,
+                    Some(response_tx),
+                    pixel_values,
+                )
+            })
+            .await
         );
-        model.notify.notify_one();
-        // wait until current response finished
-        data.finish_notify.notified().await;
+        // Wait until the request finished, the engine records its response before it releases
+        // the engine. It reports why it aborted the request on its channel.
+        while let Ok(response) = rx.recv_async().await {
+            match response {
+                ChatResponse::Done => break,
+                // The engine refuses the prompts which do not fit in its KV cache.
+                ChatResponse::ValidationError(e) => {
+                    let param = if text_completion {
+                        "prompt"
+                    } else {
+                        "messages"
+                    };
+                    return Err(APIError::invalid_param(param, e));
+                }
+                ChatResponse::InternalError(e) | ChatResponse::ModelError(e) => {
+                    return Err(APIError::new(e))
+                }
+                _ => {}
+            }
+        }
         let model = data.model.lock().await;
-        if !model.completion_records.contains_key(&request_id) {
-            return ChatResponder::ModelError(APIError::from(format!(
+        match model.completion_records.get(&request_id) {
+            Some((choices, usage)) => Ok(Either::Right((
+                choices.to_vec(),
+                usage.clone(),
+                model.request_metrics(&request_id).copied(),
+                model.system_fingerprint_id().to_string(),
+            ))),
+            None => Err(APIError::from(format!(
                 "Unable to generate response for request {}",
                 request_id
+            ))),
+        }
+    }
+}
+
+// Prompt of a completion request, the fill-in-the-middle prompt of the model when the request
+// has a `suffix`. Also returns the token ending the middle part for the models that have one.
+async fn get_completion_prompt(
+    data: &OpenAIServerData,
+    request: &CompletionRequest,
+) -> Result<(String, Option<usize>), APIError> {
+    let model = data.model.lock().await;
+    if model.get_pipeline().audio_processor().is_some() {
+        return Err(APIError::new(format!(
+            "Model `{}` transcribes audio, use `/v1/audio/transcriptions`.",
+            model.get_pipeline().name()
+        )));
+    }
+    let suffix = match &request.suffix {
+        Some(suffix) if !suffix.is_empty() => suffix,
+        _ => return Ok((request.prompt.clone(), None)),
+    };
+    let tokenizer = model.get_pipeline().tokenizer().tokenizer();
+    let template = FimTemplate::detect(tokenizer).ok_or(APIError::new(format!(
+        "Model `{}` does not support fill-in-the-middle, `suffix` is not supported.",
+        model.get_pipeline().name()
+    )))?;
+    let end_token_id = template
+        .end_token()
+        .and_then(|token| tokenizer.token_to_id(token))
+        .map(|id| id as usize);
+    Ok((template.prompt(&request.prompt, suffix), end_token_id))
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/completions",
+    request_body = CompletionRequest,
+    responses((status = 200, description = "Completions"))
+)]
+pub async fn completions(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    request: Result<Json<CompletionRequest>, JsonRejection>,
+) -> ChatResponder {
+    match request {
+        Ok(request) => {
+            let tenant = data.tenant(bearer_token(&headers));
+            completion(data, request.0, false, tenant).await
+        }
+        Err(rejection) => ChatResponder::ValidationError(APIError::new(rejection.body_text())),
+    }
+}
+
+/// Requests of `low_priority` are scheduled behind the others.
+async fn completion(
+    data: Arc<OpenAIServerData>,
+    request: CompletionRequest,
+    low_priority: bool,
+    tenant: Option<String>,
+) -> ChatResponder {
+    if let Err(e) = verify_model(&data, &request.model) {
+        return ChatResponder::ModelNotFound(e);
+    }
+    if let Err(e) = validate_completion_request(&request, data.reject_unknown_fields) {
+        return ChatResponder::ValidationError(e);
+    }
+    let cache_key = response_cache_key(
+        &data,
+        "completions",
+        &request,
+        request.stream,
+        request.temperature,
+        request.dump_logits,
+    );
+    if let Some(CachedResponse::Text(mut response)) = cached_response(&data, &cache_key) {
+        response.id = format!("cmpl-{}", Uuid::new_v4());
+        response.created = get_created_time_secs();
+        return ChatResponder::TextCompletion(response, None);
+    }
+    if !low_priority {
+        if let Err(refusal) = admit(&data).await {
+            return refusal.into();
+        }
+    }
+    if request.logit_bias.as_ref().is_some_and(|x| !x.is_empty()) {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "`logit_bias` is not currently supported.",
+        ));
+    }
+
+    let (prompt, end_token_id) = match get_completion_prompt(&data, &request).await {
+        Ok(prompt) => prompt,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+
+    let (token_ids, prompt_len, max_tokens) =
+        match check_length(request.max_tokens, prompt.clone(), 0, &data).await {
+            Ok(token_ids) => token_ids,
+            Err(e) => return ChatResponder::ValidationError(e),
+        };
+
+    let stream_options = match get_stream_options(request.stream, &request.stream_options) {
+        Ok(stream_options) => stream_options,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+
+    let request_id = format!("cmpl-{}", Uuid::new_v4());
+    info!(%request_id, prompt_tokens = token_ids.len(), "request received");
+    debug!(%request_id, %prompt, "prompt");
+
+    let mut stop_token_ids = request.stop_token_ids.clone().unwrap_or_default();
+    stop_token_ids.extend(end_token_id);
+    let pipeline_config = data.pipeline_config();
+    let temperature = request.temperature.unwrap_or(pipeline_config.temperature);
+    let (top_p, top_k) = pipeline_config.top_p_top_k(temperature);
+    let sampling_params = SamplingParams::new(
+        request.n.unwrap_or(1),
+        request.best_of,
+        request.presence_penalty.unwrap_or(0.0),
+        request.frequency_penalty.unwrap_or(0.0),
+        request
+            .repetition_penalty
+            .unwrap_or(pipeline_config.penalty),
+        temperature,
+        request.top_p.unwrap_or(top_p),
+        request.top_k.unwrap_or(top_k),
+        request.use_beam_search.unwrap_or(false),
+        request.length_penalty.unwrap_or(1.0),
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        request.stop.clone(),
+        stop_token_ids,
+        request.ignore_eos.unwrap_or(false),
+        max_tokens,
+        None,
+        None,
+        request.skip_special_tokens.unwrap_or(true),
+    );
+    let mut sampling_params = match sampling_params {
+        Ok(sampling_params) => sampling_params,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+    if let Err(e) = sampling_params.set_timeout(request.timeout) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_min_tokens(request.min_tokens) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_sampling_schedule(request.sampling_schedule.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.token_healing = request.token_healing.unwrap_or(false);
+    sampling_params.spaces_between_special_tokens =
+        request.spaces_between_special_tokens.unwrap_or(true);
+    sampling_params.low_priority = low_priority;
+    sampling_params.tenant = tenant;
+    if let Err(e) = sampling_params.set_guided_choice(request.guided_choice.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_conversation_id(request.conversation_id.clone()) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_kv_cache_retention(request.kv_cache_retention) {
+        return ChatResponder::ValidationError(e);
+    }
+    let negative_prompt_ids =
+        match get_negative_prompt(&data, &request.negative_prompt, request.max_tokens).await {
+            Ok(negative_prompt_ids) => negative_prompt_ids,
+            Err(e) => return ChatResponder::ValidationError(e),
+        };
+    if let Err(e) = sampling_params.set_guidance(negative_prompt_ids, request.guidance_scale) {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.return_tokens = request.return_tokens.unwrap_or(false);
+    if let Err(e) =
+        sampling_params.set_control_vectors(request.control_vectors.clone(), &data.control_vectors)
+    {
+        return ChatResponder::ValidationError(e);
+    }
+    sampling_params.priority = request.priority.unwrap_or(0);
+    if let Err(e) =
+        sampling_params.set_logit_dump(request.dump_logits, data.logit_dumps.as_ref(), &request_id)
+    {
+        return ChatResponder::ValidationError(e);
+    }
+    let prompt_token_ids = prompt_token_ids(&sampling_params, &token_ids);
+    if stream_options.is_some() && sampling_params.best_of != sampling_params.n {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "`best_of` must be equal to `n` when streaming.",
+        ));
+    }
+
+    let generated = generate(
+        data.clone(),
+        request_id.clone(),
+        token_ids,
+        sampling_params,
+        prompt_len,
+        prompt_token_ids.clone(),
+        false,
+        stream_options,
+        None,
+        true,
+    )
+    .await;
+    match generated {
+        Ok(Either::Left(streamer)) => ChatResponder::Streamer(streamer),
+        Ok(Either::Right((choices, usage, metrics, system_fingerprint))) => {
+            let response = CompletionResponse {
+                id: request_id,
+                choices: choices.into_iter().map(CompletionChoice::from).collect(),
+                created: usage.created,
+                model: request.model.clone(),
+                object: "text_completion",
+                system_fingerprint: Some(system_fingerprint),
+                usage,
+                prompt_token_ids,
+            };
+            cache_response(&data, cache_key, || CachedResponse::Text(response.clone()));
+            ChatResponder::TextCompletion(response, metrics)
+        }
+        // Errors about a parameter of the request are the ones of its client.
+        Err(e) if e.param().is_some() => ChatResponder::ValidationError(e),
+        Err(e) => ChatResponder::ModelError(e),
+    }
+}
+
+/// Fields of a `multipart/form-data` request to the audio endpoints.
+#[derive(Default)]
+struct AudioRequest {
+    file: Option<Bytes>,
+    model: String,
+    language: Option<String>,
+    prompt: Option<String>,
+    response_format: TranscriptionFormat,
+    temperature: Option<f32>,
+}
+
+async fn read_audio_request(mut multipart: Multipart) -> Result<AudioRequest, APIError> {
+    let mut request = AudioRequest::default();
+    while let Some(field) = try_api!(multipart.next_field().await) {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            request.file = Some(try_api!(field.bytes().await));
+            continue;
+        }
+        let value = try_api!(field.text().await);
+        match name.as_str() {
+            "model" => request.model = value,
+            "language" => request.language = Some(value),
+            "prompt" => request.prompt = Some(value),
+            "response_format" => request.response_format = TranscriptionFormat::parse(&value)?,
+            "temperature" => {
+                request.temperature = Some(value.parse().map_err(|_| {
+                    APIError::new(format!("`temperature` must be a number, got `{value}`."))
+                })?)
+            }
+            _ => {}
+        }
+    }
+    Ok(request)
+}
+
+// Decoder prompt of Whisper for `task` (`transcribe` or `translate`) of audio in `language`,
+// after the text of the previous window when the request has a `prompt`.
+fn whisper_prompt(task: &str, language: &str, prompt: Option<&str>) -> String {
+    let previous = match prompt.map(str::trim) {
+        Some(prompt) if !prompt.is_empty() => format!("<|startofprev|> {prompt}"),
+        _ => String::new(),
+    };
+    format!("{previous}<|startoftranscript|><|{language}|><|{task}|><|notimestamps|>")
+}
+
+// Decode the audio file of a request and preprocess each window of it into the log-mel
+// spectrogram of its request. Also returns the length of the audio in seconds.
+async fn get_audio_features(
+    data: &OpenAIServerData,
+    file: Bytes,
+) -> Result<(Vec<Tensor>, f64), APIError> {
+    let (audio_processor, dtype, device) = {
+        let model = data.model.lock().await;
+        let pipeline = model.get_pipeline();
+        let audio_processor = pipeline
+            .audio_processor()
+            .cloned()
+            .ok_or(APIError::new(format!(
+                "Model `{}` does not accept audio inputs.",
+                pipeline.name()
+            )))?;
+        (
+            audio_processor,
+            pipeline.get_dtype(),
+            pipeline.device().clone(),
+        )
+    };
+    // The spectrogram takes a while for long audio, it is not computed on the runtime threads.
+    let features = tokio::task::spawn_blocking(move || {
+        let samples = AudioProcessor::decode_wav(&file)?;
+        let duration = samples.len() as f64 / SAMPLE_RATE as f64;
+        let features = AudioProcessor::chunks(&samples)
+            .map(|chunk| audio_processor.preprocess(chunk, dtype, &device))
+            .collect::<Result<Vec<_>, APIError>>()?;
+        Ok::<_, APIError>((features, duration))
+    });
+    try_api!(features.await)
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/audio/transcriptions",
+    request_body(
+        content_type = "multipart/form-data",
+        description = "The WAV `file`, `model`, `language`, `prompt`, `response_format` and `temperature`"
+    ),
+    responses((status = 200, description = "Transcription of the audio"))
+)]
+pub async fn audio_transcriptions(
+    State(data): State<Arc<OpenAIServerData>>,
+    multipart: Multipart,
+) -> AudioResponder {
+    audio_task(data, multipart, "transcribe").await
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/audio/translations",
+    request_body(
+        content_type = "multipart/form-data",
+        description = "The WAV `file`, `model`, `language`, `prompt`, `response_format` and `temperature`"
+    ),
+    responses((status = 200, description = "English translation of the audio"))
+)]
+pub async fn audio_translations(
+    State(data): State<Arc<OpenAIServerData>>,
+    multipart: Multipart,
+) -> AudioResponder {
+    audio_task(data, multipart, "translate").await
+}
+
+/// Transcribe or translate the audio of a request, each window of `CHUNK_LENGTH` seconds by a
+/// request of its own, all of which are scheduled at once.
+async fn audio_task(
+    data: Arc<OpenAIServerData>,
+    multipart: Multipart,
+    task: &'static str,
+) -> AudioResponder {
+    let request = match read_audio_request(multipart).await {
+        Ok(request) => request,
+        Err(e) => return AudioResponder::ValidationError(e),
+    };
+    if let Err(e) = verify_model(&data, &request.model) {
+        return AudioResponder::ModelNotFound(e);
+    }
+    if let Err(refusal) = admit(&data).await {
+        return refusal.into();
+    }
+    let Some(file) = request.file else {
+        return AudioResponder::ValidationError(APIError::new_str("`file` is missing."));
+    };
+    // The language is not detected, it is the one of the request or English.
+    let language = request.language.unwrap_or_else(|| "en".to_string());
+    let has_language = {
+        let model = data.model.lock().await;
+        let tokenizer = model.get_pipeline().tokenizer().tokenizer();
+        tokenizer.token_to_id(&format!("<|{language}|>")).is_some()
+    };
+    if !has_language {
+        return AudioResponder::ValidationError(APIError::new(format!(
+            "Language `{language}` is not supported by the model."
+        )));
+    }
+
+    let (features, duration) = match get_audio_features(&data, file).await {
+        Ok(features) => features,
+        Err(e) => return AudioResponder::ValidationError(e),
+    };
+
+    let prompt = whisper_prompt(task, &language, request.prompt.as_deref());
+    // The prompt and the transcription of a window share the context of the decoder.
+    let max_tokens = data.pipeline_config().max_model_len / 2;
+    let (token_ids, prompt_len, _) =
+        match check_length(Some(max_tokens), prompt.clone(), 0, &data).await {
+            Ok(token_ids) => token_ids,
+            Err(e) => return AudioResponder::ValidationError(e),
+        };
+
+    let request_id = format!("trsc-{}", Uuid::new_v4());
+    info!(%request_id, windows = features.len(), duration, "audio request received");
+    debug!(%request_id, %prompt, "prompt");
+
+    let sampling_params = SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        1.0,
+        request.temperature.unwrap_or(0.0),
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        Vec::new(),
+        false,
+        max_tokens,
+        None,
+        None,
+        true,
+    );
+    let sampling_params = match sampling_params {
+        Ok(sampling_params) => sampling_params,
+        Err(e) => return AudioResponder::ValidationError(e),
+    };
+
+    let windows = features.into_iter().enumerate().map(|(i, pixel_values)| {
+        let generated = generate(
+            data.clone(),
+            format!("{request_id}-{i}"),
+            token_ids.clone(),
+            sampling_params.clone(),
+            prompt_len,
+            None,
+            false,
+            None,
+            Some(pixel_values),
+            true,
+        );
+        async move {
+            match generated.await? {
+                Either::Right((choices, _, _, _)) => Ok(choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content)
+                    .unwrap_or_default()),
+                Either::Left(_) => Err(APIError::new_str("Audio requests are not streamed.")),
+            }
+        }
+    });
+    let texts = match futures::future::try_join_all(windows).await {
+        Ok(texts) => texts,
+        Err(e) => return AudioResponder::ModelError(e),
+    };
+    let segments = texts
+        .into_iter()
+        .enumerate()
+        .map(|(id, text)| TranscriptionSegment {
+            id,
+            start: (id * CHUNK_LENGTH) as f64,
+            end: (((id + 1) * CHUNK_LENGTH) as f64).min(duration),
+            text: text.trim().to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    let text = segments_text(&segments);
+    match request.response_format {
+        TranscriptionFormat::Json => AudioResponder::Json(TranscriptionResponse { text }),
+        TranscriptionFormat::Text => AudioResponder::Text("text/plain; charset=utf-8", text),
+        TranscriptionFormat::Srt => {
+            AudioResponder::Text("text/plain; charset=utf-8", format_srt(&segments))
+        }
+        TranscriptionFormat::Vtt => {
+            AudioResponder::Text("text/vtt; charset=utf-8", format_vtt(&segments))
+        }
+        TranscriptionFormat::VerboseJson => {
+            AudioResponder::VerboseJson(TranscriptionVerboseResponse {
+                task,
+                language,
+                duration,
+                text,
+                segments,
+            })
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/debug/scheduler",
+    responses((status = 200, description = "Scheduler queues, block tables and free blocks"))
+)]
+pub async fn debug_scheduler(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+) -> Result<Json<SchedulerSnapshot>, AdminResponder> {
+    // The snapshot lists the ids of the requests of every client.
+    check_admin_token(&data, &headers)?;
+    Ok(Json(scheduler_snapshot(&data)))
+}
+
+/// The engine stays locked while a batch is generating, in which case the state recorded at the
+/// last scheduler step is reported.
+fn scheduler_snapshot(data: &OpenAIServerData) -> SchedulerSnapshot {
+    match data.model.try_lock() {
+        Ok(model) => model.scheduler_snapshot(),
+        Err(_) => data
+            .scheduler_trace
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/cache_stats",
+    responses((status = 200, description = "Free and used KV cache blocks, blocks per sequence and fragmentation"))
+)]
+pub async fn cache_stats(State(data): State<Arc<OpenAIServerData>>) -> Json<CacheStats> {
+    Json(scheduler_snapshot(&data).cache_stats())
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/recovered_requests",
+    responses((status = 200, description = "Requests found unfinished in the request log at startup, lost or replayed"))
+)]
+pub async fn recovered_requests(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RecoveredRequestStatus>>, AdminResponder> {
+    // The choices of the replayed requests are the completions of other clients.
+    check_admin_token(&data, &headers)?;
+    let model = data.model.lock().await;
+    let statuses = model
+        .recovered_requests()
+        .iter()
+        .map(|request| {
+            let record = model.completion_records.get(&request.request_id);
+            RecoveredRequestStatus {
+                request: request.clone(),
+                choices: record.map(|(choices, _)| choices.clone()),
+                usage: record.map(|(_, usage)| usage.clone()),
+            }
+        })
+        .collect();
+    Ok(Json(statuses))
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/sleep",
+    params(("level" = Option<u8>, Query, description = "1 frees the KV cache (default), 2 the weights too")),
+    responses((status = 200, description = "The engine sleeps until `/wake`"))
+)]
+pub async fn sleep(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    Query(query): Query<SleepQuery>,
+) -> Result<SleepResponder, AdminResponder> {
+    check_admin_token(&data, &headers)?;
+    let level = match query.level.unwrap_or(1) {
+        1 => SleepLevel::KvCache,
+        2 => SleepLevel::Weights,
+        level => {
+            return Ok(SleepResponder::ValidationError(APIError::new(format!(
+                "Sleep level must be 1 or 2, got {level}."
+            ))))
+        }
+    };
+    let mut model = data.model.lock().await;
+    Ok(match model.sleep(level) {
+        Ok(()) => SleepResponder::Status(SleepStatus { is_sleeping: true }),
+        Err(e) => SleepResponder::Conflict(e),
+    })
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/wake",
+    responses((status = 200, description = "The engine serves requests again"))
+)]
+pub async fn wake(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+) -> Result<SleepResponder, AdminResponder> {
+    check_admin_token(&data, &headers)?;
+    let mut model = data.model.lock().await;
+    Ok(match model.wake() {
+        Ok(()) => SleepResponder::Status(SleepStatus { is_sleeping: false }),
+        Err(e) => SleepResponder::InternalError(e),
+    })
+}
+
+/// Token of an `Authorization: Bearer <token>` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Requests to the `/admin` endpoints carry the admin token as `Authorization: Bearer <token>`.
+fn check_admin_token(data: &OpenAIServerData, headers: &HeaderMap) -> Result<(), AdminResponder> {
+    let Some(admin_token) = &data.admin_token else {
+        return Err(AdminResponder::Disabled(APIError::new_str(
+            "The admin API is disabled, start the server with `--admin-token`.",
+        )));
+    };
+    let token = bearer_token(headers);
+    // Compared in constant time, not to leak how much of the token was guessed.
+    let authorized = token.is_some_and(|token| {
+        token.len() == admin_token.len()
+            && std::iter::zip(token.bytes(), admin_token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    });
+    if !authorized {
+        return Err(AdminResponder::Unauthorized(APIError::new_str(
+            "Missing or invalid admin token.",
+        )));
+    }
+    Ok(())
+}
+
+fn admin_config(data: &OpenAIServerData) -> AdminConfig {
+    let limits = *data
+        .scheduler_limits
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    let pipeline_config = data.pipeline_config();
+    AdminConfig {
+        max_num_seqs: limits.max_num_seqs,
+        max_num_prefill_tokens: limits.max_num_prefill_tokens,
+        temperature: pipeline_config.temperature,
+        top_p: pipeline_config.top_p,
+        top_k: pipeline_config.top_k,
+        repetition_penalty: pipeline_config.penalty,
+        max_tokens: pipeline_config.default_max_tokens,
+        log_level: data.log_filter.as_ref().map(|filter| filter.directives()),
+        enable_prefix_caching: false,
+    }
+}
+
+/// Validate the whole of `update` before applying any of it. The default sampling params apply to
+/// the requests received from now on, the scheduler limits from the next scheduler step.
+fn update_admin_config(data: &OpenAIServerData, update: AdminConfigUpdate) -> Result<(), APIError> {
+    if update.enable_prefix_caching == Some(true) {
+        return Err(APIError::new_str(
+            "Prefix caching is not supported, the KV cache blocks of a sequence are not shared.",
+        ));
+    }
+    let mut limits = *data
+        .scheduler_limits
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(max_num_seqs) = update.max_num_seqs {
+        if max_num_seqs == 0 {
+            return Err(APIError::new_str("max_num_seqs must be at least 1."));
+        }
+        limits.max_num_seqs = max_num_seqs;
+    }
+    if let Some(max_num_prefill_tokens) = update.max_num_prefill_tokens {
+        if max_num_prefill_tokens == Some(0) {
+            return Err(APIError::new_str(
+                "max_num_prefill_tokens must be at least 1, or null for no limit.",
+            ));
+        }
+        limits.max_num_prefill_tokens = max_num_prefill_tokens;
+    }
+
+    let mut pipeline_config = data.pipeline_config();
+    if let Some(temperature) = update.temperature {
+        if temperature < 0.0 {
+            return Err(APIError::new(format!(
+                "temperature must be non-negative, got {temperature}"
+            )));
+        }
+        pipeline_config.temperature = temperature;
+    }
+    if let Some(top_p) = update.top_p {
+        if !(top_p > 0.0 && top_p <= 1.0) {
+            return Err(APIError::new(format!(
+                "top_p must be in (0, 1], got {top_p}"
+            )));
+        }
+        pipeline_config.top_p = top_p;
+    }
+    if let Some(top_k) = update.top_k {
+        if top_k == 0 || top_k < -1 {
+            return Err(APIError::new(format!(
+                "top_k must be -1 (disabled) or at least 1, got {top_k}"
+            )));
+        }
+        pipeline_config.top_k = top_k;
+    }
+    if let Some(repetition_penalty) = update.repetition_penalty {
+        if !(repetition_penalty > 0.0 && repetition_penalty <= 2.0) {
+            return Err(APIError::new(format!(
+                "repetition_penalty must be in (0, 2], got {repetition_penalty}"
+            )));
+        }
+        pipeline_config.penalty = repetition_penalty;
+    }
+    if let Some(max_tokens) = update.max_tokens {
+        if max_tokens == 0 || max_tokens > pipeline_config.max_model_len {
+            return Err(APIError::new(format!(
+                "max_tokens must be in [1, {}], got {max_tokens}",
+                pipeline_config.max_model_len
             )));
         }
+        pipeline_config.default_max_tokens = max_tokens;
+    }
+
+    // The log filter is only parsed as it is applied, before the rest of the update.
+    if let Some(log_level) = &update.log_level {
+        let Some(log_filter) = &data.log_filter else {
+            return Err(APIError::new_str(
+                "The log level cannot be changed, the server did not install the logger.",
+            ));
+        };
+        log_filter.set_directives(log_level)?;
+        info!(log_level, "log level changed");
+    }
+    *data
+        .pipeline_config
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = pipeline_config;
+    // The cached responses were generated with the previous defaults.
+    if let Some(cache) = &data.response_cache {
+        cache.clear();
+    }
+    *data
+        .scheduler_limits
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = limits;
+    info!(?update, "runtime configuration changed");
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/admin/config",
+    responses((status = 200, description = "Scheduler limits, default sampling params and log level"))
+)]
+pub async fn get_admin_config(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+) -> AdminResponder {
+    if let Err(responder) = check_admin_token(&data, &headers) {
+        return responder;
+    }
+    AdminResponder::Config(admin_config(&data))
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/admin/config",
+    responses((status = 200, description = "The configuration after the update"))
+)]
+pub async fn post_admin_config(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    Json(update): Json<AdminConfigUpdate>,
+) -> AdminResponder {
+    if let Err(responder) = check_admin_token(&data, &headers) {
+        return responder;
+    }
+    match update_admin_config(&data, update) {
+        Ok(()) => AdminResponder::Config(admin_config(&data)),
+        Err(e) => AdminResponder::ValidationError(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/v1/requests",
+    responses((status = 200, description = "Requests waiting, running or swapped out, with their progress"))
+)]
+pub async fn list_requests(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+) -> AdminResponder {
+    if let Err(responder) = check_admin_token(&data, &headers) {
+        return responder;
+    }
+    AdminResponder::Requests(ActiveRequestList {
+        object: "list".to_string(),
+        data: scheduler_snapshot(&data).active_requests(get_time_millis()),
+    })
+}
+
+#[utoipa::path(
+    delete,
+    tag = "candle-vllm",
+    path = "/v1/requests/{request_id}",
+    params(("request_id" = String, Path, description = "Id of the request")),
+    responses((status = 200, description = "The request, aborted at the next scheduler step with the `abort` finish reason"))
+)]
+pub async fn abort_request(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> AdminResponder {
+    if let Err(responder) = check_admin_token(&data, &headers) {
+        return responder;
+    }
+    let request = scheduler_snapshot(&data)
+        .active_requests(get_time_millis())
+        .into_iter()
+        .find(|request| request.id == request_id);
+    let Some(request) = request else {
+        return AdminResponder::NotFound(APIError::new(format!(
+            "No request `{request_id}` is waiting or running."
+        )));
+    };
+    data.aborted_requests
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(request_id);
+    AdminResponder::Request(request)
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/files",
+    params(
+        ("purpose" = String, Query, description = "`batch` for the input file of a batch"),
+        ("filename" = Option<String>, Query, description = "Name of the file"),
+    ),
+    request_body(content = String, description = "Content of the file, JSONL for a batch"),
+    responses((status = 200, description = "The uploaded file"))
+)]
+pub async fn upload_file(
+    State(data): State<Arc<OpenAIServerData>>,
+    Query(query): Query<FileUploadQuery>,
+    content: Bytes,
+) -> BatchResponder {
+    if query.purpose != "batch" {
+        return BatchResponder::ValidationError(APIError::new(format!(
+            "Only files of purpose `batch` can be uploaded, got `{}`.",
+            query.purpose
+        )));
+    }
+    let filename = query.filename.unwrap_or_else(|| "input.jsonl".to_string());
+    BatchResponder::File(data.batches.add_file(filename, query.purpose, content))
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/v1/files/{file_id}",
+    params(("file_id" = String, Path, description = "Id of the file")),
+    responses((status = 200, description = "The file"))
+)]
+pub async fn get_file(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(file_id): Path<String>,
+) -> BatchResponder {
+    match data.batches.file(&file_id) {
+        Some(file) => BatchResponder::File(file),
+        None => BatchResponder::NotFound(file_not_found(&file_id)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/v1/files/{file_id}/content",
+    params(("file_id" = String, Path, description = "Id of the file")),
+    responses((status = 200, description = "Content of the file"))
+)]
+pub async fn get_file_content(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(file_id): Path<String>,
+) -> BatchResponder {
+    match data.batches.file_content(&file_id) {
+        Some(content) => BatchResponder::FileContent(content),
+        None => BatchResponder::NotFound(file_not_found(&file_id)),
+    }
+}
+
+fn file_not_found(file_id: &str) -> APIError {
+    APIError::new(format!("No file `{file_id}`."))
+}
+
+fn batch_not_found(batch_id: &str) -> APIError {
+    APIError::new(format!("No batch `{batch_id}`."))
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/batches",
+    request_body = CreateBatchRequest,
+    responses((status = 200, description = "The batch, validating its input file"))
+)]
+pub async fn create_batch(
+    State(data): State<Arc<OpenAIServerData>>,
+    Json(request): Json<CreateBatchRequest>,
+) -> BatchResponder {
+    if !BATCH_ENDPOINTS.contains(&request.endpoint.as_str()) {
+        return BatchResponder::ValidationError(APIError::new(format!(
+            "The endpoint of a batch must be one of {}, got `{}`.",
+            BATCH_ENDPOINTS.join(", "),
+            request.endpoint
+        )));
+    }
+    if request.completion_window != COMPLETION_WINDOW {
+        return BatchResponder::ValidationError(APIError::new(format!(
+            "The completion window of a batch must be `{COMPLETION_WINDOW}`, got `{}`.",
+            request.completion_window
+        )));
+    }
+    match data.batches.file(&request.input_file_id) {
+        Some(file) if file.purpose == "batch" => {}
+        Some(file) => {
+            return BatchResponder::ValidationError(APIError::new(format!(
+                "The input file of a batch must have the purpose `batch`, got `{}`.",
+                file.purpose
+            )))
+        }
+        None => return BatchResponder::NotFound(file_not_found(&request.input_file_id)),
+    }
+    let batch = data
+        .batches
+        .add_batch(request.input_file_id, request.endpoint, request.metadata);
+    info!(batch_id = %batch.id, "batch created");
+    tokio::spawn(run_batch(data.clone(), batch.id.clone()));
+    BatchResponder::Batch(Box::new(batch))
+}
 
-        let choices = &model.completion_records[&request_id].0;
-        let usage = &model.completion_records[&request_id].1;
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/v1/batches",
+    responses((status = 200, description = "The batches, the most recent first"))
+)]
+pub async fn list_batches(State(data): State<Arc<OpenAIServerData>>) -> BatchResponder {
+    BatchResponder::Batches(BatchList {
+        object: "list".to_string(),
+        data: data.batches.batches(),
+    })
+}
 
-        ChatResponder::Completion(ChatCompletionResponse {
-            id: request_id,
-            choices: choices.to_vec(),
-            created: usage.created,
-            model: request.model.clone(),
-            object: "chat.completion",
-            usage: usage.clone(),
+#[utoipa::path(
+    get,
+    tag = "candle-vllm",
+    path = "/v1/batches/{batch_id}",
+    params(("batch_id" = String, Path, description = "Id of the batch")),
+    responses((status = 200, description = "Status and request counts of the batch"))
+)]
+pub async fn get_batch(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(batch_id): Path<String>,
+) -> BatchResponder {
+    match data.batches.batch(&batch_id) {
+        Some(batch) => BatchResponder::Batch(Box::new(batch)),
+        None => BatchResponder::NotFound(batch_not_found(&batch_id)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/batches/{batch_id}/cancel",
+    params(("batch_id" = String, Path, description = "Id of the batch")),
+    responses((status = 200, description = "The batch, cancelling until its requests in flight finish"))
+)]
+pub async fn cancel_batch(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(batch_id): Path<String>,
+) -> BatchResponder {
+    match data.batches.cancel_batch(&batch_id) {
+        Some(batch) => BatchResponder::Batch(Box::new(batch)),
+        None => BatchResponder::NotFound(batch_not_found(&batch_id)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "candle-vllm",
+    path = "/v1/watermark/detect",
+    request_body = WatermarkDetectRequest,
+    responses((status = 200, description = "Whether the text carries the watermark of the server"))
+)]
+pub async fn detect_watermark(
+    State(data): State<Arc<OpenAIServerData>>,
+    Json(request): Json<WatermarkDetectRequest>,
+) -> WatermarkResponder {
+    let Some(watermark) = &data.watermark else {
+        return WatermarkResponder::Disabled(APIError::new_str(
+            "The server does not watermark its output, start it with `--watermark-key`.",
+        ));
+    };
+    let z_threshold = request.z_threshold.unwrap_or(DEFAULT_Z_THRESHOLD);
+    if !z_threshold.is_finite() {
+        return WatermarkResponder::ValidationError(APIError::new(format!(
+            "The z-score threshold has to be finite, got {z_threshold}."
+        )));
+    }
+    let tokens = match data.tokenizer_pool.encode(request.text).await {
+        Ok(encoding) => encoding.get_ids().to_vec(),
+        Err(e) => return WatermarkResponder::InternalError(e),
+    };
+    let detection = watermark.detect(&tokens);
+    WatermarkResponder::Detection(WatermarkDetectResponse {
+        watermarked: detection.is_watermarked(z_threshold),
+        detection,
+        z_threshold,
+    })
+}
+
+/// Validate the input file of the batch `batch_id`, send its requests at low priority, at most
+/// `max_concurrent_requests` at once, and write their responses to its output and error files.
+async fn run_batch(data: Arc<OpenAIServerData>, batch_id: String) {
+    let Some(batch) = data.batches.batch(&batch_id) else {
+        return;
+    };
+    let content = data
+        .batches
+        .file_content(&batch.input_file_id)
+        .unwrap_or_default();
+    let inputs = match parse_batch_input(&content, &batch.endpoint) {
+        Ok(inputs) => inputs,
+        Err(errors) => {
+            warn!(%batch_id, errors = errors.len(), "batch input file rejected");
+            data.batches.update_batch(&batch_id, |batch| {
+                batch.status = BatchStatus::Failed;
+                batch.failed_at = Some(get_created_time_secs());
+                batch.errors = Some(BatchErrors {
+                    object: "list".to_string(),
+                    data: errors,
+                });
+            });
+            return;
+        }
+    };
+    data.batches.update_batch(&batch_id, |batch| {
+        if batch.status == BatchStatus::Validating {
+            batch.status = BatchStatus::InProgress;
+            batch.in_progress_at = Some(get_created_time_secs());
+        }
+        batch.request_counts.total = inputs.len();
+    });
+
+    let outputs = futures::stream::iter(inputs)
+        .map(|input| {
+            let data = data.clone();
+            let batch_id = batch_id.clone();
+            let endpoint = batch.endpoint.clone();
+            async move {
+                let cancelling = data
+                    .batches
+                    .batch(&batch_id)
+                    .is_some_and(|batch| batch.status == BatchStatus::Cancelling);
+                if cancelling {
+                    return None;
+                }
+                let output = run_batch_request(data.clone(), &endpoint, input).await;
+                data.batches.update_batch(&batch_id, |batch| {
+                    if output.response.status_code == 200 {
+                        batch.request_counts.completed += 1;
+                    } else {
+                        batch.request_counts.failed += 1;
+                    }
+                });
+                Some(output)
+            }
         })
+        .buffered(data.batches.max_concurrent_requests)
+        .filter_map(futures::future::ready)
+        .collect::<Vec<_>>()
+        .await;
+
+    data.batches.update_batch(&batch_id, |batch| {
+        if batch.status == BatchStatus::InProgress {
+            batch.status = BatchStatus::Finalizing;
+            batch.finalizing_at = Some(get_created_time_secs());
+        }
+    });
+    let (succeeded, failed): (Vec<_>, Vec<_>) = outputs
+        .into_iter()
+        .partition(|output| output.response.status_code == 200);
+    let jsonl = |outputs: Vec<BatchOutput>| {
+        let mut content = String::new();
+        for output in outputs {
+            content.push_str(&serde_json::to_string(&output).unwrap_or_default());
+            content.push('\n');
+        }
+        Bytes::from(content)
+    };
+    let output_file = data.batches.add_file(
+        format!("{batch_id}_output.jsonl"),
+        "batch_output".to_string(),
+        jsonl(succeeded),
+    );
+    let error_file = (!failed.is_empty()).then(|| {
+        data.batches.add_file(
+            format!("{batch_id}_error.jsonl"),
+            "batch_output".to_string(),
+            jsonl(failed),
+        )
+    });
+    let batch = data.batches.update_batch(&batch_id, |batch| {
+        batch.output_file_id = Some(output_file.id);
+        batch.error_file_id = error_file.map(|file| file.id);
+        if batch.status == BatchStatus::Cancelling {
+            batch.status = BatchStatus::Cancelled;
+            batch.cancelled_at = Some(get_created_time_secs());
+        } else {
+            batch.status = BatchStatus::Completed;
+            batch.completed_at = Some(get_created_time_secs());
+        }
+    });
+    if let Some(batch) = batch {
+        info!(
+            %batch_id,
+            status = ?batch.status,
+            completed = batch.request_counts.completed,
+            failed = batch.request_counts.failed,
+            "batch finished"
+        );
+    }
+}
+
+/// Send `input` to `endpoint` at low priority and wait for its response. Streaming is turned
+/// off, the output file holds the whole response.
+async fn run_batch_request(
+    data: Arc<OpenAIServerData>,
+    endpoint: &str,
+    input: BatchInput,
+) -> BatchOutput {
+    let responder = if endpoint == "/v1/completions" {
+        match serde_json::from_value::<CompletionRequest>(input.body) {
+            Ok(mut request) => {
+                request.stream = None;
+                request.stream_options = None;
+                completion(data, request, true, None).await
+            }
+            Err(e) => ChatResponder::ValidationError(APIError::from(e)),
+        }
+    } else {
+        match serde_json::from_value::<ChatCompletionRequest>(input.body) {
+            Ok(mut request) => {
+                request.stream = None;
+                request.stream_options = None;
+                chat_completion(data, request, true, None).await
+            }
+            Err(e) => ChatResponder::ValidationError(APIError::from(e)),
+        }
+    };
+    let response = responder.into_response();
+    let status_code = response.status().as_u16();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok())
+        .unwrap_or_default();
+    let request_id = body
+        .get("id")
+        .and_then(|id| id.as_str())
+        .map_or_else(|| format!("cmpl-{}", Uuid::new_v4()), str::to_string);
+    BatchOutput {
+        id: format!("batch_req_{}", Uuid::new_v4()),
+        custom_id: input.custom_id,
+        response: BatchOutputResponse {
+            status_code,
+            request_id,
+            body,
+        },
     }
 }
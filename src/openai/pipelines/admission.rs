@@ -0,0 +1,209 @@
+//! Admission of new requests while the engine runs a step. What a request needs before it can be
+//! scheduled, the expansion of its image placeholders, the tokens healing its prompt and the trie
+//! of its guided choices, is worked out by the task submitting it, without the engine. The
+//! request then waits in the `AdmissionQueue` until the engine takes it in at the start of its
+//! next step, so that a new request neither waits for the step running nor delays it.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use candle_core::Tensor;
+use flume::Sender;
+use tokenizers::{Encoding, Tokenizer};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use super::ModulePipeline;
+use crate::{
+    openai::{
+        guided_choice::ChoiceTrie, image_processor::ImageProcessor,
+        sampling_params::SamplingParams, streaming::ChatResponse,
+    },
+    scheduler::sequence::TokenHealing,
+};
+
+/// A request ready to be scheduled, see `AdmissionContext::prepare`.
+pub struct PreparedRequest {
+    /// The prompt as it was encoded, before its image placeholders were expanded.
+    pub(crate) prompt: Encoding,
+    /// The tokens the prompt is run with.
+    pub(crate) prompt_ids: Vec<u32>,
+    pub(crate) request_id: String,
+    pub(crate) created: SystemTime,
+    pub(crate) sampling_params: SamplingParams,
+    pub(crate) use_logprobs: bool,
+    pub(crate) sender: Option<Sender<ChatResponse>>,
+    pub(crate) pixel_values: Option<Tensor>,
+    pub(crate) token_healing: Option<TokenHealing>,
+    pub(crate) guided_choice: Option<ChoiceTrie>,
+}
+
+/// What of the pipeline preparing a request takes, shared with the tasks submitting requests.
+pub struct AdmissionContext {
+    tokenizer: Tokenizer,
+    image_processor: Option<ImageProcessor>,
+    /// Whether the model takes pixel values, images or the spectrograms of audio models.
+    accepts_inputs: bool,
+    is_encoder_decoder: bool,
+}
+
+impl AdmissionContext {
+    pub fn new(pipeline: &dyn ModulePipeline) -> Self {
+        let image_processor = pipeline.image_processor().cloned();
+        // The spectrograms of audio models travel as pixel values, with no token to expand.
+        let accepts_inputs = image_processor.is_some() || pipeline.audio_processor().is_some();
+        Self {
+            tokenizer: pipeline.tokenizer().tokenizer().clone(),
+            image_processor,
+            accepts_inputs,
+            is_encoder_decoder: pipeline.is_encoder_decoder(),
+        }
+    }
+
+    /// `pixel_values` are the preprocessed images of the prompt, whose image placeholders are
+    /// expanded here to one token per image feature.
+    #[allow(clippy::too_many_arguments)]
+    pub fn prepare(
+        &self,
+        prompt: Encoding,
+        request_id: String,
+        created: SystemTime,
+        sampling_params: SamplingParams,
+        use_logprobs: bool,
+        sender: Option<Sender<ChatResponse>>,
+        pixel_values: Option<Tensor>,
+    ) -> PreparedRequest {
+        let pixel_values = pixel_values.filter(|_| self.accepts_inputs);
+        let mut prompt_ids = match &self.image_processor {
+            Some(image_processor) if pixel_values.is_some() => {
+                image_processor.expand_image_tokens(prompt.get_ids())
+            }
+            _ => prompt.get_ids().to_vec(),
+        };
+        // The output of an encoder-decoder model does not continue its prompt.
+        let token_healing = if sampling_params.token_healing && !self.is_encoder_decoder {
+            self.heal_prompt(&mut prompt_ids)
+        } else {
+            None
+        };
+        let guided_choice = sampling_params.guided_choice.as_ref().and_then(|choices| {
+            ChoiceTrie::from_choices(&self.tokenizer, choices)
+                .map_err(|e| warn!(%request_id, "failed to tokenize the guided choices: {e:?}"))
+                .ok()
+        });
+        PreparedRequest {
+            prompt,
+            prompt_ids,
+            request_id,
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+            pixel_values,
+            token_healing,
+            guided_choice,
+        }
+    }
+
+    /// Remove the last token of `prompt_ids` for token healing. Special tokens, image
+    /// placeholders among them, are kept. So are the tokens of prompts of two tokens or less,
+    /// the model runs a prompt of one token as a decode step.
+    fn heal_prompt(&self, prompt_ids: &mut Vec<u32>) -> Option<TokenHealing> {
+        let token = match prompt_ids.as_slice() {
+            [_, _, .., last] => *last,
+            _ => return None,
+        };
+        if self
+            .tokenizer
+            .get_added_tokens_decoder()
+            .contains_key(&token)
+        {
+            return None;
+        }
+        let text = self.tokenizer.id_to_token(token)?;
+        let allowed_token_ids = self
+            .tokenizer
+            .get_vocab(false)
+            .into_iter()
+            .filter(|(candidate, _)| candidate.starts_with(&text))
+            .map(|(_, id)| id)
+            .collect();
+        prompt_ids.pop();
+        Some(TokenHealing {
+            token,
+            allowed_token_ids,
+        })
+    }
+}
+
+/// Requests submitted while the engine may be running a step, which it takes in at the start of
+/// its next one. Clones share the queue.
+#[derive(Clone)]
+pub struct AdmissionQueue {
+    context: Arc<AdmissionContext>,
+    requests: Arc<Mutex<VecDeque<PreparedRequest>>>,
+    /// Wakes the background task of the engine up.
+    notify: Arc<Notify>,
+}
+
+impl AdmissionQueue {
+    pub fn new(context: AdmissionContext, notify: Arc<Notify>) -> Self {
+        Self {
+            context: Arc::new(context),
+            requests: Arc::new(Mutex::new(VecDeque::new())),
+            notify,
+        }
+    }
+
+    pub fn context(&self) -> &AdmissionContext {
+        &self.context
+    }
+
+    /// Prepare a request on the calling thread and queue it for the engine, see
+    /// `LLMEngine::add_request` for the arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit(
+        &self,
+        prompt: Encoding,
+        request_id: String,
+        created: SystemTime,
+        sampling_params: SamplingParams,
+        use_logprobs: bool,
+        sender: Option<Sender<ChatResponse>>,
+        pixel_values: Option<Tensor>,
+    ) {
+        let request = self.context.prepare(
+            prompt,
+            request_id,
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+            pixel_values,
+        );
+        self.requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back(request);
+        self.notify.notify_one();
+    }
+
+    /// Requests waiting to be taken in by the engine.
+    pub fn len(&self) -> usize {
+        self.requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The queued requests, in the order they were submitted.
+    pub(crate) fn take(&self) -> VecDeque<PreparedRequest> {
+        std::mem::take(&mut *self.requests.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
@@ -0,0 +1,290 @@
+use std::{collections::HashMap, iter::zip};
+
+use candle_core::Tensor;
+
+use super::{
+    tensor_parallel::new_nccl_id,
+    worker::{CacheOps, ModelInput, Worker},
+    worker_process::{blocks_from_tensors, blocks_to_tensors, WorkerProcess, WorkerRequest},
+    ModulePipeline,
+};
+use crate::{openai::responses::APIError, scheduler::cache_engine::KVCache};
+use tracing::warn;
+
+/// Runs the steps of the engine on its workers, one per device. Every worker runs the same step
+/// on its part of the model and KV cache. The engine tokenizes and samples the logits of the
+/// first worker with the pipeline of the executor.
+pub trait Executor: Send + Sync {
+    fn pipeline(&self) -> &dyn ModulePipeline;
+
+    fn pipeline_mut(&mut self) -> &mut dyn ModulePipeline;
+
+    fn execute_cache_ops(&mut self, ops: &CacheOps) -> Result<(), APIError>;
+
+    /// Logits of the first worker for `input`, see `Worker::execute_model`.
+    fn execute_model(&mut self, input: &ModelInput) -> Result<Tensor, APIError>;
+
+    fn get_num_gpu_blocks(&self) -> usize;
+
+    fn resize_gpu_cache(&mut self, num_blocks: usize) -> Result<(), APIError>;
+
+    /// Copy the blocks `block_ids` of the KV cache of the first worker to the CPU.
+    fn export_blocks(&mut self, block_ids: &[usize]) -> Result<Vec<KVCache>, APIError>;
+
+    /// Fails if `blocks` do not fit the KV cache of the workers.
+    fn check_blocks(&mut self, blocks: &[KVCache]) -> Result<(), APIError>;
+
+    /// Write `blocks`, exported with `export_blocks`, to the blocks `block_ids` of the workers.
+    fn import_blocks(&mut self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError>;
+
+    /// Drop what the workers keep of finished sequences outside of the KV cache.
+    fn free_sequences(&mut self, seq_ids: &[usize]);
+
+    fn release_weights(&mut self);
+
+    fn reload_weights(&mut self) -> Result<(), APIError>;
+
+    fn reset_decoder(&mut self);
+
+    /// Ranks the model is sharded over with tensor parallelism.
+    fn tensor_parallel_size(&self) -> usize {
+        self.pipeline().shard().world_size
+    }
+}
+
+/// Runs a single worker in the engine thread.
+pub struct LocalExecutor {
+    worker: Worker,
+}
+
+impl LocalExecutor {
+    pub fn new(worker: Worker) -> Self {
+        Self { worker }
+    }
+}
+
+impl Executor for LocalExecutor {
+    fn pipeline(&self) -> &dyn ModulePipeline {
+        self.worker.pipeline()
+    }
+
+    fn pipeline_mut(&mut self) -> &mut dyn ModulePipeline {
+        self.worker.pipeline_mut()
+    }
+
+    fn execute_cache_ops(&mut self, ops: &CacheOps) -> Result<(), APIError> {
+        self.worker.execute_cache_ops(ops)
+    }
+
+    fn execute_model(&mut self, input: &ModelInput) -> Result<Tensor, APIError> {
+        self.worker.execute_model(input)
+    }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.worker.cache_engine().get_num_gpu_blocks()
+    }
+
+    fn resize_gpu_cache(&mut self, num_blocks: usize) -> Result<(), APIError> {
+        self.worker.resize_gpu_cache(num_blocks)
+    }
+
+    fn export_blocks(&mut self, block_ids: &[usize]) -> Result<Vec<KVCache>, APIError> {
+        self.worker.cache_engine().export_blocks(block_ids)
+    }
+
+    fn check_blocks(&mut self, blocks: &[KVCache]) -> Result<(), APIError> {
+        self.worker.cache_engine().check_blocks(blocks)
+    }
+
+    fn import_blocks(&mut self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError> {
+        self.worker.import_blocks(blocks, block_ids)
+    }
+
+    fn free_sequences(&mut self, seq_ids: &[usize]) {
+        self.worker.pipeline_mut().free_sequences(seq_ids);
+    }
+
+    fn release_weights(&mut self) {
+        self.worker.pipeline_mut().release_weights();
+    }
+
+    fn reload_weights(&mut self) -> Result<(), APIError> {
+        self.worker.pipeline_mut().reload_weights()
+    }
+
+    fn reset_decoder(&mut self) {
+        self.worker.pipeline_mut().reset_decoder();
+    }
+}
+
+/// Runs the workers in worker processes, one per GPU, so that a crash of the model or of CUDA
+/// fails the requests in flight instead of the engine process. The engine process only holds
+/// the tokenizer and configuration of the model, in a pipeline without weights.
+///
+/// With tensor parallelism, the workers each hold a shard of the model, possibly on other nodes,
+/// and every one of them computes the logits of the step.
+pub struct MultiprocExecutor {
+    pipeline: Box<dyn ModulePipeline>,
+    workers: Vec<WorkerProcess>,
+    num_gpu_blocks: usize,
+    tensor_parallel: bool,
+}
+
+impl MultiprocExecutor {
+    /// Drive the worker processes `workers`, `pipeline` being loaded without weights, once they
+    /// loaded the model: each a replica of it, or a shard with `tensor_parallel`, their rank
+    /// being their index.
+    pub fn new(
+        pipeline: Box<dyn ModulePipeline>,
+        workers: Vec<WorkerProcess>,
+        tensor_parallel: bool,
+    ) -> Result<Self, APIError> {
+        if workers.is_empty() {
+            return Err(APIError::new_str("At least one worker process is needed."));
+        }
+        let mut executor = Self {
+            pipeline,
+            workers,
+            num_gpu_blocks: 0,
+            tensor_parallel,
+        };
+        if tensor_parallel {
+            let request = WorkerRequest::InitTensorParallel {
+                world_size: executor.workers.len(),
+                nccl_id: new_nccl_id()?,
+            };
+            executor.broadcast(request, HashMap::new())?;
+        }
+        for worker in &mut executor.workers {
+            worker.wait_ready()?;
+        }
+        executor.num_gpu_blocks = executor.workers[0].num_gpu_blocks()?;
+        Ok(executor)
+    }
+
+    /// Blocks of a sharded model are split over the workers, they are not moved between
+    /// engines.
+    fn check_replicated(&self) -> Result<(), APIError> {
+        if self.tensor_parallel {
+            return Err(APIError::new_str(
+                "KV cache blocks cannot be moved with tensor parallelism.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send `request` with `tensors` to every worker at once, so that they run it in parallel,
+    /// then wait for them. Returns the tensors the first worker answered with.
+    fn broadcast(
+        &mut self,
+        request: WorkerRequest,
+        tensors: HashMap<String, Tensor>,
+    ) -> Result<HashMap<String, Tensor>, APIError> {
+        let sent = self
+            .workers
+            .iter_mut()
+            .map(|worker| worker.send(&request, tensors.clone()))
+            .collect::<Vec<_>>();
+        // Every worker that got the request is waited for before failing, so that the next
+        // request is not answered with the response to this one.
+        let received = zip(&mut self.workers, sent)
+            .map(|(worker, sent)| sent.and_then(|()| worker.receive()))
+            .collect::<Vec<_>>();
+        let mut received = received.into_iter().collect::<Result<Vec<_>, _>>()?;
+        Ok(received.swap_remove(0))
+    }
+}
+
+impl Executor for MultiprocExecutor {
+    fn pipeline(&self) -> &dyn ModulePipeline {
+        &*self.pipeline
+    }
+
+    fn pipeline_mut(&mut self) -> &mut dyn ModulePipeline {
+        &mut *self.pipeline
+    }
+
+    fn execute_cache_ops(&mut self, ops: &CacheOps) -> Result<(), APIError> {
+        self.broadcast(WorkerRequest::ExecuteCacheOps(ops.clone()), HashMap::new())?;
+        Ok(())
+    }
+
+    fn execute_model(&mut self, input: &ModelInput) -> Result<Tensor, APIError> {
+        // The images travel with the tensors of the request.
+        let tensors = input
+            .seqs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, seq)| {
+                let pixel_values = seq.pixel_values.clone()?;
+                Some((format!("pixel_values.{i}"), pixel_values))
+            })
+            .collect();
+        self.broadcast(WorkerRequest::ExecuteModel(input.clone()), tensors)?
+            .remove("logits")
+            .ok_or_else(|| APIError::new_str("The worker process returned no logits."))
+    }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks
+    }
+
+    fn resize_gpu_cache(&mut self, num_blocks: usize) -> Result<(), APIError> {
+        self.broadcast(WorkerRequest::ResizeGpuCache(num_blocks), HashMap::new())?;
+        self.num_gpu_blocks = num_blocks;
+        Ok(())
+    }
+
+    fn export_blocks(&mut self, block_ids: &[usize]) -> Result<Vec<KVCache>, APIError> {
+        self.check_replicated()?;
+        let worker = &mut self.workers[0];
+        let request = WorkerRequest::ExportBlocks(block_ids.to_vec());
+        worker.send(&request, HashMap::new())?;
+        blocks_from_tensors(worker.receive()?)
+    }
+
+    fn check_blocks(&mut self, blocks: &[KVCache]) -> Result<(), APIError> {
+        self.check_replicated()?;
+        self.broadcast(WorkerRequest::CheckBlocks, blocks_to_tensors(blocks))?;
+        Ok(())
+    }
+
+    fn import_blocks(&mut self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError> {
+        self.check_replicated()?;
+        self.broadcast(
+            WorkerRequest::ImportBlocks(block_ids.to_vec()),
+            blocks_to_tensors(blocks),
+        )?;
+        Ok(())
+    }
+
+    fn free_sequences(&mut self, seq_ids: &[usize]) {
+        let request = WorkerRequest::FreeSequences(seq_ids.to_vec());
+        if let Err(err) = self.broadcast(request, HashMap::new()) {
+            warn!("failed to free the sequences of the workers: {err}");
+        }
+    }
+
+    fn release_weights(&mut self) {
+        if let Err(err) = self.broadcast(WorkerRequest::ReleaseWeights, HashMap::new()) {
+            warn!("failed to release the weights of the workers: {err}");
+        }
+    }
+
+    fn reload_weights(&mut self) -> Result<(), APIError> {
+        self.broadcast(WorkerRequest::ReloadWeights, HashMap::new())?;
+        Ok(())
+    }
+
+    fn reset_decoder(&mut self) {
+        self.pipeline.reset_decoder();
+    }
+
+    fn tensor_parallel_size(&self) -> usize {
+        if self.tensor_parallel {
+            self.workers.len()
+        } else {
+            1
+        }
+    }
+}
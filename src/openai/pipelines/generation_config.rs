@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use either::Either;
+use serde::Deserialize;
+
+use crate::{
+    openai::{models::TokenID, responses::APIError},
+    try_api,
+};
+
+/// Defaults for sampling and stopping that a checkpoint ships in its `generation_config.json`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GenerationConfig {
+    /// A single id or a list of them, e.g. Llama 3 ends a turn with `<|eot_id|>` and a text with
+    /// `<|end_of_text|>`.
+    pub eos_token_id: Option<TokenID>,
+    /// Sampling is greedy when set to false, whatever the temperature.
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<isize>,
+    /// Max number of tokens of the prompt and the generated tokens together.
+    pub max_length: Option<usize>,
+    pub max_new_tokens: Option<usize>,
+}
+
+impl GenerationConfig {
+    pub fn load(path: &Path) -> Result<Self, APIError> {
+        Ok(try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+            path
+        )))))
+    }
+
+    pub fn eos_token_ids(&self) -> Vec<u32> {
+        match self.eos_token_id.as_ref().map(|id| &id.0) {
+            Some(Either::Left(Some(id))) => vec![*id],
+            Some(Either::Right(Some(ids))) => ids.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// The default temperature, zero for greedy sampling.
+    pub fn temperature(&self) -> Option<f32> {
+        match self.do_sample {
+            Some(false) => Some(0.),
+            _ => self.temperature,
+        }
+    }
+}
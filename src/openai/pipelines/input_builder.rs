@@ -0,0 +1,263 @@
+use std::iter::zip;
+
+use candle_core::{Device, Tensor};
+
+use super::{_make_tensor_with_pad, worker::SequenceInput};
+use crate::{
+    openai::responses::APIError,
+    paged_attention::input_metadata::{DraftTreeAttention, InputMetadata},
+    scheduler::{
+        block_engine::{attended_blocks, compute_slot},
+        draft_tree::DraftTree,
+    },
+    try_api,
+};
+
+pub const PAD_SLOT_ID: i64 = -1;
+
+/// Tensors of a model step, whichever model runs it.
+pub struct PreparedInputs {
+    /// `(num_seqs, max_prompt_len)` during prefill, else `(num_rows, 1)`.
+    pub tokens: Tensor,
+    pub positions: Vec<Vec<usize>>,
+    pub metadata: InputMetadata,
+    /// Rows whose logits are sampled, `None` for all of them. The rows writing the rest of a
+    /// cached prompt are left out but its last one.
+    pub logits_rows: Option<Vec<u32>>,
+}
+
+/// Builds the tokens, positions and paged attention metadata of the scheduled sequences from
+/// their block tables. It only knows the layout of the KV cache, so that engines other than
+/// the worker, e.g. to verify speculated tokens, can prepare inputs of their own.
+#[derive(Clone, Debug)]
+pub struct InputBuilder {
+    block_size: usize,
+    sliding_window: Option<usize>,
+    prefill_chunk_size: Option<usize>,
+    device: Device,
+}
+
+impl InputBuilder {
+    pub fn new(block_size: usize, sliding_window: Option<usize>, device: Device) -> Self {
+        Self {
+            block_size,
+            sliding_window,
+            prefill_chunk_size: None,
+            device,
+        }
+    }
+
+    /// Attend prompts longer than `prefill_chunk_size` tokens a tile of queries at a time.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: Option<usize>) -> Self {
+        self.prefill_chunk_size = prefill_chunk_size;
+        self
+    }
+
+    /// One row per prompt, padded to the longest one. The slots of the padding are
+    /// `PAD_SLOT_ID`, as are all the slots of sequences without a block table while profiling.
+    pub fn prepare_prompt(&self, seqs: &[SequenceInput]) -> Result<PreparedInputs, APIError> {
+        let mut prompt_lens = Vec::new();
+        let mut input_tokens = Vec::new();
+        let mut input_positions = Vec::new();
+        let mut slot_mappings = Vec::new();
+        let mut seq_ids = Vec::new();
+        for seq in seqs {
+            seq_ids.push(seq.seq_id);
+
+            let prompt_len = seq.token_ids.len();
+            prompt_lens.push(prompt_len);
+
+            input_tokens.push(seq.token_ids.clone());
+            input_positions.push(seq.positions.clone());
+            let Some(table) = &seq.block_table else {
+                // Will be None during profiling.
+                slot_mappings.push([PAD_SLOT_ID].repeat(prompt_len));
+                continue;
+            };
+
+            // Every token of the prompt is written to the cache, with a sliding window too: the
+            // decode steps attend the window out of the whole block table.
+            let mut slot_mapping = Vec::new();
+            for &i in &seq.cache_indices {
+                let slot = compute_slot(table, i, self.block_size).unwrap_or_else(|| {
+                    panic!(
+                        "Block table is too small (prompt)! i={} block_size={} table_len={}",
+                        i,
+                        self.block_size,
+                        table.len()
+                    )
+                });
+                slot_mapping.push(slot.try_into().unwrap());
+            }
+            slot_mappings.push(slot_mapping);
+        }
+
+        let max_prompt_len = prompt_lens.iter().max().unwrap();
+        let input_tokens = _make_tensor_with_pad(
+            input_tokens
+                .iter()
+                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            *max_prompt_len,
+            0,
+            &self.device,
+        )?;
+        let slot_mapping =
+            _make_tensor_with_pad(slot_mappings, *max_prompt_len, PAD_SLOT_ID, &self.device)?;
+
+        Ok(PreparedInputs {
+            tokens: input_tokens,
+            positions: input_positions,
+            metadata: InputMetadata {
+                prompt_lens,
+                slot_mapping,
+                max_context_len: None,
+                context_lens: None,
+                block_tables: None,
+                attn_bias: None,
+                is_prompt: true,
+                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
+                seq_ids,
+                draft_trees: vec![],
+                recurrent_state: None,
+                prefill_chunk_size: self.prefill_chunk_size,
+                steering: None,
+                block_sparse: None,
+            },
+            logits_rows: None,
+        })
+    }
+
+    /// One row per token of each sequence: the tokens missing from the KV cache, of which the
+    /// last one is sampled, followed by the proposed tokens to verify them in the same forward
+    /// pass. Proposed tokens forming a tree take one slot each but only attend their ancestors.
+    pub fn prepare_decode(&self, seqs: &[SequenceInput]) -> Result<PreparedInputs, APIError> {
+        let mut input_tokens = Vec::new();
+        let mut input_positions = Vec::new();
+        let mut context_lens = Vec::new();
+        let mut slot_mappings = Vec::new();
+        let mut block_tables = Vec::new();
+        let mut seq_ids = Vec::new();
+        let mut logits_rows = Vec::new();
+        let mut draft_trees = Vec::new();
+        for seq in seqs {
+            let table = seq.block_table.as_ref().unwrap();
+            let num_rows = input_tokens.len() + seq.token_ids.len();
+            logits_rows.extend((num_rows - seq.num_sampled_tokens..num_rows).map(|row| row as u32));
+            if let Some(tree) = &seq.draft_tree {
+                let root = seq.token_ids.len() - seq.num_sampled_tokens;
+                draft_trees.push(self.draft_tree_attention(
+                    tree,
+                    table,
+                    num_rows - seq.num_sampled_tokens,
+                    seq.cache_indices[root],
+                )?);
+            }
+            let rows = zip(&seq.token_ids, zip(&seq.positions, &seq.cache_indices));
+            for (&token, (&position, &cache_index)) in rows {
+                input_tokens.push(vec![token]);
+                input_positions.push(vec![position]);
+                // Evicted tokens leave no slot behind, the cache is indexed without them.
+                let slot = compute_slot(table, cache_index, self.block_size)
+                    .unwrap_or_else(|| {
+                        panic!("Block table is too small (completion)! start_pos={} block_size={} table_len={}", cache_index, self.block_size, table.len())
+                    });
+                let slot = slot.try_into().unwrap();
+                slot_mappings.push(vec![slot]);
+                // Each row attends its own window, which ends with its own token.
+                let (attended, context_len) =
+                    attended_blocks(table, cache_index, self.block_size, self.sliding_window)
+                        .unwrap();
+                context_lens.push(context_len);
+                block_tables.push(attended.to_vec());
+            }
+            seq_ids.push(seq.seq_id);
+        }
+        let logits_rows = (logits_rows.len() < input_tokens.len()).then_some(logits_rows);
+
+        let input_tokens = _make_tensor_with_pad(
+            input_tokens
+                .iter()
+                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            1,
+            0,
+            &self.device,
+        )?;
+        let slot_mapping = _make_tensor_with_pad(slot_mappings, 1, PAD_SLOT_ID, &self.device)?;
+
+        let max_context_len = context_lens.iter().max().unwrap();
+        let context_lens = try_api!(Tensor::from_vec(
+            context_lens.iter().map(|x| *x as u32).collect::<Vec<_>>(),
+            (context_lens.len(),),
+            &self.device,
+        ));
+
+        let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
+        let block_tables = _make_tensor_with_pad(
+            block_tables
+                .iter()
+                .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            max_block_table_len,
+            0,
+            &self.device,
+        )?;
+        let block_tables = try_api!(block_tables.reshape(((), max_block_table_len)));
+        Ok(PreparedInputs {
+            tokens: input_tokens,
+            positions: input_positions,
+            metadata: InputMetadata {
+                prompt_lens: vec![],
+                slot_mapping,
+                max_context_len: Some(*max_context_len),
+                context_lens: Some(context_lens),
+                block_tables: Some(block_tables),
+                attn_bias: None,
+                is_prompt: false,
+                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
+                seq_ids,
+                draft_trees,
+                recurrent_state: None,
+                prefill_chunk_size: None,
+                steering: None,
+                block_sparse: None,
+            },
+            logits_rows,
+        })
+    }
+
+    /// Attention of the nodes of `tree`, whose root is the token at `first_row` and
+    /// `cache_index`.
+    fn draft_tree_attention(
+        &self,
+        tree: &DraftTree,
+        block_table: &[usize],
+        first_row: usize,
+        cache_index: usize,
+    ) -> Result<DraftTreeAttention, APIError> {
+        if self.sliding_window.is_some() {
+            return Err(APIError::new_str(
+                "Draft trees are not supported for models with a sliding window.",
+            ));
+        }
+        let num_nodes = tree.len() + 1;
+        let mask = (0..num_nodes)
+            .flat_map(|node| {
+                (0..num_nodes).map(move |other| {
+                    if tree.attends(node, other) {
+                        0.
+                    } else {
+                        f32::NEG_INFINITY
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(DraftTreeAttention {
+            first_row,
+            block_table: block_table.iter().map(|&block| block as u32).collect(),
+            context_len: cache_index,
+            mask: try_api!(Tensor::from_vec(mask, (num_nodes, num_nodes), &self.device)),
+        })
+    }
+}
@@ -0,0 +1,188 @@
+//! Integrity of the safetensors shards of a checkpoint, checked before they load so that a
+//! truncated or corrupted download fails at startup instead of generating garbage.
+//!
+//! The size of every shard is checked against the one its header declares. Shards are also
+//! hashed with SHA-256 and compared with the digest of a manifest given by the user, or else with
+//! the one of the hub: files downloaded with `hf-hub` are links to a blob named after their
+//! etag, which is the SHA-256 of the file for the LFS files weights are stored in.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::openai::responses::APIError;
+
+/// Expected SHA-256 of the shards of a checkpoint, by file name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WeightManifest {
+    digests: HashMap<String, String>,
+}
+
+impl WeightManifest {
+    /// Read a manifest, either a JSON object of file names to their digest or the output of
+    /// `sha256sum`.
+    pub fn load(path: &Path) -> Result<Self, APIError> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            APIError::new(format!(
+                "Cannot read the weights manifest {}: {err}",
+                path.display()
+            ))
+        })?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, APIError> {
+        let digests: HashMap<String, String> = if text.trim_start().starts_with('{') {
+            serde_json::from_str(text)
+                .map_err(|err| APIError::new(format!("Invalid weights manifest: {err}")))?
+        } else {
+            let mut digests = HashMap::new();
+            for line in text.lines().filter(|line| !line.trim().is_empty()) {
+                // `sha256sum` marks files hashed in binary mode with a `*`.
+                let Some((digest, file)) = line.trim().split_once(char::is_whitespace) else {
+                    return Err(APIError::new(format!(
+                        "Invalid line in the weights manifest: {line}"
+                    )));
+                };
+                let file = file.trim_start().trim_start_matches('*');
+                digests.insert(file.to_string(), digest.to_string());
+            }
+            digests
+        };
+        let mut manifest = Self::default();
+        for (file, digest) in digests {
+            if !is_sha256(&digest) {
+                return Err(APIError::new(format!(
+                    "The digest of {file} in the weights manifest is not a SHA-256: {digest}"
+                )));
+            }
+            // Only the file name is matched, shards are listed from any folder.
+            let name = Path::new(&file)
+                .file_name()
+                .map_or(file.clone(), |name| name.to_string_lossy().into_owned());
+            manifest.digests.insert(name, digest.to_lowercase());
+        }
+        Ok(manifest)
+    }
+
+    /// Expected digest of the shard `file`.
+    pub fn digest(&self, file: &Path) -> Option<&str> {
+        let name = file.file_name()?.to_str()?;
+        self.digests.get(name).map(String::as_str)
+    }
+}
+
+fn is_sha256(digest: &str) -> bool {
+    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Digest of `file` in the hub, from the blob of the `hf-hub` cache it links to. `None` for files
+/// outside of the cache and files not stored with LFS, whose etag is not a SHA-256.
+pub fn hub_digest(file: &Path) -> Option<String> {
+    let blob = std::fs::read_link(file).ok()?;
+    let name = blob.file_name()?.to_str()?;
+    is_sha256(name).then(|| name.to_lowercase())
+}
+
+/// SHA-256 of the contents of `file`, in hex.
+pub fn sha256_file(file: &Path) -> io::Result<String> {
+    let mut reader = File::open(file)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check that the size of the shard `file` is the one its header declares: the 8 bytes of the
+/// header size, the JSON header and the data of its tensors.
+pub fn check_safetensors_size(file: &Path) -> Result<(), APIError> {
+    let invalid = |reason: String| {
+        APIError::new(format!(
+            "The weights in {} are corrupted ({reason}), download them again.",
+            file.display()
+        ))
+    };
+    let mut reader = File::open(file)
+        .map_err(|err| APIError::new(format!("Cannot open {}: {err}", file.display())))?;
+    let size = reader.metadata().map_err(APIError::from)?.len();
+    let mut header_size = [0; 8];
+    reader
+        .read_exact(&mut header_size)
+        .map_err(|_| invalid(format!("{size} bytes is too short for a safetensors file")))?;
+    let header_size = u64::from_le_bytes(header_size);
+    if header_size > size - 8 {
+        return Err(invalid(format!(
+            "a header of {header_size} bytes in a file of {size} bytes"
+        )));
+    }
+    let mut header = vec![0; header_size as usize];
+    reader.read_exact(&mut header).map_err(APIError::from)?;
+    let header: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&header).map_err(|err| invalid(format!("invalid header: {err}")))?;
+    let data_size = header
+        .iter()
+        .filter(|(name, _)| *name != "__metadata__")
+        .filter_map(|(_, tensor)| tensor["data_offsets"][1].as_u64())
+        .max()
+        .unwrap_or(0);
+    let expected = 8 + header_size + data_size;
+    if size != expected {
+        return Err(invalid(format!(
+            "{size} bytes instead of the {expected} bytes of its header"
+        )));
+    }
+    Ok(())
+}
+
+/// Check the shards `files` before they load: their size, and their digest in `manifest` or
+/// else in the hub. With a manifest, every shard must be listed in it. The shards are hashed in
+/// parallel.
+pub fn verify_weights(
+    files: &[PathBuf],
+    manifest: Option<&WeightManifest>,
+) -> Result<(), APIError> {
+    // The index of a sharded checkpoint lists a shard once per tensor in it.
+    let mut shards = files.to_vec();
+    shards.sort();
+    shards.dedup();
+    shards.par_iter().try_for_each(|file| {
+        check_safetensors_size(file)?;
+        let expected = match manifest {
+            Some(manifest) => Some(manifest.digest(file).map(str::to_string).ok_or_else(|| {
+                APIError::new(format!(
+                    "{} is not listed in the weights manifest.",
+                    file.display()
+                ))
+            })?),
+            None => hub_digest(file),
+        };
+        let Some(expected) = expected else {
+            warn!(
+                "no digest is known for {}, only its size is checked",
+                file.display()
+            );
+            return Ok(());
+        };
+        let digest = sha256_file(file).map_err(APIError::from)?;
+        if digest != expected {
+            return Err(APIError::new(format!(
+                "The weights in {} are corrupted: their SHA-256 is {digest} instead of \
+                 {expected}, download them again.",
+                file.display()
+            )));
+        }
+        Ok(())
+    })
+}
@@ -1,103 +1,309 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     iter::zip,
     sync::Arc,
 };
 
-use super::{ModulePipeline, _make_tensor_with_pad};
+use super::{
+    admission::{AdmissionContext, AdmissionQueue, PreparedRequest},
+    executor::{Executor, LocalExecutor},
+    worker::{CacheOps, ModelInput, SequenceInput, Worker, WorkerConfig},
+    ModulePipeline,
+};
 use crate::openai::streaming::ChatResponse;
 use crate::scheduler::Scheduler;
 use crate::{
     openai::{
+        fingerprint::{kernels, SystemFingerprint},
+        guidance::guide_logits,
+        hooks::{
+            EngineObserver, LogitsProcessor, ModerationContext, ModerationMatch, Moderator,
+            StepEvent, TokenAction, TokenEvent,
+        },
+        logit_dump::LogitDump,
         responses::{
             APIError, ChatChoice, ChatChoiceData, ChatCompletionChunk, ChatCompletionUsageResponse,
-            Choice, ChoiceData, WrapperLogprobs,
+            Choice, ChoiceData, ToolCall, WrapperLogprobs,
         },
-        sampling_params::SamplingParams,
+        sampling_params::{Logprobs, SamplingParams},
+        special_tokens::SpecialTokens,
+        tool_calls::finish_choice,
         utils::get_created_time_secs,
     },
-    paged_attention::input_metadata::InputMetadata,
     scheduler::{
-        cache_engine::{CacheConfig, CacheEngine},
-        sequence::{Sequence, SequenceGroup, _Sequence},
-        SchedulerConfig, SchedulerOutput,
+        cache_engine::CacheConfig,
+        draft_tree::DraftTree,
+        kv_transfer::SequenceKV,
+        policy::SchedulingPolicy,
+        prompt_lookup::PromptLookupConfig,
+        request_log::{AcceptedRequest, RecoveredRequest, RequestLog},
+        sequence::{_Sequence, Sequence, SequenceGroup, SequenceGroupMetrics, SequenceStatus},
+        CacheStats, SchedulerConfig, SchedulerLimits, SchedulerOutput, SchedulerSnapshot,
     },
     try_api,
 };
 use candle_core::Tensor;
 use either::Either;
 use flume::Sender;
-use std::time::SystemTime;
-use tokenizers::Encoding;
+use std::time::{Instant, SystemTime};
+use tokenizers::{Encoding, Token};
 use tokio::sync::Mutex;
 use tokio::sync::Notify;
-#[allow(dead_code)]
-struct PreparedInputs {
-    tokens: Tensor,
-    positions: Vec<Vec<usize>>,
-    metadata: InputMetadata,
+use tracing::{error, info, warn};
+
+/// Choices and usage of a finished request.
+pub type Response = (Vec<ChatChoice>, ChatCompletionUsageResponse);
+
+/// A request run or finished by `LLMEngine::step`.
+#[derive(Debug, Clone)]
+pub struct RequestOutput {
+    pub request_id: String,
+    /// Tokens each choice generated so far.
+    pub token_ids: Vec<Vec<usize>>,
+    /// Choices and usage of the request, once it finished.
+    pub response: Option<Response>,
 }
 
-const _PAD_SLOT_ID: i64 = -1;
+/// What a sleeping engine frees of the device memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SleepLevel {
+    /// Free the GPU KV cache.
+    KvCache,
+    /// Free the GPU KV cache and the weights, which are reloaded from the checkpoint on wake up.
+    Weights,
+}
 
+/// Sequences are shared between the scheduler, the block engine and the pipeline as
+/// `Arc<Sequence>`/`Arc<SequenceGroup>` behind locks, so the engine is `Send + Sync` and can be
+/// driven from a tokio task.
 pub struct LLMEngine {
-    pipeline: Box<dyn ModulePipeline>,
+    /// Runs the model, the engine only schedules and samples.
+    executor: Box<dyn Executor>,
     scheduler: Scheduler,
     seq_id: usize,
     cache_config: CacheConfig,
+    /// What the workers run the model with, e.g. the control vectors requests may steer with.
+    worker_config: WorkerConfig,
     group_id: usize,
-    cache_engine: CacheEngine,
-    sliding_window: Option<usize>,
+    prompt_lookup: Option<PromptLookupConfig>,
+    /// Tokens proposed by prompt lookup so far, and how many of them were accepted.
+    num_proposed_tokens: usize,
+    num_accepted_tokens: usize,
+    /// Run every sequence through the model on its own, see `SchedulerConfig::batch_invariant`.
+    batch_invariant: bool,
     pub notify: Arc<Notify>,
     pub finish_notify: Arc<Notify>,
-    pub completion_records: HashMap<String, (Vec<ChatChoice>, ChatCompletionUsageResponse)>,
+    /// Scheduler state as of the last step, readable while the engine is busy generating.
+    pub scheduler_trace: Arc<std::sync::RwLock<SchedulerSnapshot>>,
+    /// Limits of the scheduler, which can be changed while the engine is busy generating and
+    /// apply from the next scheduler step.
+    pub scheduler_limits: Arc<std::sync::RwLock<SchedulerLimits>>,
+    /// Requests to abort, which can be asked while the engine is busy generating and are aborted
+    /// at the next scheduler step.
+    pub aborted_requests: Arc<std::sync::Mutex<HashSet<String>>>,
+    pub completion_records: HashMap<String, Response>,
+    /// When the first token of the running groups was sampled, by group id.
+    prompt_finish_times: HashMap<usize, SystemTime>,
+    /// Set while the engine sleeps, requests are rejected until it wakes up.
+    sleeping: Option<SleepLevel>,
+    /// KV cache of the finished requests that asked for `export_kv`, by request id.
+    exported_kv: HashMap<String, SequenceKV>,
+    /// Metrics of the finished requests, by request id.
+    request_metrics: HashMap<String, SequenceGroupMetrics>,
+    request_log: Option<RequestLog>,
+    /// Requests found unfinished in the request log when the engine started.
+    recovered_requests: Vec<RecoveredRequest>,
+    observers: Vec<Arc<dyn EngineObserver>>,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    moderators: Vec<Arc<dyn Moderator>>,
+    /// Special tokens of the tokenizer, dropped from the text of the choices or spaced out.
+    special_tokens: SpecialTokens,
+    /// Requests submitted while the engine may be busy, taken in at the start of each step.
+    admissions: AdmissionQueue,
+    system_fingerprint: SystemFingerprint,
+    /// `id` of `system_fingerprint`, which every response and streamed chunk carries.
+    system_fingerprint_id: String,
 }
 
 impl LLMEngine {
+    /// An engine running `pipeline` on its device, in the engine thread.
     pub fn new(
         pipeline: Box<dyn ModulePipeline>,
         scheduler_config: SchedulerConfig,
         cache_config: CacheConfig,
+        worker_config: WorkerConfig,
+        notify: Arc<Notify>,
+        finish_notify: Arc<Notify>,
+    ) -> Result<Arc<Mutex<Self>>, APIError> {
+        let worker = Worker::new(pipeline, &cache_config, &worker_config)?;
+        Self::with_executor(
+            Box::new(LocalExecutor::new(worker)),
+            scheduler_config,
+            cache_config,
+            worker_config,
+            notify,
+            finish_notify,
+        )
+    }
+
+    /// An engine running the model on the workers of `executor`, whose KV cache was allocated
+    /// with `cache_config` and which run the model with `worker_config`.
+    pub fn with_executor(
+        executor: Box<dyn Executor>,
+        scheduler_config: SchedulerConfig,
+        cache_config: CacheConfig,
+        worker_config: WorkerConfig,
         notify: Arc<Notify>,
         finish_notify: Arc<Notify>,
     ) -> Result<Arc<Mutex<Self>>, APIError> {
-        let cache_engine = CacheEngine::new(
-            pipeline.get_model_config(),
-            cache_config.clone(),
-            cache_config.dtype,
-            &pipeline.device(),
-        )?;
+        let pipeline = executor.pipeline();
+        worker_config.verify_args()?;
+        worker_config.verify_model(pipeline)?;
         let sliding_window = pipeline.get_model_config().sliding_window;
+        if sliding_window.is_some() && cache_config.attention_sinks.is_some() {
+            return Err(APIError::new_str(
+                "Attention sinks are not supported for models with a sliding window.",
+            ));
+        }
+        if pipeline.is_encoder_decoder() && cache_config.attention_sinks.is_some() {
+            return Err(APIError::new_str(
+                "Attention sinks are not supported for encoder-decoder models.",
+            ));
+        }
+        if let Some(prompt_lookup) = &scheduler_config.prompt_lookup {
+            prompt_lookup.verify_args()?;
+            if sliding_window.is_some()
+                || pipeline.is_encoder_decoder()
+                || cache_config.attention_sinks.is_some()
+            {
+                return Err(APIError::new_str(
+                    "Prompt lookup is not supported for models with a sliding window, \
+                    encoder-decoder models and attention sinks.",
+                ));
+            }
+        }
+        if scheduler_config.max_sessions > 0 && pipeline.is_encoder_decoder() {
+            return Err(APIError::new_str(
+                "Conversation sessions are not supported for encoder-decoder models.",
+            ));
+        }
+        if pipeline.get_model_config().recurrent_state.is_some() {
+            // The recurrent states hold the whole sequence, they cannot be rolled back to a
+            // shared prefix or past rejected proposals.
+            if scheduler_config.max_sessions > 0 || scheduler_config.prompt_lookup.is_some() {
+                return Err(APIError::new_str(
+                    "Conversation sessions and prompt lookup are not supported for hybrid models.",
+                ));
+            }
+            if cache_config.num_state_slots.is_none() {
+                return Err(APIError::new_str(
+                    "The model has recurrent layers, the cache needs slots for their states.",
+                ));
+            }
+        }
+        if scheduler_config.long_prefill_token_threshold.is_some()
+            && scheduler_config.max_long_prefills == 0
+        {
+            return Err(APIError::new_str(
+                "At least one long prefill has to be allowed per step.",
+            ));
+        }
+        let special_tokens = SpecialTokens::from_tokenizer(pipeline.tokenizer().tokenizer());
+        let admissions = AdmissionQueue::new(AdmissionContext::new(pipeline), notify.clone());
+        let system_fingerprint = SystemFingerprint {
+            model: pipeline.name().to_string(),
+            weights: None,
+            dtype: pipeline.get_dtype(),
+            kv_cache_dtype: cache_config.dtype,
+            tensor_parallel_size: executor.tensor_parallel_size(),
+            kernels: kernels(pipeline.get_model_config().use_flash_attn),
+        };
+        let system_fingerprint_id = system_fingerprint.id();
+        let idle_shrink_after = cache_config.idle_shrink_after;
+        let prompt_lookup = scheduler_config.prompt_lookup;
+        let batch_invariant = scheduler_config.batch_invariant;
+
+        let scheduler = Scheduler::new(scheduler_config, &cache_config);
+        let scheduler_limits = Arc::new(std::sync::RwLock::new(scheduler.limits()));
 
         let engine = Arc::new(Mutex::new(Self {
-            pipeline,
-            scheduler: Scheduler::new(scheduler_config, &cache_config),
+            executor,
+            scheduler,
             seq_id: 0,
             cache_config,
+            worker_config,
             group_id: 0,
-            cache_engine,
-            sliding_window,
+            prompt_lookup,
+            num_proposed_tokens: 0,
+            num_accepted_tokens: 0,
+            batch_invariant,
             notify: notify.clone(),
             finish_notify: finish_notify.clone(),
+            scheduler_trace: Arc::new(std::sync::RwLock::new(SchedulerSnapshot::default())),
+            scheduler_limits,
+            aborted_requests: Arc::new(std::sync::Mutex::new(HashSet::new())),
             completion_records: HashMap::new(),
+            prompt_finish_times: HashMap::new(),
+            sleeping: None,
+            exported_kv: HashMap::new(),
+            request_metrics: HashMap::new(),
+            request_log: None,
+            recovered_requests: Vec::new(),
+            observers: Vec::new(),
+            logits_processors: Vec::new(),
+            moderators: Vec::new(),
+            special_tokens,
+            admissions,
+            system_fingerprint,
+            system_fingerprint_id,
         }));
         let engine_clone = engine.clone();
 
         let _ = tokio::task::spawn_blocking(move || {
             tokio::runtime::Handle::current().block_on(async move {
                 loop {
-                    notify.notified().await; // Blocking call to wait for notification
+                    match idle_shrink_after {
+                        Some(idle) => {
+                            if tokio::time::timeout(idle, notify.notified()).await.is_err() {
+                                if let Err(err) = engine.lock().await.shrink_idle_cache() {
+                                    println!("Failed to shrink the idle KV cache: {err:?}");
+                                }
+                                continue;
+                            }
+                        }
+                        None => notify.notified().await, // Blocking call to wait for notification
+                    }
                     let _ = tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-                    let mut e = engine.lock().await;
-                    let result = e.generate_once().unwrap();
+                    // The engine is let go of between steps, for requests to be submitted and
+                    // their state read while the others run.
+                    let mut result = HashMap::new();
+                    loop {
+                        let mut e = engine.lock().await;
+                        let finished = match e.generate_step() {
+                            Ok(Some(finished)) => finished,
+                            Ok(None) => break,
+                            Err(err) => {
+                                error!(%err, "generation failed, aborting all requests");
+                                e.abort_all(&err);
+                                finish_notify.notify_waiters();
+                                break;
+                            }
+                        };
+                        if finished.is_empty() {
+                            continue;
+                        }
+                        // Recorded before the engine is let go of, for the requests waiting on
+                        // their response.
+                        for (request_id, response) in &finished {
+                            e.completion_records.insert(request_id.clone(), response.clone());
+                        }
+                        finish_notify.notify_one();
+                        result.extend(finished);
+                    }
                     if result.len() == 0 {
                         continue;
                     }
 
-                    let _ = result.values()
-                    .map(|usage| e.completion_records.insert(usage.1.request_id.clone(), usage.clone()));
-                    finish_notify.notify_one();
-
                     //chat completion statistics
                     let overall_usage = ChatCompletionUsageResponse {
                         request_id: "".to_string(),
@@ -117,6 +323,11 @@ impl LLMEngine {
                             .map(|(_, usage)| usage.completion_time_costs)
                             .max()
                             .unwrap_or(0),
+                        max_tokens: result
+                            .values()
+                            .map(|(_, usage)| usage.max_tokens)
+                            .max()
+                            .unwrap_or(0),
                     };
 
                     println!(
@@ -145,29 +356,439 @@ impl LLMEngine {
         Ok(engine_clone)
     }
 
+    /// What of the serving stack the output of the requests depends on, see `SystemFingerprint`.
+    pub fn system_fingerprint(&self) -> &SystemFingerprint {
+        &self.system_fingerprint
+    }
+
+    /// The `system_fingerprint` of the responses.
+    pub fn system_fingerprint_id(&self) -> &str {
+        &self.system_fingerprint_id
+    }
+
+    /// Digest of the weights the model was loaded from, which the engine is not given with
+    /// the pipeline. See `weights_digest`.
+    pub fn set_weights_digest(&mut self, digest: String) {
+        self.system_fingerprint.weights = Some(digest);
+        self.system_fingerprint_id = self.system_fingerprint.id();
+        info!(
+            system_fingerprint = %self.system_fingerprint,
+            id = %self.system_fingerprint_id,
+            "serving stack"
+        );
+    }
+
+    /// Names of the control vectors requests may steer with.
+    pub fn control_vector_names(&self) -> Vec<String> {
+        self.worker_config
+            .control_vectors
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Pipeline of the executor, which tokenizes and samples.
     pub fn get_pipeline(&self) -> &dyn ModulePipeline {
-        &*self.pipeline
+        self.executor.pipeline()
     }
 
     pub fn get_mut_pipeline(&mut self) -> &mut dyn ModulePipeline {
-        &mut *self.pipeline
+        self.executor.pipeline_mut()
+    }
+
+    pub fn get_cache_config(&self) -> &CacheConfig {
+        &self.cache_config
+    }
+
+    pub fn scheduler_snapshot(&self) -> SchedulerSnapshot {
+        SchedulerSnapshot {
+            model: self.get_pipeline().name().to_string(),
+            num_proposed_tokens: self.num_proposed_tokens,
+            num_accepted_tokens: self.num_accepted_tokens,
+            acceptance_rate: (self.num_proposed_tokens > 0)
+                .then(|| self.num_accepted_tokens as f64 / self.num_proposed_tokens as f64),
+            ..self.scheduler.snapshot()
+        }
+    }
+
+    /// Requests waiting to be scheduled, including those not yet admitted.
+    pub fn num_waiting_requests(&self) -> usize {
+        self.scheduler.num_waiting() + self.admissions.len()
+    }
+
+    /// Queue of the requests submitted without the engine, which it takes in at its next step.
+    pub fn admission_queue(&self) -> AdmissionQueue {
+        self.admissions.clone()
+    }
+
+    /// Tokens the free GPU blocks of the KV cache hold, including those it has yet to grow into.
+    pub fn num_free_gpu_tokens(&self) -> usize {
+        self.scheduler.num_free_gpu_blocks() * self.cache_config.block_size
+    }
+
+    /// Block usage of the KV cache, as polled by autoscalers.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.scheduler.snapshot().cache_stats()
+    }
+
+    /// Release the GPU cache grown beyond its first chunk, if no request is in flight. Returns
+    /// whether the cache was shrunk.
+    pub fn shrink_idle_cache(&mut self) -> Result<bool, APIError> {
+        if self.sleeping.is_some()
+            || self.scheduler.has_unfinished_sequences()
+            || !self.scheduler.block_engine.shrink_gpu_blocks()
+        {
+            return Ok(false);
+        }
+        self.sync_gpu_cache_size()?;
+        Ok(true)
+    }
+
+    /// Free the GPU KV cache, and the weights at `SleepLevel::Weights`, to yield the device to
+    /// other jobs until `wake`. The engine only sleeps once no request is in flight.
+    pub fn sleep(&mut self, level: SleepLevel) -> Result<(), APIError> {
+        if self.has_unfinished_requests() {
+            return Err(APIError::new_str(
+                "The engine cannot sleep while requests are in flight.",
+            ));
+        }
+        // The KV cache kept for conversations goes with the cache, a cache grown lazily is
+        // restored to its first chunk only.
+        self.scheduler.clear_sessions();
+        self.scheduler.block_engine.shrink_gpu_blocks();
+        self.executor.resize_gpu_cache(0)?;
+        if level == SleepLevel::Weights {
+            self.executor.release_weights();
+        }
+        self.sleeping = self.sleeping.max(Some(level));
+        info!(?level, "engine sleeping");
+        Ok(())
+    }
+
+    /// Restore what `sleep` freed, waking an engine that is awake does nothing.
+    pub fn wake(&mut self) -> Result<(), APIError> {
+        let Some(level) = self.sleeping else {
+            return Ok(());
+        };
+        if level == SleepLevel::Weights {
+            self.executor.reload_weights()?;
+        }
+        self.sync_gpu_cache_size()?;
+        self.sleeping = None;
+        info!("engine awake");
+        Ok(())
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping.is_some()
     }
 
+    /// Fails while the engine sleeps, with the error its requests are rejected with.
+    pub fn check_awake(&self) -> Result<(), APIError> {
+        match self.sleeping {
+            Some(_) => Err(APIError::new_str(
+                "The engine is sleeping, `POST /wake` to serve requests.",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Tokens proposed by prompt lookup so far and how many of them were accepted.
+    pub fn speculative_tokens(&self) -> (usize, usize) {
+        (self.num_proposed_tokens, self.num_accepted_tokens)
+    }
+
+    /// Have `observer` called on the tokens, steps and requests run from now on.
+    pub fn add_observer(&mut self, observer: Arc<dyn EngineObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Have `processor` process the logits of every request added from now on, before the
+    /// processors of the request.
+    pub fn add_logits_processor(&mut self, processor: Arc<dyn LogitsProcessor>) {
+        self.logits_processors.push(processor);
+    }
+
+    /// Have `moderator` moderate the text of the requests run from now on.
+    pub fn add_moderator(&mut self, moderator: Arc<dyn Moderator>) {
+        self.moderators.push(moderator);
+    }
+
+    /// Schedule the requests with `policy` from now on, see `Scheduler::set_policy`.
+    pub fn set_scheduling_policy(&mut self, policy: Arc<dyn SchedulingPolicy>) {
+        self.scheduler.set_policy(policy);
+    }
+
+    /// Processors of the logits of a request with `sampling_params`.
+    fn request_logits_processors(
+        &self,
+        sampling_params: &SamplingParams,
+    ) -> Vec<Arc<dyn LogitsProcessor>> {
+        self.logits_processors
+            .iter()
+            .chain(&sampling_params.logits_processors.0)
+            .cloned()
+            .collect()
+    }
+
+    /// What the observers make of a sampled token, the first one that stops the choice wins.
+    fn observe_token(&self, request_id: &str, index: usize, logprobs: &Logprobs) -> TokenAction {
+        let token = TokenEvent {
+            request_id,
+            index,
+            logprobs,
+        };
+        self.observers
+            .iter()
+            .map(|observer| observer.on_token(&token))
+            .find(|action| *action != TokenAction::Continue)
+            .unwrap_or(TokenAction::Continue)
+    }
+
+    /// Text the token of `logprobs` adds to the choice `seq` of a request of `sampling_params`,
+    /// without the special tokens it skips.
+    fn token_delta(
+        &self,
+        sampling_params: &SamplingParams,
+        seq: &Sequence,
+        logprobs: &Logprobs,
+    ) -> String {
+        self.special_tokens.delta(
+            seq.deref()
+                .get_last_output_token()
+                .map(|token| token as u32),
+            logprobs.token as u32,
+            &logprobs.bytes,
+            sampling_params.skip_special_tokens,
+            sampling_params.spaces_between_special_tokens,
+        )
+    }
+
+    /// What the moderators match in the text of the choice `seq` with the text `delta` of its new
+    /// token, recorded in the seq.
+    fn moderate_token(
+        &self,
+        request_id: &str,
+        index: usize,
+        seq: &Sequence,
+        delta: &str,
+    ) -> Vec<ModerationMatch> {
+        if self.moderators.is_empty() {
+            return Vec::new();
+        }
+        seq.deref_mut().moderate(delta, |text, new_text| {
+            let context = ModerationContext {
+                request_id,
+                index,
+                text,
+                new_text,
+            };
+            self.moderators
+                .iter()
+                .filter_map(|moderator| moderator.moderate(&context))
+                .collect()
+        })
+    }
+
+    /// Log the requests accepted from now on to `request_log`.
+    pub fn set_request_log(&mut self, request_log: RequestLog) {
+        self.request_log = Some(request_log);
+    }
+
+    /// Report `requests`, found unfinished in the request log, as recovered and queue the
+    /// idempotent ones again under their request id if `replay` is set. The others are lost.
+    pub fn recover(&mut self, requests: Vec<AcceptedRequest>, replay: bool) {
+        for request in requests {
+            let replayed = replay && request.is_idempotent();
+            let system_fingerprint = &self.system_fingerprint_id;
+            if replayed
+                && request
+                    .system_fingerprint
+                    .as_ref()
+                    .is_some_and(|logged| logged != system_fingerprint)
+            {
+                warn!(
+                    request_id = %request.request_id,
+                    logged = request.system_fingerprint.as_deref().unwrap_or_default(),
+                    %system_fingerprint,
+                    "request replayed on another serving stack, its output may differ"
+                );
+            }
+            if replayed {
+                let tokens = request
+                    .prompt_tokens
+                    .iter()
+                    .map(|&id| Token::new(id, String::new(), (0, 0)))
+                    .collect();
+                self.add_request(
+                    Encoding::from_tokens(tokens, 0),
+                    request.request_id.clone(),
+                    SystemTime::now(),
+                    request.sampling_params,
+                    request.use_logprobs,
+                    None,
+                    None,
+                );
+            } else {
+                warn!(
+                    request_id = %request.request_id,
+                    prompt_hash = %request.prompt_hash,
+                    "request lost in a crash"
+                );
+            }
+            self.recovered_requests.push(RecoveredRequest {
+                request_id: request.request_id,
+                prompt_hash: request.prompt_hash,
+                prompt_tokens: request.prompt_tokens.len(),
+                accepted_at: request.accepted_at,
+                replayed,
+            });
+        }
+    }
+
+    pub fn recovered_requests(&self) -> &[RecoveredRequest] {
+        &self.recovered_requests
+    }
+
+    fn log_finished(&mut self, request_id: &str) {
+        if let Some(request_log) = &mut self.request_log {
+            if let Err(err) = request_log.finished(request_id) {
+                warn!(%request_id, "failed to log the request as finished: {err}");
+            }
+        }
+    }
+
+    /// Take the KV cache exported when the request `request_id`, which set `export_kv`, finished.
+    pub fn take_exported_kv(&mut self, request_id: &str) -> Option<SequenceKV> {
+        self.exported_kv.remove(request_id)
+    }
+
+    /// When the finished request `request_id` was queued, scheduled, generated its first token
+    /// and finished.
+    pub fn request_metrics(&self, request_id: &str) -> Option<&SequenceGroupMetrics> {
+        self.request_metrics.get(request_id)
+    }
+
+    fn export_kv(&mut self, group: &SequenceGroup) -> Result<SequenceKV, APIError> {
+        if self.get_pipeline().is_encoder_decoder() {
+            return Err(APIError::new_str(
+                "The KV cache of encoder-decoder models cannot be exported.",
+            ));
+        }
+        if self
+            .get_pipeline()
+            .get_model_config()
+            .recurrent_state
+            .is_some()
+        {
+            return Err(APIError::new_str(
+                "The KV cache of hybrid models does not hold their recurrent states.",
+            ));
+        }
+        let seq = group.get_seqs().values().next().unwrap();
+        let seq = seq.deref();
+        if seq.get_num_evicted_tokens() > 0 {
+            return Err(APIError::new_str(
+                "The KV cache of a sequence with evicted tokens cannot be exported.",
+            ));
+        }
+        let to_u32 = |ids: &[usize]| ids.iter().map(|&id| id as u32).collect();
+        let token_ids = seq.get_token_ids();
+        let (prompt_token_ids, output_token_ids) = token_ids.split_at(seq.get_prompt_len());
+        let block_ids = self
+            .scheduler
+            .block_engine
+            .get_block_table_ids(seq.get_id())
+            .ok_or_else(|| APIError::new_str("The sequence has no blocks to export."))?;
+        Ok(SequenceKV {
+            prompt_token_ids: to_u32(prompt_token_ids),
+            output_token_ids: to_u32(output_token_ids),
+            block_size: self.cache_config.block_size,
+            blocks: self.executor.export_blocks(&block_ids)?,
+        })
+    }
+
+    /// Resize the GPU cache to the blocks the block engine has allocated.
+    fn sync_gpu_cache_size(&mut self) -> Result<(), APIError> {
+        let num_blocks = self.scheduler.block_engine.get_num_allocated_gpu_blocks();
+        if num_blocks != self.executor.get_num_gpu_blocks() {
+            self.executor.resize_gpu_cache(num_blocks)?;
+            println!("KV cache resized to {num_blocks} GPU blocks");
+        }
+        Ok(())
+    }
+
+    fn record_scheduler_trace(&self) {
+        let snapshot = self.scheduler_snapshot();
+        *self
+            .scheduler_trace
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = snapshot;
+    }
+
+    /// What the streamed choice `seq` of `group` finishing with `finish_reason` ends with: the
+    /// text its stop checker held back, or its tool calls, and the finish reason they make.
+    fn last_delta(
+        group: &SequenceGroup,
+        seq: &Sequence,
+        finish_reason: String,
+    ) -> (Option<String>, Option<Vec<ToolCall>>, String) {
+        if group.stop_checker.is_empty() {
+            return (None, None, finish_reason);
+        }
+        let mut seq = seq.deref_mut();
+        let (content, tool_calls, finish_reason) = finish_choice(
+            seq.get_checked_text().to_string(),
+            &group.sampling_params.tools,
+            &finish_reason,
+        );
+        let content = match tool_calls {
+            // The text before the calls was streamed up to where they could start.
+            Some(_) => content
+                .and_then(|content| content.get(seq.get_streamed_len()..).map(str::to_string)),
+            None => Some(seq.take_unstreamed()),
+        };
+        let tool_calls = tool_calls.map(|calls| {
+            calls
+                .into_iter()
+                .enumerate()
+                .map(|(index, call)| ToolCall {
+                    index: Some(index),
+                    ..call
+                })
+                .collect()
+        });
+        let content = content.filter(|content| !content.is_empty());
+        (content, tool_calls, finish_reason)
+    }
+
+    /// A chunk carries the delta of a single choice, so that the choices of a request with `n > 1`
+    /// can be told apart by their `index` as their tokens interleave in the stream.
     fn get_stream_response(
         &mut self,
         request_id: String,
         created: u64,
+        index: usize,
         content: Option<String>,
         finish_reason: Option<String>,
     ) -> ChatCompletionChunk {
         let mut choices = Vec::new();
         let choice = Choice {
             delta: ChoiceData {
-                role: self.pipeline.get_conversation(true).get_roles().0.clone(),
+                role: self
+                    .get_mut_pipeline()
+                    .get_conversation(true)
+                    .get_roles()
+                    .0
+                    .clone(),
                 content: content,
+                tool_calls: None,
             },
             finish_reason: finish_reason,
-            index: 0,
+            index,
+            logprobs: None,
+            moderation: Vec::new(),
+            token_ids: None,
         };
         choices.push(choice);
 
@@ -175,433 +796,1020 @@ impl LLMEngine {
             id: request_id,
             choices: choices,
             created: created,
-            model: self.pipeline.name().to_string(),
+            model: self.get_pipeline().name().to_string(),
             object: "chat.completion.chunk",
-            system_fingerprint: None,
+            system_fingerprint: Some(self.system_fingerprint_id.clone()),
+            usage: None,
+            prompt_token_ids: None,
+        }
+    }
+
+    /// Run the engine until every request added so far finished. Returns the choices and usage
+    /// of each request, by request id.
+    pub fn generate_once(&mut self) -> Result<HashMap<String, Response>, APIError> {
+        let mut responses = HashMap::<String, Response>::new();
+        while let Some(finished) = self.generate_step()? {
+            responses.extend(finished);
+        }
+        Ok(responses)
+    }
+
+    /// A step of `generate_once`, which the background task of the engine runs one at a time.
+    /// Returns the responses of the requests the step finished, `None` once every request did.
+    fn generate_step(&mut self) -> Result<Option<HashMap<String, Response>>, APIError> {
+        // Requests that came in as the engine fell asleep are aborted.
+        self.check_awake()?;
+        self.admit_queued();
+        if !self.scheduler.has_unfinished_sequences() {
+            self.record_scheduler_trace();
+            self.executor.reset_decoder();
+            return Ok(None);
         }
+        let (_, finished) = self.run_step()?;
+        Ok(Some(finished))
     }
 
-    pub fn generate_once(
+    /// Run a single iteration of the engine: schedule the requests, run the model on them and
+    /// sample their next tokens. For embedders driving the engine from their own loop, e.g. a
+    /// game tick, instead of the background task of the engine: they add requests with
+    /// `add_request` and call `step` while `has_unfinished_requests`, without notifying
+    /// `notify`, which wakes the background task up. Returns the requests the step ran or
+    /// finished.
+    pub fn step(&mut self) -> Result<Vec<RequestOutput>, APIError> {
+        self.check_awake()?;
+        self.admit_queued();
+        if !self.scheduler.has_unfinished_sequences() {
+            return Ok(Vec::new());
+        }
+        let (groups, mut finished) = self.run_step()?;
+        if !self.scheduler.has_unfinished_sequences() {
+            self.record_scheduler_trace();
+            self.executor.reset_decoder();
+        }
+        Ok(groups
+            .iter()
+            .map(|group| RequestOutput {
+                request_id: group.request_id.clone(),
+                token_ids: group
+                    .get_choices()
+                    .map(|seq| {
+                        let outputs = seq.deref().get_output_tokens();
+                        outputs.iter().map(|logprobs| logprobs.token).collect()
+                    })
+                    .collect(),
+                response: finished.remove(&group.request_id),
+            })
+            .collect())
+    }
+
+    /// Whether requests are waiting or running, see `step`.
+    pub fn has_unfinished_requests(&self) -> bool {
+        self.scheduler.has_unfinished_sequences() || !self.admissions.is_empty()
+    }
+
+    /// Roll the choice `index` of the unfinished request `request_id` back by its last
+    /// `num_tokens` tokens between two `step`s, reusing the KV cache of the tokens left instead
+    /// of prefilling them again, see `Scheduler::rollback`.
+    pub fn rollback(
         &mut self,
-    ) -> Result<HashMap<String, (Vec<ChatChoice>, ChatCompletionUsageResponse)>, APIError> {
-        let mut responses =
-            HashMap::<String, (Vec<ChatChoice>, ChatCompletionUsageResponse)>::new();
-        let mut prompt_finish_times = HashMap::<usize, SystemTime>::new();
-        // let mut prompt_finish_time = SystemTime::now();
-        while self.scheduler.has_unfinished_sequences() {
-            let scheduler_outputs = self.scheduler.schedule();
-            if !scheduler_outputs.ignored_seq_groups.is_empty() {
-                todo!();
+        request_id: &str,
+        index: usize,
+        num_tokens: usize,
+    ) -> Result<(), APIError> {
+        self.scheduler.rollback(request_id, index, num_tokens)
+    }
+
+    /// One step of the scheduler and the model. Returns the groups it timed out or ran, and the
+    /// responses of the ones it finished.
+    fn run_step(
+        &mut self,
+    ) -> Result<(Vec<Arc<SequenceGroup>>, HashMap<String, Response>), APIError> {
+        let mut responses = HashMap::<String, Response>::new();
+        // Requests submitted during the last step join this one.
+        self.admit_queued();
+        let limits = *self
+            .scheduler_limits
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        self.scheduler.set_limits(limits);
+        // Ids of requests that finished in the meantime are dropped with the others.
+        let aborted_requests = std::mem::take(
+            &mut *self
+                .aborted_requests
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        let aborted = self.scheduler.abort_requests(&aborted_requests);
+        let scheduler_outputs = self.scheduler.schedule();
+        self.record_scheduler_trace();
+        // The prompts which cannot fit in the KV cache however long they wait are refused.
+        for group in scheduler_outputs.ignored_seq_groups.iter() {
+            let prompt_len = group
+                .get_seqs()
+                .values()
+                .next()
+                .map_or(0, |seq| seq.deref().get_prompt_len());
+            let err = APIError::new(format!(
+                "The prompt of {prompt_len} tokens, for {} sequences, does not fit in the KV \
+                 cache of {} blocks of {} tokens.",
+                group.get_seqs().len(),
+                self.scheduler.block_engine.get_num_gpu_blocks(),
+                self.cache_config.block_size
+            ));
+            warn!(request_id = %group.request_id, prompt_len, "request ignored: {}", err.message());
+            if let Some(sender) = &group.sender {
+                let _ = sender.send(ChatResponse::ValidationError(err.message().to_string()));
             }
+            let response = self.finish_seq_group(group, None);
+            responses.insert(group.request_id.clone(), response);
+        }
 
-            self.execute_scheduler_ops(&scheduler_outputs).unwrap();
+        let interrupted = aborted.iter().map(|group| (group, "abort")).chain(
+            scheduler_outputs
+                .timed_out
+                .iter()
+                .map(|group| (group, "timeout")),
+        );
+        for (group, reason) in interrupted {
+            warn!(request_id = %group.request_id, finish_reason = reason, "request interrupted");
+            if let Some(sender) = &group.sender {
+                for (index, seq) in group.get_choices().enumerate() {
+                    let finish_reason = seq.deref().get_finish_reason();
+                    if finish_reason == reason {
+                        let chunk = self.get_stream_response(
+                            group.request_id.clone(),
+                            group.arrival_time,
+                            index,
+                            None,
+                            Some(finish_reason),
+                        );
+                        let _ = sender.send(ChatResponse::Chunk(chunk));
+                    }
+                }
+            }
+            let prompt_finish_time = self.prompt_finish_times.remove(group.get_id());
+            let response = self.finish_seq_group(group, prompt_finish_time);
+            responses.insert(group.request_id.clone(), response);
+        }
+        let mut groups = aborted;
+        groups.extend(scheduler_outputs.timed_out.iter().cloned());
+        groups.extend(scheduler_outputs.ignored_seq_groups.iter().cloned());
+        if scheduler_outputs.scheduled.is_empty() {
+            return Ok((groups, responses));
+        }
+        groups.extend(scheduler_outputs.scheduled.iter().cloned());
 
-            let scheduled: &VecDeque<Arc<SequenceGroup>> = &*scheduler_outputs.scheduled;
-            // for group in scheduled.iter() {
-            let seqs = scheduled[0].get_seqs();
+        self.sync_gpu_cache_size()?;
+        self.execute_scheduler_ops(&scheduler_outputs)?;
 
-            let PreparedInputs {
-                tokens,
-                positions,
-                metadata,
-            } = if seqs.values().nth(0).unwrap().deref().is_prompt() {
-                self.prepare_prompt(scheduled)
-            } else {
-                self.prepare_decode(scheduled)
+        let scheduled: &VecDeque<Arc<SequenceGroup>> = &*scheduler_outputs.scheduled;
+        let step_start = Instant::now();
+        let now = SystemTime::now();
+        for group in scheduled.iter() {
+            group.record_scheduled(now);
+        }
+        // for group in scheduled.iter() {
+        let seqs = scheduled[0].get_seqs();
+        let is_prompt = seqs.values().nth(0).unwrap().deref().is_prompt();
+        if is_prompt {
+            for group in scheduled.iter() {
+                info!(request_id = %group.request_id, "prefill started");
             }
-            .unwrap();
+        }
 
-            let logits = self
-                .pipeline
-                .forward(
-                    tokens,
-                    &positions,
-                    Some(&*self.cache_engine.get_kv_cache()),
-                    metadata,
-                )
-                .unwrap();
-            let results = self.pipeline.sample(logits, scheduled).unwrap();
+        let proposals = if is_prompt {
+            None
+        } else {
+            self.propose_tokens(scheduled)
+        };
+        let logits = self.forward(scheduled, is_prompt, proposals.as_deref());
+        let logits = match logits {
+            Ok(logits) => logits,
+            Err(err) if is_out_of_memory(&err) => {
+                // Retry the step with a smaller batch, the preempted groups run later.
+                let Some(blocks_to_swap_out) = self.scheduler.shrink_batch(scheduled, is_prompt)
+                else {
+                    return Err(err);
+                };
+                self.executor.execute_cache_ops(&CacheOps {
+                    blocks_to_swap_out,
+                    ..Default::default()
+                })?;
+                warn!(
+                    max_batch_seqs = self.scheduler.get_max_batch_seqs(),
+                    "out of memory, shrinking the batch, consider lowering max_num_seqs or the \
+                    kvcache memory"
+                );
+                self.record_scheduler_trace();
+                return Ok((groups, responses));
+            }
+            Err(err) => return Err(err),
+        };
+        let logits = self.apply_guidance(logits, scheduled)?;
+        // The rest of the cached prompts is written, their sequences decode from now on.
+        for (_, seq) in unfinished_seqs(scheduled) {
+            if seq.deref().get_num_cached_tokens().is_some() {
+                seq.deref_mut().set_num_cached_tokens(None);
+            }
+        }
+        let results = match &proposals {
+            Some(proposals) => {
+                let results = self
+                    .get_mut_pipeline()
+                    .verify_proposals(logits, scheduled, proposals)?;
+                for ((group, seq), (results, proposal)) in
+                    zip(unfinished_seqs(scheduled), zip(&results, proposals))
+                {
+                    let accepted =
+                        proposal.accepted_nodes(results.iter().map_while(|result| match result {
+                            Either::Left(logprobs) => Some(logprobs.token as u32),
+                            Either::Right(_) => None,
+                        }));
+                    self.num_proposed_tokens += proposal.len();
+                    self.num_accepted_tokens += accepted.len();
+                    // The branch is exhausted when the last accepted node has no child left.
+                    let last_node = accepted.last().copied().unwrap_or(0);
+                    let exhausted = !proposal.parents().contains(&last_node);
+                    group.record_speculation(proposal.len(), accepted.len(), exhausted);
+                    // The accepted nodes of a tree were written after the last token in node
+                    // order, the ones past the first branch are written again at their place
+                    // in the next step.
+                    let num_in_place = accepted
+                        .iter()
+                        .enumerate()
+                        .take_while(|&(depth, &node)| node == depth + 1)
+                        .count();
+                    if num_in_place < accepted.len() {
+                        let num_cached_tokens = seq.deref().get_len() + num_in_place;
+                        seq.deref_mut()
+                            .set_num_cached_tokens(Some(num_cached_tokens));
+                    }
+                }
+                results
+            }
+            None => self
+                .get_mut_pipeline()
+                .sample(logits, scheduled)?
+                .into_iter()
+                .map(|result| vec![result])
+                .collect(),
+        };
 
-            for (result_, group) in zip(results, scheduled) {
-                match result_ {
+        // Results come in the order of the sequences, which is the order of the choices
+        // within each group.
+        let seqs = scheduled
+            .iter()
+            .flat_map(|group| {
+                group
+                    .get_unfinished_choices()
+                    .map(move |(index, seq)| (group, index, seq.clone()))
+            })
+            .collect::<Vec<_>>();
+        let num_seqs = seqs.len();
+        let mut num_tokens = 0;
+        for (results, (group, index, seq)) in zip(results, seqs) {
+            // Several tokens of a seq are accepted at once with speculative decoding.
+            for result_ in results {
+                let mut moderation = Vec::new();
+                // The text clients see, never the special tokens the request skips.
+                let delta = match &result_ {
+                    Either::Left(logprobs) => {
+                        self.token_delta(&group.sampling_params, &seq, logprobs)
+                    }
+                    Either::Right(_) => String::new(),
+                };
+                let result_ = match result_ {
+                    Either::Left(logprobs) => {
+                        match self.observe_token(&group.request_id, index, &logprobs) {
+                            TokenAction::Continue => {
+                                moderation =
+                                    self.moderate_token(&group.request_id, index, &seq, &delta);
+                                if moderation.iter().any(|m| m.halt) {
+                                    Either::Right("content_filter".to_string())
+                                } else {
+                                    Either::Left(logprobs)
+                                }
+                            }
+                            TokenAction::Stop(finish_reason) => Either::Right(finish_reason),
+                        }
+                    }
+                    finished => finished,
+                };
+                // Only the text before a stop string is streamed, and the choice stops once the
+                // token completing one was added.
+                let mut stopped = false;
+                let delta = match &result_ {
+                    Either::Left(_) if !group.stop_checker.is_empty() => {
+                        let (text, stop) = seq.deref_mut().check_stop(&delta, &group.stop_checker);
+                        stopped = stop;
+                        text
+                    }
+                    _ => delta,
+                };
+                let mut finish_reason = match result_ {
                     Either::Left(logprobs) => {
-                        let seq = group.get_seqs().values().nth(0).unwrap();
-                        if seq.deref().is_prompt() {
-                            prompt_finish_times.insert(*group.get_id(), SystemTime::now());
+                        if seq.deref().is_prompt()
+                            && !self.prompt_finish_times.contains_key(group.get_id())
+                        {
+                            let now = SystemTime::now();
+                            self.prompt_finish_times.insert(*group.get_id(), now);
+                            group.record_first_token(now);
+                            let time_to_first_token =
+                                now.duration_since(group.created_time).unwrap_or_default();
+                            info!(
+                                request_id = %group.request_id,
+                                time_to_first_token_ms = time_to_first_token.as_millis() as u64,
+                                "first token"
+                            );
                         }
                         if let Some(sender) = &group.sender {
-                            let chunk = self.get_stream_response(
+                            let mut chunk = self.get_stream_response(
                                 group.request_id.clone(),
                                 group.arrival_time,
-                                Some(logprobs.bytes.clone()),
+                                index,
+                                Some(delta),
                                 None,
                             );
+                            if group.use_logprobs {
+                                chunk.choices[0].logprobs = Some(WrapperLogprobs {
+                                    content: vec![logprobs.clone()],
+                                });
+                            }
+                            chunk.choices[0].moderation = moderation;
+                            if group.sampling_params.return_tokens {
+                                chunk.choices[0].token_ids = Some(vec![logprobs.token]);
+                            }
                             let ret = sender.send(ChatResponse::Chunk(chunk));
                             if ret.is_err() {
-                                println!("Send stream response error!");
-                                seq.deref_mut().set_finish_reason("Abort".to_string());
+                                warn!(
+                                    request_id = %group.request_id,
+                                    "stream closed by the client, aborting"
+                                );
+                                seq.deref_mut().set_finish_reason("abort".to_string());
                                 break;
                             }
                         };
                         // print!("{}", logprobs.bytes.clone());
                         seq.deref_mut().add_token(logprobs);
+                        num_tokens += 1;
+                        if !stopped {
+                            continue;
+                        }
+                        moderation = Vec::new();
+                        "stop".to_string()
                     }
-                    Either::Right(finish_reason) => {
-                        let seq = group.get_seqs().values().nth(0).unwrap();
-                        if let Some(sender) = &group.sender {
-                            let chunk = self.get_stream_response(
-                                group.request_id.clone(),
-                                group.arrival_time,
-                                None,
-                                Some(finish_reason.clone()),
-                            );
-                            sender.send(ChatResponse::Chunk(chunk)).unwrap();
-                        };
-                        seq.deref_mut().set_finish_reason(finish_reason)
+                    Either::Right(finish_reason) => finish_reason,
+                };
+                if let Some(sender) = &group.sender {
+                    let (content, tool_calls, reason) =
+                        Self::last_delta(group, &seq, finish_reason);
+                    finish_reason = reason;
+                    let mut chunk = self.get_stream_response(
+                        group.request_id.clone(),
+                        group.arrival_time,
+                        index,
+                        content,
+                        Some(finish_reason.clone()),
+                    );
+                    chunk.choices[0].delta.tool_calls = tool_calls;
+                    chunk.choices[0].moderation = moderation;
+                    let _ = sender.send(ChatResponse::Chunk(chunk));
+                };
+                seq.deref_mut().set_finish_reason(finish_reason);
+                break;
+            }
+        }
+        if !self.observers.is_empty() {
+            let step = StepEvent {
+                is_prompt,
+                request_ids: scheduled
+                    .iter()
+                    .map(|group| group.request_id.clone())
+                    .collect(),
+                num_seqs,
+                num_tokens,
+                duration: step_start.elapsed(),
+            };
+            for observer in &self.observers {
+                observer.on_step(&step);
+            }
+        }
+
+        for group in scheduled.iter() {
+            group.sync_negative_seqs();
+        }
+
+        // The blocks of the finished groups are freed next.
+        for group in scheduled.iter() {
+            if group.sampling_params.export_kv && group.is_finished() {
+                match self.export_kv(group) {
+                    Ok(kv) => {
+                        self.exported_kv.insert(group.request_id.clone(), kv);
+                    }
+                    Err(err) => {
+                        warn!(request_id = %group.request_id, "failed to export the KV cache: {err}")
                     }
                 }
             }
+        }
+        self.scheduler.free_finished_sequence_groups();
 
-            self.scheduler.free_finished_sequence_groups();
+        for group in scheduled.iter() {
+            if group.is_finished() && !responses.contains_key(&group.request_id) {
+                let prompt_finish_time = self.prompt_finish_times.remove(group.get_id());
+                let response = self.finish_seq_group(group, prompt_finish_time);
+                responses.insert(group.request_id.clone(), response);
+            }
+        }
+        Ok((groups, responses))
+    }
+}
 
-            for group in scheduled.iter() {
-                if group.is_finished() && !responses.contains_key(&group.request_id) {
-                    let end_time = SystemTime::now();
-                    let prompt_finish_time = prompt_finish_times[group.get_id()];
-                    let completion_time_costs = end_time
-                        .duration_since(prompt_finish_time)
-                        .unwrap()
-                        .as_millis();
-                    println!(
-                        "Request {} decoding finished in {} seconds",
-                        group.request_id,
-                        completion_time_costs / 1000
-                    );
-                    // Create choices from the group
-                    let mut seqs = group.get_seqs().values().collect::<Vec<_>>();
-                    seqs.sort_by(|seq_a, seq_b| {
-                        seq_b
-                            .deref_mut()
-                            .get_cumulative_logprob()
-                            .partial_cmp(&seq_a.deref_mut().get_cumulative_logprob())
-                            .unwrap()
-                    });
-                    let top_n = seqs.get(0..group.sampling_params.n).unwrap();
-
-                    let mut choices = Vec::new();
-                    for (index, seq) in top_n.iter().enumerate() {
-                        let outputs = seq.deref_mut().get_output_tokens();
-                        let data = outputs
-                            .iter()
-                            .map(|x| x.token.try_into().unwrap())
-                            .collect::<Vec<_>>();
-                        let data = self
-                            .pipeline
-                            .tokenizer()
-                            .tokenizer()
-                            .decode(&data, false)
-                            .unwrap();
-                        let choice = ChatChoice {
-                            message: ChatChoiceData {
-                                role: self.pipeline.get_conversation(true).get_roles().0.clone(),
-                                content: Some(data),
-                            },
-                            finish_reason: Some(seq.deref_mut().get_finish_reason().clone()),
-                            index,
-                            logprobs: if group.use_logprobs {
-                                Some(WrapperLogprobs { content: outputs })
-                            } else {
-                                None
-                            },
-                        };
-                        choices.push(choice);
-                    }
+/// The unfinished sequences of `groups` with their group, in the order of their rows.
+fn unfinished_seqs(
+    groups: &VecDeque<Arc<SequenceGroup>>,
+) -> Vec<(&Arc<SequenceGroup>, &Arc<Sequence>)> {
+    groups
+        .iter()
+        .flat_map(|group| group.get_unfinished_seqs().map(move |seq| (group, seq)))
+        .collect()
+}
 
-                    let completion_tokens = top_n
-                        .iter()
-                        .map(|seq| seq.deref().get_len() - seq.deref().get_prompt_len())
-                        .sum();
-                    let prompt_tokens = top_n.first().unwrap().deref().get_prompt_len();
-
-                    let prompt_time_costs = prompt_finish_time
-                        .duration_since(group.created_time)
-                        .unwrap()
-                        .as_millis();
-
-                    let usage = ChatCompletionUsageResponse {
-                        request_id: group.request_id.clone(),
-                        created: group.arrival_time,
-                        completion_tokens: completion_tokens,
-                        prompt_tokens: prompt_tokens,
-                        total_tokens: completion_tokens + prompt_tokens,
-                        prompt_time_costs: prompt_time_costs as usize,
-                        completion_time_costs: completion_time_costs as usize,
-                    };
+/// Whether `err` is a failed allocation of the device, after which the step can be retried with a
+/// smaller batch.
+fn is_out_of_memory(err: &APIError) -> bool {
+    let err = err.to_string();
+    err.contains("CUDA_ERROR_OUT_OF_MEMORY") || err.contains("out of memory")
+}
 
-                    responses.insert(group.request_id.clone(), (choices, usage));
+impl LLMEngine {
+    /// Abort all requests after the engine failed, their streams end with `err`.
+    pub fn abort_all(&mut self, err: &APIError) {
+        self.admit_queued();
+        // The KV cache kept for conversations may not have survived the failure.
+        self.scheduler.clear_sessions();
+        for group in self.scheduler.abort_all() {
+            warn!(request_id = %group.request_id, "request aborted");
+            self.log_finished(&group.request_id);
+            for observer in &self.observers {
+                observer.on_abort(&group.request_id, err);
+            }
+            let seq_ids = group.get_seqs().keys().copied().collect::<Vec<_>>();
+            self.executor.free_sequences(&seq_ids);
+            if let Some(sender) = &group.sender {
+                let _ = sender.send(ChatResponse::ModelError(err.to_string()));
+            }
+        }
+        self.prompt_finish_times.clear();
+        self.record_scheduler_trace();
+        self.executor.reset_decoder();
+    }
 
-                    if let Some(sender) = &group.sender {
-                        let _ = sender.send(ChatResponse::Done);
-                    };
+    /// Build the response of a finished group from its best `n` seqs, and end its stream.
+    fn finish_seq_group(
+        &mut self,
+        group: &SequenceGroup,
+        prompt_finish_time: Option<SystemTime>,
+    ) -> (Vec<ChatChoice>, ChatCompletionUsageResponse) {
+        let end_time = SystemTime::now();
+        // Groups that timed out while waiting never had their prompt processed.
+        let prompt_finish_time = prompt_finish_time.unwrap_or(end_time);
+        let completion_time_costs = end_time
+            .duration_since(prompt_finish_time)
+            .unwrap()
+            .as_millis();
+        // Create choices from the group, beams ranked by their length normalized score.
+        let score = |seq: &Arc<Sequence>| {
+            let seq = seq.deref_mut();
+            let params = &group.sampling_params;
+            if params.use_beam_search {
+                params.beam_search_score(seq.get_cumulative_logprob(), seq.get_len())
+            } else {
+                seq.get_cumulative_logprob()
+            }
+        };
+        let mut seqs = group.get_choices().collect::<Vec<_>>();
+        seqs.sort_by(|seq_a, seq_b| score(seq_b).partial_cmp(&score(seq_a)).unwrap());
+        let top_n = seqs.get(0..group.sampling_params.n).unwrap();
+
+        let mut choices = Vec::new();
+        for (index, seq) in top_n.iter().enumerate() {
+            let outputs = seq.deref_mut().get_output_tokens();
+            let data = outputs
+                .iter()
+                .map(|x| x.token.try_into().unwrap())
+                .collect::<Vec<_>>();
+            let tokenizer = self.get_pipeline().tokenizer().tokenizer();
+            let mut data = self
+                .special_tokens
+                .decode(
+                    tokenizer,
+                    &data,
+                    group.sampling_params.skip_special_tokens,
+                    group.sampling_params.spaces_between_special_tokens,
+                )
+                .unwrap();
+            // The first token generated after token healing repeats the end of the prompt.
+            if let Some(healing) = &group.token_healing {
+                let healed = tokenizer.decode(&[healing.token], false).unwrap();
+                if let Some(generated) = data.strip_prefix(&healed) {
+                    data = generated.to_string();
                 }
             }
+            // The output ends before the stop string that stopped it.
+            if let Some(stop) = group.stop_checker.find_stop(&data) {
+                data.truncate(stop);
+            }
+            let (content, tool_calls, finish_reason) = finish_choice(
+                data,
+                &group.sampling_params.tools,
+                &seq.deref().get_finish_reason(),
+            );
+            let token_ids = group
+                .sampling_params
+                .return_tokens
+                .then(|| outputs.iter().map(|logprobs| logprobs.token).collect());
+            let choice = ChatChoice {
+                message: ChatChoiceData {
+                    role: self
+                        .get_mut_pipeline()
+                        .get_conversation(true)
+                        .get_roles()
+                        .0
+                        .clone(),
+                    content,
+                    tool_calls,
+                },
+                finish_reason: Some(finish_reason),
+                index,
+                logprobs: if group.use_logprobs {
+                    Some(WrapperLogprobs { content: outputs })
+                } else {
+                    None
+                },
+                moderation: seq.deref().get_moderation(),
+                token_ids,
+            };
+            choices.push(choice);
         }
-        self.pipeline.reset_decoder();
-        Ok(responses)
+
+        let completion_tokens = top_n
+            .iter()
+            .map(|seq| seq.deref().get_len() - seq.deref().get_prompt_len())
+            .sum();
+        let prompt_tokens = top_n.first().unwrap().deref().get_prompt_len();
+        group.record_finished(end_time, completion_tokens);
+        let metrics = group.metrics();
+        self.request_metrics
+            .insert(group.request_id.clone(), metrics);
+
+        let prompt_time_costs = prompt_finish_time
+            .duration_since(group.created_time)
+            .unwrap()
+            .as_millis();
+
+        let usage = ChatCompletionUsageResponse {
+            request_id: group.request_id.clone(),
+            created: group.arrival_time,
+            completion_tokens: completion_tokens,
+            prompt_tokens: prompt_tokens,
+            total_tokens: completion_tokens + prompt_tokens,
+            prompt_time_costs: prompt_time_costs as usize,
+            completion_time_costs: completion_time_costs as usize,
+            max_tokens: group.sampling_params.max_tokens,
+        };
+
+        info!(
+            request_id = %group.request_id,
+            finish_reason = %choices
+                .iter()
+                .filter_map(|choice| choice.finish_reason.as_deref())
+                .collect::<Vec<_>>()
+                .join(","),
+            prompt_tokens,
+            completion_tokens,
+            prompt_time_ms = prompt_time_costs as u64,
+            completion_time_ms = completion_time_costs as u64,
+            "request finished"
+        );
+
+        let seq_ids = group.get_seqs().keys().copied().collect::<Vec<_>>();
+        self.executor.free_sequences(&seq_ids);
+
+        if let Some(sender) = &group.sender {
+            let _ = sender.send(ChatResponse::Done);
+        };
+        self.log_finished(&group.request_id);
+        for observer in &self.observers {
+            observer.on_finish(&group.request_id, &choices, &usage);
+            observer.on_request_metrics(&group.request_id, &metrics);
+        }
+
+        (choices, usage)
     }
-}
 
-impl LLMEngine {
     fn execute_scheduler_ops(
         &mut self,
         scheduler_output: &SchedulerOutput,
     ) -> Result<(), APIError> {
-        if scheduler_output.blocks_to_swap_in.len() > 0 {
-            try_api!(self
-                .cache_engine
-                .swap_in(scheduler_output.blocks_to_swap_in.clone()));
-        }
-        if scheduler_output.blocks_to_swap_out.len() > 0 {
-            try_api!(self
-                .cache_engine
-                .swap_out(scheduler_output.blocks_to_swap_out.clone()));
-        }
-        if scheduler_output.blocks_to_copy.len() > 0 {
-            try_api!(self
-                .cache_engine
-                .copy(scheduler_output.blocks_to_copy.clone()));
+        self.executor.execute_cache_ops(&CacheOps {
+            blocks_to_swap_in: scheduler_output.blocks_to_swap_in.clone(),
+            blocks_to_swap_out: scheduler_output.blocks_to_swap_out.clone(),
+            blocks_to_copy: scheduler_output.blocks_to_copy.clone(),
+            states_to_reset: self.scheduler.block_engine.take_new_state_slots(),
+        })
+    }
+
+    /// Logits of the unfinished sequences of `groups`, one row per sequence (and per proposed
+    /// token while decoding) in order. In batch invariant mode each sequence runs through the
+    /// model on its own, so that its rows do not depend on the sequences batched with it: the
+    /// prompt is not padded to the longest one and the kernels are picked for it alone.
+    fn forward(
+        &mut self,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+        is_prompt: bool,
+        proposals: Option<&[DraftTree]>,
+    ) -> Result<Tensor, APIError> {
+        let seqs = self.sequence_inputs(groups, is_prompt, proposals);
+        if !self.batch_invariant {
+            return self.executor.execute_model(&ModelInput { is_prompt, seqs });
         }
-        Ok(())
+        let logits = seqs
+            .into_iter()
+            .map(|seq| {
+                self.executor.execute_model(&ModelInput {
+                    is_prompt,
+                    seqs: vec![seq],
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(try_api!(Tensor::cat(&logits, 0)))
     }
 
-    fn prepare_prompt(
+    /// One row of logits per unfinished choice of `groups`, out of the rows of their unfinished
+    /// sequences: a guided choice has the rows of its negative prompt folded into its own.
+    fn apply_guidance(
         &self,
+        logits: Tensor,
         groups: &VecDeque<Arc<SequenceGroup>>,
-    ) -> Result<PreparedInputs, APIError> {
-        let mut prompt_lens = Vec::new();
-        let mut input_tokens = Vec::new();
-        let mut input_positions = Vec::new();
-        let mut slot_mappings = Vec::new();
+    ) -> Result<Tensor, APIError> {
+        if groups.iter().all(|group| group.guidance.is_none()) {
+            return Ok(logits);
+        }
+        let mut rows = Vec::new();
+        let mut first_row = 0;
         for group in groups {
-            for seq in group.get_seqs().values() {
-                let prompt_ids = seq.deref_mut().get_token_ids();
+            let seq_ids = group
+                .get_unfinished_seqs()
+                .map(|seq| seq.deref().get_id())
+                .collect::<Vec<_>>();
+            let row = |seq: &Arc<Sequence>| {
+                let seq_id = seq.deref().get_id();
+                let index = seq_ids.iter().position(|&id| id == seq_id).unwrap();
+                logits.narrow(0, first_row + index, 1)
+            };
+            for (_, seq) in group.get_unfinished_choices() {
+                let choice_logits = try_api!(row(seq));
+                let negative = group.get_negative_seq(seq.deref().get_id());
+                rows.push(match (negative, &group.guidance) {
+                    (Some(negative), Some(guidance)) => try_api!(guide_logits(
+                        &choice_logits,
+                        &try_api!(row(negative)),
+                        guidance.scale
+                    )),
+                    _ => choice_logits,
+                });
+            }
+            first_row += seq_ids.len();
+        }
+        Ok(try_api!(Tensor::cat(&rows, 0)))
+    }
 
-                let prompt_len = prompt_ids.len();
-                prompt_lens.push(prompt_len);
+    /// What the workers need of the unfinished sequences of `groups` to run them through the
+    /// model: the whole prompt during prefill, else the tokens missing from the KV cache followed
+    /// by the `proposals` of the sequence.
+    fn sequence_inputs(
+        &self,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+        is_prompt: bool,
+        proposals: Option<&[DraftTree]>,
+    ) -> Vec<SequenceInput> {
+        let mut inputs = Vec::new();
+        for (i, (group, seq)) in unfinished_seqs(groups).into_iter().enumerate() {
+            let seq = seq.deref();
+            // A sequence continuing a cached prompt writes the rest of it in its first decode
+            // step, the logits of its last token are the only ones sampled.
+            let (first_position, proposal) = if is_prompt {
+                (0, None)
+            } else {
+                let last_position = seq.get_len() - 1;
+                (
+                    seq.get_num_cached_tokens().unwrap_or(last_position),
+                    proposals.map(|proposals| &proposals[i]),
+                )
+            };
+            let mut token_ids = seq.get_token_ids()[first_position..].to_vec();
+            let indices = first_position..seq.get_len();
+            let mut positions = indices
+                .clone()
+                .map(|i| seq.get_position(i))
+                .collect::<Vec<_>>();
+            // Every token of the prompt is written to the cache, a decode step skips the evicted
+            // tokens.
+            let mut cache_indices = if is_prompt {
+                indices.collect()
+            } else {
+                indices.map(|i| seq.get_cache_index(i)).collect::<Vec<_>>()
+            };
+            let num_proposed = proposal.map_or(0, DraftTree::len);
+            if let Some(proposal) = proposal {
+                // Proposed tokens are written in node order after the last token, and positioned
+                // at their depth in the tree.
+                let last = seq.get_len() - 1;
+                for node in 1..=num_proposed {
+                    positions.push(seq.get_position(last + proposal.depth(node)));
+                    cache_indices.push(seq.get_cache_index(last + node));
+                }
+                token_ids.extend(proposal.tokens().iter().map(|&token| token as usize));
+            }
+            inputs.push(SequenceInput {
+                seq_id: seq.get_id(),
+                group_id: *group.get_id(),
+                pixel_values: group.pixel_values.clone().filter(|_| is_prompt),
+                positions,
+                cache_indices,
+                token_ids,
+                block_table: self
+                    .scheduler
+                    .block_engine
+                    .get_block_table_ids(seq.get_id()),
+                state_slot: self.scheduler.block_engine.get_state_slot(seq.get_id()),
+                control_vectors: group.sampling_params.control_vectors.clone(),
+                num_sampled_tokens: num_proposed + 1,
+                draft_tree: proposal.filter(|proposal| !proposal.is_chain()).cloned(),
+            });
+        }
+        inputs
+    }
 
-                input_tokens.push(prompt_ids);
-                input_positions.push((0..prompt_len).collect::<Vec<_>>());
-                let table = self
+    /// Tokens proposed by prompt lookup for each unfinished sequence of `groups`, limited to the
+    /// slots left in its blocks. `None` if nothing is proposed.
+    fn propose_tokens(&self, groups: &VecDeque<Arc<SequenceGroup>>) -> Option<Vec<DraftTree>> {
+        let prompt_lookup = self.prompt_lookup?;
+        // The rows of a guided choice are combined with the ones of its negative prompt, which
+        // has nothing to verify the proposals with. Logit dumps are written as tokens are
+        // sampled, proposals which end up rejected would be dumped too.
+        if groups
+            .iter()
+            .any(|group| group.guidance.is_some() || group.logit_dump.is_some())
+        {
+            return None;
+        }
+        let mut proposals = Vec::new();
+        for group in groups {
+            for seq in group.get_unfinished_seqs() {
+                let seq = seq.deref();
+                let num_blocks = self
                     .scheduler
                     .block_engine
                     .block_tables
-                    .get(&seq.deref_mut().get_id());
-                if table.is_none() {
-                    // Will be None during profiling.
-                    slot_mappings.push([_PAD_SLOT_ID].repeat(prompt_len));
-                    continue;
-                }
-                let table = table
-                    .unwrap()
-                    .iter()
-                    .map(|block| block.deref_mut().block_id)
-                    .collect::<Vec<_>>();
-
-                let start_idx = if let Some(sliding_window) = self.sliding_window {
-                    if prompt_len > sliding_window {
-                        0.min(prompt_len - sliding_window)
-                    } else {
-                        0
-                    }
-                } else {
-                    0
+                    .get(&seq.get_id())
+                    .map_or(0, |table| table.len());
+                // The token sampled after the accepted ones needs a slot too.
+                let free_slots =
+                    (num_blocks * self.cache_config.block_size).saturating_sub(seq.get_len() + 1);
+                let lookup = PromptLookupConfig {
+                    num_speculative_tokens: prompt_lookup.draft_length(&group.draft_acceptance()),
+                    ..prompt_lookup
                 };
-
-                let mut slot_mapping = Vec::new();
-                for i in 0..prompt_len {
-                    if i < start_idx {
-                        // Pad [0,start_idx) with _PAD_TOKEN_ID
-                        slot_mapping.push(_PAD_SLOT_ID);
-                    }
-
-                    let block_number = if i / self.cache_config.block_size >= table.len() {
-                        panic!(
-                            "Block table is too small (prompt)! i={} block_size={} table_len={}",
-                            i,
-                            self.cache_config.block_size,
-                            table.len()
-                        );
-                    } else {
-                        table.get(i / self.cache_config.block_size).unwrap()
-                    };
-                    let block_offset = i % self.cache_config.block_size;
-                    let slot = block_number * self.cache_config.block_size + block_offset;
-                    slot_mapping.push(slot.try_into().unwrap());
-                }
-                slot_mappings.push(slot_mapping);
+                proposals.push(lookup.propose_tree(&seq.get_token_ids(), free_slots));
             }
         }
-
-        let max_prompt_len = prompt_lens.iter().max().unwrap();
-        let input_tokens = _make_tensor_with_pad(
-            input_tokens
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            *max_prompt_len,
-            0,
-            &self.pipeline.device(),
-        )?;
-        let slot_mapping = _make_tensor_with_pad(
-            slot_mappings,
-            *max_prompt_len,
-            _PAD_SLOT_ID,
-            &self.pipeline.device(),
-        )?;
-
-        Ok(PreparedInputs {
-            tokens: input_tokens,
-            positions: input_positions,
-            metadata: InputMetadata {
-                prompt_lens,
-                slot_mapping,
-                max_context_len: None,
-                context_lens: None,
-                block_tables: None,
-                attn_bias: None,
-                is_prompt: true,
-                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
-            },
-        })
+        proposals
+            .iter()
+            .any(|proposal| !proposal.is_empty())
+            .then_some(proposals)
     }
 
-    fn prepare_decode(
-        &self,
-        groups: &VecDeque<Arc<SequenceGroup>>,
-    ) -> Result<PreparedInputs, APIError> {
-        let mut input_tokens = Vec::new();
-        let mut input_positions = Vec::new();
-        let mut context_lens = Vec::new();
-        let mut slot_mappings = Vec::new();
-        let mut block_tables = Vec::new();
-        for group in groups {
-            for seq in group.get_seqs().values() {
-                let last_token_id = seq.deref_mut().get_last_token_id();
-                input_tokens.push(vec![last_token_id]);
+    /// `pixel_values` are the preprocessed images of the prompt, whose image placeholders are
+    /// expanded here to one token per image feature.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_request(
+        &mut self,
+        prompt: Encoding,
+        request_id: String,
+        created: SystemTime,
+        sampling_params: SamplingParams,
+        use_logprobs: bool,
+        sender: Option<Sender<ChatResponse>>,
+        pixel_values: Option<Tensor>,
+    ) {
+        let request = self.admissions.context().prepare(
+            prompt,
+            request_id,
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+            pixel_values,
+        );
+        self.admit(request);
+    }
 
-                let position = seq.deref_mut().get_len() - 1;
-                input_positions.push(vec![position]);
+    /// Take in the requests submitted to the admission queue since the last step.
+    fn admit_queued(&mut self) {
+        for request in self.admissions.take() {
+            self.admit(request);
+        }
+    }
 
-                let context_len = if let Some(sliding_window) = self.sliding_window {
-                    seq.deref_mut().get_len().min(sliding_window)
+    /// Schedule a request prepared by `AdmissionContext::prepare`.
+    fn admit(&mut self, request: PreparedRequest) {
+        let PreparedRequest {
+            prompt,
+            prompt_ids,
+            request_id,
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+            pixel_values,
+            token_healing,
+            guided_choice,
+        } = request;
+        let prompt_len = prompt_ids.len();
+        // One sequence per candidate choice, `n` of them are returned.
+        let seqs = (0..sampling_params.best_of)
+            .map(|_| {
+                let seq = Arc::new(Sequence(std::sync::RwLock::new(_Sequence::new(
+                    prompt_ids.iter().map(|x| *x as usize).collect::<Vec<_>>(),
+                    self.seq_id,
+                    self.cache_config.block_size,
+                ))));
+                self.seq_id += 1;
+                seq
+            })
+            .collect::<Vec<_>>();
+        let mut seq_group = SequenceGroup::new(
+            &seqs,
+            get_created_time_secs(),
+            self.group_id,
+            request_id.clone(),
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+        );
+        seq_group.pixel_values = pixel_values;
+        seq_group.token_healing = token_healing;
+        seq_group.guided_choice = guided_choice;
+        seq_group.logit_dump = seq_group
+            .sampling_params
+            .logit_dump
+            .as_ref()
+            .and_then(|params| {
+                LogitDump::create(params)
+                    .map_err(|e| warn!(%request_id, "failed to dump the logits: {e:?}"))
+                    .ok()
+            });
+        seq_group.logits_processors = self.request_logits_processors(&seq_group.sampling_params);
+        if let Some(negative_prompt_ids) = seq_group.sampling_params.negative_prompt_ids.clone() {
+            if seq_group.pixel_values.is_some() || self.get_pipeline().is_encoder_decoder() {
+                warn!(%request_id, "negative prompts only guide text prompts of decoders, ignored");
+            } else {
+                // An empty negative prompt is the first token of the prompt, e.g. BOS: the
+                // choices are guided away from what the model generates without a prompt.
+                let negative_prompt_ids = if negative_prompt_ids.is_empty() {
+                    prompt_ids.iter().take(1).copied().collect()
                 } else {
-                    seq.deref_mut().get_len()
+                    negative_prompt_ids
                 };
-                context_lens.push(context_len);
-
-                let table = self
-                    .scheduler
-                    .block_engine
-                    .block_tables
-                    .get(&seq.deref_mut().get_id())
-                    .unwrap();
-                let table = table
-                    .iter()
-                    .map(|block| block.deref_mut().block_id)
+                let negative_seqs = (0..seq_group.sampling_params.best_of)
+                    .map(|_| {
+                        let seq = Arc::new(Sequence(std::sync::RwLock::new(_Sequence::new(
+                            negative_prompt_ids.iter().map(|&id| id as usize).collect(),
+                            self.seq_id,
+                            self.cache_config.block_size,
+                        ))));
+                        self.seq_id += 1;
+                        seq
+                    })
                     .collect::<Vec<_>>();
-
-                let block_number = if position / self.cache_config.block_size >= table.len() {
-                    panic!("Block table is too small (completion)! start_pos={} block_size={} table_len={}", position, self.cache_config.block_size, table.len());
-                } else {
-                    table.get(position / self.cache_config.block_size).unwrap()
-                };
-                let block_offset = position % self.cache_config.block_size;
-                let slot = block_number * self.cache_config.block_size + block_offset;
-                let slot = slot.try_into().unwrap();
-                slot_mappings.push(vec![slot]);
-
-                if let Some(sliding_window) = self.sliding_window {
-                    let sliding_window_blocks = sliding_window / self.cache_config.block_size;
-                    let slide_idx = if table.len() > sliding_window_blocks {
-                        table.len() - sliding_window_blocks
-                    } else {
-                        0
-                    };
-                    block_tables.push(table.get(slide_idx..).unwrap().to_vec());
-                } else {
-                    block_tables.push(table);
-                }
+                let scale = seq_group.sampling_params.guidance_scale;
+                seq_group.add_negative_seqs(&negative_seqs, scale);
             }
         }
+        self.group_id += 1;
 
-        let input_tokens = _make_tensor_with_pad(
-            input_tokens
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            1,
-            0,
-            &self.pipeline.device(),
-        )?;
-        let slot_mapping =
-            _make_tensor_with_pad(slot_mappings, 1, _PAD_SLOT_ID, &self.pipeline.device())?;
-
-        let max_context_len = context_lens.iter().max().unwrap();
-        let context_lens = try_api!(Tensor::from_vec(
-            context_lens.iter().map(|x| *x as u32).collect::<Vec<_>>(),
-            (context_lens.len(),),
-            &self.pipeline.device(),
-        ));
-
-        let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
-        let block_tables = _make_tensor_with_pad(
-            block_tables
-                .iter()
-                .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            max_block_table_len,
-            0,
-            &self.pipeline.device(),
-        )?;
-        let block_tables = try_api!(block_tables.reshape(((), max_block_table_len)));
-        Ok(PreparedInputs {
-            tokens: input_tokens,
-            positions: input_positions,
-            metadata: InputMetadata {
-                prompt_lens: vec![],
-                slot_mapping,
-                max_context_len: Some(*max_context_len),
-                context_lens: Some(context_lens),
-                block_tables: Some(block_tables),
-                attn_bias: None,
-                is_prompt: false,
-                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
-            },
-        })
+        let system_fingerprint = self.system_fingerprint_id.clone();
+        if let Some(request_log) = &mut self.request_log {
+            let mut request = AcceptedRequest::new(
+                request_id.clone(),
+                prompt.get_ids().to_vec(),
+                seq_group.sampling_params.clone(),
+                use_logprobs,
+                seq_group.pixel_values.is_some(),
+            );
+            request.system_fingerprint = Some(system_fingerprint.clone());
+            if let Err(err) = request_log.accepted(request) {
+                warn!(%request_id, "failed to log the request: {err}");
+            }
+        }
+        // A turn of a conversation continues the KV cache of the previous turn.
+        match self.scheduler.resume_conversation(seq_group) {
+            Ok((_, cached_tokens)) => {
+                info!(
+                    %request_id,
+                    prompt_tokens = prompt_len,
+                    cached_tokens,
+                    %system_fingerprint,
+                    "request resumed"
+                );
+            }
+            Err(seq_group) => {
+                self.scheduler.add_sequence(seq_group);
+                info!(
+                    %request_id,
+                    prompt_tokens = prompt_len,
+                    %system_fingerprint,
+                    "request queued"
+                );
+            }
+        }
     }
 
-    pub fn add_request(
+    /// Continue a sequence exported by another engine serving the same model, from the KV cache
+    /// in `kv` instead of a prefill. The tokens `kv` holds count towards the `max_tokens` of
+    /// `sampling_params` and are part of the output.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_request_with_kv(
         &mut self,
-        prompt: Encoding,
+        kv: SequenceKV,
         request_id: String,
         created: SystemTime,
         sampling_params: SamplingParams,
         use_logprobs: bool,
         sender: Option<Sender<ChatResponse>>,
-    ) {
-        let prompt_len = prompt.get_ids().len();
-        let seq = Arc::new(Sequence(std::sync::RwLock::new(_Sequence::new(
-            prompt
-                .get_ids()
-                .to_vec()
-                .iter()
-                .map(|x| *x as usize)
-                .collect::<Vec<_>>(),
+    ) -> Result<(), APIError> {
+        self.check_awake()?;
+        if self.get_pipeline().is_encoder_decoder() {
+            return Err(APIError::new_str(
+                "The KV cache of encoder-decoder models cannot be imported.",
+            ));
+        }
+        if self
+            .get_pipeline()
+            .get_model_config()
+            .recurrent_state
+            .is_some()
+        {
+            return Err(APIError::new_str(
+                "The KV cache of hybrid models does not hold their recurrent states.",
+            ));
+        }
+        if sampling_params.best_of != 1 {
+            return Err(APIError::new(format!(
+                "An imported sequence needs best_of=1, got {}.",
+                sampling_params.best_of
+            )));
+        }
+        if kv.output_token_ids.is_empty() {
+            return Err(APIError::new_str(
+                "The imported sequence has to hold at least one generated token.",
+            ));
+        }
+        if kv.block_size != self.cache_config.block_size {
+            return Err(APIError::new(format!(
+                "The imported sequence has blocks of {} tokens instead of {}.",
+                kv.block_size, self.cache_config.block_size
+            )));
+        }
+        self.executor.check_blocks(&kv.blocks)?;
+
+        let mut seq = _Sequence::new(
+            kv.prompt_token_ids.iter().map(|&id| id as usize).collect(),
             self.seq_id,
             self.cache_config.block_size,
-        ))));
+        );
+        for &token in &kv.output_token_ids {
+            seq.add_token(Logprobs {
+                token: token as usize,
+                logprob: 0.0,
+                top_logprobs: vec![],
+                bytes: self.get_pipeline().token_text(token),
+            });
+        }
+        if seq.get_logical_token_blocks() != kv.num_blocks() {
+            return Err(APIError::new(format!(
+                "The imported sequence of {} tokens has {} blocks instead of {}.",
+                kv.num_tokens(),
+                kv.num_blocks(),
+                seq.get_logical_token_blocks()
+            )));
+        }
+        let seq = Arc::new(Sequence(std::sync::RwLock::new(seq)));
         self.seq_id += 1;
-        let seq_group = SequenceGroup::new(
+        let mut seq_group = SequenceGroup::new(
             &[seq],
             get_created_time_secs(),
             self.group_id,
@@ -611,13 +1819,27 @@ impl LLMEngine {
             use_logprobs,
             sender,
         );
+        seq_group.logits_processors = self.request_logits_processors(&seq_group.sampling_params);
         self.group_id += 1;
 
-        self.scheduler.add_sequence(seq_group);
-        println!(
-            "Request {} with length {} added to sequence group.",
-            request_id.clone(),
-            prompt_len
-        );
+        let Some(seq_group) = self.scheduler.add_running_sequence(seq_group) else {
+            return Err(APIError::new_str(
+                "Not enough free KV cache blocks to import the sequence.",
+            ));
+        };
+        self.sync_gpu_cache_size()?;
+        let seq_id = self.seq_id - 1;
+        let block_ids = self
+            .scheduler
+            .block_engine
+            .get_block_table_ids(seq_id)
+            .unwrap();
+        if let Err(err) = self.executor.import_blocks(&kv.blocks, &block_ids) {
+            seq_group.set_status(SequenceStatus::FinishedAborted);
+            self.scheduler.free_finished_sequence_groups();
+            return Err(err);
+        }
+        info!(%request_id, tokens = kv.num_tokens(), "request imported");
+        Ok(())
     }
 }
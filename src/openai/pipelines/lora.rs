@@ -0,0 +1,163 @@
+use super::{weights::load_safetensors, ModelPaths};
+use crate::{openai::responses::APIError, try_api};
+use candle_core::{DType, Device, Tensor};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Prefix PEFT gives the names of the tensors of an adapter.
+const PEFT_PREFIX: &str = "base_model.model.";
+
+/// The `adapter_config.json` of a PEFT LoRA adapter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    /// The delta is scaled by `lora_alpha / sqrt(r)` instead of `lora_alpha / r`.
+    #[serde(default)]
+    pub use_rslora: bool,
+    /// The adapted weights are stored as `(in, out)`, as the Conv1D layers of GPT-2.
+    #[serde(default)]
+    pub fan_in_fan_out: bool,
+}
+
+impl LoraConfig {
+    pub fn scaling(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+/// The files of a checkpoint, with a LoRA adapter to merge into its weights.
+struct LoraMergePaths {
+    paths: Box<dyn ModelPaths>,
+    adapter: PathBuf,
+}
+
+impl ModelPaths for LoraMergePaths {
+    fn get_weight_filenames(&self) -> &Vec<PathBuf> {
+        self.paths.get_weight_filenames()
+    }
+    fn get_config_filename(&self) -> &PathBuf {
+        self.paths.get_config_filename()
+    }
+    fn get_tokenizer_filename(&self) -> &PathBuf {
+        self.paths.get_tokenizer_filename()
+    }
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        self.paths.get_generation_config_filename()
+    }
+    fn get_lora_adapter(&self) -> Option<&PathBuf> {
+        Some(&self.adapter)
+    }
+}
+
+/// The checkpoint of `paths`, whose weights are merged with the PEFT adapter of the directory
+/// `adapter` when they are loaded.
+pub fn with_lora_merge(paths: Box<dyn ModelPaths>, adapter: PathBuf) -> Box<dyn ModelPaths> {
+    Box::new(LoraMergePaths { paths, adapter })
+}
+
+/// Merge the PEFT LoRA adapter of the directory `adapter_dir` (its `adapter_config.json` and
+/// `adapter_model.safetensors`) into the weights `tensors` of the model, so that it is served
+/// without the cost of the adapter at runtime. Each adapted weight `W` becomes
+/// `W + scaling * B @ A`, computed in f32 and cast back to the dtype of `W`. The modules the
+/// adapter trained in full (`modules_to_save`) replace the ones of the model.
+///
+/// Returns the number of weights changed.
+pub fn merge_lora(
+    tensors: &mut HashMap<String, Tensor>,
+    adapter_dir: &Path,
+    device: &Device,
+    buffer_size: usize,
+) -> Result<usize, APIError> {
+    let config: LoraConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+        adapter_dir.join("adapter_config.json")
+    ))));
+    let adapter_file = adapter_dir.join("adapter_model.safetensors");
+    if !adapter_file.exists() {
+        return Err(APIError::new(format!(
+            "No adapter_model.safetensors in {}, only safetensors adapters can be merged.",
+            adapter_dir.display()
+        )));
+    }
+    let adapter = try_api!(load_safetensors(
+        &[adapter_file],
+        DType::F32,
+        device,
+        buffer_size
+    ));
+
+    let mut pairs: HashMap<String, (Option<Tensor>, Option<Tensor>, bool)> = HashMap::new();
+    let mut replaced = Vec::new();
+    for (name, tensor) in adapter {
+        let name = name.strip_prefix(PEFT_PREFIX).unwrap_or(&name).to_string();
+        let (module, part, embedding) = if let Some(m) = name.strip_suffix(".lora_A.weight") {
+            (m, 0, false)
+        } else if let Some(m) = name.strip_suffix(".lora_B.weight") {
+            (m, 1, false)
+        } else if let Some(m) = name.strip_suffix(".lora_embedding_A") {
+            (m, 0, true)
+        } else if let Some(m) = name.strip_suffix(".lora_embedding_B") {
+            (m, 1, true)
+        } else {
+            // A module of `modules_to_save`, saved in full.
+            replaced.push((name.replace(".modules_to_save.default", ""), tensor));
+            continue;
+        };
+        let pair = pairs.entry(format!("{module}.weight")).or_default();
+        if part == 0 {
+            pair.0 = Some(tensor);
+        } else {
+            pair.1 = Some(tensor);
+        }
+        pair.2 = embedding;
+    }
+
+    let scaling = config.scaling();
+    let mut merged = 0;
+    for (weight_name, pair) in pairs {
+        let (Some(a), Some(b), embedding) = pair else {
+            return Err(APIError::new(format!(
+                "The adapter of {weight_name} lacks its A or B matrix."
+            )));
+        };
+        let Some(weight) = tensors.get(&weight_name) else {
+            return Err(APIError::new(format!(
+                "The adapter targets {weight_name}, which the model does not have."
+            )));
+        };
+        // An embedding adapter maps token ids through A, its delta is the transpose.
+        let mut delta = try_api!(try_api!(b.matmul(&a)).affine(scaling, 0.));
+        if embedding || config.fan_in_fan_out {
+            delta = try_api!(delta.t());
+        }
+        if delta.dims() != weight.dims() {
+            return Err(APIError::new(format!(
+                "The adapter of {weight_name} has shape {:?}, the weight {:?}.",
+                delta.dims(),
+                weight.dims()
+            )));
+        }
+        let dtype = weight.dtype();
+        let weight = try_api!(try_api!(weight.to_dtype(DType::F32)).add(&delta));
+        tensors.insert(weight_name, try_api!(weight.to_dtype(dtype)));
+        merged += 1;
+    }
+    for (name, tensor) in replaced {
+        let Some(weight) = tensors.get(&name) else {
+            return Err(APIError::new(format!(
+                "The adapter replaces {name}, which the model does not have."
+            )));
+        };
+        let dtype = weight.dtype();
+        tensors.insert(name, try_api!(tensor.to_dtype(dtype)));
+        merged += 1;
+    }
+    Ok(merged)
+}
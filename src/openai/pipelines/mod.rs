@@ -6,13 +6,36 @@ use std::{env, fs, path::PathBuf, sync::Arc};
 
 use crate::{paged_attention::input_metadata::InputMetadata, try_api};
 
-use super::{conversation::Conversation, models::Config, responses::APIError, PipelineConfig};
+use super::{
+    audio_processor::AudioProcessor, conversation::Conversation, image_processor::ImageProcessor,
+    models::Config, responses::APIError, PipelineConfig,
+};
 use candle_examples::token_output_stream::TokenOutputStream;
 /// The LLMEngine is effectively a wrapper around a ModulePipeline. It contains a Scheduler and a CacheEngine
 /// which are used to scheduler and manage the cache during generation requests, respectively.
+/// Admission of new requests while the engine runs a step.
+pub mod admission;
+/// Drives the workers running the model, for the engine.
+pub mod executor;
+/// Sampling and stopping defaults from the `generation_config.json` of a checkpoint.
+pub mod generation_config;
+/// Tokens, positions and paged attention metadata of a model step.
+pub mod input_builder;
+/// Size and digest checks of the weights of a checkpoint before they load.
+pub mod integrity;
 pub mod llm_engine;
+/// Merging PEFT LoRA adapters into the weights of a model while it loads.
+pub mod lora;
 pub mod pipeline;
-use crate::scheduler::sequence::SequenceGroup;
+/// Sharding of a model over the GPUs of several workers.
+pub mod tensor_parallel;
+pub mod weights;
+/// Model runner and KV cache of a device, driven by the engine through an executor.
+pub mod worker;
+/// Worker processes and their connection to the engine.
+pub mod worker_process;
+use crate::scheduler::{draft_tree::DraftTree, sequence::SequenceGroup};
+use tensor_parallel::{Shard, TensorParallel};
 type TokenOrFinishReason = Either<Logprobs, String>;
 use std::collections::VecDeque;
 pub trait ModulePipeline: Send + Sync {
@@ -22,14 +45,30 @@ pub trait ModulePipeline: Send + Sync {
         input_positions: &Vec<Vec<usize>>,
         kv_cache: Option<&Vec<(Tensor, Tensor)>>,
         input_metadata: InputMetadata,
+        image_features: &[Option<Tensor>],
     ) -> Result<Tensor, APIError>;
 
+    /// One result for each unfinished sequence of `groups`, whose logits are the rows of
+    /// `logits` in the same order.
     fn sample(
         &mut self,
         logits: Tensor,
         groups: &VecDeque<Arc<SequenceGroup>>,
     ) -> Result<Vec<TokenOrFinishReason>, APIError>;
 
+    /// Verify the tokens proposed for each unfinished sequence of `groups` by speculative
+    /// decoding. The rows of `logits` are, for each sequence in order, the logits of the nodes of
+    /// its draft tree in `proposals`: its last token followed by each proposed token. Proposed
+    /// tokens are accepted as long as they are the tokens sampled, from the root of the tree down
+    /// the branch of each: the results of a sequence are its accepted tokens, ending with the
+    /// first token sampled that no node proposed (or after a leaf), or with a finish reason.
+    fn verify_proposals(
+        &mut self,
+        logits: Tensor,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+        proposals: &[DraftTree],
+    ) -> Result<Vec<Vec<TokenOrFinishReason>>, APIError>;
+
     fn name(&self) -> &str;
 
     fn tokenizer(&self) -> &TokenOutputStream;
@@ -38,10 +77,40 @@ pub trait ModulePipeline: Send + Sync {
 
     fn get_model_config(&self) -> Config;
 
+    /// Preprocessing of image inputs, `None` if the model only accepts text.
+    fn image_processor(&self) -> Option<&ImageProcessor>;
+
+    /// Preprocessing of audio inputs, `None` unless the model transcribes speech. The log-mel
+    /// spectrograms of audio models go through `encode_images` like pixel values.
+    fn audio_processor(&self) -> Option<&AudioProcessor>;
+
+    /// Vision encoder stage: turn pixel values into the features that replace the image tokens
+    /// of the prompt, one `(num_image_tokens, hidden_size)` matrix per image.
+    fn encode_images(&self, pixel_values: &Tensor) -> Result<Tensor, APIError>;
+
+    /// Whether the prompt is the input of an encoder, instead of the start of the generated text.
+    fn is_encoder_decoder(&self) -> bool;
+
+    /// Drop what the model keeps of finished sequences outside of the KV cache (e.g. the encoder
+    /// output of encoder-decoder models).
+    fn free_sequences(&mut self, seq_ids: &[usize]);
+
+    /// Drop the weights of the model to free the device memory they take.
+    fn release_weights(&mut self);
+
+    /// Load the weights dropped by `release_weights` from the checkpoint again.
+    fn reload_weights(&mut self) -> Result<(), APIError>;
+
+    /// Text of a single generated token.
+    fn token_text(&self, token: u32) -> String;
+
     fn get_dtype(&self) -> DType;
 
     fn device(&self) -> &Device;
 
+    /// The part of the model loaded, with tensor parallelism.
+    fn shard(&self) -> Shard;
+
     fn reset_decoder(&mut self) -> Option<String>;
 }
 
@@ -89,6 +158,12 @@ pub trait ModelPaths {
     fn get_weight_filenames(&self) -> &Vec<PathBuf>;
     fn get_config_filename(&self) -> &PathBuf;
     fn get_tokenizer_filename(&self) -> &PathBuf;
+    /// `None` if the checkpoint has no `generation_config.json`.
+    fn get_generation_config_filename(&self) -> Option<&PathBuf>;
+    /// The directory of a PEFT LoRA adapter merged into the weights while they load.
+    fn get_lora_adapter(&self) -> Option<&PathBuf> {
+        None
+    }
 }
 
 pub trait ModelLoader {
@@ -100,10 +175,30 @@ pub trait ModelLoader {
         hf_token_path: Option<String>,
     ) -> Result<Box<dyn ModelPaths>, APIError>;
 
+    /// Load the weights on `device` through a host buffer of `weight_buffer_mem` MB.
     fn load_model(
         &self,
         paths: Box<dyn ModelPaths>,
         dtype: DType,
         device: Device,
+        weight_buffer_mem: usize,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError>;
+
+    /// `load_model` for the rank of `parallel` of a model sharded with tensor parallelism.
+    fn load_model_shard(
+        &self,
+        paths: Box<dyn ModelPaths>,
+        dtype: DType,
+        device: Device,
+        weight_buffer_mem: usize,
+        parallel: TensorParallel,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError>;
+
+    /// Load the tokenizer and configuration of the model on the CPU without its weights, for the
+    /// engine to tokenize and sample with while worker processes run the model.
+    fn load_model_without_weights(
+        &self,
+        paths: Box<dyn ModelPaths>,
+        dtype: DType,
     ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError>;
 }
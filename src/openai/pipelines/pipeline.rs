@@ -1,24 +1,42 @@
-use super::{get_token, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason};
-use crate::openai::logits_processor::{LogitsProcessor, Sampling};
+use super::{
+    generation_config::GenerationConfig,
+    get_token,
+    lora::merge_lora,
+    tensor_parallel::{check_tensor_parallel, Shard, TensorParallel},
+    weights::{load_safetensors_shard, DEFAULT_WEIGHT_BUFFER_MEM},
+    ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason,
+};
+use crate::openai::logits_processor::{
+    mask_logits, suppress_logits, token_logprobs, LogitsProcessor, Sampling,
+};
 use crate::openai::models::TokenID;
-use crate::openai::sampling_params::{Logprobs, TopLogprob};
-use crate::scheduler::sequence::SequenceGroup;
+use crate::openai::sampling_params::{Logprobs, SamplingParams, TopLogprob};
+use crate::scheduler::{draft_tree::DraftTree, sequence::SequenceGroup};
 use crate::{
+    backend::{rejection_sample, sample_tokens},
+    get_checkpoint_dtype,
     openai::{
+        audio_processor::AudioProcessor,
         conversation::{
             default_conversation::{
                 DefaultConversation, DefaultConversationSeparators, SeparatorStyle,
             },
             Conversation,
         },
+        hooks::LogitsContext,
+        image_processor::ImageProcessor,
+        json_mode::JsonState,
         models::{
             gemma::{Gemma, GemmaConfig},
             llama::{Llama, LlamaConfig},
+            llava::{LLaVA, LLaVAConfig},
             mistral::{Mistral, MistralConfig},
             phi2::{Phi2, Phi2Config},
             phi3::{Phi, PhiConfig},
             qwen2::{Qwen2, QwenConfig},
             stable_lm::{StableLM, StableLMConfig},
+            t5::{T5Config, T5},
+            whisper::{Whisper, WhisperConfig},
             yi::{Yi, YiConfig},
             Config,
         },
@@ -26,18 +44,23 @@ use crate::{
         PipelineConfig,
     },
     paged_attention::input_metadata::InputMetadata,
-    try_api,
+    try_api, SIZE_IN_MB,
 };
-use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_core::{DType, Device, IndexOp, Tensor, D};
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_nn::VarBuilder;
 use either::Either;
 use either::Either::{Left, Right};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use rayon::prelude::*;
-use std::collections::VecDeque;
-use std::{path::PathBuf, sync::Arc};
+use std::collections::{HashMap, VecDeque};
+use std::iter::zip;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
 use tokenizers::Tokenizer;
+use tracing::warn;
 const EOS_TOKEN: &str = "</s>";
 const SAMPLING_SEED: u64 = 299792458;
 const MIN_GEN_TOKENS: usize = 128;
@@ -82,10 +105,14 @@ enum LLMModel {
     Mistral(Mistral),
     Yi(Yi),
     StableLM(StableLM),
+    LLaVA(LLaVA),
+    T5(T5),
+    Whisper(Whisper),
 }
 /// top-p, multinomial, and argmax sampling are implemented. Beam search is not implemented.
 pub struct DefaultPipeline {
-    model: LLMModel,
+    /// `None` while the weights are released.
+    model: Option<LLMModel>,
     args: SpecificConfig,
     tokenizer: TokenOutputStream,
     logits_processor: LogitsProcessor,
@@ -95,6 +122,19 @@ pub struct DefaultPipeline {
     device: Device,
     config: Config,
     stop_token_ids: Vec<u32>,
+    image_processor: Option<ImageProcessor>,
+    audio_processor: Option<AudioProcessor>,
+    /// Checkpoint the weights are reloaded from after they were released.
+    weight_files: Vec<PathBuf>,
+    lora_adapter: Option<PathBuf>,
+    weight_buffer_mem: usize,
+    /// The shard of the model loaded, with tensor parallelism.
+    parallel: TensorParallel,
+    llava_config: Option<LLaVAConfig>,
+    t5_config: Option<T5Config>,
+    whisper_config: Option<WhisperConfig>,
+    /// Text of each token of the vocabulary, for JSON mode.
+    vocab_texts: OnceLock<Vec<String>>,
 }
 
 pub struct DefaultLoader {
@@ -106,6 +146,7 @@ pub struct DefaultModelPaths<P> {
     pub tokenizer_filename: P,
     pub config_filename: P,
     pub filenames: Vec<P>,
+    pub generation_config_filename: Option<P>,
 }
 
 impl ModelPaths for DefaultModelPaths<PathBuf> {
@@ -118,6 +159,9 @@ impl ModelPaths for DefaultModelPaths<PathBuf> {
     fn get_weight_filenames(&self) -> &Vec<PathBuf> {
         &self.filenames
     }
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        self.generation_config_filename.as_ref()
+    }
 }
 
 impl DefaultLoader {
@@ -126,6 +170,121 @@ impl DefaultLoader {
     }
 }
 
+/// Download only the `config.json` of `model_id` from the hub, e.g. to detect its architecture.
+pub fn download_config(
+    model_id: String,
+    revision: Option<String>,
+    hf_token: Option<String>,
+    hf_token_path: Option<String>,
+) -> Result<PathBuf, APIError> {
+    let api = try_api!(ApiBuilder::new()
+        .with_progress(true)
+        .with_token(Some(get_token(hf_token, hf_token_path)?))
+        .build());
+    let revision = revision.unwrap_or("main".to_string());
+    let api = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
+    Ok(try_api!(api.get("config.json")))
+}
+
+/// The config of a model, with the configs of the parts of multimodal and encoder-decoder models
+/// the pipeline needs.
+pub struct ModelConfigs {
+    pub config: Config,
+    pub llava: Option<LLaVAConfig>,
+    pub t5: Option<T5Config>,
+    pub whisper: Option<WhisperConfig>,
+}
+
+/// Read the `config.json` of the model `name`, whose KV cache is stored in `dtype`.
+pub fn load_config(
+    name: &str,
+    config_filename: &Path,
+    dtype: DType,
+) -> Result<ModelConfigs, APIError> {
+    let mut llava_config = None;
+    let mut t5_config = None;
+    let mut whisper_config = None;
+    let config = match name {
+        "llama" | "llama3" => {
+            let config: LlamaConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "phi2" => {
+            let config: Phi2Config = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            //Phi2 use F32 type for kvcache
+            config.into_config(false, DType::F32)
+        }
+        "phi3" => {
+            let config: PhiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "qwen2" => {
+            let config: QwenConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "gemma" => {
+            let config: GemmaConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "mistral" => {
+            let config: MistralConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "yi" => {
+            let config: YiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            config.into_config(false, dtype)
+        }
+        "stablelm" => {
+            let config: StableLMConfig = try_api!(serde_json::from_slice(&try_api!(
+                std::fs::read(config_filename)
+            ),));
+            config.into_config(false, dtype)
+        }
+        "llava" => {
+            let config: LLaVAConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            llava_config = Some(config.clone());
+            config.into_config(false, dtype)
+        }
+        "t5" => {
+            let config: T5Config = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            t5_config = Some(config.clone());
+            config.into_config(false, dtype)
+        }
+        "whisper" => {
+            let config: WhisperConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+                config_filename
+            )),));
+            whisper_config = Some(config.clone());
+            config.into_config(false, dtype)
+        }
+        _ => return Err(APIError::new(format!("Model {name} not supported!"))),
+    };
+    Ok(ModelConfigs {
+        config,
+        llava: llava_config,
+        t5: t5_config,
+        whisper: whisper_config,
+    })
+}
+
 impl ModelLoader for DefaultLoader {
     fn download_model(
         &self,
@@ -145,6 +304,9 @@ impl ModelLoader for DefaultLoader {
 
         let config_filename = try_api!(api.get("config.json"));
 
+        // Not every checkpoint has one.
+        let generation_config_filename = api.get("generation_config.json").ok();
+
         let mut filenames = vec![];
         for rfilename in try_api!(api.info())
             .siblings
@@ -160,6 +322,7 @@ impl ModelLoader for DefaultLoader {
             tokenizer_filename,
             config_filename,
             filenames,
+            generation_config_filename,
         }))
     }
 
@@ -168,124 +331,131 @@ impl ModelLoader for DefaultLoader {
         paths: Box<dyn ModelPaths>,
         dtype: DType,
         device: Device,
+        weight_buffer_mem: usize,
     ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
-        let specific_args = self.config.clone();
+        let parallel = TensorParallel::default();
+        self.load_pipeline(paths, dtype, device, weight_buffer_mem, parallel, true)
+    }
 
-        let config = match self.name.as_str() {
-            "llama" | "llama3" => {
-                let config: LlamaConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                config.into_config(false, dtype)
-            }
-            "phi2" => {
-                let config: Phi2Config = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                //Phi2 use F32 type for kvcache
-                config.into_config(false, DType::F32)
-            }
-            "phi3" => {
-                let config: PhiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
-                    paths.get_config_filename()
-                )),));
-                config.into_config(false, dtype)
-            }
-            "qwen2" => {
-                let config: QwenConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                config.into_config(false, dtype)
-            }
-            "gemma" => {
-                let config: GemmaConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                config.into_config(false, dtype)
-            }
-            "mistral" => {
-                let config: MistralConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                config.into_config(false, dtype)
-            }
-            "yi" => {
-                let config: YiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
-                    paths.get_config_filename()
-                )),));
-                config.into_config(false, dtype)
-            }
-            "stablelm" => {
-                let config: StableLMConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
-                config.into_config(false, dtype)
-            }
-            _ => panic!("Model not supported!"),
-        };
+    fn load_model_shard(
+        &self,
+        paths: Box<dyn ModelPaths>,
+        dtype: DType,
+        device: Device,
+        weight_buffer_mem: usize,
+        parallel: TensorParallel,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
+        self.load_pipeline(paths, dtype, device, weight_buffer_mem, parallel, true)
+    }
 
-        println!("Model {:?}", config);
+    fn load_model_without_weights(
+        &self,
+        paths: Box<dyn ModelPaths>,
+        dtype: DType,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
+        let parallel = TensorParallel::default();
+        self.load_pipeline(
+            paths,
+            dtype,
+            Device::Cpu,
+            DEFAULT_WEIGHT_BUFFER_MEM,
+            parallel,
+            false,
+        )
+    }
+}
 
-        println!("Loading {} model.", self.name);
+impl DefaultLoader {
+    /// The pipeline of the model, whose weights are only loaded with `load_weights`.
+    fn load_pipeline(
+        &self,
+        paths: Box<dyn ModelPaths>,
+        dtype: DType,
+        device: Device,
+        weight_buffer_mem: usize,
+        parallel: TensorParallel,
+        load_weights: bool,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
+        let specific_args = self.config.clone();
 
-        let vb = match unsafe {
-            VarBuilder::from_mmaped_safetensors(&paths.get_weight_filenames(), dtype, &device)
-        } {
-            Ok(vb_) => vb_,
-            _ => panic!("Load model weights failed!"),
-        };
+        let ModelConfigs {
+            config,
+            llava: llava_config,
+            t5: t5_config,
+            whisper: whisper_config,
+        } = load_config(&self.name, paths.get_config_filename(), dtype)?;
 
-        let (model, sep_style) = match self.name.as_str() {
-            "llama" => (
-                LLMModel::LLAMA(try_api!(Llama::load(vb, &config, dtype, &device))),
-                SeparatorStyle::Llama,
-            ),
-            "llama3" => (
-                LLMModel::LLAMA(try_api!(Llama::load(vb, &config, dtype, &device))),
-                SeparatorStyle::Llama3,
-            ),
-            "phi2" => (
-                LLMModel::Phi2(try_api!(Phi2::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Phi,
-            ),
-            "phi3" => (
-                LLMModel::Phi3(try_api!(Phi::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Phi,
-            ),
-            "qwen2" => (
-                LLMModel::Qwen2(try_api!(Qwen2::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Qwen2,
-            ),
-            "gemma" => (
-                LLMModel::Gemma(try_api!(Gemma::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Gemma,
-            ),
-            "mistral" => (
-                LLMModel::Mistral(try_api!(Mistral::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Mistral,
-            ),
-            "yi" => (
-                LLMModel::Yi(try_api!(Yi::new(vb, &config, dtype, &device))),
-                SeparatorStyle::Yi,
-            ),
-            "stablelm" => (
-                LLMModel::StableLM(try_api!(StableLM::new(vb, &config, dtype, &device))),
-                SeparatorStyle::StableLM,
-            ),
-            _ => panic!("Model not supported!"),
+        println!("Model {:?}", config);
+        check_tensor_parallel(&self.name, &config, parallel.shard.world_size)?;
+        if parallel.shard.world_size > 1 && paths.get_lora_adapter().is_some() {
+            return Err(APIError::new_str(
+                "LoRA adapters cannot be merged into a model sharded with tensor parallelism.",
+            ));
+        }
+
+        let model = if load_weights {
+            println!("Loading {} model.", self.name);
+            if let Ok(Some(checkpoint_dtype)) = get_checkpoint_dtype(paths.get_config_filename()) {
+                if checkpoint_dtype != dtype {
+                    println!("Casting {checkpoint_dtype:?} weights to {dtype:?} while loading.");
+                }
+            }
+            Some(build_model(
+                &self.name,
+                paths.get_weight_filenames(),
+                paths.get_lora_adapter().map(PathBuf::as_path),
+                weight_buffer_mem,
+                &config,
+                llava_config.as_ref(),
+                t5_config.as_ref(),
+                whisper_config.as_ref(),
+                dtype,
+                &device,
+                &parallel,
+            )?)
+        } else {
+            None
         };
 
         let tokenizer_ = Tokenizer::from_file(paths.get_tokenizer_filename())
             .map_err(|x| APIError::new(x.to_string()))?;
 
+        let image_processor = llava_config.as_ref().map(|llava_config| {
+            let image_token = tokenizer_
+                .id_to_token(llava_config.image_token_index)
+                .unwrap_or("<image>".to_string());
+            ImageProcessor::new(
+                llava_config.vision_config.image_size,
+                image_token,
+                llava_config.image_token_index,
+                llava_config.num_image_tokens(),
+            )
+        });
+
+        let audio_processor = whisper_config
+            .as_ref()
+            .map(|whisper_config| AudioProcessor::new(whisper_config.num_mel_bins));
+
         let tokenizer = candle_examples::token_output_stream::TokenOutputStream::new(tokenizer_);
 
         println!("Done loading.");
 
+        let generation_config = match paths.get_generation_config_filename() {
+            Some(path) => GenerationConfig::load(path)?,
+            None => GenerationConfig::default(),
+        };
+        println!("{:?}", generation_config);
+        let max_model_len = generation_config
+            .max_length
+            .map_or(config.max_seq_len, |max_length| {
+                max_length.min(config.max_seq_len)
+            });
+
         //max and min number of tokens generated per request
         let mut default_max_tokens = specific_args
             .max_gen_tokens
-            .unwrap_or(config.max_seq_len / 5);
+            .or(generation_config.max_new_tokens)
+            .unwrap_or(max_model_len / 5);
         if default_max_tokens < MIN_GEN_TOKENS {
             default_max_tokens = MIN_GEN_TOKENS;
         } else if default_max_tokens > MAX_GEN_TOKENS {
@@ -293,11 +463,16 @@ impl ModelLoader for DefaultLoader {
         }
 
         let pipeline_config = PipelineConfig {
-            max_model_len: config.max_seq_len,
+            max_model_len,
             default_max_tokens,
             penalty: specific_args.penalty.unwrap_or(1.),
             repeat_last_n: specific_args.repeat_last_n.unwrap_or(64),
-            temperature: specific_args.temperature.unwrap_or(0.7),
+            temperature: specific_args
+                .temperature
+                .or(generation_config.temperature())
+                .unwrap_or(0.7),
+            top_p: generation_config.top_p.unwrap_or(1.0),
+            top_k: generation_config.top_k.unwrap_or(-1),
         };
 
         println!("{:?}", pipeline_config);
@@ -318,6 +493,13 @@ impl ModelLoader for DefaultLoader {
             }
         }
 
+        // e.g. Llama 3 only lists the end of turn as EOS in its generation config.
+        for eos_token in generation_config.eos_token_ids() {
+            if !stop_token_ids.contains(&eos_token) {
+                stop_token_ids.push(eos_token);
+            }
+        }
+
         if stop_token_ids.len() == 0 {
             //if no eos_token defined in the config, use default
             let eos_token = match tokenizer.get_token("<|endoftext|>") {
@@ -340,17 +522,13 @@ impl ModelLoader for DefaultLoader {
         println!("{:?}", specific_args);
 
         let logits_processor = {
-            let temperature = pipeline_config.temperature as f64;
-            let sampling = if temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (specific_args.top_k, specific_args.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
-            };
+            let top_k = specific_args
+                .top_k
+                .or(pipeline_config.top_k.try_into().ok());
+            let top_p = specific_args
+                .top_p
+                .or(Some(pipeline_config.top_p as f64).filter(|&p| p < 1.0));
+            let sampling = Sampling::new(pipeline_config.temperature as f64, top_k, top_p);
             LogitsProcessor::from_sampling(SAMPLING_SEED, sampling)
         };
 
@@ -365,7 +543,7 @@ impl ModelLoader for DefaultLoader {
                     "[INST] <<SYS>>\n{}\n<</SYS>>\n\n [/INST]".to_string(),
                     Vec::default(),
                     0,
-                    sep_style,
+                    separator_style(&self.name),
                     "".to_string(),
                     stop_token_ids.clone(),
                     ("user".to_string(), "assistant".to_string()),
@@ -379,12 +557,458 @@ impl ModelLoader for DefaultLoader {
                 device: device.clone(),
                 config: config.clone(),
                 stop_token_ids,
+                image_processor,
+                audio_processor,
+                weight_files: paths.get_weight_filenames().clone(),
+                lora_adapter: paths.get_lora_adapter().cloned(),
+                weight_buffer_mem,
+                parallel,
+                llava_config,
+                t5_config,
+                whisper_config,
+                vocab_texts: OnceLock::new(),
             }),
             pipeline_config,
         ))
     }
 }
 
+/// Load the weights of the model `name` from the safetensors `weight_files`, merged with the LoRA
+/// adapter of the directory `lora_adapter` if any, and build it. Only the shard of `parallel` is
+/// loaded with tensor parallelism.
+#[allow(clippy::too_many_arguments)]
+fn build_model(
+    name: &str,
+    weight_files: &[PathBuf],
+    lora_adapter: Option<&Path>,
+    weight_buffer_mem: usize,
+    config: &Config,
+    llava_config: Option<&LLaVAConfig>,
+    t5_config: Option<&T5Config>,
+    whisper_config: Option<&WhisperConfig>,
+    dtype: DType,
+    device: &Device,
+    parallel: &TensorParallel,
+) -> Result<LLMModel, APIError> {
+    let mut tensors = try_api!(load_safetensors_shard(
+        weight_files,
+        dtype,
+        device,
+        weight_buffer_mem * SIZE_IN_MB,
+        parallel.shard,
+    ));
+    if let Some(adapter) = lora_adapter {
+        let merged = merge_lora(
+            &mut tensors,
+            adapter,
+            device,
+            weight_buffer_mem * SIZE_IN_MB,
+        )?;
+        println!(
+            "Merged {merged} weights of the LoRA adapter {}.",
+            adapter.display()
+        );
+    }
+    let vb = VarBuilder::from_tensors(tensors, dtype, device);
+
+    Ok(match name {
+        "llama" | "llama3" => LLMModel::LLAMA(try_api!(Llama::load_shard(
+            vb, config, dtype, device, parallel
+        ))),
+        "phi2" => LLMModel::Phi2(try_api!(Phi2::new(vb, config, dtype, device))),
+        "phi3" => LLMModel::Phi3(try_api!(Phi::new(vb, config, dtype, device))),
+        "qwen2" => LLMModel::Qwen2(try_api!(Qwen2::new(vb, config, dtype, device))),
+        "gemma" => LLMModel::Gemma(try_api!(Gemma::new(vb, config, dtype, device))),
+        "mistral" => LLMModel::Mistral(try_api!(Mistral::new(vb, config, dtype, device))),
+        "yi" => LLMModel::Yi(try_api!(Yi::new(vb, config, dtype, device))),
+        "stablelm" => LLMModel::StableLM(try_api!(StableLM::new(vb, config, dtype, device))),
+        "llava" => LLMModel::LLaVA(try_api!(LLaVA::load(
+            vb,
+            llava_config.unwrap(),
+            config,
+            dtype,
+            device
+        ))),
+        "t5" => LLMModel::T5(try_api!(T5::new(
+            vb,
+            t5_config.unwrap(),
+            config,
+            dtype,
+            device
+        ))),
+        "whisper" => LLMModel::Whisper(try_api!(Whisper::new(
+            vb,
+            whisper_config.unwrap(),
+            config,
+            dtype,
+            device
+        ))),
+        _ => panic!("Model not supported!"),
+    })
+}
+
+/// Chat template of the model `name`.
+fn separator_style(name: &str) -> SeparatorStyle {
+    match name {
+        "llama" => SeparatorStyle::Llama,
+        "llama3" => SeparatorStyle::Llama3,
+        "phi2" | "phi3" => SeparatorStyle::Phi,
+        "qwen2" => SeparatorStyle::Qwen2,
+        "gemma" => SeparatorStyle::Gemma,
+        "mistral" => SeparatorStyle::Mistral,
+        "yi" => SeparatorStyle::Yi,
+        "stablelm" => SeparatorStyle::StableLM,
+        "llava" => SeparatorStyle::AddColonTwo,
+        "t5" | "whisper" => SeparatorStyle::AddColonSingle,
+        _ => panic!("Model not supported!"),
+    }
+}
+
+impl DefaultPipeline {
+    /// Sample the token following `tokens`, the prompt of `prompt_len` tokens and the tokens
+    /// generated so far by a seq of `group`, from the logits of the last one.
+    fn sample_next(
+        &self,
+        group: &SequenceGroup,
+        tokens: &[u32],
+        prompt_len: usize,
+        logits: Tensor,
+    ) -> TokenOrFinishReason {
+        let sampling_params = &group.sampling_params;
+        let tokens_generated = tokens.len() - prompt_len;
+
+        if tokens_generated > sampling_params.max_tokens {
+            return Right("length".to_string());
+        }
+
+        let logits = if sampling_params.repetition_penalty == 1.
+            || self.args.repeat_last_n.unwrap_or(64) >= tokens_generated
+        {
+            logits
+        } else {
+            let start_at = tokens
+                .len()
+                .saturating_sub(self.args.repeat_last_n.unwrap_or(64));
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                sampling_params.repetition_penalty,
+                &tokens[start_at..],
+            )
+            .unwrap_or(logits)
+        };
+
+        // After token healing, the first token has to extend the token removed from
+        // the prompt, whose text it then repeats.
+        let healing = group
+            .token_healing
+            .as_ref()
+            .filter(|_| tokens_generated == 0);
+        let logits = match healing {
+            Some(healing) => mask_logits(&logits, &healing.allowed_token_ids).unwrap_or(logits),
+            None => logits,
+        };
+
+        // Neither EOS nor the stop tokens of the request end a seq before it has
+        // `min_tokens`.
+        let stop_token_ids = self.stop_token_ids(sampling_params);
+        let below_min_tokens = tokens_generated < sampling_params.min_tokens;
+        let logits = if below_min_tokens && group.guided_choice.is_none() {
+            suppress_logits(&logits, &stop_token_ids).unwrap_or(logits)
+        } else {
+            logits
+        };
+
+        // With guided choice, only tokens continuing a choice are sampled, and stop
+        // tokens once a whole choice was generated.
+        let mut choice_complete = false;
+        let logits = match &group.guided_choice {
+            Some(choices) => {
+                let generated = &tokens[prompt_len..];
+                let mut allowed = choices.next_tokens(generated);
+                choice_complete = choices.is_choice(generated);
+                if choice_complete {
+                    if allowed.is_empty() {
+                        return Right("stop".to_string());
+                    }
+                    if !below_min_tokens {
+                        allowed.extend(&stop_token_ids);
+                    }
+                }
+                mask_logits(&logits, &allowed).unwrap_or(logits)
+            }
+            None => logits,
+        };
+
+        // In JSON mode, only tokens keeping the output the prefix of a JSON object are sampled,
+        // and the output ends with the object.
+        let logits = if sampling_params.json_mode {
+            let vocab = self.vocab_texts();
+            let mut state = JsonState::default();
+            for &token in &tokens[prompt_len..] {
+                state.advance(vocab.get(token as usize).map_or("", String::as_str));
+            }
+            if state.is_complete() {
+                return Right("stop".to_string());
+            }
+            let mut allowed = state.allowed_tokens(vocab);
+            allowed.retain(|token| !stop_token_ids.contains(token));
+            if allowed.is_empty() {
+                return Right("stop".to_string());
+            }
+            mask_logits(&logits, &allowed).unwrap_or(logits)
+        } else {
+            logits
+        };
+
+        // The logits processors of the request come last, over the constrained logits.
+        let context = LogitsContext {
+            request_id: &group.request_id,
+            prompt: &tokens[..prompt_len],
+            generated: &tokens[prompt_len..],
+        };
+        let logits = group
+            .logits_processors
+            .iter()
+            .fold(logits, |logits, processor| {
+                processor.process(&context, &logits).unwrap_or_else(|e| {
+                    warn!(request_id = %group.request_id, "logits processor failed: {e}");
+                    logits
+                })
+            });
+
+        // A scheduled request samples by the stage its seq reached, with the generator of the
+        // server, and a greedy one takes the most likely token.
+        let next_token = match &sampling_params.sampling_schedule {
+            None if sampling_params.is_greedy() => self
+                .logits_processor
+                .sample_with(&logits, &Sampling::ArgMax),
+            Some(schedule) => {
+                let (temperature, top_p) = schedule.at(
+                    tokens_generated,
+                    sampling_params.temperature,
+                    sampling_params.top_p,
+                );
+                let sampling = Sampling::new(
+                    temperature as f64,
+                    usize::try_from(sampling_params.top_k)
+                        .ok()
+                        .filter(|&k| k > 0),
+                    Some(top_p as f64).filter(|&p| p < 1.0),
+                );
+                self.logits_processor.sample_with(&logits, &sampling)
+            }
+            None => self.logits_processor.sample(&logits),
+        }
+        .unwrap();
+        let mut text = self.token_text(next_token);
+        if let Some(healing) = healing {
+            if let Some(generated) = text.strip_prefix(&self.token_text(healing.token)) {
+                text = generated.to_string();
+            }
+        }
+        if stop_token_ids.contains(&next_token)
+            && !below_min_tokens
+            && (tokens_generated > 1 || choice_complete)
+        {
+            return Right("stop".to_string());
+        }
+        // Logprobs are the ones of the logits the token was sampled from, at a temperature of 1.
+        let (logprob, top_logprobs) = match sampling_params.logprobs {
+            Some(top_n) => match token_logprobs(&logits, next_token, top_n) {
+                Ok((logprob, top)) => (
+                    logprob,
+                    top.into_iter()
+                        .map(|(token, logprob)| TopLogprob {
+                            token: token as usize,
+                            logprob,
+                            bytes: self.token_text(token),
+                        })
+                        .collect(),
+                ),
+                Err(e) => {
+                    warn!(request_id = %group.request_id, "failed to compute logprobs: {e}");
+                    (0.0, Vec::new())
+                }
+            },
+            None => (0.0, Vec::new()),
+        };
+        Left(Logprobs {
+            token: next_token as usize,
+            logprob,
+            top_logprobs,
+            bytes: text,
+        })
+    }
+
+    /// The token `next_token` taken or sampled on the device for a seq of `group` which
+    /// generated `tokens_generated` tokens, or why the seq finishes instead, as `sample_next`
+    /// would have sampled it from logits which needed no processing on the host.
+    fn greedy_next(
+        &self,
+        group: &SequenceGroup,
+        tokens_generated: usize,
+        next_token: u32,
+    ) -> TokenOrFinishReason {
+        let sampling_params = &group.sampling_params;
+        if tokens_generated > sampling_params.max_tokens {
+            return Right("length".to_string());
+        }
+        if tokens_generated > 1 && self.stop_token_ids(sampling_params).contains(&next_token) {
+            return Right("stop".to_string());
+        }
+        Left(Logprobs {
+            token: next_token as usize,
+            logprob: 0.0,
+            top_logprobs: Vec::<TopLogprob>::new(),
+            bytes: self.token_text(next_token),
+        })
+    }
+
+    /// Whether the logits of a seq of `group` which generated `tokens_generated` tokens have to
+    /// be processed on the host before `num_tokens` more are sampled, for its repeat penalty, its
+    /// `min_tokens`, its guided choice, JSON mode or logits processors, its sampling schedule,
+    /// token healing or logprobs.
+    fn processed_on_host(
+        &self,
+        group: &SequenceGroup,
+        tokens_generated: usize,
+        num_tokens: usize,
+    ) -> bool {
+        let sampling_params = &group.sampling_params;
+        (sampling_params.repetition_penalty != 1.
+            && self.args.repeat_last_n.unwrap_or(64) < tokens_generated + num_tokens)
+            || tokens_generated < sampling_params.min_tokens
+            || group.guided_choice.is_some()
+            || sampling_params.json_mode
+            || !group.logits_processors.is_empty()
+            || sampling_params.sampling_schedule.is_some()
+            || (group.token_healing.is_some() && tokens_generated == 0)
+            || sampling_params.logprobs.is_some()
+            || group.logit_dump.is_some()
+    }
+
+    /// Text of each token as it is streamed, computed the first time it is needed. Special
+    /// tokens have none, they are never part of a JSON object.
+    fn vocab_texts(&self) -> &[String] {
+        self.vocab_texts.get_or_init(|| {
+            let tokenizer = self.tokenizer.tokenizer();
+            let special = tokenizer.get_added_tokens_decoder();
+            (0..tokenizer.get_vocab_size(true) as u32)
+                .map(|token| match special.get(&token) {
+                    Some(added) if added.special => String::new(),
+                    _ => self.token_text(token),
+                })
+                .collect()
+        })
+    }
+
+    /// EOS and the stop tokens of a request.
+    fn stop_token_ids(&self, sampling_params: &SamplingParams) -> Vec<u32> {
+        self.stop_token_ids
+            .iter()
+            .copied()
+            .chain(sampling_params.stop_token_ids.iter().map(|&id| id as u32))
+            .collect()
+    }
+
+    /// Verify all the proposals of the batch on the device with the rejection sampler, which
+    /// only sends the accepted tokens back. `None` if the logits of a seq have to be processed on
+    /// the host first (see `processed_on_host`), for top-k and top-p sampling, or if a proposal
+    /// is a tree.
+    fn verify_on_device(
+        &self,
+        logits: &Tensor,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+        proposals: &[DraftTree],
+    ) -> Result<Option<Vec<Vec<TokenOrFinishReason>>>, APIError> {
+        if proposals.iter().any(|proposal| !proposal.is_chain()) {
+            return Ok(None);
+        }
+        let temperature = match self.logits_processor.sampling() {
+            Sampling::ArgMax => None,
+            Sampling::All { temperature } => Some(*temperature),
+            _ => return Ok(None),
+        };
+        let seqs = groups
+            .iter()
+            .flat_map(|group| {
+                group.get_unfinished_choices().map(move |(_, seq)| {
+                    let seq = seq.deref();
+                    (group, seq.get_len() - seq.get_prompt_len())
+                })
+            })
+            .zip(proposals)
+            .collect::<Vec<_>>();
+        let on_host = seqs.iter().any(|((group, tokens_generated), proposed)| {
+            self.processed_on_host(group, *tokens_generated, proposed.len())
+        });
+        if on_host {
+            return Ok(None);
+        }
+
+        let logits = try_api!(logits.to_dtype(DType::F32));
+        let probs = match temperature {
+            Some(temperature) => {
+                try_api!(candle_nn::ops::softmax_last_dim(&try_api!(
+                    &logits / temperature
+                )))
+            }
+            None => logits,
+        };
+        // Greedy verification draws nothing, as greedy sampling does.
+        let num_draws = 2 * try_api!(probs.dim(0));
+        let uniform = match temperature {
+            Some(_) => self.logits_processor.uniform(num_draws),
+            None => vec![0.; num_draws],
+        };
+        let chains = proposals
+            .iter()
+            .map(|proposal| proposal.tokens().to_vec())
+            .collect::<Vec<_>>();
+        let accepted = try_api!(rejection_sample(
+            &probs,
+            &chains,
+            &uniform,
+            temperature.is_none()
+        ));
+
+        // The finish reasons are the ones `sample_next` gives with these tokens.
+        Ok(Some(
+            zip(seqs, accepted)
+                .map(|(((group, generated_before), _), tokens)| {
+                    let sampling_params = &group.sampling_params;
+                    let stop_token_ids = self.stop_token_ids(sampling_params);
+                    let mut results = Vec::new();
+                    for (i, token) in tokens.into_iter().enumerate() {
+                        let tokens_generated = generated_before + i;
+                        if tokens_generated > sampling_params.max_tokens {
+                            results.push(Right("length".to_string()));
+                            break;
+                        }
+                        if stop_token_ids.contains(&token) && tokens_generated > 1 {
+                            results.push(Right("stop".to_string()));
+                            break;
+                        }
+                        results.push(Left(Logprobs {
+                            token: token as usize,
+                            logprob: 0.0,
+                            top_logprobs: Vec::<TopLogprob>::new(),
+                            bytes: self.token_text(token),
+                        }));
+                    }
+                    results
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Logits of the `row`th token of a batch.
+fn logits_row(logits: &Tensor, row: usize) -> Tensor {
+    let logits = logits.i((row, ..)).unwrap().contiguous();
+    logits.unwrap().squeeze(0).unwrap()
+}
+
 impl ModulePipeline for DefaultPipeline {
     fn forward(
         &mut self,
@@ -392,6 +1016,7 @@ impl ModulePipeline for DefaultPipeline {
         input_positions: &Vec<Vec<usize>>,
         kv_cache: Option<&Vec<(Tensor, Tensor)>>,
         mut input_metadata: InputMetadata,
+        image_features: &[Option<Tensor>],
     ) -> Result<Tensor, APIError> {
         let input_tokens = if input_tokens.shape().dims().len() < 2 {
             input_tokens
@@ -401,7 +1026,12 @@ impl ModulePipeline for DefaultPipeline {
             input_tokens
         };
 
-        let ret = match &mut self.model {
+        let Some(model) = &mut self.model else {
+            return Err(APIError::new_str(
+                "The weights of the model are released, wake the engine first.",
+            ));
+        };
+        let ret = match model {
             LLMModel::LLAMA(llama) => llama
                 .forward(
                     &input_tokens,
@@ -466,6 +1096,27 @@ impl ModulePipeline for DefaultPipeline {
                     &mut input_metadata,
                 )
                 .map_err(APIError::from),
+            LLMModel::LLaVA(llava) => llava
+                .forward(
+                    &input_tokens,
+                    input_positions,
+                    kv_cache,
+                    &mut input_metadata,
+                    image_features,
+                )
+                .map_err(APIError::from),
+            LLMModel::T5(t5) => t5
+                .forward(&input_tokens, kv_cache, &input_metadata)
+                .map_err(APIError::from),
+            LLMModel::Whisper(whisper) => whisper
+                .forward(
+                    &input_tokens,
+                    input_positions,
+                    kv_cache,
+                    &mut input_metadata,
+                    image_features,
+                )
+                .map_err(APIError::from),
         };
 
         return ret;
@@ -476,95 +1127,157 @@ impl ModulePipeline for DefaultPipeline {
         logits: Tensor,
         groups: &VecDeque<Arc<SequenceGroup>>,
     ) -> Result<Vec<TokenOrFinishReason>, APIError> {
-        use std::collections::HashMap;
-        use std::sync::Mutex;
-        let shared_result = Arc::new(Mutex::new(HashMap::<usize, TokenOrFinishReason>::new()));
-        let shared_group_idx = Arc::new(Mutex::new(0));
-        groups.par_iter().for_each(|group| {
-            let mut group_idx = 0;
-            {
-                let mut groupidx = shared_group_idx.lock().unwrap();
-                group_idx = *groupidx;
-                *groupidx += 1;
-            }
-
-            let sampling_params = &group.sampling_params;
-            for (_, seq) in group.get_seqs() {
-                let logits = logits.i((group_idx, ..)).unwrap().contiguous();
-                let logits = logits.unwrap().squeeze(0).unwrap();
-                let sq = seq.deref_mut();
+        let seqs = groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .get_unfinished_choices()
+                    .map(move |(choice, seq)| (group, choice, seq))
+            })
+            .collect::<Vec<_>>();
+        // The greedy seqs whose logits need no processing on the host take their token with a
+        // single argmax over their rows, only the tokens are sent back.
+        let greedy_rows = seqs
+            .iter()
+            .enumerate()
+            .filter(|(_, (group, _, seq))| {
+                let seq = seq.deref();
+                group.sampling_params.is_greedy()
+                    && !self.processed_on_host(group, seq.get_len() - seq.get_prompt_len(), 0)
+            })
+            .map(|(row, _)| row as u32)
+            .collect::<Vec<_>>();
+        let mut device_tokens = HashMap::new();
+        if !greedy_rows.is_empty() {
+            let rows = try_api!(Tensor::new(greedy_rows.as_slice(), logits.device()));
+            let logits = try_api!(logits.index_select(&rows, 0));
+            let tokens = try_api!(try_api!(logits.argmax(D::Minus1)).flatten_all());
+            let tokens = try_api!(tokens.to_vec1::<u32>());
+            device_tokens.extend(greedy_rows.into_iter().zip(tokens));
+        }
+        // The other ones sample with the sampling of the server in a single launch of the
+        // sampling kernel, rather than moving their whole rows to the host.
+        let (temperature, top_k, top_p) = match *self.logits_processor.sampling() {
+            Sampling::ArgMax => (0., None, None),
+            Sampling::All { temperature } => (temperature, None, None),
+            Sampling::TopK { k, temperature } => (temperature, Some(k), None),
+            Sampling::TopP { p, temperature } => (temperature, None, Some(p as f32)),
+            Sampling::TopKThenTopP { k, p, temperature } => (temperature, Some(k), Some(p as f32)),
+        };
+        let sampled_rows = seqs
+            .iter()
+            .enumerate()
+            .filter(|(_, (group, _, seq))| {
+                let seq = seq.deref();
+                temperature > 0.
+                    && !group.sampling_params.is_greedy()
+                    && !self.processed_on_host(group, seq.get_len() - seq.get_prompt_len(), 0)
+            })
+            .map(|(row, _)| row as u32)
+            .collect::<Vec<_>>();
+        if !sampled_rows.is_empty() {
+            let rows = try_api!(Tensor::new(sampled_rows.as_slice(), logits.device()));
+            let logits = try_api!(logits.index_select(&rows, 0));
+            let uniform = self.logits_processor.uniform(sampled_rows.len());
+            let tokens = try_api!(sample_tokens(
+                &logits,
+                temperature as f32,
+                top_k,
+                top_p,
+                &uniform
+            ));
+            device_tokens.extend(sampled_rows.into_iter().zip(tokens));
+        }
+        let result = seqs
+            .par_iter()
+            .enumerate()
+            .map(|(row, (group, choice, seq))| {
+                let sq = seq.deref();
+                if let Some(&token) = device_tokens.get(&(row as u32)) {
+                    return self.greedy_next(group, sq.get_len() - sq.get_prompt_len(), token);
+                }
                 let tokens = sq
                     .get_token_ids()
                     .iter()
                     .map(|x| *x as u32)
                     .collect::<Vec<_>>();
-                let tokens_generated = sq.get_len() - sq.get_prompt_len();
-
-                if tokens_generated > sampling_params.max_tokens {
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Right("length".to_string()));
-                    break;
+                let logits = logits_row(&logits, row);
+                let next = self.sample_next(group, &tokens, sq.get_prompt_len(), logits.clone());
+                // The logits of the model, before the penalties, constraints and processors of
+                // the request.
+                if let (Some(dump), Left(next)) = (&group.logit_dump, &next) {
+                    let position = sq.get_len() - sq.get_prompt_len();
+                    if let Err(e) = dump.write(*choice, position, next.token as u32, &logits) {
+                        warn!(request_id = %group.request_id, "failed to dump the logits: {e}");
+                    }
                 }
+                next
+            })
+            .collect::<Vec<TokenOrFinishReason>>();
 
-                let logits = if sampling_params.repetition_penalty == 1.
-                    || self.args.repeat_last_n.unwrap_or(64) >= tokens_generated
-                {
-                    logits
-                } else {
-                    let start_at = tokens
-                        .len()
-                        .saturating_sub(self.args.repeat_last_n.unwrap_or(64));
-                    candle_transformers::utils::apply_repeat_penalty(
-                        &logits,
-                        sampling_params.repetition_penalty,
-                        &tokens[start_at..],
-                    )
-                    .unwrap_or(logits)
-                };
+        Ok(result)
+    }
 
-                let next_token = self.logits_processor.sample(&logits).unwrap();
-                let mut text = self
-                    .tokenizer
-                    .tokenizer()
-                    .decode(&[next_token], false)
-                    .unwrap_or(" ".to_string());
-                let origin_text = self
-                    .tokenizer
-                    .tokenizer()
-                    .id_to_token(next_token)
-                    .unwrap_or("".to_string());
-                //properly handle space token
-                if origin_text.contains("▁") && origin_text.replace("▁", "") == text {
-                    text = origin_text.replace("▁", " ");
-                }
-                if self.stop_token_ids.contains(&next_token) && tokens_generated > 1 {
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Right("stop".to_string()));
-                    break;
-                }
-                {
-                    let logprob = Logprobs {
-                        token: next_token as usize,
-                        logprob: 0.0,
-                        top_logprobs: Vec::<TopLogprob>::new(),
-                        bytes: text,
+    fn verify_proposals(
+        &mut self,
+        logits: Tensor,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+        proposals: &[DraftTree],
+    ) -> Result<Vec<Vec<TokenOrFinishReason>>, APIError> {
+        if let Some(results) = self.verify_on_device(&logits, groups, proposals)? {
+            return Ok(results);
+        }
+        let seqs = groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .get_unfinished_choices()
+                    .map(move |(_, seq)| (group, seq))
+            })
+            .zip(proposals)
+            .scan(0, |first_row, (seq, proposed)| {
+                let row = *first_row;
+                *first_row += proposed.len() + 1;
+                Some((row, seq, proposed))
+            })
+            .collect::<Vec<_>>();
+        let result = seqs
+            .par_iter()
+            .map(|(first_row, (group, seq), proposed)| {
+                let sq = seq.deref();
+                let mut tokens = sq
+                    .get_token_ids()
+                    .iter()
+                    .map(|x| *x as u32)
+                    .collect::<Vec<_>>();
+                let prompt_len = sq.get_prompt_len();
+                drop(sq);
+                let mut results = Vec::new();
+                // The rows of a sequence are the nodes of its tree, the root first.
+                let mut node = 0;
+                loop {
+                    let result = self.sample_next(
+                        group,
+                        &tokens,
+                        prompt_len,
+                        logits_row(&logits, first_row + node),
+                    );
+                    let accepted = match &result {
+                        Left(logprobs) => {
+                            tokens.push(logprobs.token as u32);
+                            proposed.child(node, logprobs.token as u32)
+                        }
+                        Right(_) => None,
                     };
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Left(logprob));
+                    results.push(result);
+                    match accepted {
+                        Some(child) => node = child,
+                        None => break,
+                    }
                 }
-            }
-        });
-
-        let final_result = Arc::try_unwrap(shared_result)
-            .expect("Arc should have only one reference left")
-            .into_inner()
-            .expect("Mutex should not be poisoned");
-
-        let mut sorted_vec: Vec<_> = final_result.into_iter().collect();
-        sorted_vec.sort_by_key(|&(key, _)| key);
-
-        let result: Vec<TokenOrFinishReason> =
-            sorted_vec.into_iter().map(|(_, value)| value).collect();
+                results
+            })
+            .collect::<Vec<_>>();
 
         Ok(result)
     }
@@ -585,7 +1298,10 @@ impl ModulePipeline for DefaultPipeline {
     }
 
     fn get_model_config(&self) -> Config {
-        match &self.model {
+        let Some(model) = &self.model else {
+            return self.config.clone();
+        };
+        match model {
             LLMModel::LLAMA(llama) => llama.get_config().clone(),
             LLMModel::Phi2(phi) => phi.get_config().clone(),
             LLMModel::Phi3(phi) => phi.get_config().clone(),
@@ -594,7 +1310,87 @@ impl ModulePipeline for DefaultPipeline {
             LLMModel::Mistral(mistral) => mistral.get_config().clone(),
             LLMModel::Yi(yi) => yi.get_config().clone(),
             LLMModel::StableLM(stablelm) => stablelm.get_config().clone(),
+            LLMModel::LLaVA(llava) => llava.get_config().clone(),
+            LLMModel::T5(t5) => t5.get_config().clone(),
+            LLMModel::Whisper(whisper) => whisper.get_config().clone(),
+        }
+    }
+
+    fn image_processor(&self) -> Option<&ImageProcessor> {
+        self.image_processor.as_ref()
+    }
+
+    fn audio_processor(&self) -> Option<&AudioProcessor> {
+        self.audio_processor.as_ref()
+    }
+
+    fn encode_images(&self, pixel_values: &Tensor) -> Result<Tensor, APIError> {
+        match &self.model {
+            Some(LLMModel::LLaVA(llava)) => {
+                llava.encode_images(pixel_values).map_err(APIError::from)
+            }
+            Some(LLMModel::Whisper(whisper)) => {
+                whisper.encode(pixel_values).map_err(APIError::from)
+            }
+            _ => Err(APIError::new(format!(
+                "Model `{}` does not accept image inputs.",
+                self.name
+            ))),
+        }
+    }
+
+    fn is_encoder_decoder(&self) -> bool {
+        self.t5_config.is_some()
+    }
+
+    fn free_sequences(&mut self, seq_ids: &[usize]) {
+        match &mut self.model {
+            Some(LLMModel::T5(t5)) => t5.free_sequences(seq_ids),
+            Some(LLMModel::Whisper(whisper)) => whisper.free_sequences(seq_ids),
+            _ => {}
+        }
+    }
+
+    fn release_weights(&mut self) {
+        self.model = None;
+    }
+
+    fn reload_weights(&mut self) -> Result<(), APIError> {
+        if self.model.is_none() {
+            let model = build_model(
+                &self.name,
+                &self.weight_files,
+                self.lora_adapter.as_deref(),
+                self.weight_buffer_mem,
+                &self.config,
+                self.llava_config.as_ref(),
+                self.t5_config.as_ref(),
+                self.whisper_config.as_ref(),
+                self.dtype,
+                &self.device,
+                &self.parallel,
+            )?;
+            self.model = Some(model);
+        }
+        Ok(())
+    }
+
+    fn token_text(&self, token: u32) -> String {
+        let mut text = self
+            .tokenizer
+            .tokenizer()
+            .decode(&[token], false)
+            .unwrap_or(" ".to_string());
+        let origin_text = self
+            .tokenizer
+            .tokenizer()
+            .id_to_token(token)
+            .unwrap_or("".to_string());
+        //properly handle space token
+        if origin_text.contains("▁") && origin_text.replace("▁", "") == text {
+            text = origin_text.replace("▁", " ");
         }
+        text
     }
 
     fn get_dtype(&self) -> DType {
@@ -605,12 +1401,13 @@ impl ModulePipeline for DefaultPipeline {
         &self.device
     }
 
+    fn shard(&self) -> Shard {
+        self.parallel.shard
+    }
+
     fn reset_decoder(&mut self) -> Option<String> {
         let ret = self.tokenizer.decode_rest().unwrap_or(None);
         self.tokenizer.clear();
         ret
     }
 }
-
-unsafe impl Send for DefaultPipeline {}
-unsafe impl Sync for DefaultPipeline {}
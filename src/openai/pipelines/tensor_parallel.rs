@@ -0,0 +1,362 @@
+//! Tensor parallelism: a model sharded over the GPUs of its worker processes, on one or several
+//! nodes, for models larger than a GPU or a machine. Each rank holds the weights of its share of
+//! the attention heads and of the MLP, as in Megatron-LM: the projections into the heads and the
+//! MLP are split along their outputs (column parallel), the projections out of them along their
+//! inputs (row parallel), and the partial outputs of the latter are summed over the ranks with
+//! an NCCL all-reduce. Embeddings, norms and the LM head are replicated, so that every rank
+//! computes the logits. When all the ranks are GPUs of one node with peer access, the small
+//! partial outputs of decode steps are summed by the custom all-reduce of `backend` instead,
+//! which costs a fraction of the latency of NCCL.
+//!
+//! The ranks join the NCCL communicator with the id the engine sends them over their connection
+//! to it, see `worker_process`.
+use std::ops::Range;
+#[cfg(feature = "nccl")]
+use std::sync::{Arc, Mutex};
+
+use candle_core::{Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::openai::{models::Config, responses::APIError};
+
+/// Models whose weights can be sharded.
+pub const TENSOR_PARALLEL_MODELS: [&str; 2] = ["llama", "llama3"];
+
+/// Projections split along their outputs, the rows of their weights.
+const COLUMN_PARALLEL: [&str; 5] = ["q_proj", "k_proj", "v_proj", "gate_proj", "up_proj"];
+/// Projections split along their inputs, the columns of their weights.
+const ROW_PARALLEL: [&str; 2] = ["o_proj", "down_proj"];
+
+/// The rank of a worker among the `world_size` ones the model is sharded over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub rank: usize,
+    pub world_size: usize,
+}
+
+impl Default for Shard {
+    /// The whole model.
+    fn default() -> Self {
+        Self {
+            rank: 0,
+            world_size: 1,
+        }
+    }
+}
+
+impl Shard {
+    /// Dimension the weight `name` is split along, `None` for replicated weights.
+    pub fn dim(&self, name: &str) -> Option<usize> {
+        if self.world_size == 1 {
+            return None;
+        }
+        let module = name.strip_suffix(".weight")?.rsplit('.').next()?;
+        if COLUMN_PARALLEL.contains(&module) {
+            Some(0)
+        } else if ROW_PARALLEL.contains(&module) {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    /// The part of a dimension of `size` held by the rank.
+    pub fn range(&self, size: usize) -> Range<usize> {
+        let len = size / self.world_size;
+        self.rank * len..(self.rank + 1) * len
+    }
+
+    /// The part of `config` the rank holds, for the layout of its KV cache: its attention and KV
+    /// heads, of the head size of the model.
+    pub fn local_config(&self, config: &Config) -> Config {
+        Config {
+            hidden_size: config.hidden_size / self.world_size,
+            intermediate_size: config.intermediate_size / self.world_size,
+            num_attention_heads: config.num_attention_heads / self.world_size,
+            num_key_value_heads: config.num_key_value_heads / self.world_size,
+            ..config.clone()
+        }
+    }
+}
+
+/// Fails unless the model `name` of `config` can be sharded over `world_size` ranks: heads,
+/// KV heads and MLP have to be split evenly.
+pub fn check_tensor_parallel(
+    name: &str,
+    config: &Config,
+    world_size: usize,
+) -> std::result::Result<(), APIError> {
+    if world_size == 1 {
+        return Ok(());
+    }
+    if !TENSOR_PARALLEL_MODELS.contains(&name) {
+        return Err(APIError::new(format!(
+            "Tensor parallelism is not supported for {name} models, only for {}.",
+            TENSOR_PARALLEL_MODELS.join(", ")
+        )));
+    }
+    for (what, size) in [
+        ("attention heads", config.num_attention_heads),
+        ("KV heads", config.num_key_value_heads),
+        ("intermediate size", config.intermediate_size),
+    ] {
+        if size % world_size != 0 {
+            return Err(APIError::new(format!(
+                "The {size} {what} of the model cannot be split over {world_size} GPUs."
+            )));
+        }
+    }
+    if !cfg!(feature = "nccl") {
+        return Err(APIError::new_str(
+            "Tensor parallelism needs a build with the nccl feature.",
+        ));
+    }
+    Ok(())
+}
+
+/// The shard of the model a worker runs, and the communicator summing the partial outputs of
+/// the ranks.
+#[derive(Clone, Default)]
+pub struct TensorParallel {
+    pub shard: Shard,
+    #[cfg(feature = "nccl")]
+    comm: Option<Arc<Mutex<nccl::NcclComm>>>,
+    #[cfg(feature = "nccl")]
+    custom: Option<Arc<Mutex<crate::backend::CustomAllReduce>>>,
+}
+
+impl std::fmt::Debug for TensorParallel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TensorParallel")
+            .field("shard", &self.shard)
+            .finish()
+    }
+}
+
+impl TensorParallel {
+    /// Join the communicator of id `nccl_id` as the rank of `shard`, on the GPU `device`, and
+    /// map the buffers of the custom all-reduce of the other ranks if they can be. Waits for
+    /// every rank to join.
+    #[cfg(feature = "nccl")]
+    pub fn new(
+        shard: Shard,
+        nccl_id: &[u8],
+        device: &candle_core::Device,
+    ) -> std::result::Result<Self, APIError> {
+        let comm = nccl::NcclComm::new(shard, nccl_id, device)?;
+        let custom = comm.custom_all_reduce(shard, device)?;
+        Ok(Self {
+            shard,
+            comm: Some(Arc::new(Mutex::new(comm))),
+            custom: custom.map(|custom| Arc::new(Mutex::new(custom))),
+        })
+    }
+
+    #[cfg(not(feature = "nccl"))]
+    pub fn new(
+        _shard: Shard,
+        _nccl_id: &[u8],
+        _device: &candle_core::Device,
+    ) -> std::result::Result<Self, APIError> {
+        Err(APIError::new_str(
+            "Tensor parallelism needs a build with the nccl feature.",
+        ))
+    }
+
+    /// Sum of `x` over the ranks, `x` itself on a single rank. Messages up to
+    /// `CUSTOM_ALL_REDUCE_MAX_SIZE` take the custom all-reduce when the ranks have it.
+    pub fn all_reduce(&self, x: &Tensor) -> Result<Tensor> {
+        if self.shard.world_size == 1 {
+            return Ok(x.clone());
+        }
+        #[cfg(feature = "nccl")]
+        if let Some(comm) = &self.comm {
+            if let Some(custom) = &self.custom {
+                let Ok(mut custom) = custom.lock() else {
+                    candle_core::bail!("a custom all-reduce of the rank panicked")
+                };
+                if let Some(sum) = custom.all_reduce(x)? {
+                    return Ok(sum);
+                }
+            }
+            return x
+                .contiguous()?
+                .apply_op1_no_bwd(&nccl::AllReduce { comm: comm.clone() });
+        }
+        candle_core::bail!("the ranks of the model are not connected")
+    }
+}
+
+/// A new NCCL id, for the engine to send to the ranks.
+#[cfg(feature = "nccl")]
+pub fn new_nccl_id() -> std::result::Result<Vec<u8>, APIError> {
+    nccl::new_id()
+}
+
+#[cfg(not(feature = "nccl"))]
+pub fn new_nccl_id() -> std::result::Result<Vec<u8>, APIError> {
+    Err(APIError::new_str(
+        "Tensor parallelism needs a build with the nccl feature.",
+    ))
+}
+
+#[cfg(feature = "nccl")]
+mod nccl {
+    use std::ffi::c_char;
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::{
+        backend::BackendStorage,
+        cuda_backend::{
+            cudarc::{
+                self,
+                nccl::safe::{Comm, Id, ReduceOp},
+            },
+            WrapErr,
+        },
+        CpuStorage, CudaStorage, CustomOp1, DType, Device, Layout, Result, Shape,
+    };
+    use half::{bf16, f16};
+    use tracing::warn;
+
+    use super::Shard;
+    use crate::backend::{CustomAllReduce, CUSTOM_ALL_REDUCE_HANDLES_LEN};
+    use crate::openai::responses::APIError;
+
+    /// Length of an NCCL id.
+    const ID_LEN: usize = 128;
+
+    pub fn new_id() -> std::result::Result<Vec<u8>, APIError> {
+        let id = Id::new().map_err(|err| APIError::new(format!("NCCL: {err:?}")))?;
+        Ok(id.internal().iter().map(|&b| b as u8).collect())
+    }
+
+    pub struct NcclComm(Comm);
+
+    // SAFETY: an NCCL communicator may be used from any thread, one at a time. It is not `Sync`,
+    // the layers of the model share it behind a lock.
+    unsafe impl Send for NcclComm {}
+
+    impl NcclComm {
+        pub fn new(
+            shard: Shard,
+            nccl_id: &[u8],
+            device: &Device,
+        ) -> std::result::Result<Self, APIError> {
+            let Device::Cuda(device) = device else {
+                return Err(APIError::new_str("Tensor parallelism needs CUDA devices."));
+            };
+            let internal: [c_char; ID_LEN] = nccl_id
+                .iter()
+                .map(|&b| b as c_char)
+                .collect::<Vec<_>>()
+                .try_into()
+                .map_err(|_| APIError::new_str("Invalid NCCL id."))?;
+            let comm = Comm::from_rank(
+                device.cuda_device(),
+                shard.rank,
+                shard.world_size,
+                Id::uninit(internal),
+            )
+            .map_err(|err| APIError::new(format!("NCCL: {:?}", err.0)))?;
+            Ok(Self(comm))
+        }
+
+        /// The custom all-reduce of the rank of `shard`, if every rank could map the buffers of
+        /// all the others. The ranks gather their handles and agree on it with NCCL, so each of
+        /// them takes part in these collectives whether it succeeds or not.
+        pub fn custom_all_reduce(
+            &self,
+            shard: Shard,
+            device: &Device,
+        ) -> std::result::Result<Option<CustomAllReduce>, APIError> {
+            let nccl_err =
+                |err: cudarc::nccl::result::NcclError| APIError::new(format!("NCCL: {:?}", err.0));
+            let mut custom = CustomAllReduce::new(device, shard.rank, shard.world_size)
+                .and_then(|custom| Ok((custom.handles()?, custom)));
+            let handles = match &custom {
+                Ok((handles, _)) => handles.clone(),
+                // Another node, as far as the others can tell.
+                Err(_) => vec![0; CUSTOM_ALL_REDUCE_HANDLES_LEN],
+            };
+            let dev = self.0.device();
+            let handles = dev.htod_copy(handles).w().map_err(APIError::from)?;
+            let mut all_handles = dev
+                .alloc_zeros::<u8>(shard.world_size * CUSTOM_ALL_REDUCE_HANDLES_LEN)
+                .w()
+                .map_err(APIError::from)?;
+            self.0
+                .all_gather(&handles, &mut all_handles)
+                .map_err(nccl_err)?;
+            let all_handles = dev
+                .dtoh_sync_copy(&all_handles)
+                .w()
+                .map_err(APIError::from)?;
+
+            let opened = match &mut custom {
+                Ok((_, custom)) => custom.open(&all_handles),
+                Err(err) => Err(candle_core::Error::Msg(err.to_string())),
+            };
+            let ready = dev
+                .htod_copy(vec![opened.is_ok() as u8 as f32])
+                .w()
+                .map_err(APIError::from)?;
+            let mut num_ready = dev.alloc_zeros::<f32>(1).w().map_err(APIError::from)?;
+            self.0
+                .all_reduce(&ready, &mut num_ready, &ReduceOp::Sum)
+                .map_err(nccl_err)?;
+            let num_ready = dev.dtoh_sync_copy(&num_ready).w().map_err(APIError::from)?[0];
+            if let Err(err) = opened {
+                warn!("The custom all-reduce is disabled: {err}");
+            }
+            Ok(match custom {
+                Ok((_, custom)) if num_ready as usize == shard.world_size => Some(custom),
+                _ => None,
+            })
+        }
+    }
+
+    pub struct AllReduce {
+        pub comm: Arc<Mutex<NcclComm>>,
+    }
+
+    impl CustomOp1 for AllReduce {
+        fn name(&self) -> &'static str {
+            "all-reduce"
+        }
+
+        fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+            candle_core::bail!("tensor parallelism runs on CUDA devices only")
+        }
+
+        fn cuda_fwd(&self, s: &CudaStorage, l: &Layout) -> Result<(CudaStorage, Shape)> {
+            let elem_count = l.shape().elem_count();
+            let dev = s.device().clone();
+            let Ok(comm) = self.comm.lock() else {
+                candle_core::bail!("an all-reduce of the rank panicked")
+            };
+            macro_rules! all_reduce {
+                ($t:ty) => {{
+                    let s = s.as_cuda_slice::<$t>()?;
+                    let s = match l.contiguous_offsets() {
+                        Some((0, len)) if len == elem_count => s,
+                        _ => candle_core::bail!("all-reduce of a non contiguous tensor"),
+                    };
+                    // SAFETY: the all-reduce writes every element.
+                    let mut dst = unsafe { dev.alloc::<$t>(elem_count) }.w()?;
+                    comm.0
+                        .all_reduce(s, &mut dst, &ReduceOp::Sum)
+                        .map_err(|err| candle_core::Error::Msg(format!("NCCL: {:?}", err.0)))?;
+                    CudaStorage::wrap_cuda_slice(dst, dev)
+                }};
+            }
+            let dst = match s.dtype() {
+                DType::BF16 => all_reduce!(bf16),
+                DType::F16 => all_reduce!(f16),
+                DType::F32 => all_reduce!(f32),
+                dtype => candle_core::bail!("all-reduce of {dtype:?} is not supported"),
+            };
+            Ok((dst, l.shape().clone()))
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use candle_core::{safetensors::MmapedSafetensors, DType, Device, Result, Tensor};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::tensor_parallel::Shard;
+
+/// Default size of the host buffer the weights are uploaded through (MB).
+pub const DEFAULT_WEIGHT_BUFFER_MEM: usize = 256;
+
+/// Most shards loaded at once.
+pub const MAX_WEIGHT_LOADERS: usize = 8;
+
+/// Load the tensors of the safetensors `files` on `device`, cast to `dtype`.
+///
+/// The shards are loaded in parallel, by up to `MAX_WEIGHT_LOADERS` threads (one per CPU at
+/// most), each of which reads, casts and uploads one shard at a time: the read of a shard from
+/// disk overlaps the upload of the others. A shard is memory-mapped while it loads and unmapped
+/// once its tensors are on the device. A tensor is copied out of the mapping in slices of at most
+/// the share of `buffer_size` bytes of its loader (or a single row of it when a row is larger), so
+/// that the host holds `buffer_size` bytes of slices at a time instead of whole tensors.
+pub fn load_safetensors(
+    files: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    buffer_size: usize,
+) -> Result<HashMap<String, Tensor>> {
+    load_safetensors_shard(files, dtype, device, buffer_size, Shard::default())
+}
+
+/// `load_safetensors` for the rank `shard` of a model sharded with tensor parallelism: only its
+/// part of the sharded weights is copied out of the mappings and uploaded.
+pub fn load_safetensors_shard(
+    files: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    buffer_size: usize,
+    shard: Shard,
+) -> Result<HashMap<String, Tensor>> {
+    // The index of a sharded checkpoint lists a shard once per tensor in it.
+    let mut seen = HashSet::new();
+    let shards = files
+        .iter()
+        .filter(|file| seen.insert(*file))
+        .collect::<Vec<_>>();
+    let num_loaders = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_WEIGHT_LOADERS)
+        .min(shards.len())
+        .max(1);
+    let buffer_size = (buffer_size / num_loaders).max(1);
+    let next_shard = AtomicUsize::new(0);
+    let loaded = std::thread::scope(|scope| {
+        let loaders = (0..num_loaders)
+            .map(|_| {
+                scope.spawn(|| -> Result<Vec<(String, Tensor)>> {
+                    let mut tensors = Vec::new();
+                    while let Some(file) = shards.get(next_shard.fetch_add(1, Ordering::Relaxed)) {
+                        tensors.extend(load_shard(file, dtype, device, buffer_size, shard)?);
+                    }
+                    Ok(tensors)
+                })
+            })
+            .collect::<Vec<_>>();
+        loaders
+            .into_iter()
+            .map(|loader| loader.join().expect("weight loader panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(loaded.into_iter().flatten().collect())
+}
+
+fn load_shard(
+    file: &Path,
+    dtype: DType,
+    device: &Device,
+    buffer_size: usize,
+    shard: Shard,
+) -> Result<Vec<(String, Tensor)>> {
+    let safetensors = unsafe { MmapedSafetensors::new(file)? };
+    safetensors
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| {
+            let part = match shard.dim(&name) {
+                Some(dim) => {
+                    let Some(&size) = view.shape().get(dim) else {
+                        candle_core::bail!("{name} has no dimension {dim} to shard")
+                    };
+                    if size % shard.world_size != 0 {
+                        candle_core::bail!(
+                            "{name} of {size} along dimension {dim} cannot be split over {} ranks",
+                            shard.world_size
+                        )
+                    }
+                    Some((dim, shard.range(size)))
+                }
+                None => None,
+            };
+            let tensor = upload(
+                view.data(),
+                DType::try_from(view.dtype())?,
+                view.shape(),
+                dtype,
+                device,
+                buffer_size,
+                part,
+            )?;
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+/// Upload the row-major `data` of a tensor in slices along its first dimension, casting them
+/// on the device. With `part`, only the range of the tensor along the given dimension (the
+/// first or the second) is uploaded.
+fn upload(
+    data: &[u8],
+    data_dtype: DType,
+    shape: &[usize],
+    dtype: DType,
+    device: &Device,
+    buffer_size: usize,
+    part: Option<(usize, Range<usize>)>,
+) -> Result<Tensor> {
+    let rows = shape.first().copied().unwrap_or(1).max(1);
+    let row_size = data.len() / rows;
+    let mut shape = shape.to_vec();
+    let (data, columns) = match part {
+        Some((0, rows)) => {
+            shape[0] = rows.len();
+            (&data[rows.start * row_size..rows.end * row_size], None)
+        }
+        Some((1, columns)) => (data, Some(columns)),
+        Some((dim, _)) => {
+            candle_core::bail!("only the first two dimensions are sharded, not {dim}")
+        }
+        None => (data, None),
+    };
+    let rows = shape.first().copied().unwrap_or(1).max(1);
+    let mut uploaded_shape = shape.clone();
+    if let Some(columns) = &columns {
+        uploaded_shape[1] = columns.len();
+    }
+    let slice_rows = (buffer_size / row_size.max(1)).max(1);
+    let slice = |start: usize, len: usize| {
+        let mut slice_shape = shape.clone();
+        if let Some(dim) = slice_shape.first_mut() {
+            *dim = len;
+        }
+        let bytes = &data[start * row_size..(start + len) * row_size];
+        let mut slice = Tensor::from_raw_buffer(bytes, data_dtype, &slice_shape, &Device::Cpu)?;
+        if let Some(columns) = &columns {
+            slice = slice
+                .narrow(1, columns.start, columns.len())?
+                .contiguous()?;
+        }
+        slice.to_device(device)?.to_dtype(dtype)
+    };
+    if slice_rows >= rows {
+        return slice(0, shape.first().copied().unwrap_or(1));
+    }
+    let tensor = Tensor::zeros(uploaded_shape, dtype, device)?;
+    for start in (0..rows).step_by(slice_rows) {
+        tensor.slice_set(&slice(start, slice_rows.min(rows - start))?, 0, start)?;
+    }
+    Ok(tensor)
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use candle_core::Tensor;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    input_builder::{InputBuilder, PreparedInputs},
+    ModulePipeline,
+};
+use crate::{
+    openai::{
+        control_vectors::{ControlVectorStrength, ControlVectors, Steering},
+        responses::APIError,
+    },
+    paged_attention::{block_sparse::BlockSparsePattern, input_metadata::BlockSparseInput},
+    scheduler::{
+        cache_engine::{CacheConfig, CacheEngine, KVCache},
+        draft_tree::DraftTree,
+        state_cache::RecurrentStateInput,
+    },
+    try_api,
+};
+
+/// Cache operations decided by the scheduler, run by every worker before the model.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheOps {
+    pub blocks_to_swap_in: HashMap<usize, usize>,
+    pub blocks_to_swap_out: HashMap<usize, usize>,
+    pub blocks_to_copy: HashMap<usize, Vec<usize>>,
+    /// Slots of recurrent state handed to new sequences, zeroed before their prefill.
+    #[serde(default)]
+    pub states_to_reset: Vec<usize>,
+}
+
+/// What a worker needs of a scheduled sequence to run it through the model, taken from the
+/// scheduler by the engine so that workers never see the scheduler.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SequenceInput {
+    pub seq_id: usize,
+    pub group_id: usize,
+    /// Preprocessed images of the group, encoded during prefill only. Sent to worker processes
+    /// as a tensor, not serialized.
+    #[serde(skip)]
+    pub pixel_values: Option<Tensor>,
+    /// Tokens run through the model: the prompt during prefill, else the tokens missing from the
+    /// KV cache followed by the proposed ones.
+    pub token_ids: Vec<usize>,
+    /// Position of each token, which the rotary embeddings encode.
+    pub positions: Vec<usize>,
+    /// Index in the KV cache of each token, its slot is found in `block_table`.
+    pub cache_indices: Vec<usize>,
+    /// Physical blocks of the sequence, `None` while profiling.
+    pub block_table: Option<Vec<usize>>,
+    /// While decoding, the logits of the last `num_sampled_tokens` tokens are the ones returned.
+    pub num_sampled_tokens: usize,
+    /// The proposed tokens, when they form a tree rather than a chain: its nodes are the last
+    /// `num_sampled_tokens` tokens.
+    pub draft_tree: Option<DraftTree>,
+    /// Slot of the recurrent states of the sequence, for hybrid models.
+    #[serde(default)]
+    pub state_slot: Option<usize>,
+    /// Control vectors the sequence steers with, by name.
+    #[serde(default)]
+    pub control_vectors: Vec<ControlVectorStrength>,
+}
+
+/// A step of the model, sent by the engine to every worker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelInput {
+    pub is_prompt: bool,
+    pub seqs: Vec<SequenceInput>,
+}
+
+/// How a worker runs its model, besides the KV cache of `CacheConfig`. Worker processes build
+/// the one of the engine from the same command line.
+#[derive(Clone, Debug, Default)]
+pub struct WorkerConfig {
+    /// Attend prompts a tile of this many queries at a time during prefill, so that the
+    /// attention scores of a long prompt are bounded by the tile rather than by its square.
+    /// `None` attends every prompt at once.
+    pub prefill_chunk_size: Option<usize>,
+    /// Names and files of the control vectors requests may steer with, which the workers load
+    /// along with the model.
+    pub control_vectors: Vec<(String, PathBuf)>,
+}
+
+impl WorkerConfig {
+    pub fn verify_args(&self) -> Result<(), APIError> {
+        if self.prefill_chunk_size == Some(0) {
+            return Err(APIError::new_str(
+                "Prefill must attend at least one query at a time.",
+            ));
+        }
+        for (i, (name, _)) in self.control_vectors.iter().enumerate() {
+            if self.control_vectors[..i]
+                .iter()
+                .any(|(other, _)| other == name)
+            {
+                return Err(APIError::new(format!(
+                    "Control vector {name} is given more than once."
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the model of `pipeline` can be run with this config.
+    pub fn verify_model(&self, pipeline: &dyn ModulePipeline) -> Result<(), APIError> {
+        // Only the decoder-only models add the control vectors to their residual stream, T5
+        // and Whisper do not.
+        if !self.control_vectors.is_empty()
+            && (pipeline.is_encoder_decoder() || pipeline.audio_processor().is_some())
+        {
+            return Err(APIError::new(format!(
+                "Control vectors are not supported for {} models.",
+                pipeline.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the model on a device: its pipeline and the KV cache the pipeline attends. Workers only
+/// know of the steps the engine sends them, the engine drives them through an `Executor`.
+pub struct Worker {
+    pipeline: Box<dyn ModulePipeline>,
+    cache_engine: CacheEngine,
+    input_builder: InputBuilder,
+    control_vectors: ControlVectors,
+    /// Sparsity pattern of each layer of a block-sparse model.
+    block_sparse: Option<Vec<Option<BlockSparsePattern>>>,
+}
+
+impl Worker {
+    /// Allocate the KV cache of `pipeline` on its device.
+    pub fn new(
+        pipeline: Box<dyn ModulePipeline>,
+        cache_config: &CacheConfig,
+        worker_config: &WorkerConfig,
+    ) -> Result<Self, APIError> {
+        worker_config.verify_args()?;
+        worker_config.verify_model(&*pipeline)?;
+        // With tensor parallelism, the cache holds the KV heads of the shard only.
+        let model_config = pipeline.shard().local_config(&pipeline.get_model_config());
+        let input_builder = InputBuilder::new(
+            cache_config.block_size,
+            model_config.sliding_window,
+            pipeline.device().clone(),
+        )
+        .with_prefill_chunk_size(worker_config.prefill_chunk_size);
+        let control_vectors =
+            ControlVectors::load(&worker_config.control_vectors, &pipeline.get_model_config())?;
+        let block_sparse = match &model_config.block_sparse {
+            Some(block_sparse) => {
+                block_sparse.verify()?;
+                Some(block_sparse.layer_patterns(
+                    model_config.num_hidden_layers,
+                    model_config.num_attention_heads,
+                ))
+            }
+            None => None,
+        };
+        let cache_engine = CacheEngine::new(
+            model_config,
+            cache_config.clone(),
+            cache_config.dtype,
+            &pipeline.device(),
+        )?;
+        Ok(Self {
+            pipeline,
+            cache_engine,
+            input_builder,
+            control_vectors,
+            block_sparse,
+        })
+    }
+
+    pub fn pipeline(&self) -> &dyn ModulePipeline {
+        &*self.pipeline
+    }
+
+    pub fn pipeline_mut(&mut self) -> &mut dyn ModulePipeline {
+        &mut *self.pipeline
+    }
+
+    pub fn cache_engine(&self) -> &CacheEngine {
+        &self.cache_engine
+    }
+
+    pub fn resize_gpu_cache(&mut self, num_blocks: usize) -> Result<(), APIError> {
+        self.cache_engine.resize_gpu_cache(num_blocks)
+    }
+
+    pub fn import_blocks(&self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError> {
+        self.cache_engine.import_blocks(blocks, block_ids)
+    }
+
+    pub fn execute_cache_ops(&mut self, ops: &CacheOps) -> Result<(), APIError> {
+        if !ops.blocks_to_swap_in.is_empty() {
+            self.cache_engine.swap_in(ops.blocks_to_swap_in.clone())?;
+        }
+        if !ops.blocks_to_swap_out.is_empty() {
+            self.cache_engine.swap_out(ops.blocks_to_swap_out.clone())?;
+        }
+        if !ops.blocks_to_copy.is_empty() {
+            self.cache_engine.copy(ops.blocks_to_copy.clone())?;
+        }
+        if let Some(state_cache) = self.cache_engine.get_state_cache() {
+            state_cache.reset(&ops.states_to_reset)?;
+        }
+        Ok(())
+    }
+
+    /// Logits of the sequences of `input`: one row per prompt during prefill, else one row per
+    /// sampled token of each sequence, in order.
+    pub fn execute_model(&mut self, input: &ModelInput) -> Result<Tensor, APIError> {
+        let (
+            PreparedInputs {
+                tokens,
+                positions,
+                mut metadata,
+                logits_rows,
+            },
+            image_features,
+        ) = if input.is_prompt {
+            (
+                self.input_builder.prepare_prompt(&input.seqs)?,
+                self.encode_images(&input.seqs)?,
+            )
+        } else {
+            (self.input_builder.prepare_decode(&input.seqs)?, vec![])
+        };
+        metadata.recurrent_state = self.recurrent_state(&input.seqs)?;
+        metadata.steering = self.steering(input)?;
+        metadata.block_sparse = self.block_sparse(input);
+        let recurrent_state = metadata.recurrent_state.clone();
+        let logits = self.pipeline.forward(
+            tokens,
+            &positions,
+            Some(&*self.cache_engine.get_kv_cache()),
+            metadata,
+            &image_features,
+        )?;
+        if let Some(recurrent_state) = recurrent_state {
+            recurrent_state.commit()?;
+        }
+        match logits_rows {
+            Some(rows) => {
+                let rows = try_api!(Tensor::new(rows, logits.device()));
+                Ok(try_api!(logits.index_select(&rows, 0)))
+            }
+            None => Ok(logits),
+        }
+    }
+
+    /// What the control vectors of the sequences of `input` add to the hidden states, over one
+    /// row per prompt during prefill, else one row per token.
+    fn steering(&self, input: &ModelInput) -> Result<Option<Steering>, APIError> {
+        if self.control_vectors.is_empty() {
+            return Ok(None);
+        }
+        let seqs = input
+            .seqs
+            .iter()
+            .map(|seq| {
+                let num_rows = if input.is_prompt {
+                    1
+                } else {
+                    seq.token_ids.len()
+                };
+                (seq.control_vectors.as_slice(), num_rows)
+            })
+            .collect::<Vec<_>>();
+        self.control_vectors
+            .steering(&seqs, self.pipeline.get_dtype(), self.pipeline.device())
+    }
+
+    /// Sparsity patterns of the layers for `input`, with the rows of a decode step.
+    fn block_sparse(&self, input: &ModelInput) -> Option<BlockSparseInput> {
+        let layers = self.block_sparse.clone()?;
+        let mut rows = Vec::new();
+        if !input.is_prompt {
+            for seq in &input.seqs {
+                let table = seq
+                    .block_table
+                    .iter()
+                    .flatten()
+                    .map(|&block| block as u32)
+                    .collect::<Vec<_>>();
+                // Each row attends the cache up to its own token.
+                for &cache_index in &seq.cache_indices {
+                    rows.push((table.clone(), cache_index + 1));
+                }
+            }
+        }
+        Some(BlockSparseInput { layers, rows })
+    }
+
+    /// States of the recurrent layers for `seqs`, with the slots the scheduler gave them.
+    fn recurrent_state(
+        &self,
+        seqs: &[SequenceInput],
+    ) -> Result<Option<RecurrentStateInput>, APIError> {
+        let Some(state_cache) = self.cache_engine.get_state_cache() else {
+            return Ok(None);
+        };
+        let slots = seqs
+            .iter()
+            .map(|seq| {
+                seq.state_slot.ok_or_else(|| {
+                    APIError::new(format!(
+                        "Sequence {} has no recurrent state slot.",
+                        seq.seq_id
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(RecurrentStateInput::new(state_cache.clone(), slots)))
+    }
+
+    /// Encoded images of each sequence of a prefill, the images of a group are encoded once for
+    /// all of its sequences.
+    fn encode_images(&self, seqs: &[SequenceInput]) -> Result<Vec<Option<Tensor>>, APIError> {
+        let mut image_features = Vec::new();
+        let mut encoded_images: Option<(usize, Option<Tensor>)> = None;
+        for seq in seqs {
+            let group_image_features = match &encoded_images {
+                Some((id, features)) if *id == seq.group_id => features.clone(),
+                _ => {
+                    let features = match &seq.pixel_values {
+                        Some(pixel_values) => Some(self.pipeline.encode_images(pixel_values)?),
+                        None => None,
+                    };
+                    encoded_images = Some((seq.group_id, features.clone()));
+                    features
+                }
+            };
+            image_features.push(group_image_features);
+        }
+        Ok(image_features)
+    }
+}
@@ -0,0 +1,652 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::Duration,
+};
+
+use candle_core::{Device, Tensor};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use super::{
+    tensor_parallel::{Shard, TensorParallel},
+    worker::{CacheOps, ModelInput, Worker},
+};
+use crate::{openai::responses::APIError, scheduler::cache_engine::KVCache, try_api};
+
+/// Environment variable naming the connection of a worker process to its engine: the path of a
+/// Unix socket, or `tcp://<host>:<port>` for the workers of other nodes.
+pub const WORKER_ENV: &str = "CANDLE_VLLM_WORKER";
+/// Environment variable holding the GPU ordinal of a worker process, set by the command that
+/// spawns it.
+pub const WORKER_DEVICE_ENV: &str = "CANDLE_VLLM_WORKER_DEVICE";
+/// Environment variable holding the rank of a worker process among the workers of all nodes.
+pub const WORKER_RANK_ENV: &str = "CANDLE_VLLM_WORKER_RANK";
+/// Environment variable holding the secret the workers of other nodes connect to the engine
+/// with. Every node is started with the same one.
+pub const WORKER_SECRET_ENV: &str = "CANDLE_VLLM_WORKER_SECRET";
+/// How long a connection to the engine has to say which worker it is.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the engine asks of a worker process, the tensors it carries are sent along.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WorkerRequest {
+    /// Join the NCCL communicator of the ranks of a sharded model, before loading its shard.
+    InitTensorParallel {
+        world_size: usize,
+        nccl_id: Vec<u8>,
+    },
+    ExecuteCacheOps(CacheOps),
+    /// With the pixel values of the sequences, as `pixel_values.{i}`.
+    ExecuteModel(ModelInput),
+    GetNumGpuBlocks,
+    ResizeGpuCache(usize),
+    ExportBlocks(Vec<usize>),
+    /// With the blocks, see `blocks_to_tensors`.
+    CheckBlocks,
+    /// With the blocks written to these block ids, see `blocks_to_tensors`.
+    ImportBlocks(Vec<usize>),
+    FreeSequences(Vec<usize>),
+    ReleaseWeights,
+    ReloadWeights,
+}
+
+/// Answer of a worker process to a request, with the logits (`logits`) of `ExecuteModel` and
+/// the blocks of `ExportBlocks`. `Connected` and `Ready` are sent unasked, once the worker
+/// connected and once it loaded the model. The workers of other nodes connect with the secret
+/// of `WORKER_SECRET_ENV`.
+#[derive(Serialize, Deserialize)]
+enum WorkerResponse {
+    Connected { rank: usize, secret: Option<String> },
+    Ready,
+    Done,
+    NumGpuBlocks(usize),
+    Error(String),
+}
+
+impl std::fmt::Debug for WorkerResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // The secret is left out of the errors and logs.
+            Self::Connected { rank, .. } => {
+                f.debug_struct("Connected").field("rank", rank).finish()
+            }
+            Self::Ready => f.write_str("Ready"),
+            Self::Done => f.write_str("Done"),
+            Self::NumGpuBlocks(num_blocks) => {
+                f.debug_tuple("NumGpuBlocks").field(num_blocks).finish()
+            }
+            Self::Error(err) => f.debug_tuple("Error").field(err).finish(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Frame<T> {
+    message: T,
+    has_tensors: bool,
+}
+
+/// Connection between the engine and a worker process, local or on another node.
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Unix(stream) => Self::Unix(stream.try_clone()?),
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// One end of the connection between the engine and a worker process. Messages are lines of
+/// JSON. Over a Unix socket, the tensors they carry are written to a safetensors file in shared
+/// memory (`/dev/shm`), one per direction, which the other end reads them from. Over TCP, they
+/// follow their message in the safetensors format, after their size in bytes (8 bytes, little
+/// endian).
+struct Channel {
+    reader: BufReader<Stream>,
+    writer: Stream,
+    /// Files the tensors are sent and received through, `None` over TCP.
+    files: Option<(PathBuf, PathBuf)>,
+}
+
+impl Channel {
+    fn new(stream: UnixStream, name: &str, is_engine: bool) -> Result<Self, APIError> {
+        let shm = Path::new("/dev/shm");
+        let dir = if shm.is_dir() {
+            shm.to_path_buf()
+        } else {
+            std::env::temp_dir()
+        };
+        let to_worker = dir.join(format!("{name}.request.safetensors"));
+        let to_engine = dir.join(format!("{name}.response.safetensors"));
+        let files = if is_engine {
+            (to_worker, to_engine)
+        } else {
+            (to_engine, to_worker)
+        };
+        let stream = Stream::Unix(stream);
+        Ok(Self {
+            reader: BufReader::new(try_api!(stream.try_clone())),
+            writer: stream,
+            files: Some(files),
+        })
+    }
+
+    fn tcp(stream: TcpStream) -> Result<Self, APIError> {
+        // Steps are small messages waited for, they are not to be delayed.
+        try_api!(stream.set_nodelay(true));
+        let stream = Stream::Tcp(stream);
+        Ok(Self {
+            reader: BufReader::new(try_api!(stream.try_clone())),
+            writer: stream,
+            files: None,
+        })
+    }
+
+    fn send<T: Serialize>(
+        &mut self,
+        message: &T,
+        tensors: HashMap<String, Tensor>,
+    ) -> Result<(), APIError> {
+        let has_tensors = !tensors.is_empty();
+        let mut inline = None;
+        if has_tensors {
+            let tensors = tensors
+                .into_iter()
+                .map(|(name, tensor)| Ok((name, try_api!(tensor.to_device(&Device::Cpu)))))
+                .collect::<Result<HashMap<_, _>, APIError>>()?;
+            match &self.files {
+                Some((send_path, _)) => {
+                    try_api!(candle_core::safetensors::save(&tensors, send_path))
+                }
+                None => inline = Some(try_api!(safetensors::serialize(&tensors, &None))),
+            }
+        }
+        let frame = try_api!(serde_json::to_string(&Frame {
+            message,
+            has_tensors
+        }));
+        try_api!(writeln!(self.writer, "{frame}"));
+        if let Some(bytes) = inline {
+            try_api!(self.writer.write_all(&(bytes.len() as u64).to_le_bytes()));
+            try_api!(self.writer.write_all(&bytes));
+        }
+        try_api!(self.writer.flush());
+        Ok(())
+    }
+
+    /// The next message and its tensors, on the CPU. `None` once the other end is gone.
+    fn receive<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(T, HashMap<String, Tensor>)>, APIError> {
+        let mut line = String::new();
+        if try_api!(self.reader.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let frame: Frame<T> = try_api!(serde_json::from_str(&line));
+        let tensors = match (frame.has_tensors, &self.files) {
+            (false, _) => HashMap::new(),
+            (true, Some((_, receive_path))) => {
+                try_api!(candle_core::safetensors::load(receive_path, &Device::Cpu))
+            }
+            (true, None) => {
+                let mut size = [0; 8];
+                try_api!(self.reader.read_exact(&mut size));
+                let mut bytes = vec![0; u64::from_le_bytes(size) as usize];
+                try_api!(self.reader.read_exact(&mut bytes));
+                try_api!(candle_core::safetensors::load_buffer(&bytes, &Device::Cpu))
+            }
+        };
+        Ok(Some((frame.message, tensors)))
+    }
+
+    fn remove_files(&self) {
+        if let Some((send_path, receive_path)) = &self.files {
+            let _ = fs::remove_file(send_path);
+            let _ = fs::remove_file(receive_path);
+        }
+    }
+}
+
+/// A worker process driven by the engine, which runs the model on its GPU: spawned by the
+/// engine, or by the launcher of another node.
+pub struct WorkerProcess {
+    rank: usize,
+    /// `None` for the workers of other nodes.
+    child: Option<Child>,
+    channel: Channel,
+}
+
+impl WorkerProcess {
+    /// Spawn `command`, which connects to the engine with `EngineConnection` as the worker of
+    /// rank `rank`, and wait until it connected. See `wait_ready` for the model.
+    pub fn spawn(rank: usize, mut command: Command) -> Result<Self, APIError> {
+        let name = format!("candle-vllm-worker-{}-{rank}", std::process::id());
+        let socket_path = std::env::temp_dir().join(format!("{name}.sock"));
+        let _ = fs::remove_file(&socket_path);
+        let listener = try_api!(UnixListener::bind(&socket_path));
+        try_api!(listener.set_nonblocking(true));
+        let mut child = try_api!(command
+            .env(WORKER_ENV, &socket_path)
+            .env(WORKER_RANK_ENV, rank.to_string())
+            .spawn());
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(status) = try_api!(child.try_wait()) {
+                        let _ = fs::remove_file(&socket_path);
+                        return Err(APIError::new(format!(
+                            "Worker process {rank} exited before it was ready: {status}."
+                        )));
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => {
+                    let _ = child.kill();
+                    return Err(APIError::from(err));
+                }
+            }
+        };
+        let _ = fs::remove_file(&socket_path);
+        try_api!(stream.set_nonblocking(false));
+        let mut worker = Self {
+            rank,
+            child: Some(child),
+            channel: Channel::new(stream, &name, true)?,
+        };
+        worker.connected()?;
+        Ok(worker)
+    }
+
+    /// Accept the workers of ranks `ranks`, run by other nodes, on `listener`. Waits for all of
+    /// them, whichever order they connect in. The connections which are not one of them with
+    /// `secret`, such as port scans or a rank connecting twice, are dropped.
+    pub fn accept_remote(
+        listener: &TcpListener,
+        ranks: Range<usize>,
+        secret: &str,
+    ) -> Result<Vec<Self>, APIError> {
+        let mut workers: Vec<Option<Self>> = ranks.clone().map(|_| None).collect();
+        while workers.iter().any(Option::is_none) {
+            let (stream, addr) = match listener.accept() {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("failed to accept a worker process: {err}");
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let worker = Self::handshake(stream, secret).and_then(|worker| {
+                if !ranks.contains(&worker.rank) {
+                    Err(APIError::new(format!(
+                        "rank {} is not one of the ranks {ranks:?} of the other nodes",
+                        worker.rank
+                    )))
+                } else if workers[worker.rank - ranks.start].is_some() {
+                    Err(APIError::new(format!(
+                        "rank {} is connected already",
+                        worker.rank
+                    )))
+                } else {
+                    Ok(worker)
+                }
+            });
+            match worker {
+                Ok(worker) => {
+                    println!("Worker process {} connected from {addr}", worker.rank);
+                    let index = worker.rank - ranks.start;
+                    workers[index] = Some(worker);
+                }
+                Err(err) => warn!(%addr, "dropped a connection to the engine: {}", err.message()),
+            }
+        }
+        Ok(workers.into_iter().flatten().collect())
+    }
+
+    /// The worker of another node connected with `stream`, once it said which rank it runs with
+    /// `secret`.
+    fn handshake(stream: TcpStream, secret: &str) -> Result<Self, APIError> {
+        // Connections which never say anything are given up on.
+        try_api!(stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)));
+        let timeout = try_api!(stream.try_clone());
+        let mut worker = Self {
+            rank: usize::MAX,
+            child: None,
+            channel: Channel::tcp(stream)?,
+        };
+        let (_, given) = worker.connected()?;
+        if !given.is_some_and(|given| same_secret(&given, secret)) {
+            return Err(APIError::new(format!(
+                "worker process {} did not give the secret of {WORKER_SECRET_ENV}",
+                worker.rank
+            )));
+        }
+        try_api!(timeout.set_read_timeout(None));
+        Ok(worker)
+    }
+
+    /// Wait for the worker to say which rank it runs, returns it and the secret it gave.
+    fn connected(&mut self) -> Result<(usize, Option<String>), APIError> {
+        match self.receive_response()? {
+            (WorkerResponse::Connected { rank, secret }, _) => {
+                if self.rank != usize::MAX && rank != self.rank {
+                    return Err(self.failed(APIError::new(format!("it connected as rank {rank}"))));
+                }
+                self.rank = rank;
+                Ok((rank, secret))
+            }
+            (response, _) => Err(self.failed(APIError::new(format!(
+                "it answered {response:?} instead of its rank"
+            )))),
+        }
+    }
+
+    /// Wait until the worker loaded the model.
+    pub fn wait_ready(&mut self) -> Result<(), APIError> {
+        match self.receive_response()? {
+            (WorkerResponse::Ready, _) => Ok(()),
+            (response, _) => Err(self.failed(APIError::new(format!(
+                "it answered {response:?} instead of being ready"
+            )))),
+        }
+    }
+
+    pub fn send(
+        &mut self,
+        request: &WorkerRequest,
+        tensors: HashMap<String, Tensor>,
+    ) -> Result<(), APIError> {
+        self.channel
+            .send(request, tensors)
+            .map_err(|err| self.failed(err))
+    }
+
+    /// Wait for the worker to handle the last request sent, returns the tensors it answered
+    /// with.
+    pub fn receive(&mut self) -> Result<HashMap<String, Tensor>, APIError> {
+        match self.receive_response()? {
+            (WorkerResponse::Error(err), _) => Err(APIError::new(format!(
+                "Worker process {}: {err}",
+                self.rank
+            ))),
+            (_, tensors) => Ok(tensors),
+        }
+    }
+
+    /// Number of blocks of the GPU KV cache of the worker.
+    pub fn num_gpu_blocks(&mut self) -> Result<usize, APIError> {
+        self.send(&WorkerRequest::GetNumGpuBlocks, HashMap::new())?;
+        match self.receive_response()? {
+            (WorkerResponse::NumGpuBlocks(num_blocks), _) => Ok(num_blocks),
+            (response, _) => Err(APIError::new(format!(
+                "Worker process {} answered {response:?} instead of its number of GPU blocks.",
+                self.rank
+            ))),
+        }
+    }
+
+    fn receive_response(&mut self) -> Result<(WorkerResponse, HashMap<String, Tensor>), APIError> {
+        match self.channel.receive() {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(self.failed(APIError::new_str("connection closed"))),
+            Err(err) => Err(self.failed(err)),
+        }
+    }
+
+    /// The error of a request the worker failed to handle, with its exit status if it is gone,
+    /// e.g. after a crash of CUDA.
+    fn failed(&mut self, err: APIError) -> APIError {
+        match self.child.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => {
+                APIError::new(format!("Worker process {} exited: {status}.", self.rank))
+            }
+            // Not known to be a worker yet, see `handshake`.
+            _ if self.rank == usize::MAX => err,
+            _ => APIError::new(format!("Worker process {}: {err}", self.rank)),
+        }
+    }
+}
+
+impl Drop for WorkerProcess {
+    fn drop(&mut self) {
+        // The workers of other nodes exit once their connection is closed.
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        self.channel.remove_files();
+    }
+}
+
+/// The connection of a worker process to its engine, found with `WORKER_ENV`.
+pub struct EngineConnection {
+    rank: usize,
+    channel: Channel,
+}
+
+impl EngineConnection {
+    /// Connect to the engine as the worker of rank `WORKER_RANK_ENV`.
+    pub fn connect() -> Result<Self, APIError> {
+        let address = std::env::var(WORKER_ENV)
+            .map_err(|_| APIError::new_str("Worker processes are spawned by the engine."))?;
+        let rank = std::env::var(WORKER_RANK_ENV)
+            .map_err(|_| APIError::new_str("Worker processes are given their rank."))?
+            .parse::<usize>()
+            .map_err(APIError::from)?;
+        let mut secret = None;
+        let mut channel = match address.strip_prefix("tcp://") {
+            Some(address) => {
+                secret = Some(worker_secret()?);
+                Channel::tcp(connect_tcp(address)?)?
+            }
+            None => {
+                let socket_path = PathBuf::from(&address);
+                let name = socket_path
+                    .file_stem()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| APIError::new_str("Invalid worker socket path."))?
+                    .to_string();
+                let stream = try_api!(UnixStream::connect(&socket_path));
+                Channel::new(stream, &name, false)?
+            }
+        };
+        channel.send(&WorkerResponse::Connected { rank, secret }, HashMap::new())?;
+        Ok(Self { rank, channel })
+    }
+
+    /// Join the communicator of the ranks of the model on `device`, with the id the engine
+    /// sends to all of them.
+    pub fn tensor_parallel(&mut self, device: &Device) -> Result<TensorParallel, APIError> {
+        let Some((request, _)) = self.channel.receive::<WorkerRequest>()? else {
+            return Err(APIError::new_str("The engine is gone."));
+        };
+        let WorkerRequest::InitTensorParallel {
+            world_size,
+            nccl_id,
+        } = request
+        else {
+            return Err(APIError::new(format!(
+                "The engine sent {request:?} before tensor parallelism was set up."
+            )));
+        };
+        let shard = Shard {
+            rank: self.rank,
+            world_size,
+        };
+        let response = match TensorParallel::new(shard, &nccl_id, device) {
+            Ok(parallel) => Ok(parallel),
+            Err(err) => {
+                self.channel
+                    .send(&WorkerResponse::Error(err.to_string()), HashMap::new())?;
+                Err(err)
+            }
+        }?;
+        self.channel.send(&WorkerResponse::Done, HashMap::new())?;
+        Ok(response)
+    }
+}
+
+/// The secret of `WORKER_SECRET_ENV`, which the engine and the workers of other nodes share.
+pub fn worker_secret() -> Result<String, APIError> {
+    match std::env::var(WORKER_SECRET_ENV) {
+        Ok(secret) if !secret.is_empty() => Ok(secret),
+        _ => Err(APIError::new(format!(
+            "The nodes of the model need the same secret in {WORKER_SECRET_ENV}."
+        ))),
+    }
+}
+
+/// Compared in constant time, not to leak how much of the secret was guessed.
+fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && std::iter::zip(given.bytes(), secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The workers of other nodes may start before the engine is listening.
+fn connect_tcp(address: &str) -> Result<TcpStream, APIError> {
+    const ATTEMPTS: usize = 600;
+    for _ in 1..ATTEMPTS {
+        if let Ok(stream) = TcpStream::connect(address) {
+            return Ok(stream);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    TcpStream::connect(address)
+        .map_err(|err| APIError::new(format!("Cannot connect to the engine at {address}: {err}")))
+}
+
+/// Serve the requests of the engine of `connection` with `worker`, which loaded the model, until
+/// the engine is gone.
+pub fn serve_engine(connection: EngineConnection, mut worker: Worker) -> Result<(), APIError> {
+    let mut channel = connection.channel;
+    channel.send(&WorkerResponse::Ready, HashMap::new())?;
+    while let Some((request, tensors)) = channel.receive::<WorkerRequest>()? {
+        let (response, tensors) = match handle_request(&mut worker, request, tensors) {
+            Ok(response) => response,
+            Err(err) => (WorkerResponse::Error(err.to_string()), HashMap::new()),
+        };
+        channel.send(&response, tensors)?;
+    }
+    Ok(())
+}
+
+fn handle_request(
+    worker: &mut Worker,
+    request: WorkerRequest,
+    mut tensors: HashMap<String, Tensor>,
+) -> Result<(WorkerResponse, HashMap<String, Tensor>), APIError> {
+    let done = |tensors| Ok((WorkerResponse::Done, tensors));
+    match request {
+        WorkerRequest::InitTensorParallel { .. } => Err(APIError::new_str(
+            "Tensor parallelism is set up before the model loads.",
+        )),
+        WorkerRequest::ExecuteCacheOps(ops) => {
+            worker.execute_cache_ops(&ops)?;
+            done(HashMap::new())
+        }
+        WorkerRequest::ExecuteModel(mut input) => {
+            let device = worker.pipeline().device().clone();
+            for (i, seq) in input.seqs.iter_mut().enumerate() {
+                if let Some(pixel_values) = tensors.remove(&format!("pixel_values.{i}")) {
+                    seq.pixel_values = Some(try_api!(pixel_values.to_device(&device)));
+                }
+            }
+            let logits = worker.execute_model(&input)?;
+            done(HashMap::from([("logits".to_string(), logits)]))
+        }
+        WorkerRequest::GetNumGpuBlocks => Ok((
+            WorkerResponse::NumGpuBlocks(worker.cache_engine().get_num_gpu_blocks()),
+            HashMap::new(),
+        )),
+        WorkerRequest::ResizeGpuCache(num_blocks) => {
+            worker.resize_gpu_cache(num_blocks)?;
+            done(HashMap::new())
+        }
+        WorkerRequest::ExportBlocks(block_ids) => {
+            let blocks = worker.cache_engine().export_blocks(&block_ids)?;
+            done(blocks_to_tensors(&blocks))
+        }
+        WorkerRequest::CheckBlocks => {
+            worker
+                .cache_engine()
+                .check_blocks(&blocks_from_tensors(tensors)?)?;
+            done(HashMap::new())
+        }
+        WorkerRequest::ImportBlocks(block_ids) => {
+            worker.import_blocks(&blocks_from_tensors(tensors)?, &block_ids)?;
+            done(HashMap::new())
+        }
+        WorkerRequest::FreeSequences(seq_ids) => {
+            worker.pipeline_mut().free_sequences(&seq_ids);
+            done(HashMap::new())
+        }
+        WorkerRequest::ReleaseWeights => {
+            worker.pipeline_mut().release_weights();
+            done(HashMap::new())
+        }
+        WorkerRequest::ReloadWeights => {
+            worker.pipeline_mut().reload_weights()?;
+            done(HashMap::new())
+        }
+    }
+}
+
+/// The key and value blocks of each layer as `layers.{i}.key` and `layers.{i}.value`, as in the
+/// files of `SequenceKV`.
+pub fn blocks_to_tensors(blocks: &[KVCache]) -> HashMap<String, Tensor> {
+    let mut tensors = HashMap::new();
+    for (i, (key_blocks, value_blocks)) in blocks.iter().enumerate() {
+        tensors.insert(format!("layers.{i}.key"), key_blocks.clone());
+        tensors.insert(format!("layers.{i}.value"), value_blocks.clone());
+    }
+    tensors
+}
+
+/// The blocks written by `blocks_to_tensors`.
+pub fn blocks_from_tensors(mut tensors: HashMap<String, Tensor>) -> Result<Vec<KVCache>, APIError> {
+    let mut blocks = Vec::new();
+    while let Some(key_blocks) = tensors.remove(&format!("layers.{}.key", blocks.len())) {
+        let value_blocks = tensors
+            .remove(&format!("layers.{}.value", blocks.len()))
+            .ok_or_else(|| APIError::new_str("Value blocks are missing."))?;
+        blocks.push((key_blocks, value_blocks));
+    }
+    Ok(blocks)
+}
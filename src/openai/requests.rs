@@ -2,19 +2,84 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use super::{control_vectors::ControlVectorStrength, sampling_params::SamplingStage};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Messages {
-    Map(Vec<HashMap<String, String>>),
+    Map(Vec<HashMap<String, MessageContent>>),
     Literal(String),
 }
 
+/// Content of a chat message: either plain text or a list of text and image parts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StopTokens {
     Multi(Vec<String>),
     Single(String),
 }
 
+/// Format of the output of a chat completion. `json_object` constrains it to a JSON object,
+/// without a schema.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+}
+
+/// A tool the model may call. Only functions are, as in the OpenAI API.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tool {
+    Function { function: FunctionDefinition },
+}
+
+/// A function the model may call, with the JSON schema of its arguments.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// Options of streamed responses.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Send the usage of the request in a last chunk without choices.
+    #[serde(default)]
+    pub include_usage: bool,
+    /// Send the usage so far with every chunk.
+    #[serde(default)]
+    pub continuous_usage_stats: bool,
+    /// Send the whole text of the choice so far with every chunk instead of the new text, for
+    /// clients that cannot assemble deltas (candle-vllm extension).
+    #[serde(default)]
+    pub full_text: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionRequest {
     pub model: String,
@@ -27,11 +92,16 @@ pub struct ChatCompletionRequest {
     pub n: Option<usize>, //1
     #[serde(default)]
     pub max_tokens: Option<usize>, //None
+    /// Tokens generated before EOS or a stop token can end a choice.
+    #[serde(default)]
+    pub min_tokens: Option<usize>, //None
     #[serde(default)]
     pub stop: Option<StopTokens>,
     #[serde(default)]
     pub stream: Option<bool>, //false
     #[serde(default)]
+    pub stream_options: Option<StreamOptions>, //None
+    #[serde(default)]
     pub presence_penalty: Option<f32>, //0.0
     #[serde(default)]
     pub frequency_penalty: Option<f32>, //0.0
@@ -46,14 +116,240 @@ pub struct ChatCompletionRequest {
     pub best_of: Option<usize>, //None
     #[serde(default)]
     pub use_beam_search: Option<bool>, //false
+    /// Exponent of the length beams are normalized by when ranked, with `use_beam_search`.
+    #[serde(default)]
+    pub length_penalty: Option<f32>, //1.0
     #[serde(default)]
     pub ignore_eos: Option<bool>, //false
     #[serde(default)]
-    pub skip_special_tokens: Option<bool>, //false
+    pub skip_special_tokens: Option<bool>, //true
+    /// Separate the special tokens kept with `skip_special_tokens: false` from the text around
+    /// them by a space.
+    #[serde(default)]
+    pub spaces_between_special_tokens: Option<bool>, //true
     #[serde(default)]
     pub stop_token_ids: Option<Vec<usize>>, //[]
     #[serde(default)]
     pub logprobs: Option<bool>, //false
+    /// Most likely tokens reported with the logprobs of each token, up to 20 unless the server
+    /// raises it with `--max-logprobs`.
+    #[serde(default)]
+    pub top_logprobs: Option<usize>, //None
     #[serde(default)]
     pub repetition_penalty: Option<f32>, //1.1
+    /// Seconds the request may take, choices still generating past it end with `timeout`.
+    #[serde(default)]
+    pub timeout: Option<f64>, //None
+    /// Back up over the last token of the prompt and have the first generated token extend it.
+    #[serde(default)]
+    pub token_healing: Option<bool>, //false
+    /// Strings the output is constrained to, each choice is exactly one of them.
+    #[serde(default)]
+    pub guided_choice: Option<Vec<String>>, //None
+    /// Conversation the request is a turn of, the next turn reuses the KV cache of this one.
+    #[serde(default)]
+    pub conversation_id: Option<String>, //None
+    /// Seconds the KV cache of this turn is kept for the next one, 0 when it will not come.
+    #[serde(default)]
+    pub kv_cache_retention: Option<f64>, //None
+    /// Prompt the choices are steered away from by classifier-free guidance, as is (no chat
+    /// template). Empty for guidance against the model without a prompt.
+    #[serde(default)]
+    pub negative_prompt: Option<String>, //None
+    /// Weight of the prompt over `negative_prompt`, 1 ignores the negative prompt.
+    #[serde(default)]
+    pub guidance_scale: Option<f32>, //1.5
+    /// Temperature and top-p of the choices by the number of tokens they generated.
+    #[serde(default)]
+    pub sampling_schedule: Option<Vec<SamplingStage>>, //None
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>, //None
+    /// Functions the model may call instead of answering, which finishes the choice with
+    /// `tool_calls`.
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>, //None
+    /// Return the token ids of the prompt and of each choice with the response (candle-vllm
+    /// extension).
+    #[serde(default)]
+    pub return_tokens: Option<bool>, //false
+    /// Control vectors of the server to steer the choices with, by name, with a strength of 1
+    /// unless given (candle-vllm extension).
+    #[serde(default)]
+    pub control_vectors: Option<Vec<ControlVectorStrength>>, //None
+    /// Rank of the request when the server schedules by priority, lower values are served first
+    /// (candle-vllm extension).
+    #[serde(default)]
+    pub priority: Option<i64>, //0
+    /// Dump the logits of this many most likely tokens at every generated token to a file of the
+    /// server, all of them with the vocabulary size (candle-vllm extension).
+    #[serde(default)]
+    pub dump_logits: Option<usize>, //None
+    /// Fields the server does not know, refused by strict validation.
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
+}
+
+/// Request of the legacy `/v1/completions` endpoint. With a `suffix`, the prompt is the code in
+/// front of the cursor and the model fills in the code between the prompt and the suffix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default)]
+    pub suffix: Option<String>, //None
+    #[serde(default)]
+    pub temperature: Option<f32>, //0.7
+    #[serde(default)]
+    pub top_p: Option<f32>, //1.0
+    #[serde(default)]
+    pub n: Option<usize>, //1
+    #[serde(default)]
+    pub max_tokens: Option<usize>, //None
+    /// Tokens generated before EOS or a stop token can end a choice.
+    #[serde(default)]
+    pub min_tokens: Option<usize>, //None
+    #[serde(default)]
+    pub stop: Option<StopTokens>,
+    #[serde(default)]
+    pub stream: Option<bool>, //false
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>, //None
+    #[serde(default)]
+    pub presence_penalty: Option<f32>, //0.0
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>, //0.0
+    #[serde(default)]
+    pub logit_bias: Option<HashMap<String, f32>>, //None
+    #[serde(default)]
+    pub user: Option<String>, //None
+    #[serde(default)]
+    //Additional candle-vllm params
+    pub top_k: Option<isize>, //-1
+    #[serde(default)]
+    pub best_of: Option<usize>, //None
+    #[serde(default)]
+    pub use_beam_search: Option<bool>, //false
+    /// Exponent of the length beams are normalized by when ranked, with `use_beam_search`.
+    #[serde(default)]
+    pub length_penalty: Option<f32>, //1.0
+    #[serde(default)]
+    pub ignore_eos: Option<bool>, //false
+    #[serde(default)]
+    pub skip_special_tokens: Option<bool>, //true
+    /// Separate the special tokens kept with `skip_special_tokens: false` from the text around
+    /// them by a space.
+    #[serde(default)]
+    pub spaces_between_special_tokens: Option<bool>, //true
+    #[serde(default)]
+    pub stop_token_ids: Option<Vec<usize>>, //[]
+    #[serde(default)]
+    pub repetition_penalty: Option<f32>, //1.1
+    /// Seconds the request may take, choices still generating past it end with `timeout`.
+    #[serde(default)]
+    pub timeout: Option<f64>, //None
+    /// Back up over the last token of the prompt and have the first generated token extend it.
+    #[serde(default)]
+    pub token_healing: Option<bool>, //false
+    /// Strings the output is constrained to, each choice is exactly one of them.
+    #[serde(default)]
+    pub guided_choice: Option<Vec<String>>, //None
+    /// Conversation the request is a turn of, the next turn reuses the KV cache of this one.
+    #[serde(default)]
+    pub conversation_id: Option<String>, //None
+    /// Seconds the KV cache of this turn is kept for the next one, 0 when it will not come.
+    #[serde(default)]
+    pub kv_cache_retention: Option<f64>, //None
+    /// Prompt the choices are steered away from by classifier-free guidance, as is (no chat
+    /// template). Empty for guidance against the model without a prompt.
+    #[serde(default)]
+    pub negative_prompt: Option<String>, //None
+    /// Weight of the prompt over `negative_prompt`, 1 ignores the negative prompt.
+    #[serde(default)]
+    pub guidance_scale: Option<f32>, //1.5
+    /// Temperature and top-p of the choices by the number of tokens they generated.
+    #[serde(default)]
+    pub sampling_schedule: Option<Vec<SamplingStage>>, //None
+    /// Return the token ids of the prompt and of each choice with the response (candle-vllm
+    /// extension).
+    #[serde(default)]
+    pub return_tokens: Option<bool>, //false
+    /// Control vectors of the server to steer the choices with, by name, with a strength of 1
+    /// unless given (candle-vllm extension).
+    #[serde(default)]
+    pub control_vectors: Option<Vec<ControlVectorStrength>>, //None
+    /// Rank of the request when the server schedules by priority, lower values are served first
+    /// (candle-vllm extension).
+    #[serde(default)]
+    pub priority: Option<i64>, //0
+    /// Dump the logits of this many most likely tokens at every generated token to a file of the
+    /// server, all of them with the vocabulary size (candle-vllm extension).
+    #[serde(default)]
+    pub dump_logits: Option<usize>, //None
+    /// Fields the server does not know, refused by strict validation.
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
+}
+
+/// Body of `POST /admin/config`, the fields left out keep their value.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfigUpdate {
+    pub max_num_seqs: Option<usize>,
+    /// Prompt tokens prefilled in one step, `null` lifts the limit.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub max_num_prefill_tokens: Option<Option<usize>>,
+    /// Default sampling params of the requests.
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<isize>,
+    pub repetition_penalty: Option<f32>,
+    pub max_tokens: Option<usize>,
+    /// Filter of the logs in the syntax of `RUST_LOG`, e.g. `debug` or `info,candle_vllm=debug`.
+    pub log_level: Option<String>,
+    pub enable_prefix_caching: Option<bool>,
+}
+
+/// Tells a field set to `null` (`Some(None)`) from a missing one (`None`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Query of `POST /sleep`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SleepQuery {
+    /// 1 frees the KV cache, 2 frees the weights too. Defaults to 1.
+    pub level: Option<u8>,
+}
+
+/// Query of `POST /v1/files`, whose body is the content of the file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileUploadQuery {
+    /// `batch` for the input file of a batch.
+    pub purpose: String,
+    pub filename: Option<String>,
+}
+
+/// Body of `POST /v1/batches`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateBatchRequest {
+    pub input_file_id: String,
+    /// `/v1/chat/completions` or `/v1/completions`, the url of every request of the batch.
+    pub endpoint: String,
+    /// Only `24h` is supported.
+    pub completion_window: String,
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// Body of `POST /v1/watermark/detect`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatermarkDetectRequest {
+    pub text: String,
+    /// z-score above which the text is watermarked, 4 by default.
+    #[serde(default)]
+    pub z_threshold: Option<f64>,
 }
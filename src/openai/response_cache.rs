@@ -0,0 +1,129 @@
+//! Exact-match cache of the responses of deterministic requests, returned without running the
+//! model again, e.g. for evaluation reruns or requests rendered from the same template.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::responses::{ChatCompletionResponse, CompletionResponse};
+
+/// Default time a response is served from the cache (seconds).
+pub const DEFAULT_RESPONSE_CACHE_TTL_SECS: u64 = 3600;
+
+#[derive(Clone, Debug)]
+pub enum CachedResponse {
+    Chat(ChatCompletionResponse),
+    Text(CompletionResponse),
+}
+
+impl CachedResponse {
+    /// Responses cut short by an abort or a timeout are not what the request would generate
+    /// again, they are not cached.
+    fn is_complete(&self) -> bool {
+        let complete = |finish_reason: Option<&String>| {
+            !finish_reason.is_some_and(|reason| reason == "abort" || reason == "timeout")
+        };
+        match self {
+            Self::Chat(response) => response
+                .choices
+                .iter()
+                .all(|choice| complete(choice.finish_reason.as_ref())),
+            Self::Text(response) => response
+                .choices
+                .iter()
+                .all(|choice| complete(choice.finish_reason.as_ref())),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    responses: HashMap<String, (Instant, CachedResponse)>,
+    /// Keys from the least to the most recently used.
+    order: VecDeque<String>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        self.responses.remove(key);
+        self.order.retain(|k| k != key);
+    }
+}
+
+/// Responses by request, up to `max_entries` of them for `ttl` each. The least recently used
+/// response is dropped for a new one once the cache is full.
+pub struct ResponseCache {
+    max_entries: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Key of `request` to `endpoint`: the request as a whole, with its model, messages and
+    /// sampling parameters.
+    pub fn key(endpoint: &str, request: &impl Serialize) -> Option<String> {
+        serde_json::to_string(request)
+            .ok()
+            .map(|request| format!("{endpoint} {request}"))
+    }
+
+    /// The response cached for `key`, unless it expired.
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let (inserted, response) = entries.responses.get(key)?;
+        if inserted.elapsed() > self.ttl {
+            entries.remove(key);
+            return None;
+        }
+        let response = response.clone();
+        entries.order.retain(|k| k != key);
+        entries.order.push_back(key.to_string());
+        Some(response)
+    }
+
+    /// Cache `response` for `key`, unless it is incomplete.
+    pub fn insert(&self, key: String, response: CachedResponse) {
+        if self.max_entries == 0 || !response.is_complete() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(&key);
+        while entries.responses.len() >= self.max_entries {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            entries.responses.remove(&oldest);
+        }
+        entries.order.push_back(key.clone());
+        entries.responses.insert(key, (Instant::now(), response));
+    }
+
+    /// Drop every response, e.g. once the defaults of the requests changed.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        *entries = Entries::default();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .responses
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
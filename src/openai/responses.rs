@@ -1,5 +1,13 @@
 use super::streaming::Streamer;
+use crate::openai::batches::{Batch, BatchList, FileObject};
+use crate::openai::hooks::ModerationMatch;
 use crate::openai::sampling_params::Logprobs;
+use crate::openai::transcription::TranscriptionSegment;
+use crate::openai::watermark::WatermarkDetection;
+use crate::scheduler::request_log::RecoveredRequest;
+use crate::scheduler::sequence::SequenceGroupMetrics;
+use crate::scheduler::ActiveRequest;
+use axum::body::Bytes;
 use axum::extract::Json;
 use axum::http::{self, StatusCode};
 use axum::response::{IntoResponse, Sse};
@@ -9,6 +17,9 @@ use serde::{Deserialize, Serialize};
 #[display(fmt = "Error: {}", data)]
 pub struct APIError {
     data: String,
+    /// Parameter of the request the error is about, reported as the `param` of the response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    param: Option<String>,
 }
 
 // impl error::ResponseError for APIError {
@@ -22,19 +33,34 @@ pub struct APIError {
 
 impl APIError {
     pub fn new(data: String) -> Self {
-        Self { data }
+        Self { data, param: None }
     }
 
     pub fn new_str(data: &str) -> Self {
+        Self::new(data.to_string())
+    }
+
+    /// An error about the parameter `param` of a request.
+    pub fn invalid_param(param: &str, data: String) -> Self {
         Self {
-            data: data.to_string(),
+            data,
+            param: Some(param.to_string()),
         }
     }
 
+    pub fn param(&self) -> Option<&str> {
+        self.param.as_deref()
+    }
+
     pub fn from<T: ToString>(value: T) -> Self {
         //panic!("{}", value.to_string());
         Self::new(value.to_string())
     }
+
+    /// The error without the `Error: ` prefix of its display.
+    pub fn message(&self) -> &str {
+        &self.data
+    }
 }
 
 #[macro_export]
@@ -58,13 +84,36 @@ pub struct ChatCompletionUsageResponse {
     pub total_tokens: usize,
     pub prompt_time_costs: usize,     //milliseconds
     pub completion_time_costs: usize, //milliseconds
+    /// Tokens each choice could generate: `max_tokens`, or the cap picked without it.
+    pub max_tokens: usize,
+}
+
+/// A call of one of the functions of the `tools` of the request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Position of the call among the ones of the choice, in streamed deltas.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: FunctionCall,
 }
 
-// tool_calls, function_call not supported!
+/// The function a tool call calls, with its arguments as a JSON object in a string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+// function_call not supported!
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatChoiceData {
     pub content: Option<String>,
     pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,6 +127,12 @@ pub struct ChatChoice {
     pub finish_reason: Option<String>,
     pub index: usize,
     pub logprobs: Option<WrapperLogprobs>,
+    /// What the moderators matched in the text of the choice.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationMatch>,
+    /// Token ids generated by the choice, for requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ids: Option<Vec<usize>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,14 +142,21 @@ pub struct ChatCompletionResponse {
     pub created: u64,
     pub model: String,
     pub object: &'static str,
+    pub system_fingerprint: Option<String>,
     pub usage: ChatCompletionUsageResponse,
+    /// Token ids of the prompt, for requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<usize>>,
 }
 
-// tool_calls, function_call not supported!
+// function_call not supported!
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChoiceData {
     pub content: Option<String>,
     pub role: String,
+    /// The tool calls of the choice, in the chunk it finishes with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -102,6 +164,23 @@ pub struct Choice {
     pub delta: ChoiceData,
     pub finish_reason: Option<String>,
     pub index: usize,
+    /// Logprobs of the token of the delta, for requests asking for `logprobs`.
+    pub logprobs: Option<WrapperLogprobs>,
+    /// What the moderators matched on the token of the delta, or on the token a choice finished
+    /// with `content_filter` without.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationMatch>,
+    /// Token id of the delta, for requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ids: Option<Vec<usize>>,
+}
+
+/// Token counts of a streamed request, sent with chunks as `stream_options` asks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,50 +191,397 @@ pub struct ChatCompletionChunk {
     pub model: String,
     pub object: &'static str,
     pub system_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<StreamUsage>,
+    /// Token ids of the prompt, sent with the first chunk of requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<usize>>,
+}
+
+/// Choice of a `/v1/completions` response, the text of the assistant message of a chat choice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: usize,
+    pub logprobs: Option<WrapperLogprobs>,
+    pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationMatch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ids: Option<Vec<usize>>,
+}
+
+impl From<ChatChoice> for CompletionChoice {
+    fn from(choice: ChatChoice) -> Self {
+        Self {
+            text: choice.message.content.unwrap_or_default(),
+            index: choice.index,
+            logprobs: choice.logprobs,
+            finish_reason: choice.finish_reason,
+            moderation: choice.moderation,
+            token_ids: choice.token_ids,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub choices: Vec<CompletionChoice>,
+    pub created: u64,
+    pub model: String,
+    pub object: &'static str,
+    pub system_fingerprint: Option<String>,
+    pub usage: ChatCompletionUsageResponse,
+    /// Token ids of the prompt, for requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionChunk {
+    pub id: String,
+    pub choices: Vec<CompletionChoice>,
+    pub created: u64,
+    pub model: String,
+    pub object: &'static str,
+    pub system_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<StreamUsage>,
+    /// Token ids of the prompt, sent with the first chunk of requests with `return_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<usize>>,
+}
+
+impl From<ChatCompletionChunk> for CompletionChunk {
+    fn from(chunk: ChatCompletionChunk) -> Self {
+        Self {
+            id: chunk.id,
+            choices: chunk
+                .choices
+                .into_iter()
+                .map(|choice| CompletionChoice {
+                    text: choice.delta.content.unwrap_or_default(),
+                    index: choice.index,
+                    logprobs: choice.logprobs,
+                    finish_reason: choice.finish_reason,
+                    moderation: choice.moderation,
+                    token_ids: choice.token_ids,
+                })
+                .collect(),
+            created: chunk.created,
+            model: chunk.model,
+            object: "text_completion",
+            system_fingerprint: chunk.system_fingerprint,
+            usage: chunk.usage,
+            prompt_token_ids: chunk.prompt_token_ids,
+        }
+    }
 }
 
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
+/// Body of every error response, the one of the OpenAI API so that its clients parse failures.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub message: String,
+    /// `invalid_request_error`, `authentication_error`, `rate_limit_error` or `server_error`,
+    /// by the status of the response.
+    #[serde(rename = "type")]
+    pub error_type: String,
+    /// Parameter of the request the error is about, when it is known.
+    pub param: Option<String>,
+    /// Machine readable error, e.g. `model_not_found`.
+    pub code: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(status: StatusCode, message: String, code: Option<&str>) -> Self {
+        let error_type = match status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            status if status.is_server_error() => "server_error",
+            _ => "invalid_request_error",
+        };
+        Self {
+            error: ErrorBody {
+                message,
+                error_type: error_type.to_string(),
+                param: None,
+                code: code.map(str::to_string),
+            },
+        }
     }
 }
 
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
+/// Response of `status` for `e`.
+fn error_response(status: StatusCode, e: APIError) -> axum::response::Response {
+    coded_error_response(status, e, None)
 }
 
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
+fn coded_error_response(
+    status: StatusCode,
+    e: APIError,
+    code: Option<&str>,
+) -> axum::response::Response {
+    let mut error = ErrorResponse::new(status, e.data, code);
+    error.error.param = e.param;
+    let mut r = Json(error).into_response();
+    *r.status_mut() = status;
+    r
+}
+
+/// `x-request-queue-ms` and `x-ttft-ms` headers of a finished request, for the stages it reached,
+/// and `x-spec-acceptance-rate` if speculative decoding proposed tokens for it.
+fn metrics_headers(metrics: Option<SequenceGroupMetrics>) -> http::HeaderMap {
+    let mut headers = http::HeaderMap::new();
+    let Some(metrics) = metrics else {
+        return headers;
+    };
+    for (name, time) in [
+        ("x-request-queue-ms", metrics.queue_time()),
+        ("x-ttft-ms", metrics.time_to_first_token()),
+    ] {
+        if let Some(time) = time {
+            headers.insert(name, (time.as_millis() as u64).into());
+        }
+    }
+    if let Some(rate) = metrics.acceptance_rate() {
+        if let Ok(rate) = http::HeaderValue::from_str(&format!("{rate:.3}")) {
+            headers.insert("x-spec-acceptance-rate", rate);
+        }
     }
+    headers
 }
-impl ErrorToResponse for JsonError {}
 
 pub enum ChatResponder {
     Streamer(Sse<Streamer>),
-    Completion(ChatCompletionResponse),
+    /// Responses which were not streamed carry the metrics of their request in headers.
+    Completion(ChatCompletionResponse, Option<SequenceGroupMetrics>),
+    TextCompletion(CompletionResponse, Option<SequenceGroupMetrics>),
     ModelError(APIError),
     InternalError(APIError),
     ValidationError(APIError),
+    /// The request names a model the server does not serve.
+    ModelNotFound(APIError),
+    /// Too many requests are waiting, clients retry later.
+    Overloaded(APIError),
+    /// The engine is sleeping.
+    Unavailable(APIError),
 }
 
 impl IntoResponse for ChatResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
             ChatResponder::Streamer(s) => s.into_response(),
-            ChatResponder::Completion(s) => Json(s).into_response(),
-            ChatResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            ChatResponder::Completion(s, metrics) => {
+                (metrics_headers(metrics), Json(s)).into_response()
             }
-            ChatResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            ChatResponder::TextCompletion(s, metrics) => {
+                (metrics_headers(metrics), Json(s)).into_response()
             }
+            ChatResponder::InternalError(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            ChatResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
             ChatResponder::ModelError(msg) => {
-                JsonError::new(msg.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, msg)
+            }
+            ChatResponder::ModelNotFound(e) => {
+                coded_error_response(StatusCode::NOT_FOUND, e, Some("model_not_found"))
+            }
+            ChatResponder::Overloaded(e) => error_response(StatusCode::TOO_MANY_REQUESTS, e),
+            ChatResponder::Unavailable(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+        }
+    }
+}
+
+/// Runtime configuration reported by `GET /admin/config` and `POST /admin/config`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub max_num_seqs: usize,
+    pub max_num_prefill_tokens: Option<usize>,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: isize,
+    pub repetition_penalty: f32,
+    pub max_tokens: usize,
+    /// `None` if the server did not install the logger.
+    pub log_level: Option<String>,
+    pub enable_prefix_caching: bool,
+}
+
+/// Requests in the queues of the engine, as of its last scheduler step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveRequestList {
+    pub object: String,
+    pub data: Vec<ActiveRequest>,
+}
+
+pub enum AdminResponder {
+    Config(AdminConfig),
+    Requests(ActiveRequestList),
+    /// The request about to be aborted.
+    Request(ActiveRequest),
+    /// The server was started without an admin token.
+    Disabled(APIError),
+    Unauthorized(APIError),
+    NotFound(APIError),
+    ValidationError(APIError),
+}
+
+impl IntoResponse for AdminResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AdminResponder::Config(c) => Json(c).into_response(),
+            AdminResponder::Requests(r) => Json(r).into_response(),
+            AdminResponder::Request(r) => Json(r).into_response(),
+            AdminResponder::Disabled(e) => error_response(StatusCode::NOT_FOUND, e),
+            AdminResponder::Unauthorized(e) => error_response(StatusCode::UNAUTHORIZED, e),
+            AdminResponder::NotFound(e) => error_response(StatusCode::NOT_FOUND, e),
+            AdminResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
+        }
+    }
+}
+
+pub enum BatchResponder {
+    Batch(Box<Batch>),
+    Batches(BatchList),
+    File(FileObject),
+    /// Content of a file, JSONL for the files of batches.
+    FileContent(Bytes),
+    NotFound(APIError),
+    ValidationError(APIError),
+}
+
+impl IntoResponse for BatchResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            BatchResponder::Batch(b) => Json(b).into_response(),
+            BatchResponder::Batches(b) => Json(b).into_response(),
+            BatchResponder::File(f) => Json(f).into_response(),
+            BatchResponder::FileContent(content) => {
+                ([(http::header::CONTENT_TYPE, "application/jsonl")], content).into_response()
+            }
+            BatchResponder::NotFound(e) => error_response(StatusCode::NOT_FOUND, e),
+            BatchResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
+        }
+    }
+}
+
+/// Element of the response of `GET /recovered_requests`, the result of a replayed request is
+/// reported once it finished.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveredRequestStatus {
+    #[serde(flatten)]
+    pub request: RecoveredRequest,
+    pub choices: Option<Vec<ChatChoice>>,
+    pub usage: Option<ChatCompletionUsageResponse>,
+}
+
+/// Response of `POST /sleep` and `POST /wake`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SleepStatus {
+    pub is_sleeping: bool,
+}
+
+pub enum SleepResponder {
+    Status(SleepStatus),
+    /// The engine cannot sleep now, e.g. requests are in flight.
+    Conflict(APIError),
+    ValidationError(APIError),
+    InternalError(APIError),
+}
+
+impl IntoResponse for SleepResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            SleepResponder::Status(s) => Json(s).into_response(),
+            SleepResponder::Conflict(e) => error_response(StatusCode::CONFLICT, e),
+            SleepResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
+            SleepResponder::InternalError(e) => {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+            }
+        }
+    }
+}
+
+/// Response of `POST /v1/watermark/detect`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WatermarkDetectResponse {
+    #[serde(flatten)]
+    pub detection: WatermarkDetection,
+    pub z_threshold: f64,
+    pub watermarked: bool,
+}
+
+pub enum WatermarkResponder {
+    Detection(WatermarkDetectResponse),
+    /// The server was started without a watermark.
+    Disabled(APIError),
+    ValidationError(APIError),
+    InternalError(APIError),
+}
+
+impl IntoResponse for WatermarkResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            WatermarkResponder::Detection(d) => Json(d).into_response(),
+            WatermarkResponder::Disabled(e) => error_response(StatusCode::NOT_FOUND, e),
+            WatermarkResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
+            WatermarkResponder::InternalError(e) => {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+            }
+        }
+    }
+}
+
+/// Response of `/v1/audio/transcriptions` and `/v1/audio/translations` in the `json` format.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptionResponse {
+    pub text: String,
+}
+
+/// Response in the `verbose_json` format.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptionVerboseResponse {
+    /// `transcribe` or `translate`.
+    pub task: &'static str,
+    pub language: String,
+    /// Length of the audio in seconds.
+    pub duration: f64,
+    pub text: String,
+    pub segments: Vec<TranscriptionSegment>,
+}
+
+pub enum AudioResponder {
+    Json(TranscriptionResponse),
+    VerboseJson(TranscriptionVerboseResponse),
+    /// The `text`, `srt` and `vtt` formats, along with their content type.
+    Text(&'static str, String),
+    ValidationError(APIError),
+    ModelError(APIError),
+    ModelNotFound(APIError),
+    Overloaded(APIError),
+    Unavailable(APIError),
+}
+
+impl IntoResponse for AudioResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AudioResponder::Json(r) => Json(r).into_response(),
+            AudioResponder::VerboseJson(r) => Json(r).into_response(),
+            AudioResponder::Text(content_type, text) => {
+                ([(http::header::CONTENT_TYPE, content_type)], text).into_response()
+            }
+            AudioResponder::ValidationError(e) => error_response(StatusCode::BAD_REQUEST, e),
+            AudioResponder::ModelError(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            AudioResponder::ModelNotFound(e) => {
+                coded_error_response(StatusCode::NOT_FOUND, e, Some("model_not_found"))
             }
+            AudioResponder::Overloaded(e) => error_response(StatusCode::TOO_MANY_REQUESTS, e),
+            AudioResponder::Unavailable(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, e),
         }
     }
 }
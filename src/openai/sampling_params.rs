@@ -1,8 +1,20 @@
-use super::{requests::StopTokens, responses::APIError};
+use super::{
+    control_vectors::ControlVectorStrength,
+    guidance::DEFAULT_GUIDANCE_SCALE,
+    hooks::LogitsProcessors,
+    logit_dump::{LogitDumpDir, LogitDumpParams},
+    requests::StopTokens,
+    responses::APIError,
+};
 use serde::{Deserialize, Serialize};
-use std::ops::Range;
+use std::{ops::Range, time::Duration};
 
-const SAMPLING_EPS: f32 = 1e-5;
+pub(crate) const SAMPLING_EPS: f32 = 1e-5;
+/// Most alternatives reported with the logprobs of each token, as in the OpenAI API.
+pub const MAX_TOP_LOGPROBS: usize = 20;
+/// Most alternatives reported over all the tokens of a request, past which its response would
+/// take hundreds of MB. Logit dumps have no such limit.
+pub const MAX_REQUEST_LOGPROBS: usize = 1 << 22;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 // Top-n logprobs element
@@ -20,7 +32,7 @@ pub struct Logprobs {
     pub top_logprobs: Vec<TopLogprob>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum EarlyStoppingCondition {
     ///True
     BestOfCompleteCandidates,
@@ -37,7 +49,7 @@ pub enum SamplingType {
     RANDOM,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SamplingParams {
     /// Number of output seqs to return for a prompt.
     pub n: usize,
@@ -65,7 +77,8 @@ pub struct SamplingParams {
     /// Use beam search instead of sampling.
     /// rec. default = false
     pub use_beam_search: bool,
-    /// Penalize based on length.
+    /// Exponent of the length a beam's cumulative logprob is divided by, >1 favors longer
+    /// outputs and <1 shorter ones.
     /// rec. default = 1
     pub length_penalty: f32,
     /// Control stopping for beam search.
@@ -89,6 +102,82 @@ pub struct SamplingParams {
     /// Skip special toks in output.
     /// rec. default = true
     pub skip_special_tokens: bool,
+    /// Separate the special toks kept in the output from the text around them by a space.
+    /// Default = true
+    pub spaces_between_special_tokens: bool,
+    /// Wall-clock budget of the request, counted from its creation. Seqs still generating when
+    /// it runs out are finished with the `timeout` finish reason.
+    /// Default = None
+    pub timeout: Option<Duration>,
+    /// Remove the last token of the prompt and have the first generated token start with it, so
+    /// that a prompt ending in the middle of a word is completed by the token the model would
+    /// have seen in training.
+    /// Default = false
+    pub token_healing: bool,
+    /// Strings the output is constrained to, every seq generates exactly one of them.
+    /// Default = None
+    pub guided_choice: Option<Vec<String>>,
+    /// Constrain the output of every seq to a JSON object, which ends it.
+    /// Default = false
+    pub json_mode: bool,
+    /// Min number of toks to gen per output seq before EOS or a stop token can end it.
+    /// Default = 0
+    pub min_tokens: usize,
+    /// Keep the KV cache of the finished seq, to be taken with `LLMEngine::take_exported_kv`.
+    /// Default = false
+    pub export_kv: bool,
+    /// Wait behind the requests of normal priority to be scheduled, as the requests of batches do.
+    /// Default = false
+    pub low_priority: bool,
+    /// Tenant the request is scheduled for by the `fair` scheduling policy, derived from its API
+    /// key.
+    /// Default = None
+    pub tenant: Option<String>,
+    /// Conversation the request is a turn of. The KV cache of the finished seq is kept for the
+    /// next turn, which only prefills the part of its prompt past the tokens of this turn.
+    /// Default = None
+    pub conversation_id: Option<String>,
+    /// How long the KV cache of the turn is kept for the next one, instead of until the cache of
+    /// the other conversations or the requests need its blocks. Zero frees it at once, for a turn
+    /// that will not be continued.
+    /// Default = None
+    pub kv_cache_retention: Option<Duration>,
+    /// Tokens of the negative prompt of classifier-free guidance. Each choice is paired with a
+    /// sequence continuing the negative prompt with the tokens of the choice, whose logits the
+    /// ones of the choice are pushed away from.
+    /// Default = None
+    pub negative_prompt_ids: Option<Vec<u32>>,
+    /// Weight of the logits of the prompt over the ones of the negative prompt.
+    /// Default = 1.5 with a negative prompt
+    pub guidance_scale: f32,
+    /// Temperature and top-p depending on the number of tokens generated so far, in place of
+    /// `temperature` and `top_p`.
+    /// Default = None
+    pub sampling_schedule: Option<SamplingSchedule>,
+    /// Report the token ids generated by each choice, in its `token_ids`.
+    /// Default = false
+    pub return_tokens: bool,
+    /// Rank of the request for the `priority` scheduling policy, lower values are served first.
+    /// Default = 0
+    pub priority: i64,
+    /// Names of the functions the model may call, whose calls are parsed out of the output of
+    /// each choice.
+    /// Default = none
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// Control vectors of the server added to the hidden states of each choice, with their
+    /// strength.
+    /// Default = none
+    #[serde(default)]
+    pub control_vectors: Vec<ControlVectorStrength>,
+    /// File the logits of every generated token are dumped to.
+    /// Default = None
+    #[serde(default)]
+    pub logit_dump: Option<LogitDumpParams>,
+    /// Processors of the logits of this request, after the ones of the engine.
+    /// Default = none
+    #[serde(skip)]
+    pub logits_processors: LogitsProcessors,
 }
 
 impl SamplingParams {
@@ -132,6 +221,26 @@ impl SamplingParams {
             logprobs,
             prompt_logprobs,
             skip_special_tokens,
+            spaces_between_special_tokens: true,
+            timeout: None,
+            token_healing: false,
+            guided_choice: None,
+            json_mode: false,
+            min_tokens: 0,
+            export_kv: false,
+            low_priority: false,
+            tenant: None,
+            conversation_id: None,
+            kv_cache_retention: None,
+            negative_prompt_ids: None,
+            guidance_scale: 1.0,
+            sampling_schedule: None,
+            return_tokens: false,
+            priority: 0,
+            tools: Vec::new(),
+            control_vectors: Vec::new(),
+            logit_dump: None,
+            logits_processors: LogitsProcessors::default(),
         };
 
         this.verify_args()?;
@@ -147,6 +256,256 @@ impl SamplingParams {
         Ok(this)
     }
 
+    /// Set the wall-clock budget of the request, in seconds.
+    pub fn set_timeout(&mut self, timeout: Option<f64>) -> Result<(), APIError> {
+        self.timeout = match timeout {
+            Some(secs) => match Duration::try_from_secs_f64(secs) {
+                Ok(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => {
+                    return Err(APIError::new(format!(
+                        "timeout must be a positive number of seconds, got {secs}"
+                    )))
+                }
+            },
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn set_min_tokens(&mut self, min_tokens: Option<usize>) -> Result<(), APIError> {
+        let min_tokens = min_tokens.unwrap_or(0);
+        if min_tokens > self.max_tokens {
+            return Err(APIError::new(format!(
+                "min_tokens must be less than or equal to max_tokens={}, got {}.",
+                self.max_tokens, min_tokens
+            )));
+        }
+        self.min_tokens = min_tokens;
+        Ok(())
+    }
+
+    /// Export the KV cache of the request once it finishes, which needs a single seq.
+    pub fn set_export_kv(&mut self, export_kv: bool) -> Result<(), APIError> {
+        if export_kv && self.best_of != 1 {
+            return Err(APIError::new(format!(
+                "export_kv needs best_of=1, got {}.",
+                self.best_of
+            )));
+        }
+        self.export_kv = export_kv;
+        Ok(())
+    }
+
+    /// Make the request a turn of the conversation `conversation_id`, which needs a single seq.
+    pub fn set_conversation_id(&mut self, conversation_id: Option<String>) -> Result<(), APIError> {
+        if conversation_id.is_some() && self.best_of != 1 {
+            return Err(APIError::new(format!(
+                "conversation_id needs best_of=1, got {}.",
+                self.best_of
+            )));
+        }
+        self.conversation_id = conversation_id;
+        Ok(())
+    }
+
+    /// Keep the KV cache of the turn for `retention` seconds, after the conversation id was set.
+    pub fn set_kv_cache_retention(&mut self, retention: Option<f64>) -> Result<(), APIError> {
+        let Some(secs) = retention else {
+            self.kv_cache_retention = None;
+            return Ok(());
+        };
+        if self.conversation_id.is_none() {
+            return Err(APIError::new_str(
+                "kv_cache_retention needs a conversation_id.",
+            ));
+        }
+        match Duration::try_from_secs_f64(secs) {
+            Ok(retention) => self.kv_cache_retention = Some(retention),
+            Err(_) => {
+                return Err(APIError::new(format!(
+                    "kv_cache_retention must be a non-negative number of seconds, got {secs}"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Guide the choices away from the tokens of `negative_prompt_ids` by `guidance_scale`, after
+    /// the conversation id was set.
+    pub fn set_guidance(
+        &mut self,
+        negative_prompt_ids: Option<Vec<u32>>,
+        guidance_scale: Option<f32>,
+    ) -> Result<(), APIError> {
+        let Some(negative_prompt_ids) = negative_prompt_ids else {
+            if guidance_scale.is_some() {
+                return Err(APIError::new_str("guidance_scale needs a negative_prompt."));
+            }
+            return Ok(());
+        };
+        let guidance_scale = guidance_scale.unwrap_or(DEFAULT_GUIDANCE_SCALE);
+        if !guidance_scale.is_finite() || guidance_scale <= 0.0 {
+            return Err(APIError::new(format!(
+                "guidance_scale must be positive, got {guidance_scale}."
+            )));
+        }
+        // Sessions keep the KV cache of a single sequence.
+        if self.conversation_id.is_some() {
+            return Err(APIError::new_str(
+                "negative_prompt can not be combined with conversation_id.",
+            ));
+        }
+        self.negative_prompt_ids = Some(negative_prompt_ids);
+        self.guidance_scale = guidance_scale;
+        Ok(())
+    }
+
+    /// Constrain the output to one of `choices`, after token healing was set.
+    pub fn set_guided_choice(&mut self, choices: Option<Vec<String>>) -> Result<(), APIError> {
+        if let Some(choices) = &choices {
+            if choices.is_empty() || choices.iter().any(|choice| choice.is_empty()) {
+                return Err(APIError::new_str(
+                    "guided_choice must hold at least one choice, and no empty ones.",
+                ));
+            }
+            if self.token_healing {
+                return Err(APIError::new_str(
+                    "guided_choice can not be combined with token_healing.",
+                ));
+            }
+        }
+        self.guided_choice = choices;
+        Ok(())
+    }
+
+    /// Constrain the output to a JSON object, after token healing and guided choice were set.
+    pub fn set_json_mode(&mut self, json_mode: bool) -> Result<(), APIError> {
+        if json_mode && self.token_healing {
+            return Err(APIError::new_str(
+                "JSON mode can not be combined with token_healing.",
+            ));
+        }
+        if json_mode && self.guided_choice.is_some() {
+            return Err(APIError::new_str(
+                "JSON mode can not be combined with guided_choice.",
+            ));
+        }
+        self.json_mode = json_mode;
+        Ok(())
+    }
+
+    /// Steer the choices with `control_vectors`, which must be among the `available` ones of the
+    /// server.
+    pub fn set_control_vectors(
+        &mut self,
+        control_vectors: Option<Vec<ControlVectorStrength>>,
+        available: &[String],
+    ) -> Result<(), APIError> {
+        let control_vectors = control_vectors.unwrap_or_default();
+        for control_vector in &control_vectors {
+            if !available.contains(&control_vector.name) {
+                return Err(APIError::new(format!(
+                    "Unknown control vector {}, the server has {:?}.",
+                    control_vector.name, available
+                )));
+            }
+            if !control_vector.strength.is_finite() {
+                return Err(APIError::new(format!(
+                    "The strength of control vector {} must be finite, got {}.",
+                    control_vector.name, control_vector.strength
+                )));
+            }
+        }
+        self.control_vectors = control_vectors;
+        Ok(())
+    }
+
+    /// Report the logprobs of each token with `top_logprobs` alternatives, up to `max_logprobs`,
+    /// which servers raise past `MAX_TOP_LOGPROBS` for research.
+    pub fn set_logprobs(
+        &mut self,
+        top_logprobs: Option<usize>,
+        max_logprobs: usize,
+    ) -> Result<(), APIError> {
+        if let Some(top_logprobs) = top_logprobs {
+            if top_logprobs > max_logprobs {
+                return Err(APIError::new(format!(
+                    "top_logprobs must be at most {max_logprobs}, got {top_logprobs}"
+                )));
+            }
+            let reported = top_logprobs
+                .saturating_mul(self.max_tokens)
+                .saturating_mul(self.best_of);
+            if reported > MAX_REQUEST_LOGPROBS {
+                return Err(APIError::new(format!(
+                    "top_logprobs={top_logprobs} over max_tokens={} and best_of={} reports more \
+                     than {MAX_REQUEST_LOGPROBS} logprobs, dump the logits instead.",
+                    self.max_tokens, self.best_of
+                )));
+            }
+        }
+        self.logprobs = top_logprobs;
+        Ok(())
+    }
+
+    /// Dump the logits of the `top` most likely tokens at every generated token, to a file of
+    /// `dumps`. Refused when the server does not dump logits.
+    pub fn set_logit_dump(
+        &mut self,
+        top: Option<usize>,
+        dumps: Option<&LogitDumpDir>,
+        request_id: &str,
+    ) -> Result<(), APIError> {
+        self.logit_dump = match (top, dumps) {
+            (None, _) => None,
+            (Some(0), _) => {
+                return Err(APIError::new_str(
+                    "dump_logits must be at least 1, the vocabulary size for every logit.",
+                ))
+            }
+            (Some(_), None) => {
+                return Err(APIError::new_str(
+                    "The server does not dump logits, start it with --logit-dump-dir.",
+                ))
+            }
+            (Some(top), Some(dumps)) => Some(dumps.params(request_id, top)),
+        };
+        Ok(())
+    }
+
+    /// Sample the tokens of each seq by the `stages` of a schedule as it progresses.
+    pub fn set_sampling_schedule(
+        &mut self,
+        stages: Option<Vec<SamplingStage>>,
+    ) -> Result<(), APIError> {
+        let Some(stages) = stages else {
+            self.sampling_schedule = None;
+            return Ok(());
+        };
+        if self.use_beam_search {
+            return Err(APIError::new_str(
+                "sampling_schedule can not be combined with beam search.",
+            ));
+        }
+        self.sampling_schedule = Some(SamplingSchedule::new(stages)?);
+        Ok(())
+    }
+
+    /// Score a beam of `seq_len` tokens, prompt included, is ranked by, as in vLLM and HF: its
+    /// cumulative logprob divided by its length to the power of `length_penalty`.
+    pub fn beam_search_score(&self, cumulative_logprob: f32, seq_len: usize) -> f32 {
+        cumulative_logprob / (seq_len as f32).powf(self.length_penalty)
+    }
+
+    /// Whether every token of the request is the most likely one, at a temperature of 0 or with
+    /// `top_k` 1. The pipeline then takes it with an argmax on the device, whatever the sampling
+    /// of the server, and without logprobs unless they are asked for.
+    pub fn is_greedy(&self) -> bool {
+        !self.use_beam_search
+            && self.sampling_schedule.is_none()
+            && (self.temperature < SAMPLING_EPS || self.top_k == 1)
+    }
+
     // pub fn get_logits_processor<'a>(
     //     &self,
     //     seed: u64,
@@ -234,6 +593,14 @@ impl SamplingParams {
                 self.max_tokens
             )));
         }
+        if let Some(logprobs) = self
+            .logprobs
+            .filter(|&logprobs| logprobs > MAX_TOP_LOGPROBS)
+        {
+            return Err(APIError::new(format!(
+                "top_logprobs must be at most {MAX_TOP_LOGPROBS}, got {logprobs}",
+            )));
+        }
         Ok(())
     }
 
@@ -255,6 +622,12 @@ impl SamplingParams {
         if self.top_k != -1 {
             return Err(APIError::new_str("top_k must be -1 when using beam search"));
         }
+        if !self.length_penalty.is_finite() {
+            return Err(APIError::new(format!(
+                "length_penalty must be a finite number, got {}",
+                self.length_penalty
+            )));
+        }
         Ok(())
     }
 
@@ -288,3 +661,107 @@ impl SamplingParams {
         Ok(())
     }
 }
+
+/// A stage of a sampling schedule, sampling the tokens generated before `until` others.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplingStage {
+    /// Number of generated tokens the stage ends at, the last stage lasts until the end if unset.
+    #[serde(default)]
+    pub until: Option<usize>,
+    /// The temperature and top-p of the request if unset.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Move the temperature and top-p linearly over the stage to the ones of the next stage, or
+    /// of the request after the last one.
+    #[serde(default)]
+    pub anneal: bool,
+}
+
+/// Stages of sampling a seq goes through as it generates tokens, e.g. a high temperature for the
+/// first tokens annealed afterwards. The temperature and top-p of the request apply past the
+/// last stage.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplingSchedule {
+    stages: Vec<SamplingStage>,
+}
+
+impl SamplingSchedule {
+    pub fn new(stages: Vec<SamplingStage>) -> Result<Self, APIError> {
+        if stages.is_empty() {
+            return Err(APIError::new_str(
+                "sampling_schedule must hold at least one stage.",
+            ));
+        }
+        let mut start = 0;
+        for (i, stage) in stages.iter().enumerate() {
+            match stage.until {
+                Some(until) if until <= start => {
+                    return Err(APIError::new(format!(
+                        "The stages of sampling_schedule must end after the previous one, stage {i} ends at {until}."
+                    )))
+                }
+                Some(until) => start = until,
+                None if i + 1 < stages.len() => {
+                    return Err(APIError::new(format!(
+                        "Only the last stage of sampling_schedule may leave out `until`, stage {i} does."
+                    )))
+                }
+                None if stage.anneal => {
+                    return Err(APIError::new_str(
+                        "An annealed stage of sampling_schedule needs an `until`.",
+                    ))
+                }
+                None => {}
+            }
+            if let Some(temperature) = stage.temperature.filter(|t| !t.is_finite() || *t < 0.0) {
+                return Err(APIError::new(format!(
+                    "temperature must be non-negative, got {temperature} in stage {i}."
+                )));
+            }
+            if let Some(top_p) = stage.top_p.filter(|p| p.is_nan() || *p <= 0.0 || *p > 1.0) {
+                return Err(APIError::new(format!(
+                    "top_p must be in (0, 1], got {top_p} in stage {i}."
+                )));
+            }
+        }
+        Ok(Self { stages })
+    }
+
+    pub fn stages(&self) -> &[SamplingStage] {
+        &self.stages
+    }
+
+    /// Temperature and top-p of the token generated after `tokens_generated` others, the ones of
+    /// the request being `temperature` and `top_p`.
+    pub fn at(&self, tokens_generated: usize, temperature: f32, top_p: f32) -> (f32, f32) {
+        let params = |stage: Option<&SamplingStage>| {
+            (
+                stage
+                    .and_then(|stage| stage.temperature)
+                    .unwrap_or(temperature),
+                stage.and_then(|stage| stage.top_p).unwrap_or(top_p),
+            )
+        };
+        let mut start = 0;
+        for (i, stage) in self.stages.iter().enumerate() {
+            let end = stage.until.unwrap_or(usize::MAX);
+            if tokens_generated >= end {
+                start = end;
+                continue;
+            }
+            let (temperature, top_p) = params(Some(stage));
+            if !stage.anneal {
+                return (temperature, top_p);
+            }
+            let (next_temperature, next_top_p) = params(self.stages.get(i + 1));
+            let progress = (tokens_generated - start) as f32 / (end - start) as f32;
+            return (
+                temperature + (next_temperature - temperature) * progress,
+                top_p + (next_top_p - top_p) * progress,
+            );
+        }
+        (temperature, top_p)
+    }
+}
@@ -0,0 +1,104 @@
+//! Special tokens in the text of the choices. Chat templates end a turn with a special token
+//! (`<|eot_id|>`, `<|im_end|>`) that is not always the EOS generation stops at, and other control
+//! tokens can be sampled anywhere. They are the added tokens of the tokenizer marked special, and
+//! requests drop them from their text by default (`skip_special_tokens`). The ones a request keeps
+//! are separated from the text around them by a space unless `spaces_between_special_tokens` is
+//! false, as in vLLM.
+//!
+//! The text of a choice and its streamed deltas follow the same rules, so that the deltas of a
+//! choice add up to its text.
+use std::collections::HashMap;
+
+use tokenizers::Tokenizer;
+
+use super::responses::APIError;
+
+/// The special tokens of a tokenizer, with their text.
+#[derive(Clone, Debug, Default)]
+pub struct SpecialTokens {
+    by_id: HashMap<u32, String>,
+}
+
+impl SpecialTokens {
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        let by_id = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, added)| added.special)
+            .map(|(id, added)| (id, added.content))
+            .collect();
+        Self { by_id }
+    }
+
+    pub fn is_special(&self, token: u32) -> bool {
+        self.by_id.contains_key(&token)
+    }
+
+    /// Text of the special token `token`, `None` for the other tokens.
+    pub fn text(&self, token: u32) -> Option<&str> {
+        self.by_id.get(&token).map(String::as_str)
+    }
+
+    /// Text of the generated `tokens`: the runs of regular tokens decoded by `tokenizer`, and the
+    /// special tokens between them dropped with `skip_special_tokens`, or else kept and joined to
+    /// the runs by a space with `spaces_between_special_tokens`.
+    pub fn decode(
+        &self,
+        tokenizer: &Tokenizer,
+        tokens: &[u32],
+        skip_special_tokens: bool,
+        spaces_between_special_tokens: bool,
+    ) -> Result<String, APIError> {
+        let mut pieces = Vec::new();
+        let mut run = Vec::new();
+        for &token in tokens {
+            match self.text(token) {
+                // A skipped token does not split the run, which decodes as if it was not there.
+                Some(_) if skip_special_tokens => {}
+                Some(text) => {
+                    if !run.is_empty() {
+                        pieces.push(tokenizer.decode(&run, false).map_err(APIError::from)?);
+                        run.clear();
+                    }
+                    pieces.push(text.to_string());
+                }
+                None => run.push(token),
+            }
+        }
+        if !run.is_empty() {
+            pieces.push(tokenizer.decode(&run, false).map_err(APIError::from)?);
+        }
+        let separator = if spaces_between_special_tokens {
+            " "
+        } else {
+            ""
+        };
+        Ok(pieces.join(separator))
+    }
+
+    /// Streamed text of `token` of text `text`, generated after `previous` (`None` for the first
+    /// token), as `decode` would add it to the text of the choice.
+    pub fn delta(
+        &self,
+        previous: Option<u32>,
+        token: u32,
+        text: &str,
+        skip_special_tokens: bool,
+        spaces_between_special_tokens: bool,
+    ) -> String {
+        let (text, special) = match self.text(token) {
+            Some(_) if skip_special_tokens => return String::new(),
+            Some(special) => (special, true),
+            None => (text, false),
+        };
+        // Kept special tokens are pieces of their own, joined to the one before them.
+        let separated = !skip_special_tokens
+            && spaces_between_special_tokens
+            && previous.is_some_and(|previous| special || self.is_special(previous));
+        if separated {
+            format!(" {text}")
+        } else {
+            text.to_string()
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Stop strings of a request, found in the text of each choice as it is generated. A choice
+//! stops at the first one, which is left out of its output, and the end of its text that may
+//! still become one is held back from streaming until it is known not to. The text of a request
+//! with tools is also held back from the start of its first tool call, see `tool_calls`.
+use super::{requests::StopTokens, tool_calls::TOOL_CALL_START};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StopChecker {
+    stop: Vec<String>,
+    tools: bool,
+}
+
+impl StopChecker {
+    /// The checker of the `stop` strings of a request, with `tools` or not.
+    pub fn new(stop: Option<&StopTokens>, tools: bool) -> Self {
+        let mut stop = match stop {
+            Some(StopTokens::Multi(stop)) => stop.clone(),
+            Some(StopTokens::Single(stop)) => vec![stop.clone()],
+            None => Vec::new(),
+        };
+        stop.retain(|stop| !stop.is_empty());
+        Self { stop, tools }
+    }
+
+    /// Whether the text of the choices streams as it is generated.
+    pub fn is_empty(&self) -> bool {
+        self.stop.is_empty() && !self.tools
+    }
+
+    /// Position of the first stop string in `text`, where the output ends.
+    pub fn find_stop(&self, text: &str) -> Option<usize> {
+        self.stop.iter().filter_map(|stop| text.find(stop)).min()
+    }
+
+    /// Length of the start of `text`, which has no stop string, that can be streamed: up to the
+    /// first tool call, without an end that may still become a stop string or a tool call.
+    pub fn streamable(&self, text: &str) -> usize {
+        let end = match self.tools {
+            true => text.find(TOOL_CALL_START).unwrap_or(text.len()),
+            false => text.len(),
+        };
+        let text = &text[..end];
+        let held = self
+            .stop
+            .iter()
+            .map(String::as_str)
+            .chain(self.tools.then_some(TOOL_CALL_START))
+            .map(|pattern| partial_suffix(text, pattern))
+            .max()
+            .unwrap_or(0);
+        end - held
+    }
+}
+
+/// Length of the longest end of `text` that `pattern` starts with, shorter than `pattern`.
+fn partial_suffix(text: &str, pattern: &str) -> usize {
+    (1..pattern.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            let start = text.len() - len;
+            text.is_char_boundary(start) && pattern.starts_with(&text[start..])
+        })
+        .unwrap_or(0)
+}
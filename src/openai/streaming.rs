@@ -1,7 +1,9 @@
-use super::responses::ChatCompletionChunk;
+use super::requests::StreamOptions;
+use super::responses::{ChatCompletionChunk, CompletionChunk, StreamUsage};
 use axum::response::sse::Event;
-use flume::Receiver;
-use futures::Stream;
+use flume::{r#async::RecvStream, Receiver};
+use futures::{ready, Stream, StreamExt};
+use serde_json::json;
 use std::{
     pin::Pin,
     task::{Context, Poll},
@@ -11,7 +13,8 @@ use std::{
 pub enum StreamingStatus {
     Uninitilized,
     Started,
-    Interrupted,
+    /// The last frame (usage or error) was sent, `[DONE]` follows.
+    Finishing,
     Stopped,
 }
 pub enum ChatResponse {
@@ -23,44 +26,150 @@ pub enum ChatResponse {
 }
 
 pub struct Streamer {
-    pub rx: Receiver<ChatResponse>,
+    pub rx: RecvStream<'static, ChatResponse>,
     pub status: StreamingStatus,
+    /// Stream the chunks of `/v1/completions` instead of chat completion chunks.
+    pub text_completion: bool,
+    options: StreamOptions,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    /// Text of every choice so far, kept when the chunks carry the full text.
+    texts: Vec<String>,
+    /// Last chunk sent, the usage chunk has its id, creation time and model.
+    last_chunk: Option<ChatCompletionChunk>,
+    /// Token ids of the prompt, sent with the first chunk.
+    prompt_token_ids: Option<Vec<usize>>,
+}
+
+impl Streamer {
+    pub fn new(
+        rx: Receiver<ChatResponse>,
+        text_completion: bool,
+        prompt_tokens: usize,
+        options: StreamOptions,
+    ) -> Self {
+        Self {
+            rx: rx.into_stream(),
+            status: StreamingStatus::Uninitilized,
+            text_completion,
+            options,
+            prompt_tokens,
+            completion_tokens: 0,
+            texts: Vec::new(),
+            last_chunk: None,
+            prompt_token_ids: None,
+        }
+    }
+
+    /// Send `prompt_token_ids` with the first chunk, for requests with `return_tokens`.
+    pub fn with_prompt_token_ids(mut self, prompt_token_ids: Option<Vec<usize>>) -> Self {
+        self.prompt_token_ids = prompt_token_ids;
+        self
+    }
+
+    fn usage(&self) -> StreamUsage {
+        StreamUsage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            total_tokens: self.prompt_tokens + self.completion_tokens,
+        }
+    }
+
+    fn chunk_event(&self, chunk: ChatCompletionChunk) -> Result<Event, axum::Error> {
+        if self.text_completion {
+            Event::default().json_data(CompletionChunk::from(chunk))
+        } else {
+            Event::default().json_data(chunk)
+        }
+    }
+
+    /// Count the token of `chunk`, and apply the stream options to it.
+    fn process_chunk(&mut self, mut chunk: ChatCompletionChunk) -> ChatCompletionChunk {
+        for choice in &mut chunk.choices {
+            let Some(content) = &mut choice.delta.content else {
+                continue;
+            };
+            // Every chunk with content is a generated token.
+            self.completion_tokens += 1;
+            if self.options.full_text {
+                if self.texts.len() <= choice.index {
+                    self.texts.resize(choice.index + 1, String::new());
+                }
+                let text = &mut self.texts[choice.index];
+                text.push_str(content);
+                content.clone_from(text);
+            }
+        }
+        if self.options.continuous_usage_stats {
+            chunk.usage = Some(self.usage());
+        }
+        chunk.prompt_token_ids = self.prompt_token_ids.take();
+        self.last_chunk = Some(chunk.clone());
+        chunk
+    }
+
+    /// Chunk without choices carrying the usage of the request.
+    fn usage_chunk(&self) -> Option<ChatCompletionChunk> {
+        let mut chunk = self.last_chunk.clone()?;
+        chunk.choices.clear();
+        chunk.prompt_token_ids = None;
+        chunk.usage = Some(self.usage());
+        Some(chunk)
+    }
+
+    /// OpenAI style error frame, clients stop reading the stream at the `[DONE]` following it.
+    fn fail(&mut self, message: String, error_type: &str) -> Result<Event, axum::Error> {
+        self.status = StreamingStatus::Finishing;
+        Event::default().json_data(json!({
+            "error": {
+                "message": message,
+                "type": error_type,
+                "param": null,
+                "code": null,
+            }
+        }))
+    }
 }
 
 impl Stream for Streamer {
     type Item = Result<Event, axum::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.status == StreamingStatus::Stopped {
-            return Poll::Ready(None);
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.status {
+            StreamingStatus::Stopped => return Poll::Ready(None),
+            StreamingStatus::Finishing => {
+                self.status = StreamingStatus::Stopped;
+                return Poll::Ready(Some(Ok(Event::default().data("[DONE]"))));
+            }
+            _ => {}
         }
-        match self.rx.try_recv() {
-            Ok(resp) => match resp {
-                ChatResponse::InternalError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::ValidationError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::ModelError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::Chunk(response) => {
-                    if self.status != StreamingStatus::Started {
-                        self.status = StreamingStatus::Started;
-                    }
-                    Poll::Ready(Some(Event::default().json_data(response)))
+        let event = match ready!(self.rx.poll_next_unpin(cx)) {
+            Some(ChatResponse::InternalError(e)) => self.fail(e, "internal_error"),
+            Some(ChatResponse::ValidationError(e)) => self.fail(e, "invalid_request_error"),
+            Some(ChatResponse::ModelError(e)) => self.fail(e, "server_error"),
+            Some(ChatResponse::Chunk(response)) => {
+                if self.status != StreamingStatus::Started {
+                    self.status = StreamingStatus::Started;
+                }
+                let chunk = self.process_chunk(response);
+                self.chunk_event(chunk)
+            }
+            Some(ChatResponse::Done) => match self.usage_chunk() {
+                Some(chunk) if self.options.include_usage => {
+                    self.status = StreamingStatus::Finishing;
+                    self.chunk_event(chunk)
                 }
-                ChatResponse::Done => {
+                _ => {
                     self.status = StreamingStatus::Stopped;
-                    Poll::Ready(Some(Ok(Event::default().data("[DONE]"))))
+                    Ok(Event::default().data("[DONE]"))
                 }
             },
-
-            Err(e) => {
-                if self.status == StreamingStatus::Started && e == flume::TryRecvError::Disconnected
-                {
-                    //no TryRecvError::Disconnected returned even if the client closed the stream or disconnected
-                    self.status = StreamingStatus::Interrupted;
-                    Poll::Ready(None)
-                } else {
-                    Poll::Pending
-                }
-            }
-        }
+            // The engine dropped the request without finishing it.
+            None => self.fail(
+                "The request ended before it finished.".to_string(),
+                "server_error",
+            ),
+        };
+        Poll::Ready(Some(event))
     }
 }
@@ -0,0 +1,62 @@
+//! Tokenization off the engine. Encoding a large prompt takes long enough to delay the model steps
+//! of the other requests when it is done while holding the engine, so the server hands prompts
+//! to a pool of worker threads with their own handle of the tokenizer, and awaits the encoding
+//! while the engine keeps running.
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::Arc;
+use tokenizers::{Encoding, Tokenizer};
+use tokio::sync::oneshot;
+
+use super::responses::APIError;
+
+pub struct TokenizerPool {
+    tokenizer: Arc<Tokenizer>,
+    pool: ThreadPool,
+}
+
+impl TokenizerPool {
+    pub fn new(tokenizer: Tokenizer, num_threads: usize) -> Result<Self, APIError> {
+        if num_threads == 0 {
+            return Err(APIError::new_str(
+                "The tokenizer pool needs at least one thread.",
+            ));
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("tokenizer-{i}"))
+            .build()
+            .map_err(|e| APIError::new(format!("Failed to start the tokenizer pool: {e}")))?;
+        Ok(Self {
+            tokenizer: Arc::new(tokenizer),
+            pool,
+        })
+    }
+
+    /// Encode `text` without special tokens on a worker thread.
+    pub async fn encode(&self, text: String) -> Result<Encoding, APIError> {
+        self.run(move |tokenizer| tokenizer.encode(text, false).map_err(APIError::from))
+            .await
+    }
+
+    /// Decode `ids`, special tokens included, on a worker thread.
+    pub async fn decode(&self, ids: Vec<u32>) -> Result<String, APIError> {
+        self.run(move |tokenizer| tokenizer.decode(&ids, false).map_err(APIError::from))
+            .await
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, APIError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Tokenizer) -> Result<T, APIError> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let tokenizer = self.tokenizer.clone();
+        self.pool.spawn(move || {
+            // The request may have been dropped in the meantime.
+            let _ = sender.send(f(&tokenizer));
+        });
+        receiver
+            .await
+            .map_err(|_| APIError::new_str("The tokenizer pool stopped."))?
+    }
+}
@@ -0,0 +1,88 @@
+//! Tool calls of chat requests with `tools`. The functions are described to the model after the
+//! system message, which asks it to call them as Hermes and Qwen 2 models were trained to: a JSON
+//! object with the `name` and `arguments` of the function between `<tool_call>` tags. The calls
+//! are parsed out of the text of a choice once it stops, and finish it with `tool_calls`.
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    requests::Tool,
+    responses::{FunctionCall, ToolCall},
+};
+
+pub const TOOL_CALL_START: &str = "<tool_call>";
+pub const TOOL_CALL_END: &str = "</tool_call>";
+
+/// The part of the system prompt describing `tools` to the model.
+pub fn tools_prompt(tools: &[Tool]) -> String {
+    let functions = tools
+        .iter()
+        .map(|Tool::Function { function }| serde_json::to_string(function).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "You may call one or more functions to assist with the user query. The functions are \
+         described by these JSON signatures:\n<tools>\n{functions}\n</tools>\nFor each function \
+         call, answer with a JSON object with the name of the function and its arguments \
+         between {TOOL_CALL_START}{TOOL_CALL_END} tags:\n{TOOL_CALL_START}\n{{\"name\": \
+         <function-name>, \"arguments\": <args-json-object>}}\n{TOOL_CALL_END}"
+    )
+}
+
+#[derive(Deserialize)]
+struct Call {
+    name: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+/// The tool calls ending `text`, after the text before the first one. `None` unless every call
+/// is a JSON object naming one of the functions of `tools`, with nothing but whitespace around
+/// them. The end tag of the last call may be missing, as when it was a stop string.
+pub fn parse_tool_calls(text: &str, tools: &[String]) -> Option<(String, Vec<ToolCall>)> {
+    let start = text.find(TOOL_CALL_START)?;
+    let content = text[..start].trim_end().to_string();
+    let mut calls = Vec::new();
+    let mut rest = &text[start..];
+    while !rest.is_empty() {
+        let call = rest.strip_prefix(TOOL_CALL_START)?;
+        let (call, next) = call.split_once(TOOL_CALL_END).unwrap_or((call, ""));
+        let call = serde_json::from_str::<Call>(call.trim()).ok()?;
+        if !tools.contains(&call.name) {
+            return None;
+        }
+        let arguments = match call.arguments {
+            serde_json::Value::String(arguments) => arguments,
+            serde_json::Value::Null => "{}".to_string(),
+            arguments => arguments.to_string(),
+        };
+        calls.push(ToolCall {
+            index: None,
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: call.name,
+                arguments,
+            },
+        });
+        rest = next.trim_start();
+    }
+    Some((content, calls))
+}
+
+/// The content, tool calls and finish reason of a choice whose output is `text` when it finished
+/// with `finish_reason`. A choice that stopped after calling some of the functions of `tools`
+/// finishes with `tool_calls`, and has no content unless it wrote some before them.
+pub fn finish_choice(
+    text: String,
+    tools: &[String],
+    finish_reason: &str,
+) -> (Option<String>, Option<Vec<ToolCall>>, String) {
+    if finish_reason == "stop" && !tools.is_empty() {
+        if let Some((content, calls)) = parse_tool_calls(&text, tools) {
+            let content = Some(content).filter(|content| !content.is_empty());
+            return (content, Some(calls), "tool_calls".to_string());
+        }
+    }
+    (Some(text), None, finish_reason.to_string())
+}
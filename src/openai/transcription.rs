@@ -0,0 +1,90 @@
+//! Response formats of `/v1/audio/transcriptions` and `/v1/audio/translations`. The audio is
+//! transcribed in windows of 30 seconds, which are the segments of the subtitles.
+use super::responses::APIError;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TranscriptionFormat {
+    #[default]
+    Json,
+    Text,
+    Srt,
+    VerboseJson,
+    Vtt,
+}
+
+impl TranscriptionFormat {
+    /// The format of the `response_format` field of a request.
+    pub fn parse(format: &str) -> Result<Self, APIError> {
+        match format {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            "srt" => Ok(Self::Srt),
+            "verbose_json" => Ok(Self::VerboseJson),
+            "vtt" => Ok(Self::Vtt),
+            _ => Err(APIError::new(format!(
+                "`response_format` must be one of json, text, srt, verbose_json or vtt, got `{format}`."
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptionSegment {
+    pub id: usize,
+    /// Start of the segment in the audio, in seconds.
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// The text of the segments, joined.
+pub fn segments_text(segments: &[TranscriptionSegment]) -> String {
+    segments
+        .iter()
+        .map(|segment| segment.text.trim())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// SubRip subtitles of the segments.
+pub fn format_srt(segments: &[TranscriptionSegment]) -> String {
+    let mut srt = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        srt.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            timestamp(segment.start, ','),
+            timestamp(segment.end, ','),
+            segment.text.trim()
+        ));
+    }
+    srt
+}
+
+/// WebVTT subtitles of the segments.
+pub fn format_vtt(segments: &[TranscriptionSegment]) -> String {
+    let mut vtt = "WEBVTT\n\n".to_string();
+    for segment in segments {
+        vtt.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            timestamp(segment.start, '.'),
+            timestamp(segment.end, '.'),
+            segment.text.trim()
+        ));
+    }
+    vtt
+}
+
+/// `HH:MM:SS` followed by the milliseconds after `separator`.
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds * 1000.).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
@@ -6,3 +6,10 @@ pub(crate) fn get_created_time_secs() -> u64 {
         .expect("Time travel has occurred...")
         .as_secs()
 }
+
+pub(crate) fn get_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred...")
+        .as_millis() as u64
+}
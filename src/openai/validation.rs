@@ -0,0 +1,260 @@
+//! Checks of the fields of chat and completion requests, run before their prompt is tokenized so
+//! that an invalid request is refused with the parameter at fault as the `param` of the error.
+use std::collections::HashMap;
+
+use super::{
+    requests::{ChatCompletionRequest, CompletionRequest, StopTokens, Tool},
+    responses::APIError,
+};
+
+/// Stop sequences a request may have, as in the OpenAI API.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+/// Bound of the biases of `logit_bias`, as in the OpenAI API.
+pub const MAX_LOGIT_BIAS: f32 = 100.0;
+
+/// The fields the requests of both endpoints share.
+struct CommonFields<'a> {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<isize>,
+    n: Option<usize>,
+    best_of: Option<usize>,
+    max_tokens: Option<usize>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    stop: &'a Option<StopTokens>,
+    logit_bias: &'a Option<HashMap<String, f32>>,
+    unknown_fields: &'a HashMap<String, serde_json::Value>,
+}
+
+/// Check `request` to `/v1/chat/completions`, refusing its unknown fields when
+/// `reject_unknown_fields` and more than `max_logprobs` top logprobs.
+pub fn validate_chat_request(
+    request: &ChatCompletionRequest,
+    reject_unknown_fields: bool,
+    max_logprobs: usize,
+) -> Result<(), APIError> {
+    validate_common(
+        CommonFields {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            n: request.n,
+            best_of: request.best_of,
+            max_tokens: request.max_tokens,
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            repetition_penalty: request.repetition_penalty,
+            stop: &request.stop,
+            logit_bias: &request.logit_bias,
+            unknown_fields: &request.unknown_fields,
+        },
+        reject_unknown_fields,
+    )?;
+    if let Some(top_logprobs) = request.top_logprobs {
+        if !request.logprobs.unwrap_or(false) {
+            return Err(APIError::invalid_param(
+                "top_logprobs",
+                "top_logprobs needs logprobs to be true.".to_string(),
+            ));
+        }
+        if top_logprobs > max_logprobs {
+            return Err(APIError::invalid_param(
+                "top_logprobs",
+                format!("top_logprobs must be at most {max_logprobs}, got {top_logprobs}."),
+            ));
+        }
+    }
+    validate_tools(&request.tools)
+}
+
+/// The model tells the functions apart by their name.
+fn validate_tools(tools: &Option<Vec<Tool>>) -> Result<(), APIError> {
+    let Some(tools) = tools else {
+        return Ok(());
+    };
+    let mut names = Vec::new();
+    for Tool::Function { function } in tools {
+        if function.name.is_empty() {
+            return Err(APIError::invalid_param(
+                "tools",
+                "function names cannot be empty.".to_string(),
+            ));
+        }
+        if names.contains(&function.name.as_str()) {
+            return Err(APIError::invalid_param(
+                "tools",
+                format!("function {:?} is defined twice.", function.name),
+            ));
+        }
+        names.push(&function.name);
+    }
+    Ok(())
+}
+
+/// Check `request` to `/v1/completions`, refusing its unknown fields when
+/// `reject_unknown_fields`.
+pub fn validate_completion_request(
+    request: &CompletionRequest,
+    reject_unknown_fields: bool,
+) -> Result<(), APIError> {
+    validate_common(
+        CommonFields {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            n: request.n,
+            best_of: request.best_of,
+            max_tokens: request.max_tokens,
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            repetition_penalty: request.repetition_penalty,
+            stop: &request.stop,
+            logit_bias: &request.logit_bias,
+            unknown_fields: &request.unknown_fields,
+        },
+        reject_unknown_fields,
+    )
+}
+
+fn validate_common(fields: CommonFields, reject_unknown_fields: bool) -> Result<(), APIError> {
+    if reject_unknown_fields {
+        // The first one by name, for a stable error.
+        if let Some(field) = fields.unknown_fields.keys().min() {
+            return Err(APIError::invalid_param(
+                field,
+                format!("Unknown field `{field}`."),
+            ));
+        }
+    }
+    check_range("temperature", fields.temperature, 0.0, 2.0, false)?;
+    check_range("top_p", fields.top_p, 0.0, 1.0, true)?;
+    check_range(
+        "presence_penalty",
+        fields.presence_penalty,
+        -2.0,
+        2.0,
+        false,
+    )?;
+    check_range(
+        "frequency_penalty",
+        fields.frequency_penalty,
+        -2.0,
+        2.0,
+        false,
+    )?;
+    check_range(
+        "repetition_penalty",
+        fields.repetition_penalty,
+        0.0,
+        2.0,
+        true,
+    )?;
+    if let Some(top_k) = fields.top_k.filter(|&top_k| top_k != -1 && top_k < 1) {
+        return Err(APIError::invalid_param(
+            "top_k",
+            format!("top_k must be -1 (disabled) or at least 1, got {top_k}."),
+        ));
+    }
+    if fields.n == Some(0) {
+        return Err(APIError::invalid_param(
+            "n",
+            "n must be at least 1, got 0.".to_string(),
+        ));
+    }
+    if let Some(best_of) = fields.best_of {
+        let n = fields.n.unwrap_or(1);
+        if best_of < n {
+            return Err(APIError::invalid_param(
+                "best_of",
+                format!("best_of must be at least n ({n}), got {best_of}."),
+            ));
+        }
+    }
+    if fields.max_tokens == Some(0) {
+        return Err(APIError::invalid_param(
+            "max_tokens",
+            "max_tokens must be at least 1, got 0.".to_string(),
+        ));
+    }
+    validate_stop(fields.stop)?;
+    validate_logit_bias(fields.logit_bias)
+}
+
+/// Check that `value` of the field `param` is in `[min, max]`, or `(min, max]` when
+/// `exclusive_min`.
+fn check_range(
+    param: &str,
+    value: Option<f32>,
+    min: f32,
+    max: f32,
+    exclusive_min: bool,
+) -> Result<(), APIError> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+    let above_min = if exclusive_min {
+        value > min
+    } else {
+        value >= min
+    };
+    if !(above_min && value <= max) {
+        let open = if exclusive_min { '(' } else { '[' };
+        return Err(APIError::invalid_param(
+            param,
+            format!("{param} must be in {open}{min}, {max}], got {value}."),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_stop(stop: &Option<StopTokens>) -> Result<(), APIError> {
+    let stop = match stop {
+        Some(StopTokens::Multi(stop)) => stop.as_slice(),
+        Some(StopTokens::Single(stop)) => std::slice::from_ref(stop),
+        None => return Ok(()),
+    };
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(APIError::invalid_param(
+            "stop",
+            format!(
+                "stop may have at most {MAX_STOP_SEQUENCES} sequences, got {}.",
+                stop.len()
+            ),
+        ));
+    }
+    if stop.iter().any(String::is_empty) {
+        return Err(APIError::invalid_param(
+            "stop",
+            "stop sequences cannot be empty.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_logit_bias(logit_bias: &Option<HashMap<String, f32>>) -> Result<(), APIError> {
+    let Some(logit_bias) = logit_bias else {
+        return Ok(());
+    };
+    let mut biases = logit_bias.iter().collect::<Vec<_>>();
+    biases.sort_by(|a, b| a.0.cmp(b.0));
+    for (token, bias) in biases {
+        if token.parse::<u32>().is_err() {
+            return Err(APIError::invalid_param(
+                "logit_bias",
+                format!("logit_bias keys must be token ids, got {token:?}."),
+            ));
+        }
+        if !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(bias) {
+            return Err(APIError::invalid_param(
+                "logit_bias",
+                format!(
+                    "logit_bias values must be in [-{MAX_LOGIT_BIAS}, {MAX_LOGIT_BIAS}], got \
+                     {bias} for token {token}."
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
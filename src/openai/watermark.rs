@@ -0,0 +1,150 @@
+//! Statistical watermark of the generated text (Kirchenbauer et al., 2023). Before each token is
+//! sampled, a fraction `gamma` of the vocabulary is picked as the green list from a hash of the
+//! secret key and the previous token, and `delta` is added to the logits of the green tokens.
+//! Watermarked text holds more green tokens than the `gamma` expected by chance, which is tested
+//! with the key alone, without the model or the prompt.
+use std::collections::HashSet;
+
+use candle_core::{DType, Tensor, D};
+use serde::Serialize;
+
+use super::{
+    hooks::{LogitsContext, LogitsProcessor},
+    responses::APIError,
+};
+
+/// z-score above which text is taken as watermarked, the odds of human text reaching it are
+/// about 3 in 100000.
+pub const DEFAULT_Z_THRESHOLD: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatermarkConfig {
+    /// Secret the green lists are derived from, needed to detect the watermark.
+    pub key: u64,
+    /// Fraction of the vocabulary in the green list, in (0, 1).
+    pub gamma: f64,
+    /// Added to the logits of the green tokens, the higher the easier to detect and the more the
+    /// output departs from the one of the model.
+    pub delta: f32,
+}
+
+impl WatermarkConfig {
+    pub fn verify_args(&self) -> Result<(), APIError> {
+        if !(self.gamma > 0.0 && self.gamma < 1.0) {
+            return Err(APIError::new(format!(
+                "The watermark green list fraction has to be in (0, 1), got {}.",
+                self.gamma
+            )));
+        }
+        if !(self.delta > 0.0 && self.delta.is_finite()) {
+            return Err(APIError::new(format!(
+                "The watermark bias has to be positive, got {}.",
+                self.delta
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Result of testing token ids for the watermark.
+#[derive(Clone, Debug, Serialize)]
+pub struct WatermarkDetection {
+    /// Tokens tested, each distinct pair of a token and its previous token counts once.
+    pub num_tokens_scored: usize,
+    pub num_green_tokens: usize,
+    pub green_fraction: f64,
+    /// Standard deviations of the green tokens above the count expected without a watermark.
+    pub z_score: f64,
+}
+
+impl WatermarkDetection {
+    pub fn is_watermarked(&self, z_threshold: f64) -> bool {
+        self.z_score > z_threshold
+    }
+}
+
+/// The watermark logits processor, added to the engine with `LLMEngine::add_logits_processor`.
+#[derive(Clone, Debug)]
+pub struct Watermark {
+    config: WatermarkConfig,
+}
+
+impl Watermark {
+    pub fn new(config: WatermarkConfig) -> Result<Self, APIError> {
+        config.verify_args()?;
+        Ok(Self { config })
+    }
+
+    pub fn config(&self) -> &WatermarkConfig {
+        &self.config
+    }
+
+    /// Whether `token` is in the green list following `previous`.
+    pub fn is_green(&self, previous: u32, token: u32) -> bool {
+        let seed = self.config.key ^ (u64::from(previous) << 32 | u64::from(token));
+        // The top 53 bits, uniform in [0, 1).
+        let draw = (splitmix64(seed) >> 11) as f64 / (1u64 << 53) as f64;
+        draw < self.config.gamma
+    }
+
+    /// Test `tokens`, the token ids of a text, for the watermark. The first token is only the
+    /// previous token of the second one.
+    pub fn detect(&self, tokens: &[u32]) -> WatermarkDetection {
+        // Repeated pairs would count the same draw again, as in repetitive text.
+        let pairs = tokens
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect::<HashSet<_>>();
+        let num_tokens_scored = pairs.len();
+        let num_green_tokens = pairs
+            .iter()
+            .filter(|(previous, token)| self.is_green(*previous, *token))
+            .count();
+        let (green_fraction, z_score) = if num_tokens_scored == 0 {
+            (0.0, 0.0)
+        } else {
+            let gamma = self.config.gamma;
+            let scored = num_tokens_scored as f64;
+            let expected = gamma * scored;
+            let std_dev = (scored * gamma * (1.0 - gamma)).sqrt();
+            (
+                num_green_tokens as f64 / scored,
+                (num_green_tokens as f64 - expected) / std_dev,
+            )
+        };
+        WatermarkDetection {
+            num_tokens_scored,
+            num_green_tokens,
+            green_fraction,
+            z_score,
+        }
+    }
+}
+
+impl LogitsProcessor for Watermark {
+    fn process(&self, context: &LogitsContext, logits: &Tensor) -> candle_core::Result<Tensor> {
+        let Some(&previous) = context.generated.last().or(context.prompt.last()) else {
+            return Ok(logits.clone());
+        };
+        let vocab_size = logits.dim(D::Minus1)?;
+        let bias = (0..vocab_size as u32)
+            .map(|token| {
+                if self.is_green(previous, token) {
+                    self.config.delta
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<_>>();
+        let bias = Tensor::from_vec(bias, vocab_size, logits.device())?;
+        logits.to_dtype(DType::F32)?.broadcast_add(&bias)
+    }
+}
+
+/// SplitMix64 finalizer, every bit of `x` flips about half of the bits of the result.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
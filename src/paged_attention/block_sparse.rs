@@ -0,0 +1,190 @@
+//! Block-sparse attention, of models like Phi-3-small whose sparse layers attend a few blocks of
+//! the keys only: the `num_local_blocks` blocks ending with the block of the query, and every
+//! `vert_stride`-th block before them, shifted from head to head unless every head has the same
+//! pattern. Attending these layers densely would hold the scores of the whole sequence, which is
+//! what the sparse pattern lets long contexts do without.
+use candle_core::{DType, Result, Tensor};
+use serde::Deserialize;
+
+use crate::openai::responses::APIError;
+
+/// The sparse attention of a model, as in its config.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct BlockSparseConfig {
+    /// Tokens of a block of the pattern, unrelated to the blocks of the KV cache.
+    pub block_size: usize,
+    pub num_local_blocks: usize,
+    pub vert_stride: usize,
+    /// Every head attends the same blocks, else the vertical blocks shift from head to head.
+    pub homo_head_pattern: bool,
+    /// Every `n`-th layer attends densely, `None` when every layer is sparse.
+    pub dense_every_n_layers: Option<usize>,
+}
+
+impl BlockSparseConfig {
+    pub fn verify(&self) -> std::result::Result<(), APIError> {
+        if self.block_size == 0 || self.num_local_blocks == 0 || self.vert_stride == 0 {
+            return Err(APIError::new(format!(
+                "Block-sparse attention needs blocks of at least one token, at least one local \
+                 block and a vertical stride of at least one, got {self:?}."
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn is_sparse(&self, layer: usize) -> bool {
+        !self
+            .dense_every_n_layers
+            .is_some_and(|n| n > 0 && (layer + 1) % n == 0)
+    }
+
+    /// Pattern of each of `num_layers` layers of `num_heads` heads, `None` for the dense ones.
+    pub fn layer_patterns(
+        &self,
+        num_layers: usize,
+        num_heads: usize,
+    ) -> Vec<Option<BlockSparsePattern>> {
+        let step = (self.vert_stride / num_heads).max(1);
+        let pattern = BlockSparsePattern {
+            block_size: self.block_size,
+            num_local_blocks: self.num_local_blocks,
+            vert_stride: self.vert_stride,
+            head_offsets: (0..num_heads)
+                .map(|head| match self.homo_head_pattern {
+                    true => 0,
+                    false => head * step % self.vert_stride,
+                })
+                .collect(),
+        };
+        (0..num_layers)
+            .map(|layer| self.is_sparse(layer).then(|| pattern.clone()))
+            .collect()
+    }
+}
+
+/// The blocks each head of a sparse layer attends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockSparsePattern {
+    block_size: usize,
+    num_local_blocks: usize,
+    vert_stride: usize,
+    /// Shift of the vertical blocks of each head.
+    head_offsets: Vec<usize>,
+}
+
+impl BlockSparsePattern {
+    /// Whether `head` attends the key at `key` from the query at `query`, both cache indices.
+    pub fn attends(&self, head: usize, query: usize, key: usize) -> bool {
+        key <= query
+            && self.attends_block(
+                self.head_offsets[head],
+                query / self.block_size,
+                key / self.block_size,
+            )
+    }
+
+    fn attends_block(&self, offset: usize, query_block: usize, key_block: usize) -> bool {
+        query_block - key_block < self.num_local_blocks
+            || (key_block + offset + 1) % self.vert_stride == 0
+    }
+
+    /// The heads of each pattern, by the shift of their vertical blocks.
+    fn head_groups(&self) -> Vec<(usize, Vec<u32>)> {
+        let mut groups: Vec<(usize, Vec<u32>)> = Vec::new();
+        for (head, &offset) in self.head_offsets.iter().enumerate() {
+            match groups.iter_mut().find(|(other, _)| *other == offset) {
+                Some((_, heads)) => heads.push(head as u32),
+                None => groups.push((offset, vec![head as u32])),
+            }
+        }
+        groups
+    }
+
+    /// Keys before `end` of the blocks the heads shifted by `offset` attend from `query_block`.
+    fn attended_keys(&self, offset: usize, query_block: usize, end: usize) -> Vec<u32> {
+        (0..=query_block)
+            .filter(|&key_block| self.attends_block(offset, query_block, key_block))
+            .flat_map(|key_block| {
+                let start = key_block * self.block_size;
+                start as u32..(start + self.block_size).min(end) as u32
+            })
+            .collect()
+    }
+}
+
+/// Attention of the queries of a sequence at cache indices `query_start..`, of shape
+/// `(num_heads, num_queries, head_size)`, over its keys and values from index 0, of shape
+/// `(num_kv_heads, num_keys, head_size)`, through `pattern`. The queries of a block are attended
+/// together, each group of heads sharing a pattern over the keys it attends only, so that the
+/// scores are bounded by a block of queries and the blocks they attend.
+pub fn block_sparse_attention(
+    query: &Tensor,
+    key: &Tensor,
+    value: &Tensor,
+    query_start: usize,
+    pattern: &BlockSparsePattern,
+    scale: f32,
+) -> Result<Tensor> {
+    let (num_heads, num_queries, _) = query.dims3()?;
+    let num_kv_heads = key.dim(0)?;
+    if num_heads != pattern.head_offsets.len() || num_heads % num_kv_heads != 0 {
+        candle_core::bail!(
+            "block-sparse attention of {num_heads} heads over {num_kv_heads} KV heads with a \
+             pattern of {} heads",
+            pattern.head_offsets.len()
+        )
+    }
+    let queries_per_kv = (num_heads / num_kv_heads) as u32;
+    let device = query.device();
+    let groups = pattern
+        .head_groups()
+        .into_iter()
+        .map(|(offset, heads)| {
+            let kv_heads = heads
+                .iter()
+                .map(|head| head / queries_per_kv)
+                .collect::<Vec<_>>();
+            let head_ids = Tensor::new(heads.as_slice(), device)?;
+            let kv_head_ids = Tensor::new(kv_heads.as_slice(), device)?;
+            Ok((offset, heads, head_ids, kv_head_ids))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let end = query_start + num_queries;
+    let mut tiles = vec![Vec::new(); num_heads];
+    let mut start = query_start;
+    while start < end {
+        let query_block = start / pattern.block_size;
+        let tile_end = ((query_block + 1) * pattern.block_size).min(end);
+        let query_tile = query.narrow(1, start - query_start, tile_end - start)?;
+        for (offset, heads, head_ids, kv_head_ids) in &groups {
+            let keys = pattern.attended_keys(*offset, query_block, tile_end);
+            // Only the block of the queries has keys past some of them.
+            let mask = (start..tile_end)
+                .flat_map(|i| {
+                    keys.iter().map(move |&j| match j as usize > i {
+                        true => f32::NEG_INFINITY,
+                        false => 0.,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mask = Tensor::from_vec(mask, (tile_end - start, keys.len()), device)?;
+            let keys = Tensor::new(keys.as_slice(), device)?;
+            let key = key.index_select(kv_head_ids, 0)?.index_select(&keys, 1)?;
+            let value = value.index_select(kv_head_ids, 0)?.index_select(&keys, 1)?;
+            let att = (query_tile.index_select(head_ids, 0)?.matmul(&key.t()?)? * scale as f64)?;
+            let att = att.to_dtype(DType::F32)?.broadcast_add(&mask)?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?.to_dtype(value.dtype())?;
+            let output = att.matmul(&value)?;
+            for (i, &head) in heads.iter().enumerate() {
+                tiles[head as usize].push(output.get(i)?);
+            }
+        }
+        start = tile_end;
+    }
+    let heads = tiles
+        .iter()
+        .map(|tiles| Tensor::cat(tiles, 0))
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::stack(&heads, 0)
+}
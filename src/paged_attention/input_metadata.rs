@@ -1,6 +1,7 @@
 use candle_core::Tensor;
 
-use super::attn_bias::AttentionBiasBlockDiagonal;
+use super::{attn_bias::AttentionBiasBlockDiagonal, block_sparse::BlockSparsePattern};
+use crate::{openai::control_vectors::Steering, scheduler::state_cache::RecurrentStateInput};
 
 pub struct InputMetadata {
     pub prompt_lens: Vec<usize>,
@@ -11,6 +12,41 @@ pub struct InputMetadata {
     pub attn_bias: Option<Box<dyn AttentionBiasBlockDiagonal>>,
     pub is_prompt: bool,
     pub kv_cache_dtype: String,
+    /// Id of the sequence of each row of the batch.
+    pub seq_ids: Vec<usize>,
+    /// Rows of the draft trees of a decode step, in order.
+    pub draft_trees: Vec<DraftTreeAttention>,
+    /// States of the recurrent layers of a hybrid model, `None` for other models.
+    pub recurrent_state: Option<RecurrentStateInput>,
+    /// Queries of the prompts attended at a time during prefill, see `chunks_prefill`.
+    pub prefill_chunk_size: Option<usize>,
+    /// What the control vectors of the sequences add to the output of the layers they steer,
+    /// `None` when none of them steers.
+    pub steering: Option<Steering>,
+    /// Sparsity patterns of the layers of a block-sparse model, `None` for dense models.
+    pub block_sparse: Option<BlockSparseInput>,
+}
+
+/// What the sparse layers of a model attend during a step.
+pub struct BlockSparseInput {
+    /// Pattern of each layer, `None` for the dense ones.
+    pub layers: Vec<Option<BlockSparsePattern>>,
+    /// Block table and context length of each row of a decode step, whose keys are gathered
+    /// from the cache. Empty during prefill.
+    pub rows: Vec<(Vec<u32>, usize)>,
+}
+
+/// Rows of the nodes of a draft tree: the last token of a sequence followed by the tokens
+/// proposed for it. Each row attends the KV cache of the sequence up to the root and then only
+/// the nodes it continues, not all the slots before its own.
+pub struct DraftTreeAttention {
+    /// Row of the root.
+    pub first_row: usize,
+    pub block_table: Vec<u32>,
+    /// Cache index of the root, the number of slots before it every node attends.
+    pub context_len: usize,
+    /// `(num_nodes, num_nodes)` f32 bias, 0 where a node attends another and -inf elsewhere.
+    pub mask: Tensor,
 }
 
 impl InputMetadata {
@@ -38,6 +74,42 @@ impl InputMetadata {
             attn_bias: None,
             is_prompt,
             kv_cache_dtype,
+            seq_ids: vec![],
+            draft_trees: vec![],
+            recurrent_state: None,
+            prefill_chunk_size: None,
+            steering: None,
+            block_sparse: None,
+        }
+    }
+
+    /// Whether prompts of `seq_len` tokens are attended a tile of `prefill_chunk_size` queries
+    /// at a time, in which case the model leaves out the mask of the whole prompt.
+    pub fn chunks_prefill(&self, seq_len: usize) -> bool {
+        self.is_prompt
+            && self
+                .prefill_chunk_size
+                .is_some_and(|chunk_size| seq_len > chunk_size)
+    }
+
+    /// Whether the model builds the causal mask of a step of `seq_len` tokens per sequence.
+    /// Decode steps attend every cached token, and long prompts are attended a tile of queries
+    /// at a time, without the mask of the whole prompt.
+    pub fn needs_prompt_mask(&self, seq_len: usize) -> bool {
+        seq_len > 1 && !self.chunks_prefill(seq_len)
+    }
+
+    /// Sparsity pattern of `layer`, `None` if it attends densely.
+    pub fn block_sparse_pattern(&self, layer: usize) -> Option<&BlockSparsePattern> {
+        self.block_sparse.as_ref()?.layers.get(layer)?.as_ref()
+    }
+
+    /// `xs`, the output of decoder layer `layer`, with the control vectors of the sequences
+    /// added. Models call it after each layer.
+    pub fn steer(&self, layer: usize, xs: Tensor) -> candle_core::Result<Tensor> {
+        match &self.steering {
+            Some(steering) => steering.apply(layer, xs),
+            None => Ok(xs),
         }
     }
 }
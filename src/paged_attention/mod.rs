@@ -1,9 +1,13 @@
 use candle_core::{DType, Device, Result, Tensor};
 
-use crate::backend::{paged_attention, reshape_and_cache};
+use crate::backend::{paged_attention, reshape_and_cache, KvCacheLayout};
 
-use self::input_metadata::InputMetadata;
+use self::{
+    block_sparse::{block_sparse_attention, BlockSparsePattern},
+    input_metadata::{DraftTreeAttention, InputMetadata},
+};
 mod attn_bias;
+pub mod block_sparse;
 pub(crate) mod input_metadata;
 pub(crate) mod utils;
 
@@ -18,6 +22,8 @@ pub struct PagedAttention {
     sliding_window: Option<usize>,
     num_queries_per_kv: usize,
     alibi_slopes: Option<Tensor>,
+    /// Layer of the attention, whose sparsity pattern it looks up in the input metadata.
+    layer: Option<usize>,
 }
 
 impl PagedAttention {
@@ -45,9 +51,16 @@ impl PagedAttention {
             sliding_window,
             num_queries_per_kv,
             alibi_slopes,
+            layer: None,
         })
     }
 
+    /// The attention of decoder layer `layer`, sparse if the metadata has a pattern for it.
+    pub fn with_layer(mut self, layer: usize) -> Self {
+        self.layer = Some(layer);
+        self
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[allow(unused_variables)]
     /// query: shape = [batch_size, seq_len, num_heads * head_size]
@@ -78,37 +91,31 @@ impl PagedAttention {
         let (batch_size, attention_heads, seq_len, head_size) = query.shape().dims4()?;
         let (_, key_value_heads, _, _) = key.shape().dims4()?;
 
-        let att = match attention_mask {
-            None => None,
-            Some(mask) => {
+        let sparse = self
+            .layer
+            .and_then(|layer| input_metadata.block_sparse_pattern(layer));
+        let att = match (attention_mask, sparse) {
+            // Prompts of a sparse layer, which ignores the dense mask.
+            (_, Some(pattern)) if seq_len > 1 => {
+                Some(self.block_sparse_prefill(query, key, value, pattern)?)
+            }
+            // A long prompt, attended a tile of queries at a time.
+            (None, _) if seq_len > 1 => Some(self.chunked_prefill_attention(
+                query,
+                key,
+                value,
+                input_metadata.prefill_chunk_size.unwrap_or(seq_len),
+            )?),
+            (None, _) => None,
+            (Some(mask), _) => {
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
                 //and remove redundant repeat_kv in decoding stage
-                let att = if key_value_heads != attention_heads {
-                    let key_repeat = if key_value_heads == 1 {
-                        key.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
-                    } else {
-                        Tensor::cat(&vec![&key; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
-                    };
-                    (query.matmul(&key_repeat.t()?.contiguous()?)? * self.scale as f64)?
-                } else {
-                    (query.matmul(&key.t()?)? * self.scale as f64)?
-                };
-
+                let att =
+                    (query.matmul(&self.repeat_kv(key)?.t()?.contiguous()?)? * self.scale as f64)?;
                 let att = att.broadcast_add(mask)?;
                 let att = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?
                     .to_dtype(att.dtype())?;
-                if key_value_heads != attention_heads {
-                    let value_repeat = if key_value_heads == 1 {
-                        value.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
-                    } else {
-                        Tensor::cat(&vec![&value; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
-                    };
-                    Some(att.matmul(&value_repeat.contiguous()?)?)
-                } else {
-                    Some(att.matmul(&value)?)
-                }
+                Some(att.matmul(&self.repeat_kv(value)?.contiguous()?)?)
             }
         };
 
@@ -151,6 +158,20 @@ impl PagedAttention {
             //prefill result
             return Ok(att.unwrap());
         }
+        if let Some(pattern) = sparse {
+            if !input_metadata.draft_trees.is_empty() {
+                candle_core::bail!("draft trees are not supported by block-sparse attention")
+            }
+            let rows = &input_metadata.block_sparse.as_ref().unwrap().rows;
+            return block_sparse_decode(
+                &query,
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
+                rows,
+                pattern,
+                self.scale,
+            );
+        }
         //  Args:
         //  output: shape = [num_generation_tokens, num_heads, head_size]
         //
@@ -165,7 +186,7 @@ impl PagedAttention {
         //  input_metadata: metadata for paged attention.
         //
         //  alibi_slopes: shape = [num_heads]
-        paged_attention(
+        let output = paged_attention(
             &query,
             &key_cache.as_ref().unwrap(),
             &value_cache.as_ref().unwrap(),
@@ -173,6 +194,257 @@ impl PagedAttention {
             &input_metadata.context_lens.as_ref().unwrap(),
             input_metadata.max_context_len.unwrap(),
             self.scale,
+        )?;
+        if input_metadata.draft_trees.is_empty() {
+            return Ok(output);
+        }
+        attend_draft_trees(
+            &query,
+            output,
+            key_cache.as_ref().unwrap(),
+            value_cache.as_ref().unwrap(),
+            &input_metadata.draft_trees,
+            self.scale,
         )
     }
 }
+
+impl PagedAttention {
+    /// `x`, `[batch_size, num_kv_heads, seq_len, head_size]`, with each KV head repeated for
+    /// the query heads attending it.
+    fn repeat_kv(&self, x: &Tensor) -> Result<Tensor> {
+        let (batch_size, key_value_heads, seq_len, head_size) = x.dims4()?;
+        if key_value_heads == self.num_attention_heads {
+            Ok(x.clone())
+        } else if key_value_heads == 1 {
+            x.broadcast_as((batch_size, self.num_attention_heads, seq_len, head_size))
+        } else {
+            Tensor::cat(&vec![x; self.num_attention_heads / key_value_heads], 2)?.reshape((
+                batch_size,
+                self.num_attention_heads,
+                seq_len,
+                head_size,
+            ))
+        }
+    }
+
+    /// Attention of the prompts, `[batch_size, num_heads, seq_len, head_size]`, through the
+    /// sparsity `pattern` of the layer. Shapes are those of `forward`.
+    fn block_sparse_prefill(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        pattern: &BlockSparsePattern,
+    ) -> Result<Tensor> {
+        let outputs = (0..query.dim(0)?)
+            .map(|i| {
+                block_sparse_attention(
+                    &query.get(i)?.contiguous()?,
+                    &key.get(i)?.contiguous()?,
+                    &value.get(i)?.contiguous()?,
+                    0,
+                    pattern,
+                    self.scale,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&outputs, 0)
+    }
+
+    /// Causal attention of the prompts, as with the mask of the model but `chunk_size` queries
+    /// at a time: the scores of a tile are `chunk_size` rows of the keys up to its last query,
+    /// so that the activations of a long prompt grow with its length rather than its square.
+    /// The keys are the ones just written to the cache. Shapes are those of `forward`.
+    fn chunked_prefill_attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        chunk_size: usize,
+    ) -> Result<Tensor> {
+        let seq_len = query.dim(2)?;
+        let window = self.sliding_window;
+        let (key, value) = (self.repeat_kv(key)?, self.repeat_kv(value)?);
+        let mut tiles = Vec::new();
+        for start in (0..seq_len).step_by(chunk_size.max(1)) {
+            let end = (start + chunk_size).min(seq_len);
+            // Keys before the window of the first query of the tile are attended by none.
+            let first_key = window.map_or(0, |window| start.saturating_sub(window));
+            let mask = (start..end)
+                .flat_map(|i| {
+                    (first_key..end).map(move |j| {
+                        if i < j || window.is_some_and(|window| j + window < i) {
+                            f32::NEG_INFINITY
+                        } else {
+                            0.
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mask = Tensor::from_vec(mask, (end - start, end - first_key), query.device())?;
+            let key = key.narrow(2, first_key, end - first_key)?.contiguous()?;
+            let value = value.narrow(2, first_key, end - first_key)?.contiguous()?;
+            let att =
+                (query.narrow(2, start, end - start)?.matmul(&key.t()?)? * self.scale as f64)?;
+            let att = att.to_dtype(DType::F32)?.broadcast_add(&mask)?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?.to_dtype(value.dtype())?;
+            tiles.push(att.matmul(&value)?);
+        }
+        Tensor::cat(&tiles, 2)
+    }
+}
+
+/// `output` of the paged attention kernel, `(num_rows, num_heads, head_size)`, with the rows of
+/// the `draft_trees` attended again through their tree mask. The kernel only attends contiguous
+/// slots, so the nodes of a tree attend the keys and values gathered from the cache instead.
+fn attend_draft_trees(
+    query: &Tensor,
+    output: Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    draft_trees: &[DraftTreeAttention],
+    scale: f32,
+) -> Result<Tensor> {
+    let (num_rows, num_heads, head_size) = query.dims3()?;
+    let mut rows = Vec::new();
+    let mut row = 0;
+    for tree in draft_trees {
+        let num_nodes = tree.mask.dim(0)?;
+        if tree.first_row > row {
+            rows.push(output.narrow(0, row, tree.first_row - row)?);
+        }
+        let len = tree.context_len + num_nodes;
+        let (key, value) = gather_kv_cache(
+            key_cache,
+            value_cache,
+            &tree.block_table,
+            0,
+            len,
+            query.dtype(),
+        )?;
+        let num_kv_heads = key.dim(1)?;
+        // [len, num_kv_heads, head_size] -> [num_heads, len, head_size]
+        let repeat_kv = |x: Tensor| {
+            x.unsqueeze(2)?
+                .expand((len, num_kv_heads, num_heads / num_kv_heads, head_size))?
+                .reshape((len, num_heads, head_size))?
+                .transpose(0, 1)?
+                .contiguous()
+        };
+        let (key, value) = (repeat_kv(key)?, repeat_kv(value)?);
+        let query = query
+            .narrow(0, tree.first_row, num_nodes)?
+            .transpose(0, 1)?
+            .contiguous()?;
+        let att = (query.matmul(&key.t()?)? * scale as f64)?.to_dtype(DType::F32)?;
+        // Every node attends the whole context before the root.
+        let context = Tensor::zeros((num_nodes, tree.context_len), DType::F32, att.device())?;
+        let mask = Tensor::cat(&[&context, &tree.mask], 1)?;
+        let att = candle_nn::ops::softmax_last_dim(&att.broadcast_add(&mask)?)?;
+        let att = att.to_dtype(value.dtype())?;
+        rows.push(att.matmul(&value)?.transpose(0, 1)?);
+        row = tree.first_row + num_nodes;
+    }
+    if row < num_rows {
+        rows.push(output.narrow(0, row, num_rows - row)?);
+    }
+    Tensor::cat(&rows, 0)
+}
+
+/// Attention of the rows of a decode step, `(num_rows, num_heads, head_size)`, through the
+/// sparsity `pattern` of the layer. The kernel attends all the slots of a row, so the keys and
+/// values of each row are gathered from the cache and only the attended ones are scored.
+fn block_sparse_decode(
+    query: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    rows: &[(Vec<u32>, usize)],
+    pattern: &BlockSparsePattern,
+    scale: f32,
+) -> Result<Tensor> {
+    let outputs = rows
+        .iter()
+        .enumerate()
+        .map(|(row, (block_table, context_len))| {
+            let (key, value) = gather_kv_cache(
+                key_cache,
+                value_cache,
+                block_table,
+                0,
+                *context_len,
+                query.dtype(),
+            )?;
+            let output = block_sparse_attention(
+                &query.get(row)?.unsqueeze(1)?,
+                &key.transpose(0, 1)?.contiguous()?,
+                &value.transpose(0, 1)?.contiguous()?,
+                context_len - 1,
+                pattern,
+                scale,
+            )?;
+            output.squeeze(1)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::stack(&outputs, 0)
+}
+
+/// Write `key` and `value`, of shape `(num_tokens, num_kv_heads, head_size)`, to the cache slots
+/// `slot_mapping`, for models attending over the cache without the paged attention kernel.
+pub fn write_kv_cache(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    reshape_and_cache(
+        &key.contiguous()?,
+        &value.contiguous()?,
+        key_cache,
+        value_cache,
+        slot_mapping,
+    )
+}
+
+/// Keys and values of the cache indices `start..start + len` of a sequence whose blocks are
+/// `block_table`, of shape `(len, num_kv_heads, head_size)`, in `dtype` whichever dtype the cache
+/// holds them in.
+pub fn gather_kv_cache(
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_table: &[u32],
+    start: usize,
+    len: usize,
+    dtype: DType,
+) -> Result<(Tensor, Tensor)> {
+    let layout = KvCacheLayout::of_caches(key_cache.shape(), value_cache.shape())?;
+    let (num_kv_heads, head_size, block_size) =
+        (layout.num_heads(), layout.head_size(), layout.block_size());
+    let first = start / block_size;
+    let last = (start + len).div_ceil(block_size);
+    let Some(blocks) = block_table.get(first..last) else {
+        candle_core::bail!(
+            "cache indices {start}..{} are out of the {} blocks of the sequence",
+            start + len,
+            block_table.len()
+        )
+    };
+    let num_slots = blocks.len() * block_size;
+    let blocks = Tensor::new(blocks, key_cache.device())?;
+    // [blocks, num_kv_heads, head_size / x, block_size, x] -> [slots, num_kv_heads, head_size]
+    let key = key_cache
+        .index_select(&blocks, 0)?
+        .permute((0, 3, 1, 2, 4))?
+        .reshape((num_slots, num_kv_heads, head_size))?;
+    // [blocks, num_kv_heads, head_size, block_size] -> [slots, num_kv_heads, head_size]
+    let value = value_cache
+        .index_select(&blocks, 0)?
+        .permute((0, 3, 1, 2))?
+        .reshape((num_slots, num_kv_heads, head_size))?;
+    let offset = start - first * block_size;
+    Ok((
+        key.narrow(0, offset, len)?.to_dtype(dtype)?,
+        value.narrow(0, offset, len)?.to_dtype(dtype)?,
+    ))
+}
@@ -0,0 +1,168 @@
+//! Capacity planning of a deployment from the config of a model alone: how much GPU memory its
+//! weights take, how many KV cache blocks fit in the rest, and so how long a context and how
+//! many sequences at once can be served, before downloading or loading any weight.
+use crate::openai::models::Config;
+use crate::openai::responses::APIError;
+use crate::{kvcache_bytes_per_token, num_kvcache_blocks, SIZE_IN_MB};
+use candle_core::DType;
+use std::fmt;
+
+/// Quantization of the weights of the layers, the embeddings and the LM head stay in the dtype
+/// of the model as quantized checkpoints usually keep them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Quantization {
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q6k")]
+    Q6K,
+    #[value(name = "q5k")]
+    Q5K,
+    #[value(name = "q4k")]
+    Q4K,
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q3k")]
+    Q3K,
+    #[value(name = "q2k")]
+    Q2K,
+}
+
+impl Quantization {
+    /// Bits per weight of the GGML block formats, scales included.
+    pub fn bits_per_weight(&self) -> f64 {
+        match self {
+            Self::Q8_0 => 8.5,
+            Self::Q6K => 6.5625,
+            Self::Q5K => 5.5,
+            Self::Q4K | Self::Q4_0 => 4.5,
+            Self::Q3K => 3.4375,
+            Self::Q2K => 2.625,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanConfig {
+    /// Dtype the model is served in.
+    pub dtype: DType,
+    pub quantization: Option<Quantization>,
+    /// Memory of the GPU (MB).
+    pub gpu_mem: usize,
+    /// GPU memory left for the activations and the CUDA context (MB).
+    pub reserved_mem: usize,
+    pub block_size: usize,
+    pub max_num_seqs: usize,
+    /// Tokens (prompt and generated) of each sequence the concurrency is planned for, the max
+    /// model length if not set.
+    pub context_len: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeploymentPlan {
+    pub num_params: usize,
+    /// Memory of the weights (MB).
+    pub weight_mem: usize,
+    /// Memory left for the KV cache, the `kvcache_mem_gpu` to serve with (MB).
+    pub kvcache_mem: usize,
+    pub kvcache_bytes_per_token: usize,
+    pub num_gpu_blocks: usize,
+    pub max_model_len: usize,
+    /// Longest context a sequence alone in the KV cache can reach.
+    pub max_context_len: usize,
+    pub context_len: usize,
+    /// Sequences of `context_len` tokens the KV cache holds at once, at most `max_num_seqs`.
+    pub max_concurrent_seqs: usize,
+}
+
+/// Parameters of the decoder layers, the embeddings and the LM head of a model. The MLP is
+/// counted as gated, as in all supported models but phi2 which it overestimates; norms and
+/// biases are left out.
+pub fn estimate_num_params(config: &Config) -> (usize, usize) {
+    let head_size = config.get_head_size();
+    let attention = 2 * config.hidden_size * config.num_attention_heads * head_size
+        + 2 * config.hidden_size * config.num_key_value_heads * head_size;
+    let mlp = 3 * config.hidden_size * config.intermediate_size;
+    let layers = config.num_hidden_layers * (attention + mlp);
+    let embeddings = config.vocab_size * config.hidden_size;
+    let lm_head = if config.tie_word_embeddings {
+        0
+    } else {
+        embeddings
+    };
+    (layers, embeddings + lm_head)
+}
+
+/// Plan the deployment of the model of `config` on one GPU.
+pub fn plan(config: &Config, plan_config: &PlanConfig) -> Result<DeploymentPlan, APIError> {
+    let (layer_params, embedding_params) = estimate_num_params(config);
+    let dsize = plan_config.dtype.size_in_bytes() as f64;
+    let layer_bytes = match plan_config.quantization {
+        Some(quantization) => layer_params as f64 * quantization.bits_per_weight() / 8.,
+        None => layer_params as f64 * dsize,
+    };
+    let weight_bytes = layer_bytes + embedding_params as f64 * dsize;
+    let weight_mem = (weight_bytes / SIZE_IN_MB as f64).ceil() as usize;
+    if weight_mem + plan_config.reserved_mem >= plan_config.gpu_mem {
+        return Err(APIError::new(format!(
+            "The weights ({weight_mem} MB) and the reserved memory ({} MB) leave no room for the KV cache in {} MB.",
+            plan_config.reserved_mem, plan_config.gpu_mem
+        )));
+    }
+    let kvcache_mem = plan_config.gpu_mem - weight_mem - plan_config.reserved_mem;
+    let block_size = plan_config.block_size;
+    let num_gpu_blocks = num_kvcache_blocks(config, block_size, kvcache_mem);
+    if num_gpu_blocks == 0 {
+        return Err(APIError::new(format!(
+            "{kvcache_mem} MB of KV cache cannot hold a block of {block_size} tokens."
+        )));
+    }
+
+    let max_model_len = config.max_seq_len;
+    let context_len = plan_config.context_len.unwrap_or(max_model_len);
+    if context_len == 0 || context_len > max_model_len {
+        return Err(APIError::new(format!(
+            "The context length must be between 1 and the max model length {max_model_len}, got {context_len}."
+        )));
+    }
+    let blocks_per_seq = context_len.div_ceil(block_size);
+    Ok(DeploymentPlan {
+        num_params: layer_params + embedding_params,
+        weight_mem,
+        kvcache_mem,
+        kvcache_bytes_per_token: kvcache_bytes_per_token(config),
+        num_gpu_blocks,
+        max_model_len,
+        max_context_len: max_model_len.min(num_gpu_blocks * block_size),
+        context_len,
+        max_concurrent_seqs: (num_gpu_blocks / blocks_per_seq).min(plan_config.max_num_seqs),
+    })
+}
+
+impl fmt::Display for DeploymentPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Parameters:                   {:.2}B",
+            self.num_params as f64 / 1e9
+        )?;
+        writeln!(f, "Weights (MB):                 {}", self.weight_mem)?;
+        writeln!(
+            f,
+            "KV cache (MB):                {} (--kvcache-mem-gpu {})",
+            self.kvcache_mem, self.kvcache_mem
+        )?;
+        writeln!(
+            f,
+            "KV cache per token (KB):      {:.1}",
+            self.kvcache_bytes_per_token as f64 / 1024.
+        )?;
+        writeln!(f, "KV cache blocks:              {}", self.num_gpu_blocks)?;
+        writeln!(f, "Max model length:             {}", self.max_model_len)?;
+        writeln!(f, "Max context per sequence:     {}", self.max_context_len)?;
+        write!(
+            f,
+            "Max concurrent sequences:     {} of {} tokens",
+            self.max_concurrent_seqs, self.context_len
+        )
+    }
+}
@@ -0,0 +1,564 @@
+//! Python module of the engine, built with the `python` feature (`maturin build --release
+//! --features python`). Requests go to the engine in process, without the HTTP server:
+//!
+//! ```python
+//! from candle_vllm import LLMEngine, SamplingParams
+//!
+//! engine = LLMEngine("llama3", weight_path="/models/llama3-8b/")
+//! outputs = engine.generate(["Explain how to best learn Rust."], SamplingParams(max_tokens=64))
+//! async for output in engine.stream("Explain how to best learn Rust."):
+//!     print(output.text, end="")
+//! ```
+use crate::openai::{
+    pipelines::{llm_engine::LLMEngine, weights::DEFAULT_WEIGHT_BUFFER_MEM, worker::WorkerConfig},
+    requests::StopTokens,
+    responses::APIError,
+    sampling_params::{EarlyStoppingCondition, SamplingParams},
+    streaming::ChatResponse,
+    PipelineConfig,
+};
+use crate::scheduler::SchedulerConfig;
+use crate::{
+    detect_model, get_cache_config, get_dtype, get_kv_cache_dtype, get_model_loader,
+    get_model_paths, ModelSelected,
+};
+use clap::Parser;
+use flume::Receiver;
+use pyo3::exceptions::{PyRuntimeError, PyStopAsyncIteration};
+use pyo3::prelude::*;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokenizers::{Encoding, Tokenizer};
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+
+impl From<APIError> for PyErr {
+    fn from(e: APIError) -> Self {
+        PyRuntimeError::new_err(e.to_string())
+    }
+}
+
+/// The model subcommand of the CLI, e.g. `llama3` or `phi3 --top-k 40`.
+#[derive(Parser, Debug)]
+struct ModelArgs {
+    #[command(subcommand)]
+    model: Option<ModelSelected>,
+}
+
+/// Sampling parameters of a request, the ones left to `None` take the defaults of the model.
+#[pyclass(name = "SamplingParams")]
+#[derive(Clone, Debug)]
+pub struct PySamplingParams {
+    #[pyo3(get, set)]
+    pub n: usize,
+    #[pyo3(get, set)]
+    pub best_of: Option<usize>,
+    #[pyo3(get, set)]
+    pub temperature: Option<f32>,
+    #[pyo3(get, set)]
+    pub top_p: f32,
+    #[pyo3(get, set)]
+    pub top_k: isize,
+    #[pyo3(get, set)]
+    pub max_tokens: Option<usize>,
+    #[pyo3(get, set)]
+    pub min_tokens: usize,
+    #[pyo3(get, set)]
+    pub presence_penalty: f32,
+    #[pyo3(get, set)]
+    pub frequency_penalty: f32,
+    #[pyo3(get, set)]
+    pub repetition_penalty: Option<f32>,
+    #[pyo3(get, set)]
+    pub stop: Option<Vec<String>>,
+    #[pyo3(get, set)]
+    pub stop_token_ids: Vec<usize>,
+    #[pyo3(get, set)]
+    pub ignore_eos: bool,
+    #[pyo3(get, set)]
+    pub skip_special_tokens: bool,
+    #[pyo3(get, set)]
+    pub spaces_between_special_tokens: bool,
+    #[pyo3(get, set)]
+    pub timeout: Option<f64>,
+    #[pyo3(get, set)]
+    pub token_healing: bool,
+    #[pyo3(get, set)]
+    pub guided_choice: Option<Vec<String>>,
+    #[pyo3(get, set)]
+    pub json_mode: bool,
+}
+
+#[pymethods]
+impl PySamplingParams {
+    #[new]
+    #[pyo3(signature = (
+        n = 1,
+        best_of = None,
+        temperature = None,
+        top_p = 1.0,
+        top_k = -1,
+        max_tokens = None,
+        min_tokens = 0,
+        presence_penalty = 0.0,
+        frequency_penalty = 0.0,
+        repetition_penalty = None,
+        stop = None,
+        stop_token_ids = vec![],
+        ignore_eos = false,
+        skip_special_tokens = true,
+        spaces_between_special_tokens = true,
+        timeout = None,
+        token_healing = false,
+        guided_choice = None,
+        json_mode = false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        n: usize,
+        best_of: Option<usize>,
+        temperature: Option<f32>,
+        top_p: f32,
+        top_k: isize,
+        max_tokens: Option<usize>,
+        min_tokens: usize,
+        presence_penalty: f32,
+        frequency_penalty: f32,
+        repetition_penalty: Option<f32>,
+        stop: Option<Vec<String>>,
+        stop_token_ids: Vec<usize>,
+        ignore_eos: bool,
+        skip_special_tokens: bool,
+        spaces_between_special_tokens: bool,
+        timeout: Option<f64>,
+        token_healing: bool,
+        guided_choice: Option<Vec<String>>,
+        json_mode: bool,
+    ) -> Self {
+        Self {
+            n,
+            best_of,
+            temperature,
+            top_p,
+            top_k,
+            max_tokens,
+            min_tokens,
+            presence_penalty,
+            frequency_penalty,
+            repetition_penalty,
+            stop,
+            stop_token_ids,
+            ignore_eos,
+            skip_special_tokens,
+            spaces_between_special_tokens,
+            timeout,
+            token_healing,
+            guided_choice,
+            json_mode,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
+impl Default for PySamplingParams {
+    fn default() -> Self {
+        Self {
+            n: 1,
+            best_of: None,
+            temperature: None,
+            top_p: 1.0,
+            top_k: -1,
+            max_tokens: None,
+            min_tokens: 0,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            repetition_penalty: None,
+            stop: None,
+            stop_token_ids: vec![],
+            ignore_eos: false,
+            skip_special_tokens: true,
+            spaces_between_special_tokens: true,
+            timeout: None,
+            token_healing: false,
+            guided_choice: None,
+            json_mode: false,
+        }
+    }
+}
+
+impl PySamplingParams {
+    fn to_sampling_params(
+        &self,
+        pipeline_config: &PipelineConfig,
+    ) -> Result<SamplingParams, APIError> {
+        let mut sampling_params = SamplingParams::new(
+            self.n,
+            self.best_of,
+            self.presence_penalty,
+            self.frequency_penalty,
+            self.repetition_penalty.unwrap_or(pipeline_config.penalty),
+            self.temperature.unwrap_or(pipeline_config.temperature),
+            self.top_p,
+            self.top_k,
+            false,
+            1.0,
+            EarlyStoppingCondition::UnlikelyBetterCandidates,
+            self.stop.clone().map(StopTokens::Multi),
+            self.stop_token_ids.clone(),
+            self.ignore_eos,
+            self.max_tokens
+                .unwrap_or(pipeline_config.default_max_tokens),
+            None,
+            None,
+            self.skip_special_tokens,
+        )?;
+        sampling_params.set_timeout(self.timeout)?;
+        sampling_params.set_min_tokens(Some(self.min_tokens))?;
+        sampling_params.token_healing = self.token_healing;
+        sampling_params.spaces_between_special_tokens = self.spaces_between_special_tokens;
+        sampling_params.set_guided_choice(self.guided_choice.clone())?;
+        sampling_params.set_json_mode(self.json_mode)?;
+        // Choices are collected from their stream, before the best `n` could be picked.
+        if sampling_params.best_of != sampling_params.n {
+            return Err(APIError::new_str("`best_of` must be equal to `n`."));
+        }
+        Ok(sampling_params)
+    }
+}
+
+/// One choice of a request: its whole text once generated, or the text of one token when
+/// streamed.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct CompletionOutput {
+    #[pyo3(get)]
+    pub index: usize,
+    #[pyo3(get)]
+    pub text: String,
+    #[pyo3(get)]
+    pub finish_reason: Option<String>,
+}
+
+#[pymethods]
+impl CompletionOutput {
+    fn __repr__(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct RequestOutput {
+    #[pyo3(get)]
+    pub prompt: String,
+    #[pyo3(get)]
+    pub outputs: Vec<CompletionOutput>,
+}
+
+#[pymethods]
+impl RequestOutput {
+    fn __repr__(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
+/// Receive the stream of a request until its end, into the text of each of its `n` choices.
+fn collect_outputs(
+    prompt: String,
+    n: usize,
+    rx: Receiver<ChatResponse>,
+) -> Result<RequestOutput, APIError> {
+    let mut outputs = (0..n)
+        .map(|index| CompletionOutput {
+            index,
+            text: String::new(),
+            finish_reason: None,
+        })
+        .collect::<Vec<_>>();
+    loop {
+        match rx.recv() {
+            Ok(ChatResponse::Chunk(chunk)) => {
+                for choice in chunk.choices {
+                    let Some(output) = outputs.get_mut(choice.index) else {
+                        continue;
+                    };
+                    if let Some(content) = choice.delta.content {
+                        output.text.push_str(&content);
+                    }
+                    if choice.finish_reason.is_some() {
+                        output.finish_reason = choice.finish_reason;
+                    }
+                }
+            }
+            Ok(ChatResponse::Done) => return Ok(RequestOutput { prompt, outputs }),
+            Ok(
+                ChatResponse::InternalError(e)
+                | ChatResponse::ValidationError(e)
+                | ChatResponse::ModelError(e),
+            ) => return Err(APIError::new(e)),
+            Err(_) => return Err(APIError::new_str("The engine dropped the request.")),
+        }
+    }
+}
+
+/// Async iterator over the tokens of a request, as the `CompletionOutput` of their choice.
+#[pyclass]
+pub struct TokenStream {
+    rx: Receiver<ChatResponse>,
+}
+
+#[pymethods]
+impl TokenStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        let rx = self.rx.clone();
+        let next = pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match rx.recv_async().await {
+                Ok(ChatResponse::Chunk(chunk)) => {
+                    let choice = chunk
+                        .choices
+                        .into_iter()
+                        .next()
+                        .ok_or(APIError::new_str("Streamed a chunk without choices."))?;
+                    Ok(CompletionOutput {
+                        index: choice.index,
+                        text: choice.delta.content.unwrap_or_default(),
+                        finish_reason: choice.finish_reason,
+                    })
+                }
+                Ok(ChatResponse::Done) | Err(_) => Err(PyStopAsyncIteration::new_err(())),
+                Ok(
+                    ChatResponse::InternalError(e)
+                    | ChatResponse::ValidationError(e)
+                    | ChatResponse::ModelError(e),
+                ) => Err(APIError::new(e).into()),
+            }
+        })?;
+        Ok(Some(next))
+    }
+}
+
+/// The engine (scheduler, cache engine and pipeline) of a model, running on the tokio runtime
+/// of the module.
+#[pyclass(name = "LLMEngine")]
+pub struct PyLLMEngine {
+    engine: Arc<Mutex<LLMEngine>>,
+    pipeline_config: PipelineConfig,
+    tokenizer: Tokenizer,
+}
+
+#[pymethods]
+impl PyLLMEngine {
+    /// `model` is the model subcommand of the CLI, with its options, e.g. `llama3` or
+    /// `phi3 --top-k 40`, or empty to detect the model from the `config.json` of the checkpoint.
+    #[new]
+    #[pyo3(signature = (
+        model,
+        weight_path = None,
+        model_id = None,
+        dtype = None,
+        kv_cache_dtype = None,
+        cpu = false,
+        block_size = 32,
+        max_num_seqs = 256,
+        batch_invariant = false,
+        kvcache_mem_gpu = 4096,
+        kvcache_mem_cpu = 4096,
+        weight_buffer_mem = DEFAULT_WEIGHT_BUFFER_MEM,
+        hf_token = None,
+        hf_token_path = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        model: &str,
+        weight_path: Option<String>,
+        model_id: Option<String>,
+        dtype: Option<String>,
+        kv_cache_dtype: Option<String>,
+        cpu: bool,
+        block_size: usize,
+        max_num_seqs: usize,
+        batch_invariant: bool,
+        kvcache_mem_gpu: usize,
+        kvcache_mem_cpu: usize,
+        weight_buffer_mem: usize,
+        hf_token: Option<String>,
+        hf_token_path: Option<String>,
+    ) -> PyResult<Self> {
+        let model = ModelArgs::try_parse_from(
+            std::iter::once("candle-vllm").chain(model.split_whitespace()),
+        )
+        .map_err(|e| APIError::from(e.render()))?
+        .model;
+        py.allow_threads(|| {
+            let model = match model {
+                Some(model) => model,
+                None => detect_model(
+                    model_id.clone(),
+                    weight_path.as_ref(),
+                    hf_token.clone(),
+                    hf_token_path.clone(),
+                )?,
+            };
+            let (loader, model_id) = get_model_loader(model, model_id);
+            let paths = get_model_paths(
+                &*loader,
+                model_id,
+                weight_path.as_ref(),
+                hf_token,
+                hf_token_path,
+            )?;
+            let dtype = get_dtype(dtype.as_deref(), &*paths)?;
+            let device = candle_examples::device(cpu).map_err(APIError::from)?;
+            let (pipeline, pipeline_config) =
+                loader.load_model(paths, dtype, device, weight_buffer_mem)?;
+            let tokenizer = pipeline.tokenizer().tokenizer().clone();
+
+            let mut config = pipeline.get_model_config();
+            config.kv_cache_dtype = get_kv_cache_dtype(kv_cache_dtype.as_deref(), dtype)?;
+            let cache_config = get_cache_config(
+                &config,
+                block_size,
+                kvcache_mem_gpu,
+                kvcache_mem_cpu,
+                None,
+                None,
+                None,
+            )?;
+            // The engine loop runs on the runtime the async iterators are awaited on.
+            let _guard = pyo3_async_runtimes::tokio::get_runtime().enter();
+            let engine = LLMEngine::new(
+                pipeline,
+                SchedulerConfig {
+                    max_num_seqs,
+                    max_num_prefill_tokens: None,
+                    long_prefill_token_threshold: None,
+                    max_long_prefills: 1,
+                    prompt_lookup: None,
+                    batch_invariant,
+                    max_sessions: 0,
+                    compaction_blocks: None,
+                },
+                cache_config,
+                WorkerConfig::default(),
+                Arc::new(Notify::new()),
+                Arc::new(Notify::new()),
+            )?;
+            Ok(Self {
+                engine,
+                pipeline_config,
+                tokenizer,
+            })
+        })
+    }
+
+    /// Generate the completions of `prompts`, batched together, and wait for all of them.
+    #[pyo3(signature = (prompts, sampling_params = None))]
+    fn generate(
+        &self,
+        py: Python<'_>,
+        prompts: Vec<String>,
+        sampling_params: Option<PySamplingParams>,
+    ) -> PyResult<Vec<RequestOutput>> {
+        let sampling_params = sampling_params.unwrap_or_default();
+        py.allow_threads(|| {
+            let requests = prompts
+                .iter()
+                .map(|prompt| self.prepare_request(prompt, &sampling_params))
+                .collect::<Result<Vec<_>, APIError>>()?;
+            let receivers = self.submit(requests);
+            let outputs = prompts
+                .into_iter()
+                .zip(receivers)
+                .map(|(prompt, rx)| collect_outputs(prompt, sampling_params.n, rx))
+                .collect::<Result<Vec<_>, APIError>>()?;
+            Ok(outputs)
+        })
+    }
+
+    /// Stream the tokens of the completion of `prompt` as they are generated.
+    #[pyo3(signature = (prompt, sampling_params = None))]
+    fn stream(
+        &self,
+        prompt: &str,
+        sampling_params: Option<PySamplingParams>,
+    ) -> PyResult<TokenStream> {
+        let sampling_params = sampling_params.unwrap_or_default();
+        let request = self.prepare_request(prompt, &sampling_params)?;
+        let rx = self.submit(vec![request]).remove(0);
+        Ok(TokenStream { rx })
+    }
+}
+
+impl PyLLMEngine {
+    fn prepare_request(
+        &self,
+        prompt: &str,
+        sampling_params: &PySamplingParams,
+    ) -> Result<(Encoding, SamplingParams), APIError> {
+        let mut params = sampling_params.to_sampling_params(&self.pipeline_config)?;
+        let token_ids = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(APIError::from)?;
+        let max_model_len = self.pipeline_config.max_model_len;
+        // Without `max_tokens`, generation stops at the end of the context.
+        if sampling_params.max_tokens.is_none() {
+            params.max_tokens = params
+                .max_tokens
+                .min(max_model_len.saturating_sub(token_ids.len()).max(1));
+        }
+        let sampling_params = params;
+        if token_ids.len() + sampling_params.max_tokens > max_model_len {
+            return Err(APIError::new(format!(
+                "This model's maximum context length is {max_model_len} tokens, the prompt has {} \
+                and {} are requested.",
+                token_ids.len(),
+                sampling_params.max_tokens
+            )));
+        }
+        Ok((token_ids, sampling_params))
+    }
+
+    /// Add the requests to the engine together, so that they are scheduled in the same batch,
+    /// and return the receiver of the stream of each.
+    fn submit(&self, requests: Vec<(Encoding, SamplingParams)>) -> Vec<Receiver<ChatResponse>> {
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            requests.iter().map(|_| flume::unbounded()).unzip();
+        let engine = self.engine.clone();
+        // The engine is locked while it generates, do not block the caller meanwhile.
+        pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+            let mut engine = engine.lock().await;
+            for ((token_ids, sampling_params), sender) in requests.into_iter().zip(senders) {
+                engine.add_request(
+                    token_ids,
+                    format!("cmpl-{}", Uuid::new_v4()),
+                    SystemTime::now(),
+                    sampling_params,
+                    false,
+                    Some(sender),
+                    None,
+                );
+            }
+            engine.notify.notify_one();
+        });
+        receivers
+    }
+}
+
+#[pymodule]
+fn candle_vllm(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyLLMEngine>()?;
+    m.add_class::<PySamplingParams>()?;
+    m.add_class::<RequestOutput>()?;
+    m.add_class::<CompletionOutput>()?;
+    m.add_class::<TokenStream>()?;
+    Ok(())
+}
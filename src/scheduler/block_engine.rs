@@ -1,12 +1,16 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     hash::Hash,
     marker::PhantomData,
-    ops::Deref,
+    ops::{Deref, Range},
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use super::sequence::{Sequence, SequenceGroup};
+use super::{
+    cache_engine::AttentionSinks,
+    sequence::{Sequence, SequenceGroup},
+    state_cache::StateSlots,
+};
 
 pub struct LogicalTokenBlock {
     tokens: Vec<usize>,
@@ -42,6 +46,11 @@ impl LogicalTokenBlock {
             self.append_token_id(*token);
         }
     }
+
+    /// Keep the first `num_tokens` tokens of the block.
+    pub fn truncate(&mut self, num_tokens: usize) {
+        self.num_tokens = self.num_tokens.min(num_tokens);
+    }
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -78,7 +87,7 @@ impl Hash for PhysicalTokenBlock {
 
 impl Eq for PhysicalTokenBlock {}
 
-type BlockTable = Vec<Arc<PhysicalTokenBlock>>;
+pub type BlockTable = Vec<Arc<PhysicalTokenBlock>>;
 struct GPUAllocator;
 struct CPUAllocator;
 
@@ -129,20 +138,26 @@ impl<T> Allocator<T> {
 
 impl Allocator<GPUAllocator> {
     fn new(block_size: usize, num_blocks: usize) -> Self {
-        let mut free_blocks = Vec::new();
-        for id in 0..num_blocks {
-            free_blocks.push(Arc::new(PhysicalTokenBlock(Mutex::new(
-                _PhysicalTokenBlock {
-                    block_id: id,
-                    block_size,
-                    refcount: 0,
-                    is_gpu: true,
-                },
-            ))))
-        }
-        Allocator {
-            free_blocks,
+        let mut allocator = Allocator {
+            free_blocks: Vec::new(),
             _ghost: PhantomData,
+        };
+        allocator.extend(block_size, 0..num_blocks);
+        allocator
+    }
+
+    /// Add free blocks with the ids in `ids`.
+    fn extend(&mut self, block_size: usize, ids: Range<usize>) {
+        for id in ids {
+            self.free_blocks
+                .push(Arc::new(PhysicalTokenBlock(Mutex::new(
+                    _PhysicalTokenBlock {
+                        block_id: id,
+                        block_size,
+                        refcount: 0,
+                        is_gpu: true,
+                    },
+                ))))
         }
     }
 
@@ -179,14 +194,59 @@ pub enum AllocStatus {
 
 type SeqID = usize;
 
+/// Maps the token at `position` of a sequence to its slot in the paged KV cache, given the
+/// physical block ids of the sequence's block table. Returns `None` if the table is too small.
+pub fn compute_slot(block_table: &[usize], position: usize, block_size: usize) -> Option<usize> {
+    block_table
+        .get(position / block_size)
+        .map(|block_number| block_number * block_size + position % block_size)
+}
+
+/// Blocks of `block_table` attended by the token at `cache_index` and the number of tokens they
+/// hold up to it, its context length, as the paged attention kernels read them: from the first
+/// slot of the first block. Returns `None` if the table is too small.
+///
+/// With a sliding window, the blocks before the one holding the first token of the window are
+/// left out. The window starts within that block, so up to `block_size - 1` older tokens are
+/// attended too, all of them written. The slots after `cache_index` are never attended, even
+/// when its block or later blocks are already allocated, e.g. for speculative tokens.
+pub fn attended_blocks(
+    block_table: &[usize],
+    cache_index: usize,
+    block_size: usize,
+    sliding_window: Option<usize>,
+) -> Option<(&[usize], usize)> {
+    let first_block = sliding_window.map_or(0, |window| {
+        (cache_index + 1).saturating_sub(window) / block_size
+    });
+    let blocks = block_table.get(first_block..=cache_index / block_size)?;
+    Some((blocks, cache_index + 1 - first_block * block_size))
+}
+
 /// A BlockEngine maps each Sequence (identified by its SeqID), to physical token blocks.
 /// The physical token blocks may not match the logical token blocks because during
 /// scheduling, physical blocks are allocated to accommodate the new tokens generated.
 /// These new tokens will be added to the logical token block for each sequence.
+///
+/// With lazy growth only the first `num_allocated_gpu_blocks` block ids are backed by the GPU
+/// cache. More are handed to the allocator a chunk at a time when it runs out, and the cache
+/// engine grows the cache to match before the next model step.
+///
+/// With attention sinks, a sequence whose next slot falls past its window gives back the oldest
+/// block after its sinks instead.
+///
+/// For hybrid models every sequence also holds a slot of the recurrent-state store, from its
+/// allocation to its end, kept while it is swapped out.
 pub struct BlockEngine {
+    block_size: usize,
     num_gpu_blocks: usize,
+    num_allocated_gpu_blocks: usize,
+    gpu_growth_blocks: Option<usize>,
+    num_cpu_blocks: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
+    attention_sinks: Option<AttentionSinks>,
+    state_slots: Option<StateSlots>,
     pub block_tables: HashMap<SeqID, BlockTable>,
 }
 
@@ -194,17 +254,142 @@ impl BlockEngine {
     #[must_use]
     pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
         Self {
+            block_size,
             num_gpu_blocks,
+            num_allocated_gpu_blocks: num_gpu_blocks,
+            gpu_growth_blocks: None,
+            num_cpu_blocks,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
+            attention_sinks: None,
+            state_slots: None,
             block_tables: HashMap::new(),
         }
     }
 
+    /// Start with `growth_blocks` GPU blocks and grow by as many at a time, up to
+    /// `num_gpu_blocks`.
+    #[must_use]
+    pub fn with_gpu_growth_blocks(mut self, growth_blocks: usize) -> Self {
+        let num_blocks = growth_blocks.min(self.num_gpu_blocks);
+        self.gpu_allocator = Allocator::<GPUAllocator>::new(self.block_size, num_blocks);
+        self.num_allocated_gpu_blocks = num_blocks;
+        self.gpu_growth_blocks = Some(growth_blocks);
+        self
+    }
+
+    /// Keep the sink blocks and a window of recent blocks of every sequence, evicting the rest.
+    #[must_use]
+    pub fn with_attention_sinks(mut self, attention_sinks: AttentionSinks) -> Self {
+        self.attention_sinks = Some(attention_sinks);
+        self
+    }
+
+    /// Hand every allocated sequence one of `num_slots` slots of recurrent state.
+    #[must_use]
+    pub fn with_state_slots(mut self, num_slots: usize) -> Self {
+        self.state_slots = Some(StateSlots::new(num_slots));
+        self
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn get_num_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks
+    }
+
+    /// Number of GPU blocks currently backed by the cache.
+    pub fn get_num_allocated_gpu_blocks(&self) -> usize {
+        self.num_allocated_gpu_blocks
+    }
+
+    pub fn get_num_cpu_blocks(&self) -> usize {
+        self.num_cpu_blocks
+    }
+
+    /// Free GPU blocks, counting those the cache has yet to grow into.
+    pub fn get_num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks() + self.num_gpu_blocks
+            - self.num_allocated_gpu_blocks
+    }
+
+    /// Grow the allocated GPU blocks a chunk at a time until `num_blocks` are free, or all of
+    /// them are allocated.
+    fn reserve_gpu_blocks(&mut self, num_blocks: usize) {
+        let Some(growth_blocks) = self.gpu_growth_blocks else {
+            return;
+        };
+        while *self.gpu_allocator.get_num_free_blocks() < num_blocks
+            && self.num_allocated_gpu_blocks < self.num_gpu_blocks
+        {
+            let num_allocated =
+                (self.num_allocated_gpu_blocks + growth_blocks).min(self.num_gpu_blocks);
+            self.gpu_allocator.extend(
+                self.block_size,
+                self.num_allocated_gpu_blocks..num_allocated,
+            );
+            self.num_allocated_gpu_blocks = num_allocated;
+        }
+    }
+
+    /// Give back all GPU blocks but the first growth chunk, provided none of them is in use.
+    /// Returns whether the number of allocated blocks changed.
+    pub fn shrink_gpu_blocks(&mut self) -> bool {
+        let Some(growth_blocks) = self.gpu_growth_blocks else {
+            return false;
+        };
+        let num_blocks = growth_blocks.min(self.num_gpu_blocks);
+        if self.num_allocated_gpu_blocks == num_blocks
+            || *self.gpu_allocator.get_num_free_blocks() < self.num_allocated_gpu_blocks
+        {
+            return false;
+        }
+        self.gpu_allocator = Allocator::<GPUAllocator>::new(self.block_size, num_blocks);
+        self.num_allocated_gpu_blocks = num_blocks;
+        true
+    }
+
+    pub fn get_num_free_cpu_blocks(&self) -> usize {
+        self.cpu_allocator.free_blocks.len()
+    }
+
+    /// The physical block ids backing a sequence.
+    pub fn get_block_table_ids(&self, seq_id: SeqID) -> Option<Vec<usize>> {
+        self.block_tables.get(&seq_id).map(|table| {
+            table
+                .iter()
+                .map(|block| block.deref_mut().block_id)
+                .collect::<Vec<_>>()
+        })
+    }
+
+    /// Slot of recurrent state of a sequence, `None` without the store.
+    pub fn get_state_slot(&self, seq_id: SeqID) -> Option<usize> {
+        self.state_slots.as_ref()?.get(seq_id)
+    }
+
+    /// Slots handed out since the last call, whose states are zeroed before the next step.
+    pub fn take_new_state_slots(&mut self) -> Vec<usize> {
+        self.state_slots
+            .as_mut()
+            .map_or_else(Vec::new, StateSlots::take_new_slots)
+    }
+
     pub fn can_allocate(&self, seq_group: &SequenceGroup) -> AllocStatus {
         let num_required_blocks = seq_group.get_total_logical_token_blocks();
-        let num_free_gpu_blocks = *self.gpu_allocator.get_num_free_blocks();
+        let num_free_gpu_blocks = self.get_num_free_gpu_blocks();
+        let num_seqs = seq_group.get_seqs().len();
 
+        if let Some(slots) = &self.state_slots {
+            if slots.num_slots() < num_seqs {
+                return AllocStatus::Impossible;
+            }
+            if slots.num_free() < num_seqs {
+                return AllocStatus::Later;
+            }
+        }
         if self.num_gpu_blocks < num_required_blocks {
             AllocStatus::Impossible
         } else if num_free_gpu_blocks > num_required_blocks {
@@ -214,20 +399,93 @@ impl BlockEngine {
         }
     }
 
+    /// Every sequence of the group gets blocks of its own, the prompt is prefilled once per
+    /// sequence.
     pub fn allocate(&mut self, seq_group: &SequenceGroup) {
-        let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq_group.get_total_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        self.reserve_gpu_blocks(seq_group.get_total_logical_token_blocks());
+        for (seq_id, seq) in seq_group.get_seqs() {
+            let mut block_table = Vec::new();
+            for _logcical_idx in 0..seq.deref_mut().get_logical_token_blocks() {
+                block_table.push(self.gpu_allocator.allocate());
+            }
+            self.block_tables.insert(*seq_id, block_table);
+            if let Some(slots) = &mut self.state_slots {
+                slots
+                    .allocate(*seq_id)
+                    .expect("can_allocate checks the free state slots");
+            }
         }
-        for seq_id in seq_group.get_seqs().keys() {
-            self.block_tables.insert(*seq_id, block_table.clone());
+    }
+
+    /// Allocate the blocks of `sequence` on top of `prefix`, blocks holding the KV cache of its
+    /// first `num_cached_tokens` tokens. The blocks of `prefix` past them are freed. Gives
+    /// `prefix` back if the other blocks cannot be allocated now.
+    pub fn allocate_with_prefix(
+        &mut self,
+        sequence: &Sequence,
+        mut prefix: BlockTable,
+        num_cached_tokens: usize,
+    ) -> Result<(), BlockTable> {
+        let num_prefix_blocks = num_cached_tokens
+            .div_ceil(self.block_size)
+            .min(prefix.len());
+        self.free_blocks(prefix.split_off(num_prefix_blocks));
+        let num_blocks = sequence
+            .deref()
+            .get_logical_token_blocks()
+            .saturating_sub(prefix.len());
+        if self.get_num_free_gpu_blocks() <= num_blocks {
+            return Err(prefix);
+        }
+        self.reserve_gpu_blocks(num_blocks);
+        for _ in 0..num_blocks {
+            prefix.push(self.gpu_allocator.allocate());
+        }
+        self.block_tables.insert(sequence.deref().get_id(), prefix);
+        Ok(())
+    }
+
+    /// Take the blocks of `sequence` holding its first `num_tokens` tokens out of the block
+    /// tables without freeing them, the other blocks are freed.
+    pub fn detach_sequence(
+        &mut self,
+        sequence: &Sequence,
+        num_tokens: usize,
+    ) -> Option<BlockTable> {
+        let seq_id = sequence.deref().get_id();
+        let mut table = self.block_tables.remove(&seq_id)?;
+        if let Some(slots) = &mut self.state_slots {
+            slots.free(seq_id);
+        }
+        let num_blocks = num_tokens.div_ceil(self.block_size).min(table.len());
+        self.free_blocks(table.split_off(num_blocks));
+        Some(table)
+    }
+
+    /// Give blocks taken with `detach_sequence` back to the allocators.
+    pub fn free_blocks(&mut self, blocks: BlockTable) {
+        for block in blocks {
+            if block.deref_mut().is_gpu {
+                self.gpu_allocator.free_block(block)
+            } else {
+                self.cpu_allocator.free_block(block)
+            }
         }
     }
 
+    /// Free the blocks of `sequence` past its logical blocks, after it was rolled back.
+    pub fn truncate_sequence(&mut self, sequence: &Sequence) {
+        let seq = sequence.deref();
+        let Some(table) = self.block_tables.get_mut(&seq.get_id()) else {
+            return;
+        };
+        let tail = table.split_off(seq.get_logical_token_blocks().min(table.len()));
+        self.free_blocks(tail);
+    }
+
     pub fn can_append_token_to_seq(&self, seq_group: &SequenceGroup) -> bool {
-        let free_blocks = self.gpu_allocator.get_num_free_blocks();
         // Physical blocks = logical blocks
-        seq_group.total_blocks_to_add_new_tok() <= *free_blocks
+        seq_group.total_blocks_to_add_new_tok() <= self.get_num_free_gpu_blocks()
     }
 
     pub fn free_sequence(&mut self, sequence: &Sequence) {
@@ -245,7 +503,11 @@ impl BlockEngine {
             }
         }
 
-        self.block_tables.remove(&sequence.deref_mut().get_id());
+        let seq_id = sequence.deref_mut().get_id();
+        self.block_tables.remove(&seq_id);
+        if let Some(slots) = &mut self.state_slots {
+            slots.free(seq_id);
+        }
     }
 
     pub fn can_swap_out_seq_group(&self, seq_group: &SequenceGroup) -> bool {
@@ -298,6 +560,10 @@ impl BlockEngine {
     // Returns the COW mapping (src, dst).
     // COW is performed if there are multiple references to the last physical block.
     pub fn append_token_slot_to_seq(&mut self, sequence: &Sequence) -> Option<(usize, usize)> {
+        if let Some(attention_sinks) = self.attention_sinks {
+            self.evict_blocks(sequence, attention_sinks);
+        }
+        self.reserve_gpu_blocks(1);
         let table = self
             .block_tables
             .get_mut(&sequence.deref_mut().get_id())
@@ -329,6 +595,79 @@ impl BlockEngine {
         }
     }
 
+    /// Evict the blocks right after the sinks until the slot of the last token of `sequence` is
+    /// within the window.
+    fn evict_blocks(&mut self, sequence: &Sequence, attention_sinks: AttentionSinks) {
+        let AttentionSinks {
+            sink_blocks,
+            window_blocks,
+        } = attention_sinks;
+        let mut seq = sequence.deref_mut();
+        let table = self.block_tables.get_mut(&seq.get_id()).unwrap();
+        while seq.get_cache_index(seq.get_len() - 1) / self.block_size
+            >= sink_blocks + window_blocks
+        {
+            self.gpu_allocator.free_block(table.remove(sink_blocks));
+            seq.evict_block(sink_blocks);
+        }
+    }
+
+    /// Move up to `max_blocks` GPU blocks of the sequences `seq_ids` into the free blocks with
+    /// the highest ids, lowest block ids first. The allocator hands out the highest free ids
+    /// first, so the blocks in use end up packed at the end of the cache and the free ones in a
+    /// single range the next long prompt is allocated from. Blocks shared between sequences and
+    /// the `pinned` ones stay. Returns the moves `(src, dst)`, whose KV cache has to be copied
+    /// before the next model step.
+    pub fn compact(
+        &mut self,
+        seq_ids: &[SeqID],
+        max_blocks: usize,
+        pinned: &HashSet<usize>,
+    ) -> Vec<(usize, usize)> {
+        let mut in_use = Vec::new();
+        for seq_id in seq_ids {
+            let Some(table) = self.block_tables.get(seq_id) else {
+                continue;
+            };
+            for (index, block) in table.iter().enumerate() {
+                let block = block.deref_mut();
+                if block.refcount == 1 && !pinned.contains(&block.block_id) {
+                    in_use.push((block.block_id, *seq_id, index));
+                }
+            }
+        }
+        in_use.sort_unstable();
+        self.gpu_allocator
+            .free_blocks
+            .sort_unstable_by_key(|block| block.deref_mut().block_id);
+
+        let mut moves = Vec::new();
+        let mut moved = Vec::new();
+        for (src, seq_id, index) in in_use.into_iter().take(max_blocks) {
+            let Some(dst) = self.gpu_allocator.free_blocks.last() else {
+                break;
+            };
+            let dst = dst.deref_mut().block_id;
+            if dst < src {
+                break;
+            }
+            let table = self.block_tables.get_mut(&seq_id).unwrap();
+            moved.push(std::mem::replace(
+                &mut table[index],
+                self.gpu_allocator.allocate(),
+            ));
+            moves.push((src, dst));
+        }
+        // The moved blocks are freed once all the destinations are taken.
+        for block in moved {
+            self.gpu_allocator.free_block(block);
+        }
+        self.gpu_allocator
+            .free_blocks
+            .sort_unstable_by_key(|block| block.deref_mut().block_id);
+        moves
+    }
+
     pub fn can_swap_in_seq_group(&self, seq_group: &SequenceGroup) -> bool {
         let blocks_required: usize = self
             .block_tables
@@ -336,7 +675,7 @@ impl BlockEngine {
             .filter(|(id, _)| seq_group.get_seqs().contains_key(id))
             .map(|(_, table)| table.len())
             .sum();
-        blocks_required <= self.gpu_allocator.free_blocks.len()
+        blocks_required <= self.get_num_free_gpu_blocks()
     }
 
     /// Update the block table so that the sequence does no longer reserve any CPU
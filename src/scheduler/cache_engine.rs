@@ -1,15 +1,130 @@
 use std::{
     collections::HashMap,
+    str::FromStr,
     sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
 };
 
 use candle_core::{DType, Device, Tensor};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
 use crate::{
-    backend::{copy_blocks, swap_blocks},
+    backend::{
+        bind_thread, check_cache_dtype, copy_blocks, device_numa_node, node_cpus, parse_cpu_list,
+        swap_blocks, KvCacheLayout, MAX_CPUS,
+    },
     openai::{models::Config, responses::APIError},
+    scheduler::state_cache::StateCache,
     try_api,
 };
+use tracing::warn;
+
+/// Block sizes the paged attention kernels are compiled for.
+pub const SUPPORTED_BLOCK_SIZES: [usize; 3] = [8, 16, 32];
+
+/// Share of the host memory the CPU KV cache may take, more leaves too little for the rest of the
+/// server. Over `SWAP_SPACE_WARN_FRACTION` of it is allowed, with a warning.
+pub const MAX_SWAP_SPACE_FRACTION: f64 = 0.7;
+pub const SWAP_SPACE_WARN_FRACTION: f64 = 0.4;
+
+/// Total memory of the host in MB, `None` where it cannot be read.
+pub fn host_memory_mb() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let total = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?;
+    let kb = total
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kb / 1024)
+}
+
+/// Check that a CPU KV cache of `swap_space` MB, where preempted sequences are swapped out to,
+/// fits in the `host_memory` MB of the host.
+pub fn verify_swap_space(swap_space: usize, host_memory: usize) -> Result<(), APIError> {
+    let fraction = swap_space as f64 / host_memory as f64;
+    if fraction > MAX_SWAP_SPACE_FRACTION {
+        return Err(APIError::new(format!(
+            "The swap space of {swap_space} MB is over {:.0}% of the {host_memory} MB of host memory.",
+            MAX_SWAP_SPACE_FRACTION * 100.
+        )));
+    }
+    if fraction > SWAP_SPACE_WARN_FRACTION {
+        warn!(
+            "the swap space of {swap_space} MB takes {:.0}% of the {host_memory} MB of host memory",
+            fraction * 100.
+        );
+    }
+    Ok(())
+}
+
+/// StreamingLLM style eviction for unbounded generation. A sequence keeps the KV cache of its
+/// first `sink_blocks` blocks, which attention keeps attending to, and of its `window_blocks` most
+/// recent ones. The blocks in between are evicted as it grows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttentionSinks {
+    pub sink_blocks: usize,
+    pub window_blocks: usize,
+}
+
+/// Threads swapping the blocks of a layer between the GPU and the CPU cache at once, at most.
+pub const MAX_SWAP_THREADS: usize = 8;
+
+/// CPUs the swaps between the GPU and the CPU KV cache run on. The CPU cache is first written and
+/// the copies are staged from there, so their memory comes from the NUMA node of these CPUs: on
+/// hosts with several sockets, the one the GPU is attached to keeps the swaps off the link
+/// between the sockets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SwapAffinity {
+    /// The CPUs of the NUMA node of the GPU, the swaps are left unbound if it is not known.
+    Auto,
+    Node(usize),
+    Cpus(Vec<usize>),
+}
+
+impl SwapAffinity {
+    /// CPUs to bind the swaps of the cache of `device` to, `None` to leave them unbound.
+    pub fn cpus(&self, device: &Device) -> Result<Option<Vec<usize>>, APIError> {
+        match self {
+            Self::Auto => match try_api!(device_numa_node(device)).and_then(node_cpus) {
+                Some(cpus) => Ok(Some(cpus)),
+                None => {
+                    warn!("the NUMA node of the device is not known, swaps are not bound to CPUs");
+                    Ok(None)
+                }
+            },
+            Self::Node(node) => node_cpus(*node)
+                .map(Some)
+                .ok_or_else(|| APIError::new(format!("There is no NUMA node {node}."))),
+            Self::Cpus(cpus) => Ok(Some(cpus.clone())),
+        }
+    }
+}
+
+impl FromStr for SwapAffinity {
+    type Err = APIError;
+
+    /// `auto`, `node:<node>` or a list of CPUs such as `0-15,32-47`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Self::Auto);
+        }
+        if let Some(node) = s.strip_prefix("node:") {
+            return node
+                .parse()
+                .map(Self::Node)
+                .map_err(|_| APIError::new(format!("Invalid NUMA node {node}.")));
+        }
+        parse_cpu_list(s).map(Self::Cpus).ok_or_else(|| {
+            APIError::new(format!(
+                "Swap affinity must be auto, node:<node> or a list of CPUs, got {s}."
+            ))
+        })
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
@@ -17,7 +132,21 @@ pub struct CacheConfig {
     pub num_gpu_blocks: Option<usize>, // Set after profiling init
     pub num_cpu_blocks: Option<usize>, // Set after profiling init
     pub fully_init: bool,
+    /// Dtype of the cache, independent of the one the model is served in.
     pub dtype: DType,
+    /// Allocate the GPU cache lazily, this many blocks at a time, up to `num_gpu_blocks`. `None`
+    /// allocates all of it at startup.
+    pub gpu_growth_blocks: Option<usize>,
+    /// Shrink the GPU cache back to a single growth chunk once the engine has been idle this long.
+    pub idle_shrink_after: Option<Duration>,
+    /// Evict the middle of long sequences instead of running out of blocks.
+    pub attention_sinks: Option<AttentionSinks>,
+    /// Slots of the recurrent-state store of hybrid models, the sequences that can run at once.
+    /// `None` for models without recurrent layers.
+    pub num_state_slots: Option<usize>,
+    /// Bind the swaps between the GPU and the CPU cache to the CPUs local to the GPU. `None`
+    /// runs them on the engine thread.
+    pub swap_affinity: Option<SwapAffinity>,
 }
 
 impl CacheConfig {
@@ -33,6 +162,74 @@ impl CacheConfig {
         }
         self.num_cpu_blocks = Some(num_cpu_blocks);
     }
+
+    /// Number of GPU blocks allocated at startup, and kept when the cache is shrunk.
+    pub fn initial_gpu_blocks(&self) -> usize {
+        let num_gpu_blocks = self.num_gpu_blocks.unwrap();
+        match self.gpu_growth_blocks {
+            Some(growth_blocks) => growth_blocks.min(num_gpu_blocks),
+            None => num_gpu_blocks,
+        }
+    }
+
+    pub fn verify_args(&self) -> Result<(), APIError> {
+        if !SUPPORTED_BLOCK_SIZES.contains(&self.block_size) {
+            return Err(APIError::new(format!(
+                "block_size must be one of {:?}, got {}.",
+                SUPPORTED_BLOCK_SIZES, self.block_size
+            )));
+        }
+        if !matches!(self.dtype, DType::F16 | DType::BF16 | DType::F32) {
+            return Err(APIError::new(format!(
+                "KV cache dtype must be f16, bf16 or f32, got {:?}.",
+                self.dtype
+            )));
+        }
+        if self.gpu_growth_blocks == Some(0) {
+            return Err(APIError::new_str(
+                "The KV cache must grow by at least one block at a time.",
+            ));
+        }
+        if self.idle_shrink_after.is_some() && self.gpu_growth_blocks.is_none() {
+            return Err(APIError::new_str(
+                "Shrinking the KV cache when idle requires growing it lazily.",
+            ));
+        }
+        if let Some(sinks) = self.attention_sinks {
+            if sinks.window_blocks == 0 {
+                return Err(APIError::new_str(
+                    "The attention window must hold at least one block.",
+                ));
+            }
+            if let Some(num_gpu_blocks) = self.num_gpu_blocks {
+                if sinks.sink_blocks + sinks.window_blocks >= num_gpu_blocks {
+                    return Err(APIError::new(format!(
+                        "Attention sinks and window take {} blocks, the GPU KV cache has {}.",
+                        sinks.sink_blocks + sinks.window_blocks,
+                        num_gpu_blocks
+                    )));
+                }
+            }
+        }
+        if self.num_state_slots == Some(0) {
+            return Err(APIError::new_str(
+                "The recurrent-state store must have at least one slot.",
+            ));
+        }
+        if let Some(SwapAffinity::Cpus(cpus)) = &self.swap_affinity {
+            if cpus.is_empty() {
+                return Err(APIError::new_str(
+                    "Swaps must be bound to at least one CPU.",
+                ));
+            }
+            if let Some(cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+                return Err(APIError::new(format!(
+                    "CPU {cpu} is past the {MAX_CPUS} CPUs swaps can be bound to."
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -40,7 +237,11 @@ pub type KVCache = (Tensor, Tensor);
 pub struct CacheEngine {
     gpu_cache: Arc<Mutex<Vec<KVCache>>>,
     cpu_cache: Vec<KVCache>,
-    num_layers: usize,
+    /// Recurrent states of the sequences of a hybrid model.
+    state_cache: Option<StateCache>,
+    /// Threads bound to the CPUs of `CacheConfig::swap_affinity`, the swaps run on the calling
+    /// thread without.
+    swap_pool: Option<ThreadPool>,
 }
 
 impl CacheEngine {
@@ -50,6 +251,34 @@ impl CacheEngine {
         dtype: DType,
         device: &Device,
     ) -> Result<Self, APIError> {
+        cache_config.verify_args()?;
+        // The keys and values of the model are cast to `dtype` as they are written.
+        try_api!(check_cache_dtype(device, dtype));
+        let state_cache = match (&model_config.recurrent_state, cache_config.num_state_slots) {
+            (Some(state_config), Some(num_slots)) => {
+                Some(StateCache::new(state_config.clone(), num_slots, device)?)
+            }
+            (Some(_), None) => {
+                return Err(APIError::new_str(
+                    "The model has recurrent layers, the cache needs slots for their states.",
+                ))
+            }
+            (None, _) => None,
+        };
+        let swap_pool = match &cache_config.swap_affinity {
+            Some(affinity) => match affinity.cpus(device)? {
+                Some(cpus) => Some(Self::swap_pool(cpus, device)?),
+                None => None,
+            },
+            None => None,
+        };
+        // Allocated from the swap threads, which first write its pages.
+        let cpu_cache = match &swap_pool {
+            Some(pool) => pool.install(|| {
+                Self::allocate_cpu_cache(&model_config, &cache_config, dtype, device)
+            })?,
+            None => Self::allocate_cpu_cache(&model_config, &cache_config, dtype, device)?,
+        };
         Ok(Self {
             gpu_cache: Arc::new(Mutex::new(Self::allocate_gpu_cache(
                 &model_config,
@@ -57,11 +286,34 @@ impl CacheEngine {
                 dtype,
                 device,
             )?)),
-            cpu_cache: Self::allocate_cpu_cache(&model_config, &cache_config, dtype, device)?,
-            num_layers: model_config.num_hidden_layers,
+            cpu_cache,
+            state_cache,
+            swap_pool,
         })
     }
 
+    /// Threads running the swaps of the cache of `device` on `cpus`, one per CPU up to
+    /// `MAX_SWAP_THREADS`.
+    fn swap_pool(cpus: Vec<usize>, device: &Device) -> Result<ThreadPool, APIError> {
+        let num_threads = cpus.len().min(MAX_SWAP_THREADS);
+        let device = device.clone();
+        ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("kv-swap-{i}"))
+            .start_handler(move |_| {
+                if let Err(e) = bind_thread(&cpus, &device) {
+                    warn!("failed to bind a swap thread to CPUs {cpus:?}: {e}");
+                }
+            })
+            .build()
+            .map_err(|e| APIError::new(format!("Failed to start the swap threads: {e}")))
+    }
+
+    /// The recurrent-state store, `None` for models without recurrent layers.
+    pub fn get_state_cache(&self) -> Option<&StateCache> {
+        self.state_cache.as_ref()
+    }
+
     pub fn get_kv_cache(&self) -> MutexGuard<'_, Vec<KVCache>> {
         loop {
             if let Ok(v) = self.gpu_cache.try_lock() {
@@ -70,6 +322,36 @@ impl CacheEngine {
         }
     }
 
+    /// Number of blocks of each layer of the GPU cache.
+    pub fn get_num_gpu_blocks(&self) -> usize {
+        self.get_kv_cache()
+            .first()
+            .map_or(0, |(key_blocks, _)| key_blocks.dims()[0])
+    }
+
+    /// Grow or shrink every layer of the GPU cache to `num_blocks` blocks. Blocks below
+    /// `num_blocks` keep their content, so block ids handed out by the scheduler stay valid.
+    pub fn resize_gpu_cache(&self, num_blocks: usize) -> Result<(), APIError> {
+        let resize = |blocks: &Tensor| {
+            let mut shape = blocks.dims().to_vec();
+            let num_cached = shape[0];
+            if num_blocks > num_cached {
+                shape[0] = num_blocks - num_cached;
+                let new_blocks = Tensor::zeros(shape, blocks.dtype(), blocks.device())?;
+                Tensor::cat(&[blocks, &new_blocks], 0)
+            } else {
+                // Copy so that the memory of the dropped blocks is released.
+                blocks.narrow(0, 0, num_blocks)?.copy()
+            }
+        };
+        let mut gpu_cache = self.get_kv_cache();
+        for (key_blocks, value_blocks) in gpu_cache.iter_mut() {
+            *key_blocks = try_api!(resize(key_blocks));
+            *value_blocks = try_api!(resize(value_blocks));
+        }
+        Ok(())
+    }
+
     fn allocate_gpu_cache(
         model_config: &Config,
         cache_config: &CacheConfig,
@@ -78,30 +360,16 @@ impl CacheEngine {
     ) -> Result<Vec<KVCache>, APIError> {
         assert!(cache_config.fully_init);
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let layout = Self::layout(model_config, dtype, cache_config.block_size)?;
         let mut gpu_cache = Vec::new();
         for _ in 0..model_config.num_hidden_layers {
             let key_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks.unwrap(),
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
+                layout.key_cache_shape(cache_config.initial_gpu_blocks()),
                 dtype,
                 device,
             ));
             let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+                layout.value_cache_shape(cache_config.initial_gpu_blocks()),
                 dtype,
                 device,
             ));
@@ -118,30 +386,16 @@ impl CacheEngine {
     ) -> Result<Vec<KVCache>, APIError> {
         assert!(cache_config.fully_init);
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let layout = Self::layout(model_config, dtype, cache_config.block_size)?;
         let mut cpu_cache = Vec::new();
         for _ in 0..model_config.num_hidden_layers {
             let key_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks.unwrap(),
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
+                layout.key_cache_shape(cache_config.num_cpu_blocks.unwrap()),
                 dtype,
                 device,
             ));
             let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+                layout.value_cache_shape(cache_config.num_cpu_blocks.unwrap()),
                 dtype,
                 device,
             ));
@@ -152,72 +406,125 @@ impl CacheEngine {
 }
 
 impl CacheEngine {
-    fn calculate_key_block_shape(
+    /// Layout of the KV cache of a layer of `model_config`.
+    fn layout(
         model_config: &Config,
         dtype: DType,
         block_size: usize,
-    ) -> (usize, usize, usize, usize) {
-        let element_size = dtype.size_in_bytes();
-        let x = 16 / element_size;
-        (
-            model_config.num_key_value_heads,
-            model_config.hidden_size / model_config.num_attention_heads / x,
-            block_size,
-            x,
-        )
-    }
-
-    fn calculate_value_block_shape(
-        model_config: &Config,
-        block_size: usize,
-    ) -> (usize, usize, usize) {
-        (
+    ) -> Result<KvCacheLayout, APIError> {
+        Ok(try_api!(KvCacheLayout::new(
             model_config.num_key_value_heads,
-            model_config.hidden_size / model_config.num_attention_heads,
+            model_config.get_head_size(),
             block_size,
-        )
+            dtype
+        )))
     }
 }
 
 impl CacheEngine {
     pub fn swap_in(&self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
-        for i in 0..self.num_layers {
-            let (src_key_cache, src_value_cache) = self.cpu_cache.get(i).unwrap();
-            let mut gpu_cache = self.get_kv_cache();
-            let (dst_key_cache, dst_value_cache) = gpu_cache.get_mut(i).unwrap();
-            // Swap (copy) key blocks
+        let mut gpu_cache = self.get_kv_cache();
+        let layers = self.cpu_cache.iter().zip(gpu_cache.iter_mut()).collect();
+        Self::swap_layers(self.swap_pool.as_ref(), layers, &src_to_dst)
+    }
+
+    pub fn swap_out(&mut self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
+        let gpu_cache = self.get_kv_cache().clone();
+        let layers = gpu_cache.iter().zip(self.cpu_cache.iter_mut()).collect();
+        Self::swap_layers(self.swap_pool.as_ref(), layers, &src_to_dst)
+    }
+
+    /// Copy the blocks `src_to_dst` of each layer from its source cache to its destination
+    /// cache, on the threads of `swap_pool`, a layer per thread at a time, if there is one.
+    fn swap_layers(
+        swap_pool: Option<&ThreadPool>,
+        layers: Vec<(&KVCache, &mut KVCache)>,
+        src_to_dst: &HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        let swap = |(src, dst): (&KVCache, &mut KVCache)| -> Result<(), APIError> {
+            let (src_key_cache, src_value_cache) = src;
+            let (dst_key_cache, dst_value_cache) = dst;
             try_api!(swap_blocks(
                 src_key_cache.clone(),
                 dst_key_cache,
                 src_to_dst.clone()
             ));
-            // Swap (copy) key blocks
             try_api!(swap_blocks(
                 src_value_cache.clone(),
                 dst_value_cache,
                 src_to_dst.clone()
             ));
+            Ok(())
+        };
+        match swap_pool {
+            Some(pool) => pool.install(|| layers.into_par_iter().try_for_each(swap)),
+            None => layers.into_iter().try_for_each(swap),
         }
-        Ok(())
     }
 
-    pub fn swap_out(&mut self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
-        for i in 0..self.num_layers {
-            let gpu_cache = self.get_kv_cache();
-            let (src_key_cache, src_value_cache) = gpu_cache.get(i).unwrap().clone();
-            drop(gpu_cache);
+    /// Copy the GPU blocks `block_ids` of each layer to the CPU.
+    pub fn export_blocks(&self, block_ids: &[usize]) -> Result<Vec<KVCache>, APIError> {
+        let ids = block_ids.iter().map(|&id| id as u32).collect::<Vec<_>>();
+        let gpu_cache = self.get_kv_cache();
+        let mut blocks = Vec::new();
+        for (key_blocks, value_blocks) in gpu_cache.iter() {
+            let export = |cache: &Tensor| {
+                let ids = Tensor::new(ids.as_slice(), cache.device())?;
+                cache.index_select(&ids, 0)?.to_device(&Device::Cpu)
+            };
+            blocks.push((try_api!(export(key_blocks)), try_api!(export(value_blocks))));
+        }
+        Ok(blocks)
+    }
 
-            let (dst_key_cache, dst_value_cache) = self.cpu_cache.get_mut(i).unwrap();
-            // Swap (copy) key blocks
+    /// Check that `blocks` exported by `export_blocks` fit the layout of this cache.
+    pub fn check_blocks(&self, blocks: &[KVCache]) -> Result<(), APIError> {
+        let gpu_cache = self.get_kv_cache();
+        if blocks.len() != gpu_cache.len() {
+            return Err(APIError::new(format!(
+                "The KV cache has {} layers instead of {}.",
+                blocks.len(),
+                gpu_cache.len()
+            )));
+        }
+        for ((key_blocks, value_blocks), (key_cache, value_cache)) in
+            blocks.iter().zip(gpu_cache.iter())
+        {
+            for (blocks, cache) in [(key_blocks, key_cache), (value_blocks, value_cache)] {
+                if blocks.dtype() != cache.dtype() || blocks.dims()[1..] != cache.dims()[1..] {
+                    return Err(APIError::new(format!(
+                        "KV cache blocks of shape {:?} and dtype {:?} do not match the {:?} \
+                        blocks of shape {:?} of this engine.",
+                        &blocks.dims()[1..],
+                        blocks.dtype(),
+                        cache.dtype(),
+                        &cache.dims()[1..],
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `blocks` checked by `check_blocks` to the GPU blocks `block_ids`, in order.
+    pub fn import_blocks(&self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError> {
+        let src_to_dst = block_ids
+            .iter()
+            .copied()
+            .enumerate()
+            .collect::<HashMap<_, _>>();
+        let mut gpu_cache = self.get_kv_cache();
+        for ((key_blocks, value_blocks), (key_cache, value_cache)) in
+            blocks.iter().zip(gpu_cache.iter_mut())
+        {
             try_api!(swap_blocks(
-                src_key_cache.clone(),
-                dst_key_cache,
+                key_blocks.clone(),
+                key_cache,
                 src_to_dst.clone()
             ));
-            // Swap (copy) key blocks
             try_api!(swap_blocks(
-                src_value_cache.clone(),
-                dst_value_cache,
+                value_blocks.clone(),
+                value_cache,
                 src_to_dst.clone()
             ));
         }
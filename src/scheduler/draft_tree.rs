@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::openai::responses::APIError;
+
+/// Tokens proposed for a sequence by speculative decoding. Node 0 is the last token of the
+/// sequence and node `i` the `i`-th proposed token, continuing its parent. A chain proposes a
+/// single continuation, a tree several ones sharing their common prefix, as multi-head proposers
+/// (Medusa, Eagle) do. All the nodes are verified in the same forward pass.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftTree {
+    tokens: Vec<u32>,
+    /// Parent of each proposed token, an earlier node.
+    parents: Vec<usize>,
+}
+
+impl DraftTree {
+    pub fn chain(tokens: Vec<u32>) -> Self {
+        let parents = (0..tokens.len()).collect();
+        Self { tokens, parents }
+    }
+
+    pub fn new(tokens: Vec<u32>, parents: Vec<usize>) -> Result<Self, APIError> {
+        if tokens.len() != parents.len() {
+            return Err(APIError::new(format!(
+                "A draft tree needs a parent for each of its {} tokens, got {}.",
+                tokens.len(),
+                parents.len()
+            )));
+        }
+        if let Some(i) = (0..parents.len()).find(|&i| parents[i] > i) {
+            return Err(APIError::new(format!(
+                "The parent of node {} of a draft tree has to be an earlier node, got {}.",
+                i + 1,
+                parents[i]
+            )));
+        }
+        Ok(Self { tokens, parents })
+    }
+
+    /// Proposed tokens, in node order.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn parents(&self) -> &[usize] {
+        &self.parents
+    }
+
+    /// Number of proposed tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Whether each proposed token continues the previous one.
+    pub fn is_chain(&self) -> bool {
+        self.parents
+            .iter()
+            .enumerate()
+            .all(|(i, &parent)| parent == i)
+    }
+
+    /// Add `token` continuing `parent`, returns its node.
+    pub fn push(&mut self, parent: usize, token: u32) -> usize {
+        assert!(parent <= self.len(), "no node {parent} in the draft tree");
+        self.tokens.push(token);
+        self.parents.push(parent);
+        self.len()
+    }
+
+    pub fn parent(&self, node: usize) -> Option<usize> {
+        node.checked_sub(1).map(|i| self.parents[i])
+    }
+
+    /// Number of tokens between `node` and the root, the offset of its position.
+    pub fn depth(&self, node: usize) -> usize {
+        let mut depth = 0;
+        let mut node = node;
+        while let Some(parent) = self.parent(node) {
+            depth += 1;
+            node = parent;
+        }
+        depth
+    }
+
+    /// Whether `other` is `node` or one of its ancestors, which `node` attends.
+    pub fn attends(&self, node: usize, other: usize) -> bool {
+        let mut node = Some(node);
+        while let Some(current) = node.filter(|&current| current >= other) {
+            if current == other {
+                return true;
+            }
+            node = self.parent(current);
+        }
+        false
+    }
+
+    /// First node continuing `node` with `token`.
+    pub fn child(&self, node: usize, token: u32) -> Option<usize> {
+        (node..self.len())
+            .find(|&i| self.parents[i] == node && self.tokens[i] == token)
+            .map(|i| i + 1)
+    }
+
+    /// Nodes accepted by the tokens sampled from the root on: the path followed as long as a
+    /// sampled token was proposed.
+    pub fn accepted_nodes(&self, sampled: impl IntoIterator<Item = u32>) -> Vec<usize> {
+        let mut nodes = Vec::new();
+        let mut node = 0;
+        for token in sampled {
+            let Some(child) = self.child(node, token) else {
+                break;
+            };
+            nodes.push(child);
+            node = child;
+        }
+        nodes
+    }
+}
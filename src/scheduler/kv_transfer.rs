@@ -0,0 +1,109 @@
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Device, Tensor};
+
+use crate::{openai::responses::APIError, try_api};
+
+use super::cache_engine::KVCache;
+
+/// The tokens of a sequence along with the KV cache of its blocks, exported from one engine to
+/// continue the sequence in another engine serving the same model.
+#[derive(Clone, Debug)]
+pub struct SequenceKV {
+    pub prompt_token_ids: Vec<u32>,
+    pub output_token_ids: Vec<u32>,
+    pub block_size: usize,
+    /// Key and value blocks of each layer, in the layout of the paged KV cache, on the CPU.
+    pub blocks: Vec<KVCache>,
+}
+
+impl SequenceKV {
+    /// Number of blocks of each layer.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks
+            .first()
+            .map_or(0, |(key_blocks, _)| key_blocks.dims()[0])
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.prompt_token_ids.len() + self.output_token_ids.len()
+    }
+
+    /// Write the sequence to the safetensors file at `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), APIError> {
+        try_api!(candle_core::safetensors::save(&self.tensors()?, path));
+        Ok(())
+    }
+
+    /// Read a sequence written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, APIError> {
+        Self::from_tensors(try_api!(candle_core::safetensors::load(path, &Device::Cpu)))
+    }
+
+    /// The sequence in the safetensors format of `save`, to send it over the network.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, APIError> {
+        Ok(try_api!(safetensors::serialize(&self.tensors()?, &None)))
+    }
+
+    /// Read a sequence serialized by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, APIError> {
+        Self::from_tensors(try_api!(candle_core::safetensors::load_buffer(
+            bytes,
+            &Device::Cpu
+        )))
+    }
+
+    fn tensors(&self) -> Result<HashMap<String, Tensor>, APIError> {
+        let tokens = |ids: &[u32]| Tensor::new(ids, &Device::Cpu);
+        let mut tensors = HashMap::new();
+        tensors.insert(
+            "prompt_token_ids".to_string(),
+            try_api!(tokens(&self.prompt_token_ids)),
+        );
+        tensors.insert(
+            "output_token_ids".to_string(),
+            try_api!(tokens(&self.output_token_ids)),
+        );
+        tensors.insert(
+            "block_size".to_string(),
+            try_api!(tokens(&[self.block_size as u32])),
+        );
+        for (i, (key_blocks, value_blocks)) in self.blocks.iter().enumerate() {
+            tensors.insert(format!("layers.{i}.key"), key_blocks.clone());
+            tensors.insert(format!("layers.{i}.value"), value_blocks.clone());
+        }
+        Ok(tensors)
+    }
+
+    fn from_tensors(mut tensors: HashMap<String, Tensor>) -> Result<Self, APIError> {
+        let mut take = |name: &str| {
+            tensors.remove(name).ok_or_else(|| {
+                APIError::new(format!("`{name}` is missing from the KV cache file."))
+            })
+        };
+        let tokens = |tensor: Tensor| -> Result<Vec<u32>, APIError> {
+            Ok(try_api!(try_api!(tensor.to_dtype(DType::U32)).to_vec1()))
+        };
+        let prompt_token_ids = tokens(take("prompt_token_ids")?)?;
+        let output_token_ids = tokens(take("output_token_ids")?)?;
+        let block_size = match tokens(take("block_size")?)?.as_slice() {
+            [block_size] => *block_size as usize,
+            _ => {
+                return Err(APIError::new_str(
+                    "Invalid block size in the KV cache file.",
+                ))
+            }
+        };
+        let mut blocks = Vec::new();
+        while let Ok(key_blocks) = take(&format!("layers.{}.key", blocks.len())) {
+            let value_blocks = take(&format!("layers.{}.value", blocks.len()))?;
+            blocks.push((key_blocks, value_blocks));
+        }
+        Ok(Self {
+            prompt_token_ids,
+            output_token_ids,
+            block_size,
+            blocks,
+        })
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{openai::responses::APIError, try_api};
+
+use super::kv_transfer::SequenceKV;
+
+/// Header of a message of `TcpKvTransport`, followed by the version of the format.
+const MAGIC: &[u8; 4] = b"CVKV";
+const VERSION: u8 = 1;
+/// Longest request id a message may carry.
+const MAX_REQUEST_ID_LEN: usize = 1 << 16;
+
+/// Moves the KV cache of sequences between engines on different nodes, for a node prefilling
+/// prompts to hand them over to nodes decoding them, see `LLMEngine::take_exported_kv` and
+/// `LLMEngine::add_request_with_kv`. `TcpKvTransport` is the baseline, RDMA or NVLink transports
+/// implement the same trait.
+pub trait KvTransport: Send + Sync {
+    /// Send the KV cache of the sequence of `request_id` to the node at `peer`, returning once
+    /// the node received it.
+    fn send(&self, peer: &str, request_id: &str, kv: &SequenceKV) -> Result<(), APIError>;
+
+    /// Wait for the next sequence sent to this node, with its request id.
+    fn receive(&self) -> Result<(String, SequenceKV), APIError>;
+}
+
+/// Sequences sent over TCP, one connection per sequence. A message is the magic `CVKV`, the
+/// version of the format, the length (u32) and bytes of the request id, and the length (u64) and
+/// bytes of the sequence in the safetensors format of `SequenceKV::to_bytes`, all little endian.
+/// The receiver acknowledges it with a byte once it is read.
+pub struct TcpKvTransport {
+    listener: TcpListener,
+}
+
+impl TcpKvTransport {
+    /// Receive sequences on `addr`, e.g. `0.0.0.0:7070`, or port 0 for any free port.
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self, APIError> {
+        Ok(Self {
+            listener: try_api!(TcpListener::bind(addr)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, APIError> {
+        Ok(try_api!(self.listener.local_addr()))
+    }
+}
+
+impl KvTransport for TcpKvTransport {
+    fn send(&self, peer: &str, request_id: &str, kv: &SequenceKV) -> Result<(), APIError> {
+        if request_id.len() > MAX_REQUEST_ID_LEN {
+            return Err(APIError::new(format!(
+                "The request id is longer than {MAX_REQUEST_ID_LEN} bytes."
+            )));
+        }
+        let bytes = kv.to_bytes()?;
+        let mut stream = try_api!(TcpStream::connect(peer));
+        let mut header = Vec::with_capacity(17 + request_id.len());
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&(request_id.len() as u32).to_le_bytes());
+        header.extend_from_slice(request_id.as_bytes());
+        header.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        try_api!(stream.write_all(&header));
+        try_api!(stream.write_all(&bytes));
+        try_api!(stream.flush());
+        let mut ack = [0u8];
+        if stream.read_exact(&mut ack).is_err() {
+            return Err(APIError::new(format!(
+                "{peer} did not acknowledge the KV cache of {request_id}."
+            )));
+        }
+        Ok(())
+    }
+
+    fn receive(&self) -> Result<(String, SequenceKV), APIError> {
+        let (mut stream, peer) = try_api!(self.listener.accept());
+        let mut header = [0u8; 9];
+        try_api!(stream.read_exact(&mut header));
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(APIError::new(format!(
+                "{peer} did not send a KV cache message of version {VERSION}."
+            )));
+        }
+        let request_id_len = u32::from_le_bytes(header[5..].try_into().unwrap()) as usize;
+        if request_id_len > MAX_REQUEST_ID_LEN {
+            return Err(APIError::new(format!(
+                "{peer} sent a request id of {request_id_len} bytes."
+            )));
+        }
+        let mut request_id = vec![0u8; request_id_len];
+        try_api!(stream.read_exact(&mut request_id));
+        let request_id = try_api!(String::from_utf8(request_id));
+        let mut len = [0u8; 8];
+        try_api!(stream.read_exact(&mut len));
+        let len = u64::from_le_bytes(len);
+        // Read as it arrives rather than allocated up front from the length the peer claims.
+        let mut bytes = Vec::new();
+        try_api!((&mut stream).take(len).read_to_end(&mut bytes));
+        if bytes.len() as u64 != len {
+            return Err(APIError::new(format!(
+                "{peer} closed the connection after {} of the {len} bytes of {request_id}.",
+                bytes.len()
+            )));
+        }
+        let kv = SequenceKV::from_bytes(&bytes)?;
+        try_api!(stream.write_all(&[1]));
+        Ok((request_id, kv))
+    }
+}
@@ -9,7 +9,24 @@ pub mod block_engine;
 /// actually allocates the KV cache for the CPU and GPU. It is used by the LLMEngine to execute
 /// operations issued by the scheduler.
 pub mod cache_engine;
+/// Tokens proposed by speculative decoding, as a chain or a tree of continuations.
+pub mod draft_tree;
+/// Export and import of the KV cache of single sequences, to move them between engines.
+pub mod kv_transfer;
+/// Transports moving the KV cache of sequences between nodes, for disaggregated serving.
+pub mod kv_transport;
+/// Order the groups are admitted and served in, batch composition and preemption victims.
+pub mod policy;
+/// Proposals of speculative decoding looked up in the tokens of a sequence.
+pub mod prompt_lookup;
+/// Write-ahead log of the accepted requests, to report or replay the ones lost by a crash.
+pub mod request_log;
 pub mod sequence;
+/// KV cache kept between the turns of a conversation, so that the next turn only prefills its
+/// new tokens.
+pub mod session_cache;
+/// Fixed-size recurrent states of the sequences of hybrid models, alongside their KV cache.
+pub mod state_cache;
 
 type CPUBlockFrom = usize;
 type GPUBlockFrom = usize;
@@ -19,13 +36,25 @@ type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    iter::{once, zip},
     sync::Arc,
+    time::{Instant, SystemTime},
 };
 
+use crate::openai::responses::APIError;
 use crate::scheduler::{block_engine::AllocStatus, sequence::SequenceStatus};
+use serde::{Deserialize, Serialize};
 
-use self::{block_engine::BlockEngine, cache_engine::CacheConfig, sequence::SequenceGroup};
+use self::{
+    block_engine::BlockEngine,
+    cache_engine::CacheConfig,
+    policy::{Fcfs, SchedulingPolicy},
+    prompt_lookup::PromptLookupConfig,
+    sequence::SequenceGroup,
+    session_cache::{Session, SessionCache},
+};
 
 pub struct SchedulerOutput {
     pub scheduled: Arc<VecDeque<Arc<SequenceGroup>>>,
@@ -33,10 +62,216 @@ pub struct SchedulerOutput {
     pub blocks_to_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
     pub ignored_seq_groups: Arc<VecDeque<Arc<SequenceGroup>>>,
+    /// Groups that ran past their deadline, finished with the `timeout` finish reason.
+    pub timed_out: Arc<VecDeque<Arc<SequenceGroup>>>,
+}
+
+/// Point-in-time view of a sequence, its status and the physical blocks backing it.
+#[derive(Clone, Debug, Serialize)]
+pub struct SequenceSnapshot {
+    pub seq_id: usize,
+    pub status: String,
+    pub prompt_len: usize,
+    pub len: usize,
+    /// Tokens evicted from the KV cache with attention sinks, `len` counts them.
+    pub num_evicted_tokens: usize,
+    pub block_table: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SequenceGroupSnapshot {
+    pub group_id: usize,
+    pub request_id: String,
+    pub arrival_time: u64,
+    /// When the group was added to the engine and first scheduled, in milliseconds since the
+    /// Unix epoch.
+    pub added_time_ms: u64,
+    pub first_scheduled_time_ms: Option<u64>,
+    pub seqs: Vec<SequenceSnapshot>,
+}
+
+/// Dump of the scheduler queues and block usage, used to diagnose preemption and fragmentation.
+/// Block tables of groups in `swapped_out` refer to CPU blocks, all others to GPU blocks.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SchedulerSnapshot {
+    /// Name of the model. Set by the engine.
+    pub model: String,
+    pub running: Vec<SequenceGroupSnapshot>,
+    pub waiting: Vec<SequenceGroupSnapshot>,
+    pub swapped_out: Vec<SequenceGroupSnapshot>,
+    /// Name of the scheduling policy.
+    pub policy: String,
+    pub block_size: usize,
+    pub num_gpu_blocks: usize,
+    /// GPU blocks backed by the cache, less than `num_gpu_blocks` while it grows lazily.
+    pub num_allocated_gpu_blocks: usize,
+    pub num_free_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+    pub num_free_cpu_blocks: usize,
+    /// Conversations whose KV cache is kept for their next turn, their GPU blocks are not free.
+    pub num_sessions: usize,
+    /// Steps that moved blocks to compact the GPU cache, and the blocks they moved.
+    pub num_compaction_steps: usize,
+    pub num_compacted_blocks: usize,
+    /// Blocks swapped out to the CPU by preemption and swapped back in to the GPU so far.
+    pub num_swapped_out_blocks: usize,
+    pub num_swapped_in_blocks: usize,
+    /// Tokens proposed by speculative decoding so far, and the ones accepted. Set by the engine.
+    pub num_proposed_tokens: usize,
+    pub num_accepted_tokens: usize,
+    /// Share of the proposed tokens that were accepted, `None` if none was proposed.
+    pub acceptance_rate: Option<f64>,
+}
+
+/// Usage of the blocks of the KV cache, for monitoring and autoscaling.
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct CacheStats {
+    pub num_gpu_blocks: usize,
+    /// GPU blocks backed by the cache, less than `num_gpu_blocks` while it grows lazily.
+    pub num_allocated_gpu_blocks: usize,
+    pub num_free_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+    pub num_free_cpu_blocks: usize,
+    /// Blocks of each running sequence on the GPU and of each swapped out one on the CPU, by
+    /// sequence id. Blocks shared by the choices of a request count for each of them.
+    pub gpu_blocks_per_seq: BTreeMap<usize, usize>,
+    pub cpu_blocks_per_seq: BTreeMap<usize, usize>,
+    /// Share of the slots of the GPU blocks in use that hold no token, lost to partially filled
+    /// last blocks.
+    pub fragmentation: f64,
+    /// Share of the prompt blocks found in a prefix cache, `None` without one.
+    pub prefix_cache_hit_rate: Option<f64>,
+    /// Steps that moved blocks to compact the GPU cache, and the blocks they moved.
+    pub num_compaction_steps: usize,
+    pub num_compacted_blocks: usize,
+    /// Blocks swapped out to the CPU by preemption and swapped back in to the GPU so far.
+    pub num_swapped_out_blocks: usize,
+    pub num_swapped_in_blocks: usize,
+}
+
+/// A request in the queues of the scheduler, as listed by `GET /v1/requests`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ActiveRequest {
+    pub id: String,
+    pub model: String,
+    /// `waiting`, `running` or `swapped`.
+    pub state: String,
+    pub prompt_tokens: usize,
+    /// Tokens generated so far by the choices of the request.
+    pub completion_tokens: usize,
+    /// Time the request waited to be scheduled, and ran since, until now.
+    pub queue_time_ms: u64,
+    pub run_time_ms: u64,
+}
+
+impl SchedulerSnapshot {
+    /// The requests in the queues at `now_ms` (milliseconds since the Unix epoch), running ones
+    /// first. A request preempted by recomputation is `waiting` again but keeps running since it
+    /// was first scheduled.
+    pub fn active_requests(&self, now_ms: u64) -> Vec<ActiveRequest> {
+        [
+            ("running", &self.running),
+            ("swapped", &self.swapped_out),
+            ("waiting", &self.waiting),
+        ]
+        .into_iter()
+        .flat_map(|(state, groups)| groups.iter().map(move |group| (state, group)))
+        .map(|(state, group)| {
+            let scheduled_ms = group.first_scheduled_time_ms.unwrap_or(now_ms);
+            ActiveRequest {
+                id: group.request_id.clone(),
+                model: self.model.clone(),
+                state: state.to_string(),
+                prompt_tokens: group.seqs.first().map_or(0, |seq| seq.prompt_len),
+                completion_tokens: group
+                    .seqs
+                    .iter()
+                    .map(|seq| seq.len.saturating_sub(seq.prompt_len))
+                    .sum(),
+                queue_time_ms: scheduled_ms.saturating_sub(group.added_time_ms),
+                run_time_ms: now_ms.saturating_sub(scheduled_ms),
+            }
+        })
+        .collect()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let blocks_per_seq = |groups: &[SequenceGroupSnapshot]| {
+            groups
+                .iter()
+                .flat_map(|group| &group.seqs)
+                .filter(|seq| !seq.block_table.is_empty())
+                .map(|seq| (seq.seq_id, seq.block_table.len()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        // Tokens held by each GPU block, shared blocks hold the most of their sequences.
+        let mut block_tokens = HashMap::new();
+        for seq in self.running.iter().flat_map(|group| &group.seqs) {
+            let num_cached = seq.len - seq.num_evicted_tokens;
+            for (i, &block) in seq.block_table.iter().enumerate() {
+                let tokens = num_cached
+                    .saturating_sub(i * self.block_size)
+                    .min(self.block_size);
+                let held = block_tokens.entry(block).or_insert(0);
+                *held = tokens.max(*held);
+            }
+        }
+        let num_slots = block_tokens.len() * self.block_size;
+        let fragmentation = if num_slots == 0 {
+            0.
+        } else {
+            1. - block_tokens.values().sum::<usize>() as f64 / num_slots as f64
+        };
+        CacheStats {
+            num_gpu_blocks: self.num_gpu_blocks,
+            num_allocated_gpu_blocks: self.num_allocated_gpu_blocks,
+            num_free_gpu_blocks: self.num_free_gpu_blocks,
+            num_cpu_blocks: self.num_cpu_blocks,
+            num_free_cpu_blocks: self.num_free_cpu_blocks,
+            gpu_blocks_per_seq: blocks_per_seq(&self.running),
+            cpu_blocks_per_seq: blocks_per_seq(&self.swapped_out),
+            fragmentation,
+            prefix_cache_hit_rate: None,
+            num_compaction_steps: self.num_compaction_steps,
+            num_compacted_blocks: self.num_compacted_blocks,
+            num_swapped_out_blocks: self.num_swapped_out_blocks,
+            num_swapped_in_blocks: self.num_swapped_in_blocks,
+        }
+    }
 }
 
 pub struct SchedulerConfig {
     pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens prefilled in one step. The first prompt of a step is
+    /// prefilled even if it is longer, alone.
+    pub max_num_prefill_tokens: Option<usize>,
+    /// Prompts longer than this are long prefills. At most `max_long_prefills` of them are
+    /// prefilled in one step, never in two steps in a row while sequences are decoding, and the
+    /// shorter prompts queued behind them may be prefilled first.
+    pub long_prefill_token_threshold: Option<usize>,
+    pub max_long_prefills: usize,
+    /// Speculative decoding by prompt lookup, the proposed tokens of a sequence are verified in
+    /// the same step as its last token.
+    pub prompt_lookup: Option<PromptLookupConfig>,
+    /// Run each scheduled sequence through the model in its own forward pass, so that its logits
+    /// are bit-identical whichever sequences it is batched with. Prompts are then not padded to
+    /// the longest prompt of the step and the kernels, which differ with the number of rows, are
+    /// picked for the sequence alone. Throughput drops accordingly.
+    pub batch_invariant: bool,
+    /// Conversations whose KV cache is kept once a turn finished, for the next turn to only
+    /// prefill its new tokens, see `SamplingParams::conversation_id`. 0 keeps none.
+    pub max_sessions: usize,
+    /// Move up to this many blocks of the running sequences per decode step to pack them at the
+    /// end of the GPU cache, leaving the free blocks in one range for the next long prompt. The
+    /// moves are copies of the KV cache, done with the copies on write of the step.
+    pub compaction_blocks: Option<usize>,
+}
+
+/// Limits of the scheduler that can be changed while it runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SchedulerLimits {
+    pub max_num_seqs: usize,
+    pub max_num_prefill_tokens: Option<usize>,
 }
 
 pub struct Scheduler {
@@ -44,41 +279,162 @@ pub struct Scheduler {
     running: VecDeque<Arc<SequenceGroup>>,
     swapped_out: VecDeque<Arc<SequenceGroup>>,
     config: SchedulerConfig,
+    policy: Arc<dyn SchedulingPolicy>,
     pub block_engine: BlockEngine,
+    /// Whether the last prefill step had a long prefill.
+    prefilled_long: bool,
+    /// Maximum number of running sequences, lowered when a step ran out of memory.
+    max_batch_seqs: Option<usize>,
+    sessions: SessionCache,
+    num_compaction_steps: usize,
+    num_compacted_blocks: usize,
+    num_swapped_out_blocks: usize,
+    num_swapped_in_blocks: usize,
 }
 
 impl Scheduler {
     pub fn new(config: SchedulerConfig, cache_config: &CacheConfig) -> Self {
         assert!(cache_config.fully_init);
+        let mut block_engine = BlockEngine::new(
+            cache_config.block_size,
+            cache_config.num_gpu_blocks.unwrap(),
+            cache_config.num_cpu_blocks.unwrap(),
+        );
+        if let Some(growth_blocks) = cache_config.gpu_growth_blocks {
+            block_engine = block_engine.with_gpu_growth_blocks(growth_blocks);
+        }
+        if let Some(attention_sinks) = cache_config.attention_sinks {
+            block_engine = block_engine.with_attention_sinks(attention_sinks);
+        }
+        if let Some(num_slots) = cache_config.num_state_slots {
+            block_engine = block_engine.with_state_slots(num_slots);
+        }
         Self {
             waiting: VecDeque::new(),
             running: VecDeque::new(),
             swapped_out: VecDeque::new(),
+            sessions: SessionCache::new(config.max_sessions),
             config,
-            block_engine: BlockEngine::new(
-                cache_config.block_size,
-                cache_config.num_gpu_blocks.unwrap(),
-                cache_config.num_cpu_blocks.unwrap(),
-            ),
+            policy: Arc::new(Fcfs),
+            block_engine,
+            prefilled_long: false,
+            max_batch_seqs: None,
+            num_compaction_steps: 0,
+            num_compacted_blocks: 0,
+            num_swapped_out_blocks: 0,
+            num_swapped_in_blocks: 0,
+        }
+    }
+
+    /// Schedule the groups with `policy` from now on, `Fcfs` by default. The queued groups are
+    /// reordered by it.
+    pub fn set_policy(&mut self, policy: Arc<dyn SchedulingPolicy>) {
+        self.policy = policy;
+        for queue in [&mut self.waiting, &mut self.running, &mut self.swapped_out] {
+            sort_by_policy(&*self.policy, queue);
         }
     }
 
+    /// The group waits behind the waiting groups the policy does not serve after it.
     pub fn add_sequence(&mut self, seq_group: SequenceGroup) {
-        self.waiting.push_back(Arc::new(seq_group));
+        let index = self
+            .waiting
+            .iter()
+            .position(|group| self.policy.compare(&seq_group, group) == Ordering::Less)
+            .unwrap_or(self.waiting.len());
+        self.waiting.insert(index, Arc::new(seq_group));
+    }
+
+    /// Allocate the blocks of a group whose KV cache is written by the caller and add it to the
+    /// running groups, skipping the prefill. Returns the group, or `None` if its blocks cannot be
+    /// allocated now.
+    pub fn add_running_sequence(&mut self, seq_group: SequenceGroup) -> Option<Arc<SequenceGroup>> {
+        if !matches!(self.block_engine.can_allocate(&seq_group), AllocStatus::Ok) {
+            return None;
+        }
+        seq_group.set_status(SequenceStatus::Running);
+        self._allocate(&seq_group);
+        let seq_group = Arc::new(seq_group);
+        self.running.push_back(seq_group.clone());
+        Some(seq_group)
+    }
+
+    /// Add a group continuing a conversation to the running groups, on top of the KV cache kept
+    /// for its previous turn, see `SessionCache`. Only the tokens of its prompt past the ones it
+    /// shares with the turn are written, by its first decode step. Returns the group and the
+    /// number of prompt tokens found in the cache, or gives the group back if none were or its
+    /// blocks cannot be allocated now. The cache of the conversation is dropped either way.
+    pub fn resume_conversation(
+        &mut self,
+        seq_group: SequenceGroup,
+    ) -> Result<(Arc<SequenceGroup>, usize), SequenceGroup> {
+        let Some(session) = seq_group
+            .sampling_params
+            .conversation_id
+            .as_ref()
+            .and_then(|conversation_id| self.sessions.take(conversation_id))
+        else {
+            return Err(seq_group);
+        };
+        let seq = seq_group.get_seqs().values().next().unwrap().clone();
+        let prompt = seq.deref().get_token_ids();
+        // The last token of the prompt is run to sample the first token.
+        let num_cached_tokens = zip(&session.token_ids, &prompt[..prompt.len() - 1])
+            .take_while(|(cached, token)| cached == token)
+            .count();
+        if num_cached_tokens == 0
+            || seq_group.get_seqs().len() != 1
+            || seq_group.pixel_values.is_some()
+        {
+            self.block_engine.free_blocks(session.blocks);
+            return Err(seq_group);
+        }
+        if let Err(blocks) =
+            self.block_engine
+                .allocate_with_prefix(&seq, session.blocks, num_cached_tokens)
+        {
+            self.block_engine.free_blocks(blocks);
+            return Err(seq_group);
+        }
+        seq.deref_mut()
+            .set_num_cached_tokens(Some(num_cached_tokens));
+        seq_group.set_status(SequenceStatus::Running);
+        let seq_group = Arc::new(seq_group);
+        self.running.push_back(seq_group.clone());
+        Ok((seq_group, num_cached_tokens))
+    }
+
+    /// Drop the KV cache kept for every conversation.
+    pub fn clear_sessions(&mut self) {
+        while self.evict_session() {}
     }
 
     pub fn schedule(&mut self) -> SchedulerOutput {
+        let timed_out = Arc::new(self.finish_timed_out_seq_groups());
+        for session in self.sessions.expire(Instant::now()) {
+            self.block_engine.free_blocks(session.blocks);
+        }
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
         if self.swapped_out.is_empty() {
             let mut scheduled = VecDeque::new();
             let mut ignored_seq_groups = VecDeque::new();
-            while !self.waiting.is_empty() {
-                let seq_group = self.waiting.front().unwrap().clone();
-
+            // Long prompts over the limit keep their place in the queue.
+            let mut deferred = VecDeque::new();
+            let mut num_prefill_tokens = 0;
+            let mut num_long_prefills = 0;
+            // Decoding sequences get a step between two steps with long prefills.
+            self.policy.reorder(&mut self.waiting, &self.running);
+            let max_long_prefills = if self.prefilled_long && !self.running.is_empty() {
+                0
+            } else {
+                self.config.max_long_prefills
+            };
+            while let Some(seq_group) = self.waiting.front().cloned() {
                 // If adding this seq means we will have too many, stop as no more could be added.
                 if self.config.max_num_seqs
-                    == self
+                    <= self
                         .running
                         .iter()
                         .map(|group| group.get_seqs().len())
@@ -88,17 +444,56 @@ impl Scheduler {
                     break;
                 }
 
+                if self
+                    .max_batch_seqs
+                    .is_some_and(|max| self.num_running_seqs() + seq_group.get_seqs().len() > max)
+                {
+                    break;
+                }
+
+                let prompt_len = seq_group.get_prompt_len();
+                let is_long = self
+                    .config
+                    .long_prefill_token_threshold
+                    .is_some_and(|threshold| prompt_len > threshold);
+                if (is_long && num_long_prefills >= max_long_prefills)
+                    || !self.policy.admit(&seq_group, &scheduled)
+                {
+                    deferred.push_back(self.waiting.pop_front().unwrap());
+                    continue;
+                }
+                // Every sequence of the group has its prompt prefilled, the negative prompt of
+                // guided groups has a length of its own.
+                let prefill_tokens = seq_group
+                    .get_seqs()
+                    .values()
+                    .map(|seq| seq.deref().get_prompt_len())
+                    .sum::<usize>();
+                if !scheduled.is_empty()
+                    && self
+                        .config
+                        .max_num_prefill_tokens
+                        .is_some_and(|max| num_prefill_tokens + prefill_tokens > max)
+                {
+                    break;
+                }
+
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
-                let can_allocate = self.block_engine.can_allocate(&seq_group);
+                let mut can_allocate = self.block_engine.can_allocate(&seq_group);
+                // The KV cache kept for conversations gives way to the requests.
+                while matches!(can_allocate, AllocStatus::Later) && self.evict_session() {
+                    can_allocate = self.block_engine.can_allocate(&seq_group);
+                }
                 match can_allocate {
                     AllocStatus::Later => break, //If we can only allocate later, do not bother iterating over the rest.
                     AllocStatus::Impossible => {
                         println!(
                             "Input prompt with length of {} tokens is too long and exceeds capacity of block engine.",
-                            seq_group.get_prompt_len()
+                            prompt_len
                         );
                         seq_group.set_status(SequenceStatus::FinishedIgnored);
                         ignored_seq_groups.push_back(self.waiting.pop_front().unwrap());
+                        continue;
                     }
                     _ => {}
                 }
@@ -109,32 +504,40 @@ impl Scheduler {
                 let seq_group = self.waiting.pop_front().unwrap();
                 self.running.push_back(seq_group.clone());
                 scheduled.push_back(seq_group);
+                num_prefill_tokens += prefill_tokens;
+                if is_long {
+                    num_long_prefills += 1;
+                }
+            }
+            while let Some(seq_group) = deferred.pop_back() {
+                self.waiting.push_front(seq_group);
             }
 
             // If we did schedule, or we ignored sequences.
             if !scheduled.is_empty() || !ignored_seq_groups.is_empty() {
+                self.prefilled_long = num_long_prefills > 0;
                 return SchedulerOutput {
                     scheduled: Arc::new(scheduled),
                     blocks_to_swap_in: HashMap::new(),
                     blocks_to_copy: HashMap::new(),
                     blocks_to_swap_out: HashMap::new(),
                     ignored_seq_groups: Arc::new(ignored_seq_groups),
+                    timed_out,
                 };
             }
         }
+        self.prefilled_long = false;
 
         let mut blocks_to_swap_out = HashMap::new();
         let mut blocks_to_swap_in = HashMap::new();
         let mut blocks_to_copy = HashMap::new();
 
-        // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
-        // Preempt lowest priority sequences that are in the running queue, forming a
+        // Reserve token slots for the running sequence groups in the order of the policy,
+        // preempting the victims it selects among the groups served after them, forming a
         // new running queue that has the actually running sequences. Remember the preempted
         // sequences, which will be put into the waiting or swapped out state depending on
         // the preemption method (recompute or swap, respectively).
-
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
-        self.sort_running_by_priority_fcfs();
+        sort_by_policy(&*self.policy, &mut self.running);
 
         let mut running = VecDeque::new();
         let mut preempted = VecDeque::new();
@@ -143,9 +546,13 @@ impl Scheduler {
             let mut finished_with_break = false;
             while !self.block_engine.can_append_token_to_seq(&seq_group) {
                 // If we cannot, now we need to preempt some seqs
+                if self.evict_session() {
+                    continue;
+                }
                 if !self.running.is_empty() {
                     // There is something to preempt.
-                    let seq_to_preempt = self.running.pop_back().unwrap();
+                    let victim = self.policy.select_victim(&self.running);
+                    let seq_to_preempt = self.running.remove(victim).unwrap();
                     self._preempt(seq_to_preempt.clone(), &mut blocks_to_swap_out);
                     preempted.push_back(seq_to_preempt);
                 } else {
@@ -166,10 +573,8 @@ impl Scheduler {
         self.running = running;
 
         // Try to swap in the swapped out sequences and add these to the
-        // running state if possible.
-
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
-        self.sort_swapped_out_by_priority_fcfs();
+        // running state if possible, in the order of the policy.
+        sort_by_policy(&*self.policy, &mut self.swapped_out);
 
         if preempted.is_empty() {
             while !self.swapped_out.is_empty() {
@@ -179,10 +584,17 @@ impl Scheduler {
                 if !self.block_engine.can_swap_in_seq_group(seq_group) {
                     break;
                 }
+                if self
+                    .max_batch_seqs
+                    .is_some_and(|max| self.num_running_seqs() + seq_group.get_seqs().len() > max)
+                {
+                    break;
+                }
 
                 let seq_group = self.swapped_out.pop_front().unwrap();
                 // Swap in the blocks
                 let to_swap_in = self.block_engine.swap_in(&seq_group);
+                self.num_swapped_in_blocks += to_swap_in.len();
                 blocks_to_swap_in.extend(to_swap_in);
                 // Reserve a new slot
                 self._append_token_slot_to_seq_group(&seq_group, &mut blocks_to_copy);
@@ -190,19 +602,151 @@ impl Scheduler {
             }
         }
 
+        // Swaps are done before the copies, a block swapped out could be overwritten first.
+        if let Some(max_blocks) = self.config.compaction_blocks {
+            if blocks_to_swap_in.is_empty() && blocks_to_swap_out.is_empty() {
+                self.compact_blocks(max_blocks, &mut blocks_to_copy);
+            }
+        }
+
         SchedulerOutput {
             scheduled: self.running.clone().into(),
             blocks_to_swap_in,
             blocks_to_copy,
             blocks_to_swap_out,
             ignored_seq_groups: Arc::new(VecDeque::new()),
+            timed_out,
         }
     }
 
+    /// Groups waiting to be scheduled, the new ones and the ones preempted by recomputation.
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn num_free_gpu_blocks(&self) -> usize {
+        self.block_engine.get_num_free_gpu_blocks()
+    }
+
     pub fn has_unfinished_sequences(&self) -> bool {
         !self.running.is_empty() || !self.waiting.is_empty()
     }
 
+    pub fn snapshot(&self) -> SchedulerSnapshot {
+        let dump = |queue: &VecDeque<Arc<SequenceGroup>>| {
+            queue
+                .iter()
+                .map(|group| self.snapshot_seq_group(group))
+                .collect::<Vec<_>>()
+        };
+        SchedulerSnapshot {
+            model: String::new(),
+            running: dump(&self.running),
+            waiting: dump(&self.waiting),
+            swapped_out: dump(&self.swapped_out),
+            policy: self.policy.name().to_string(),
+            block_size: self.block_engine.get_block_size(),
+            num_gpu_blocks: self.block_engine.get_num_gpu_blocks(),
+            num_allocated_gpu_blocks: self.block_engine.get_num_allocated_gpu_blocks(),
+            num_free_gpu_blocks: self.block_engine.get_num_free_gpu_blocks(),
+            num_cpu_blocks: self.block_engine.get_num_cpu_blocks(),
+            num_free_cpu_blocks: self.block_engine.get_num_free_cpu_blocks(),
+            num_sessions: self.sessions.len(),
+            num_compaction_steps: self.num_compaction_steps,
+            num_compacted_blocks: self.num_compacted_blocks,
+            num_swapped_out_blocks: self.num_swapped_out_blocks,
+            num_swapped_in_blocks: self.num_swapped_in_blocks,
+            num_proposed_tokens: 0,
+            num_accepted_tokens: 0,
+            acceptance_rate: None,
+        }
+    }
+
+    /// Shrink the batch after the step of the `scheduled` groups ran out of memory. The groups of
+    /// a prompt step go back to waiting, the half of the running groups of a decode step the
+    /// policy serves last is preempted, and the running sequences are limited to what is left of the batch from then on.
+    /// Returns the blocks to swap out, or `None` when a single group is scheduled.
+    pub fn shrink_batch(
+        &mut self,
+        scheduled: &VecDeque<Arc<SequenceGroup>>,
+        is_prompt: bool,
+    ) -> Option<HashMap<GPUBlockFrom, CPUBlockTo>> {
+        if scheduled.len() <= 1 {
+            return None;
+        }
+        let mut blocks_to_swap_out = HashMap::new();
+        let max_batch_seqs = if is_prompt {
+            let not_scheduled = |group: &Arc<SequenceGroup>| {
+                !scheduled
+                    .iter()
+                    .any(|scheduled| scheduled.get_id() == group.get_id())
+            };
+            self.running.retain(not_scheduled);
+            for seq_group in scheduled.iter().rev() {
+                seq_group.set_status(SequenceStatus::Waiting);
+                self._free(seq_group);
+                self.waiting.push_front(seq_group.clone());
+            }
+            let num_scheduled_seqs = scheduled
+                .iter()
+                .map(|group| group.get_seqs().len())
+                .sum::<usize>();
+            self.num_running_seqs() + (num_scheduled_seqs / 2).max(1)
+        } else {
+            sort_by_policy(&*self.policy, &mut self.running);
+            let last = self
+                .running
+                .split_off(self.running.len() - self.running.len() / 2);
+            // Recomputed groups go to the front of the waiting queue, in their order.
+            for seq_group in last.into_iter().rev() {
+                self._preempt(seq_group, &mut blocks_to_swap_out);
+            }
+            self.num_running_seqs()
+        };
+        self.max_batch_seqs = Some(
+            self.max_batch_seqs
+                .map_or(max_batch_seqs, |max| max.min(max_batch_seqs)),
+        );
+        Some(blocks_to_swap_out)
+    }
+
+    pub fn limits(&self) -> SchedulerLimits {
+        SchedulerLimits {
+            max_num_seqs: self.config.max_num_seqs,
+            max_num_prefill_tokens: self.config.max_num_prefill_tokens,
+        }
+    }
+
+    /// The new limits apply from the next `schedule`, running sequences over them are not
+    /// preempted and finish.
+    pub fn set_limits(&mut self, limits: SchedulerLimits) {
+        self.config.max_num_seqs = limits.max_num_seqs;
+        self.config.max_num_prefill_tokens = limits.max_num_prefill_tokens;
+    }
+
+    /// Maximum number of running sequences since a step ran out of memory.
+    pub fn get_max_batch_seqs(&self) -> Option<usize> {
+        self.max_batch_seqs
+    }
+
+    /// Abort every sequence group and free its blocks, returning the aborted groups.
+    pub fn abort_all(&mut self) -> Vec<Arc<SequenceGroup>> {
+        // Waiting groups have no blocks, recomputed ones were freed when they were preempted.
+        let waiting = std::mem::take(&mut self.waiting);
+        let allocated = std::mem::take(&mut self.running)
+            .into_iter()
+            .chain(std::mem::take(&mut self.swapped_out))
+            .collect::<Vec<_>>();
+        for group in &allocated {
+            self._free(group);
+        }
+        let aborted = allocated.into_iter().chain(waiting).collect::<Vec<_>>();
+        for group in &aborted {
+            group.set_status(SequenceStatus::FinishedAborted);
+        }
+        aborted
+    }
+
     pub fn free_finished_sequence_groups(&mut self) {
         let mut to_free = Vec::new();
         let clone = self.running.clone();
@@ -219,7 +763,9 @@ impl Scheduler {
             .cloned()
             .collect::<VecDeque<_>>();
         for group in to_free {
-            self._free(&group);
+            if !self.keep_session(&group) {
+                self._free(&group);
+            }
         }
     }
 }
@@ -251,12 +797,48 @@ impl Scheduler {
             self.swapped_out.remove(idx);
         };
     }
+    fn snapshot_seq_group(&self, seq_group: &SequenceGroup) -> SequenceGroupSnapshot {
+        let mut seqs = seq_group
+            .get_seqs()
+            .values()
+            .map(|seq| {
+                let seq = seq.deref();
+                SequenceSnapshot {
+                    seq_id: seq.get_id(),
+                    status: format!("{:?}", seq.get_status()),
+                    prompt_len: seq.get_prompt_len(),
+                    len: seq.get_len(),
+                    num_evicted_tokens: seq.get_num_evicted_tokens(),
+                    block_table: self
+                        .block_engine
+                        .get_block_table_ids(seq.get_id())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect::<Vec<_>>();
+        seqs.sort_by_key(|seq| seq.seq_id);
+        let metrics = seq_group.metrics();
+        let unix_ms = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        };
+        SequenceGroupSnapshot {
+            group_id: *seq_group.get_id(),
+            request_id: seq_group.get_request_id().clone(),
+            arrival_time: seq_group.arrival_time(),
+            added_time_ms: unix_ms(metrics.arrival_time),
+            first_scheduled_time_ms: metrics.first_scheduled_time.map(unix_ms),
+            seqs,
+        }
+    }
+
     fn _append_token_slot_to_seq_group(
         &mut self,
         seq_group: &SequenceGroup,
         blocks_to_copy: &mut HashMap<usize, Vec<usize>>,
     ) {
-        for seq in seq_group.get_seqs().values() {
+        for seq in seq_group.get_unfinished_seqs() {
             let op = self.block_engine.append_token_slot_to_seq(seq);
             if let Some((src_block, dst_block)) = op {
                 if let std::collections::hash_map::Entry::Vacant(e) =
@@ -270,6 +852,164 @@ impl Scheduler {
         }
     }
 
+    /// Move up to `max_blocks` blocks of the running sequences, see `BlockEngine::compact`. The
+    /// blocks copied on write by the step stay, the order of the copies is not defined.
+    fn compact_blocks(
+        &mut self,
+        max_blocks: usize,
+        blocks_to_copy: &mut HashMap<usize, Vec<usize>>,
+    ) {
+        let pinned = blocks_to_copy
+            .iter()
+            .flat_map(|(src, dsts)| once(src).chain(dsts))
+            .copied()
+            .collect::<HashSet<_>>();
+        let seq_ids = self
+            .running
+            .iter()
+            .flat_map(|group| group.get_seqs().keys().copied())
+            .collect::<Vec<_>>();
+        let moves = self.block_engine.compact(&seq_ids, max_blocks, &pinned);
+        if moves.is_empty() {
+            return;
+        }
+        self.num_compaction_steps += 1;
+        self.num_compacted_blocks += moves.len();
+        for (src, dst) in moves {
+            blocks_to_copy.entry(src).or_default().push(dst);
+        }
+    }
+
+    /// Remove the groups whose deadline has passed from every queue and free their blocks. Their
+    /// unfinished seqs are finished with the `timeout` finish reason, keeping what they generated.
+    fn finish_timed_out_seq_groups(&mut self) -> VecDeque<Arc<SequenceGroup>> {
+        let now = SystemTime::now();
+        self.finish_seq_groups(
+            |group| group.deadline().is_some_and(|deadline| deadline <= now),
+            "timeout",
+        )
+    }
+
+    /// Remove the groups of `request_ids` from every queue and free their blocks, as for a
+    /// timeout but with the `abort` finish reason. Ids of requests not in the queues are ignored.
+    pub fn abort_requests(&mut self, request_ids: &HashSet<String>) -> Vec<Arc<SequenceGroup>> {
+        if request_ids.is_empty() {
+            return Vec::new();
+        }
+        self.finish_seq_groups(|group| request_ids.contains(&group.request_id), "abort")
+            .into()
+    }
+
+    /// Roll the choice `index` of the unfinished request `request_id` back by its last
+    /// `num_tokens` tokens, e.g. for a guided decoder backtracking or an agent editing the end
+    /// of its context. The tokens of the prompt can be rolled back too when it is the only
+    /// choice. The KV cache of the tokens left is kept, the blocks past them are freed and the
+    /// next decode step runs from the new last token. Text already streamed is not taken back.
+    pub fn rollback(
+        &mut self,
+        request_id: &str,
+        index: usize,
+        num_tokens: usize,
+    ) -> Result<(), APIError> {
+        // Waiting groups hold no blocks, they were either never allocated or preempted.
+        let (group, has_blocks) = [
+            (&self.waiting, false),
+            (&self.running, true),
+            (&self.swapped_out, true),
+        ]
+        .into_iter()
+        .find_map(|(queue, has_blocks)| {
+            queue
+                .iter()
+                .find(|group| group.request_id == request_id)
+                .map(|group| (group.clone(), has_blocks))
+        })
+        .ok_or_else(|| APIError::new(format!("Request `{request_id}` is not running.")))?;
+        if group.guidance.is_some() || group.sampling_params.use_beam_search {
+            return Err(APIError::new_str(
+                "Requests with guidance or beam search cannot be rolled back.",
+            ));
+        }
+        let seq = group
+            .get_unfinished_choices()
+            .find(|(choice, _)| *choice == index)
+            .map(|(_, seq)| seq.clone())
+            .ok_or_else(|| {
+                APIError::new(format!(
+                    "Request `{request_id}` has no unfinished choice {index}."
+                ))
+            })?;
+        if num_tokens == 0 {
+            return Ok(());
+        }
+        {
+            let seq = seq.deref();
+            let len = seq.get_len();
+            let num_outputs = len - seq.get_prompt_len();
+            if num_tokens >= len || (num_tokens > num_outputs && group.get_seqs().len() > 1) {
+                return Err(APIError::new(format!(
+                    "Cannot roll back {num_tokens} of the {len} tokens of request \
+                     `{request_id}`, {num_outputs} of which were generated."
+                )));
+            }
+            let evicted = seq.get_evicted_tokens();
+            if !evicted.is_empty() && len - num_tokens <= evicted.end {
+                return Err(APIError::new(format!(
+                    "The tokens request `{request_id}` would roll back to were evicted from \
+                     the KV cache."
+                )));
+            }
+            if has_blocks && self.block_engine.get_state_slot(seq.get_id()).is_some() {
+                return Err(APIError::new_str(
+                    "The recurrent states of hybrid models cannot be rolled back.",
+                ));
+            }
+        }
+        let was_prompt = seq.deref().is_prompt();
+        seq.deref_mut().rollback(num_tokens);
+        if has_blocks {
+            self.block_engine.truncate_sequence(&seq);
+            // The KV cache of the tokens before the new last one is written, the next decode
+            // step writes the rest.
+            if !was_prompt {
+                let mut seq = seq.deref_mut();
+                let last = seq.get_len() - 1;
+                let num_cached_tokens = seq.get_num_cached_tokens().unwrap_or(last).min(last);
+                seq.set_num_cached_tokens(Some(num_cached_tokens));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_seq_groups(
+        &mut self,
+        is_finished: impl Fn(&Arc<SequenceGroup>) -> bool,
+        finish_reason: &str,
+    ) -> VecDeque<Arc<SequenceGroup>> {
+        let mut finished = VecDeque::new();
+        for (queue, has_blocks) in [
+            (&mut self.waiting, false),
+            (&mut self.running, true),
+            (&mut self.swapped_out, true),
+        ] {
+            let (matched, remaining): (VecDeque<_>, VecDeque<_>) =
+                queue.drain(..).partition(&is_finished);
+            *queue = remaining;
+            finished.extend(matched.into_iter().map(|group| (group, has_blocks)));
+        }
+        finished
+            .into_iter()
+            .map(|(group, has_blocks)| {
+                group.set_status(SequenceStatus::Finished(finish_reason.to_string()));
+                // Waiting groups hold no blocks, they were either never allocated or preempted.
+                if has_blocks {
+                    self._free(&group);
+                }
+                group
+            })
+            .collect()
+    }
+
     fn _abort_seq_group(&mut self, seq_group: &SequenceGroup) {
         self.remove_seq_group(seq_group);
         seq_group.set_status(SequenceStatus::FinishedAborted);
@@ -305,33 +1045,82 @@ impl Scheduler {
             return;
         }
         let new_to_swap = self.block_engine.swap_out(&seq_group);
+        self.num_swapped_out_blocks += new_to_swap.len();
         blocks_to_swap_out.extend(new_to_swap);
         seq_group.set_status(SequenceStatus::Swapped);
 
         self.swapped_out.push_back(seq_group);
     }
 
+    /// Keep the blocks of a finished group continuing a conversation for its next turn, instead
+    /// of freeing them. Returns whether they were kept.
+    fn keep_session(&mut self, seq_group: &SequenceGroup) -> bool {
+        let Some(conversation_id) = &seq_group.sampling_params.conversation_id else {
+            return false;
+        };
+        let retention = seq_group.sampling_params.kv_cache_retention;
+        if !self.sessions.is_enabled()
+            || retention.is_some_and(|retention| retention.is_zero())
+            || seq_group.get_seqs().len() != 1
+            || seq_group.pixel_values.is_some()
+        {
+            return false;
+        }
+        let seq = seq_group.get_seqs().values().next().unwrap();
+        if seq.deref().get_num_evicted_tokens() > 0 {
+            return false;
+        }
+        let mut token_ids = seq.deref().get_token_ids();
+        // The last token was sampled and never run, the cache does not hold it.
+        token_ids.pop();
+        let Some(blocks) = self.block_engine.detach_sequence(seq, token_ids.len()) else {
+            return false;
+        };
+        let session = Session {
+            token_ids,
+            blocks,
+            retained_until: retention.map(|retention| Instant::now() + retention),
+        };
+        let evicted = self.sessions.insert(conversation_id.clone(), session);
+        for session in evicted {
+            self.block_engine.free_blocks(session.blocks);
+        }
+        true
+    }
+
+    /// Free the blocks of the least recently used session. Returns whether there was one.
+    fn evict_session(&mut self) -> bool {
+        match self.sessions.pop_oldest() {
+            Some(session) => {
+                self.block_engine.free_blocks(session.blocks);
+                true
+            }
+            None => false,
+        }
+    }
+
     fn _allocate(&mut self, seq_group: &SequenceGroup) {
         self.block_engine.allocate(seq_group)
     }
 
+    fn num_running_seqs(&self) -> usize {
+        self.running
+            .iter()
+            .map(|group| group.get_seqs().len())
+            .sum()
+    }
+
     fn _free(&mut self, seq_group: &SequenceGroup) {
         for seq in seq_group.get_seqs().values() {
             self.block_engine.free_sequence(seq);
+            // A preempted sequence continuing a cached prompt is prefilled from scratch.
+            seq.deref_mut().set_num_cached_tokens(None);
         }
     }
+}
 
-    fn sort_running_by_priority_fcfs(&mut self) {
-        self.running
-            .make_contiguous()
-            .sort_by_key(|seq_group| seq_group.arrival_time());
-        self.running.make_contiguous().reverse();
-    }
-
-    fn sort_swapped_out_by_priority_fcfs(&mut self) {
-        self.swapped_out
-            .make_contiguous()
-            .sort_by_key(|seq_group| seq_group.arrival_time());
-        self.swapped_out.make_contiguous().reverse();
-    }
+/// Sort `queue` in the order the groups are served by `policy`, keeping the order of the groups
+/// it does not tell apart.
+fn sort_by_policy(policy: &dyn SchedulingPolicy, queue: &mut VecDeque<Arc<SequenceGroup>>) {
+    queue.make_contiguous().sort_by(|a, b| policy.compare(a, b));
 }
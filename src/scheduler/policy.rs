@@ -0,0 +1,236 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use super::sequence::SequenceGroup;
+
+/// Policy of the scheduler, set with `LLMEngine::set_scheduling_policy`: the order the groups are
+/// admitted, swapped in and served in, which groups a prefill step batches together, and which
+/// running group is preempted when the KV cache is full. It is called by the engine loop, so it
+/// has to return quickly.
+pub trait SchedulingPolicy: Send + Sync {
+    /// Name of the policy, reported by the scheduler snapshot.
+    fn name(&self) -> &str;
+
+    /// Whether `a` is served before (`Less`) or after (`Greater`) `b`. A new group waits behind
+    /// every waiting group not served after it, and the running and swapped out groups are kept
+    /// in this order.
+    fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering;
+
+    /// Whether the waiting group `seq_group` is prefilled in the step of the groups `scheduled`
+    /// so far. A group that is not keeps its place in the queue for the next step. Every group is
+    /// by default.
+    fn admit(&self, _seq_group: &SequenceGroup, _scheduled: &VecDeque<Arc<SequenceGroup>>) -> bool {
+        true
+    }
+
+    /// Index of the group of `running`, in the order of `compare`, preempted to free blocks for
+    /// the groups served before it. The last one by default.
+    fn select_victim(&self, running: &VecDeque<Arc<SequenceGroup>>) -> usize {
+        running.len() - 1
+    }
+
+    /// Called before the waiting groups are considered for a prefill step, to reorder them by the
+    /// `running` groups, for policies whose order changes with what runs. Does nothing by
+    /// default.
+    fn reorder(
+        &self,
+        _waiting: &mut VecDeque<Arc<SequenceGroup>>,
+        _running: &VecDeque<Arc<SequenceGroup>>,
+    ) {
+    }
+}
+
+/// Groups are served in the order they arrived, the ones of low priority behind the others.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fcfs;
+
+impl SchedulingPolicy for Fcfs {
+    fn name(&self) -> &str {
+        "fcfs"
+    }
+
+    fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering {
+        let key =
+            |group: &SequenceGroup| (group.sampling_params.low_priority, group.arrival_time());
+        key(a).cmp(&key(b))
+    }
+}
+
+/// Groups are served by the `priority` of their request, lower values first, then in the order
+/// they arrived. Requests of low priority wait behind all the others.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Priority;
+
+impl SchedulingPolicy for Priority {
+    fn name(&self) -> &str {
+        "priority"
+    }
+
+    fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering {
+        let key = |group: &SequenceGroup| {
+            (
+                group.sampling_params.low_priority,
+                group.sampling_params.priority,
+                group.arrival_time(),
+            )
+        };
+        key(a).cmp(&key(b))
+    }
+}
+
+/// Prompt tokens a tenant is credited with per round of `FairShare` by default.
+pub const DEFAULT_FAIR_SHARE_QUANTUM: usize = 512;
+
+/// Tenants, the API keys of the requests, get a fair share of the prefills however many
+/// requests each of them sends. Before each prefill step, the waiting groups are ordered by
+/// deficit round-robin over their tenants: each round credits every tenant with `quantum` prompt
+/// tokens, and a tenant's requests are taken in the order they arrived while its credit covers
+/// their prompts. A tenant starts in debt by the tokens of its running requests, so the tenants
+/// with the least in flight go first. The running requests of the tenant with the most tokens
+/// in flight are preempted first. Requests of low priority wait behind all the others.
+#[derive(Clone, Copy, Debug)]
+pub struct FairShare {
+    quantum: usize,
+}
+
+impl FairShare {
+    pub fn new(quantum: usize) -> Self {
+        Self {
+            quantum: quantum.max(1),
+        }
+    }
+
+    /// `groups` in the order of deficit round-robin over their tenants.
+    fn round_robin(
+        &self,
+        groups: Vec<Arc<SequenceGroup>>,
+        in_flight: &HashMap<&str, usize>,
+    ) -> Vec<Arc<SequenceGroup>> {
+        let num_groups = groups.len();
+        // Tenants in the order of their first request, with their queue and deficit.
+        let mut tenants: Vec<(String, VecDeque<Arc<SequenceGroup>>, i64)> = Vec::new();
+        for group in groups {
+            match tenants
+                .iter_mut()
+                .find(|(tenant, ..)| tenant.as_str() == tenant_of(&group))
+            {
+                Some((_, queue, _)) => queue.push_back(group),
+                None => {
+                    let tenant = tenant_of(&group).to_string();
+                    let debt = in_flight.get(tenant.as_str()).copied().unwrap_or(0);
+                    tenants.push((tenant, VecDeque::from([group]), -(debt as i64)));
+                }
+            }
+        }
+        tenants.sort_by_key(|(.., deficit)| -*deficit);
+
+        let quantum = self.quantum as i64;
+        let mut ordered = Vec::with_capacity(num_groups);
+        while ordered.len() < num_groups {
+            // Skip the rounds in which no tenant could take a request.
+            let rounds = tenants
+                .iter()
+                .filter_map(|(_, queue, deficit)| {
+                    let cost = prompt_tokens(queue.front()?) as i64;
+                    Some(((cost - deficit).max(0) + quantum - 1) / quantum)
+                })
+                .min()
+                .unwrap_or(1)
+                .max(1);
+            for (_, queue, deficit) in &mut tenants {
+                if queue.is_empty() {
+                    continue;
+                }
+                *deficit += rounds * quantum;
+                while let Some(group) = queue.front() {
+                    let cost = prompt_tokens(group) as i64;
+                    if cost > *deficit {
+                        break;
+                    }
+                    *deficit -= cost;
+                    ordered.push(queue.pop_front().unwrap());
+                }
+            }
+        }
+        ordered
+    }
+}
+
+impl Default for FairShare {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAIR_SHARE_QUANTUM)
+    }
+}
+
+impl SchedulingPolicy for FairShare {
+    fn name(&self) -> &str {
+        "fair"
+    }
+
+    fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering {
+        Fcfs.compare(a, b)
+    }
+
+    fn select_victim(&self, running: &VecDeque<Arc<SequenceGroup>>) -> usize {
+        let in_flight = tokens_in_flight(running);
+        let max = in_flight.values().copied().max().unwrap_or(0);
+        running
+            .iter()
+            .rposition(|group| in_flight[tenant_of(group)] == max)
+            .unwrap_or(running.len() - 1)
+    }
+
+    fn reorder(
+        &self,
+        waiting: &mut VecDeque<Arc<SequenceGroup>>,
+        running: &VecDeque<Arc<SequenceGroup>>,
+    ) {
+        let in_flight = tokens_in_flight(running);
+        let (low_priority, normal): (Vec<_>, Vec<_>) = waiting
+            .drain(..)
+            .partition(|group| group.sampling_params.low_priority);
+        waiting.extend(self.round_robin(normal, &in_flight));
+        waiting.extend(self.round_robin(low_priority, &in_flight));
+    }
+}
+
+/// Tenant of the request of `seq_group`, requests without an API key share one.
+fn tenant_of(seq_group: &SequenceGroup) -> &str {
+    seq_group.sampling_params.tenant.as_deref().unwrap_or("")
+}
+
+/// Tokens prefilled for the prompt of `seq_group`, once per sequence.
+fn prompt_tokens(seq_group: &SequenceGroup) -> usize {
+    seq_group
+        .get_seqs()
+        .values()
+        .map(|seq| seq.deref().get_prompt_len())
+        .sum()
+}
+
+/// Tokens of the sequences of `groups` by tenant.
+fn tokens_in_flight(groups: &VecDeque<Arc<SequenceGroup>>) -> HashMap<&str, usize> {
+    let mut in_flight = HashMap::new();
+    for group in groups {
+        let tokens = group
+            .get_seqs()
+            .values()
+            .map(|seq| seq.deref().get_len())
+            .sum::<usize>();
+        *in_flight.entry(tenant_of(group)).or_default() += tokens;
+    }
+    in_flight
+}
+
+/// The built-in policy called `name`.
+pub fn get_policy(name: &str) -> Option<Arc<dyn SchedulingPolicy>> {
+    match name {
+        "fcfs" => Some(Arc::new(Fcfs)),
+        "priority" => Some(Arc::new(Priority)),
+        "fair" => Some(Arc::new(FairShare::default())),
+        _ => None,
+    }
+}
@@ -0,0 +1,147 @@
+use super::draft_tree::DraftTree;
+use crate::openai::responses::APIError;
+
+/// Prompt lookup decoding, speculative decoding without a draft model. The tokens that followed
+/// an earlier occurrence of the last n-gram of a sequence, in its prompt or its output, are
+/// proposed as its continuation and verified in a single forward pass. Summaries and answers
+/// quoting their context are decoded several tokens per step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PromptLookupConfig {
+    /// Maximum number of tokens proposed for a sequence in one step.
+    pub num_speculative_tokens: usize,
+    /// Longest n-gram looked up, shorter ones down to `min_ngram` are tried if it is not found.
+    pub max_ngram: usize,
+    pub min_ngram: usize,
+    /// Continuations of this many earlier occurrences are proposed at once, as a tree.
+    pub num_branches: usize,
+    /// Propose fewer tokens for the requests whose recent proposals were mostly rejected, see
+    /// `draft_length`.
+    pub adaptive: bool,
+}
+
+/// Proposed tokens are only worth verifying while their chance to be accepted is at least this.
+pub const MIN_TOKEN_ACCEPTANCE: f64 = 0.3;
+/// Weight the acceptance of earlier proposals keeps at each new one.
+pub const ACCEPTANCE_DECAY: f64 = 0.8;
+
+/// Acceptance rate of the recent proposals of a request: the share of the proposed tokens it
+/// verified that were accepted. Tokens past the first rejected one are not verified, the ones of
+/// older proposals weigh less and less.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DraftAcceptance {
+    accepted: f64,
+    verified: f64,
+}
+
+impl DraftAcceptance {
+    /// Record a proposal of which `num_accepted` tokens were accepted, `exhausted` if all of
+    /// them were on the branch it followed.
+    pub fn record(&mut self, num_accepted: usize, exhausted: bool) {
+        let num_verified = num_accepted + usize::from(!exhausted);
+        self.accepted = self.accepted * ACCEPTANCE_DECAY + num_accepted as f64;
+        self.verified = self.verified * ACCEPTANCE_DECAY + num_verified as f64;
+    }
+
+    /// Chance that a proposed token is accepted after its parent was, `None` before anything
+    /// was verified.
+    pub fn rate(&self) -> Option<f64> {
+        (self.verified > 0.).then(|| self.accepted / self.verified)
+    }
+}
+
+impl PromptLookupConfig {
+    pub fn verify_args(&self) -> Result<(), APIError> {
+        if self.num_speculative_tokens == 0 {
+            return Err(APIError::new_str(
+                "At least one speculative token has to be proposed.",
+            ));
+        }
+        if self.min_ngram == 0 || self.min_ngram > self.max_ngram {
+            return Err(APIError::new(format!(
+                "The n-grams looked up need 0 < min_ngram <= max_ngram, got {} and {}.",
+                self.min_ngram, self.max_ngram
+            )));
+        }
+        if self.num_branches == 0 {
+            return Err(APIError::new_str("At least one branch has to be proposed."));
+        }
+        Ok(())
+    }
+
+    /// Tokens to propose for a request whose proposals are accepted at `acceptance`:
+    /// `num_speculative_tokens`, unless `adaptive`, in which case the `k`-th token is proposed
+    /// only if the chance that the `k` first tokens are accepted is at least
+    /// `MIN_TOKEN_ACCEPTANCE`. At least one token is proposed so that the rate keeps being
+    /// measured.
+    pub fn draft_length(&self, acceptance: &DraftAcceptance) -> usize {
+        let Some(rate) = acceptance.rate().filter(|_| self.adaptive) else {
+            return self.num_speculative_tokens;
+        };
+        if rate >= 1. {
+            return self.num_speculative_tokens;
+        }
+        let length = if rate > 0. {
+            (MIN_TOKEN_ACCEPTANCE.ln() / rate.ln()).floor() as usize
+        } else {
+            0
+        };
+        length.clamp(1, self.num_speculative_tokens)
+    }
+
+    /// Continuations of the earlier occurrences of the longest suffix of `tokens` of
+    /// `max_ngram` to `min_ngram` tokens that occurs earlier, the most recent first.
+    fn continuations<'a>(&self, tokens: &'a [usize]) -> Vec<&'a [usize]> {
+        for n in (self.min_ngram..=self.max_ngram.min(tokens.len().saturating_sub(1))).rev() {
+            let ngram = &tokens[tokens.len() - n..];
+            let continuations = (0..tokens.len() - n)
+                .rev()
+                .filter(|&start| &tokens[start..start + n] == ngram)
+                .map(|start| &tokens[start + n..])
+                .collect::<Vec<_>>();
+            if !continuations.is_empty() {
+                return continuations;
+            }
+        }
+        vec![]
+    }
+
+    /// Up to `max_tokens` (and `num_speculative_tokens`) tokens continuing `tokens`: the ones
+    /// following the most recent earlier occurrence of its longest suffix of `max_ngram` to
+    /// `min_ngram` tokens. Empty if no suffix occurs earlier.
+    pub fn propose(&self, tokens: &[usize], max_tokens: usize) -> Vec<usize> {
+        let max_tokens = max_tokens.min(self.num_speculative_tokens);
+        self.continuations(tokens)
+            .first()
+            .map_or(vec![], |continuation| {
+                continuation[..continuation.len().min(max_tokens)].to_vec()
+            })
+    }
+
+    /// Up to `max_tokens` tokens continuing `tokens` as a tree: the continuations of up to
+    /// `num_branches` earlier occurrences, each of up to `num_speculative_tokens` tokens, sharing
+    /// their common prefixes. The most recent occurrence is the first branch, a chain of the
+    /// tokens `propose` gives.
+    pub fn propose_tree(&self, tokens: &[usize], max_tokens: usize) -> DraftTree {
+        let mut tree = DraftTree::default();
+        let mut num_branches = 0;
+        for continuation in self.continuations(tokens) {
+            if num_branches == self.num_branches {
+                break;
+            }
+            let num_nodes = tree.len();
+            let mut node = 0;
+            for &token in continuation.iter().take(self.num_speculative_tokens) {
+                node = match tree.child(node, token as u32) {
+                    Some(child) => child,
+                    None if tree.len() < max_tokens => tree.push(node, token as u32),
+                    None => break,
+                };
+            }
+            // Continuations already in the tree are no new branch.
+            if tree.len() > num_nodes {
+                num_branches += 1;
+            }
+        }
+        tree
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    openai::{
+        responses::APIError,
+        sampling_params::{SamplingParams, SAMPLING_EPS},
+    },
+    try_api,
+};
+
+/// A request the engine accepted, as written to the request log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AcceptedRequest {
+    pub request_id: String,
+    /// FNV-1a hash of the prompt tokens, to tell requests apart without their prompt.
+    pub prompt_hash: String,
+    pub prompt_tokens: Vec<u32>,
+    /// Seconds since the Unix epoch.
+    pub accepted_at: u64,
+    pub sampling_params: SamplingParams,
+    pub use_logprobs: bool,
+    /// Images are not logged, a request with images cannot be replayed.
+    pub has_images: bool,
+    /// `system_fingerprint` of the engine which accepted the request, `None` in logs written
+    /// before it was logged.
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+}
+
+impl AcceptedRequest {
+    pub fn new(
+        request_id: String,
+        prompt_tokens: Vec<u32>,
+        sampling_params: SamplingParams,
+        use_logprobs: bool,
+        has_images: bool,
+    ) -> Self {
+        let accepted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Self {
+            request_id,
+            prompt_hash: prompt_hash(&prompt_tokens),
+            prompt_tokens,
+            accepted_at,
+            sampling_params,
+            use_logprobs,
+            has_images,
+            system_fingerprint: None,
+        }
+    }
+
+    /// Whether running the request again gives the output it would have had: it samples
+    /// greedily (without a sampling schedule) or with beam search and holds no image.
+    pub fn is_idempotent(&self) -> bool {
+        let params = &self.sampling_params;
+        let greedy = params.temperature < SAMPLING_EPS && params.sampling_schedule.is_none();
+        !self.has_images && (params.use_beam_search || greedy)
+    }
+}
+
+/// A request found unfinished in the request log when the engine started.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecoveredRequest {
+    pub request_id: String,
+    pub prompt_hash: String,
+    pub prompt_tokens: usize,
+    pub accepted_at: u64,
+    /// Whether the request was queued again, it is lost otherwise.
+    pub replayed: bool,
+}
+
+/// FNV-1a hash of `prompt_tokens`, in hex.
+pub fn prompt_hash(prompt_tokens: &[u32]) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let hash = prompt_tokens
+        .iter()
+        .flat_map(|token| token.to_le_bytes())
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        });
+    format!("{hash:016x}")
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Entry {
+    Accepted(Box<AcceptedRequest>),
+    Finished { request_id: String },
+}
+
+/// Write-ahead log of the requests accepted by the engine, one JSON entry per line. A request is
+/// logged as accepted before it is queued and as finished once it is answered or aborted, so the
+/// requests accepted but never finished are the ones lost by a crash. Each entry is synced to
+/// disk before the engine goes on, and the log is emptied whenever no request is pending.
+pub struct RequestLog {
+    file: File,
+    /// Requests logged as accepted and not finished yet.
+    pending: HashSet<String>,
+}
+
+impl RequestLog {
+    /// Open the log at `path` and return the requests it holds as accepted but not finished, in
+    /// the order they were accepted. The log starts over empty.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<AcceptedRequest>), APIError> {
+        let path = path.as_ref();
+        let mut unfinished: Vec<AcceptedRequest> = Vec::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (number, line) in BufReader::new(file).lines().enumerate() {
+                    let line = try_api!(line);
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    // The last entry is torn if the crash happened while it was written.
+                    match serde_json::from_str(&line) {
+                        Ok(Entry::Accepted(request)) => unfinished.push(*request),
+                        Ok(Entry::Finished { request_id }) => {
+                            if let Some(index) = unfinished
+                                .iter()
+                                .rposition(|request| request.request_id == request_id)
+                            {
+                                unfinished.remove(index);
+                            }
+                        }
+                        Err(err) => warn!(
+                            path = %path.display(),
+                            line = number + 1,
+                            "skipping an unreadable request log entry: {err}"
+                        ),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(APIError::from(err)),
+        }
+        let file = try_api!(OpenOptions::new().create(true).append(true).open(path));
+        try_api!(file.set_len(0));
+        try_api!(file.sync_all());
+        let log = Self {
+            file,
+            pending: HashSet::new(),
+        };
+        Ok((log, unfinished))
+    }
+
+    pub fn accepted(&mut self, request: AcceptedRequest) -> Result<(), APIError> {
+        let request_id = request.request_id.clone();
+        self.write(&Entry::Accepted(Box::new(request)))?;
+        self.pending.insert(request_id);
+        Ok(())
+    }
+
+    /// Requests that were never logged as accepted are not logged either.
+    pub fn finished(&mut self, request_id: &str) -> Result<(), APIError> {
+        if !self.pending.remove(request_id) {
+            return Ok(());
+        }
+        if self.pending.is_empty() {
+            try_api!(self.file.set_len(0));
+            try_api!(self.file.sync_data());
+            return Ok(());
+        }
+        self.write(&Entry::Finished {
+            request_id: request_id.to_string(),
+        })
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<(), APIError> {
+        let mut line = try_api!(serde_json::to_string(entry));
+        line.push('\n');
+        try_api!(self.file.write_all(line.as_bytes()));
+        try_api!(self.file.sync_data());
+        Ok(())
+    }
+}
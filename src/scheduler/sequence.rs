@@ -1,14 +1,22 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    collections::BTreeMap,
+    iter::zip,
+    ops::Range,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime},
 };
 
 use super::block_engine::LogicalTokenBlock;
+use super::prompt_lookup::DraftAcceptance;
+use crate::openai::guided_choice::ChoiceTrie;
+use crate::openai::hooks::{LogitsProcessor, ModerationMatch};
+use crate::openai::logit_dump::LogitDump;
 use crate::openai::sampling_params::{Logprobs, SamplingParams};
+use crate::openai::stop_checker::StopChecker;
 use crate::openai::streaming::ChatResponse;
+use candle_core::Tensor;
 use flume::Sender;
-use std::time::SystemTime;
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum SequenceStatus {
     FinishedIgnored,
     Waiting,
@@ -44,6 +52,15 @@ impl SequenceData {
         self.status = status;
     }
 
+    /// Keep the first `len` tokens, the generated ones go first.
+    fn truncate(&mut self, len: usize) {
+        let num_outputs = len.saturating_sub(self.prompt_token_ids.len());
+        for logprobs in self.output_token_ids.drain(num_outputs..) {
+            self.cumulative_logprob -= logprobs.logprob;
+        }
+        self.prompt_token_ids.truncate(len);
+    }
+
     fn get_cumulative_logprob(&self) -> f32 {
         self.cumulative_logprob
     }
@@ -56,6 +73,25 @@ pub struct _Sequence {
     seq_id: usize,
     logical_token_blocks: Vec<LogicalTokenBlock>,
     block_size: usize,
+    /// Position of the first token of the sequence. Tokens the sequence continues without
+    /// holding them, e.g. a reused prefix whose KV cache is already filled, shift its positions.
+    position_offset: usize,
+    /// Tokens whose KV cache was evicted to make room, see `AttentionSinks`.
+    evicted_tokens: Range<usize>,
+    /// Tokens at the start of the prompt whose KV cache was already written when the sequence
+    /// was scheduled, e.g. by the previous turn of its conversation. Its first decode step writes
+    /// the rest of the prompt in place of a prefill.
+    num_cached_tokens: Option<usize>,
+    /// Text generated so far as the moderators saw it, and what they matched in it.
+    moderated_text: String,
+    moderation: Vec<ModerationMatch>,
+    /// Text generated so far as the stop checker saw it, and the length of the start of it that
+    /// was streamed.
+    checked_text: String,
+    streamed_len: usize,
+    /// Lengths of the moderated and checked text after each generated token, to roll them back
+    /// with it.
+    text_lens: Vec<(usize, usize)>,
 }
 
 impl _Sequence {
@@ -65,6 +101,14 @@ impl _Sequence {
             seq_id,
             logical_token_blocks: Vec::new(),
             block_size,
+            position_offset: 0,
+            evicted_tokens: 0..0,
+            num_cached_tokens: None,
+            moderated_text: String::new(),
+            moderation: Vec::new(),
+            checked_text: String::new(),
+            streamed_len: 0,
+            text_lens: Vec::new(),
         };
         this.append_tokens_to_blocks(prompt_token_ids);
         this
@@ -73,6 +117,26 @@ impl _Sequence {
     pub fn add_token(&mut self, logprobs: Logprobs) {
         self.append_token_to_blocks(logprobs.token);
         self.deref_mut().append_token_id(logprobs);
+        self.text_lens
+            .push((self.moderated_text.len(), self.checked_text.len()));
+    }
+
+    /// Drop the last `num_tokens` tokens, generated first then of the prompt, along with their
+    /// logical blocks and text. At least one token must be left, and it must not be evicted.
+    pub fn rollback(&mut self, num_tokens: usize) {
+        let len = self.get_len() - num_tokens;
+        self.deref_mut().truncate(len);
+        let num_outputs = self.deref().output_token_ids.len();
+        self.text_lens.truncate(num_outputs);
+        let (moderated_len, checked_len) = self.text_lens.last().copied().unwrap_or_default();
+        self.moderated_text.truncate(moderated_len);
+        self.checked_text.truncate(checked_len);
+        self.streamed_len = self.streamed_len.min(checked_len);
+        // The blocks are full but the last one, which may be empty, as after appending tokens.
+        let num_cached = self.get_cache_index(len - 1) + 1;
+        let num_full_blocks = num_cached / self.block_size;
+        self.logical_token_blocks.truncate(num_full_blocks + 1);
+        self.logical_token_blocks[num_full_blocks].truncate(num_cached % self.block_size);
     }
 
     pub fn blocks_to_add_new_tok(&self) -> usize {
@@ -93,8 +157,61 @@ impl _Sequence {
         self.seq_id
     }
 
+    pub fn get_position_offset(&self) -> usize {
+        self.position_offset
+    }
+
+    pub fn set_position_offset(&mut self, position_offset: usize) {
+        self.position_offset = position_offset;
+    }
+
+    /// Position of the token at `index` in the sequence, which the rotary embeddings encode.
+    /// As in StreamingLLM, evicted tokens do not count: the position is the one in the KV cache.
+    pub fn get_position(&self, index: usize) -> usize {
+        self.position_offset + self.get_cache_index(index)
+    }
+
+    /// Index in the KV cache of the token at `index` in the sequence, which must not be evicted.
+    pub fn get_cache_index(&self, index: usize) -> usize {
+        if index >= self.evicted_tokens.end {
+            index - self.evicted_tokens.len()
+        } else {
+            index
+        }
+    }
+
+    pub fn get_num_evicted_tokens(&self) -> usize {
+        self.evicted_tokens.len()
+    }
+
+    pub fn get_evicted_tokens(&self) -> Range<usize> {
+        self.evicted_tokens.clone()
+    }
+
+    /// Drop the logical block at `block`, whose physical block was evicted. Blocks are evicted
+    /// from the same index on, so that the evicted tokens are contiguous.
+    pub fn evict_block(&mut self, block: usize) {
+        self.logical_token_blocks.remove(block);
+        let start = block * self.block_size;
+        if self.evicted_tokens.is_empty() {
+            self.evicted_tokens = start..start;
+        }
+        assert_eq!(self.evicted_tokens.start, start);
+        self.evicted_tokens.end += self.block_size;
+    }
+
+    pub fn get_num_cached_tokens(&self) -> Option<usize> {
+        self.num_cached_tokens
+    }
+
+    pub fn set_num_cached_tokens(&mut self, num_cached_tokens: Option<usize>) {
+        self.num_cached_tokens = num_cached_tokens;
+    }
+
+    /// Whether the sequence has yet to be prefilled, a sequence continuing a cached prompt is
+    /// not.
     pub fn is_prompt(&self) -> bool {
-        self.deref().output_token_ids.is_empty()
+        self.deref().output_token_ids.is_empty() && self.num_cached_tokens.is_none()
     }
 
     pub fn get_prompt_len(&self) -> usize {
@@ -118,6 +235,14 @@ impl _Sequence {
         res
     }
 
+    /// The last generated token, `None` before the first one.
+    pub fn get_last_output_token(&self) -> Option<usize> {
+        self.deref()
+            .output_token_ids
+            .last()
+            .map(|logprobs| logprobs.token)
+    }
+
     pub fn get_last_token_id(&self) -> usize {
         if self.deref().output_token_ids.is_empty() {
             *self.deref().prompt_token_ids.last().unwrap()
@@ -139,6 +264,10 @@ impl _Sequence {
         self.deref().get_cumulative_logprob()
     }
 
+    pub fn get_status(&self) -> SequenceStatus {
+        self.deref().status.clone()
+    }
+
     pub fn set_finish_reason(&mut self, finish_reason: String) {
         self.deref_mut()
             .set_status(SequenceStatus::Finished(finish_reason.clone()));
@@ -155,6 +284,64 @@ impl _Sequence {
         }
     }
 
+    /// Adds `new_text` to the moderated text and has `moderate` match the whole text and the new
+    /// text. The matches are recorded and returned, the text is left without `new_text` if one of
+    /// them halts the sequence.
+    pub fn moderate(
+        &mut self,
+        new_text: &str,
+        moderate: impl FnOnce(&str, &str) -> Vec<ModerationMatch>,
+    ) -> Vec<ModerationMatch> {
+        let start = self.moderated_text.len();
+        self.moderated_text.push_str(new_text);
+        let matches = moderate(&self.moderated_text, &self.moderated_text[start..]);
+        if matches.iter().any(|m| m.halt) {
+            self.moderated_text.truncate(start);
+        }
+        self.moderation.extend(matches.iter().cloned());
+        matches
+    }
+
+    /// Adds `new_text` to the checked text. Returns the text to stream now and whether a stop
+    /// string completed, in which case the text ends before it and what was held back is left
+    /// for the last chunk, where it may turn out to be tool calls.
+    pub fn check_stop(&mut self, new_text: &str, checker: &StopChecker) -> (String, bool) {
+        self.checked_text.push_str(new_text);
+        // What was streamed cannot be the start of a stop string.
+        if let Some(stop) = checker.find_stop(&self.checked_text) {
+            self.checked_text.truncate(stop.max(self.streamed_len));
+            return (String::new(), true);
+        }
+        let end = checker
+            .streamable(&self.checked_text)
+            .max(self.streamed_len);
+        let text = self.checked_text[self.streamed_len..end].to_string();
+        self.streamed_len = end;
+        (text, false)
+    }
+
+    /// The checked text held back from streaming, which is streamed now.
+    pub fn take_unstreamed(&mut self) -> String {
+        let text = self.checked_text[self.streamed_len..].to_string();
+        self.streamed_len = self.checked_text.len();
+        text
+    }
+
+    /// Text generated so far as the stop checker saw it.
+    pub fn get_checked_text(&self) -> &str {
+        &self.checked_text
+    }
+
+    /// Length of the start of the checked text that was streamed.
+    pub fn get_streamed_len(&self) -> usize {
+        self.streamed_len
+    }
+
+    /// Matches of the moderators in the text of the sequence.
+    pub fn get_moderation(&self) -> Vec<ModerationMatch> {
+        self.moderation.clone()
+    }
+
     #[must_use]
     /// Clones the internal logprobs.
     pub fn get_output_tokens(&self) -> Vec<Logprobs> {
@@ -228,10 +415,90 @@ impl Sequence {
 
 type SeqID = usize;
 
+/// Token healing of a prompt whose last token was removed.
+#[derive(Debug, Clone)]
+pub struct TokenHealing {
+    /// The token removed from the end of the prompt.
+    pub token: u32,
+    /// Tokens starting with the removed one, the first generated token is one of them.
+    pub allowed_token_ids: Vec<u32>,
+}
+
+/// Classifier-free guidance of the choices of a group by the sequences of its negative prompt.
+#[derive(Debug, Clone)]
+pub struct Guidance {
+    pub scale: f32,
+    /// The sequence of the negative prompt paired with each choice, by the seq id of the choice.
+    pub negative_seq_ids: BTreeMap<SeqID, SeqID>,
+}
+
+/// When a request went through each stage of its life in the engine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequenceGroupMetrics {
+    /// When the request was added to the engine.
+    pub arrival_time: SystemTime,
+    /// When it was scheduled for the first time, a preempted request keeps it.
+    pub first_scheduled_time: Option<SystemTime>,
+    pub first_token_time: Option<SystemTime>,
+    pub finished_time: Option<SystemTime>,
+    /// Tokens generated by the returned choices.
+    pub num_output_tokens: usize,
+    /// Tokens proposed for the choices by speculative decoding, and the ones accepted.
+    pub num_proposed_tokens: usize,
+    pub num_accepted_tokens: usize,
+}
+
+impl SequenceGroupMetrics {
+    fn new(arrival_time: SystemTime) -> Self {
+        Self {
+            arrival_time,
+            first_scheduled_time: None,
+            first_token_time: None,
+            finished_time: None,
+            num_output_tokens: 0,
+            num_proposed_tokens: 0,
+            num_accepted_tokens: 0,
+        }
+    }
+
+    /// Share of the proposed tokens that were accepted, `None` if none was proposed.
+    pub fn acceptance_rate(&self) -> Option<f64> {
+        (self.num_proposed_tokens > 0)
+            .then(|| self.num_accepted_tokens as f64 / self.num_proposed_tokens as f64)
+    }
+
+    /// Time spent waiting to be scheduled.
+    pub fn queue_time(&self) -> Option<Duration> {
+        Some(since(self.arrival_time, self.first_scheduled_time?))
+    }
+
+    /// Time from the arrival of the request to its first token.
+    pub fn time_to_first_token(&self) -> Option<Duration> {
+        Some(since(self.arrival_time, self.first_token_time?))
+    }
+
+    /// Mean time between the tokens following the first one, `None` with fewer than two.
+    pub fn time_per_output_token(&self) -> Option<Duration> {
+        let decoding = since(self.first_token_time?, self.finished_time?);
+        let num_tokens = u32::try_from(self.num_output_tokens.checked_sub(1)?).ok()?;
+        (num_tokens > 0).then(|| decoding / num_tokens)
+    }
+}
+
+/// Time from `start` to `end`, zero if the clock went back in between.
+fn since(start: SystemTime, end: SystemTime) -> Duration {
+    end.duration_since(start).unwrap_or_default()
+}
+
 /// A SequenceGroup holds the `n` (see SamplingParams) sequences generated from a single prompt.
 /// A SequenceGroup contains only sequences with the same prompt. They will always be scheduled together.
+/// Sequences are kept in the order they were added, which is the `index` of their choice.
+/// The prompt of a request with images holds one placeholder token per image feature, so that
+/// image features take up KV cache blocks and count towards the context length like text tokens.
+/// With classifier-free guidance, the group also holds the sequences of the negative prompt, one
+/// per choice: they are scheduled, allocated and run with the choices, but sampled with them.
 pub struct SequenceGroup {
-    seqs: HashMap<SeqID, Arc<Sequence>>,
+    seqs: BTreeMap<SeqID, Arc<Sequence>>,
     pub arrival_time: u64,
     pub group_id: usize,
     pub request_id: String,
@@ -239,6 +506,21 @@ pub struct SequenceGroup {
     pub sampling_params: SamplingParams,
     pub use_logprobs: bool,
     pub sender: Option<Sender<ChatResponse>>,
+    /// Preprocessed images of the prompt, `(num_images, 3, height, width)`. They are kept until
+    /// the group finishes since a preempted group is prefilled again.
+    pub pixel_values: Option<Tensor>,
+    pub token_healing: Option<TokenHealing>,
+    /// The tokenized `guided_choice` of the sampling params.
+    pub guided_choice: Option<ChoiceTrie>,
+    /// The stop strings and tools of the sampling params.
+    pub stop_checker: StopChecker,
+    /// Processors of the logits of the engine followed by the ones of the sampling params.
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    pub guidance: Option<Guidance>,
+    /// The file of the `logit_dump` of the sampling params.
+    pub logit_dump: Option<LogitDump>,
+    metrics: Mutex<SequenceGroupMetrics>,
+    draft_acceptance: Mutex<DraftAcceptance>,
 }
 
 impl SequenceGroup {
@@ -252,10 +534,14 @@ impl SequenceGroup {
         use_logprobs: bool,
         sender: Option<Sender<ChatResponse>>,
     ) -> Self {
-        let mut seq_map = HashMap::new();
+        let mut seq_map = BTreeMap::new();
         for seq in seqs {
             seq_map.insert(seq.deref_mut().get_id(), seq.clone());
         }
+        let stop_checker = StopChecker::new(
+            sampling_params.stop.as_ref(),
+            !sampling_params.tools.is_empty(),
+        );
         Self {
             seqs: seq_map,
             arrival_time,
@@ -265,9 +551,127 @@ impl SequenceGroup {
             sampling_params,
             use_logprobs,
             sender,
+            pixel_values: None,
+            token_healing: None,
+            guided_choice: None,
+            stop_checker,
+            logits_processors: Vec::new(),
+            guidance: None,
+            logit_dump: None,
+            metrics: Mutex::new(SequenceGroupMetrics::new(SystemTime::now())),
+            draft_acceptance: Mutex::new(DraftAcceptance::default()),
         }
     }
 
+    pub fn metrics(&self) -> SequenceGroupMetrics {
+        *self.metrics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update_metrics(&self, f: impl FnOnce(&mut SequenceGroupMetrics)) {
+        f(&mut self.metrics.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Record that the group was scheduled at `time`, unless it already was.
+    pub fn record_scheduled(&self, time: SystemTime) {
+        self.update_metrics(|metrics| {
+            metrics.first_scheduled_time.get_or_insert(time);
+        })
+    }
+
+    /// Record that the group generated its first token at `time`, unless it already did.
+    pub fn record_first_token(&self, time: SystemTime) {
+        self.update_metrics(|metrics| {
+            metrics.first_token_time.get_or_insert(time);
+        })
+    }
+
+    /// Record that the group finished at `time` with `num_output_tokens` generated tokens.
+    pub fn record_finished(&self, time: SystemTime, num_output_tokens: usize) {
+        self.update_metrics(|metrics| {
+            metrics.finished_time = Some(time);
+            metrics.num_output_tokens = num_output_tokens;
+        })
+    }
+
+    /// Record that `num_accepted` of the `num_proposed` tokens proposed for a choice were
+    /// accepted, `exhausted` if they were all of the branch they followed.
+    pub fn record_speculation(&self, num_proposed: usize, num_accepted: usize, exhausted: bool) {
+        if num_proposed == 0 {
+            return;
+        }
+        self.update_metrics(|metrics| {
+            metrics.num_proposed_tokens += num_proposed;
+            metrics.num_accepted_tokens += num_accepted;
+        });
+        self.draft_acceptance
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(num_accepted, exhausted);
+    }
+
+    /// Acceptance of the recent proposals of the choices, the number of tokens proposed adapts
+    /// to.
+    pub fn draft_acceptance(&self) -> DraftAcceptance {
+        *self
+            .draft_acceptance
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Guide the choices by `negative_seqs`, the sequences of the negative prompt paired with
+    /// them in order, with the guidance scale `scale`.
+    pub fn add_negative_seqs(&mut self, negative_seqs: &[Arc<Sequence>], scale: f32) {
+        let choice_ids = self.seqs.keys().copied().collect::<Vec<_>>();
+        let mut negative_seq_ids = BTreeMap::new();
+        for (choice_id, seq) in zip(choice_ids, negative_seqs) {
+            let seq_id = seq.deref().get_id();
+            negative_seq_ids.insert(choice_id, seq_id);
+            self.seqs.insert(seq_id, seq.clone());
+        }
+        self.guidance = Some(Guidance {
+            scale,
+            negative_seq_ids,
+        });
+    }
+
+    fn is_negative_seq(&self, seq_id: SeqID) -> bool {
+        self.guidance
+            .as_ref()
+            .is_some_and(|guidance| guidance.negative_seq_ids.values().any(|&id| id == seq_id))
+    }
+
+    /// The sequence of the negative prompt paired with the choice `seq_id`, if it is guided.
+    pub fn get_negative_seq(&self, seq_id: SeqID) -> Option<&Arc<Sequence>> {
+        let guidance = self.guidance.as_ref()?;
+        self.seqs.get(guidance.negative_seq_ids.get(&seq_id)?)
+    }
+
+    /// Have the sequences of the negative prompt follow their choice: they continue with the
+    /// tokens it generated, and finish with it.
+    pub fn sync_negative_seqs(&self) {
+        let Some(guidance) = &self.guidance else {
+            return;
+        };
+        for (choice_id, seq_id) in &guidance.negative_seq_ids {
+            let choice = self.seqs[choice_id].deref();
+            let mut seq = self.seqs[seq_id].deref_mut();
+            let num_generated = seq.get_len() - seq.get_prompt_len();
+            for logprobs in choice.get_output_tokens().into_iter().skip(num_generated) {
+                seq.add_token(logprobs);
+            }
+            if choice.is_finished() && !seq.is_finished() {
+                seq.set_finish_reason(choice.get_finish_reason());
+            }
+        }
+    }
+
+    /// When the request runs out of its `timeout`, if it has one.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.sampling_params
+            .timeout
+            .and_then(|timeout| self.created_time.checked_add(timeout))
+    }
+
     pub fn set_status(&self, status: SequenceStatus) {
         // for seq in self.seqs.values() {
         //     seq.deref_mut().deref().set_status(status.clone());
@@ -275,7 +679,10 @@ impl SequenceGroup {
         for seq in self.seqs.values() {
             // Lock each sequence individually and set the status
             if let Ok(seq_guard) = seq.0.write() {
-                seq_guard.deref_mut().set_status(status.clone());
+                // Choices that already finished keep their finish reason.
+                if !seq_guard.is_finished() {
+                    seq_guard.deref_mut().set_status(status.clone());
+                }
             }
         }
     }
@@ -288,8 +695,12 @@ impl SequenceGroup {
             .sum()
     }
 
+    /// Length of the prompt the sequences of the group share.
     pub fn get_prompt_len(&self) -> usize {
-        self.seqs.len()
+        self.seqs
+            .values()
+            .next()
+            .map_or(0, |seq| seq.deref().get_prompt_len())
     }
 
     pub fn get_total_logical_token_blocks(&self) -> usize {
@@ -299,10 +710,32 @@ impl SequenceGroup {
             .sum()
     }
 
-    pub fn get_seqs(&self) -> &HashMap<SeqID, Arc<Sequence>> {
+    /// All the sequences of the group, the ones of the negative prompt included.
+    pub fn get_seqs(&self) -> &BTreeMap<SeqID, Arc<Sequence>> {
         &self.seqs
     }
 
+    /// The sequences still generating, the ones of the negative prompt after the choices, in
+    /// the order of their rows in the batch.
+    pub fn get_unfinished_seqs(&self) -> impl Iterator<Item = &Arc<Sequence>> {
+        self.seqs.values().filter(|seq| !seq.deref().is_finished())
+    }
+
+    /// The sequences of the choices, in the order of their index.
+    pub fn get_choices(&self) -> impl Iterator<Item = &Arc<Sequence>> {
+        self.seqs
+            .iter()
+            .filter(|(seq_id, _)| !self.is_negative_seq(**seq_id))
+            .map(|(_, seq)| seq)
+    }
+
+    /// The choices still generating, with their index.
+    pub fn get_unfinished_choices(&self) -> impl Iterator<Item = (usize, &Arc<Sequence>)> {
+        self.get_choices()
+            .enumerate()
+            .filter(|(_, seq)| !seq.deref().is_finished())
+    }
+
     pub fn arrival_time(&self) -> u64 {
         self.arrival_time
     }
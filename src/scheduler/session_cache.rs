@@ -0,0 +1,98 @@
+use std::{collections::VecDeque, time::Instant};
+
+use super::block_engine::BlockTable;
+
+/// The KV cache of the last turn of a conversation, kept in its GPU blocks.
+pub struct Session {
+    /// Tokens whose KV cache the blocks hold, the prompt and output of the turn but its last
+    /// token, which was sampled and never run through the model.
+    pub token_ids: Vec<usize>,
+    pub blocks: BlockTable,
+    /// End of the retention the turn asked for with `kv_cache_retention`. Until then the session
+    /// is only evicted once no other is left, after it the session is dropped.
+    pub retained_until: Option<Instant>,
+}
+
+impl Session {
+    fn is_retained(&self, now: Instant) -> bool {
+        self.retained_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Sessions of up to `max_sessions` conversations, by conversation id. The least recently used
+/// ones are evicted first, when there are too many or their blocks are needed, the ones still
+/// retained last.
+pub struct SessionCache {
+    max_sessions: usize,
+    /// Least recently used first.
+    sessions: VecDeque<(String, Session)>,
+}
+
+impl SessionCache {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            max_sessions,
+            sessions: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_sessions > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Keep `session` for `conversation_id`. Returns the sessions evicted, whose blocks have to
+    /// be freed: the previous one of the conversation and the least recently used ones past
+    /// `max_sessions`.
+    pub fn insert(&mut self, conversation_id: String, session: Session) -> Vec<Session> {
+        let mut evicted = self.take(&conversation_id).into_iter().collect::<Vec<_>>();
+        self.sessions.push_back((conversation_id, session));
+        while self.sessions.len() > self.max_sessions {
+            evicted.extend(self.pop_oldest());
+        }
+        evicted
+    }
+
+    /// Remove the session of `conversation_id`, the next turn continues it.
+    pub fn take(&mut self, conversation_id: &str) -> Option<Session> {
+        let index = self
+            .sessions
+            .iter()
+            .position(|(id, _)| id == conversation_id)?;
+        self.sessions.remove(index).map(|(_, session)| session)
+    }
+
+    /// Remove the least recently used session that is not retained, or the least recently used
+    /// one if they all are.
+    pub fn pop_oldest(&mut self) -> Option<Session> {
+        let now = Instant::now();
+        let index = self
+            .sessions
+            .iter()
+            .position(|(_, session)| !session.is_retained(now))
+            .unwrap_or(0);
+        self.sessions.remove(index).map(|(_, session)| session)
+    }
+
+    /// Remove the sessions whose retention ended by `now`.
+    pub fn expire(&mut self, now: Instant) -> Vec<Session> {
+        let mut expired = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.sessions.len());
+        for (id, session) in self.sessions.drain(..) {
+            if session.retained_until.is_some_and(|until| until <= now) {
+                expired.push(session);
+            } else {
+                kept.push_back((id, session));
+            }
+        }
+        self.sessions = kept;
+        expired
+    }
+}
@@ -0,0 +1,271 @@
+//! State of the recurrent layers of hybrid models, which mix attention with state-space or
+//! recurrent blocks (Mamba, RWKV, Jamba), kept alongside the paged KV cache of their attention
+//! layers. Unlike the KV cache it does not grow with the sequence: every sequence holds a slot of
+//! fixed-size tensors from its allocation to its end, handed out by `StateSlots` as the block
+//! engine hands out blocks, and zeroed before its prefill.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use candle_core::{DType, Device, Tensor};
+
+use crate::{openai::responses::APIError, try_api};
+
+/// The recurrent layers of a hybrid model and the states each of them keeps per sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecurrentStateConfig {
+    /// Layers that are recurrent, the other layers attend the KV cache.
+    pub layers: Vec<usize>,
+    /// Shapes of the states a recurrent layer keeps per sequence, e.g. the convolution and SSM
+    /// states of a Mamba block.
+    pub state_shapes: Vec<Vec<usize>>,
+    pub dtype: DType,
+}
+
+impl RecurrentStateConfig {
+    /// Bytes of the states of a sequence, over every recurrent layer.
+    pub fn slot_bytes(&self) -> usize {
+        let elements = self
+            .state_shapes
+            .iter()
+            .map(|shape| shape.iter().product::<usize>())
+            .sum::<usize>();
+        self.layers.len() * elements * self.dtype.size_in_bytes()
+    }
+}
+
+/// The slots of the state store, by sequence.
+#[derive(Debug)]
+pub struct StateSlots {
+    num_slots: usize,
+    free: Vec<usize>,
+    /// Slot of each sequence, by sequence id.
+    by_seq: HashMap<usize, usize>,
+    /// Slots handed out since the last `take_new_slots`, zeroed before the next step.
+    new_slots: Vec<usize>,
+}
+
+impl StateSlots {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            num_slots,
+            // Lowest slots first.
+            free: (0..num_slots).rev().collect(),
+            by_seq: HashMap::new(),
+            new_slots: Vec::new(),
+        }
+    }
+
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Hand a slot to `seq_id`, `None` if they are all taken.
+    pub fn allocate(&mut self, seq_id: usize) -> Option<usize> {
+        if let Some(&slot) = self.by_seq.get(&seq_id) {
+            return Some(slot);
+        }
+        let slot = self.free.pop()?;
+        self.by_seq.insert(seq_id, slot);
+        self.new_slots.push(slot);
+        Some(slot)
+    }
+
+    /// Give the slot of `seq_id` back, if it has one.
+    pub fn free(&mut self, seq_id: usize) {
+        if let Some(slot) = self.by_seq.remove(&seq_id) {
+            self.new_slots.retain(|&new| new != slot);
+            self.free.push(slot);
+        }
+    }
+
+    pub fn get(&self, seq_id: usize) -> Option<usize> {
+        self.by_seq.get(&seq_id).copied()
+    }
+
+    /// The slots handed out since the last call, whose states have to be zeroed.
+    pub fn take_new_slots(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.new_slots)
+    }
+}
+
+/// The states of every slot on the device: for each recurrent layer, a `(num_slots, ..shape)`
+/// tensor per state. Clones share the tensors.
+#[derive(Clone)]
+pub struct StateCache {
+    config: RecurrentStateConfig,
+    states: Arc<Mutex<Vec<Vec<Tensor>>>>,
+}
+
+impl StateCache {
+    pub fn new(
+        config: RecurrentStateConfig,
+        num_slots: usize,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let mut states = Vec::new();
+        for _ in &config.layers {
+            let mut layer = Vec::new();
+            for shape in &config.state_shapes {
+                let shape = [&[num_slots][..], shape].concat();
+                layer.push(try_api!(Tensor::zeros(shape, config.dtype, device)));
+            }
+            states.push(layer);
+        }
+        Ok(Self {
+            config,
+            states: Arc::new(Mutex::new(states)),
+        })
+    }
+
+    pub fn config(&self) -> &RecurrentStateConfig {
+        &self.config
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Vec<Tensor>>> {
+        self.states.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Index of the model layer `layer` among the recurrent layers.
+    fn layer_index(&self, layer: usize) -> Result<usize, APIError> {
+        self.config
+            .layers
+            .iter()
+            .position(|&l| l == layer)
+            .ok_or_else(|| APIError::new(format!("Layer {layer} has no recurrent state.")))
+    }
+
+    /// Zero the states of `slots`, handed to new sequences.
+    pub fn reset(&self, slots: &[usize]) -> Result<(), APIError> {
+        if slots.is_empty() {
+            return Ok(());
+        }
+        let mut states = self.lock();
+        for layer in states.iter_mut() {
+            for state in layer.iter_mut() {
+                let mut shape = state.dims().to_vec();
+                shape[0] = slots.len();
+                let zeros = try_api!(Tensor::zeros(shape, state.dtype(), state.device()));
+                *state = try_api!(Self::write_rows(state, slots, &zeros));
+            }
+        }
+        Ok(())
+    }
+
+    /// States of the model layer `layer` for `slots`: a `(slots.len(), ..shape)` tensor per
+    /// state, in the order of `state_shapes`.
+    pub fn read(&self, layer: usize, slots: &[usize]) -> Result<Vec<Tensor>, APIError> {
+        let index = self.layer_index(layer)?;
+        let states = self.lock();
+        let mut read = Vec::new();
+        for state in &states[index] {
+            let ids = Self::slot_ids(slots, state.device())?;
+            read.push(try_api!(state.index_select(&ids, 0)));
+        }
+        Ok(read)
+    }
+
+    /// Write the states `new_states` of the model layer `layer` for `slots`, as returned by
+    /// `read`.
+    pub fn write(
+        &self,
+        layer: usize,
+        slots: &[usize],
+        new_states: &[Tensor],
+    ) -> Result<(), APIError> {
+        let index = self.layer_index(layer)?;
+        let mut states = self.lock();
+        if new_states.len() != states[index].len() {
+            return Err(APIError::new(format!(
+                "Layer {layer} keeps {} states, got {}.",
+                states[index].len(),
+                new_states.len()
+            )));
+        }
+        for (state, new_state) in states[index].iter_mut().zip(new_states) {
+            if new_state.dims()[0] != slots.len() || new_state.dims()[1..] != state.dims()[1..] {
+                return Err(APIError::new(format!(
+                    "States of shape {:?} do not fit {} slots of shape {:?}.",
+                    new_state.dims(),
+                    slots.len(),
+                    &state.dims()[1..]
+                )));
+            }
+            let new_state = try_api!(new_state.to_dtype(state.dtype()));
+            *state = try_api!(Self::write_rows(state, slots, &new_state));
+        }
+        Ok(())
+    }
+
+    /// `state` with its rows `slots` replaced by the rows of `rows`, in order.
+    fn write_rows(state: &Tensor, slots: &[usize], rows: &Tensor) -> candle_core::Result<Tensor> {
+        let num_slots = state.dims()[0];
+        let mut ids = (0..num_slots as u32).collect::<Vec<_>>();
+        for (i, &slot) in slots.iter().enumerate() {
+            ids[slot] = (num_slots + i) as u32;
+        }
+        let ids = Tensor::new(ids.as_slice(), state.device())?;
+        Tensor::cat(&[state, rows], 0)?.index_select(&ids, 0)
+    }
+
+    fn slot_ids(slots: &[usize], device: &Device) -> Result<Tensor, APIError> {
+        let ids = slots.iter().map(|&slot| slot as u32).collect::<Vec<_>>();
+        Ok(try_api!(Tensor::new(ids.as_slice(), device)))
+    }
+}
+
+/// The recurrent states of the sequences of a model step, for the recurrent layers of a hybrid
+/// model to read before their step and write after it. Prompts are padded to the longest one, a
+/// layer leaves the state of a sequence as it was at the end of its `prompt_lens`.
+///
+/// The writes are kept aside until `commit`, which the worker calls once the whole step ran: a
+/// step that fails, e.g. out of memory and retried with a smaller batch, leaves the states as
+/// they were.
+#[derive(Clone)]
+pub struct RecurrentStateInput {
+    cache: StateCache,
+    /// Slot of each sequence of the step, in the order of the batch.
+    slots: Vec<usize>,
+    written: Arc<Mutex<Vec<(usize, Vec<Tensor>)>>>,
+}
+
+impl RecurrentStateInput {
+    pub fn new(cache: StateCache, slots: Vec<usize>) -> Self {
+        Self {
+            cache,
+            slots,
+            written: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn slots(&self) -> &[usize] {
+        &self.slots
+    }
+
+    /// States of the model layer `layer`, one row per sequence of the step.
+    pub fn read(&self, layer: usize) -> Result<Vec<Tensor>, APIError> {
+        self.cache.read(layer, &self.slots)
+    }
+
+    /// New states of the model layer `layer`, as returned by `read`.
+    pub fn write(&self, layer: usize, states: Vec<Tensor>) {
+        self.written
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((layer, states));
+    }
+
+    /// Write the states of the step to the store.
+    pub fn commit(&self) -> Result<(), APIError> {
+        let written = std::mem::take(&mut *self.written.lock().unwrap_or_else(|e| e.into_inner()));
+        for (layer, states) in written {
+            self.cache.write(layer, &self.slots, &states)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+//! Export of the metrics of the engine and of a trace per request to an OpenTelemetry collector,
+//! over OTLP/HTTP with JSON bodies. The exporter observes the engine, aggregating its steps and
+//! finished requests in memory, and a task posts the cumulative metrics and the spans of the
+//! requests finished since the last export at a fixed interval, so that the engine loop never
+//! waits on the collector. Only plain HTTP endpoints are supported: TLS to a remote collector
+//! goes through a local one.
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{client::HttpConnector, Body, Client, Request, Uri};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    openai::{
+        hooks::{EngineObserver, StepEvent},
+        responses::{APIError, ChatChoice, ChatCompletionUsageResponse},
+    },
+    scheduler::sequence::SequenceGroupMetrics,
+};
+
+/// Spans kept for the next export, the oldest are dropped past it while the collector is down.
+pub const MAX_QUEUED_SPANS: usize = 2048;
+/// Upper bounds of the buckets of the latency histograms (seconds).
+const LATENCY_BOUNDS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TelemetryConfig {
+    /// Base URL of the collector, e.g. `http://localhost:4318`, the signals are posted to
+    /// `/v1/metrics` and `/v1/traces` under it.
+    pub endpoint: String,
+    /// Share of the requests traced, from 0 to 1.
+    pub trace_sample_ratio: f64,
+    pub export_interval: Duration,
+    /// Attributes of the resource of every signal, `service.name` among them.
+    pub resource: Vec<(String, String)>,
+}
+
+/// Id of the trace of the request `request_id`, derived from it so that a request is found in
+/// the collector by its id.
+pub fn trace_id(request_id: &str) -> String {
+    format!("{:x}", Sha256::digest(request_id.as_bytes()))[..32].to_string()
+}
+
+fn span_id(request_id: &str, span: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{request_id}/{span}")))[..16].to_string()
+}
+
+/// Whether the request with the trace `trace_id` is traced: the first 53 bits of the id, a
+/// uniform number in [0, 1), fall under `ratio`.
+fn sampled(trace_id: &str, ratio: f64) -> bool {
+    let bits = u64::from_str_radix(&trace_id[..16], 16).unwrap_or(u64::MAX) >> 11;
+    (bits as f64 / (1u64 << 53) as f64) < ratio
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attributes(attributes: &[(&str, Value)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Number(n) if n.is_u64() || n.is_i64() => json!({"intValue": n.to_string()}),
+                Value::Number(n) => json!({"doubleValue": n}),
+                value => json!({"stringValue": value.as_str().unwrap_or_default()}),
+            };
+            json!({"key": key, "value": value})
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Observations in each bucket, the last one past the last bound.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BOUNDS.len() + 1];
+            (self.min, self.max) = (value, value);
+        }
+        self.buckets[LATENCY_BOUNDS.partition_point(|&bound| bound < value)] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn data_point(&self, attributes: Value, start: &str, now: &str) -> Value {
+        json!({
+            "attributes": attributes,
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "count": self.count.to_string(),
+            "sum": self.sum,
+            "min": self.min,
+            "max": self.max,
+            "bucketCounts": self.buckets.iter().map(u64::to_string).collect::<Vec<_>>(),
+            "explicitBounds": LATENCY_BOUNDS,
+        })
+    }
+}
+
+/// Aggregates of the engine since the exporter was created, indexed by prefill (0) and decode
+/// (1) for the steps.
+#[derive(Debug, Default)]
+struct EngineMetrics {
+    /// Finished requests by the finish reasons of their choices.
+    requests: BTreeMap<String, u64>,
+    aborted_requests: u64,
+    prompt_tokens: u64,
+    generation_tokens: u64,
+    steps: [u64; 2],
+    step_duration: [Histogram; 2],
+    /// Sequences run by the last step.
+    batch_seqs: usize,
+    queue_time: Histogram,
+    time_to_first_token: Histogram,
+    time_per_output_token: Histogram,
+    e2e_latency: Histogram,
+}
+
+/// What `on_finish` knows of a request that `on_request_metrics` traces.
+struct FinishedRequest {
+    prompt_tokens: usize,
+    finish_reasons: String,
+}
+
+/// Observer of the engine exporting its metrics and traces over OTLP, added with
+/// `LLMEngine::add_observer` and exported by `spawn`.
+pub struct OtlpExporter {
+    config: TelemetryConfig,
+    client: Client<HttpConnector>,
+    start_time: SystemTime,
+    metrics: Mutex<EngineMetrics>,
+    finished: Mutex<HashMap<String, FinishedRequest>>,
+    spans: Mutex<VecDeque<Value>>,
+    dropped_spans: AtomicU64,
+}
+
+impl OtlpExporter {
+    pub fn new(mut config: TelemetryConfig) -> Result<Self, APIError> {
+        let uri = config.endpoint.parse::<Uri>().map_err(|e| {
+            APIError::new(format!("Invalid OTLP endpoint {}: {e}", config.endpoint))
+        })?;
+        if uri.scheme_str() != Some("http") {
+            return Err(APIError::new(format!(
+                "The OTLP endpoint {} is not an http:// URL, export through a local collector \
+                 for TLS.",
+                config.endpoint
+            )));
+        }
+        if !(0.0..=1.0).contains(&config.trace_sample_ratio) {
+            return Err(APIError::new(format!(
+                "The trace sample ratio must be between 0 and 1, got {}.",
+                config.trace_sample_ratio
+            )));
+        }
+        if config.export_interval.is_zero() {
+            return Err(APIError::new_str("The OTLP export interval cannot be 0."));
+        }
+        config.endpoint = config.endpoint.trim_end_matches('/').to_string();
+        Ok(Self {
+            config,
+            client: Client::new(),
+            start_time: SystemTime::now(),
+            metrics: Mutex::new(EngineMetrics::default()),
+            finished: Mutex::new(HashMap::new()),
+            spans: Mutex::new(VecDeque::new()),
+            dropped_spans: AtomicU64::new(0),
+        })
+    }
+
+    /// Add the attribute `key` to the resource of the signals, e.g. one known once the model
+    /// loaded.
+    pub fn with_resource(mut self, key: &str, value: String) -> Self {
+        self.config.resource.push((key.to_string(), value));
+        self
+    }
+
+    fn resource(&self) -> Value {
+        let resource = self
+            .config
+            .resource
+            .iter()
+            .map(|(key, value)| (key.as_str(), json!(value)))
+            .collect::<Vec<_>>();
+        json!({"attributes": attributes(&resource)})
+    }
+
+    fn scope() -> Value {
+        json!({"name": "candle-vllm", "version": env!("CARGO_PKG_VERSION")})
+    }
+
+    /// Body of `/v1/metrics` with the metrics aggregated since the exporter was created.
+    pub fn metrics_payload(&self) -> Value {
+        let metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        let start = unix_nanos(self.start_time);
+        let now = unix_nanos(SystemTime::now());
+        let counter = |name: &str, unit: &str, description: &str, points: Vec<(Value, u64)>| {
+            let points = points
+                .into_iter()
+                .map(|(attributes, value)| {
+                    json!({
+                        "attributes": attributes,
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                        "asInt": value.to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "name": name,
+                "unit": unit,
+                "description": description,
+                "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": points},
+            })
+        };
+        let histogram = |name: &str, description: &str, points: Vec<(Value, &Histogram)>| {
+            let points = points
+                .into_iter()
+                .filter(|(_, histogram)| histogram.count > 0)
+                .map(|(attributes, histogram)| histogram.data_point(attributes, &start, &now))
+                .collect::<Vec<_>>();
+            json!({
+                "name": name,
+                "unit": "s",
+                "description": description,
+                "histogram": {"aggregationTemporality": 2, "dataPoints": points},
+            })
+        };
+        let phase = |phase: &str| attributes(&[("phase", json!(phase))]);
+        let no_attributes = || attributes(&[]);
+        let metrics = vec![
+            counter(
+                "candle_vllm.requests",
+                "{request}",
+                "Finished requests, by the finish reasons of their choices",
+                metrics
+                    .requests
+                    .iter()
+                    .map(|(reasons, count)| {
+                        (attributes(&[("finish_reason", json!(reasons))]), *count)
+                    })
+                    .collect(),
+            ),
+            counter(
+                "candle_vllm.requests.aborted",
+                "{request}",
+                "Requests aborted as the engine failed",
+                vec![(no_attributes(), metrics.aborted_requests)],
+            ),
+            counter(
+                "candle_vllm.prompt_tokens",
+                "{token}",
+                "Prompt tokens of the finished requests",
+                vec![(no_attributes(), metrics.prompt_tokens)],
+            ),
+            counter(
+                "candle_vllm.generation_tokens",
+                "{token}",
+                "Tokens generated by the finished requests",
+                vec![(no_attributes(), metrics.generation_tokens)],
+            ),
+            counter(
+                "candle_vllm.steps",
+                "{step}",
+                "Scheduler steps run by the engine",
+                vec![
+                    (phase("prefill"), metrics.steps[0]),
+                    (phase("decode"), metrics.steps[1]),
+                ],
+            ),
+            histogram(
+                "candle_vllm.step.duration",
+                "Duration of the scheduler steps",
+                vec![
+                    (phase("prefill"), &metrics.step_duration[0]),
+                    (phase("decode"), &metrics.step_duration[1]),
+                ],
+            ),
+            json!({
+                "name": "candle_vllm.batch.seqs",
+                "unit": "{sequence}",
+                "description": "Sequences run by the last scheduler step",
+                "gauge": {"dataPoints": [{
+                    "timeUnixNano": now,
+                    "asInt": metrics.batch_seqs.to_string(),
+                }]},
+            }),
+            histogram(
+                "candle_vllm.request.queue_time",
+                "Time the requests waited to be scheduled",
+                vec![(no_attributes(), &metrics.queue_time)],
+            ),
+            histogram(
+                "candle_vllm.request.time_to_first_token",
+                "Time from the arrival of the requests to their first token",
+                vec![(no_attributes(), &metrics.time_to_first_token)],
+            ),
+            histogram(
+                "candle_vllm.request.time_per_output_token",
+                "Mean time between the tokens following the first one of the requests",
+                vec![(no_attributes(), &metrics.time_per_output_token)],
+            ),
+            histogram(
+                "candle_vllm.request.e2e_latency",
+                "Time from the arrival of the requests to their end",
+                vec![(no_attributes(), &metrics.e2e_latency)],
+            ),
+        ];
+        json!({"resourceMetrics": [{
+            "resource": self.resource(),
+            "scopeMetrics": [{"scope": Self::scope(), "metrics": metrics}],
+        }]})
+    }
+
+    /// Body of `/v1/traces` with the spans of the requests traced since the last call, `None`
+    /// without any.
+    pub fn take_traces_payload(&self) -> Option<Value> {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap_or_else(|e| e.into_inner()));
+        if spans.is_empty() {
+            return None;
+        }
+        Some(json!({"resourceSpans": [{
+            "resource": self.resource(),
+            "scopeSpans": [{"scope": Self::scope(), "spans": Vec::from(spans)}],
+        }]}))
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> Result<(), APIError> {
+        let request = Request::post(format!("{}{path}", self.config.endpoint))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(body).map_err(APIError::from)?,
+            ))
+            .map_err(APIError::from)?;
+        let response = tokio::time::timeout(EXPORT_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| APIError::new(format!("The OTLP collector timed out on {path}")))?
+            .map_err(APIError::from)?;
+        if !response.status().is_success() {
+            return Err(APIError::new(format!(
+                "The OTLP collector answered {} to {path}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Post the metrics and the spans queued since the last export to the collector. Spans it
+    /// refuses are lost, the metrics are cumulative and sent whole every time.
+    pub async fn export(&self) -> Result<(), APIError> {
+        let dropped = self.dropped_spans.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                dropped,
+                "spans dropped while the OTLP collector was unreachable"
+            );
+        }
+        let traces = self.take_traces_payload();
+        let metrics = self.post("/v1/metrics", &self.metrics_payload()).await;
+        if let Some(traces) = traces {
+            self.post("/v1/traces", &traces).await?;
+        }
+        metrics
+    }
+
+    /// Export every `export_interval` for as long as the server runs.
+    pub fn spawn(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.export_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.export().await {
+                    warn!("OTLP export failed: {e}");
+                }
+            }
+        });
+    }
+
+    fn queue_spans(&self, spans: Vec<Value>) {
+        let mut queued = self.spans.lock().unwrap_or_else(|e| e.into_inner());
+        queued.extend(spans);
+        let excess = queued.len().saturating_sub(MAX_QUEUED_SPANS);
+        if excess > 0 {
+            queued.drain(..excess);
+            self.dropped_spans
+                .fetch_add(excess as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl EngineObserver for OtlpExporter {
+    fn on_step(&self, step: &StepEvent) {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        let phase = usize::from(!step.is_prompt);
+        metrics.steps[phase] += 1;
+        metrics.step_duration[phase].record(step.duration);
+        metrics.batch_seqs = step.num_seqs;
+    }
+
+    fn on_finish(
+        &self,
+        request_id: &str,
+        choices: &[ChatChoice],
+        usage: &ChatCompletionUsageResponse,
+    ) {
+        let mut finish_reasons = choices
+            .iter()
+            .filter_map(|choice| choice.finish_reason.as_deref())
+            .collect::<Vec<_>>();
+        finish_reasons.sort_unstable();
+        finish_reasons.dedup();
+        let finish_reasons = finish_reasons.join(",");
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        *metrics.requests.entry(finish_reasons.clone()).or_default() += 1;
+        metrics.prompt_tokens += usage.prompt_tokens as u64;
+        metrics.generation_tokens += usage.completion_tokens as u64;
+        drop(metrics);
+        self.finished
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                request_id.to_string(),
+                FinishedRequest {
+                    prompt_tokens: usage.prompt_tokens,
+                    finish_reasons,
+                },
+            );
+    }
+
+    fn on_request_metrics(&self, request_id: &str, request: &SequenceGroupMetrics) {
+        let finished = self
+            .finished
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(request_id);
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(queue_time) = request.queue_time() {
+            metrics.queue_time.record(queue_time);
+        }
+        if let Some(time_to_first_token) = request.time_to_first_token() {
+            metrics.time_to_first_token.record(time_to_first_token);
+        }
+        if let Some(time_per_output_token) = request.time_per_output_token() {
+            metrics.time_per_output_token.record(time_per_output_token);
+        }
+        let Some(finished_time) = request.finished_time else {
+            return;
+        };
+        metrics.e2e_latency.record(
+            finished_time
+                .duration_since(request.arrival_time)
+                .unwrap_or_default(),
+        );
+        drop(metrics);
+
+        let trace_id = trace_id(request_id);
+        if !sampled(&trace_id, self.config.trace_sample_ratio) {
+            return;
+        }
+        let root = span_id(request_id, "request");
+        let mut root_attributes = vec![
+            ("candle_vllm.request_id", json!(request_id)),
+            (
+                "gen_ai.usage.output_tokens",
+                json!(request.num_output_tokens),
+            ),
+        ];
+        if let Some(finished) = &finished {
+            root_attributes.push(("gen_ai.usage.input_tokens", json!(finished.prompt_tokens)));
+            root_attributes.push((
+                "gen_ai.response.finish_reasons",
+                json!(finished.finish_reasons),
+            ));
+        }
+        if request.num_proposed_tokens > 0 {
+            root_attributes.push((
+                "candle_vllm.proposed_tokens",
+                json!(request.num_proposed_tokens),
+            ));
+            root_attributes.push((
+                "candle_vllm.accepted_tokens",
+                json!(request.num_accepted_tokens),
+            ));
+        }
+        let span = |name: &str,
+                    parent: Option<&str>,
+                    start: SystemTime,
+                    end: SystemTime,
+                    attrs: &[(&str, Value)]| {
+            let mut span = json!({
+                "traceId": trace_id,
+                "spanId": if parent.is_some() { span_id(request_id, name) } else { root.clone() },
+                "name": name,
+                // A server span for the request, internal ones for its stages.
+                "kind": if parent.is_some() { 1 } else { 2 },
+                "startTimeUnixNano": unix_nanos(start),
+                "endTimeUnixNano": unix_nanos(end),
+                "attributes": attributes(attrs),
+                "status": {"code": 1},
+            });
+            if let Some(parent) = parent {
+                span["parentSpanId"] = json!(parent);
+            }
+            span
+        };
+        let mut spans = vec![span(
+            "request",
+            None,
+            request.arrival_time,
+            finished_time,
+            &root_attributes,
+        )];
+        // A preempted request keeps the time it was first scheduled, its prefill span covers
+        // the time it was swapped out or recomputed.
+        let stages = [
+            (
+                "queue",
+                Some(request.arrival_time),
+                request.first_scheduled_time,
+            ),
+            (
+                "prefill",
+                request.first_scheduled_time,
+                request.first_token_time,
+            ),
+            ("decode", request.first_token_time, Some(finished_time)),
+        ];
+        for (name, start, end) in stages {
+            if let (Some(start), Some(end)) = (start, end) {
+                spans.push(span(name, Some(&root), start, end, &[]));
+            }
+        }
+        self.queue_spans(spans);
+    }
+
+    fn on_abort(&self, request_id: &str, _error: &APIError) {
+        self.metrics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .aborted_requests += 1;
+        self.finished
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(request_id);
+    }
+}
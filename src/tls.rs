@@ -0,0 +1,86 @@
+//! TLS termination in the server, for deployments without a proxy in front of it. The certificate
+//! chain and private key are PEM files read at startup and again on SIGHUP, so that renewed
+//! certificates are picked up without a restart: the connections accepted after the reload use
+//! them, the open ones keep theirs.
+use std::{net::SocketAddr, path::PathBuf};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::openai::responses::APIError;
+
+/// PEM files of the certificate chain and the private key of the server.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    pub async fn load(&self) -> Result<RustlsConfig, APIError> {
+        let (cert, key) = self.read()?;
+        RustlsConfig::from_pem(cert, key)
+            .await
+            .map_err(|err| self.invalid(err))
+    }
+
+    /// Replace the certificate and key of `config` with the ones in the files. `config` is left
+    /// as is if they cannot be loaded, e.g. while a renewal is half written.
+    pub async fn reload(&self, config: &RustlsConfig) -> Result<(), APIError> {
+        let (cert, key) = self.read()?;
+        config
+            .reload_from_pem(cert, key)
+            .await
+            .map_err(|err| self.invalid(err))
+    }
+
+    fn read(&self) -> Result<(Vec<u8>, Vec<u8>), APIError> {
+        let read = |kind: &str, path: &PathBuf| {
+            std::fs::read(path).map_err(|err| {
+                APIError::new(format!(
+                    "Cannot read the TLS {kind} {}: {err}",
+                    path.display()
+                ))
+            })
+        };
+        Ok((read("certificate", &self.cert)?, read("key", &self.key)?))
+    }
+
+    fn invalid(&self, err: std::io::Error) -> APIError {
+        APIError::new(format!(
+            "Invalid TLS certificate {} or key {}: {err}",
+            self.cert.display(),
+            self.key.display()
+        ))
+    }
+}
+
+/// Reload `config` from `files` on every SIGHUP, keeping the previous certificate on errors.
+#[cfg(unix)]
+pub fn reload_on_sighup(files: TlsFiles, config: RustlsConfig) -> Result<(), APIError> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).map_err(APIError::from)?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match files.reload(&config).await {
+                Ok(()) => tracing::info!(cert = %files.cert.display(), "TLS certificate reloaded"),
+                Err(err) => tracing::warn!("TLS certificate not reloaded: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_files: TlsFiles, _config: RustlsConfig) -> Result<(), APIError> {
+    Ok(())
+}
+
+/// Serve `app` over HTTPS on `addr`.
+pub async fn serve(addr: SocketAddr, app: Router, config: RustlsConfig) -> Result<(), APIError> {
+    axum_server::bind_rustls(addr, config)
+        .serve(app.into_make_service())
+        .await
+        .map_err(APIError::from)
+}
@@ -0,0 +1,281 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use candle_vllm::{
+    openai::{
+        openai_server::{
+            abort_request, debug_scheduler, get_admin_config, list_requests, post_admin_config,
+            recovered_requests, sleep, wake,
+        },
+        requests::{AdminConfigUpdate, SleepQuery},
+        OpenAIServerData,
+    },
+    scheduler::{Scheduler, SchedulerConfig, SchedulerLimits},
+};
+use serde_json::{json, Value};
+use std::{future::Future, sync::Arc};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::{cache_config, sequence_group, tiny_model::TinyEngine};
+
+const TOKEN: &str = "secret";
+
+fn bearer(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        format!("Bearer {token}").parse().unwrap(),
+    );
+    headers
+}
+
+/// Status and JSON body of the response of `responder`.
+fn respond(responder: impl Future<Output = impl IntoResponse>) -> (StatusCode, Value) {
+    Runtime::new().unwrap().block_on(async {
+        let response = responder.await.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    })
+}
+
+fn get(data: &Arc<OpenAIServerData>, headers: HeaderMap) -> (StatusCode, Value) {
+    respond(get_admin_config(State(data.clone()), headers))
+}
+
+fn post(data: &Arc<OpenAIServerData>, update: Value) -> (StatusCode, Value) {
+    let update: AdminConfigUpdate = serde_json::from_value(update).unwrap();
+    respond(post_admin_config(
+        State(data.clone()),
+        bearer(TOKEN),
+        Json(update),
+    ))
+}
+
+#[test]
+fn admin_api_needs_the_token() {
+    let engine = TinyEngine::new(16);
+    let disabled = Arc::new(engine.server_data(None));
+    assert_eq!(get(&disabled, bearer(TOKEN)).0, StatusCode::NOT_FOUND);
+
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    assert_eq!(get(&data, HeaderMap::new()).0, StatusCode::UNAUTHORIZED);
+    assert_eq!(get(&data, bearer("secreT")).0, StatusCode::UNAUTHORIZED);
+    assert_eq!(get(&data, bearer("secret2")).0, StatusCode::UNAUTHORIZED);
+    let (status, config) = get(&data, bearer(TOKEN));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(config["max_num_seqs"], 16);
+    assert_eq!(config["enable_prefix_caching"], false);
+}
+
+#[test]
+fn sleep_wake_and_the_scheduler_snapshot_need_the_token() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    let sleep_query = || Query(SleepQuery { level: None });
+    for headers in [HeaderMap::new(), bearer("secreT")] {
+        let status = respond(sleep(State(data.clone()), headers.clone(), sleep_query())).0;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            respond(wake(State(data.clone()), headers.clone())).0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            respond(debug_scheduler(State(data.clone()), headers)).0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+    let disabled = Arc::new(engine.server_data(None));
+    assert_eq!(
+        respond(sleep(State(disabled), bearer(TOKEN), sleep_query())).0,
+        StatusCode::NOT_FOUND
+    );
+
+    let (status, body) = respond(sleep(State(data.clone()), bearer(TOKEN), sleep_query()));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["is_sleeping"], true);
+    let (status, body) = respond(wake(State(data.clone()), bearer(TOKEN)));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["is_sleeping"], false);
+    assert_eq!(
+        respond(debug_scheduler(State(data), bearer(TOKEN))).0,
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn recovered_requests_need_the_token() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    assert_eq!(
+        respond(recovered_requests(State(data.clone()), HeaderMap::new())).0,
+        StatusCode::UNAUTHORIZED
+    );
+    let (status, body) = respond(recovered_requests(State(data), bearer(TOKEN)));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+}
+
+#[test]
+fn update_changes_limits_and_sampling_defaults() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    let (status, config) = post(
+        &data,
+        json!({"max_num_seqs": 4, "max_num_prefill_tokens": 64, "temperature": 0.7, "top_k": 20}),
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(config["max_num_seqs"], 4);
+    assert_eq!(config["max_num_prefill_tokens"], 64);
+    assert_eq!(config["top_k"], 20);
+    assert_eq!(data.pipeline_config().top_k, 20);
+    assert_eq!(
+        *data.scheduler_limits.read().unwrap(),
+        SchedulerLimits {
+            max_num_seqs: 4,
+            max_num_prefill_tokens: Some(64),
+        }
+    );
+
+    // `null` lifts the prefill budget, a missing field keeps its value.
+    let (_, config) = post(&data, json!({"max_num_prefill_tokens": null}));
+    assert_eq!(config["max_num_prefill_tokens"], Value::Null);
+    assert_eq!(config["max_num_seqs"], 4);
+}
+
+#[test]
+fn invalid_update_changes_nothing() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    let (_, before) = get(&data, bearer(TOKEN));
+    for update in [
+        json!({"max_num_seqs": 8, "top_p": 0.0}),
+        json!({"max_num_seqs": 0}),
+        json!({"temperature": 0.5, "repetition_penalty": 3.0}),
+        json!({"max_tokens": 1_000_000}),
+        json!({"enable_prefix_caching": true}),
+        // The test server did not install the logger.
+        json!({"max_num_seqs": 8, "log_level": "debug"}),
+    ] {
+        let (status, _) = post(&data, update.clone());
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{update}");
+        assert_eq!(get(&data, bearer(TOKEN)).1, before, "{update}");
+    }
+}
+
+#[test]
+fn lowered_limit_applies_from_the_next_step() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 8,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    for id in 0..3 {
+        scheduler.add_sequence(sequence_group(id, 8, block_size));
+    }
+    scheduler.schedule();
+    assert_eq!(scheduler.snapshot().running.len(), 3);
+
+    // Running sequences over the new limit finish, no other one is admitted meanwhile.
+    scheduler.set_limits(SchedulerLimits {
+        max_num_seqs: 2,
+        max_num_prefill_tokens: None,
+    });
+    scheduler.add_sequence(sequence_group(3, 8, block_size));
+    scheduler.schedule();
+    let snapshot = scheduler.snapshot();
+    assert_eq!(snapshot.running.len(), 3);
+    assert_eq!(snapshot.waiting.len(), 1);
+}
+
+#[test]
+fn engine_generates_under_updated_limits() {
+    let mut engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    let prompts = ["t5 t9 t17", "t11 t3 t50", "t6 t23 t31"].map(|prompt| engine.encode(prompt));
+    let alone = prompts
+        .iter()
+        .map(|prompt| engine.generate(std::slice::from_ref(prompt), 4).remove(0))
+        .collect::<Vec<_>>();
+    assert_eq!(post(&data, json!({"max_num_seqs": 2})).0, StatusCode::OK);
+    assert_eq!(engine.generate(&prompts, 4), alone);
+}
+
+#[test]
+fn running_requests_are_listed_and_aborted() {
+    let mut engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(Some(TOKEN)));
+    let prompts = ["t5 t9 t17", "t11 t3 t50 t8"].map(|prompt| engine.encode(prompt));
+    engine.submit(&prompts, 8);
+    engine.step().unwrap();
+
+    let list = || respond(list_requests(State(data.clone()), bearer(TOKEN)));
+    assert_eq!(
+        respond(list_requests(State(data.clone()), HeaderMap::new())).0,
+        StatusCode::UNAUTHORIZED
+    );
+    let (status, requests) = list();
+    assert_eq!(status, StatusCode::OK);
+    let requests = requests["data"].as_array().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    for (request, prompt) in requests.iter().zip(&prompts) {
+        assert_eq!(request["state"], "running");
+        assert_eq!(request["prompt_tokens"], prompt.len());
+        assert_eq!(request["completion_tokens"], 1);
+    }
+
+    let abort = |request_id: &str| {
+        respond(abort_request(
+            State(data.clone()),
+            bearer(TOKEN),
+            Path(request_id.to_string()),
+        ))
+    };
+    let (status, aborted) = abort("tiny-0");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(aborted["id"], "tiny-0");
+    assert_eq!(abort("tiny-7").0, StatusCode::NOT_FOUND);
+
+    // The request is aborted at the next step, keeping what it generated, the other one runs on.
+    let outputs = engine.step().unwrap();
+    let output = outputs.iter().find(|o| o.request_id == "tiny-0").unwrap();
+    let (choices, _) = output.response.as_ref().unwrap();
+    assert_eq!(choices[0].finish_reason.as_deref(), Some("abort"));
+    assert_eq!(output.token_ids[0].len(), 1);
+    let (_, requests) = list();
+    let requests = requests["data"].as_array().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["id"], "tiny-1");
+    assert_eq!(requests[0]["completion_tokens"], 2);
+}
+
+#[test]
+fn only_configured_api_keys_are_tenants() {
+    let engine = TinyEngine::new(16);
+    let data = OpenAIServerData {
+        api_keys: ["team-a", "team-b"].map(str::to_string).into(),
+        ..engine.server_data(None)
+    };
+    let team_a = data.tenant(Some("team-a")).unwrap();
+    assert_eq!(data.tenant(Some("team-a")), Some(team_a.clone()));
+    assert_ne!(data.tenant(Some("team-b")), Some(team_a.clone()));
+    assert!(!team_a.contains("team-a"));
+    // Made up keys share the tenant of the requests without one.
+    assert_eq!(data.tenant(Some("random-1")), None);
+    assert_eq!(data.tenant(Some("random-2")), None);
+    assert_eq!(data.tenant(None), None);
+}
@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use candle_vllm::openai::{
+    openai_server::completions, pipelines::llm_engine::SleepLevel, responses::ChatResponder,
+    OpenAIServerData,
+};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const MAX_TOKENS: usize = 6;
+
+#[test]
+fn queued_requests_are_taken_in_at_the_next_step() {
+    let mut expected = TinyEngine::new(16);
+    let prompts = ["t5 t9 t17 t33", "t40 t11 t3"].map(|prompt| expected.encode(prompt));
+    let expected = prompts
+        .iter()
+        .map(|prompt| {
+            expected
+                .generate(std::slice::from_ref(prompt), MAX_TOKENS)
+                .remove(0)
+        })
+        .collect::<Vec<_>>();
+
+    let mut engine = TinyEngine::new(16);
+    engine.submit(&prompts[..1], MAX_TOKENS);
+    engine.step().unwrap();
+    let (request_ids, tokens) = engine.enqueue(&prompts[1..], MAX_TOKENS, |engine| {
+        // Queued, the request is waiting like the ones the scheduler holds.
+        assert_eq!(engine.num_waiting_requests(), 1);
+        let mut tokens = HashMap::new();
+        while engine.has_unfinished_requests() {
+            for output in engine.step().unwrap() {
+                tokens.insert(output.request_id, output.token_ids[0].clone());
+            }
+        }
+        tokens
+    });
+    assert_eq!(tokens["tiny-0"], expected[0]);
+    assert_eq!(tokens[&request_ids[0]], expected[1]);
+}
+
+#[test]
+fn an_idle_engine_generates_the_queued_requests() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = [engine.encode("t5 t9 t17"), engine.encode("t8 t2")];
+    let (request_ids, responses) = engine.enqueue(&prompts, MAX_TOKENS, |engine| {
+        engine.generate_once().unwrap()
+    });
+    for request_id in &request_ids {
+        assert_eq!(responses[request_id].1.completion_tokens, MAX_TOKENS);
+    }
+    assert!(!engine.has_unfinished_requests());
+}
+
+#[test]
+fn the_engine_does_not_sleep_over_queued_requests() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode("t5 t9 t17");
+    let (_, slept) = engine.enqueue(&[prompt], MAX_TOKENS, |engine| {
+        engine.sleep(SleepLevel::Cache)
+    });
+    assert!(slept.is_err());
+}
+
+fn complete(data: &Arc<OpenAIServerData>, max_tokens: usize) -> ChatResponder {
+    let request = json!({
+        "model": "llama",
+        "prompt": "t5 t9 t17",
+        "temperature": 0.0,
+        "max_tokens": max_tokens,
+        "ignore_eos": true,
+    });
+    let request = serde_json::from_value(request).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        completions(State(data.clone()), HeaderMap::new(), Ok(Json(request))).await
+    })
+}
+
+#[test]
+fn requests_are_admitted_while_others_generate() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(None));
+    let long = {
+        let data = data.clone();
+        std::thread::spawn(move || complete(&data, 48))
+    };
+    std::thread::sleep(std::time::Duration::from_millis(250));
+    // Submitted while the long request runs.
+    let ChatResponder::TextCompletion(short, _) = complete(&data, 2) else {
+        panic!("the request submitted during generation failed");
+    };
+    assert_eq!(short.usage.completion_tokens, 2);
+    let ChatResponder::TextCompletion(long, _) = long.join().unwrap() else {
+        panic!("the running request failed");
+    };
+    assert_eq!(long.usage.completion_tokens, 48);
+}
@@ -0,0 +1,65 @@
+use candle_vllm::{detect_model, get_model_architecture, SUPPORTED_ARCHITECTURES};
+
+mod common;
+use common::tiny_model::tiny_llama_dir;
+
+fn write_config(name: &str, config: serde_json::Value) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-architecture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.json"));
+    std::fs::write(&path, config.to_string()).unwrap();
+    path
+}
+
+#[test]
+fn architectures_map_to_models() {
+    for (architecture, name) in SUPPORTED_ARCHITECTURES {
+        let path = write_config(
+            architecture,
+            serde_json::json!({"architectures": [architecture], "vocab_size": 32000}),
+        );
+        let model = get_model_architecture(&path).unwrap();
+        assert_eq!(model.to_string(), *name, "{architecture}");
+    }
+
+    // Llama 3 is told apart by its vocabulary.
+    let path = write_config(
+        "llama3",
+        serde_json::json!({"architectures": ["LlamaForCausalLM"], "vocab_size": 128256}),
+    );
+    assert_eq!(get_model_architecture(&path).unwrap().to_string(), "llama3");
+
+    // The first supported one is taken.
+    let path = write_config(
+        "several",
+        serde_json::json!({"architectures": ["MambaForCausalLM", "MistralForCausalLM"]}),
+    );
+    assert_eq!(
+        get_model_architecture(&path).unwrap().to_string(),
+        "mistral"
+    );
+}
+
+#[test]
+fn unknown_architectures_list_the_supported_ones() {
+    let path = write_config(
+        "mamba",
+        serde_json::json!({"architectures": ["MambaForCausalLM"]}),
+    );
+    let err = get_model_architecture(&path).err().unwrap().to_string();
+    assert!(err.contains("MambaForCausalLM"), "{err}");
+    for (architecture, _) in SUPPORTED_ARCHITECTURES {
+        assert!(err.contains(architecture), "{err}");
+    }
+
+    let path = write_config("none", serde_json::json!({"vocab_size": 32000}));
+    assert!(get_model_architecture(&path).is_err());
+}
+
+#[test]
+fn model_is_detected_from_the_weight_path() {
+    let weight_path = format!("{}/", tiny_llama_dir().display());
+    let model = detect_model(None, Some(&weight_path), None, None).unwrap();
+    assert_eq!(model.to_string(), "llama");
+    assert!(detect_model(None, None, None, None).is_err());
+}
@@ -0,0 +1,92 @@
+use candle_core::DType;
+use candle_vllm::{
+    openai::sampling_params::Logprobs,
+    scheduler::{
+        block_engine::BlockEngine,
+        cache_engine::{AttentionSinks, CacheConfig},
+    },
+};
+
+mod common;
+use common::{cache_config, sequence_group, tiny_model::TinyEngine};
+
+const SINKS: AttentionSinks = AttentionSinks {
+    sink_blocks: 1,
+    window_blocks: 2,
+};
+
+#[test]
+fn rejects_windows_without_room() {
+    let with_sinks = |attention_sinks| CacheConfig {
+        attention_sinks: Some(attention_sinks),
+        ..cache_config(8)
+    };
+    assert!(with_sinks(SINKS).verify_args().is_ok());
+    assert!(with_sinks(AttentionSinks {
+        sink_blocks: 1,
+        window_blocks: 0,
+    })
+    .verify_args()
+    .is_err());
+    // The 64 blocks of the cache must leave room for the block being filled.
+    assert!(with_sinks(AttentionSinks {
+        sink_blocks: 4,
+        window_blocks: 60,
+    })
+    .verify_args()
+    .is_err());
+}
+
+#[test]
+fn evicts_the_blocks_between_sinks_and_window() {
+    let block_size = 8;
+    let group = sequence_group(0, 10, block_size);
+    let seq = group.get_seqs()[&0].clone();
+    let mut block_engine = BlockEngine::new(block_size, 16, 16).with_attention_sinks(SINKS);
+    block_engine.allocate(&group);
+    let sink_block = block_engine.get_block_table_ids(0).unwrap()[0];
+
+    for token in 10..200 {
+        seq.deref_mut().add_token(Logprobs {
+            token,
+            logprob: 0.,
+            bytes: String::new(),
+            top_logprobs: vec![],
+        });
+        block_engine.append_token_slot_to_seq(&seq);
+
+        let table = block_engine.get_block_table_ids(0).unwrap();
+        assert!(table.len() <= 4, "{} blocks at token {token}", table.len());
+        assert_eq!(table[0], sink_block);
+        let seq = seq.deref();
+        let cache_index = seq.get_cache_index(token);
+        assert!(cache_index < table.len() * block_size);
+        assert_eq!(cache_index, token - seq.get_num_evicted_tokens());
+        assert_eq!(seq.get_position(token), cache_index);
+    }
+    assert!(block_engine.get_num_free_gpu_blocks() >= 12);
+    // The sinks are never evicted, the tokens right after them are.
+    assert_eq!(seq.deref().get_cache_index(3), 3);
+    assert_eq!(seq.deref().get_num_evicted_tokens() % block_size, 0);
+}
+
+#[test]
+fn generates_past_the_capacity_of_the_cache() {
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        dtype: DType::F32,
+        num_gpu_blocks: Some(4),
+        attention_sinks: Some(SINKS),
+        ..cache_config(8)
+    });
+    let a = engine.encode("t5 t9 t17 t33 t40 t41 t7 t8 t12 t60");
+    // 4 blocks of 8 tokens hold 32, the tiny llama has 256 positions.
+    let generated = engine.generate(&[a], 300);
+    assert_eq!(generated[0].len(), 301);
+    // Nothing is evicted before the window is full, the output is the greedy one so far.
+    assert_eq!(
+        generated[0][..15],
+        [45, 3, 46, 18, 49, 41, 23, 44, 32, 32, 23, 44, 44, 23, 44]
+    );
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+}
@@ -0,0 +1,116 @@
+use candle_core::{DType, Device};
+use candle_vllm::openai::{
+    audio_processor::{AudioProcessor, N_FRAMES, N_SAMPLES, SAMPLE_RATE},
+    transcription::{
+        format_srt, format_vtt, segments_text, TranscriptionFormat, TranscriptionSegment,
+    },
+};
+
+/// A WAV file of 16-bit PCM `samples`, interleaved over `channels`.
+fn wav(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<u8> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut bytes = Vec::new();
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data_len).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend((channels * 2).to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(data_len.to_le_bytes());
+    for sample in samples {
+        bytes.extend(sample.to_le_bytes());
+    }
+    bytes
+}
+
+fn segment(id: usize, start: f64, end: f64, text: &str) -> TranscriptionSegment {
+    TranscriptionSegment {
+        id,
+        start,
+        end,
+        text: text.to_string(),
+    }
+}
+
+#[test]
+fn wav_is_mixed_down_and_resampled() {
+    // One second of stereo at 32 kHz, the channels cancel out but for a constant offset.
+    let samples = (0..32000)
+        .flat_map(|i| {
+            let x = if i % 2 == 0 { 8192 } else { -8192 };
+            [x + 16384, -x + 16384]
+        })
+        .collect::<Vec<i16>>();
+    let decoded = AudioProcessor::decode_wav(&wav(&samples, 2, 32000)).unwrap();
+    assert_eq!(decoded.len(), SAMPLE_RATE);
+    assert!(decoded.iter().all(|&x| (x - 0.5).abs() < 1e-4));
+}
+
+#[test]
+fn other_audio_formats_are_rejected() {
+    let err = AudioProcessor::decode_wav(b"ID3\x04\0\0\0\0\0\0mp3 frames").unwrap_err();
+    assert!(err.to_string().contains("Only WAV"));
+}
+
+#[test]
+fn audio_is_cut_in_windows_of_30_seconds() {
+    let samples = vec![0.; N_SAMPLES * 2 + 100];
+    let lens = AudioProcessor::chunks(&samples)
+        .map(|chunk| chunk.len())
+        .collect::<Vec<_>>();
+    assert_eq!(lens, [N_SAMPLES, N_SAMPLES, 100]);
+}
+
+#[test]
+fn spectrogram_is_padded_to_a_window() {
+    let processor = AudioProcessor::new(80);
+    let tone = (0..SAMPLE_RATE)
+        .map(|i| (2. * std::f32::consts::PI * 440. * i as f32 / SAMPLE_RATE as f32).sin())
+        .collect::<Vec<_>>();
+    let mel = processor
+        .preprocess(&tone, DType::F32, &Device::Cpu)
+        .unwrap();
+    assert_eq!(mel.dims(), [1, 80, N_FRAMES]);
+    let mel = mel.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+    let max = mel.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let min = mel.iter().copied().fold(f32::INFINITY, f32::min);
+    // The silence after the tone is clamped to 8 (rescaled by 4) below its loudest frequency.
+    assert!((max - min - 2.).abs() < 1e-4);
+}
+
+#[test]
+fn segments_are_formatted_as_subtitles() {
+    let segments = [
+        segment(0, 0., 30., " Hello there. "),
+        segment(1, 30., 3725.5, "General Kenobi."),
+    ];
+    assert_eq!(segments_text(&segments), "Hello there. General Kenobi.");
+    assert_eq!(
+        format_srt(&segments),
+        "1\n00:00:00,000 --> 00:00:30,000\nHello there.\n\n\
+         2\n00:00:30,000 --> 01:02:05,500\nGeneral Kenobi.\n\n"
+    );
+    assert_eq!(
+        format_vtt(&segments),
+        "WEBVTT\n\n00:00:00.000 --> 00:00:30.000\nHello there.\n\n\
+         00:00:30.000 --> 01:02:05.500\nGeneral Kenobi.\n\n"
+    );
+}
+
+#[test]
+fn response_formats_are_parsed() {
+    assert_eq!(
+        TranscriptionFormat::parse("verbose_json").unwrap(),
+        TranscriptionFormat::VerboseJson
+    );
+    assert_eq!(
+        TranscriptionFormat::parse("srt").unwrap(),
+        TranscriptionFormat::Srt
+    );
+    assert!(TranscriptionFormat::parse("mp3").is_err());
+}
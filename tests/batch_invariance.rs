@@ -0,0 +1,45 @@
+mod common;
+use common::tiny_model::TinyEngine;
+
+const SHORT_PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const LONG_PROMPT: &str = "t11 t3 t50 t22 t19 t44 t6 t23 t31 t58 t13 t27 t36 t48 t52 t9 t61 t20";
+const MAX_TOKENS: usize = 16;
+
+fn engine() -> TinyEngine {
+    TinyEngine::with_batch_invariance(TinyEngine::cache_config(8)).unwrap()
+}
+
+#[test]
+fn prompts_of_different_lengths_match_their_output_alone() {
+    let mut engine = engine();
+    let short = engine.encode(SHORT_PROMPT);
+    let long = engine.encode(LONG_PROMPT);
+    let short_alone = engine
+        .generate_logprobs(std::slice::from_ref(&short), MAX_TOKENS)
+        .unwrap();
+    let long_alone = engine
+        .generate_logprobs(std::slice::from_ref(&long), MAX_TOKENS)
+        .unwrap();
+    // The short prompt would be padded to the long one in a batched prefill, the logprobs are
+    // compared exactly.
+    let batched = engine
+        .generate_logprobs(&[long, short], MAX_TOKENS)
+        .unwrap();
+    assert_eq!(batched, [long_alone[0].clone(), short_alone[0].clone()]);
+}
+
+#[test]
+fn output_does_not_depend_on_the_batch_size() {
+    let mut engine = engine();
+    let prompt = engine.encode(SHORT_PROMPT);
+    let alone = engine
+        .generate_logprobs(std::slice::from_ref(&prompt), MAX_TOKENS)
+        .unwrap();
+    let batched = engine
+        .generate_logprobs(&[prompt.clone(), prompt.clone(), prompt], MAX_TOKENS)
+        .unwrap();
+    assert_eq!(
+        batched,
+        [alone[0].clone(), alone[0].clone(), alone[0].clone()]
+    );
+}
@@ -0,0 +1,240 @@
+use axum::{
+    body::Bytes,
+    extract::{Json, Path, Query, State},
+    response::IntoResponse,
+};
+use candle_vllm::{
+    openai::{
+        batches::{parse_batch_input, BatchStatus, BatchStore},
+        openai_server::{create_batch, get_batch, get_file_content, upload_file},
+        requests::{CreateBatchRequest, FileUploadQuery},
+        responses::BatchResponder,
+        OpenAIServerData,
+    },
+    scheduler::{Scheduler, SchedulerConfig},
+};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::{cache_config, sequence_group, tiny_model::TinyEngine};
+
+const ENDPOINT: &str = "/v1/chat/completions";
+
+fn input_line(custom_id: &str, body: Value) -> String {
+    json!({"custom_id": custom_id, "method": "POST", "url": ENDPOINT, "body": body}).to_string()
+}
+
+fn chat(prompt: &str) -> Value {
+    json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": prompt}],
+        "max_tokens": 4,
+        "temperature": 0.0,
+    })
+}
+
+/// Status and body of the response of `responder`, parsed as JSON lines for file contents.
+async fn respond(responder: BatchResponder) -> (u16, Vec<Value>) {
+    let response = responder.into_response();
+    let status = response.status().as_u16();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let lines = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    (status, lines)
+}
+
+#[test]
+fn invalid_lines_are_reported() {
+    let content = [
+        input_line("a", chat("t5")),
+        "{not json".to_string(),
+        json!({"custom_id": "b", "method": "GET", "url": ENDPOINT, "body": {}}).to_string(),
+        json!({"custom_id": "c", "method": "POST", "url": "/v1/completions", "body": {}})
+            .to_string(),
+        input_line("a", chat("t9")),
+    ]
+    .join("\n");
+    let errors = parse_batch_input(content.as_bytes(), ENDPOINT).unwrap_err();
+    let codes = errors
+        .iter()
+        .map(|error| (error.code.as_str(), error.line))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        [
+            ("invalid_json_line", Some(2)),
+            ("invalid_method", Some(3)),
+            ("invalid_url", Some(4)),
+            ("duplicate_custom_id", Some(5)),
+        ]
+    );
+
+    let inputs = parse_batch_input(content.lines().next().unwrap().as_bytes(), ENDPOINT).unwrap();
+    assert_eq!(inputs[0].custom_id, "a");
+    assert_eq!(
+        parse_batch_input(b"\n", ENDPOINT).unwrap_err()[0].code,
+        "empty_file"
+    );
+}
+
+#[test]
+fn only_running_batches_are_cancelled() {
+    let store = BatchStore::new(1);
+    let file = store.add_file("input.jsonl".to_string(), "batch".to_string(), Bytes::new());
+    let batch = store.add_batch(file.id, ENDPOINT.to_string(), None);
+    let cancelled = store.cancel_batch(&batch.id).unwrap();
+    assert_eq!(cancelled.status, BatchStatus::Cancelling);
+    assert!(cancelled.cancelling_at.is_some());
+
+    store.update_batch(&batch.id, |batch| batch.status = BatchStatus::Completed);
+    assert_eq!(
+        store.cancel_batch(&batch.id).unwrap().status,
+        BatchStatus::Completed
+    );
+    assert!(store.cancel_batch("batch_missing").is_none());
+}
+
+#[test]
+fn low_priority_groups_wait_behind_the_others() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 8,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    for id in 0..4 {
+        let mut group = sequence_group(id, 8, block_size);
+        group.sampling_params.low_priority = id < 2;
+        scheduler.add_sequence(group);
+    }
+    let waiting = scheduler
+        .snapshot()
+        .waiting
+        .iter()
+        .map(|group| group.request_id.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(waiting, ["test-2", "test-3", "test-0", "test-1"]);
+}
+
+#[test]
+fn batch_writes_the_responses_of_its_requests() {
+    let engine = TinyEngine::new(16);
+    let data = Arc::new(engine.server_data(None));
+    let content = [
+        input_line("first", chat("t5 t9 t17")),
+        input_line("second", chat("t11 t3 t50")),
+        // `messages` is missing, the request fails on its own.
+        input_line("broken", json!({"model": "llama"})),
+    ]
+    .join("\n");
+    Runtime::new().unwrap().block_on(async {
+        let (status, file) = respond(
+            upload_file(
+                State(data.clone()),
+                Query(FileUploadQuery {
+                    purpose: "batch".to_string(),
+                    filename: None,
+                }),
+                Bytes::from(content),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, 200);
+        let request = CreateBatchRequest {
+            input_file_id: file[0]["id"].as_str().unwrap().to_string(),
+            endpoint: ENDPOINT.to_string(),
+            completion_window: "24h".to_string(),
+            metadata: None,
+        };
+        let (_, batch) = respond(create_batch(State(data.clone()), Json(request)).await).await;
+        let batch_id = batch[0]["id"].as_str().unwrap().to_string();
+
+        let batch = loop {
+            let (_, batch) =
+                respond(get_batch(State(data.clone()), Path(batch_id.clone())).await).await;
+            if batch[0]["status"] == "completed" {
+                break batch[0].clone();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(
+            batch["request_counts"],
+            json!({"total": 3, "completed": 2, "failed": 1})
+        );
+
+        let file_id = batch["output_file_id"].as_str().unwrap().to_string();
+        let (_, outputs) =
+            respond(get_file_content(State(data.clone()), Path(file_id)).await).await;
+        let custom_ids = outputs
+            .iter()
+            .map(|output| output["custom_id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(custom_ids, ["first", "second"]);
+        for output in &outputs {
+            let response = &output["response"];
+            assert_eq!(response["status_code"], 200);
+            assert_eq!(response["request_id"], response["body"]["id"]);
+            assert_eq!(response["body"]["object"], "chat.completion");
+        }
+
+        let file_id = batch["error_file_id"].as_str().unwrap().to_string();
+        let (_, errors) = respond(get_file_content(State(data.clone()), Path(file_id)).await).await;
+        assert_eq!(errors[0]["custom_id"], "broken");
+        assert_eq!(errors[0]["response"]["status_code"], 400);
+    });
+}
+
+#[test]
+fn batch_of_an_invalid_file_fails() {
+    let engine = TinyEngine::new(16);
+    let data: Arc<OpenAIServerData> = Arc::new(engine.server_data(None));
+    Runtime::new().unwrap().block_on(async {
+        let file = data.batches.add_file(
+            "input.jsonl".to_string(),
+            "batch".to_string(),
+            Bytes::from("{not json"),
+        );
+        let request = CreateBatchRequest {
+            input_file_id: file.id,
+            endpoint: ENDPOINT.to_string(),
+            completion_window: "24h".to_string(),
+            metadata: None,
+        };
+        let (_, batch) = respond(create_batch(State(data.clone()), Json(request)).await).await;
+        let batch_id = batch[0]["id"].as_str().unwrap();
+        let batch = loop {
+            let batch = data.batches.batch(batch_id).unwrap();
+            if batch.status != BatchStatus::Validating {
+                break batch;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(batch.status, BatchStatus::Failed);
+        assert_eq!(batch.errors.unwrap().data[0].code, "invalid_json_line");
+
+        let request = CreateBatchRequest {
+            input_file_id: "file-missing".to_string(),
+            endpoint: "/v1/embeddings".to_string(),
+            completion_window: "24h".to_string(),
+            metadata: None,
+        };
+        let (status, _) = respond(create_batch(State(data.clone()), Json(request)).await).await;
+        assert_eq!(status, 400);
+    });
+}
@@ -0,0 +1,63 @@
+use candle_vllm::openai::{
+    requests::CompletionRequest,
+    sampling_params::{EarlyStoppingCondition, SamplingParams},
+};
+
+mod common;
+use common::sampling_params;
+
+fn beam_params(length_penalty: f32) -> SamplingParams {
+    SamplingParams {
+        best_of: 2,
+        use_beam_search: true,
+        length_penalty,
+        ..sampling_params()
+    }
+}
+
+#[test]
+fn parses_length_penalty() {
+    let request: CompletionRequest =
+        serde_json::from_str(r#"{"model": "llama", "prompt": "a", "length_penalty": 0.5}"#)
+            .unwrap();
+    assert_eq!(request.length_penalty, Some(0.5));
+}
+
+#[test]
+fn beams_are_scored_by_their_length() {
+    let params = beam_params(1.0);
+    assert_eq!(params.beam_search_score(-8.0, 4), -2.0);
+    // A positive penalty favors the longer beam of the same mean logprob, a negative one the
+    // shorter.
+    let longer = beam_params(2.0);
+    assert!(longer.beam_search_score(-8.0, 8) > longer.beam_search_score(-4.0, 4));
+    let shorter = beam_params(-1.0);
+    assert!(shorter.beam_search_score(-8.0, 8) < shorter.beam_search_score(-4.0, 4));
+    assert_eq!(beam_params(0.0).beam_search_score(-8.0, 4), -8.0);
+
+    let new = |length_penalty| {
+        SamplingParams::new(
+            1,
+            Some(2),
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+            -1,
+            true,
+            length_penalty,
+            EarlyStoppingCondition::UnlikelyBetterCandidates,
+            None,
+            vec![],
+            false,
+            16,
+            None,
+            None,
+            true,
+        )
+    };
+    assert!(new(2.0).is_ok());
+    assert!(new(f32::NAN).is_err());
+    assert!(new(f32::INFINITY).is_err());
+}
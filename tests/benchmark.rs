@@ -0,0 +1,85 @@
+use candle_vllm::benchmark::{
+    parse_sharegpt, poisson_arrivals, BenchmarkReport, Percentiles, RequestMetrics,
+};
+use std::time::Duration;
+
+#[test]
+fn sharegpt_keeps_first_two_turns() {
+    let data = r#"[
+        {"id": "a", "conversations": [
+            {"from": "human", "value": "What is paged attention?"},
+            {"from": "gpt", "value": "A way to store the KV cache in blocks."},
+            {"from": "human", "value": "Thanks"}
+        ]},
+        {"id": "b", "conversations": [{"from": "human", "value": "Unanswered"}]},
+        {"id": "c", "conversations": []}
+    ]"#;
+    let pairs = parse_sharegpt(data).unwrap();
+    assert_eq!(
+        pairs,
+        vec![(
+            "What is paged attention?".to_string(),
+            "A way to store the KV cache in blocks.".to_string()
+        )]
+    );
+    assert!(parse_sharegpt("{}").is_err());
+}
+
+#[test]
+fn poisson_arrivals_follow_request_rate() {
+    assert!(poisson_arrivals(8, f64::INFINITY, 0)
+        .iter()
+        .all(|arrival| arrival.is_zero()));
+
+    let arrivals = poisson_arrivals(10_000, 4.0, 0);
+    assert!(arrivals.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(arrivals, poisson_arrivals(10_000, 4.0, 0));
+    // 10000 requests at 4 req/s should take about 2500 seconds.
+    let span = arrivals.last().unwrap().as_secs_f64();
+    assert!((2300.0..2700.0).contains(&span), "{span}");
+}
+
+#[test]
+fn percentiles_interpolate_between_ranks() {
+    let values = (1..=101).map(f64::from).collect::<Vec<_>>();
+    let p = Percentiles::new(&values);
+    assert_eq!(p.mean, 51.0);
+    assert_eq!(p.p50, 51.0);
+    assert_eq!(p.p90, 91.0);
+    assert_eq!(p.p99, 100.0);
+
+    let p = Percentiles::new(&[4.0, 1.0]);
+    assert_eq!(p.p50, 2.5);
+    assert_eq!(Percentiles::new(&[]), Percentiles::default());
+}
+
+#[test]
+fn report_aggregates_request_metrics() {
+    let metrics = vec![
+        RequestMetrics {
+            prompt_len: 10,
+            output_len: 11,
+            ttft: Duration::from_millis(100),
+            latency: Duration::from_millis(600),
+        },
+        RequestMetrics {
+            prompt_len: 20,
+            output_len: 1,
+            ttft: Duration::from_millis(300),
+            latency: Duration::from_millis(300),
+        },
+    ];
+    assert_eq!(metrics[0].tpot(), Some(Duration::from_millis(50)));
+    assert_eq!(metrics[1].tpot(), None);
+
+    let report = BenchmarkReport::new(&metrics, Duration::from_secs(2), &[0.25, 0.75]);
+    assert_eq!(report.prompt_tokens, 30);
+    assert_eq!(report.output_tokens, 12);
+    assert_eq!(report.request_throughput(), 1.0);
+    assert_eq!(report.token_throughput(), 21.0);
+    assert_eq!(report.ttft_ms.mean, 200.0);
+    // Single-token requests have no TPOT.
+    assert_eq!(report.tpot_ms.p99, 50.0);
+    assert_eq!(report.mean_kv_usage, 0.5);
+    assert_eq!(report.peak_kv_usage, 0.75);
+}
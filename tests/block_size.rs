@@ -0,0 +1,82 @@
+use candle_vllm::scheduler::{
+    block_engine::{attended_blocks, compute_slot, BlockEngine},
+    cache_engine::SUPPORTED_BLOCK_SIZES,
+};
+use std::collections::HashSet;
+
+mod common;
+use common::{cache_config, sequence_group};
+
+#[test]
+fn rejects_block_sizes_without_kernels() {
+    for block_size in SUPPORTED_BLOCK_SIZES {
+        assert!(cache_config(block_size).verify_args().is_ok());
+    }
+    for block_size in [0, 1, 12, 64, 128] {
+        assert!(cache_config(block_size).verify_args().is_err());
+    }
+}
+
+#[test]
+fn slot_mapping_is_consistent_across_block_sizes() {
+    let prompt_len = 75;
+    for block_size in SUPPORTED_BLOCK_SIZES {
+        let group = sequence_group(0, prompt_len, block_size);
+        let mut block_engine = BlockEngine::new(block_size, 64, 64);
+        block_engine.allocate(&group);
+
+        let table = block_engine.block_tables[&0]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect::<Vec<_>>();
+        assert!(table.len() * block_size >= prompt_len);
+
+        let mut slots = HashSet::new();
+        for position in 0..prompt_len {
+            let slot = compute_slot(&table, position, block_size).unwrap();
+            assert!(slot < 64 * block_size);
+            // Every token maps back to its own logical position, whatever the block size.
+            let logical_block = table.iter().position(|b| *b == slot / block_size).unwrap();
+            assert_eq!(logical_block * block_size + slot % block_size, position);
+            assert!(slots.insert(slot));
+        }
+        assert!(compute_slot(&table, table.len() * block_size, block_size).is_none());
+    }
+}
+
+#[test]
+fn sliding_window_attends_written_slots_only() {
+    let block_size = 16;
+    let table = [7, 3, 9, 12, 5];
+    for window in [32, 40, 45, 64, 100] {
+        for cache_index in 0..table.len() * block_size {
+            let (blocks, context_len) =
+                attended_blocks(&table, cache_index, block_size, Some(window)).unwrap();
+            // The kernels read `context_len` slots from the first one of `blocks`, the last one
+            // read is the token itself.
+            let first_position = cache_index + 1 - context_len;
+            assert_eq!(first_position % block_size, 0);
+            assert_eq!(blocks[0], table[first_position / block_size]);
+            assert_eq!((context_len - 1) / block_size + 1, blocks.len());
+            // The whole window and at most a block of older tokens.
+            assert!(context_len >= window.min(cache_index + 1));
+            assert!(context_len < window + block_size);
+        }
+    }
+}
+
+#[test]
+fn attended_blocks_ignore_the_slots_ahead() {
+    let block_size = 16;
+    let table = [7, 3, 9, 12];
+    // Blocks allocated ahead, e.g. for speculative tokens, are not attended.
+    let (blocks, context_len) = attended_blocks(&table, 20, block_size, None).unwrap();
+    assert_eq!((blocks, context_len), (&table[..2], 21));
+    // An aligned window past its start leaves the oldest blocks out.
+    let (blocks, context_len) = attended_blocks(&table, 47, block_size, Some(32)).unwrap();
+    assert_eq!((blocks, context_len), (&table[1..3], 32));
+    // A sequence shorter than the window attends all of it.
+    let (blocks, context_len) = attended_blocks(&table, 5, block_size, Some(32)).unwrap();
+    assert_eq!((blocks, context_len), (&table[..1], 6));
+    assert!(attended_blocks(&table, table.len() * block_size, block_size, Some(32)).is_none());
+}
@@ -0,0 +1,132 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::paged_attention::block_sparse::{
+    block_sparse_attention, BlockSparseConfig, BlockSparsePattern,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+mod common;
+use common::tiny_model::random_tensor;
+
+const NUM_HEADS: usize = 4;
+const NUM_KV_HEADS: usize = 2;
+const HEAD_SIZE: usize = 8;
+
+fn random_heads(rng: &mut StdRng, num_heads: usize, len: usize) -> Tensor {
+    random_tensor(rng, (num_heads, len * HEAD_SIZE), 1.0)
+        .reshape((num_heads, len, HEAD_SIZE))
+        .unwrap()
+}
+
+fn config(homo_head_pattern: bool) -> BlockSparseConfig {
+    BlockSparseConfig {
+        block_size: 2,
+        num_local_blocks: 2,
+        vert_stride: 3,
+        homo_head_pattern,
+        dense_every_n_layers: Some(2),
+    }
+}
+
+fn pattern(homo_head_pattern: bool) -> BlockSparsePattern {
+    config(homo_head_pattern).layer_patterns(1, NUM_HEADS)[0]
+        .clone()
+        .unwrap()
+}
+
+#[test]
+fn every_n_th_layer_is_dense() {
+    let patterns = config(false).layer_patterns(4, NUM_HEADS);
+    assert!(patterns[0].is_some() && patterns[2].is_some());
+    assert!(patterns[1].is_none() && patterns[3].is_none());
+    let config = BlockSparseConfig {
+        vert_stride: 0,
+        ..config(false)
+    };
+    assert!(config.verify().is_err());
+}
+
+#[test]
+fn heads_attend_the_local_and_vertical_blocks() {
+    let pattern = pattern(true);
+    // The block of the query and the one before it.
+    assert!(pattern.attends(0, 9, 9) && pattern.attends(0, 9, 6));
+    assert!(!pattern.attends(0, 9, 10));
+    // Every third block, the third one of keys 4 and 5 among them.
+    assert!(pattern.attends(0, 9, 4) && !pattern.attends(0, 9, 2));
+    assert!(!pattern.attends(0, 9, 0));
+
+    // The vertical blocks shift from head to head.
+    let pattern = self::pattern(false);
+    assert!(pattern.attends(0, 9, 4) && !pattern.attends(1, 9, 4));
+    assert!(pattern.attends(1, 9, 2));
+}
+
+/// Attention over the keys `pattern` attends, masking the scores of the others.
+fn dense_attention(
+    query: &Tensor,
+    key: &Tensor,
+    value: &Tensor,
+    query_start: usize,
+    pattern: &BlockSparsePattern,
+    scale: f32,
+) -> Tensor {
+    let (num_heads, num_queries, _) = query.dims3().unwrap();
+    let num_keys = key.dim(1).unwrap();
+    let queries_per_kv = num_heads / key.dim(0).unwrap();
+    let heads = (0..num_heads)
+        .map(|head| {
+            let mask = (0..num_queries)
+                .flat_map(|i| {
+                    (0..num_keys).map(move |j| match pattern.attends(head, query_start + i, j) {
+                        true => 0f32,
+                        false => f32::NEG_INFINITY,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mask = Tensor::from_vec(mask, (num_queries, num_keys), &Device::Cpu).unwrap();
+            let key = key.get(head / queries_per_kv).unwrap();
+            let value = value.get(head / queries_per_kv).unwrap();
+            let att = (query.get(head).unwrap().matmul(&key.t().unwrap()).unwrap() * scale as f64)
+                .unwrap();
+            let att = candle_nn::ops::softmax_last_dim(&(att + mask).unwrap()).unwrap();
+            att.matmul(&value).unwrap()
+        })
+        .collect::<Vec<_>>();
+    Tensor::stack(&heads, 0).unwrap()
+}
+
+fn assert_close(a: &Tensor, b: &Tensor) {
+    let diff = (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .max_keepdim(2)
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap();
+    assert!(diff < 1e-5, "outputs differ by {diff}");
+}
+
+#[test]
+fn sparse_attention_is_dense_attention_of_the_attended_keys() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let num_keys = 15;
+    let key = random_heads(&mut rng, NUM_KV_HEADS, num_keys);
+    let value = random_heads(&mut rng, NUM_KV_HEADS, num_keys);
+    let scale = 1. / (HEAD_SIZE as f32).sqrt();
+    for homo_head_pattern in [true, false] {
+        let pattern = pattern(homo_head_pattern);
+        // A whole prompt, the last queries of a prompt and a single decoded token.
+        for query_start in [0, 5, num_keys - 1] {
+            let query = random_heads(&mut rng, NUM_HEADS, num_keys - query_start);
+            let output =
+                block_sparse_attention(&query, &key, &value, query_start, &pattern, scale).unwrap();
+            let expected = dense_attention(&query, &key, &value, query_start, &pattern, scale);
+            assert_close(&output, &expected);
+        }
+    }
+}
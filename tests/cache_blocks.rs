@@ -0,0 +1,167 @@
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_vllm::backend::{copy_blocks, swap_blocks, CacheOpError};
+use std::collections::HashMap;
+
+// A value cache of 4 blocks, each holding 2 heads of size 3 for a block size of 2.
+fn cache(offset: f32) -> Tensor {
+    Tensor::arange(offset, offset + 48., &Device::Cpu)
+        .unwrap()
+        .reshape((4, 2, 3, 2))
+        .unwrap()
+}
+
+fn block(cache: &Tensor, block: usize) -> Vec<f32> {
+    cache
+        .i(block)
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .to_vec1()
+        .unwrap()
+}
+
+#[test]
+fn swap_blocks_copies_whole_blocks() {
+    let src = cache(100.);
+    let mut dst = Tensor::zeros((4, 2, 3, 2), DType::F32, &Device::Cpu).unwrap();
+    swap_blocks(src.clone(), &mut dst, HashMap::from([(3, 0), (1, 2)])).unwrap();
+    assert_eq!(block(&dst, 0), block(&src, 3));
+    assert_eq!(block(&dst, 2), block(&src, 1));
+    assert_eq!(block(&dst, 1), [0.; 12]);
+    assert_eq!(block(&dst, 3), [0.; 12]);
+}
+
+#[test]
+fn copy_blocks_copies_within_each_cache() {
+    let mut key_cache = cache(0.);
+    let mut value_cache = cache(1000.);
+    let (key_before, value_before) = (key_cache.copy().unwrap(), value_cache.copy().unwrap());
+    unsafe {
+        copy_blocks(
+            vec![&mut key_cache],
+            vec![&mut value_cache],
+            HashMap::from([(0, vec![1, 3])]),
+        )
+        .unwrap();
+    }
+    for (cache, before) in [(&key_cache, &key_before), (&value_cache, &value_before)] {
+        assert_eq!(block(cache, 1), block(before, 0));
+        assert_eq!(block(cache, 3), block(before, 0));
+        assert_eq!(block(cache, 2), block(before, 2));
+    }
+}
+
+#[test]
+fn block_ops_check_bounds_and_layout() {
+    let src = cache(0.);
+    let mut dst = cache(0.);
+    assert!(swap_blocks(src.clone(), &mut dst, HashMap::from([(4, 0)])).is_err());
+    assert!(swap_blocks(src.clone(), &mut dst, HashMap::from([(0, 4)])).is_err());
+
+    // Blocks of a different size.
+    let mut small = Tensor::zeros((4, 2, 3, 1), DType::F32, &Device::Cpu).unwrap();
+    assert!(swap_blocks(src.clone(), &mut small, HashMap::from([(0, 0)])).is_err());
+
+    // A transposed cache is not contiguous, its blocks are not ranges of the storage.
+    let mut transposed = cache(0.).transpose(0, 1).unwrap();
+    assert!(swap_blocks(src, &mut transposed, HashMap::from([(0, 0)])).is_err());
+}
+
+#[test]
+fn caches_written_in_place_have_to_be_contiguous() {
+    let mut transposed = cache(0.).transpose(1, 2).unwrap();
+    assert!(matches!(
+        swap_blocks(cache(0.), &mut transposed, HashMap::from([(0, 0)])),
+        Err(CacheOpError::NotContiguous { .. })
+    ));
+    // Half of the head size of each block.
+    let mut sliced = cache(0.).narrow(3, 0, 1).unwrap();
+    let error = unsafe {
+        copy_blocks(
+            vec![&mut cache(0.)],
+            vec![&mut sliced],
+            HashMap::from([(0, vec![1])]),
+        )
+    }
+    .unwrap_err();
+    assert!(matches!(error, CacheOpError::NotContiguous { .. }));
+    assert!(error.to_string().contains("[4, 2, 3, 1]"), "{error}");
+}
+
+#[test]
+fn swap_blocks_reads_sources_of_any_layout() {
+    // The blocks of a transposed cache, made contiguous before they are copied.
+    let blocks = cache(100.).transpose(2, 3).unwrap();
+    let mut dst = Tensor::zeros((4, 2, 2, 3), DType::F32, &Device::Cpu).unwrap();
+    swap_blocks(blocks.clone(), &mut dst, HashMap::from([(3, 0), (1, 2)])).unwrap();
+    let contiguous = blocks.contiguous().unwrap();
+    assert_eq!(block(&dst, 0), block(&contiguous, 3));
+    assert_eq!(block(&dst, 2), block(&contiguous, 1));
+
+    // The last 2 blocks of a larger cache, they start past the beginning of its storage.
+    let sliced = cache(100.).narrow(0, 2, 2).unwrap();
+    let mut dst = Tensor::zeros((4, 2, 3, 2), DType::F32, &Device::Cpu).unwrap();
+    swap_blocks(sliced, &mut dst, HashMap::from([(1, 0)])).unwrap();
+    assert_eq!(block(&dst, 0), block(&cache(100.), 3));
+
+    // Out of range of the slice, not of the cache it was sliced from.
+    let sliced = cache(100.).narrow(0, 0, 2).unwrap();
+    assert!(matches!(
+        swap_blocks(sliced, &mut dst, HashMap::from([(2, 0)])),
+        Err(CacheOpError::Blocks(_))
+    ));
+}
+
+#[test]
+fn copy_blocks_checks_the_caches_up_front() {
+    let copy = |mut key_caches: Vec<Tensor>, mut value_caches: Vec<Tensor>, src, dst| unsafe {
+        copy_blocks(
+            key_caches.iter_mut().collect(),
+            value_caches.iter_mut().collect(),
+            HashMap::from([(src, vec![dst])]),
+        )
+    };
+    assert!(matches!(
+        copy(vec![cache(0.), cache(0.)], vec![cache(0.)], 0, 1),
+        Err(CacheOpError::LayerMismatch { keys: 2, values: 1 })
+    ));
+    let half = cache(0.).to_dtype(DType::F16).unwrap();
+    assert!(matches!(
+        copy(vec![cache(0.)], vec![half], 0, 1),
+        Err(CacheOpError::DtypeMismatch {
+            expected: DType::F32,
+            found: DType::F16
+        })
+    ));
+    let ints = cache(0.).to_dtype(DType::U32).unwrap();
+    assert!(matches!(
+        copy(vec![ints.clone()], vec![ints], 0, 1),
+        Err(CacheOpError::UnsupportedDtype(DType::U32))
+    ));
+    assert!(matches!(
+        copy(vec![cache(0.)], vec![cache(0.)], 0, 4),
+        Err(CacheOpError::Blocks(_))
+    ));
+    assert!(copy(vec![], vec![], 0, 4).is_ok());
+}
+
+#[test]
+fn swap_blocks_checks_the_caches_up_front() {
+    let mut dst = cache(0.);
+    let half = cache(0.).to_dtype(DType::F16).unwrap();
+    assert!(matches!(
+        swap_blocks(half, &mut dst, HashMap::from([(0, 0)])),
+        Err(CacheOpError::DtypeMismatch {
+            expected: DType::F32,
+            found: DType::F16
+        })
+    ));
+    let mut ints = cache(0.).to_dtype(DType::U32).unwrap();
+    assert!(matches!(
+        swap_blocks(ints.clone(), &mut ints, HashMap::from([(0, 1)])),
+        Err(CacheOpError::UnsupportedDtype(DType::U32))
+    ));
+    let error = swap_blocks(cache(0.), &mut dst, HashMap::from([(4, 0)])).unwrap_err();
+    assert!(matches!(error, CacheOpError::Blocks(_)));
+    assert!(error.to_string().contains("out of range"), "{error}");
+}
@@ -0,0 +1,74 @@
+#![allow(dead_code)]
+
+pub mod tiny_model;
+
+use candle_core::DType;
+use candle_vllm::{
+    openai::sampling_params::{EarlyStoppingCondition, SamplingParams},
+    scheduler::{
+        cache_engine::CacheConfig,
+        sequence::{_Sequence, Sequence, SequenceGroup},
+    },
+};
+use std::{
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+pub fn cache_config(block_size: usize) -> CacheConfig {
+    CacheConfig {
+        block_size,
+        num_gpu_blocks: Some(64),
+        num_cpu_blocks: Some(64),
+        fully_init: true,
+        dtype: DType::F16,
+        gpu_growth_blocks: None,
+        idle_shrink_after: None,
+        attention_sinks: None,
+        num_state_slots: None,
+        swap_affinity: None,
+    }
+}
+
+pub fn sampling_params() -> SamplingParams {
+    SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        1.0,
+        0.7,
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        vec![],
+        false,
+        16,
+        None,
+        None,
+        true,
+    )
+    .unwrap()
+}
+
+/// A single-sequence group whose sequence id, group id and arrival time are all `id`.
+pub fn sequence_group(id: usize, prompt_len: usize, block_size: usize) -> SequenceGroup {
+    let seq = Arc::new(Sequence(RwLock::new(_Sequence::new(
+        (0..prompt_len).collect(),
+        id,
+        block_size,
+    ))));
+    SequenceGroup::new(
+        &[seq],
+        id as u64,
+        id,
+        format!("test-{id}"),
+        SystemTime::now(),
+        sampling_params(),
+        false,
+        None,
+    )
+}
@@ -0,0 +1,811 @@
+//! A tiny llama checkpoint with seeded random weights, loaded through the regular model loader
+//! and run on CPU by the full engine.
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::{
+    get_model_loader, get_model_paths,
+    openai::{
+        batches::BatchStore,
+        control_vectors::ControlVectorStrength,
+        hooks::{EngineObserver, LogitsProcessor, LogitsProcessors, Moderator},
+        logit_dump::LogitDumpParams,
+        pipelines::{
+            executor::{Executor, LocalExecutor},
+            llm_engine::{LLMEngine, RequestOutput, SleepLevel},
+            weights::DEFAULT_WEIGHT_BUFFER_MEM,
+            worker::{Worker, WorkerConfig},
+        },
+        responses::{APIError, ChatCompletionChunk},
+        sampling_params::{
+            EarlyStoppingCondition, SamplingParams, SamplingStage, MAX_TOP_LOGPROBS,
+        },
+        streaming::ChatResponse,
+        tokenizer_pool::TokenizerPool,
+        OpenAIServerData, PipelineConfig,
+    },
+    scheduler::{
+        cache_engine::CacheConfig,
+        kv_transfer::SequenceKV,
+        prompt_lookup::PromptLookupConfig,
+        request_log::{AcceptedRequest, RecoveredRequest, RequestLog},
+        sequence::SequenceGroupMetrics,
+        SchedulerConfig, SchedulerSnapshot,
+    },
+    ModelSelected,
+};
+use flume::Receiver;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+use tokenizers::Encoding;
+use tokio::{
+    runtime::Runtime,
+    sync::{Mutex, Notify},
+};
+
+pub const VOCAB_SIZE: usize = 64;
+pub const HIDDEN_SIZE: usize = 64;
+const INTERMEDIATE_SIZE: usize = 128;
+pub const NUM_LAYERS: usize = 2;
+const NUM_HEADS: usize = 4;
+const NUM_KV_HEADS: usize = 2;
+const SEED: u64 = 42;
+
+pub fn random_tensor(rng: &mut StdRng, shape: (usize, usize), scale: f32) -> Tensor {
+    let data = (0..shape.0 * shape.1)
+        .map(|_| rng.gen_range(-scale..scale))
+        .collect::<Vec<f32>>();
+    Tensor::from_vec(data, shape, &Device::Cpu).unwrap()
+}
+
+/// Word level tokenizer over `special_tokens`, followed by "t{id}" up to the vocab size.
+pub fn write_tokenizer(dir: &Path, special_tokens: &[&str]) {
+    let mut vocab = serde_json::Map::new();
+    for (id, token) in special_tokens.iter().enumerate() {
+        vocab.insert(token.to_string(), id.into());
+    }
+    for id in special_tokens.len()..VOCAB_SIZE {
+        vocab.insert(format!("t{id}"), id.into());
+    }
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<unk>"},
+    });
+    std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+}
+
+fn write_checkpoint(dir: &Path) {
+    std::fs::create_dir_all(dir).unwrap();
+    let config = serde_json::json!({
+        "architectures": ["LlamaForCausalLM"],
+        "hidden_size": HIDDEN_SIZE,
+        "intermediate_size": INTERMEDIATE_SIZE,
+        "vocab_size": VOCAB_SIZE,
+        "num_hidden_layers": NUM_LAYERS,
+        "num_attention_heads": NUM_HEADS,
+        "num_key_value_heads": NUM_KV_HEADS,
+        "rms_norm_eps": 1e-5,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "max_position_embeddings": 256,
+        "torch_dtype": "float32",
+    });
+    std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+    write_tokenizer(dir, &["<unk>", "<s>", "</s>"]);
+
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let head_dim = HIDDEN_SIZE / NUM_HEADS;
+    let kv_size = NUM_KV_HEADS * head_dim;
+    let ones = |n: usize| Tensor::ones(n, DType::F32, &Device::Cpu).unwrap();
+    let mut weights = HashMap::new();
+    weights.insert(
+        "model.embed_tokens.weight".to_string(),
+        random_tensor(&mut rng, (VOCAB_SIZE, HIDDEN_SIZE), 1.0),
+    );
+    for i in 0..NUM_LAYERS {
+        let prefix = format!("model.layers.{i}");
+        let linears = [
+            ("self_attn.q_proj", (HIDDEN_SIZE, HIDDEN_SIZE)),
+            ("self_attn.k_proj", (kv_size, HIDDEN_SIZE)),
+            ("self_attn.v_proj", (kv_size, HIDDEN_SIZE)),
+            ("self_attn.o_proj", (HIDDEN_SIZE, HIDDEN_SIZE)),
+            ("mlp.gate_proj", (INTERMEDIATE_SIZE, HIDDEN_SIZE)),
+            ("mlp.up_proj", (INTERMEDIATE_SIZE, HIDDEN_SIZE)),
+            ("mlp.down_proj", (HIDDEN_SIZE, INTERMEDIATE_SIZE)),
+        ];
+        for (name, shape) in linears {
+            let scale = 2.0 / (shape.1 as f32).sqrt();
+            weights.insert(
+                format!("{prefix}.{name}.weight"),
+                random_tensor(&mut rng, shape, scale),
+            );
+        }
+        weights.insert(
+            format!("{prefix}.input_layernorm.weight"),
+            ones(HIDDEN_SIZE),
+        );
+        weights.insert(
+            format!("{prefix}.post_attention_layernorm.weight"),
+            ones(HIDDEN_SIZE),
+        );
+    }
+    weights.insert("model.norm.weight".to_string(), ones(HIDDEN_SIZE));
+    weights.insert(
+        "lm_head.weight".to_string(),
+        random_tensor(&mut rng, (VOCAB_SIZE, HIDDEN_SIZE), 1.0),
+    );
+    candle_core::safetensors::save(&weights, dir.join("model.safetensors")).unwrap();
+}
+
+/// The checkpoint is written once per test binary.
+pub fn tiny_llama_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir =
+            std::env::temp_dir().join(format!("candle-vllm-tiny-llama-{}", std::process::id()));
+        write_checkpoint(&dir);
+        dir
+    })
+}
+
+fn local_executor(worker: Worker) -> Box<dyn Executor> {
+    Box::new(LocalExecutor::new(worker))
+}
+
+/// The full engine (scheduler, cache engine and pipeline) over the tiny llama, sampling greedily.
+pub struct TinyEngine {
+    runtime: Option<Runtime>,
+    engine: Arc<Mutex<LLMEngine>>,
+    pipeline_config: PipelineConfig,
+    num_requests: usize,
+    /// `timeout` in seconds of the requests submitted from now on.
+    pub timeout: Option<f64>,
+    /// Whether the requests submitted from now on heal the last token of their prompt.
+    pub token_healing: bool,
+    /// Strings the output of the requests submitted from now on is constrained to.
+    pub guided_choice: Option<Vec<String>>,
+    /// Whether the output of the requests submitted from now on is a JSON object.
+    pub json_mode: bool,
+    /// `min_tokens` and `stop_token_ids` of the requests submitted from now on.
+    pub min_tokens: Option<usize>,
+    pub stop_token_ids: Vec<usize>,
+    /// Whether the requests submitted from now on export their KV cache once finished.
+    pub export_kv: bool,
+    /// Logits processors of the requests submitted from now on.
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    /// Conversation the requests submitted from now on are turns of.
+    pub conversation_id: Option<String>,
+    /// Seconds the KV cache of their turns is kept for, see `kv_cache_retention`.
+    pub kv_cache_retention: Option<f64>,
+    /// Negative prompt and guidance scale of the requests submitted from now on.
+    pub negative_prompt: Option<Vec<u32>>,
+    pub guidance_scale: Option<f32>,
+    /// Sampling schedule of the requests submitted from now on.
+    pub sampling_schedule: Option<Vec<SamplingStage>>,
+    /// Alternatives reported with the logprobs of each token of the requests submitted from now
+    /// on, `None` for none of the logprobs.
+    pub top_logprobs: Option<usize>,
+    /// Control vectors the requests submitted from now on steer with.
+    pub control_vectors: Vec<ControlVectorStrength>,
+    /// Logit dump of the requests submitted from now on.
+    pub logit_dump: Option<LogitDumpParams>,
+}
+
+impl TinyEngine {
+    pub fn new(block_size: usize) -> Self {
+        Self::with_cache_config(Self::cache_config(block_size))
+    }
+
+    pub fn cache_config(block_size: usize) -> CacheConfig {
+        CacheConfig {
+            block_size,
+            num_gpu_blocks: Some(64),
+            num_cpu_blocks: Some(64),
+            fully_init: true,
+            dtype: DType::F32,
+            gpu_growth_blocks: None,
+            idle_shrink_after: None,
+            attention_sinks: None,
+            num_state_slots: None,
+            swap_affinity: None,
+        }
+    }
+
+    fn model() -> ModelSelected {
+        ModelSelected::Llama {
+            repeat_last_n: None,
+            temperature: Some(0.),
+            penalty: Some(1.),
+            max_gen_tokens: None,
+        }
+    }
+
+    /// The model is served in the dtype of the cache.
+    pub fn with_cache_config(cache_config: CacheConfig) -> Self {
+        Self::try_with_cache_config(cache_config).unwrap()
+    }
+
+    pub fn try_with_cache_config(cache_config: CacheConfig) -> Result<Self, APIError> {
+        Self::load(Self::model(), tiny_llama_dir(), cache_config)
+    }
+
+    /// The engine over the checkpoint of `model` in `dir`, which has to sample greedily.
+    pub fn load(
+        model: ModelSelected,
+        dir: &Path,
+        cache_config: CacheConfig,
+    ) -> Result<Self, APIError> {
+        Self::load_with_worker_config(model, dir, cache_config, WorkerConfig::default())
+    }
+
+    /// `load` with the worker running the model with `worker_config`.
+    pub fn load_with_worker_config(
+        model: ModelSelected,
+        dir: &Path,
+        cache_config: CacheConfig,
+        worker_config: WorkerConfig,
+    ) -> Result<Self, APIError> {
+        let dtype = cache_config.dtype;
+        Self::load_in(
+            dtype,
+            model,
+            dir,
+            cache_config,
+            worker_config,
+            Self::scheduler_config(),
+            local_executor,
+        )
+    }
+
+    /// The tiny llama run with `worker_config`.
+    pub fn with_worker_config(worker_config: WorkerConfig) -> Result<Self, APIError> {
+        Self::load_with_worker_config(
+            Self::model(),
+            tiny_llama_dir(),
+            Self::cache_config(16),
+            worker_config,
+        )
+    }
+
+    fn scheduler_config() -> SchedulerConfig {
+        SchedulerConfig {
+            max_num_seqs: 16,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        }
+    }
+
+    /// The tiny llama speculating with `prompt_lookup`.
+    pub fn with_prompt_lookup(
+        cache_config: CacheConfig,
+        prompt_lookup: PromptLookupConfig,
+    ) -> Result<Self, APIError> {
+        Self::load_with(
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            SchedulerConfig {
+                prompt_lookup: Some(prompt_lookup),
+                ..Self::scheduler_config()
+            },
+            local_executor,
+        )
+    }
+
+    /// The tiny llama running each sequence through the model on its own.
+    pub fn with_batch_invariance(cache_config: CacheConfig) -> Result<Self, APIError> {
+        Self::load_with(
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            SchedulerConfig {
+                batch_invariant: true,
+                ..Self::scheduler_config()
+            },
+            local_executor,
+        )
+    }
+
+    /// The tiny llama keeping the KV cache of up to `max_sessions` conversations.
+    pub fn with_sessions(cache_config: CacheConfig, max_sessions: usize) -> Self {
+        Self::load_with(
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            SchedulerConfig {
+                max_sessions,
+                ..Self::scheduler_config()
+            },
+            local_executor,
+        )
+        .unwrap()
+    }
+
+    /// The tiny llama moving up to `compaction_blocks` blocks per step to compact its cache.
+    pub fn with_compaction(cache_config: CacheConfig, compaction_blocks: usize) -> Self {
+        Self::load_with(
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            SchedulerConfig {
+                compaction_blocks: Some(compaction_blocks),
+                ..Self::scheduler_config()
+            },
+            local_executor,
+        )
+        .unwrap()
+    }
+
+    /// The tiny llama run by the executor `executor` makes of its worker.
+    pub fn with_executor(
+        cache_config: CacheConfig,
+        executor: impl FnOnce(Worker) -> Box<dyn Executor>,
+    ) -> Self {
+        Self::load_with(
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            Self::scheduler_config(),
+            executor,
+        )
+        .unwrap()
+    }
+
+    /// The tiny llama served in `dtype` over a cache of the dtype of `cache_config`.
+    pub fn with_dtype(dtype: DType, cache_config: CacheConfig) -> Result<Self, APIError> {
+        Self::load_in(
+            dtype,
+            Self::model(),
+            tiny_llama_dir(),
+            cache_config,
+            WorkerConfig::default(),
+            Self::scheduler_config(),
+            local_executor,
+        )
+    }
+
+    fn load_with(
+        model: ModelSelected,
+        dir: &Path,
+        cache_config: CacheConfig,
+        scheduler_config: SchedulerConfig,
+        executor: impl FnOnce(Worker) -> Box<dyn Executor>,
+    ) -> Result<Self, APIError> {
+        let dtype = cache_config.dtype;
+        Self::load_in(
+            dtype,
+            model,
+            dir,
+            cache_config,
+            WorkerConfig::default(),
+            scheduler_config,
+            executor,
+        )
+    }
+
+    fn load_in(
+        dtype: DType,
+        model: ModelSelected,
+        dir: &Path,
+        cache_config: CacheConfig,
+        worker_config: WorkerConfig,
+        scheduler_config: SchedulerConfig,
+        executor: impl FnOnce(Worker) -> Box<dyn Executor>,
+    ) -> Result<Self, APIError> {
+        let (loader, model_id) = get_model_loader(model, None);
+        let weight_path = format!("{}/", dir.display());
+        let paths = get_model_paths(&*loader, model_id, Some(&weight_path), None, None)?;
+        let (pipeline, pipeline_config) =
+            loader.load_model(paths, dtype, Device::Cpu, DEFAULT_WEIGHT_BUFFER_MEM)?;
+
+        let runtime = Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let worker = Worker::new(pipeline, &cache_config, &worker_config)?;
+        let engine = LLMEngine::with_executor(
+            executor(worker),
+            scheduler_config,
+            cache_config,
+            worker_config,
+            Arc::new(Notify::new()),
+            Arc::new(Notify::new()),
+        )?;
+        Ok(Self {
+            runtime: Some(runtime),
+            engine,
+            pipeline_config,
+            num_requests: 0,
+            timeout: None,
+            token_healing: false,
+            guided_choice: None,
+            json_mode: false,
+            min_tokens: None,
+            stop_token_ids: vec![],
+            export_kv: false,
+            logits_processors: vec![],
+            conversation_id: None,
+            kv_cache_retention: None,
+            negative_prompt: None,
+            guidance_scale: None,
+            sampling_schedule: None,
+            top_logprobs: None,
+            control_vectors: vec![],
+            logit_dump: None,
+        })
+    }
+
+    pub fn encode(&self, prompt: &str) -> Encoding {
+        let engine = self.engine.blocking_lock();
+        engine
+            .get_pipeline()
+            .tokenizer()
+            .tokenizer()
+            .encode(prompt, false)
+            .unwrap()
+    }
+
+    /// State of a server over the engine, whose `/admin` endpoints take `admin_token`.
+    pub fn server_data(&self, admin_token: Option<&str>) -> OpenAIServerData {
+        let engine = self.engine.blocking_lock();
+        let tokenizer = engine.get_pipeline().tokenizer().tokenizer().clone();
+        OpenAIServerData {
+            model: self.engine.clone(),
+            pipeline_config: std::sync::RwLock::new(self.pipeline_config.clone()),
+            record_conversation: false,
+            device: Device::Cpu,
+            finish_notify: engine.finish_notify.clone(),
+            scheduler_trace: engine.scheduler_trace.clone(),
+            tokenizer_pool: TokenizerPool::new(tokenizer, 1).unwrap(),
+            scheduler_limits: engine.scheduler_limits.clone(),
+            aborted_requests: engine.aborted_requests.clone(),
+            admin_token: admin_token.map(str::to_string),
+            log_filter: None,
+            batches: BatchStore::new(4),
+            watermark: None,
+            served_model_names: vec![],
+            control_vectors: engine.control_vector_names(),
+            max_waiting_requests: None,
+            cap_max_tokens_to_free_blocks: false,
+            response_cache: None,
+            reject_unknown_fields: false,
+            max_logprobs: MAX_TOP_LOGPROBS,
+            logit_dumps: None,
+            admissions: engine.admission_queue(),
+            api_keys: HashSet::new(),
+        }
+    }
+
+    pub fn scheduler_snapshot(&self) -> SchedulerSnapshot {
+        self.engine.blocking_lock().scheduler_snapshot()
+    }
+
+    pub fn request_metrics(&self, request_id: &str) -> Option<SequenceGroupMetrics> {
+        self.engine
+            .blocking_lock()
+            .request_metrics(request_id)
+            .copied()
+    }
+
+    /// `system_fingerprint` of the responses of the engine.
+    pub fn system_fingerprint(&self) -> String {
+        self.engine
+            .blocking_lock()
+            .system_fingerprint_id()
+            .to_string()
+    }
+
+    pub fn speculative_tokens(&self) -> (usize, usize) {
+        self.engine.blocking_lock().speculative_tokens()
+    }
+
+    pub fn shrink_idle_cache(&self) -> bool {
+        self.engine.blocking_lock().shrink_idle_cache().unwrap()
+    }
+
+    pub fn sleep(&self, level: SleepLevel) -> Result<(), APIError> {
+        self.engine.blocking_lock().sleep(level)
+    }
+
+    pub fn wake(&self) -> Result<(), APIError> {
+        self.engine.blocking_lock().wake()
+    }
+
+    pub fn add_observer(&self, observer: Arc<dyn EngineObserver>) {
+        self.engine.blocking_lock().add_observer(observer);
+    }
+
+    pub fn add_logits_processor(&self, processor: Arc<dyn LogitsProcessor>) {
+        self.engine.blocking_lock().add_logits_processor(processor);
+    }
+
+    pub fn add_moderator(&self, moderator: Arc<dyn Moderator>) {
+        self.engine.blocking_lock().add_moderator(moderator);
+    }
+
+    /// Log the requests submitted from now on to `request_log`.
+    pub fn set_request_log(&self, request_log: RequestLog) {
+        self.engine.blocking_lock().set_request_log(request_log);
+    }
+
+    /// Recover `requests` found unfinished in a request log and return the generated token ids
+    /// of the replayed ones by request id.
+    pub fn recover(
+        &self,
+        requests: Vec<AcceptedRequest>,
+        replay: bool,
+    ) -> HashMap<String, Vec<usize>> {
+        let mut engine = self.engine.blocking_lock();
+        engine.recover(requests, replay);
+        engine
+            .generate_once()
+            .unwrap()
+            .into_iter()
+            .map(|(request_id, (choices, _))| {
+                let logprobs = choices[0].logprobs.as_ref().unwrap();
+                (
+                    request_id,
+                    logprobs.content.iter().map(|l| l.token).collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn recovered_requests(&self) -> Vec<RecoveredRequest> {
+        self.engine.blocking_lock().recovered_requests().to_vec()
+    }
+
+    /// Queue `prompts` without running them, the next `generate` runs them along with its own.
+    pub fn submit(&mut self, prompts: &[Encoding], max_tokens: usize) {
+        self.add_requests(prompts, 1, max_tokens, false);
+    }
+
+    /// Queue `prompts` through the admission queue, as the server does, and run `f` on the
+    /// engine before its background task can take them in. Returns the ids of the requests.
+    pub fn enqueue<R>(
+        &mut self,
+        prompts: &[Encoding],
+        max_tokens: usize,
+        f: impl FnOnce(&mut LLMEngine) -> R,
+    ) -> (Vec<String>, R) {
+        let mut engine = self.engine.blocking_lock();
+        let admissions = engine.admission_queue();
+        let mut request_ids = Vec::new();
+        for prompt in prompts {
+            let request_id = format!("tiny-{}", self.num_requests);
+            self.num_requests += 1;
+            admissions.submit(
+                prompt.clone(),
+                request_id.clone(),
+                SystemTime::now(),
+                self.sampling_params(1, max_tokens),
+                true,
+                None,
+                None,
+            );
+            request_ids.push(request_id);
+        }
+        let result = f(&mut engine);
+        (request_ids, result)
+    }
+
+    /// Run one step of the engine on the requests submitted so far.
+    pub fn step(&self) -> Result<Vec<RequestOutput>, APIError> {
+        self.engine.blocking_lock().step()
+    }
+
+    pub fn has_unfinished_requests(&self) -> bool {
+        self.engine.blocking_lock().has_unfinished_requests()
+    }
+
+    pub fn rollback(&self, request_id: &str, num_tokens: usize) -> Result<(), APIError> {
+        self.engine
+            .blocking_lock()
+            .rollback(request_id, 0, num_tokens)
+    }
+
+    fn sampling_params(&self, n: usize, max_tokens: usize) -> SamplingParams {
+        // More than one choice needs random sampling to pass validation, the pipeline itself
+        // samples greedily so that every choice is the greedy output.
+        let temperature = if n > 1 { 1.0 } else { 0.0 };
+        let mut sampling_params = SamplingParams::new(
+            n,
+            None,
+            0.0,
+            0.0,
+            1.0,
+            temperature,
+            1.0,
+            -1,
+            false,
+            1.0,
+            EarlyStoppingCondition::UnlikelyBetterCandidates,
+            None,
+            self.stop_token_ids.clone(),
+            true,
+            max_tokens,
+            None,
+            None,
+            true,
+        )
+        .unwrap();
+        sampling_params.set_timeout(self.timeout).unwrap();
+        sampling_params.set_min_tokens(self.min_tokens).unwrap();
+        sampling_params.token_healing = self.token_healing;
+        sampling_params
+            .set_guided_choice(self.guided_choice.clone())
+            .unwrap();
+        sampling_params.set_json_mode(self.json_mode).unwrap();
+        sampling_params.set_export_kv(self.export_kv).unwrap();
+        sampling_params.logits_processors = LogitsProcessors(self.logits_processors.clone());
+        sampling_params
+            .set_conversation_id(self.conversation_id.clone())
+            .unwrap();
+        sampling_params
+            .set_kv_cache_retention(self.kv_cache_retention)
+            .unwrap();
+        sampling_params
+            .set_guidance(self.negative_prompt.clone(), self.guidance_scale)
+            .unwrap();
+        sampling_params
+            .set_sampling_schedule(self.sampling_schedule.clone())
+            .unwrap();
+        sampling_params.logprobs = self.top_logprobs;
+        sampling_params.control_vectors = self.control_vectors.clone();
+        sampling_params.logit_dump = self.logit_dump.clone();
+        sampling_params
+    }
+
+    fn add_requests(
+        &mut self,
+        prompts: &[Encoding],
+        n: usize,
+        max_tokens: usize,
+        stream: bool,
+    ) -> Vec<(String, Receiver<ChatResponse>)> {
+        let mut requests = Vec::new();
+        for prompt in prompts {
+            let request_id = format!("tiny-{}", self.num_requests);
+            self.num_requests += 1;
+            let sampling_params = self.sampling_params(n, max_tokens);
+            let (sender, receiver) = flume::unbounded();
+            self.engine.blocking_lock().add_request(
+                prompt.clone(),
+                request_id.clone(),
+                SystemTime::now(),
+                sampling_params,
+                true,
+                stream.then_some(sender),
+                None,
+            );
+            requests.push((request_id, receiver));
+        }
+        requests
+    }
+
+    /// Submit `prompts` together and return the generated token ids of each, in order.
+    pub fn generate(&mut self, prompts: &[Encoding], max_tokens: usize) -> Vec<Vec<usize>> {
+        self.try_generate(prompts, max_tokens).unwrap()
+    }
+
+    pub fn try_generate(
+        &mut self,
+        prompts: &[Encoding],
+        max_tokens: usize,
+    ) -> Result<Vec<Vec<usize>>, APIError> {
+        Ok(self
+            .generate_logprobs(prompts, max_tokens)?
+            .into_iter()
+            .map(|logprobs| logprobs.into_iter().map(|(token, _)| token).collect())
+            .collect())
+    }
+
+    /// Submit `prompts` together and return the generated token ids of each with their logprob.
+    pub fn generate_logprobs(
+        &mut self,
+        prompts: &[Encoding],
+        max_tokens: usize,
+    ) -> Result<Vec<Vec<(usize, f32)>>, APIError> {
+        let requests = self.add_requests(prompts, 1, max_tokens, false);
+        let mut results = self.engine.blocking_lock().generate_once()?;
+        Ok(requests
+            .iter()
+            .map(|(request_id, _)| {
+                let (choices, _) = results.remove(request_id).unwrap();
+                let logprobs = choices[0].logprobs.as_ref().unwrap();
+                logprobs
+                    .content
+                    .iter()
+                    .map(|l| (l.token, l.logprob))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Generate from `prompt` and export the KV cache of the sequence once it finishes.
+    pub fn generate_and_export(
+        &mut self,
+        prompt: &Encoding,
+        max_tokens: usize,
+    ) -> (Vec<usize>, SequenceKV) {
+        let export_kv = std::mem::replace(&mut self.export_kv, true);
+        let tokens = self
+            .generate(std::slice::from_ref(prompt), max_tokens)
+            .remove(0);
+        self.export_kv = export_kv;
+        let request_id = format!("tiny-{}", self.num_requests - 1);
+        let kv = self.engine.blocking_lock().take_exported_kv(&request_id);
+        (tokens, kv.unwrap())
+    }
+
+    /// Continue the sequence exported in `kv` up to `max_tokens` and return all of its generated
+    /// token ids, the imported ones included.
+    pub fn import(&mut self, kv: SequenceKV, max_tokens: usize) -> Result<Vec<usize>, APIError> {
+        let request_id = format!("tiny-{}", self.num_requests);
+        self.num_requests += 1;
+        let sampling_params = self.sampling_params(1, max_tokens);
+        let mut engine = self.engine.blocking_lock();
+        engine.add_request_with_kv(
+            kv,
+            request_id.clone(),
+            SystemTime::now(),
+            sampling_params,
+            true,
+            None,
+        )?;
+        let (choices, _) = engine.generate_once()?.remove(&request_id).unwrap();
+        let logprobs = choices[0].logprobs.as_ref().unwrap();
+        Ok(logprobs.content.iter().map(|l| l.token).collect())
+    }
+
+    /// Submit `prompts` together as streaming requests for `n` choices and return the chunks
+    /// streamed for each, in order, up to the final `[DONE]`.
+    pub fn stream(
+        &mut self,
+        prompts: &[Encoding],
+        n: usize,
+        max_tokens: usize,
+    ) -> Vec<Vec<ChatCompletionChunk>> {
+        let requests = self.add_requests(prompts, n, max_tokens, true);
+        self.engine.blocking_lock().generate_once().unwrap();
+        requests
+            .iter()
+            .map(|(_, receiver)| {
+                let mut chunks = Vec::new();
+                loop {
+                    match receiver.try_recv() {
+                        Ok(ChatResponse::Chunk(chunk)) => chunks.push(chunk),
+                        Ok(ChatResponse::Done) => break,
+                        Ok(_) => panic!("error streamed"),
+                        Err(e) => panic!("stream ended without [DONE]: {e}"),
+                    }
+                }
+                assert!(receiver.is_empty(), "chunks streamed after [DONE]");
+                chunks
+            })
+            .collect()
+    }
+}
+
+impl Drop for TinyEngine {
+    fn drop(&mut self) {
+        // The engine loop never returns, do not wait for it.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use candle_vllm::scheduler::block_engine::BlockEngine;
+use std::collections::HashSet;
+
+mod common;
+use common::{sequence_group, tiny_model::TinyEngine};
+
+const PROMPTS: [&str; 3] = [
+    "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60",
+    "t20 t21 t22 t23 t24 t25 t26",
+    "t3 t1 t4 t1 t5 t9 t2 t6 t5 t3 t5",
+];
+const MAX_TOKENS: usize = 24;
+
+/// A block engine of 8 blocks of 16 tokens where sequence 1 holds blocks 4 and 3, after the
+/// blocks 7 to 5 of sequence 0 above them were freed.
+fn fragmented() -> BlockEngine {
+    let mut block_engine = BlockEngine::new(16, 8, 8);
+    let first = sequence_group(0, 40, 16);
+    block_engine.allocate(&first);
+    block_engine.allocate(&sequence_group(1, 24, 16));
+    assert_eq!(block_engine.get_block_table_ids(1).unwrap(), [4, 3]);
+    block_engine.free_sequence(&first.get_seqs()[&0]);
+    block_engine
+}
+
+#[test]
+fn blocks_are_packed_at_the_end_of_the_cache() {
+    let mut block_engine = fragmented();
+    let moves = block_engine.compact(&[0, 1], 8, &HashSet::new());
+    assert_eq!(moves, [(3, 7), (4, 6)]);
+    assert_eq!(block_engine.get_block_table_ids(1).unwrap(), [6, 7]);
+    assert_eq!(block_engine.get_num_free_gpu_blocks(), 6);
+
+    // The next prompt takes the free blocks in one range.
+    block_engine.allocate(&sequence_group(2, 72, 16));
+    assert_eq!(
+        block_engine.get_block_table_ids(2).unwrap(),
+        [5, 4, 3, 2, 1]
+    );
+
+    // Nothing is left to move.
+    assert!(block_engine.compact(&[1, 2], 8, &HashSet::new()).is_empty());
+}
+
+#[test]
+fn compaction_is_bounded_and_leaves_pinned_blocks() {
+    let mut block_engine = fragmented();
+    assert_eq!(block_engine.compact(&[1], 1, &HashSet::new()), [(3, 7)]);
+    assert_eq!(block_engine.get_block_table_ids(1).unwrap(), [4, 7]);
+
+    let mut block_engine = fragmented();
+    assert_eq!(block_engine.compact(&[1], 8, &HashSet::from([3])), [(4, 7)]);
+    assert_eq!(block_engine.get_block_table_ids(1).unwrap(), [7, 3]);
+}
+
+#[test]
+fn compaction_does_not_change_the_outputs() {
+    let generate = |engine: &mut TinyEngine| {
+        let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+        // The first request finishes early, leaving a hole in the cache.
+        engine.submit(&prompts[..1], 4);
+        engine.generate(&prompts[1..], MAX_TOKENS)
+    };
+    let expected = generate(&mut TinyEngine::new(8));
+
+    let mut engine = TinyEngine::with_compaction(TinyEngine::cache_config(8), 2);
+    assert_eq!(generate(&mut engine), expected);
+    let stats = engine.scheduler_snapshot().cache_stats();
+    assert!(stats.num_compacted_blocks > 0);
+    assert!(stats.num_compacted_blocks <= 2 * stats.num_compaction_steps);
+    assert_eq!(stats.num_free_gpu_blocks, stats.num_gpu_blocks);
+}
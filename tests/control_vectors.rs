@@ -0,0 +1,187 @@
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    Tensor,
+};
+use candle_vllm::openai::{
+    control_vectors::{parse_control_vector_arg, ControlVectorStrength},
+    pipelines::worker::WorkerConfig,
+    sampling_params::{EarlyStoppingCondition, SamplingParams},
+};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+mod common;
+use common::tiny_model::{random_tensor, TinyEngine, HIDDEN_SIZE, NUM_LAYERS};
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33", "t40 t41 t20"];
+const MAX_TOKENS: usize = 8;
+
+fn directions(size: usize, layers: &[usize]) -> HashMap<String, Tensor> {
+    let mut rng = StdRng::seed_from_u64(7);
+    layers
+        .iter()
+        .map(|layer| {
+            let direction = random_tensor(&mut rng, (1, size), 8.0)
+                .flatten_all()
+                .unwrap();
+            (format!("direction.{layer}"), direction)
+        })
+        .collect()
+}
+
+fn vector_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "candle-vllm-control-vector-{}-{name}",
+        std::process::id()
+    ))
+}
+
+fn write_safetensors(name: &str, directions: &HashMap<String, Tensor>) -> PathBuf {
+    let path = vector_path(&format!("{name}.safetensors"));
+    candle_core::safetensors::save(directions, &path).unwrap();
+    path
+}
+
+fn write_gguf(name: &str, directions: &HashMap<String, Tensor>) -> PathBuf {
+    let path = vector_path(&format!("{name}.gguf"));
+    let tensors = directions
+        .iter()
+        .map(|(name, direction)| {
+            let tensor = QTensor::quantize(direction, GgmlDType::F32).unwrap();
+            (name.as_str(), tensor)
+        })
+        .collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(name, tensor)| (*name, tensor))
+        .collect::<Vec<_>>();
+    let mut file = std::fs::File::create(&path).unwrap();
+    gguf_file::write(&mut file, &[], &tensors).unwrap();
+    path
+}
+
+fn with_vectors(vectors: &[(&str, &Path)]) -> WorkerConfig {
+    WorkerConfig {
+        control_vectors: vectors
+            .iter()
+            .map(|(name, path)| (name.to_string(), path.to_path_buf()))
+            .collect(),
+        ..WorkerConfig::default()
+    }
+}
+
+fn steer(name: &str, strength: f32) -> Vec<ControlVectorStrength> {
+    vec![ControlVectorStrength {
+        name: name.to_string(),
+        strength,
+    }]
+}
+
+#[test]
+fn steered_choices_leave_the_others_of_the_batch_alone() {
+    let mut expected = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| expected.encode(prompt));
+    let expected = expected.generate(&prompts, MAX_TOKENS);
+
+    let path = write_safetensors("batch", &directions(HIDDEN_SIZE, &[0, 1]));
+    let mut engine = TinyEngine::with_worker_config(with_vectors(&[("happy", &path)])).unwrap();
+    // With a strength of 0, the vector steers nothing.
+    engine.control_vectors = steer("happy", 0.0);
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+
+    // A request without the vector is not steered by the one it runs along with.
+    engine.control_vectors = steer("happy", 4.0);
+    engine.submit(&prompts[..1], MAX_TOKENS);
+    engine.control_vectors = vec![];
+    engine.submit(&prompts[1..], MAX_TOKENS);
+    let mut outputs = HashMap::new();
+    while engine.has_unfinished_requests() {
+        for output in engine.step().unwrap() {
+            outputs.insert(output.request_id, output.token_ids[0].clone());
+        }
+    }
+    assert_ne!(outputs["tiny-2"], expected[0]);
+    assert_eq!(outputs["tiny-3"], expected[1]);
+}
+
+#[test]
+fn gguf_and_safetensors_vectors_steer_alike() {
+    let directions = directions(HIDDEN_SIZE, &[1]);
+    let safetensors = write_safetensors("alike", &directions);
+    let gguf = write_gguf("alike", &directions);
+    let mut engine =
+        TinyEngine::with_worker_config(with_vectors(&[("st", &safetensors), ("gguf", &gguf)]))
+            .unwrap();
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    engine.control_vectors = steer("st", -3.0);
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+    engine.control_vectors = steer("gguf", -3.0);
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+}
+
+#[test]
+fn vectors_must_fit_the_model() {
+    let path = write_safetensors("size", &directions(HIDDEN_SIZE + 1, &[0]));
+    assert!(TinyEngine::with_worker_config(with_vectors(&[("size", &path)])).is_err());
+    let path = write_safetensors("layer", &directions(HIDDEN_SIZE, &[NUM_LAYERS]));
+    assert!(TinyEngine::with_worker_config(with_vectors(&[("layer", &path)])).is_err());
+    let path = write_safetensors("empty", &HashMap::new());
+    assert!(TinyEngine::with_worker_config(with_vectors(&[("empty", &path)])).is_err());
+
+    let path = vector_path("twice.safetensors");
+    assert!(with_vectors(&[("a", &path), ("a", &path)])
+        .verify_args()
+        .is_err());
+}
+
+#[test]
+fn control_vectors_are_named_on_the_command_line() {
+    let (name, path) = parse_control_vector_arg("happy=vectors/happy.gguf").unwrap();
+    assert_eq!(name, "happy");
+    assert_eq!(path, PathBuf::from("vectors/happy.gguf"));
+    assert!(parse_control_vector_arg("vectors/happy.gguf").is_err());
+    assert!(parse_control_vector_arg("=vectors/happy.gguf").is_err());
+}
+
+#[test]
+fn requests_steer_with_the_vectors_of_the_server() {
+    let mut params = SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        vec![],
+        false,
+        16,
+        None,
+        None,
+        true,
+    )
+    .unwrap();
+    let available = ["happy".to_string()];
+    assert!(params
+        .set_control_vectors(Some(steer("sad", 1.0)), &available)
+        .is_err());
+    assert!(params
+        .set_control_vectors(Some(steer("happy", f32::NAN)), &available)
+        .is_err());
+    params
+        .set_control_vectors(Some(steer("happy", -0.5)), &available)
+        .unwrap();
+    assert_eq!(params.control_vectors, steer("happy", -0.5));
+
+    let strengths: Vec<ControlVectorStrength> =
+        serde_json::from_str(r#"[{"name": "happy"}]"#).unwrap();
+    assert_eq!(strengths, steer("happy", 1.0));
+}
@@ -0,0 +1,268 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::{
+    backend::{
+        check_cache_dtype, copy_blocks, paged_attention, reshape_and_cache, swap_blocks,
+        KvCacheLayout,
+    },
+    get_checkpoint_dtype, get_dtype, get_kv_cache_dtype, get_model_loader, get_model_paths,
+    openai::models::llama::LlamaConfig,
+    paged_attention::gather_kv_cache,
+    scheduler::cache_engine::{CacheConfig, CacheEngine},
+    ModelSelected,
+};
+use std::collections::HashMap;
+
+mod common;
+use common::{
+    cache_config,
+    tiny_model::{tiny_llama_dir, TinyEngine},
+};
+
+const PROMPT_A: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+
+fn write_config(name: &str, config: serde_json::Value) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-dtype-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.json"));
+    std::fs::write(&path, config.to_string()).unwrap();
+    path
+}
+
+#[test]
+fn reads_the_checkpoint_dtype() {
+    let cases = [
+        (
+            "f16",
+            serde_json::json!({"torch_dtype": "float16"}),
+            Some(DType::F16),
+        ),
+        (
+            "bf16",
+            serde_json::json!({"torch_dtype": "bfloat16"}),
+            Some(DType::BF16),
+        ),
+        (
+            "f32",
+            serde_json::json!({"torch_dtype": "float32"}),
+            Some(DType::F32),
+        ),
+        (
+            "llava",
+            serde_json::json!({"text_config": {"torch_dtype": "float16"}}),
+            Some(DType::F16),
+        ),
+        ("none", serde_json::json!({}), None),
+    ];
+    for (name, config, dtype) in cases {
+        let path = write_config(name, config);
+        assert_eq!(get_checkpoint_dtype(&path).unwrap(), dtype, "{name}");
+    }
+    let path = write_config("int8", serde_json::json!({"torch_dtype": "int8"}));
+    assert!(get_checkpoint_dtype(&path).is_err());
+}
+
+#[test]
+fn auto_dtype_follows_the_checkpoint() {
+    let (loader, model_id) = get_model_loader(
+        ModelSelected::Llama {
+            repeat_last_n: None,
+            temperature: None,
+            penalty: None,
+            max_gen_tokens: None,
+        },
+        None,
+    );
+    let weight_path = format!("{}/", tiny_llama_dir().display());
+    let paths = get_model_paths(&*loader, model_id, Some(&weight_path), None, None).unwrap();
+    assert_eq!(get_dtype(Some("auto"), &*paths).unwrap(), DType::F32);
+    assert_eq!(get_dtype(Some("f16"), &*paths).unwrap(), DType::F16);
+    assert_eq!(get_dtype(None, &*paths).unwrap(), DType::BF16);
+    assert!(get_dtype(Some("f64"), &*paths).is_err());
+
+    // The cache takes the dtype of the model unless given its own.
+    assert_eq!(get_kv_cache_dtype(None, DType::BF16).unwrap(), DType::BF16);
+    assert_eq!(
+        get_kv_cache_dtype(Some("auto"), DType::F32).unwrap(),
+        DType::F32
+    );
+    assert_eq!(
+        get_kv_cache_dtype(Some("f16"), DType::F32).unwrap(),
+        DType::F16
+    );
+    // fp8 is refused as not supported, not as an unknown dtype.
+    let err = get_kv_cache_dtype(Some("fp8"), DType::BF16).unwrap_err();
+    assert!(err.message().contains("not supported yet"), "{err}");
+    assert!(get_kv_cache_dtype(Some("f8"), DType::BF16).is_err());
+}
+
+#[test]
+fn cache_dtype_is_independent_of_the_model() {
+    let config: LlamaConfig = serde_json::from_value(serde_json::json!({
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "vocab_size": 64,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+    }))
+    .unwrap();
+    let config = config.into_config(false, DType::F32);
+    let new_cache = |dtype| {
+        let cache_config = CacheConfig {
+            dtype,
+            ..cache_config(16)
+        };
+        CacheEngine::new(config.clone(), cache_config, dtype, &Device::Cpu)
+    };
+    assert!(new_cache(DType::F32).is_ok());
+    assert!(new_cache(DType::F16).is_ok());
+
+    let u8_cache = CacheConfig {
+        dtype: DType::U8,
+        ..cache_config(16)
+    };
+    assert!(u8_cache.verify_args().is_err());
+}
+
+#[test]
+fn serves_f32_checkpoints_in_f16() {
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        dtype: DType::F16,
+        ..cache_config(16)
+    });
+    let a = engine.encode(PROMPT_A);
+    let generated = engine.generate(&[a], 8);
+    assert_eq!(generated[0].len(), 9);
+}
+
+#[test]
+fn serves_f32_models_over_a_f16_cache() {
+    // The cache config of the tests is f16.
+    let mut engine = TinyEngine::with_dtype(DType::F32, cache_config(16)).unwrap();
+    let a = engine.encode(PROMPT_A);
+    let generated = engine.generate(&[a], 8);
+    assert_eq!(generated[0].len(), 9);
+}
+
+#[test]
+fn serves_in_bf16_end_to_end() {
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        dtype: DType::BF16,
+        ..cache_config(16)
+    });
+    let a = engine.encode(PROMPT_A);
+    let generated = engine.generate(&[a], 8);
+    assert_eq!(generated[0].len(), 9);
+}
+
+#[test]
+fn bf16_caches_go_through_every_cache_op() {
+    let device = Device::Cpu;
+    assert!(check_cache_dtype(&device, DType::BF16).is_ok());
+    assert!(check_cache_dtype(&device, DType::U8).is_err());
+
+    // Keys and values of 5 tokens in block 1, 2 kv heads of size 16 for 4 query heads.
+    let key = Tensor::randn(0f32, 1., (5, 2, 16), &device).unwrap();
+    let value = Tensor::randn(0f32, 1., (5, 2, 16), &device).unwrap();
+    let q = Tensor::randn(0f32, 1., (1, 4, 16), &device).unwrap();
+    let slots = Tensor::arange(8i64, 13, &device).unwrap();
+    let context_lens = Tensor::new(&[5u32], &device).unwrap();
+    let attend = |dtype: DType, key_cache: &Tensor, value_cache: &Tensor, block: u32| {
+        let block_tables = Tensor::new(&[[block]], &device).unwrap();
+        let q = q.to_dtype(dtype).unwrap();
+        paged_attention(
+            &q,
+            key_cache,
+            value_cache,
+            &block_tables,
+            &context_lens,
+            5,
+            0.25,
+        )
+        .unwrap()
+        .to_dtype(DType::F32)
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .to_vec1::<f32>()
+        .unwrap()
+    };
+    let caches = |dtype: DType| {
+        let layout = KvCacheLayout::new(2, 16, 8, dtype).unwrap();
+        let key_cache = Tensor::zeros(layout.key_cache_shape(4), dtype, &device).unwrap();
+        let value_cache = Tensor::zeros(layout.value_cache_shape(4), dtype, &device).unwrap();
+        let (key, value) = (key.to_dtype(dtype).unwrap(), value.to_dtype(dtype).unwrap());
+        reshape_and_cache(&key, &value, &key_cache, &value_cache, &slots).unwrap();
+        (key_cache, value_cache)
+    };
+
+    let (key_f32, value_f32) = caches(DType::F32);
+    let expected = attend(DType::F32, &key_f32, &value_f32, 1);
+    let (mut key_cache, mut value_cache) = caches(DType::BF16);
+    let bf16 = attend(DType::BF16, &key_cache, &value_cache, 1);
+    for (a, e) in bf16.iter().zip(&expected) {
+        assert!((a - e).abs() < 0.05, "{bf16:?} != {expected:?}");
+    }
+
+    // Blocks copied within a cache and swapped between caches keep every bit.
+    unsafe {
+        copy_blocks(
+            vec![&mut key_cache],
+            vec![&mut value_cache],
+            HashMap::from([(1, vec![3])]),
+        )
+        .unwrap();
+    }
+    assert_eq!(attend(DType::BF16, &key_cache, &value_cache, 3), bf16);
+    let mut swapped_keys = key_cache.zeros_like().unwrap();
+    let mut swapped_values = value_cache.zeros_like().unwrap();
+    swap_blocks(key_cache, &mut swapped_keys, HashMap::from([(3, 0)])).unwrap();
+    swap_blocks(value_cache, &mut swapped_values, HashMap::from([(3, 0)])).unwrap();
+    assert_eq!(attend(DType::BF16, &swapped_keys, &swapped_values, 0), bf16);
+}
+
+#[test]
+fn caches_of_another_dtype_are_written_and_attended_in_their_own() {
+    let device = Device::Cpu;
+    let key = Tensor::randn(0f32, 1., (5, 2, 16), &device).unwrap();
+    let value = Tensor::randn(0f32, 1., (5, 2, 16), &device).unwrap();
+    let q = Tensor::randn(0f32, 1., (1, 4, 16), &device).unwrap();
+    let slots = Tensor::arange(0i64, 5, &device).unwrap();
+    let block_tables = Tensor::new(&[[0u32]], &device).unwrap();
+    let context_lens = Tensor::new(&[5u32], &device).unwrap();
+    let attend = |dtype: DType| {
+        let layout = KvCacheLayout::new(2, 16, 8, dtype).unwrap();
+        let key_cache = Tensor::zeros(layout.key_cache_shape(2), dtype, &device).unwrap();
+        let value_cache = Tensor::zeros(layout.value_cache_shape(2), dtype, &device).unwrap();
+        // f32 keys and values, cast to the dtype of the cache.
+        reshape_and_cache(&key, &value, &key_cache, &value_cache, &slots).unwrap();
+        let (gathered, _) =
+            gather_kv_cache(&key_cache, &value_cache, &[0], 0, 5, DType::F32).unwrap();
+        assert_eq!(gathered.dtype(), DType::F32);
+        let output = paged_attention(
+            &q,
+            &key_cache,
+            &value_cache,
+            &block_tables,
+            &context_lens,
+            5,
+            0.25,
+        )
+        .unwrap();
+        assert_eq!(output.dtype(), DType::F32);
+        output.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+    };
+    let expected = attend(DType::F32);
+    for dtype in [DType::F16, DType::BF16] {
+        let output = attend(dtype);
+        for (a, e) in output.iter().zip(&expected) {
+            assert!(
+                (a - e).abs() < 0.05,
+                "{dtype:?}: {output:?} != {expected:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::t5;
+use candle_vllm::{
+    openai::pipelines::worker::WorkerConfig,
+    scheduler::cache_engine::{AttentionSinks, CacheConfig},
+    ModelSelected,
+};
+use common::tiny_model::{random_tensor, write_tokenizer, TinyEngine, VOCAB_SIZE};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+const D_MODEL: usize = 48;
+// The inner dimension of the attention differs from `D_MODEL`, as in flan-t5-small.
+const D_KV: usize = 8;
+const NUM_HEADS: usize = 4;
+const D_FF: usize = 64;
+const NUM_LAYERS: usize = 2;
+const NUM_BUCKETS: usize = 32;
+const EOS_TOKEN_ID: u32 = 1;
+
+fn write_t5_checkpoint(dir: &Path) {
+    std::fs::create_dir_all(dir).unwrap();
+    let config = serde_json::json!({
+        "vocab_size": VOCAB_SIZE,
+        "d_model": D_MODEL,
+        "d_kv": D_KV,
+        "d_ff": D_FF,
+        "num_layers": NUM_LAYERS,
+        "num_decoder_layers": NUM_LAYERS,
+        "num_heads": NUM_HEADS,
+        "relative_attention_num_buckets": NUM_BUCKETS,
+        "relative_attention_max_distance": 128,
+        "dropout_rate": 0.1,
+        "layer_norm_epsilon": 1e-6,
+        "initializer_factor": 1.0,
+        "feed_forward_proj": "gated-gelu",
+        "tie_word_embeddings": true,
+        "is_encoder_decoder": true,
+        "pad_token_id": 0,
+        "eos_token_id": EOS_TOKEN_ID,
+        "decoder_start_token_id": 0,
+        "torch_dtype": "float32",
+    });
+    std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+    write_tokenizer(dir, &["<pad>", "</s>", "<unk>"]);
+
+    let mut rng = StdRng::seed_from_u64(3);
+    let inner_dim = NUM_HEADS * D_KV;
+    let ones = |n: usize| Tensor::ones(n, DType::F32, &Device::Cpu).unwrap();
+    let mut weights = HashMap::new();
+    weights.insert(
+        "shared.weight".to_string(),
+        random_tensor(&mut rng, (VOCAB_SIZE, D_MODEL), 1.0),
+    );
+    for stack in ["encoder", "decoder"] {
+        let mut layers = vec!["SelfAttention"];
+        if stack == "decoder" {
+            layers.push("EncDecAttention");
+        }
+        for i in 0..NUM_LAYERS {
+            let prefix = format!("{stack}.block.{i}.layer");
+            for (j, attention) in layers.iter().enumerate() {
+                let linears = [
+                    ("q", (inner_dim, D_MODEL)),
+                    ("k", (inner_dim, D_MODEL)),
+                    ("v", (inner_dim, D_MODEL)),
+                    ("o", (D_MODEL, inner_dim)),
+                ];
+                for (name, shape) in linears {
+                    let scale = 4.0 / (shape.1 as f32).sqrt();
+                    weights.insert(
+                        format!("{prefix}.{j}.{attention}.{name}.weight"),
+                        random_tensor(&mut rng, shape, scale),
+                    );
+                }
+                weights.insert(format!("{prefix}.{j}.layer_norm.weight"), ones(D_MODEL));
+            }
+            weights.insert(
+                format!("{prefix}.0.SelfAttention.relative_attention_bias.weight"),
+                random_tensor(&mut rng, (NUM_BUCKETS, NUM_HEADS), 1.0),
+            );
+            let ff = layers.len();
+            let linears = [
+                ("wi_0", (D_FF, D_MODEL)),
+                ("wi_1", (D_FF, D_MODEL)),
+                ("wo", (D_MODEL, D_FF)),
+            ];
+            for (name, shape) in linears {
+                let scale = 4.0 / (shape.1 as f32).sqrt();
+                weights.insert(
+                    format!("{prefix}.{ff}.DenseReluDense.{name}.weight"),
+                    random_tensor(&mut rng, shape, scale),
+                );
+            }
+            weights.insert(format!("{prefix}.{ff}.layer_norm.weight"), ones(D_MODEL));
+        }
+        weights.insert(format!("{stack}.final_layer_norm.weight"), ones(D_MODEL));
+    }
+    // Only the first layer of a stack has a relative position bias.
+    weights.retain(|name, _| !name.contains("block.1.layer.0.SelfAttention.relative"));
+    candle_core::safetensors::save(&weights, dir.join("model.safetensors")).unwrap();
+}
+
+fn tiny_t5_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("candle-vllm-tiny-t5-{}", std::process::id()));
+        write_t5_checkpoint(&dir);
+        dir
+    })
+}
+
+fn t5_engine(cache_config: CacheConfig) -> Result<TinyEngine, String> {
+    t5_engine_with(cache_config, WorkerConfig::default())
+}
+
+fn t5_engine_with(
+    cache_config: CacheConfig,
+    worker_config: WorkerConfig,
+) -> Result<TinyEngine, String> {
+    let model = ModelSelected::T5 {
+        repeat_last_n: None,
+        temperature: Some(0.),
+        penalty: Some(1.),
+        max_gen_tokens: None,
+    };
+    TinyEngine::load_with_worker_config(model, tiny_t5_dir(), cache_config, worker_config)
+        .map_err(|e| e.to_string())
+}
+
+fn cache_config(block_size: usize) -> CacheConfig {
+    let mut cache_config = common::cache_config(block_size);
+    cache_config.dtype = DType::F32;
+    cache_config
+}
+
+/// Greedy output of the candle T5, whose decoder applies the bidirectional position buckets
+/// too. Both agree while the decoder has less than `NUM_BUCKETS / 4` tokens.
+fn reference_generate(prompt: &[u32], num_tokens: usize) -> Vec<usize> {
+    let config: t5::Config =
+        serde_json::from_slice(&std::fs::read(tiny_t5_dir().join("config.json")).unwrap()).unwrap();
+    let weights = [tiny_t5_dir().join("model.safetensors")];
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weights, DType::F32, &Device::Cpu) };
+    let mut model = t5::T5ForConditionalGeneration::load(vb.unwrap(), &config).unwrap();
+
+    let mut input = prompt.to_vec();
+    input.push(EOS_TOKEN_ID);
+    let input = Tensor::new(input.as_slice(), &Device::Cpu)
+        .unwrap()
+        .unsqueeze(0)
+        .unwrap();
+    let encoder_output = model.encode(&input).unwrap();
+    let mut token = 0u32;
+    let mut tokens = Vec::new();
+    for _ in 0..num_tokens {
+        let decoder_input = Tensor::new(&[[token]], &Device::Cpu).unwrap();
+        let logits = model.decode(&decoder_input, &encoder_output).unwrap();
+        token = logits
+            .squeeze(0)
+            .unwrap()
+            .argmax(0)
+            .unwrap()
+            .to_scalar::<u32>()
+            .unwrap();
+        tokens.push(token as usize);
+    }
+    tokens
+}
+
+#[test]
+fn t5_generates_the_greedy_output_of_the_reference() {
+    let max_tokens = 4;
+    for block_size in [8, 16] {
+        let mut engine = t5_engine(cache_config(block_size)).unwrap();
+        // Prompts of different lengths, their decoders start at different slots of a block.
+        let prompts = [
+            engine.encode("t5 t17 t9 t33 t41"),
+            engine.encode("t12 t8 t60 t3 t27 t50 t19 t44 t6"),
+        ];
+        let generated = engine.generate(&prompts, max_tokens);
+        for (prompt, tokens) in prompts.iter().zip(generated) {
+            assert!(!tokens.is_empty());
+            let expected = reference_generate(prompt.get_ids(), max_tokens + 1);
+            assert_eq!(tokens, expected[..tokens.len()], "block size {block_size}");
+            // Generation only stops before the limit at EOS.
+            if tokens.len() < expected.len() {
+                assert_eq!(expected[tokens.len()], EOS_TOKEN_ID as usize);
+            }
+        }
+    }
+}
+
+#[test]
+fn t5_streams_the_same_output_for_every_choice() {
+    let mut engine = t5_engine(cache_config(8)).unwrap();
+    let prompt = engine.encode("t5 t17 t9 t33 t41");
+    let expected = engine.generate(std::slice::from_ref(&prompt), 4).remove(0);
+    let chunks = engine.stream(&[prompt], 2, 4).remove(0);
+    for index in 0..2 {
+        let content = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .filter(|choice| choice.index == index)
+            .filter_map(|choice| choice.delta.content.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(content.len(), expected.len());
+    }
+}
+
+#[test]
+fn attention_sinks_are_rejected_for_t5() {
+    let mut cache_config = cache_config(8);
+    cache_config.attention_sinks = Some(AttentionSinks {
+        sink_blocks: 1,
+        window_blocks: 4,
+    });
+    let err = t5_engine(cache_config).err().unwrap();
+    assert!(err.contains("encoder-decoder"), "{err}");
+}
+
+#[test]
+fn control_vectors_are_rejected_for_t5() {
+    let worker_config = WorkerConfig {
+        control_vectors: vec![("happy".to_string(), PathBuf::from("happy.safetensors"))],
+        ..WorkerConfig::default()
+    };
+    let err = t5_engine_with(cache_config(8), worker_config)
+        .err()
+        .unwrap();
+    assert!(err.contains("Control vectors are not supported"), "{err}");
+}
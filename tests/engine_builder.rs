@@ -0,0 +1,109 @@
+use candle_core::DType;
+use candle_vllm::{
+    engine_builder::EngineBuilder,
+    openai::sampling_params::{EarlyStoppingCondition, SamplingParams},
+    ModelSelected,
+};
+use std::time::SystemTime;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::{tiny_llama_dir, TinyEngine};
+
+const PROMPT: &str = "t5 t9 t17 t33";
+const MAX_TOKENS: usize = 6;
+
+/// The tiny llama on CPU, with a KV cache of 1 MB.
+fn builder() -> EngineBuilder {
+    EngineBuilder::new("tiny-llama")
+        .model(ModelSelected::Llama {
+            repeat_last_n: None,
+            temperature: Some(0.),
+            penalty: Some(1.),
+            max_gen_tokens: None,
+        })
+        .weight_path(format!("{}/", tiny_llama_dir().display()))
+        .cpu(true)
+        .dtype(DType::F32)
+        .kvcache_mem(1)
+        .swap_space_mem(1)
+        .max_num_seqs(4)
+}
+
+fn greedy(max_tokens: usize) -> SamplingParams {
+    SamplingParams::new(
+        1,
+        None,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+        -1,
+        false,
+        1.0,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        None,
+        vec![],
+        true,
+        max_tokens,
+        None,
+        None,
+        true,
+    )
+    .unwrap()
+}
+
+#[test]
+fn built_engines_generate_once_warmed_up() {
+    let mut expected = TinyEngine::new(16);
+    let prompt = expected.encode(PROMPT);
+    let expected = expected
+        .generate(std::slice::from_ref(&prompt), MAX_TOKENS)
+        .remove(0);
+
+    Runtime::new().unwrap().block_on(async {
+        let (engine, pipeline_config) = builder().max_model_len(128).build().await.unwrap();
+        assert_eq!(pipeline_config.max_model_len, 128);
+        let mut engine = engine.lock().await;
+        // The warmup request already finished.
+        assert!(!engine.has_unfinished_requests());
+
+        engine.add_request(
+            prompt,
+            "request".to_string(),
+            SystemTime::now(),
+            greedy(MAX_TOKENS),
+            false,
+            None,
+            None,
+        );
+        let mut tokens = Vec::new();
+        while engine.has_unfinished_requests() {
+            for output in engine.step().unwrap() {
+                tokens = output.token_ids[0].clone();
+            }
+        }
+        assert_eq!(tokens, expected);
+    });
+}
+
+#[test]
+fn rejects_settings_the_model_or_cache_cannot_serve() {
+    Runtime::new().unwrap().block_on(async {
+        let err = builder()
+            .gpu_memory_utilization(1.5)
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("gpu_memory_utilization"));
+
+        // The tiny llama has 256 positions.
+        let err = builder().max_model_len(512).build().await.err().unwrap();
+        assert!(err.to_string().contains("max_model_len"));
+
+        let err = builder().kvcache_mem(0).build().await.err().unwrap();
+        assert!(err.to_string().contains("less than a sequence"));
+    });
+}
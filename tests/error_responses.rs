@@ -0,0 +1,178 @@
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use candle_vllm::openai::{
+    openai_server::chat_completions,
+    pipelines::llm_engine::SleepLevel,
+    responses::{ChatResponder, ErrorResponse},
+    OpenAIServerData,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+/// A chat completion request, with the fields of `fields`.
+fn chat(fields: Value) -> Value {
+    let mut request = json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": "t5 t9 t17"}],
+        "max_tokens": 4,
+        "temperature": 0.0,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    request
+}
+
+/// Status and error of the response to the chat completion `request`.
+fn respond(data: OpenAIServerData, request: Value) -> (StatusCode, Option<ErrorResponse>) {
+    let request = serde_json::from_value(request).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let responder: ChatResponder =
+            chat_completions(State(Arc::new(data)), HeaderMap::new(), Ok(Json(request))).await;
+        let response = responder.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    })
+}
+
+#[test]
+fn errors_have_the_type_of_their_status() {
+    let error = ErrorResponse::new(StatusCode::BAD_REQUEST, "bad".to_string(), None);
+    assert_eq!(
+        serde_json::to_value(&error).unwrap(),
+        json!({"error": {
+            "message": "bad",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": null,
+        }})
+    );
+    for (status, error_type) in [
+        (StatusCode::NOT_FOUND, "invalid_request_error"),
+        (StatusCode::UNAUTHORIZED, "authentication_error"),
+        (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error"),
+        (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+        (StatusCode::SERVICE_UNAVAILABLE, "server_error"),
+    ] {
+        let error = ErrorResponse::new(status, String::new(), None);
+        assert_eq!(error.error.error_type, error_type, "{status}");
+    }
+}
+
+#[test]
+fn invalid_requests_are_bad_requests() {
+    let engine = TinyEngine::new(16);
+    let (status, error) = respond(engine.server_data(None), chat(json!({"n": 0})));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = error.unwrap().error;
+    assert_eq!(error.error_type, "invalid_request_error");
+    assert!(!error.message.starts_with("Error: "), "{}", error.message);
+}
+
+#[test]
+fn prompts_larger_than_the_cache_are_bad_requests() {
+    let mut cache_config = TinyEngine::cache_config(16);
+    cache_config.num_gpu_blocks = Some(1);
+    let engine = TinyEngine::with_cache_config(cache_config);
+    let content = (0..20)
+        .map(|i| format!("t{i}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let request = chat(json!({"messages": [{"role": "user", "content": content}]}));
+    let (status, error) = respond(engine.server_data(None), request);
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = error.unwrap().error;
+    assert_eq!(error.param.as_deref(), Some("messages"));
+    assert!(error.message.contains("KV cache"), "{}", error.message);
+}
+
+#[test]
+fn only_the_served_models_are_found() {
+    let engine = TinyEngine::new(16);
+    let mut data = engine.server_data(None);
+    data.served_model_names = vec!["llama".to_string(), "tiny".to_string()];
+    let (status, error) = respond(data, chat(json!({"model": "gpt-4"})));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let error = error.unwrap().error;
+    assert_eq!(error.code.as_deref(), Some("model_not_found"));
+    assert!(error.message.contains("gpt-4"), "{}", error.message);
+
+    let mut data = engine.server_data(None);
+    data.served_model_names = vec!["llama".to_string(), "tiny".to_string()];
+    assert_eq!(
+        respond(data, chat(json!({"model": "tiny"}))).0,
+        StatusCode::OK
+    );
+    // Any model is served without names.
+    let data = engine.server_data(None);
+    assert_eq!(
+        respond(data, chat(json!({"model": "gpt-4"}))).0,
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn requests_are_refused_while_the_engine_cannot_take_them() {
+    let engine = TinyEngine::new(16);
+    let mut data = engine.server_data(None);
+    data.max_waiting_requests = Some(0);
+    let (status, error) = respond(data, chat(json!({})));
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error.unwrap().error.error_type, "rate_limit_error");
+
+    let data = engine.server_data(None);
+    engine.sleep(SleepLevel::KvCache).unwrap();
+    let (status, error) = respond(data, chat(json!({})));
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error.unwrap().error.error_type, "server_error");
+}
+
+#[test]
+fn invalid_fields_are_reported_as_the_param_of_the_error() {
+    let engine = TinyEngine::new(16);
+    for (fields, param) in [
+        (json!({"temperature": 2.5}), "temperature"),
+        (json!({"top_p": 0.0}), "top_p"),
+        (json!({"presence_penalty": -3.0}), "presence_penalty"),
+        (json!({"top_k": 0}), "top_k"),
+        (json!({"n": 2, "best_of": 1}), "best_of"),
+        (json!({"logit_bias": {"hello": 1.0}}), "logit_bias"),
+        (json!({"logit_bias": {"5": 150.0}}), "logit_bias"),
+    ] {
+        let (status, error) = respond(engine.server_data(None), chat(fields.clone()));
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{fields}");
+        let error = error.unwrap().error;
+        assert_eq!(error.param.as_deref(), Some(param), "{fields}");
+        assert!(error.message.contains(param), "{}", error.message);
+    }
+}
+
+#[test]
+fn unknown_fields_are_only_refused_by_strict_validation() {
+    let engine = TinyEngine::new(16);
+    let request = chat(json!({"frobnicate": true}));
+    assert_eq!(
+        respond(engine.server_data(None), request.clone()).0,
+        StatusCode::OK
+    );
+
+    let mut data = engine.server_data(None);
+    data.reject_unknown_fields = true;
+    let (status, error) = respond(data, request);
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error.unwrap().error.param.as_deref(), Some("frobnicate"));
+
+    let mut data = engine.server_data(None);
+    data.reject_unknown_fields = true;
+    assert_eq!(respond(data, chat(json!({}))).0, StatusCode::OK);
+}
@@ -0,0 +1,134 @@
+use candle_core::Tensor;
+use candle_vllm::{
+    openai::{
+        hooks::{EngineObserver, StepEvent},
+        pipelines::{
+            executor::{Executor, LocalExecutor},
+            worker::{CacheOps, ModelInput},
+            ModulePipeline,
+        },
+        responses::APIError,
+    },
+    scheduler::cache_engine::KVCache,
+};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 8;
+
+/// Runs the local worker, recording the steps it is sent: whether they are prompts and the
+/// sequences and tokens they run.
+struct RecordingExecutor {
+    inner: LocalExecutor,
+    steps: Arc<Mutex<Vec<(bool, usize, usize)>>>,
+}
+
+impl Executor for RecordingExecutor {
+    fn pipeline(&self) -> &dyn ModulePipeline {
+        self.inner.pipeline()
+    }
+
+    fn pipeline_mut(&mut self) -> &mut dyn ModulePipeline {
+        self.inner.pipeline_mut()
+    }
+
+    fn execute_cache_ops(&mut self, ops: &CacheOps) -> Result<(), APIError> {
+        self.inner.execute_cache_ops(ops)
+    }
+
+    fn execute_model(&mut self, input: &ModelInput) -> Result<Tensor, APIError> {
+        let num_tokens = input.seqs.iter().map(|seq| seq.token_ids.len()).sum();
+        self.steps
+            .lock()
+            .unwrap()
+            .push((input.is_prompt, input.seqs.len(), num_tokens));
+        self.inner.execute_model(input)
+    }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.inner.get_num_gpu_blocks()
+    }
+
+    fn resize_gpu_cache(&mut self, num_blocks: usize) -> Result<(), APIError> {
+        self.inner.resize_gpu_cache(num_blocks)
+    }
+
+    fn export_blocks(&mut self, block_ids: &[usize]) -> Result<Vec<KVCache>, APIError> {
+        self.inner.export_blocks(block_ids)
+    }
+
+    fn check_blocks(&mut self, blocks: &[KVCache]) -> Result<(), APIError> {
+        self.inner.check_blocks(blocks)
+    }
+
+    fn import_blocks(&mut self, blocks: &[KVCache], block_ids: &[usize]) -> Result<(), APIError> {
+        self.inner.import_blocks(blocks, block_ids)
+    }
+
+    fn free_sequences(&mut self, seq_ids: &[usize]) {
+        self.inner.free_sequences(seq_ids)
+    }
+
+    fn release_weights(&mut self) {
+        self.inner.release_weights()
+    }
+
+    fn reload_weights(&mut self) -> Result<(), APIError> {
+        self.inner.reload_weights()
+    }
+
+    fn reset_decoder(&mut self) {
+        self.inner.reset_decoder()
+    }
+}
+
+/// Counts the steps of the engine.
+#[derive(Default)]
+struct Steps(Mutex<Vec<bool>>);
+
+impl EngineObserver for Steps {
+    fn on_step(&self, step: &StepEvent) {
+        self.0.lock().unwrap().push(step.is_prompt);
+    }
+}
+
+#[test]
+fn engine_drives_the_model_through_its_executor() {
+    let mut expected = TinyEngine::new(16);
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let steps = recorded.clone();
+    let mut engine = TinyEngine::with_executor(TinyEngine::cache_config(16), move |worker| {
+        Box::new(RecordingExecutor {
+            inner: LocalExecutor::new(worker),
+            steps,
+        })
+    });
+    let observed = Arc::new(Steps::default());
+    engine.add_observer(observed.clone());
+
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    assert_eq!(
+        engine.generate(&prompts, MAX_TOKENS),
+        expected.generate(&prompts, MAX_TOKENS)
+    );
+
+    // The model runs once per step of the engine, the prompts in a single prefill.
+    let recorded = recorded.lock().unwrap();
+    let observed = observed.0.lock().unwrap();
+    assert_eq!(
+        recorded
+            .iter()
+            .map(|&(is_prompt, _, _)| is_prompt)
+            .collect::<Vec<_>>(),
+        *observed
+    );
+    let prompt_tokens = prompts.iter().map(|prompt| prompt.len()).sum();
+    assert_eq!(recorded[0], (true, 2, prompt_tokens));
+    // A decode step runs the last token of each sequence.
+    for &(_, num_seqs, num_tokens) in &recorded[1..] {
+        assert_eq!(num_tokens, num_seqs);
+    }
+}
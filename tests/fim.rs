@@ -0,0 +1,131 @@
+use candle_vllm::openai::{
+    fim::FimTemplate,
+    requests::CompletionRequest,
+    responses::{ChatCompletionChunk, Choice, ChoiceData, CompletionChunk},
+};
+use std::str::FromStr;
+use tokenizers::Tokenizer;
+
+// Word level tokenizer over "<unk>", "def" and "f", with `added` as special tokens.
+fn tokenizer(added: &[&str]) -> Tokenizer {
+    let added_tokens = added
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            serde_json::json!({
+                "id": 3 + i,
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut vocab = serde_json::json!({"<unk>": 0, "def": 1, "f": 2});
+    for (i, token) in added.iter().enumerate() {
+        vocab[token] = (3 + i).into();
+    }
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<unk>"},
+    });
+    Tokenizer::from_str(&tokenizer.to_string()).unwrap()
+}
+
+#[test]
+fn builds_prefix_suffix_middle_prompts() {
+    assert_eq!(
+        FimTemplate::StarCoder.prompt("def f(", "):"),
+        "<fim_prefix>def f(<fim_suffix>):<fim_middle>"
+    );
+    assert_eq!(
+        FimTemplate::CodeLlama.prompt("def f(", "):"),
+        "<s>▁<PRE> def f(▁<SUF>):▁<MID>"
+    );
+    assert_eq!(
+        FimTemplate::DeepSeekCoder.prompt("def f(", "):"),
+        "<｜begin▁of▁sentence｜><｜fim▁begin｜>def f(<｜fim▁hole｜>):<｜fim▁end｜>"
+    );
+}
+
+#[test]
+fn detects_template_from_vocabulary() {
+    for template in [
+        FimTemplate::StarCoder,
+        FimTemplate::CodeLlama,
+        FimTemplate::DeepSeekCoder,
+    ] {
+        let tokenizer = tokenizer(&template.sentinels());
+        assert_eq!(FimTemplate::detect(&tokenizer), Some(template));
+    }
+    assert_eq!(FimTemplate::detect(&tokenizer(&[])), None);
+    // Every sentinel is needed.
+    assert_eq!(
+        FimTemplate::detect(&tokenizer(&["<fim_prefix>", "<fim_middle>"])),
+        None
+    );
+}
+
+#[test]
+fn sentinels_are_encoded_as_single_tokens() {
+    let tokenizer = tokenizer(&FimTemplate::StarCoder.sentinels());
+    let prompt = FimTemplate::StarCoder.prompt("def", "f");
+    let encoding = tokenizer.encode(prompt, false).unwrap();
+    assert_eq!(encoding.get_ids(), [3, 1, 4, 2, 5]);
+}
+
+#[test]
+fn parses_completion_requests() {
+    let request: CompletionRequest = serde_json::from_str(
+        r#"{"model": "starcoder", "prompt": "def f(", "suffix": "):", "max_tokens": 8}"#,
+    )
+    .unwrap();
+    assert_eq!(request.prompt, "def f(");
+    assert_eq!(request.suffix.as_deref(), Some("):"));
+    assert_eq!(request.max_tokens, Some(8));
+    assert_eq!(request.stream, None);
+
+    let request: CompletionRequest =
+        serde_json::from_str(r#"{"model": "starcoder", "prompt": "def f("}"#).unwrap();
+    assert_eq!(request.suffix, None);
+}
+
+#[test]
+fn chat_chunks_convert_to_text_completion_chunks() {
+    let chunk = ChatCompletionChunk {
+        id: "cmpl-0".to_string(),
+        choices: vec![Choice {
+            delta: ChoiceData {
+                content: Some("x, y".to_string()),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: Some("stop".to_string()),
+            index: 1,
+            logprobs: None,
+            moderation: Vec::new(),
+            token_ids: None,
+        }],
+        created: 7,
+        model: "starcoder".to_string(),
+        object: "chat.completion.chunk",
+        system_fingerprint: None,
+        usage: None,
+        prompt_token_ids: None,
+    };
+    let chunk = CompletionChunk::from(chunk);
+    assert_eq!(chunk.object, "text_completion");
+    assert_eq!(chunk.created, 7);
+    assert_eq!(chunk.choices[0].text, "x, y");
+    assert_eq!(chunk.choices[0].index, 1);
+    assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("stop"));
+}
@@ -0,0 +1,105 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use candle_core::DType;
+use candle_vllm::openai::{
+    fingerprint::{kernels, weights_digest, SystemFingerprint},
+    openai_server::completions,
+    responses::ChatResponder,
+};
+use serde_json::json;
+use std::{path::PathBuf, sync::Arc};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+fn fingerprint() -> SystemFingerprint {
+    SystemFingerprint {
+        model: "llama".to_string(),
+        weights: Some("0123456789abcdef".to_string()),
+        dtype: DType::BF16,
+        kv_cache_dtype: DType::BF16,
+        tensor_parallel_size: 1,
+        kernels: kernels(false),
+    }
+}
+
+#[test]
+fn fingerprints_change_with_the_serving_stack() {
+    let id = fingerprint().id();
+    assert!(id.starts_with("fp_") && id.len() == 13, "{id}");
+    assert_eq!(fingerprint().id(), id);
+    let changes = [
+        SystemFingerprint {
+            weights: None,
+            ..fingerprint()
+        },
+        SystemFingerprint {
+            kv_cache_dtype: DType::F16,
+            ..fingerprint()
+        },
+        SystemFingerprint {
+            tensor_parallel_size: 2,
+            ..fingerprint()
+        },
+        SystemFingerprint {
+            kernels: kernels(true),
+            ..fingerprint()
+        },
+    ];
+    for changed in changes {
+        assert_ne!(changed.id(), id, "{changed}");
+    }
+}
+
+fn weights_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "candle-vllm-fingerprint-{}-{name}",
+        std::process::id()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn weights_are_digested_by_their_files() {
+    let first = weights_file("model-00001.safetensors", b"first");
+    let second = weights_file("model-00002.safetensors", b"second");
+    let digest = weights_digest(&[first.clone(), second.clone()]);
+    // Shards listed once per tensor, in any order.
+    assert_eq!(
+        weights_digest(&[second.clone(), first.clone(), second.clone()]),
+        digest
+    );
+    assert_ne!(weights_digest(&[first.clone()]), digest);
+    std::fs::write(&second, b"second, retrained").unwrap();
+    assert_ne!(weights_digest(&[first, second]), digest);
+}
+
+#[test]
+fn responses_carry_the_fingerprint_of_the_engine() {
+    let mut engine = TinyEngine::new(16);
+    let system_fingerprint = engine.system_fingerprint();
+    let prompt = engine.encode("t5 t9 t17");
+    for chunk in engine.stream(&[prompt], 1, 4).remove(0) {
+        assert_eq!(chunk.system_fingerprint.as_ref(), Some(&system_fingerprint));
+    }
+
+    let data = Arc::new(engine.server_data(None));
+    let request = json!({
+        "model": "llama",
+        "prompt": "t5 t9 t17",
+        "temperature": 0.0,
+        "max_tokens": 4,
+    });
+    let request = serde_json::from_value(request).unwrap();
+    let response = Runtime::new().unwrap().block_on(async {
+        completions(State(data.clone()), HeaderMap::new(), Ok(Json(request))).await
+    });
+    let ChatResponder::TextCompletion(response, _) = response else {
+        panic!("the request failed");
+    };
+    assert_eq!(response.system_fingerprint, Some(system_fingerprint));
+}
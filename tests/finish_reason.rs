@@ -0,0 +1,100 @@
+use candle_vllm::openai::{
+    requests::{StopTokens, Tool},
+    stop_checker::StopChecker,
+    tool_calls::{finish_choice, parse_tool_calls},
+};
+
+fn checker(stop: &[&str], tools: bool) -> StopChecker {
+    let stop = StopTokens::Multi(stop.iter().map(|stop| stop.to_string()).collect());
+    StopChecker::new(Some(&stop), tools)
+}
+
+#[test]
+fn the_output_ends_before_the_first_stop_string() {
+    let checker = checker(&["END", "\n\n"], false);
+    assert_eq!(checker.find_stop("one\n\ntwo END"), Some(3));
+    assert_eq!(checker.find_stop("one END\n\n"), Some(4));
+    assert_eq!(checker.find_stop("one EN"), None);
+    assert!(StopChecker::new(None, false).is_empty());
+    assert!(!checker.is_empty());
+}
+
+#[test]
+fn what_may_become_a_stop_string_is_held_back() {
+    let checker = checker(&["END"], false);
+    assert_eq!(checker.streamable("one E"), 4);
+    assert_eq!(checker.streamable("one EN"), 4);
+    assert_eq!(checker.streamable("one ENx"), 7);
+    assert_eq!(checker.streamable("é"), 2);
+}
+
+#[test]
+fn tool_calls_are_held_back() {
+    let checker = checker(&[], true);
+    assert_eq!(checker.streamable("Let me check. <tool"), 14);
+    assert_eq!(
+        checker.streamable("Let me check. <tool_call>\n{\"name\""),
+        14
+    );
+}
+
+fn tools() -> Vec<String> {
+    vec!["get_weather".to_string()]
+}
+
+#[test]
+fn tool_calls_are_parsed_after_the_content() {
+    let text = "Checking.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \
+                \"Paris\"}}\n</tool_call>\n<tool_call>{\"name\": \"get_weather\", \"arguments\": \
+                {\"city\": \"Oslo\"}}";
+    let (content, calls) = parse_tool_calls(text, &tools()).unwrap();
+    assert_eq!(content, "Checking.");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].function.name, "get_weather");
+    assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+    assert_eq!(calls[1].function.arguments, r#"{"city":"Oslo"}"#);
+    assert_eq!(calls[0].call_type, "function");
+    assert_ne!(calls[0].id, calls[1].id);
+}
+
+#[test]
+fn only_calls_of_the_tools_of_the_request_are_parsed() {
+    let unknown = r#"<tool_call>{"name": "rm", "arguments": {}}</tool_call>"#;
+    assert!(parse_tool_calls(unknown, &tools()).is_none());
+    let not_json = "<tool_call>get_weather(Paris)</tool_call>";
+    assert!(parse_tool_calls(not_json, &tools()).is_none());
+    let trailing = r#"<tool_call>{"name": "get_weather"}</tool_call> Done."#;
+    assert!(parse_tool_calls(trailing, &tools()).is_none());
+    assert!(parse_tool_calls("It is sunny.", &tools()).is_none());
+}
+
+#[test]
+fn choices_that_called_tools_finish_with_tool_calls() {
+    let text = r#"<tool_call>{"name": "get_weather", "arguments": {}}</tool_call>"#;
+    let (content, calls, reason) = finish_choice(text.to_string(), &tools(), "stop");
+    assert_eq!(content, None);
+    assert_eq!(calls.unwrap()[0].function.arguments, "{}");
+    assert_eq!(reason, "tool_calls");
+
+    // Calls cut off by the token limit are left as text.
+    let (content, calls, reason) = finish_choice(text.to_string(), &tools(), "length");
+    assert_eq!(content.as_deref(), Some(text));
+    assert!(calls.is_none());
+    assert_eq!(reason, "length");
+
+    let (content, calls, reason) = finish_choice(text.to_string(), &[], "stop");
+    assert_eq!(content.as_deref(), Some(text));
+    assert!(calls.is_none());
+    assert_eq!(reason, "stop");
+}
+
+#[test]
+fn tools_are_functions() {
+    let tools: Vec<Tool> = serde_json::from_str(
+        r#"[{"type": "function", "function": {"name": "get_weather", "parameters": {}}}]"#,
+    )
+    .unwrap();
+    let Tool::Function { function } = &tools[0];
+    assert_eq!(function.name, "get_weather");
+    assert!(serde_json::from_str::<Vec<Tool>>(r#"[{"type": "retrieval"}]"#).is_err());
+}
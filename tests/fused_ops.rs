@@ -0,0 +1,76 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::backend::{fused_add_rms_norm, rms_norm, rotary_embedding, silu_and_mul};
+
+const EPS: f32 = 1e-5;
+
+fn tensor(values: &[f32], shape: &[usize]) -> Tensor {
+    Tensor::from_slice(values, shape, &Device::Cpu).unwrap()
+}
+
+fn assert_close(actual: &Tensor, expected: &[f32]) {
+    let actual = actual.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-5, "{actual:?} != {expected:?}");
+    }
+}
+
+#[test]
+fn rms_norm_scales_each_row_by_its_root_mean_square() {
+    let x = tensor(&[3., 4., 0., 0., 1., 1., 1., 1.], &[2, 4]);
+    let weight = tensor(&[1., 2., 1., 0.5], &[4]);
+    // The rows have a root mean square of 2.5 and 1.
+    let expected = [1.2, 3.2, 0., 0., 1., 2., 1., 0.5];
+    assert_close(&rms_norm(&x, &weight, 0.).unwrap(), &expected);
+
+    // The residual is added before the norm, and the sum comes back as well.
+    let half = (&x / 2.).unwrap();
+    let (normed, sum) = fused_add_rms_norm(&half, &half, &weight, 0.).unwrap();
+    assert_close(&normed, &expected);
+    assert_close(&sum, &x.flatten_all().unwrap().to_vec1::<f32>().unwrap());
+}
+
+#[test]
+fn fused_ops_match_the_candle_ops_they_replace() {
+    // The host runs the candle ops themselves, a GPU the fused kernels.
+    let device = Device::cuda_if_available(0).unwrap();
+    let x = Tensor::randn(0f32, 1., (2, 3, 16), &device).unwrap();
+    let residual = Tensor::randn(0f32, 1., (2, 3, 16), &device).unwrap();
+    let weight = Tensor::randn(0f32, 1., 16, &device).unwrap();
+
+    let sum = (&x + &residual).unwrap();
+    let expected = candle_nn::ops::rms_norm(&sum, &weight, EPS).unwrap();
+    let (normed, fused_sum) = fused_add_rms_norm(&x, &residual, &weight, EPS).unwrap();
+    let expected = expected.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+    assert_close(&normed, &expected);
+    assert_close(
+        &fused_sum,
+        &sum.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+    );
+
+    let expected = (candle_nn::ops::silu(&x).unwrap() * &residual).unwrap();
+    assert_close(
+        &silu_and_mul(&x, &residual).unwrap(),
+        &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+    );
+}
+
+#[test]
+fn rotary_embedding_rotates_each_entry_from_its_position() {
+    // Heads of size 2 rotate by the angle of their position: 0, 90, 180 and 270 degrees.
+    let cos = tensor(&[1., 0., -1., 0.], &[4, 1]);
+    let sin = tensor(&[0., 1., 0., -1.], &[4, 1]);
+    // A batch of two entries, one head of two tokens each.
+    let q = tensor(&[1., 0., 1., 0., 1., 2., 1., 2.], &[2, 1, 2, 2]);
+    let k = (&q * 2.).unwrap();
+    let (q, k) = rotary_embedding(&q, &k, &cos, &sin, &[vec![0, 1], vec![2, 3]]).unwrap();
+    // (x1, x2) becomes (x1 cos - x2 sin, x1 sin + x2 cos).
+    let expected = [1., 0., 0., 1., -1., -2., 2., -1.];
+    assert_close(&q, &expected);
+    assert_close(&k, &expected.map(|v| v * 2.));
+
+    // Every position has to have its angles.
+    let q = tensor(&[1., 0., 1., 0.], &[1, 1, 2, 2]);
+    assert!(rotary_embedding(&q, &q, &cos, &sin, &[vec![3, 4]]).is_err());
+    assert!(rotary_embedding(&q, &q, &cos, &sin, &[vec![0], vec![0]]).is_err());
+}
@@ -0,0 +1,128 @@
+use candle_core::{DType, Device};
+use candle_vllm::{
+    get_model_loader, get_model_paths,
+    openai::{pipelines::weights::DEFAULT_WEIGHT_BUFFER_MEM, PipelineConfig},
+    scheduler::cache_engine::CacheConfig,
+    ModelSelected,
+};
+use std::path::{Path, PathBuf};
+
+mod common;
+use common::{
+    cache_config,
+    tiny_model::{tiny_llama_dir, TinyEngine},
+};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 16;
+
+fn llama(temperature: Option<f32>) -> ModelSelected {
+    ModelSelected::Llama {
+        repeat_last_n: None,
+        temperature,
+        penalty: None,
+        max_gen_tokens: None,
+    }
+}
+
+/// The tiny llama with `generation_config`, if any.
+fn checkpoint(name: &str, generation_config: Option<serde_json::Value>) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for file in ["config.json", "tokenizer.json", "model.safetensors"] {
+        std::fs::copy(tiny_llama_dir().join(file), dir.join(file)).unwrap();
+    }
+    if let Some(generation_config) = generation_config {
+        std::fs::write(
+            dir.join("generation_config.json"),
+            generation_config.to_string(),
+        )
+        .unwrap();
+    }
+    dir
+}
+
+fn pipeline_config(model: ModelSelected, dir: &Path) -> PipelineConfig {
+    let (loader, model_id) = get_model_loader(model, None);
+    let weight_path = format!("{}/", dir.display());
+    let paths = get_model_paths(&*loader, model_id, Some(&weight_path), None, None).unwrap();
+    let (_, pipeline_config) = loader
+        .load_model(paths, DType::F32, Device::Cpu, DEFAULT_WEIGHT_BUFFER_MEM)
+        .unwrap();
+    pipeline_config
+}
+
+#[test]
+fn request_defaults_come_from_the_generation_config() {
+    let dir = checkpoint(
+        "candle-vllm-generation-config",
+        Some(serde_json::json!({
+            "do_sample": true,
+            "temperature": 0.6,
+            "top_p": 0.9,
+            "max_length": 200,
+            "max_new_tokens": 150,
+            "eos_token_id": [2, 9],
+        })),
+    );
+    let config = pipeline_config(llama(None), &dir);
+    assert_eq!(config.temperature, 0.6);
+    assert_eq!(config.top_p, 0.9);
+    assert_eq!(config.top_k, -1);
+    // Capped by the 256 positions of the model.
+    assert_eq!(config.max_model_len, 200);
+    assert_eq!(config.default_max_tokens, 150);
+    assert_eq!(config.top_p_top_k(0.6), (0.9, -1));
+    // Greedy requests do not take the default top_p.
+    assert_eq!(config.top_p_top_k(0.), (1.0, -1));
+
+    // Arguments given to the server take precedence.
+    assert_eq!(pipeline_config(llama(Some(0.2)), &dir).temperature, 0.2);
+
+    let greedy = checkpoint(
+        "candle-vllm-generation-config-greedy",
+        Some(serde_json::json!({"do_sample": false, "temperature": 0.6, "max_length": 4096})),
+    );
+    let config = pipeline_config(llama(None), &greedy);
+    assert_eq!(config.temperature, 0.);
+    assert_eq!(config.max_model_len, 256);
+}
+
+#[test]
+fn defaults_without_a_generation_config() {
+    let dir = checkpoint("candle-vllm-no-generation-config", None);
+    let config = pipeline_config(llama(None), &dir);
+    assert_eq!(config.temperature, 0.7);
+    assert_eq!((config.top_p, config.top_k), (1.0, -1));
+    assert_eq!(config.max_model_len, 256);
+}
+
+#[test]
+fn generation_stops_at_every_eos_of_the_generation_config() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(&[prompt], MAX_TOKENS).remove(0);
+    // EOS only ends a sequence after its second token.
+    let stop = (2..expected.len())
+        .find(|&i| !expected[..i].contains(&expected[i]))
+        .unwrap();
+
+    let dir = checkpoint(
+        "candle-vllm-generation-config-eos",
+        Some(serde_json::json!({"eos_token_id": [2, expected[stop]]})),
+    );
+    let mut engine = TinyEngine::load(
+        llama(Some(0.)),
+        &dir,
+        CacheConfig {
+            dtype: DType::F32,
+            ..cache_config(16)
+        },
+    )
+    .unwrap();
+    let prompt = engine.encode(PROMPT);
+    assert_eq!(
+        engine.generate(&[prompt], MAX_TOKENS).remove(0),
+        expected[..stop]
+    );
+}
@@ -0,0 +1,55 @@
+use candle_vllm::{openai::sampling_params::SamplingStage, ModelSelected};
+
+mod common;
+use common::{
+    sampling_params,
+    tiny_model::{tiny_llama_dir, TinyEngine},
+};
+
+const PROMPTS: [&str; 2] = [
+    "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60",
+    "t3 t1 t4 t1 t5 t9 t2 t6 t5 t3",
+];
+const MAX_TOKENS: usize = 16;
+
+#[test]
+fn requests_at_temperature_zero_or_top_k_one_are_greedy() {
+    let mut params = sampling_params();
+    assert!(!params.is_greedy());
+    params.top_k = 1;
+    assert!(params.is_greedy());
+    params.top_k = -1;
+    params.temperature = 0.0;
+    assert!(params.is_greedy());
+    // A schedule samples by its stages.
+    params
+        .set_sampling_schedule(Some(vec![SamplingStage {
+            until: None,
+            temperature: Some(1.0),
+            top_p: None,
+            anneal: false,
+        }]))
+        .unwrap();
+    assert!(!params.is_greedy());
+}
+
+#[test]
+fn greedy_requests_ignore_the_sampling_of_the_server() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+
+    // The requests of the engine are at a temperature of 0.
+    let model = ModelSelected::Llama {
+        repeat_last_n: None,
+        temperature: Some(1.5),
+        penalty: Some(1.),
+        max_gen_tokens: None,
+    };
+    let mut engine =
+        TinyEngine::load(model, tiny_llama_dir(), TinyEngine::cache_config(16)).unwrap();
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+    // Along with a seq whose logits are processed on the host.
+    engine.min_tokens = Some(4);
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+}
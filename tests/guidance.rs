@@ -0,0 +1,108 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::openai::guidance::{guide_logits, DEFAULT_GUIDANCE_SCALE};
+
+mod common;
+use common::{sampling_params, tiny_model::TinyEngine};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const NEGATIVE_PROMPT: &str = "t20 t21 t22 t23";
+const MAX_TOKENS: usize = 12;
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let sum = logits.iter().map(|x| x.exp()).sum::<f32>();
+    logits.iter().map(|x| x - sum.ln()).collect()
+}
+
+#[test]
+fn log_probs_are_pushed_away_from_the_negative_prompt() {
+    let logits = [2.0f32, 1.0, 0.0];
+    let negative = [0.0f32, 3.0, 0.0];
+    let guided = guide_logits(
+        &Tensor::new(&[logits], &Device::Cpu).unwrap(),
+        &Tensor::new(&[negative], &Device::Cpu).unwrap(),
+        2.0,
+    )
+    .unwrap()
+    .squeeze(0)
+    .unwrap()
+    .to_vec1::<f32>()
+    .unwrap();
+    let (logits, negative) = (log_softmax(&logits), log_softmax(&negative));
+    for i in 0..3 {
+        let expected = negative[i] + 2.0 * (logits[i] - negative[i]);
+        assert!((guided[i] - expected).abs() < 1e-5, "{guided:?}");
+    }
+}
+
+#[test]
+fn guidance_needs_a_negative_prompt_and_a_positive_scale() {
+    let mut params = sampling_params();
+    assert!(params.set_guidance(None, Some(2.0)).is_err());
+    assert!(params.set_guidance(Some(vec![1]), Some(0.0)).is_err());
+    assert!(params.set_guidance(Some(vec![1]), Some(f32::NAN)).is_err());
+    params.set_guidance(Some(vec![1]), None).unwrap();
+    assert_eq!(params.guidance_scale, DEFAULT_GUIDANCE_SCALE);
+
+    let mut params = sampling_params();
+    params
+        .set_conversation_id(Some("chat".to_string()))
+        .unwrap();
+    assert!(params.set_guidance(Some(vec![1]), None).is_err());
+}
+
+#[test]
+fn scale_of_one_ignores_the_negative_prompt() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(&[prompt.clone()], MAX_TOKENS);
+
+    engine.negative_prompt = Some(engine.encode(NEGATIVE_PROMPT).get_ids().to_vec());
+    engine.guidance_scale = Some(1.0);
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), expected);
+}
+
+#[test]
+fn prompt_as_its_own_negative_prompt_changes_nothing() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(&[prompt.clone()], MAX_TOKENS);
+
+    engine.negative_prompt = Some(prompt.get_ids().to_vec());
+    engine.guidance_scale = Some(3.0);
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), expected);
+}
+
+#[test]
+fn guided_requests_batch_with_unguided_ones() {
+    let mut engine = TinyEngine::new(8);
+    let prompts = [engine.encode(PROMPT), engine.encode(NEGATIVE_PROMPT)];
+    let expected = engine.generate(&prompts[1..], MAX_TOKENS).remove(0);
+
+    engine.negative_prompt = Some(Vec::new());
+    engine.guidance_scale = Some(2.0);
+    engine.submit(&prompts[..1], MAX_TOKENS);
+    engine.negative_prompt = None;
+    engine.guidance_scale = None;
+    let generated = engine.generate(&prompts[1..], MAX_TOKENS).remove(0);
+    assert_eq!(generated, expected);
+
+    // The sequences of the negative prompt free their blocks with their choice.
+    let snapshot = engine.scheduler_snapshot();
+    assert!(snapshot.running.is_empty());
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+}
+
+#[test]
+fn negative_sequences_are_not_choices() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.negative_prompt = Some(engine.encode(NEGATIVE_PROMPT).get_ids().to_vec());
+    let chunks = engine.stream(&[prompt], 2, MAX_TOKENS).remove(0);
+    let mut indices = chunks
+        .iter()
+        .map(|chunk| chunk.choices[0].index)
+        .collect::<Vec<_>>();
+    indices.sort();
+    indices.dedup();
+    assert_eq!(indices, [0, 1]);
+}
@@ -0,0 +1,69 @@
+use candle_vllm::openai::guided_choice::ChoiceTrie;
+
+mod common;
+use common::{sampling_params, tiny_model::TinyEngine};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 12;
+
+fn choices(choices: &[&str]) -> Option<Vec<String>> {
+    Some(choices.iter().map(|choice| choice.to_string()).collect())
+}
+
+#[test]
+fn trie_allows_the_tokens_continuing_a_choice() {
+    let trie = ChoiceTrie::new(&[vec![3, 4], vec![3, 5, 6], vec![7], vec![7, 8]]);
+    assert_eq!(trie.next_tokens(&[]), [3, 7]);
+    assert_eq!(trie.next_tokens(&[3]), [4, 5]);
+    assert_eq!(trie.next_tokens(&[3, 5]), [6]);
+    assert!(trie.next_tokens(&[3, 4]).is_empty());
+    assert!(trie.next_tokens(&[9]).is_empty());
+
+    assert!(!trie.is_choice(&[3]));
+    assert!(trie.is_choice(&[3, 4]));
+    assert!(trie.is_choice(&[3, 5, 6]));
+    // A choice can be the prefix of another.
+    assert!(trie.is_choice(&[7]));
+    assert_eq!(trie.next_tokens(&[7]), [8]);
+}
+
+#[test]
+fn rejects_empty_choices_and_token_healing() {
+    let mut params = sampling_params();
+    assert!(params.set_guided_choice(choices(&[])).is_err());
+    assert!(params.set_guided_choice(choices(&["a", ""])).is_err());
+    assert!(params.set_guided_choice(choices(&["a", "b"])).is_ok());
+    assert!(params.set_guided_choice(None).is_ok());
+
+    params.token_healing = true;
+    assert!(params.set_guided_choice(choices(&["a", "b"])).is_err());
+}
+
+#[test]
+fn output_is_one_of_the_choices() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.guided_choice = choices(&["t20 t21 t22", "t30 t31", "t30 t32"]);
+    let generated = engine.generate(&[prompt], MAX_TOKENS).remove(0);
+    assert!(
+        [vec![20, 21, 22], vec![30, 31], vec![30, 32]].contains(&generated),
+        "{generated:?}"
+    );
+}
+
+#[test]
+fn single_choice_is_forced_and_stops() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.guided_choice = choices(&["t20 t21 t22"]);
+    let chunks = engine.stream(&[prompt], 1, MAX_TOKENS).remove(0);
+    let text = chunks
+        .iter()
+        .filter_map(|chunk| chunk.choices[0].delta.content.clone())
+        .collect::<String>();
+    assert_eq!(text, "t20t21t22");
+    assert_eq!(
+        chunks.last().unwrap().choices[0].finish_reason.as_deref(),
+        Some("stop")
+    );
+}
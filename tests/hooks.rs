@@ -0,0 +1,115 @@
+use candle_vllm::openai::{
+    hooks::{EngineObserver, StepEvent, TokenAction, TokenEvent},
+    responses::{ChatChoice, ChatCompletionUsageResponse},
+};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 8;
+
+/// Request id, finish reasons and completion tokens of a finished request.
+type Finished = (String, Vec<Option<String>>, usize);
+
+/// Records what it is called with, and stops every choice after `stop_after` tokens.
+#[derive(Default)]
+struct Recorder {
+    stop_after: Option<usize>,
+    tokens: Mutex<Vec<(String, usize)>>,
+    steps: Mutex<Vec<StepEvent>>,
+    finished: Mutex<Vec<Finished>>,
+}
+
+impl Recorder {
+    fn tokens_of(&self, request_id: &str) -> Vec<usize> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| id == request_id)
+            .map(|(_, token)| *token)
+            .collect()
+    }
+}
+
+impl EngineObserver for Recorder {
+    fn on_token(&self, token: &TokenEvent) -> TokenAction {
+        let seen = self.tokens_of(token.request_id).len();
+        if self.stop_after.is_some_and(|stop_after| seen >= stop_after) {
+            return TokenAction::Stop("content_filter".to_string());
+        }
+        self.tokens
+            .lock()
+            .unwrap()
+            .push((token.request_id.to_string(), token.logprobs.token));
+        TokenAction::Continue
+    }
+
+    fn on_step(&self, step: &StepEvent) {
+        self.steps.lock().unwrap().push(step.clone());
+    }
+
+    fn on_finish(
+        &self,
+        request_id: &str,
+        choices: &[ChatChoice],
+        usage: &ChatCompletionUsageResponse,
+    ) {
+        let finish_reasons = choices
+            .iter()
+            .map(|choice| choice.finish_reason.clone())
+            .collect();
+        self.finished.lock().unwrap().push((
+            request_id.to_string(),
+            finish_reasons,
+            usage.completion_tokens,
+        ));
+    }
+}
+
+#[test]
+fn observer_sees_every_token_step_and_request() {
+    let mut engine = TinyEngine::new(16);
+    let recorder = Arc::new(Recorder::default());
+    engine.add_observer(recorder.clone());
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let outputs = engine.generate(&prompts, MAX_TOKENS);
+
+    assert_eq!(recorder.tokens_of("tiny-0"), outputs[0]);
+    assert_eq!(recorder.tokens_of("tiny-1"), outputs[1]);
+    let steps = recorder.steps.lock().unwrap();
+    assert!(steps[0].is_prompt);
+    assert_eq!(steps[0].request_ids, ["tiny-0", "tiny-1"]);
+    assert_eq!(
+        steps.iter().map(|step| step.num_tokens).sum::<usize>(),
+        outputs.iter().map(Vec::len).sum::<usize>()
+    );
+    let mut finished = recorder.finished.lock().unwrap().clone();
+    finished.sort();
+    assert_eq!(
+        finished
+            .iter()
+            .map(|(request_id, _, completion_tokens)| (request_id.as_str(), *completion_tokens))
+            .collect::<Vec<_>>(),
+        [("tiny-0", outputs[0].len()), ("tiny-1", outputs[1].len())]
+    );
+}
+
+#[test]
+fn observer_stops_a_choice() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPTS[0]);
+    let unobserved = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+
+    let recorder = Arc::new(Recorder {
+        stop_after: Some(3),
+        ..Recorder::default()
+    });
+    engine.add_observer(recorder.clone());
+    let stopped = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+    assert_eq!(stopped[0], unobserved[0][..3]);
+    let finished = recorder.finished.lock().unwrap();
+    assert_eq!(finished[0].1, [Some("content_filter".to_string())]);
+}
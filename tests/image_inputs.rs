@@ -0,0 +1,114 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::{
+    image_processor::{ImageProcessor, OPENAI_CLIP_MEAN, OPENAI_CLIP_STD},
+    models::llava::merge_image_features,
+    requests::{ChatCompletionRequest, ContentPart, MessageContent, Messages},
+};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+fn data_url(image: &DynamicImage) -> String {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    format!("data:image/png;base64,{}", STANDARD.encode(png))
+}
+
+#[test]
+fn parses_image_url_content_parts() {
+    let request: ChatCompletionRequest = serde_json::from_str(
+        r#"{
+            "model": "llava",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is in this image?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+                ]}
+            ]
+        }"#,
+    )
+    .unwrap();
+    let Messages::Map(messages) = request.messages else {
+        panic!("expected a list of messages");
+    };
+    assert!(matches!(&messages[0]["content"], MessageContent::Text(text) if text == "Be brief."));
+    let MessageContent::Parts(parts) = &messages[1]["content"] else {
+        panic!("expected content parts");
+    };
+    assert!(matches!(&parts[0], ContentPart::Text { text } if text == "What is in this image?"));
+    assert!(
+        matches!(&parts[1], ContentPart::ImageUrl { image_url } if image_url.url == "data:image/png;base64,AAAA")
+    );
+}
+
+#[test]
+fn loads_base64_data_urls_only() {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(5, 3, Rgb([10, 20, 30])));
+    let loaded = ImageProcessor::load_image_url(&data_url(&image)).unwrap();
+    assert_eq!(loaded.to_rgb8(), image.to_rgb8());
+
+    assert!(ImageProcessor::load_image_url("https://example.com/cat.png").is_err());
+    assert!(ImageProcessor::load_image_url("data:image/png;base64,not-base64!").is_err());
+}
+
+#[test]
+fn preprocess_crops_and_normalizes() {
+    let processor = ImageProcessor::new(8, "<image>".to_string(), 32000, 4);
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(24, 12, Rgb([255, 0, 128])));
+    let pixels = processor
+        .preprocess(&image, DType::F32, &Device::Cpu)
+        .unwrap();
+    assert_eq!(pixels.dims(), [3, 8, 8]);
+
+    for (channel, value) in [255.0, 0.0, 128.0].into_iter().enumerate() {
+        let expected = (value / 255.0 - OPENAI_CLIP_MEAN[channel]) / OPENAI_CLIP_STD[channel];
+        let values = pixels
+            .get(channel)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(
+            values.iter().all(|v| (v - expected).abs() < 1e-4),
+            "{values:?}"
+        );
+    }
+}
+
+#[test]
+fn expands_image_placeholders() {
+    let processor = ImageProcessor::new(336, "<image>".to_string(), 9, 3);
+    assert_eq!(
+        processor.expand_image_tokens(&[1, 9, 5, 9]),
+        [1, 9, 9, 9, 5, 9, 9, 9]
+    );
+    assert_eq!(processor.expand_image_tokens(&[1, 5]), [1, 5]);
+}
+
+#[test]
+fn image_features_replace_placeholder_embeddings() {
+    let device = Device::Cpu;
+    // Two sequences of 4 tokens with embeddings of size 2, the first with two image tokens.
+    let embeds = Tensor::arange(0f32, 16., &device)
+        .unwrap()
+        .reshape((2, 4, 2))
+        .unwrap();
+    let input_ids = Tensor::new(&[[5i64, 9, 9, 6], [9, 7, 8, 0]], &device).unwrap();
+    let features = Tensor::new(&[[[-1f32, -2.], [-3., -4.]]], &device).unwrap();
+
+    let merged = merge_image_features(&embeds, &input_ids, &[Some(features), None], 9).unwrap();
+    assert_eq!(
+        merged.to_vec3::<f32>().unwrap(),
+        [
+            [[0., 1.], [-1., -2.], [-3., -4.], [6., 7.]],
+            [[8., 9.], [10., 11.], [12., 13.], [14., 15.]],
+        ]
+    );
+
+    let too_few = Tensor::new(&[[[-1f32, -2.]]], &device).unwrap();
+    assert!(merge_image_features(&embeds, &input_ids, &[Some(too_few), None], 9).is_err());
+}
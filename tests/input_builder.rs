@@ -0,0 +1,177 @@
+use candle_core::Device;
+use candle_vllm::{
+    openai::pipelines::{
+        input_builder::{InputBuilder, PAD_SLOT_ID},
+        worker::SequenceInput,
+    },
+    scheduler::draft_tree::DraftTree,
+};
+
+/// The tokens of sequence `seq_id` from `position` on, of which the last `num_sampled_tokens`
+/// are sampled.
+fn seq(
+    seq_id: usize,
+    token_ids: Vec<usize>,
+    position: usize,
+    block_table: Option<Vec<usize>>,
+    num_sampled_tokens: usize,
+) -> SequenceInput {
+    let positions = (position..position + token_ids.len()).collect::<Vec<_>>();
+    SequenceInput {
+        seq_id,
+        group_id: seq_id,
+        pixel_values: None,
+        token_ids,
+        cache_indices: positions.clone(),
+        positions,
+        block_table,
+        num_sampled_tokens,
+        draft_tree: None,
+        state_slot: None,
+        control_vectors: Vec::new(),
+    }
+}
+
+fn builder(sliding_window: Option<usize>) -> InputBuilder {
+    InputBuilder::new(8, sliding_window, Device::Cpu)
+}
+
+#[test]
+fn prompts_are_padded_to_the_longest() {
+    let seqs = [
+        seq(0, (1..=10).collect(), 0, Some(vec![3, 5]), 1),
+        seq(1, vec![7, 8, 9, 10], 0, Some(vec![7]), 1),
+    ];
+    let inputs = builder(None).prepare_prompt(&seqs).unwrap();
+    assert_eq!(
+        inputs.tokens.to_vec2::<i64>().unwrap(),
+        [
+            (1..=10i64).collect::<Vec<_>>(),
+            vec![7, 8, 9, 10, 0, 0, 0, 0, 0, 0]
+        ]
+    );
+    let pad = PAD_SLOT_ID;
+    assert_eq!(
+        inputs.metadata.slot_mapping.to_vec2::<i64>().unwrap(),
+        [
+            vec![24, 25, 26, 27, 28, 29, 30, 31, 40, 41],
+            vec![56, 57, 58, 59, pad, pad, pad, pad, pad, pad]
+        ]
+    );
+    assert_eq!(inputs.metadata.prompt_lens, [10, 4]);
+    assert_eq!(inputs.metadata.seq_ids, [0, 1]);
+    assert!(inputs.metadata.is_prompt);
+    assert!(inputs.logits_rows.is_none());
+}
+
+#[test]
+fn prompts_without_blocks_write_no_slot() {
+    // Sequences have no block table while the memory is profiled.
+    let inputs = builder(None)
+        .prepare_prompt(&[seq(0, vec![1, 2, 3], 0, None, 1)])
+        .unwrap();
+    assert_eq!(
+        inputs.metadata.slot_mapping.to_vec2::<i64>().unwrap(),
+        [[PAD_SLOT_ID; 3]]
+    );
+}
+
+#[test]
+fn decode_has_a_row_per_token() {
+    let seqs = [
+        seq(0, vec![11], 10, Some(vec![3, 5]), 1),
+        // A token missing from the cache followed by two proposed tokens, all verified.
+        seq(1, vec![5, 6, 7], 4, Some(vec![7]), 3),
+    ];
+    let inputs = builder(None).prepare_decode(&seqs).unwrap();
+    assert_eq!(
+        inputs.tokens.to_vec2::<i64>().unwrap(),
+        [[11], [5], [6], [7]]
+    );
+    assert_eq!(inputs.positions, [[10], [4], [5], [6]]);
+    assert_eq!(
+        inputs.metadata.slot_mapping.to_vec2::<i64>().unwrap(),
+        [[42], [60], [61], [62]]
+    );
+    let metadata = &inputs.metadata;
+    let context_lens = metadata.context_lens.as_ref().unwrap();
+    assert_eq!(context_lens.to_vec1::<u32>().unwrap(), [11, 5, 6, 7]);
+    assert_eq!(metadata.max_context_len, Some(11));
+    // Block tables are padded to the longest one.
+    let block_tables = metadata.block_tables.as_ref().unwrap();
+    assert_eq!(
+        block_tables.to_vec2::<u32>().unwrap(),
+        [[3, 5], [7, 0], [7, 0], [7, 0]]
+    );
+    assert_eq!(metadata.seq_ids, [0, 1]);
+    // Every row is sampled.
+    assert!(inputs.logits_rows.is_none());
+}
+
+#[test]
+fn only_the_sampled_rows_are_returned() {
+    let seqs = [
+        // The rest of a cached prompt, only its last token is sampled.
+        seq(0, vec![1, 2, 3], 8, Some(vec![3, 5]), 1),
+        seq(1, vec![4], 2, Some(vec![7]), 1),
+    ];
+    let inputs = builder(None).prepare_decode(&seqs).unwrap();
+    assert_eq!(inputs.logits_rows, Some(vec![2, 3]));
+}
+
+#[test]
+fn rows_attend_their_own_window() {
+    let table = vec![1, 2, 3, 4, 5, 6];
+    let inputs = builder(Some(16))
+        .prepare_decode(&[seq(0, vec![1, 2], 39, Some(table), 1)])
+        .unwrap();
+    // The token is written to its slot in the whole table, but only the blocks from the one
+    // holding the first token of its window are attended.
+    assert_eq!(
+        inputs.metadata.slot_mapping.to_vec2::<i64>().unwrap(),
+        [[47], [48]]
+    );
+    let metadata = &inputs.metadata;
+    let context_lens = metadata.context_lens.as_ref().unwrap();
+    assert_eq!(context_lens.to_vec1::<u32>().unwrap(), [16, 17]);
+    let block_tables = metadata.block_tables.as_ref().unwrap();
+    assert_eq!(
+        block_tables.to_vec2::<u32>().unwrap(),
+        [[4, 5, 0], [4, 5, 6]]
+    );
+}
+
+#[test]
+fn draft_tree_nodes_attend_their_ancestors() {
+    // Nodes 1 and 2 continue the last token, node 3 continues node 1.
+    let tree = DraftTree::new(vec![21, 22, 23], vec![0, 0, 1]).unwrap();
+    let mut tree_seq = seq(0, vec![11, 21, 22, 23], 10, Some(vec![3, 5]), 4);
+    tree_seq.positions = vec![10, 11, 11, 12];
+    tree_seq.draft_tree = Some(tree);
+    let seqs = [seq(1, vec![4], 2, Some(vec![7]), 1), tree_seq];
+    let inputs = builder(None).prepare_decode(&seqs).unwrap();
+    assert_eq!(inputs.positions, [[2], [10], [11], [11], [12]]);
+    // Each node has a slot of its own after the last token.
+    assert_eq!(
+        inputs.metadata.slot_mapping.to_vec2::<i64>().unwrap(),
+        [[58], [42], [43], [44], [45]]
+    );
+    let [draft_tree] = &inputs.metadata.draft_trees[..] else {
+        panic!("a single draft tree was expected");
+    };
+    assert_eq!(draft_tree.first_row, 1);
+    assert_eq!(draft_tree.context_len, 10);
+    assert_eq!(draft_tree.block_table, [3, 5]);
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(
+        draft_tree.mask.to_vec2::<f32>().unwrap(),
+        [
+            [0., inf, inf, inf],
+            [0., 0., inf, inf],
+            [0., inf, 0., inf],
+            [0., 0., inf, 0.]
+        ]
+    );
+
+    assert!(builder(Some(16)).prepare_decode(&seqs).is_err());
+}
@@ -0,0 +1,126 @@
+use candle_vllm::openai::json_mode::JsonState;
+use candle_vllm::ModelSelected;
+
+mod common;
+use common::{
+    sampling_params,
+    tiny_model::{tiny_llama_dir, write_tokenizer, TinyEngine},
+};
+
+const PROMPT: &str = "t17 t33 t40 t41 t20 t60 t21 t44";
+const MAX_TOKENS: usize = 48;
+
+fn advance(text: &str) -> Option<JsonState> {
+    let mut state = JsonState::default();
+    state.advance(text).then_some(state)
+}
+
+#[test]
+fn accepts_the_prefixes_of_objects() {
+    for object in [
+        "{}",
+        r#"{"a": 1}"#,
+        r#"{ "a" : [1, -2.5e+3, 0, 0.25E2], "b": {"c": [true, false, null, []]} }"#,
+        r#"{"text": "quote \" backslash \\ newline \n unicode é é"}"#,
+        "{\n  \"a\": {}\n}",
+    ] {
+        serde_json::from_str::<serde_json::Value>(object).unwrap();
+        for (end, _) in object.char_indices() {
+            let state = advance(&object[..end]).unwrap_or_else(|| panic!("{object:?} {end}"));
+            assert!(!state.is_complete(), "{object:?} {end}");
+        }
+        assert!(advance(object).unwrap().is_complete(), "{object:?}");
+    }
+}
+
+#[test]
+fn rejects_what_is_not_an_object() {
+    for text in [
+        " {}",
+        "[]",
+        "1",
+        "\"a\"",
+        "{}}",
+        "{} ",
+        "{a: 1}",
+        r#"{"a" 1}"#,
+        r#"{"a": 01}"#,
+        r#"{"a": 1.}"#,
+        r#"{"a": -}"#,
+        r#"{"a": 1,}"#,
+        r#"{"a": [1,]}"#,
+        r#"{"a": [1}"#,
+        r#"{"a": tru}"#,
+        r#"{"a": "\x"}"#,
+        r#"{"a": "\u12g4"}"#,
+        "{\"a\": \"line\nbreak\"}",
+    ] {
+        assert!(advance(text).is_none(), "{text:?}");
+    }
+}
+
+#[test]
+fn allows_the_tokens_continuing_the_object() {
+    let vocab = ["{", "}", "\"", "\"a\":", "1", "1}", " ", "", "x", "]"].map(String::from);
+    let allowed = |text| advance(text).unwrap().allowed_tokens(&vocab);
+    assert_eq!(allowed(""), [0]);
+    assert_eq!(allowed("{"), [1, 2, 3, 6]);
+    assert_eq!(allowed(r#"{"a":"#), [0, 2, 4, 5, 6]);
+    assert_eq!(allowed(r#"{"a":1"#), [1, 4, 5, 6]);
+    // In a key, anything but the tokens without text goes, as long as a colon follows the key.
+    assert_eq!(allowed(r#"{""#), [0, 1, 2, 4, 5, 6, 8, 9]);
+    assert!(allowed("{}").is_empty());
+}
+
+#[test]
+fn rejects_token_healing_and_guided_choice() {
+    let mut params = sampling_params();
+    assert!(params.set_json_mode(true).is_ok());
+    assert!(params.set_json_mode(false).is_ok());
+
+    params.token_healing = true;
+    assert!(params.set_json_mode(true).is_err());
+    params.token_healing = false;
+    params
+        .set_guided_choice(Some(vec!["a".to_string()]))
+        .unwrap();
+    assert!(params.set_json_mode(true).is_err());
+}
+
+#[test]
+fn output_is_an_object() {
+    // The tiny llama with JSON punctuation in its vocabulary.
+    let dir = std::env::temp_dir().join(format!("candle-vllm-json-mode-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for file in ["config.json", "model.safetensors"] {
+        std::fs::copy(tiny_llama_dir().join(file), dir.join(file)).unwrap();
+    }
+    write_tokenizer(
+        &dir,
+        &[
+            "<unk>", "<s>", "</s>", "{", "}", "\"", ":", ",", "[", "]", "1", "true", "null",
+        ],
+    );
+    let model = ModelSelected::Llama {
+        repeat_last_n: None,
+        temperature: Some(0.),
+        penalty: Some(1.),
+        max_gen_tokens: None,
+    };
+    let mut engine = TinyEngine::load(model, &dir, TinyEngine::cache_config(16)).unwrap();
+    let prompt = engine.encode(PROMPT);
+    engine.json_mode = true;
+    let chunks = engine.stream(&[prompt], 1, MAX_TOKENS).remove(0);
+    let text = chunks
+        .iter()
+        .filter_map(|chunk| chunk.choices[0].delta.content.clone())
+        .collect::<String>();
+    // The output ends with the object.
+    assert!(serde_json::from_str::<serde_json::Value>(&text)
+        .unwrap()
+        .is_object());
+    assert_eq!(
+        chunks.last().unwrap().choices[0].finish_reason.as_deref(),
+        Some("stop")
+    );
+}
@@ -0,0 +1,65 @@
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_vllm::backend::KvCacheLayout;
+
+#[test]
+fn packs_sixteen_bytes_of_each_key() {
+    for (dtype, x) in [(DType::F32, 4), (DType::F16, 8), (DType::BF16, 8)] {
+        let layout = KvCacheLayout::new(2, 64, 16, dtype).unwrap();
+        assert_eq!(layout.x(), x, "{dtype:?}");
+        assert!(layout.packs(dtype));
+        assert_eq!(layout.key_cache_shape(10), (10, 2, 64 / x, 16, x));
+        assert_eq!(layout.value_cache_shape(10), (10, 2, 64, 16));
+    }
+    assert!(!KvCacheLayout::new(2, 64, 16, DType::F32)
+        .unwrap()
+        .packs(DType::F16));
+    assert!(KvCacheLayout::new(2, 12, 16, DType::F16).is_err());
+}
+
+#[test]
+fn indices_address_the_cache_tensors() {
+    let layout = KvCacheLayout::new(3, 16, 8, DType::F32).unwrap();
+    let num_blocks = 4;
+    let key_cache = Tensor::arange(0u32, 4 * 3 * 16 * 8, &Device::Cpu)
+        .unwrap()
+        .reshape(layout.key_cache_shape(num_blocks))
+        .unwrap();
+    let value_cache = key_cache
+        .reshape(layout.value_cache_shape(num_blocks))
+        .unwrap();
+    assert_eq!(
+        KvCacheLayout::of_caches(key_cache.shape(), value_cache.shape()).unwrap(),
+        layout
+    );
+
+    let x = layout.x();
+    for (block, head, d, offset) in [(0, 0, 0, 0), (1, 2, 5, 3), (3, 1, 15, 7), (2, 0, 9, 0)] {
+        let key = key_cache
+            .i((block, head, d / x, offset, d % x))
+            .unwrap()
+            .to_scalar::<u32>()
+            .unwrap();
+        assert_eq!(key as usize, layout.key_index(block, head, d, offset));
+        let value = value_cache
+            .i((block, head, d, offset))
+            .unwrap()
+            .to_scalar::<u32>()
+            .unwrap();
+        assert_eq!(value as usize, layout.value_index(block, head, d, offset));
+    }
+}
+
+#[test]
+fn key_and_value_caches_have_to_agree() {
+    let layout = KvCacheLayout::new(2, 16, 8, DType::F32).unwrap();
+    let key_cache = Tensor::zeros(layout.key_cache_shape(4), DType::F32, &Device::Cpu).unwrap();
+    for value_shape in [(4, 2, 16, 16), (3, 2, 16, 8), (4, 1, 16, 8), (4, 2, 8, 8)] {
+        let value_cache = Tensor::zeros(value_shape, DType::F32, &Device::Cpu).unwrap();
+        assert!(
+            KvCacheLayout::of_caches(key_cache.shape(), value_cache.shape()).is_err(),
+            "{value_shape:?}"
+        );
+    }
+    // The value cache has one dimension less.
+    assert!(KvCacheLayout::of_caches(key_cache.shape(), key_cache.shape()).is_err());
+}
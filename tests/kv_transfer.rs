@@ -0,0 +1,121 @@
+use candle_vllm::scheduler::{
+    kv_transfer::SequenceKV,
+    kv_transport::{KvTransport, TcpKvTransport},
+};
+use std::{io::Write, net::TcpStream};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const OTHER_PROMPT: &str = "t12 t8 t60 t3 t27";
+const MAX_TOKENS: usize = 16;
+
+#[test]
+fn exported_sequence_continues_in_another_engine() {
+    for (block_size, exported_tokens) in [(8, 1), (8, 5), (16, 6)] {
+        let mut engine = TinyEngine::new(block_size);
+        let prompt = engine.encode(PROMPT);
+        let expected = engine
+            .generate(std::slice::from_ref(&prompt), MAX_TOKENS)
+            .remove(0);
+
+        let (tokens, kv) = engine.generate_and_export(&prompt, exported_tokens);
+        assert_eq!(tokens, expected[..exported_tokens + 1]);
+        assert_eq!(kv.prompt_token_ids, prompt.get_ids());
+        assert_eq!(
+            kv.output_token_ids,
+            tokens.iter().map(|&t| t as u32).collect::<Vec<_>>()
+        );
+        let path = std::env::temp_dir().join(format!(
+            "candle-vllm-kv-{block_size}-{exported_tokens}-{}.safetensors",
+            std::process::id()
+        ));
+        kv.save(&path).unwrap();
+        let kv = SequenceKV::load(&path).unwrap();
+
+        // The imported sequence decodes along with a prompt being prefilled.
+        let mut other = TinyEngine::new(block_size);
+        let other_prompt = other.encode(OTHER_PROMPT);
+        other.submit(&[other_prompt], MAX_TOKENS);
+        assert_eq!(
+            other.import(kv, MAX_TOKENS).unwrap(),
+            expected,
+            "block size {block_size}, {exported_tokens} exported tokens"
+        );
+    }
+}
+
+#[test]
+fn import_is_rejected_for_a_different_cache_layout() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    let (_, kv) = engine.generate_and_export(&prompt, 4);
+
+    let err = TinyEngine::new(16)
+        .import(kv.clone(), MAX_TOKENS)
+        .unwrap_err();
+    assert!(err.to_string().contains("blocks of 8 tokens"), "{err}");
+
+    let mut missing_block = kv.clone();
+    for (key_blocks, value_blocks) in missing_block.blocks.iter_mut() {
+        *key_blocks = key_blocks.narrow(0, 0, 1).unwrap();
+        *value_blocks = value_blocks.narrow(0, 0, 1).unwrap();
+    }
+    let err = engine.import(missing_block, MAX_TOKENS).unwrap_err();
+    assert!(
+        err.to_string().contains("has 1 blocks instead of 2"),
+        "{err}"
+    );
+
+    let mut missing_layer = kv.clone();
+    missing_layer.blocks.pop();
+    let err = engine.import(missing_layer, MAX_TOKENS).unwrap_err();
+    assert!(err.to_string().contains("layers"), "{err}");
+
+    let mut prompt_only = kv;
+    prompt_only.output_token_ids.clear();
+    let err = engine.import(prompt_only, MAX_TOKENS).unwrap_err();
+    assert!(err.to_string().contains("generated token"), "{err}");
+
+    // Rejected imports leave the engine serving requests.
+    assert_eq!(
+        engine.generate(&[prompt], MAX_TOKENS)[0].len(),
+        MAX_TOKENS + 1
+    );
+}
+
+#[test]
+fn prefilled_sequence_is_sent_to_a_decode_node_over_tcp() {
+    let mut prefill = TinyEngine::new(8);
+    let prompt = prefill.encode(PROMPT);
+    let expected = prefill
+        .generate(std::slice::from_ref(&prompt), MAX_TOKENS)
+        .remove(0);
+    let (_, kv) = prefill.generate_and_export(&prompt, 1);
+
+    let transport = TcpKvTransport::bind("127.0.0.1:0").unwrap();
+    let peer = transport.local_addr().unwrap().to_string();
+    let sender = std::thread::spawn(move || {
+        TcpKvTransport::bind("127.0.0.1:0")
+            .unwrap()
+            .send(&peer, "prefilled-0", &kv)
+    });
+    let (request_id, kv) = transport.receive().unwrap();
+    sender.join().unwrap().unwrap();
+    assert_eq!(request_id, "prefilled-0");
+
+    let mut decode = TinyEngine::new(8);
+    assert_eq!(decode.import(kv, MAX_TOKENS).unwrap(), expected);
+}
+
+#[test]
+fn transport_rejects_other_protocols() {
+    let transport = TcpKvTransport::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(transport.local_addr().unwrap()).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let err = transport.receive().unwrap_err();
+    assert!(err.to_string().contains("KV cache message"), "{err}");
+}
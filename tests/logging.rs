@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use candle_vllm::logging::LogFormat;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT_A: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+
+/// Collects what the subscriber writes.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MakeWriter<'_> for Buffer {
+    type Writer = Buffer;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[test]
+fn parses_log_formats() {
+    assert_eq!(LogFormat::from_str("text", false), Ok(LogFormat::Text));
+    assert_eq!(LogFormat::from_str("json", false), Ok(LogFormat::Json));
+    assert!(LogFormat::from_str("yaml", false).is_err());
+}
+
+#[test]
+fn logs_the_lifecycle_of_a_request_as_json() {
+    let mut engine = TinyEngine::new(16);
+    let a = engine.encode(PROMPT_A);
+    let buffer = Buffer::default();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_writer(buffer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || engine.generate(&[a], 8));
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let events = logs
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|event| event["request_id"] == "tiny-0")
+        .collect::<Vec<_>>();
+    let messages = events
+        .iter()
+        .map(|event| event["message"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        [
+            "request queued",
+            "prefill started",
+            "first token",
+            "request finished"
+        ]
+    );
+    assert_eq!(events[0]["prompt_tokens"], 10);
+    assert!(events[2]["time_to_first_token_ms"].is_u64());
+    let finished = &events[3];
+    assert_eq!(finished["level"], "INFO");
+    assert_eq!(finished["finish_reason"], "length");
+    assert_eq!(finished["prompt_tokens"], 10);
+    // `max_tokens` yields one extra token.
+    assert_eq!(finished["completion_tokens"], 9);
+    assert!(finished["completion_time_ms"].is_u64());
+}
@@ -0,0 +1,166 @@
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use candle_vllm::openai::{
+    logit_dump::{LogitDumpDir, LogitDumpLine, LogitDumpParams},
+    openai_server::chat_completions,
+    responses::ChatResponder,
+    sampling_params::MAX_REQUEST_LOGPROBS,
+    OpenAIServerData,
+};
+use serde_json::{json, Value};
+use std::{path::PathBuf, sync::Arc};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::{
+    sampling_params,
+    tiny_model::{TinyEngine, VOCAB_SIZE},
+};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 12;
+
+fn dump_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "candle-vllm-logit-dump-{name}-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn read_dump(path: &PathBuf) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// Dump of the greedy generation of `PROMPT`, with the tokens generated without dumping.
+fn dump(name: &str, top: usize, max_bytes: u64) -> (Vec<Value>, Vec<usize>) {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+    let path = dump_path(name);
+    engine.logit_dump = Some(LogitDumpParams {
+        path: path.clone(),
+        top,
+        max_bytes,
+    });
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), expected);
+    (read_dump(&path), expected.into_iter().next().unwrap())
+}
+
+#[test]
+fn the_whole_vocabulary_is_dumped_at_every_token() {
+    let (lines, tokens) = dump("vocab", VOCAB_SIZE, u64::MAX);
+    assert_eq!(lines.len(), tokens.len());
+    for (position, (line, token)) in lines.into_iter().zip(tokens).enumerate() {
+        let line: LogitDumpLine = serde_json::from_value(line).unwrap();
+        assert_eq!((line.choice, line.position), (0, position));
+        assert_eq!(line.token as usize, token);
+        assert_eq!(line.token_ids, None);
+        assert_eq!(line.logits.len(), VOCAB_SIZE);
+        // Greedy sampling takes the most likely token of the logits of the model.
+        let argmax = (0..VOCAB_SIZE)
+            .max_by(|&a, &b| line.logits[a].total_cmp(&line.logits[b]))
+            .unwrap();
+        assert_eq!(argmax, token);
+    }
+}
+
+#[test]
+fn the_most_likely_tokens_are_dumped_in_order() {
+    let (lines, tokens) = dump("top", 5, u64::MAX);
+    assert_eq!(lines.len(), tokens.len());
+    for (line, token) in lines.into_iter().zip(tokens) {
+        let line: LogitDumpLine = serde_json::from_value(line).unwrap();
+        let token_ids = line.token_ids.unwrap();
+        assert_eq!(token_ids.len(), 5);
+        assert_eq!(line.logits.len(), 5);
+        assert_eq!(token_ids[0] as usize, token);
+        assert!(line.logits.windows(2).all(|w| w[0] >= w[1]));
+    }
+}
+
+#[test]
+fn a_dump_stops_at_its_size_limit() {
+    let (full, _) = dump("untruncated", 5, u64::MAX);
+    let line_bytes = serde_json::to_vec(&full[0]).unwrap().len() as u64 + 1;
+    let (lines, _) = dump("truncated", 5, 3 * line_bytes);
+    assert!(lines.len() < full.len());
+    assert_eq!(lines.last().unwrap(), &json!({"truncated": true}));
+    assert_eq!(&lines[..lines.len() - 1], &full[..lines.len() - 1]);
+}
+
+/// Status of the response of `data` to a chat completion request with the fields of `fields`.
+fn status(data: OpenAIServerData, fields: Value) -> StatusCode {
+    let mut request = json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": PROMPT}],
+        "max_tokens": 4,
+        "temperature": 0.0,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    let request = serde_json::from_value(request).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let responder: ChatResponder =
+            chat_completions(State(Arc::new(data)), HeaderMap::new(), Ok(Json(request))).await;
+        responder.into_response().status()
+    })
+}
+
+#[test]
+fn requests_dump_to_the_directory_of_the_server() {
+    let engine = TinyEngine::new(16);
+    assert_eq!(
+        status(engine.server_data(None), json!({"dump_logits": 5})),
+        StatusCode::BAD_REQUEST
+    );
+
+    let dir = std::env::temp_dir().join(format!("candle-vllm-logit-dumps-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut data = engine.server_data(None);
+    data.logit_dumps = Some(LogitDumpDir {
+        dir: dir.clone(),
+        max_bytes: u64::MAX,
+    });
+    assert_eq!(
+        status(engine.server_data(None), json!({"dump_logits": 0})),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(status(data, json!({"dump_logits": 5})), StatusCode::OK);
+    let dumps = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(dumps.len(), 1);
+    assert_eq!(read_dump(&dumps[0]).len(), 4);
+}
+
+#[test]
+fn servers_raise_the_top_logprobs_of_a_request() {
+    let engine = TinyEngine::new(16);
+    let fields = json!({"logprobs": true, "top_logprobs": 40});
+    assert_eq!(
+        status(engine.server_data(None), fields.clone()),
+        StatusCode::BAD_REQUEST
+    );
+    let mut data = engine.server_data(None);
+    data.max_logprobs = VOCAB_SIZE;
+    assert_eq!(status(data, fields), StatusCode::OK);
+
+    // Requests reporting too many logprobs overall are refused whatever the limit.
+    let mut params = sampling_params();
+    params.max_tokens = MAX_REQUEST_LOGPROBS;
+    assert!(params.set_logprobs(Some(2), usize::MAX).is_err());
+    assert!(params.set_logprobs(Some(1), usize::MAX).is_ok());
+    assert_eq!(params.logprobs, Some(1));
+}
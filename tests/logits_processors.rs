@@ -0,0 +1,108 @@
+use candle_core::{Error, Result, Tensor};
+use candle_vllm::openai::{
+    hooks::{LogitsContext, LogitsProcessor},
+    logits_processor::mask_logits,
+};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 8;
+
+/// Only lets `token` be sampled.
+struct Force(u32);
+
+impl LogitsProcessor for Force {
+    fn process(&self, _context: &LogitsContext, logits: &Tensor) -> Result<Tensor> {
+        mask_logits(logits, &[self.0])
+    }
+}
+
+/// Records its name, the request and the tokens generated so far on every call.
+struct Recorder {
+    name: &'static str,
+    calls: Arc<Mutex<Vec<(&'static str, String, usize)>>>,
+}
+
+impl LogitsProcessor for Recorder {
+    fn process(&self, context: &LogitsContext, logits: &Tensor) -> Result<Tensor> {
+        self.calls.lock().unwrap().push((
+            self.name,
+            context.request_id.to_string(),
+            context.generated.len(),
+        ));
+        Ok(logits.clone())
+    }
+}
+
+struct Failing;
+
+impl LogitsProcessor for Failing {
+    fn process(&self, _context: &LogitsContext, _logits: &Tensor) -> Result<Tensor> {
+        Err(Error::Msg("failing processor".to_string()))
+    }
+}
+
+#[test]
+fn engine_processor_applies_to_every_request() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    engine.add_logits_processor(Arc::new(Force(42)));
+    for tokens in engine.generate(&prompts, MAX_TOKENS) {
+        assert!(!tokens.is_empty());
+        assert!(tokens.iter().all(|&token| token == 42), "{tokens:?}");
+    }
+}
+
+#[test]
+fn request_processors_run_after_the_engine_ones() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    engine.add_logits_processor(Arc::new(Recorder {
+        name: "engine",
+        calls: calls.clone(),
+    }));
+    engine.logits_processors = vec![
+        Arc::new(Recorder {
+            name: "request",
+            calls: calls.clone(),
+        }),
+        Arc::new(Force(42)),
+    ];
+    engine.submit(&prompts[..1], MAX_TOKENS);
+    engine.logits_processors.clear();
+    let outputs = engine.generate(&prompts[1..], MAX_TOKENS);
+    // The processors of a request leave the other requests alone.
+    assert_eq!(outputs[0], expected[1]);
+
+    let calls = calls.lock().unwrap();
+    let calls_of = |request_id: &str| {
+        calls
+            .iter()
+            .filter(|(_, id, _)| id == request_id)
+            .map(|(name, _, generated)| (*name, *generated))
+            .collect::<Vec<_>>()
+    };
+    let forced = calls_of("tiny-2");
+    assert_eq!(
+        forced[..4],
+        [("engine", 0), ("request", 0), ("engine", 1), ("request", 1)]
+    );
+    let other = calls_of("tiny-3");
+    assert!(other.iter().all(|(name, _)| *name == "engine"));
+    assert_eq!(other.len(), expected[1].len());
+}
+
+#[test]
+fn failing_processor_is_skipped() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+    engine.logits_processors = vec![Arc::new(Failing)];
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+}
@@ -0,0 +1,86 @@
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use candle_vllm::openai::{openai_server::chat_completions, responses::ChatResponder};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 12;
+
+#[test]
+fn streamed_chunks_carry_the_logprobs_of_their_token() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+
+    engine.top_logprobs = Some(3);
+    let chunks = engine.stream(&[prompt], 1, MAX_TOKENS).remove(0);
+    let logprobs = chunks
+        .iter()
+        .flat_map(|chunk| &chunk.choices)
+        .filter(|choice| choice.delta.content.is_some())
+        .map(|choice| {
+            let logprobs = &choice.logprobs.as_ref().unwrap().content;
+            assert_eq!(logprobs.len(), 1);
+            logprobs[0].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        logprobs.iter().map(|l| l.token).collect::<Vec<_>>(),
+        expected[0]
+    );
+    for logprob in &logprobs {
+        assert!(logprob.logprob < 0.0, "{}", logprob.logprob);
+        let top = &logprob.top_logprobs;
+        assert_eq!(top.len(), 3);
+        assert!(top.windows(2).all(|w| w[0].logprob >= w[1].logprob));
+        // Greedy sampling takes the most likely token.
+        assert_eq!(top[0].token, logprob.token);
+        assert_eq!(top[0].logprob, logprob.logprob);
+        assert_eq!(top[0].bytes, logprob.bytes);
+    }
+}
+
+/// Status of the response to a chat completion request with the fields of `fields`.
+fn status(fields: Value) -> StatusCode {
+    let engine = TinyEngine::new(16);
+    let mut request = json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": PROMPT}],
+        "max_tokens": 4,
+        "temperature": 0.0,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    let request = serde_json::from_value(request).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let responder: ChatResponder = chat_completions(
+            State(Arc::new(engine.server_data(None))),
+            HeaderMap::new(),
+            Ok(Json(request)),
+        )
+        .await;
+        responder.into_response().status()
+    })
+}
+
+#[test]
+fn top_logprobs_are_validated() {
+    assert_eq!(
+        status(json!({"logprobs": true, "top_logprobs": 5})),
+        StatusCode::OK
+    );
+    assert_eq!(
+        status(json!({"logprobs": true, "top_logprobs": 21})),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(status(json!({"top_logprobs": 5})), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,96 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::pipelines::lora::merge_lora;
+use std::{collections::HashMap, path::PathBuf};
+
+fn write_adapter(name: &str, config: &str, tensors: HashMap<&str, Tensor>) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("adapter_config.json"), config).unwrap();
+    candle_core::safetensors::save(&tensors, dir.join("adapter_model.safetensors")).unwrap();
+    dir
+}
+
+fn values(t: &Tensor) -> Vec<f32> {
+    let t = t.to_dtype(DType::F32).unwrap().flatten_all().unwrap();
+    t.to_vec1::<f32>().unwrap()
+}
+
+#[test]
+fn adapters_are_merged_into_the_weights() {
+    let dev = Device::Cpu;
+    let weight = Tensor::arange(0f32, 12., &dev)
+        .unwrap()
+        .reshape((4, 3))
+        .unwrap();
+    let embed = Tensor::zeros((5, 2), DType::F32, &dev).unwrap();
+    let head = Tensor::zeros((5, 2), DType::F32, &dev).unwrap();
+    // r = 2, A is (r, in) and B is (out, r).
+    let a = Tensor::new(&[[1f32, 0., 1.], [0., 1., 0.]], &dev).unwrap();
+    let b = Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.], [0., 0.]], &dev).unwrap();
+    let embed_a = Tensor::ones((2, 5), DType::F32, &dev).unwrap();
+    let embed_b = Tensor::ones((2, 2), DType::F32, &dev).unwrap();
+    let saved_head = Tensor::ones((5, 2), DType::F32, &dev).unwrap();
+    let adapter = write_adapter(
+        "lora-merge",
+        r#"{"r": 2, "lora_alpha": 4, "target_modules": ["q_proj"]}"#,
+        HashMap::from([
+            ("base_model.model.layers.0.q_proj.lora_A.weight", a.clone()),
+            ("base_model.model.layers.0.q_proj.lora_B.weight", b.clone()),
+            ("base_model.model.embed_tokens.lora_embedding_A", embed_a),
+            ("base_model.model.embed_tokens.lora_embedding_B", embed_b),
+            ("base_model.model.lm_head.weight", saved_head),
+        ]),
+    );
+
+    for dtype in [DType::F32, DType::BF16] {
+        let mut tensors = HashMap::from([
+            (
+                "layers.0.q_proj.weight".to_string(),
+                weight.to_dtype(dtype).unwrap(),
+            ),
+            ("embed_tokens.weight".to_string(), embed.clone()),
+            ("lm_head.weight".to_string(), head.clone()),
+            (
+                "norm.weight".to_string(),
+                Tensor::ones(3, dtype, &dev).unwrap(),
+            ),
+        ]);
+        let merged = merge_lora(&mut tensors, &adapter, &dev, 1 << 20).unwrap();
+        assert_eq!(merged, 3);
+
+        // Scaled by lora_alpha / r = 2.
+        let expected = (&weight + (b.matmul(&a).unwrap() * 2.).unwrap()).unwrap();
+        let q_proj = &tensors["layers.0.q_proj.weight"];
+        assert_eq!(q_proj.dtype(), dtype);
+        assert_eq!(values(q_proj), values(&expected));
+        // Embedding deltas are (B @ A)^T, every entry 2 * 2.
+        assert_eq!(values(&tensors["embed_tokens.weight"]), vec![4.; 10]);
+        assert_eq!(values(&tensors["lm_head.weight"]), vec![1.; 10]);
+        assert_eq!(values(&tensors["norm.weight"]), vec![1.; 3]);
+    }
+}
+
+#[test]
+fn adapters_of_missing_weights_are_rejected() {
+    let dev = Device::Cpu;
+    let adapter = write_adapter(
+        "lora-merge-missing",
+        r#"{"r": 1, "lora_alpha": 1, "use_rslora": true}"#,
+        HashMap::from([
+            (
+                "base_model.model.layers.0.v_proj.lora_A.weight",
+                Tensor::ones((1, 3), DType::F32, &dev).unwrap(),
+            ),
+            (
+                "base_model.model.layers.0.v_proj.lora_B.weight",
+                Tensor::ones((4, 1), DType::F32, &dev).unwrap(),
+            ),
+        ]),
+    );
+    let mut tensors = HashMap::from([(
+        "layers.0.q_proj.weight".to_string(),
+        Tensor::zeros((4, 3), DType::F32, &dev).unwrap(),
+    )]);
+    let err = merge_lora(&mut tensors, &adapter, &dev, 1 << 20).unwrap_err();
+    assert!(err.to_string().contains("layers.0.v_proj.weight"), "{err}");
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33", "t40 t11 t3"];
+const MAX_TOKENS: usize = 6;
+
+#[test]
+fn steps_generate_what_the_engine_loop_generates() {
+    let mut expected = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| expected.encode(prompt));
+    let expected = expected.generate(&prompts, MAX_TOKENS);
+
+    let mut engine = TinyEngine::new(16);
+    engine.submit(&prompts, MAX_TOKENS);
+    let mut tokens = HashMap::new();
+    let mut finished = HashMap::new();
+    let mut num_steps = 0;
+    while engine.has_unfinished_requests() {
+        for output in engine.step().unwrap() {
+            // Each step adds a token to every choice it ran.
+            let previous: &Vec<usize> = tokens.entry(output.request_id.clone()).or_default();
+            assert_eq!(output.token_ids[0].len(), previous.len() + 1);
+            tokens.insert(output.request_id.clone(), output.token_ids[0].clone());
+            if let Some(response) = output.response {
+                assert!(finished.insert(output.request_id, response).is_none());
+            }
+        }
+        num_steps += 1;
+    }
+    // A prefill and the decode steps of the rest of the tokens.
+    assert_eq!(num_steps, MAX_TOKENS);
+    assert_eq!(tokens["tiny-0"], expected[0]);
+    assert_eq!(tokens["tiny-1"], expected[1]);
+    assert_eq!(finished.len(), 2);
+    assert_eq!(finished["tiny-1"].1.completion_tokens, MAX_TOKENS);
+}
+
+#[test]
+fn stepping_an_idle_engine_does_nothing() {
+    let engine = TinyEngine::new(16);
+    assert!(!engine.has_unfinished_requests());
+    assert!(engine.step().unwrap().is_empty());
+}
+
+#[test]
+fn prompts_larger_than_the_cache_are_refused() {
+    let mut cache_config = TinyEngine::cache_config(16);
+    cache_config.num_gpu_blocks = Some(1);
+    let mut engine = TinyEngine::with_cache_config(cache_config);
+    let long = (0..20).map(|i| format!("t{i}")).collect::<Vec<_>>();
+    let prompts = [engine.encode(&long.join(" ")), engine.encode(PROMPTS[0])];
+    engine.submit(&prompts, MAX_TOKENS);
+    let outputs = engine.step().unwrap();
+    let refused = outputs
+        .iter()
+        .find(|output| output.request_id == "tiny-0")
+        .unwrap();
+    let (choices, usage) = refused.response.as_ref().unwrap();
+    assert_eq!(choices[0].finish_reason.as_deref(), Some("length"));
+    assert_eq!(usage.completion_tokens, 0);
+    // The prompts which fit are run as usual.
+    while engine.has_unfinished_requests() {
+        engine.step().unwrap();
+    }
+}
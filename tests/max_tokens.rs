@@ -0,0 +1,64 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use candle_vllm::openai::{
+    openai_server::chat_completions,
+    responses::{ChatCompletionUsageResponse, ChatResponder},
+    OpenAIServerData,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+/// Usage of the greedy chat completion of a short prompt, with the fields of `fields`.
+fn usage(data: OpenAIServerData, fields: Value) -> ChatCompletionUsageResponse {
+    let mut request = json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": "t5 t9 t17"}],
+        "temperature": 0.0,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    let request = serde_json::from_value(request).unwrap();
+    let responder = Runtime::new().unwrap().block_on(async {
+        chat_completions(State(Arc::new(data)), HeaderMap::new(), Ok(Json(request))).await
+    });
+    let ChatResponder::Completion(response, _) = responder else {
+        panic!("the request was not completed");
+    };
+    response.usage
+}
+
+#[test]
+fn requests_without_max_tokens_generate_until_the_context_is_full() {
+    let engine = TinyEngine::new(16);
+    let data = engine.server_data(None);
+    let max_model_len = {
+        let mut config = data.pipeline_config.write().unwrap();
+        config.default_max_tokens = config.max_model_len;
+        config.max_model_len
+    };
+    let usage = usage(data, json!({}));
+    assert_eq!(usage.max_tokens, max_model_len - usage.prompt_tokens);
+
+    // Explicit ones are kept.
+    let usage = self::usage(engine.server_data(None), json!({"max_tokens": 4}));
+    assert_eq!(usage.max_tokens, 4);
+}
+
+#[test]
+fn requests_without_max_tokens_may_be_capped_to_the_free_blocks() {
+    let mut cache_config = TinyEngine::cache_config(16);
+    cache_config.num_gpu_blocks = Some(4);
+    let engine = TinyEngine::with_cache_config(cache_config);
+    // The default of the server does not fit the 64 tokens of the cache.
+    let mut data = engine.server_data(None);
+    data.cap_max_tokens_to_free_blocks = true;
+    let usage = usage(data, json!({}));
+    assert_eq!(usage.max_tokens, 4 * 16 - usage.prompt_tokens);
+}
@@ -0,0 +1,67 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::openai::logits_processor::suppress_logits;
+
+mod common;
+use common::{sampling_params, tiny_model::TinyEngine};
+
+const PROMPT_A: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const PROMPT_B: &str = "t11 t3 t50 t22 t19 t44 t6 t23 t31 t58";
+const MAX_TOKENS: usize = 24;
+
+// Greedy output of the tiny llama for B, which stops at EOS.
+const GOLDEN_B: [usize; 18] = [
+    38, 34, 46, 45, 41, 17, 6, 34, 18, 6, 13, 35, 51, 8, 43, 39, 43, 39,
+];
+
+#[test]
+fn suppresses_logits_of_the_given_tokens() {
+    let logits = Tensor::new(&[0.5f32, 3.0, -1.0, 2.0], &Device::Cpu).unwrap();
+    let suppressed = suppress_logits(&logits, &[1, 9]).unwrap();
+    assert_eq!(
+        suppressed.to_vec1::<f32>().unwrap(),
+        [0.5, f32::NEG_INFINITY, -1.0, 2.0]
+    );
+    assert_eq!(suppressed.argmax(0).unwrap().to_scalar::<u32>().unwrap(), 3);
+}
+
+#[test]
+fn min_tokens_may_not_exceed_max_tokens() {
+    let mut params = sampling_params();
+    assert!(params.set_min_tokens(Some(params.max_tokens)).is_ok());
+    assert_eq!(params.min_tokens, params.max_tokens);
+    assert!(params.set_min_tokens(Some(params.max_tokens + 1)).is_err());
+    assert!(params.set_min_tokens(None).is_ok());
+    assert_eq!(params.min_tokens, 0);
+}
+
+#[test]
+fn eos_is_suppressed_until_min_tokens() {
+    let mut engine = TinyEngine::new(16);
+    let b = engine.encode(PROMPT_B);
+
+    // EOS comes after 18 tokens, which is enough.
+    engine.min_tokens = Some(GOLDEN_B.len());
+    let generated = engine.generate(std::slice::from_ref(&b), MAX_TOKENS);
+    assert_eq!(generated, [GOLDEN_B.to_vec()]);
+
+    engine.min_tokens = Some(20);
+    let generated = engine.generate(&[b], MAX_TOKENS).remove(0);
+    assert!(generated.len() >= 20, "{generated:?}");
+    assert_eq!(generated[..GOLDEN_B.len()], GOLDEN_B);
+    assert!(!generated[..20].contains(&2));
+}
+
+#[test]
+fn stop_token_ids_of_the_request_are_suppressed_too() {
+    let mut engine = TinyEngine::new(16);
+    let a = engine.encode(PROMPT_A);
+    // The greedy output of A starts with 45, 3, 46.
+    engine.stop_token_ids = vec![46];
+    let generated = engine.generate(std::slice::from_ref(&a), MAX_TOKENS);
+    assert_eq!(generated, [vec![45, 3]]);
+
+    engine.min_tokens = Some(4);
+    let generated = engine.generate(&[a], MAX_TOKENS).remove(0);
+    assert!(generated.len() >= 4, "{generated:?}");
+    assert!(!generated[..4].contains(&46), "{generated:?}");
+}
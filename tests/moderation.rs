@@ -0,0 +1,132 @@
+use candle_vllm::openai::{
+    hooks::{EngineObserver, ModerationContext, ModerationMatch, Moderator},
+    moderation::RegexModerator,
+    responses::{ChatChoice, ChatCompletionChunk, ChatCompletionUsageResponse},
+};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17 t33 t40";
+const MAX_TOKENS: usize = 8;
+
+/// Moderation of the choices of the finished requests.
+#[derive(Default)]
+struct Finished(Mutex<Vec<Vec<ModerationMatch>>>);
+
+impl EngineObserver for Finished {
+    fn on_finish(&self, _: &str, choices: &[ChatChoice], _: &ChatCompletionUsageResponse) {
+        let mut finished = self.0.lock().unwrap();
+        finished.extend(choices.iter().map(|choice| choice.moderation.clone()));
+    }
+}
+
+fn contents(chunks: &[ChatCompletionChunk]) -> Vec<String> {
+    chunks
+        .iter()
+        .filter_map(|chunk| chunk.choices[0].delta.content.clone())
+        .collect()
+}
+
+fn context<'a>(text: &'a str, new_text: &'a str) -> ModerationContext<'a> {
+    ModerationContext {
+        request_id: "cmpl-0",
+        index: 0,
+        text,
+        new_text,
+    }
+}
+
+#[test]
+fn regex_moderator_reports_each_match_once() {
+    let urls = RegexModerator::parse(r"url=https?://\S+", true).unwrap();
+    assert_eq!(urls.moderate(&context("see ", "see ")), None);
+    let found = urls.moderate(&context("see http://a", "http://a")).unwrap();
+    assert_eq!(
+        found,
+        ModerationMatch {
+            category: "url".to_string(),
+            text: "http://a".to_string(),
+            halt: true,
+        }
+    );
+    // The URL goes on with the next token, it was reported already.
+    assert_eq!(urls.moderate(&context("see http://ab", "b")), None);
+    let found = urls.moderate(&context("see http://ab or https://c", " or https://c"));
+    assert_eq!(found.unwrap().text, "https://c");
+
+    assert!(RegexModerator::parse("https?://", true).is_err());
+    assert!(RegexModerator::parse("=https?://", true).is_err());
+    assert!(RegexModerator::parse("url=(", true).is_err());
+}
+
+#[test]
+fn moderator_halts_a_choice_with_content_filter() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let unmoderated = contents(&engine.stream(std::slice::from_ref(&prompt), 1, MAX_TOKENS)[0]);
+
+    // The text of the first three tokens, matched once the third one is sampled.
+    let text = unmoderated[..3].concat();
+    let pattern = format!("^{}", regex::escape(&text));
+    let moderator = RegexModerator::new("test", &pattern, true).unwrap();
+    engine.add_moderator(Arc::new(moderator));
+    let finished = Arc::new(Finished::default());
+    engine.add_observer(finished.clone());
+    let chunks = engine.stream(std::slice::from_ref(&prompt), 1, MAX_TOKENS);
+
+    let expected = ModerationMatch {
+        category: "test".to_string(),
+        text,
+        halt: true,
+    };
+    // The third token is dropped, the last chunk reports why.
+    assert_eq!(contents(&chunks[0]), unmoderated[..2]);
+    let last = &chunks[0].last().unwrap().choices[0];
+    assert_eq!(last.finish_reason.as_deref(), Some("content_filter"));
+    assert_eq!(last.moderation, [expected.clone()]);
+    assert_eq!(*finished.0.lock().unwrap(), [vec![expected]]);
+}
+
+/// Flags every token, without halting.
+struct FlagAll;
+
+impl Moderator for FlagAll {
+    fn moderate(&self, context: &ModerationContext) -> Option<ModerationMatch> {
+        Some(ModerationMatch {
+            category: "token".to_string(),
+            text: context.new_text.to_string(),
+            halt: false,
+        })
+    }
+}
+
+#[test]
+fn matches_that_do_not_halt_are_reported() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let unmoderated = contents(&engine.stream(std::slice::from_ref(&prompt), 1, MAX_TOKENS)[0]);
+
+    engine.add_moderator(Arc::new(FlagAll));
+    let finished = Arc::new(Finished::default());
+    engine.add_observer(finished.clone());
+    let chunks = engine.stream(std::slice::from_ref(&prompt), 1, MAX_TOKENS);
+    assert_eq!(contents(&chunks[0]), unmoderated);
+    for chunk in chunks[0]
+        .iter()
+        .filter(|chunk| chunk.choices[0].delta.content.is_some())
+    {
+        let choice = &chunk.choices[0];
+        assert_eq!(
+            choice.moderation[0].text,
+            *choice.delta.content.as_ref().unwrap()
+        );
+    }
+    let finished = finished.0.lock().unwrap();
+    let flagged = finished[0]
+        .iter()
+        .map(|m| m.text.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(flagged, unmoderated);
+}
@@ -0,0 +1,95 @@
+use candle_vllm::scheduler::{Scheduler, SchedulerConfig};
+
+mod common;
+use common::{cache_config, sequence_group};
+
+const BLOCK_SIZE: usize = 16;
+
+fn scheduler(num_groups: usize) -> Scheduler {
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 16,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(BLOCK_SIZE),
+    );
+    for id in 0..num_groups {
+        scheduler.add_sequence(sequence_group(id, 8, BLOCK_SIZE));
+    }
+    scheduler
+}
+
+fn request_ids(groups: &[String]) -> Vec<&str> {
+    groups.iter().map(String::as_str).collect()
+}
+
+fn snapshot_ids(scheduler: &Scheduler) -> (Vec<String>, Vec<String>) {
+    let snapshot = scheduler.snapshot();
+    let ids = |groups: &[candle_vllm::scheduler::SequenceGroupSnapshot]| {
+        groups
+            .iter()
+            .map(|group| group.request_id.clone())
+            .collect::<Vec<_>>()
+    };
+    (ids(&snapshot.running), ids(&snapshot.waiting))
+}
+
+#[test]
+fn prompt_step_is_retried_with_half_the_groups() {
+    let mut scheduler = scheduler(4);
+    let output = scheduler.schedule();
+    assert_eq!(output.scheduled.len(), 4);
+
+    let blocks_to_swap_out = scheduler.shrink_batch(&output.scheduled, true).unwrap();
+    assert!(blocks_to_swap_out.is_empty());
+    assert_eq!(scheduler.get_max_batch_seqs(), Some(2));
+    let (running, waiting) = snapshot_ids(&scheduler);
+    assert!(running.is_empty());
+    assert_eq!(
+        request_ids(&waiting),
+        ["test-0", "test-1", "test-2", "test-3"]
+    );
+    let snapshot = scheduler.snapshot();
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+
+    let output = scheduler.schedule();
+    let scheduled = output
+        .scheduled
+        .iter()
+        .map(|group| group.request_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(scheduled, ["test-0", "test-1"]);
+}
+
+#[test]
+fn decode_step_preempts_the_newest_groups() {
+    let mut scheduler = scheduler(4);
+    scheduler.schedule();
+    let output = scheduler.schedule();
+    assert_eq!(output.scheduled.len(), 4);
+
+    scheduler.shrink_batch(&output.scheduled, false).unwrap();
+    assert_eq!(scheduler.get_max_batch_seqs(), Some(2));
+    let (running, waiting) = snapshot_ids(&scheduler);
+    assert_eq!(request_ids(&running), ["test-0", "test-1"]);
+    assert_eq!(request_ids(&waiting), ["test-2", "test-3"]);
+
+    // The preempted groups wait while the batch is full.
+    assert_eq!(scheduler.schedule().scheduled.len(), 2);
+    let (_, waiting) = snapshot_ids(&scheduler);
+    assert_eq!(waiting.len(), 2);
+}
+
+#[test]
+fn a_single_group_cannot_shrink() {
+    let mut scheduler = scheduler(1);
+    let output = scheduler.schedule();
+    assert!(scheduler.shrink_batch(&output.scheduled, true).is_none());
+    assert_eq!(scheduler.get_max_batch_seqs(), None);
+}
@@ -0,0 +1,90 @@
+use candle_core::DType;
+use candle_vllm::{
+    get_cache_config,
+    openai::models::{llama::LlamaConfig, Config},
+    planning::{plan, PlanConfig, Quantization},
+};
+
+/// The config of Llama 2 7B.
+fn llama_7b() -> Config {
+    let config: LlamaConfig = serde_json::from_value(serde_json::json!({
+        "hidden_size": 4096,
+        "intermediate_size": 11008,
+        "vocab_size": 32000,
+        "num_hidden_layers": 32,
+        "num_attention_heads": 32,
+        "num_key_value_heads": 32,
+        "rms_norm_eps": 1e-5,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "max_position_embeddings": 4096
+    }))
+    .unwrap();
+    config.into_config(false, DType::BF16)
+}
+
+fn plan_config(gpu_mem: usize) -> PlanConfig {
+    PlanConfig {
+        dtype: DType::BF16,
+        quantization: None,
+        gpu_mem,
+        reserved_mem: 2048,
+        block_size: 32,
+        max_num_seqs: 256,
+        context_len: None,
+    }
+}
+
+#[test]
+fn llama_7b_on_24gb() {
+    let plan = plan(&llama_7b(), &plan_config(24576)).unwrap();
+    assert_eq!(plan.num_params, 6_738_149_376);
+    assert_eq!(plan.weight_mem, 12852);
+    assert_eq!(plan.kvcache_mem, 24576 - 12852 - 2048);
+    // 512 KB per token, 16 MB per block of 32 tokens.
+    assert_eq!(plan.kvcache_bytes_per_token, 512 * 1024);
+    assert_eq!(plan.num_gpu_blocks, 604);
+    assert_eq!(plan.max_context_len, 4096);
+    assert_eq!(plan.max_concurrent_seqs, 604 / 128);
+}
+
+#[test]
+fn shorter_contexts_serve_more_sequences() {
+    let mut plan_config = plan_config(24576);
+    plan_config.context_len = Some(1000);
+    let plan = plan(&llama_7b(), &plan_config).unwrap();
+    assert_eq!(plan.max_concurrent_seqs, 604 / 32);
+
+    plan_config.max_num_seqs = 8;
+    assert_eq!(
+        plan(&llama_7b(), &plan_config).unwrap().max_concurrent_seqs,
+        8
+    );
+
+    plan_config.context_len = Some(4097);
+    assert!(plan(&llama_7b(), &plan_config).is_err());
+}
+
+#[test]
+fn quantized_weights_leave_room_for_the_kv_cache() {
+    let mut plan_config = plan_config(7168);
+    assert!(plan(&llama_7b(), &plan_config).is_err());
+
+    plan_config.quantization = Some(Quantization::Q4K);
+    let plan = plan(&llama_7b(), &plan_config).unwrap();
+    // The layers take 4.5 bits per weight, the embeddings and the LM head stay in bf16.
+    assert_eq!(plan.weight_mem, 3974);
+    assert_eq!(plan.num_gpu_blocks, (7168 - 3974 - 2048) / 16);
+    // A sequence alone cannot reach the max model length.
+    assert_eq!(plan.max_context_len, plan.num_gpu_blocks * 32);
+    assert_eq!(plan.max_concurrent_seqs, 0);
+}
+
+#[test]
+fn planned_kvcache_mem_gives_the_planned_blocks() {
+    let config = llama_7b();
+    let plan = plan(&config, &plan_config(24576)).unwrap();
+    let cache_config =
+        get_cache_config(&config, 32, plan.kvcache_mem, 4096, None, None, None).unwrap();
+    assert_eq!(cache_config.num_gpu_blocks, Some(plan.num_gpu_blocks));
+}
@@ -0,0 +1,13 @@
+use candle_vllm::scheduler::sequence::_Sequence;
+
+#[test]
+fn positions_start_at_the_offset() {
+    let mut seq = _Sequence::new(vec![5, 9, 17], 0, 16);
+    assert_eq!(seq.get_position_offset(), 0);
+    assert_eq!(seq.get_position(2), 2);
+
+    // A sequence continuing a reused prefix of 40 tokens.
+    seq.set_position_offset(40);
+    assert_eq!(seq.get_position(0), 40);
+    assert_eq!(seq.get_position(seq.get_len() - 1), 42);
+}
@@ -0,0 +1,70 @@
+use candle_vllm::scheduler::{Scheduler, SchedulerConfig};
+
+mod common;
+use common::{cache_config, sequence_group};
+
+const BLOCK_SIZE: usize = 16;
+
+fn scheduler(
+    max_num_prefill_tokens: Option<usize>,
+    long_prefill_token_threshold: Option<usize>,
+) -> Scheduler {
+    Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 16,
+            max_num_prefill_tokens,
+            long_prefill_token_threshold,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(BLOCK_SIZE),
+    )
+}
+
+/// Request ids of the groups scheduled in the next step.
+fn schedule(scheduler: &mut Scheduler) -> Vec<String> {
+    scheduler
+        .schedule()
+        .scheduled
+        .iter()
+        .map(|group| group.request_id.clone())
+        .collect()
+}
+
+#[test]
+fn prefill_steps_stay_within_the_token_budget() {
+    let mut scheduler = scheduler(Some(48), None);
+    for (id, prompt_len) in [40, 8, 16].into_iter().enumerate() {
+        scheduler.add_sequence(sequence_group(id, prompt_len, BLOCK_SIZE));
+    }
+    assert_eq!(schedule(&mut scheduler), ["test-0", "test-1"]);
+    assert_eq!(schedule(&mut scheduler), ["test-2"]);
+}
+
+#[test]
+fn prompts_over_the_budget_are_prefilled_alone() {
+    let mut scheduler = scheduler(Some(32), None);
+    scheduler.add_sequence(sequence_group(0, 40, BLOCK_SIZE));
+    scheduler.add_sequence(sequence_group(1, 8, BLOCK_SIZE));
+    assert_eq!(schedule(&mut scheduler), ["test-0"]);
+    assert_eq!(schedule(&mut scheduler), ["test-1"]);
+}
+
+#[test]
+fn long_prefills_are_limited_and_interleaved_with_decoding() {
+    let mut scheduler = scheduler(None, Some(32));
+    for (id, prompt_len) in [40, 50, 8].into_iter().enumerate() {
+        scheduler.add_sequence(sequence_group(id, prompt_len, BLOCK_SIZE));
+    }
+    // The short prompt is prefilled before the second long one.
+    assert_eq!(schedule(&mut scheduler), ["test-0", "test-2"]);
+    // The running sequences decode before the next long prefill.
+    let decoded = schedule(&mut scheduler);
+    assert_eq!(decoded.len(), 2);
+    assert!(!decoded.contains(&"test-1".to_string()));
+    assert_eq!(schedule(&mut scheduler), ["test-1"]);
+    assert_eq!(scheduler.snapshot().waiting.len(), 0);
+}
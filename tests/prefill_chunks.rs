@@ -0,0 +1,47 @@
+use candle_vllm::openai::pipelines::worker::WorkerConfig;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = [
+    "t5 t9 t17 t33 t40 t11 t3 t21 t44 t60 t8 t13",
+    "t40 t41 t20 t60 t21",
+];
+const MAX_TOKENS: usize = 8;
+
+fn with_chunks(prefill_chunk_size: Option<usize>) -> WorkerConfig {
+    WorkerConfig {
+        prefill_chunk_size,
+        ..WorkerConfig::default()
+    }
+}
+
+#[test]
+fn rejects_empty_chunks() {
+    assert!(with_chunks(Some(1)).verify_args().is_ok());
+    assert!(with_chunks(Some(0)).verify_args().is_err());
+}
+
+#[test]
+fn chunked_prefill_generates_what_a_masked_one_generates() {
+    let mut expected = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| expected.encode(prompt));
+    let expected = expected.generate_logprobs(&prompts, MAX_TOKENS).unwrap();
+
+    // Tiles that split the prompts unevenly, and one longer than the short prompt.
+    for chunk_size in [1, 5, 7] {
+        let mut engine = TinyEngine::with_worker_config(with_chunks(Some(chunk_size))).unwrap();
+        let outputs = engine.generate_logprobs(&prompts, MAX_TOKENS).unwrap();
+        for (output, expected) in outputs.iter().zip(&expected) {
+            for ((token, logprob), (expected_token, expected_logprob)) in
+                output.iter().zip(expected)
+            {
+                assert_eq!(token, expected_token, "chunk size {chunk_size}");
+                assert!(
+                    (logprob - expected_logprob).abs() < 1e-4,
+                    "chunk size {chunk_size}"
+                );
+            }
+        }
+    }
+}
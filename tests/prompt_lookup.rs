@@ -0,0 +1,232 @@
+use candle_vllm::scheduler::{
+    cache_engine::{AttentionSinks, CacheConfig},
+    draft_tree::DraftTree,
+    prompt_lookup::{DraftAcceptance, PromptLookupConfig},
+};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const REPEATED_PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t5 t9 t17 t33 t40 t41 t7 t8 t5 t9";
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+/// `t5 t9` is followed by three different continuations.
+const BRANCHING_PROMPT: &str = "t5 t9 t17 t33 t5 t9 t40 t41 t5 t9 t17 t8 t5 t9 t12 t5 t9";
+const MAX_TOKENS: usize = 24;
+
+fn config(num_speculative_tokens: usize, max_ngram: usize, min_ngram: usize) -> PromptLookupConfig {
+    PromptLookupConfig {
+        num_speculative_tokens,
+        max_ngram,
+        min_ngram,
+        num_branches: 1,
+        adaptive: false,
+    }
+}
+
+#[test]
+fn continuation_of_the_longest_ngram_is_proposed() {
+    let lookup = config(3, 3, 1);
+    // `2 3` occurs twice, the continuation of the most recent occurrence wins.
+    assert_eq!(
+        lookup.propose(&[1, 2, 3, 4, 2, 3, 5, 6, 7, 2, 3], 8),
+        [5, 6, 7]
+    );
+    // `4 2 3` is longer than `2 3`.
+    assert_eq!(
+        lookup.propose(&[4, 2, 3, 9, 2, 3, 8, 4, 2, 3], 8),
+        [9, 2, 3]
+    );
+    // Capped by the slots left, and by the end of the sequence.
+    assert_eq!(
+        lookup.propose(&[1, 2, 3, 4, 2, 3, 5, 6, 7, 2, 3], 2),
+        [5, 6]
+    );
+    assert_eq!(lookup.propose(&[1, 2, 1], 8), [2, 1]);
+    assert!(lookup.propose(&[1, 2, 3, 4], 8).is_empty());
+    assert!(lookup.propose(&[1, 2, 1, 2], 0).is_empty());
+    // A single token is too short an n-gram when bigrams are the shortest.
+    assert!(config(3, 3, 2).propose(&[1, 2, 3, 1], 8).is_empty());
+
+    assert!(config(0, 3, 1).verify_args().is_err());
+    assert!(config(3, 1, 2).verify_args().is_err());
+    assert!(config(3, 3, 0).verify_args().is_err());
+    assert!(lookup.verify_args().is_ok());
+}
+
+#[test]
+fn continuations_of_several_occurrences_form_a_tree() {
+    let lookup = PromptLookupConfig {
+        num_branches: 2,
+        ..config(3, 3, 1)
+    };
+    // `1 2` is followed by `5 7 1` and, earlier, by `5 6 1`: the branches share their `5`.
+    let tokens = [1, 2, 5, 6, 1, 2, 5, 7, 1, 2];
+    let tree = lookup.propose_tree(&tokens, 8);
+    assert_eq!(tree.tokens(), [5, 7, 1, 6, 1]);
+    assert_eq!(tree.parents(), [0, 1, 2, 1, 4]);
+    assert!(!tree.is_chain());
+    assert_eq!(tree.depth(5), 3);
+    assert!(tree.attends(5, 1) && tree.attends(5, 0) && !tree.attends(5, 2));
+    // The sampled tokens follow the second branch until `9`, which was not proposed.
+    assert_eq!(tree.accepted_nodes([5, 6, 1, 9]), [1, 4, 5]);
+    assert!(tree.accepted_nodes([8]).is_empty());
+
+    // Capped by the slots left, the most recent continuation first.
+    let tree = lookup.propose_tree(&tokens, 4);
+    assert_eq!(tree.tokens(), [5, 7, 1, 6]);
+    // A single branch is the chain `propose` gives.
+    let tree = config(3, 3, 1).propose_tree(&tokens, 8);
+    assert!(tree.is_chain());
+    assert_eq!(tree, DraftTree::chain(vec![5, 7, 1]));
+
+    assert!(DraftTree::new(vec![3, 4], vec![0, 2]).is_err());
+    assert!(DraftTree::new(vec![3], vec![]).is_err());
+    assert!(PromptLookupConfig {
+        num_branches: 0,
+        ..lookup
+    }
+    .verify_args()
+    .is_err());
+}
+
+#[test]
+fn speculation_does_not_change_the_output() {
+    for (block_size, num_speculative_tokens) in [(8, 3), (16, 5), (16, 1)] {
+        let mut engine = TinyEngine::new(block_size);
+        let prompts = [engine.encode(REPEATED_PROMPT), engine.encode(PROMPT)];
+        let expected = engine.generate(&prompts, MAX_TOKENS);
+        let expected_alone = prompts
+            .iter()
+            .map(|prompt| engine.generate(std::slice::from_ref(prompt), MAX_TOKENS))
+            .collect::<Vec<_>>();
+
+        let mut engine = TinyEngine::with_prompt_lookup(
+            TinyEngine::cache_config(block_size),
+            config(num_speculative_tokens, 3, 1),
+        )
+        .unwrap();
+        let case = format!("block size {block_size}, {num_speculative_tokens} speculative tokens");
+        assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected, "{case}");
+        let (proposed, accepted) = engine.speculative_tokens();
+        assert!(proposed > 0, "{case}");
+        assert!(accepted > 0 && accepted <= proposed, "{case}");
+
+        // Alone, no other sequence is verified in the same forward pass.
+        for (prompt, expected) in prompts.iter().zip(expected_alone) {
+            assert_eq!(
+                engine.generate(std::slice::from_ref(prompt), MAX_TOKENS),
+                expected,
+                "{case}"
+            );
+        }
+    }
+}
+
+#[test]
+fn tree_speculation_does_not_change_the_output() {
+    for (block_size, num_branches) in [(8, 2), (16, 3)] {
+        let mut engine = TinyEngine::new(block_size);
+        let prompts = [
+            engine.encode(BRANCHING_PROMPT),
+            engine.encode(REPEATED_PROMPT),
+            engine.encode(PROMPT),
+        ];
+        let expected = engine.generate(&prompts, MAX_TOKENS);
+
+        let lookup = PromptLookupConfig {
+            num_branches,
+            ..config(3, 3, 1)
+        };
+        let mut engine =
+            TinyEngine::with_prompt_lookup(TinyEngine::cache_config(block_size), lookup).unwrap();
+        let case = format!("block size {block_size}, {num_branches} branches");
+        assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected, "{case}");
+        let (proposed, accepted) = engine.speculative_tokens();
+        assert!(accepted > 0 && accepted <= proposed, "{case}");
+    }
+}
+
+#[test]
+fn draft_length_follows_the_acceptance_rate() {
+    let adaptive = PromptLookupConfig {
+        adaptive: true,
+        ..config(5, 3, 1)
+    };
+    let mut acceptance = DraftAcceptance::default();
+    assert_eq!(acceptance.rate(), None);
+    assert_eq!(adaptive.draft_length(&acceptance), 5);
+
+    // Half of the verified tokens accepted: a second token is accepted a quarter of the time.
+    acceptance.record(1, false);
+    assert_eq!(acceptance.rate(), Some(0.5));
+    assert_eq!(adaptive.draft_length(&acceptance), 1);
+    assert_eq!(config(5, 3, 1).draft_length(&acceptance), 5);
+
+    // Nothing accepted still proposes a token, to keep measuring.
+    let mut rejected = DraftAcceptance::default();
+    rejected.record(0, false);
+    assert_eq!(adaptive.draft_length(&rejected), 1);
+
+    // Exhausted proposals verify no rejected token, the length goes back to the maximum.
+    let mut accepted = DraftAcceptance::default();
+    for _ in 0..8 {
+        accepted.record(4, true);
+    }
+    assert_eq!(accepted.rate(), Some(1.));
+    assert_eq!(adaptive.draft_length(&accepted), 5);
+    let mut mostly = DraftAcceptance::default();
+    mostly.record(9, false);
+    assert_eq!(adaptive.draft_length(&mostly), 5);
+}
+
+#[test]
+fn adaptive_speculation_does_not_change_the_output() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = [engine.encode(REPEATED_PROMPT), engine.encode(PROMPT)];
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+
+    let lookup = PromptLookupConfig {
+        adaptive: true,
+        ..config(5, 3, 1)
+    };
+    let mut engine = TinyEngine::with_prompt_lookup(TinyEngine::cache_config(16), lookup).unwrap();
+    assert_eq!(engine.generate(&prompts, MAX_TOKENS), expected);
+    let metrics = engine.request_metrics("tiny-0").unwrap();
+    assert!(metrics.num_proposed_tokens > 0);
+    assert!(metrics.num_accepted_tokens <= metrics.num_proposed_tokens);
+    assert!(metrics.acceptance_rate().is_some());
+    let (proposed, accepted) = engine.speculative_tokens();
+    assert!(proposed >= metrics.num_proposed_tokens);
+    assert!(accepted > 0 && accepted <= proposed);
+}
+
+#[test]
+fn proposals_are_verified_on_the_host_below_min_tokens() {
+    // Stop tokens are suppressed on the host until `min_tokens`, the rejection sampler is not
+    // used for these requests.
+    let mut engine = TinyEngine::new(16);
+    engine.min_tokens = Some(MAX_TOKENS);
+    let prompt = engine.encode(REPEATED_PROMPT);
+    let expected = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+
+    let mut engine =
+        TinyEngine::with_prompt_lookup(TinyEngine::cache_config(16), config(4, 3, 1)).unwrap();
+    engine.min_tokens = Some(MAX_TOKENS);
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), expected);
+    assert!(engine.speculative_tokens().1 > 0);
+}
+
+#[test]
+fn prompt_lookup_is_rejected_with_attention_sinks() {
+    let cache_config = CacheConfig {
+        attention_sinks: Some(AttentionSinks {
+            sink_blocks: 1,
+            window_blocks: 2,
+        }),
+        ..TinyEngine::cache_config(8)
+    };
+    let err = TinyEngine::with_prompt_lookup(cache_config, config(3, 3, 1))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("Prompt lookup"), "{err}");
+}
@@ -0,0 +1,77 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::backend::rejection_sample;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn probs(rows: &[[f32; 4]]) -> Tensor {
+    Tensor::from_iter(rows.iter().flatten().copied(), &Device::Cpu)
+        .unwrap()
+        .reshape((rows.len(), 4))
+        .unwrap()
+}
+
+#[test]
+fn greedy_verification_accepts_the_argmax() {
+    let probs = probs(&[
+        // The first seq proposes 2 and 0, its rows favour 2, then 1.
+        [0.1, 0.2, 0.6, 0.1],
+        [0.1, 0.5, 0.2, 0.2],
+        [0.3, 0.3, 0.2, 0.2],
+        // The second one proposes 3 and 1, both favoured, then ties between 0 and 3.
+        [0.1, 0.2, 0.3, 0.4],
+        [0.1, 0.7, 0.1, 0.1],
+        [0.4, 0.1, 0.1, 0.4],
+        // The third one proposes nothing.
+        [0.1, 0.1, 0.1, 0.7],
+    ]);
+    let accepted =
+        rejection_sample(&probs, &[vec![2, 0], vec![3, 1], vec![]], &[0.; 14], true).unwrap();
+    assert_eq!(accepted, [vec![2, 1], vec![3, 1, 0], vec![3]]);
+}
+
+#[test]
+fn draft_tokens_are_accepted_with_their_probability() {
+    let probs = probs(&[
+        [0.1, 0.2, 0.3, 0.4],
+        [0.25, 0.25, 0.25, 0.25],
+        [0.1, 0.2, 0.3, 0.4],
+        [0.1, 0.2, 0.3, 0.4],
+    ]);
+    // Token 3 is accepted with 0.35 < 0.4, and the bonus token is the first one past 0.3 of the
+    // mass. Token 1 is rejected with 0.9 >= 0.2, and replaced without it: 0.4 of the remaining
+    // mass of 0.8 is passed at token 2.
+    let uniform = [0.35, 0., 0., 0.3, 0.9, 0.4, 0., 0.];
+    let accepted = rejection_sample(&probs, &[vec![3], vec![1]], &uniform, false).unwrap();
+    assert_eq!(accepted, [vec![3, 1], vec![2]]);
+}
+
+#[test]
+fn verified_tokens_follow_the_model_distribution() {
+    // Whether the draft token is accepted or replaced, the first token is distributed as the
+    // model says.
+    let model = [0.5, 0.3, 0.2, 0.];
+    let num_seqs = 20000;
+    let rows = vec![model; 2 * num_seqs];
+    let drafts = vec![vec![1]; num_seqs];
+    let mut rng = StdRng::seed_from_u64(0);
+    let uniform = (0..4 * num_seqs).map(|_| rng.gen()).collect::<Vec<f32>>();
+    let accepted = rejection_sample(&probs(&rows), &drafts, &uniform, false).unwrap();
+
+    let mut counts = [0usize; 4];
+    for tokens in &accepted {
+        counts[tokens[0] as usize] += 1;
+        assert_eq!(tokens.len(), if tokens[0] == 1 { 2 } else { 1 });
+    }
+    for (count, p) in counts.iter().zip(model) {
+        let frequency = *count as f32 / num_seqs as f32;
+        assert!((frequency - p).abs() < 0.02, "{counts:?}");
+    }
+}
+
+#[test]
+fn rows_have_to_match_the_draft_tokens() {
+    let probs = probs(&[[0.1, 0.2, 0.3, 0.4]; 3]);
+    assert!(rejection_sample(&probs, &[vec![1]], &[0.; 6], true).is_err());
+    assert!(rejection_sample(&probs, &[vec![1, 2]], &[0.; 4], true).is_err());
+    assert!(rejection_sample(&probs, &[], &[0.; 6], true).is_err());
+    assert!(rejection_sample(&probs, &[vec![1, 2]], &[0.; 6], true).is_ok());
+}
@@ -0,0 +1,162 @@
+use candle_vllm::scheduler::request_log::{prompt_hash, AcceptedRequest, RequestLog};
+use std::{io::Write, path::PathBuf};
+
+mod common;
+use common::{sampling_params, tiny_model::TinyEngine};
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 8;
+
+fn log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "candle-vllm-request-log-{name}-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn request(request_id: &str, prompt_tokens: Vec<u32>) -> AcceptedRequest {
+    AcceptedRequest::new(
+        request_id.to_string(),
+        prompt_tokens,
+        sampling_params(),
+        false,
+        false,
+    )
+}
+
+fn request_ids(requests: &[AcceptedRequest]) -> Vec<&str> {
+    requests
+        .iter()
+        .map(|request| request.request_id.as_str())
+        .collect()
+}
+
+#[test]
+fn unfinished_requests_are_read_back_once() {
+    let path = log_path("unfinished");
+    let (mut log, unfinished) = RequestLog::open(&path).unwrap();
+    assert!(unfinished.is_empty());
+    for (request_id, tokens) in [("a", vec![5, 9]), ("b", vec![11]), ("c", vec![3, 50, 7])] {
+        log.accepted(request(request_id, tokens)).unwrap();
+    }
+    log.finished("b").unwrap();
+    // Requests that were never logged are ignored.
+    log.finished("d").unwrap();
+    drop(log);
+
+    let (_log, unfinished) = RequestLog::open(&path).unwrap();
+    assert_eq!(request_ids(&unfinished), ["a", "c"]);
+    assert_eq!(unfinished[1].prompt_tokens, [3, 50, 7]);
+    assert_eq!(unfinished[1].prompt_hash, prompt_hash(&[3, 50, 7]));
+    assert_eq!(unfinished[1].sampling_params.temperature, 0.7);
+
+    // Reported once, the log starts over.
+    let (_log, unfinished) = RequestLog::open(&path).unwrap();
+    assert!(unfinished.is_empty());
+}
+
+#[test]
+fn log_is_emptied_once_nothing_is_pending() {
+    let path = log_path("emptied");
+    let (mut log, _) = RequestLog::open(&path).unwrap();
+    log.accepted(request("a", vec![5])).unwrap();
+    log.accepted(request("b", vec![9])).unwrap();
+    log.finished("a").unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() > 0);
+    log.finished("b").unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+    log.accepted(request("c", vec![17])).unwrap();
+    drop(log);
+    let (_, unfinished) = RequestLog::open(&path).unwrap();
+    assert_eq!(request_ids(&unfinished), ["c"]);
+}
+
+#[test]
+fn torn_entry_is_skipped() {
+    let path = log_path("torn");
+    let (mut log, _) = RequestLog::open(&path).unwrap();
+    log.accepted(request("a", vec![5, 9])).unwrap();
+    drop(log);
+    // The crash happened while the next entry was written.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(br#"{"event":"accepted","request_id":"b","prompt_h"#)
+        .unwrap();
+    drop(file);
+
+    let (_, unfinished) = RequestLog::open(&path).unwrap();
+    assert_eq!(request_ids(&unfinished), ["a"]);
+}
+
+#[test]
+fn only_greedy_requests_without_images_are_idempotent() {
+    let random = request("a", vec![5]);
+    assert!(!random.is_idempotent());
+    let mut greedy = random.clone();
+    greedy.sampling_params.temperature = 0.0;
+    assert!(greedy.is_idempotent());
+    let mut with_images = greedy.clone();
+    with_images.has_images = true;
+    assert!(!with_images.is_idempotent());
+}
+
+#[test]
+fn replayed_requests_match_their_output_before_the_crash() {
+    let path = log_path("replay");
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let expected = engine.generate(&prompts, MAX_TOKENS);
+
+    // The first request is answered before the crash, the others are still queued.
+    let mut crashed = TinyEngine::new(16);
+    let (log, _) = RequestLog::open(&path).unwrap();
+    crashed.set_request_log(log);
+    crashed.generate(&prompts[..1], MAX_TOKENS);
+    crashed.submit(&prompts, MAX_TOKENS);
+    drop(crashed);
+
+    let restarted = TinyEngine::new(16);
+    let (log, unfinished) = RequestLog::open(&path).unwrap();
+    assert_eq!(request_ids(&unfinished), ["tiny-1", "tiny-2"]);
+    // Logged with the serving stack that accepted them, the same one here.
+    let system_fingerprint = restarted.system_fingerprint();
+    assert!(unfinished
+        .iter()
+        .all(|request| request.system_fingerprint.as_ref() == Some(&system_fingerprint)));
+    restarted.set_request_log(log);
+    let outputs = restarted.recover(unfinished, true);
+    assert_eq!(outputs["tiny-1"], expected[0]);
+    assert_eq!(outputs["tiny-2"], expected[1]);
+    let recovered = restarted.recovered_requests();
+    assert!(recovered.iter().all(|request| request.replayed));
+    assert_eq!(recovered[1].prompt_tokens, prompts[1].len());
+
+    // The replayed requests finished, nothing is left for the next start.
+    drop(restarted);
+    let (_, unfinished) = RequestLog::open(&path).unwrap();
+    assert!(unfinished.is_empty());
+}
+
+#[test]
+fn requests_not_replayed_are_reported_lost() {
+    let path = log_path("lost");
+    let mut crashed = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| crashed.encode(prompt));
+    let (log, _) = RequestLog::open(&path).unwrap();
+    crashed.set_request_log(log);
+    crashed.submit(&prompts, MAX_TOKENS);
+    drop(crashed);
+
+    let restarted = TinyEngine::new(16);
+    let (_, unfinished) = RequestLog::open(&path).unwrap();
+    assert!(restarted.recover(unfinished, false).is_empty());
+    let recovered = restarted.recovered_requests();
+    assert_eq!(recovered.len(), 2);
+    assert!(recovered.iter().all(|request| !request.replayed));
+    assert_eq!(recovered[0].prompt_hash, prompt_hash(prompts[0].get_ids()));
+}
@@ -0,0 +1,97 @@
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use candle_vllm::{
+    openai::{openai_server::chat_completions, responses::ChatResponder},
+    scheduler::sequence::SequenceGroupMetrics,
+};
+use serde_json::json;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = [
+    "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60",
+    "t3 t1 t4 t1 t5 t9 t2 t6 t5 t3",
+];
+const MAX_TOKENS: usize = 8;
+
+#[test]
+fn metrics_are_measured_between_the_stages_of_a_request() {
+    let arrival_time = SystemTime::UNIX_EPOCH;
+    let ms = Duration::from_millis;
+    let mut metrics = SequenceGroupMetrics {
+        arrival_time,
+        first_scheduled_time: None,
+        first_token_time: None,
+        finished_time: None,
+        num_output_tokens: 0,
+        num_proposed_tokens: 0,
+        num_accepted_tokens: 0,
+    };
+    assert_eq!(metrics.queue_time(), None);
+    assert_eq!(metrics.time_to_first_token(), None);
+
+    metrics.first_scheduled_time = Some(arrival_time + ms(20));
+    metrics.first_token_time = Some(arrival_time + ms(50));
+    metrics.finished_time = Some(arrival_time + ms(110));
+    metrics.num_output_tokens = 4;
+    assert_eq!(metrics.queue_time(), Some(ms(20)));
+    assert_eq!(metrics.time_to_first_token(), Some(ms(50)));
+    assert_eq!(metrics.time_per_output_token(), Some(ms(20)));
+    // Nothing is decoded after a single token.
+    metrics.num_output_tokens = 1;
+    assert_eq!(metrics.time_per_output_token(), None);
+}
+
+#[test]
+fn the_engine_records_the_stages_of_each_request() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let generated = engine.generate(&prompts, MAX_TOKENS);
+    for (i, tokens) in generated.iter().enumerate() {
+        let metrics = engine.request_metrics(&format!("tiny-{i}")).unwrap();
+        let scheduled = metrics.first_scheduled_time.unwrap();
+        let first_token = metrics.first_token_time.unwrap();
+        let finished = metrics.finished_time.unwrap();
+        assert!(metrics.arrival_time <= scheduled);
+        assert!(scheduled <= first_token);
+        assert!(first_token <= finished);
+        assert_eq!(metrics.num_output_tokens, tokens.len());
+        assert!(metrics.time_per_output_token().is_some());
+    }
+    assert!(engine.request_metrics("unknown").is_none());
+}
+
+#[test]
+fn completions_report_their_queue_time_and_ttft_in_headers() {
+    let engine = TinyEngine::new(16);
+    let request = json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": PROMPTS[0]}],
+        "max_tokens": MAX_TOKENS,
+        "temperature": 0.0,
+    });
+    let request = serde_json::from_value(request).unwrap();
+    let response = Runtime::new().unwrap().block_on(async {
+        let responder: ChatResponder = chat_completions(
+            State(Arc::new(engine.server_data(None))),
+            HeaderMap::new(),
+            Ok(Json(request)),
+        )
+        .await;
+        responder.into_response()
+    });
+    assert_eq!(response.status(), StatusCode::OK);
+    for header in ["x-request-queue-ms", "x-ttft-ms"] {
+        let value = response.headers().get(header).unwrap();
+        assert!(value.to_str().unwrap().parse::<u64>().is_ok(), "{header}");
+    }
+}
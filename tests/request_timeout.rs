@@ -0,0 +1,97 @@
+use candle_vllm::scheduler::{sequence::SequenceGroup, Scheduler, SchedulerConfig};
+use std::time::Duration;
+
+mod common;
+use common::{cache_config, sampling_params, sequence_group, tiny_model::TinyEngine};
+
+const PROMPT_A: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+
+fn finish_reason(group: &SequenceGroup) -> String {
+    let seq = group.get_seqs().values().next().unwrap();
+    let finish_reason = seq.deref().get_finish_reason();
+    finish_reason
+}
+
+#[test]
+fn rejects_non_positive_timeouts() {
+    let mut params = sampling_params();
+    assert!(params.set_timeout(Some(1.5)).is_ok());
+    assert_eq!(params.timeout, Some(Duration::from_millis(1500)));
+    for timeout in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(params.set_timeout(Some(timeout)).is_err(), "{timeout}");
+    }
+    assert!(params.set_timeout(None).is_ok());
+    assert_eq!(params.timeout, None);
+}
+
+#[test]
+fn scheduler_finishes_and_frees_timed_out_groups() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 4,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    let mut running = sequence_group(0, 40, block_size);
+    running.sampling_params.timeout = Some(Duration::from_millis(200));
+    scheduler.add_sequence(running);
+    scheduler.add_sequence(sequence_group(1, 8, block_size));
+    let mut expired = sequence_group(2, 8, block_size);
+    expired.created_time -= Duration::from_secs(1);
+    expired.sampling_params.timeout = Some(Duration::from_millis(200));
+    scheduler.add_sequence(expired);
+
+    // The expired group times out while waiting, the others are scheduled.
+    let output = scheduler.schedule();
+    assert_eq!(output.timed_out.len(), 1);
+    assert_eq!(output.timed_out[0].request_id, "test-2");
+    assert_eq!(finish_reason(&output.timed_out[0]), "timeout");
+    assert_eq!(output.scheduled.len(), 2);
+
+    // Past its deadline the running group is removed and its blocks are freed.
+    std::thread::sleep(Duration::from_millis(250));
+    let output = scheduler.schedule();
+    assert_eq!(output.timed_out.len(), 1);
+    assert_eq!(output.timed_out[0].request_id, "test-0");
+    assert_eq!(finish_reason(&output.timed_out[0]), "timeout");
+    let snapshot = scheduler.snapshot();
+    assert_eq!(snapshot.running.len(), 1);
+    assert_eq!(snapshot.running[0].request_id, "test-1");
+    assert_eq!(
+        snapshot.num_free_gpu_blocks,
+        snapshot.num_gpu_blocks - snapshot.running[0].seqs[0].block_table.len()
+    );
+}
+
+#[test]
+fn timed_out_request_streams_timeout_finish_reason() {
+    let mut engine = TinyEngine::new(8);
+    let a = engine.encode(PROMPT_A);
+
+    engine.timeout = Some(1e-9);
+    let chunks = engine.stream(std::slice::from_ref(&a), 1, 24).remove(0);
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].choices[0].delta.content, None);
+    assert_eq!(
+        chunks[0].choices[0].finish_reason.as_deref(),
+        Some("timeout")
+    );
+    assert_eq!(
+        engine.generate(std::slice::from_ref(&a), 24),
+        [Vec::<usize>::new()]
+    );
+
+    // Nothing of the timed out requests is left behind.
+    engine.timeout = None;
+    assert_eq!(engine.generate(&[a], 24)[0].len(), 25);
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+}
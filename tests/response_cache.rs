@@ -0,0 +1,127 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use candle_vllm::openai::{
+    openai_server::{chat_completions, completions},
+    response_cache::ResponseCache,
+    responses::ChatResponder,
+    OpenAIServerData,
+};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+fn request(fields: Value) -> Value {
+    let mut request = json!({
+        "model": "llama",
+        "temperature": 0.0,
+        "max_tokens": 4,
+        "ignore_eos": true,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    request
+}
+
+fn chat(data: &Arc<OpenAIServerData>, fields: Value) -> ChatResponder {
+    let mut request = request(fields);
+    request["messages"] = json!([{"role": "user", "content": "t5 t9 t17"}]);
+    let request = serde_json::from_value(request).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        chat_completions(State(data.clone()), HeaderMap::new(), Ok(Json(request))).await
+    })
+}
+
+fn complete(data: &Arc<OpenAIServerData>, fields: Value) -> ChatResponder {
+    let request = serde_json::from_value(request(fields)).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        completions(State(data.clone()), HeaderMap::new(), Ok(Json(request))).await
+    })
+}
+
+fn cached_server(engine: &TinyEngine, max_entries: usize) -> Arc<OpenAIServerData> {
+    let mut data = engine.server_data(None);
+    data.response_cache = Some(ResponseCache::new(max_entries, Duration::from_secs(60)));
+    Arc::new(data)
+}
+
+#[test]
+fn identical_greedy_requests_are_served_from_the_cache() {
+    let engine = TinyEngine::new(16);
+    let data = cached_server(&engine, 8);
+    let ChatResponder::Completion(first, Some(_)) = chat(&data, json!({})) else {
+        panic!("the request was not generated");
+    };
+    assert_eq!(data.response_cache.as_ref().unwrap().len(), 1);
+
+    // Served without metrics, as the request was not scheduled, under a new id.
+    let ChatResponder::Completion(second, None) = chat(&data, json!({})) else {
+        panic!("the request was not served from the cache");
+    };
+    assert_ne!(first.id, second.id);
+    assert_eq!(
+        first.choices[0].message.content,
+        second.choices[0].message.content
+    );
+    assert_eq!(
+        first.usage.completion_tokens,
+        second.usage.completion_tokens
+    );
+
+    // A different request, or one to the other endpoint, is generated.
+    let ChatResponder::Completion(_, Some(_)) = chat(&data, json!({"max_tokens": 3})) else {
+        panic!("a different request was served from the cache");
+    };
+    let ChatResponder::TextCompletion(_, Some(_)) = complete(&data, json!({"prompt": "t5 t9"}))
+    else {
+        panic!("the completion was not generated");
+    };
+    let ChatResponder::TextCompletion(_, None) = complete(&data, json!({"prompt": "t5 t9"})) else {
+        panic!("the completion was not served from the cache");
+    };
+}
+
+#[test]
+fn sampled_requests_are_not_cached() {
+    let engine = TinyEngine::new(16);
+    let data = cached_server(&engine, 8);
+    let ChatResponder::Completion(..) = chat(&data, json!({"temperature": 0.8, "seed": 7})) else {
+        panic!("the request was not completed");
+    };
+    assert!(data.response_cache.as_ref().unwrap().is_empty());
+}
+
+#[test]
+fn the_least_recently_used_response_is_dropped() {
+    let engine = TinyEngine::new(16);
+    let data = cached_server(&engine, 2);
+    for prompt in ["t5", "t9", "t5", "t17"] {
+        complete(&data, json!({ "prompt": prompt }));
+    }
+    assert_eq!(data.response_cache.as_ref().unwrap().len(), 2);
+    // "t5" was used after "t9", which made room for "t17".
+    let ChatResponder::TextCompletion(_, None) = complete(&data, json!({"prompt": "t5"})) else {
+        panic!("the most recently used response was dropped");
+    };
+    let ChatResponder::TextCompletion(_, Some(_)) = complete(&data, json!({"prompt": "t9"})) else {
+        panic!("the least recently used response was kept");
+    };
+}
+
+#[test]
+fn expired_responses_are_generated_again() {
+    let engine = TinyEngine::new(16);
+    let mut data = engine.server_data(None);
+    data.response_cache = Some(ResponseCache::new(8, Duration::ZERO));
+    let data = Arc::new(data);
+    complete(&data, json!({"prompt": "t5"}));
+    std::thread::sleep(Duration::from_millis(2));
+    let ChatResponder::TextCompletion(_, Some(_)) = complete(&data, json!({"prompt": "t5"})) else {
+        panic!("an expired response was served");
+    };
+}
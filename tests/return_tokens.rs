@@ -0,0 +1,89 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use candle_vllm::openai::{
+    openai_server::{chat_completions, completions},
+    responses::ChatResponder,
+    OpenAIServerData,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17";
+const MAX_TOKENS: usize = 6;
+
+fn request(fields: Value) -> Value {
+    let mut request = json!({
+        "model": "llama",
+        "temperature": 0.0,
+        "max_tokens": MAX_TOKENS,
+        "ignore_eos": true,
+    });
+    for (field, value) in fields.as_object().unwrap() {
+        request[field] = value.clone();
+    }
+    request
+}
+
+fn complete(data: OpenAIServerData, fields: Value) -> ChatResponder {
+    let request = serde_json::from_value(request(fields)).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        completions(State(Arc::new(data)), HeaderMap::new(), Ok(Json(request))).await
+    })
+}
+
+fn chat(data: OpenAIServerData, fields: Value) -> ChatResponder {
+    let request = serde_json::from_value(request(fields)).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        chat_completions(State(Arc::new(data)), HeaderMap::new(), Ok(Json(request))).await
+    })
+}
+
+fn ids(engine: &TinyEngine, text: &str) -> Vec<usize> {
+    let encoding = engine.encode(text);
+    encoding.get_ids().iter().map(|&id| id as usize).collect()
+}
+
+#[test]
+fn completions_return_the_token_ids_of_the_prompt_and_the_choices() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let generated = engine.generate(&[prompt], MAX_TOKENS).remove(0);
+    let fields = json!({"prompt": PROMPT, "return_tokens": true});
+    let ChatResponder::TextCompletion(response, _) = complete(engine.server_data(None), fields)
+    else {
+        panic!("the request was not completed");
+    };
+    assert_eq!(response.prompt_token_ids, Some(ids(&engine, PROMPT)));
+    assert_eq!(response.choices[0].token_ids, Some(generated));
+
+    // They are left out unless asked for.
+    let fields = json!({"prompt": PROMPT});
+    let ChatResponder::TextCompletion(response, _) = complete(engine.server_data(None), fields)
+    else {
+        panic!("the request was not completed");
+    };
+    assert_eq!(response.prompt_token_ids, None);
+    assert_eq!(response.choices[0].token_ids, None);
+    let response = serde_json::to_value(&response).unwrap();
+    assert!(response.get("prompt_token_ids").is_none());
+    assert!(response["choices"][0].get("token_ids").is_none());
+}
+
+#[test]
+fn chat_completions_return_the_token_ids_of_the_templated_prompt() {
+    let engine = TinyEngine::new(16);
+    let fields = json!({"messages": [{"role": "user", "content": PROMPT}], "return_tokens": true});
+    let ChatResponder::Completion(response, _) = chat(engine.server_data(None), fields) else {
+        panic!("the request was not completed");
+    };
+    let prompt_token_ids = response.prompt_token_ids.unwrap();
+    assert_eq!(prompt_token_ids.len(), response.usage.prompt_tokens);
+    let token_ids = response.choices[0].token_ids.clone().unwrap();
+    assert_eq!(token_ids.len(), response.usage.completion_tokens);
+}
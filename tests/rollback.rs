@@ -0,0 +1,71 @@
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17 t33";
+const MAX_TOKENS: usize = 12;
+const BLOCK_SIZE: usize = 4;
+
+/// Step `engine` until its only request finishes and return the tokens it generated.
+fn finish(engine: &TinyEngine) -> Vec<usize> {
+    let mut tokens = Vec::new();
+    while engine.has_unfinished_requests() {
+        for output in engine.step().unwrap() {
+            tokens = output.token_ids[0].clone();
+        }
+    }
+    tokens
+}
+
+/// Step `engine` `num_steps` times and return the tokens generated so far.
+fn run(engine: &TinyEngine, num_steps: usize) -> Vec<usize> {
+    let mut tokens = Vec::new();
+    for _ in 0..num_steps {
+        tokens = engine.step().unwrap().remove(0).token_ids.remove(0);
+    }
+    tokens
+}
+
+#[test]
+fn rolled_back_tokens_are_generated_again_from_the_kv_cache() {
+    let mut expected = TinyEngine::new(BLOCK_SIZE);
+    let prompt = expected.encode(PROMPT);
+    let expected = expected.generate(&[prompt.clone()], MAX_TOKENS).remove(0);
+
+    let mut engine = TinyEngine::new(BLOCK_SIZE);
+    engine.submit(&[prompt], MAX_TOKENS);
+    assert_eq!(run(&engine, 9), expected[..9]);
+    let num_free_blocks = engine.scheduler_snapshot().num_free_gpu_blocks;
+    engine.rollback("tiny-0", 6).unwrap();
+    // The blocks past the 7 tokens left are freed.
+    assert!(engine.scheduler_snapshot().num_free_gpu_blocks >= num_free_blocks + 2);
+    // Greedy decoding generates the same tokens again, up to `max_tokens`.
+    assert_eq!(run(&engine, 1), expected[..4]);
+    assert_eq!(finish(&engine), expected);
+}
+
+#[test]
+fn the_only_choice_rolls_back_into_its_prompt() {
+    let mut expected = TinyEngine::new(BLOCK_SIZE);
+    let prompt = expected.encode(PROMPT);
+    let shorter = expected.encode("t5 t9 t17");
+    let expected = expected.generate(&[shorter], MAX_TOKENS).remove(0);
+
+    let mut engine = TinyEngine::new(BLOCK_SIZE);
+    engine.submit(&[prompt], MAX_TOKENS);
+    run(&engine, 3);
+    engine.rollback("tiny-0", 4).unwrap();
+    assert_eq!(finish(&engine), expected);
+}
+
+#[test]
+fn only_unfinished_requests_keeping_a_token_roll_back() {
+    let mut engine = TinyEngine::new(BLOCK_SIZE);
+    let prompt = engine.encode(PROMPT);
+    assert!(engine.rollback("tiny-0", 1).is_err());
+    engine.submit(&[prompt], MAX_TOKENS);
+    run(&engine, 2);
+    assert!(engine.rollback("tiny-0", 6).is_err());
+    engine.rollback("tiny-0", 0).unwrap();
+    finish(&engine);
+    assert!(engine.rollback("tiny-0", 1).is_err());
+}
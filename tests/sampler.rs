@@ -0,0 +1,75 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::backend::sample_tokens;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Logits whose softmax at a temperature of 1 is `probs`.
+fn logits(rows: &[[f32; 4]]) -> Tensor {
+    Tensor::from_iter(rows.iter().flatten().map(|p| p.ln()), &Device::Cpu)
+        .unwrap()
+        .reshape((rows.len(), 4))
+        .unwrap()
+}
+
+#[test]
+fn top_k_of_one_takes_the_argmax() {
+    let logits = logits(&[[0.1, 0.2, 0.6, 0.1], [0.4, 0.3, 0.2, 0.1]]);
+    for u in [0., 0.5, 0.99] {
+        let tokens = sample_tokens(&logits, 1., Some(1), None, &[u, u]).unwrap();
+        assert_eq!(tokens, [2, 0]);
+    }
+}
+
+#[test]
+fn tokens_are_sampled_among_the_kept_ones() {
+    let logits = logits(&[[0.5, 0.3, 0.15, 0.05]; 3]);
+    // Without filtering, 0.9 of the mass is passed at token 2.
+    let tokens = sample_tokens(&logits, 1., None, None, &[0.9, 0.3, 0.6]).unwrap();
+    assert_eq!(tokens, [2, 0, 1]);
+    // Top-p of 0.7 keeps tokens 0 and 1, of which 0.9 of the mass is passed at token 1.
+    let tokens = sample_tokens(&logits, 1., None, Some(0.7), &[0.9, 0.3, 0.99]).unwrap();
+    assert_eq!(tokens, [1, 0, 1]);
+    // Top-k of 3, then top-p of 0.9 of the mass of the whole vocabulary, keeps all three.
+    let tokens = sample_tokens(&logits, 1., Some(3), Some(0.9), &[0.99, 0.1, 0.5]).unwrap();
+    assert_eq!(tokens, [2, 0, 0]);
+    // Top-p above the mass of the top-k tokens keeps them all.
+    let tokens = sample_tokens(&logits, 1., Some(2), Some(0.95), &[0.99, 0.7, 0.]).unwrap();
+    assert_eq!(tokens, [1, 1, 0]);
+}
+
+#[test]
+fn tokens_tied_with_the_last_one_kept_are_kept() {
+    let logits = logits(&[[0.2, 0.4, 0.4, 0.0001]]);
+    // Tokens 1 and 2 are tied for the first place, the second half of the mass is token 2's.
+    let tokens = sample_tokens(&logits, 1., Some(1), None, &[0.75]).unwrap();
+    assert_eq!(tokens, [2]);
+    let tokens = sample_tokens(&logits, 1., None, Some(0.3), &[0.25]).unwrap();
+    assert_eq!(tokens, [1]);
+}
+
+#[test]
+fn sampled_tokens_follow_the_tempered_distribution() {
+    // At a temperature of 0.5, probabilities are squared before being normalized.
+    let num_rows = 20000;
+    let logits = logits(&vec![[0.1, 0.2, 0.3, 0.4]; num_rows]);
+    let mut rng = StdRng::seed_from_u64(0);
+    let uniform = (0..num_rows).map(|_| rng.gen()).collect::<Vec<f32>>();
+    let tokens = sample_tokens(&logits, 0.5, None, None, &uniform).unwrap();
+
+    let mut counts = [0usize; 4];
+    for token in tokens {
+        counts[token as usize] += 1;
+    }
+    for (count, p) in counts.iter().zip([1., 4., 9., 16.]) {
+        let frequency = *count as f32 / num_rows as f32;
+        assert!((frequency - p / 30.).abs() < 0.02, "{counts:?}");
+    }
+}
+
+#[test]
+fn rows_need_a_draw_and_a_temperature() {
+    let logits = logits(&[[0.1, 0.2, 0.3, 0.4]; 2]);
+    assert!(sample_tokens(&logits, 1., None, None, &[0.5]).is_err());
+    // Greedy rows take the argmax instead.
+    assert!(sample_tokens(&logits, 0., None, None, &[0.5, 0.5]).is_err());
+    assert!(sample_tokens(&logits, 1., None, None, &[0.5, 0.5]).is_ok());
+}
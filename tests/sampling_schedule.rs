@@ -0,0 +1,93 @@
+use candle_vllm::openai::sampling_params::{SamplingSchedule, SamplingStage};
+
+mod common;
+use common::{sampling_params, tiny_model::TinyEngine};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 24;
+
+fn stage(until: Option<usize>, temperature: f32, anneal: bool) -> SamplingStage {
+    SamplingStage {
+        until,
+        temperature: Some(temperature),
+        top_p: None,
+        anneal,
+    }
+}
+
+#[test]
+fn stages_apply_by_the_tokens_generated() {
+    let schedule = SamplingSchedule::new(vec![
+        stage(Some(4), 1.5, false),
+        SamplingStage {
+            top_p: Some(0.5),
+            ..stage(Some(8), 1.0, true)
+        },
+        stage(Some(12), 0.2, false),
+    ])
+    .unwrap();
+    assert_eq!(schedule.at(0, 0.7, 0.9), (1.5, 0.9));
+    assert_eq!(schedule.at(3, 0.7, 0.9), (1.5, 0.9));
+    // Annealed from the second stage to the third one.
+    assert_eq!(schedule.at(4, 0.7, 0.9), (1.0, 0.5));
+    let (temperature, top_p) = schedule.at(6, 0.7, 0.9);
+    assert!((temperature - 0.6).abs() < 1e-6, "{temperature}");
+    assert!((top_p - 0.7).abs() < 1e-6, "{top_p}");
+    assert_eq!(schedule.at(8, 0.7, 0.9), (0.2, 0.9));
+    // The request's own past the last stage.
+    assert_eq!(schedule.at(12, 0.7, 0.9), (0.7, 0.9));
+}
+
+#[test]
+fn invalid_schedules_are_rejected() {
+    assert!(SamplingSchedule::new(vec![]).is_err());
+    // Stages end in order, only the last one may last until the end.
+    assert!(
+        SamplingSchedule::new(vec![stage(Some(4), 1.0, false), stage(Some(4), 0.5, false)])
+            .is_err()
+    );
+    assert!(
+        SamplingSchedule::new(vec![stage(None, 1.0, false), stage(Some(4), 0.5, false)]).is_err()
+    );
+    assert!(SamplingSchedule::new(vec![stage(Some(0), 1.0, false)]).is_err());
+    assert!(SamplingSchedule::new(vec![stage(None, 1.0, true)]).is_err());
+    assert!(SamplingSchedule::new(vec![stage(Some(4), -1.0, false)]).is_err());
+    assert!(SamplingSchedule::new(vec![SamplingStage {
+        top_p: Some(0.0),
+        ..stage(Some(4), 1.0, false)
+    }])
+    .is_err());
+    assert!(
+        SamplingSchedule::new(vec![stage(Some(4), 1.0, true), stage(None, 0.0, false)]).is_ok()
+    );
+
+    let mut params = sampling_params();
+    assert!(params
+        .set_sampling_schedule(Some(vec![stage(Some(4), 1.0, false)]))
+        .is_ok());
+    assert!(params.sampling_schedule.is_some());
+    params.use_beam_search = true;
+    assert!(params
+        .set_sampling_schedule(Some(vec![stage(Some(4), 1.0, false)]))
+        .is_err());
+}
+
+#[test]
+fn each_sequence_samples_by_its_own_stage() {
+    let mut engine = TinyEngine::new(16);
+    let prompt = engine.encode(PROMPT);
+    let greedy = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+
+    // A schedule that stays greedy changes nothing.
+    engine.sampling_schedule = Some(vec![stage(Some(8), 0.0, false), stage(None, 0.0, false)]);
+    assert_eq!(
+        engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS),
+        greedy
+    );
+
+    // Greedy for 4 tokens, then sampled at a high temperature.
+    engine.sampling_schedule = Some(vec![stage(Some(4), 0.0, false), stage(None, 100.0, false)]);
+    let generated = engine.generate(&[prompt], MAX_TOKENS).remove(0);
+    assert_eq!(generated[..4], greedy[0][..4]);
+    assert_ne!(generated, greedy[0]);
+}
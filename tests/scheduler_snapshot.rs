@@ -0,0 +1,94 @@
+use candle_vllm::scheduler::{Scheduler, SchedulerConfig};
+use std::collections::BTreeMap;
+
+mod common;
+use common::{cache_config, sequence_group};
+
+#[test]
+fn snapshot_reports_queues_and_block_tables() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 2,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    scheduler.add_sequence(sequence_group(0, 40, block_size));
+    scheduler.add_sequence(sequence_group(1, 8, block_size));
+
+    let snapshot = scheduler.snapshot();
+    assert!(snapshot.running.is_empty());
+    assert_eq!(snapshot.waiting.len(), 2);
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+    assert!(snapshot.waiting[0].seqs[0].block_table.is_empty());
+
+    // Only one sequence fits in the batch, the other keeps waiting without blocks.
+    scheduler.schedule();
+    let snapshot = scheduler.snapshot();
+    assert_eq!(snapshot.running.len(), 1);
+    assert_eq!(snapshot.waiting.len(), 1);
+    assert_eq!(snapshot.running[0].request_id, "test-0");
+
+    let running = &snapshot.running[0].seqs[0];
+    assert_eq!(running.prompt_len, 40);
+    assert_eq!(running.status, "Running");
+    assert_eq!(running.block_table.len(), 3);
+    assert_eq!(
+        snapshot.num_free_gpu_blocks,
+        snapshot.num_gpu_blocks - running.block_table.len()
+    );
+    assert!(snapshot.waiting[0].seqs[0].block_table.is_empty());
+
+    let json = serde_json::to_value(&snapshot).unwrap();
+    assert_eq!(
+        json["running"][0]["seqs"][0]["block_table"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn cache_stats_count_blocks_and_fragmentation() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 4,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    let stats = scheduler.snapshot().cache_stats();
+    assert_eq!(stats.num_free_gpu_blocks, stats.num_gpu_blocks);
+    assert!(stats.gpu_blocks_per_seq.is_empty());
+    assert_eq!(stats.fragmentation, 0.);
+    assert_eq!(stats.prefix_cache_hit_rate, None);
+
+    scheduler.add_sequence(sequence_group(0, 40, block_size));
+    scheduler.add_sequence(sequence_group(1, 8, block_size));
+    scheduler.schedule();
+    let stats = scheduler.snapshot().cache_stats();
+    assert_eq!(stats.gpu_blocks_per_seq, BTreeMap::from([(0, 3), (1, 1)]));
+    assert!(stats.cpu_blocks_per_seq.is_empty());
+    assert_eq!(stats.num_free_gpu_blocks, stats.num_gpu_blocks - 4);
+    // 48 tokens in 4 blocks of 16 slots.
+    assert_eq!(stats.fragmentation, 0.25);
+
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json["gpu_blocks_per_seq"]["0"], 3);
+    assert!(json["prefix_cache_hit_rate"].is_null());
+}
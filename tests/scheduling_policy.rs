@@ -0,0 +1,186 @@
+use candle_vllm::scheduler::{
+    policy::{FairShare, Fcfs, Priority, SchedulingPolicy},
+    sequence::SequenceGroup,
+    Scheduler, SchedulerConfig,
+};
+use std::{cmp::Ordering, collections::VecDeque, sync::Arc};
+
+mod common;
+use common::{cache_config, sequence_group};
+
+const BLOCK_SIZE: usize = 16;
+
+fn scheduler(policy: Arc<dyn SchedulingPolicy>) -> Scheduler {
+    budget_scheduler(policy, None)
+}
+
+fn budget_scheduler(
+    policy: Arc<dyn SchedulingPolicy>,
+    max_num_prefill_tokens: Option<usize>,
+) -> Scheduler {
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 16,
+            max_num_prefill_tokens,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(BLOCK_SIZE),
+    );
+    scheduler.set_policy(policy);
+    scheduler
+}
+
+fn group(id: usize, prompt_len: usize, priority: i64) -> SequenceGroup {
+    let mut group = sequence_group(id, prompt_len, BLOCK_SIZE);
+    group.sampling_params.priority = priority;
+    group
+}
+
+fn scheduled_ids(scheduler: &mut Scheduler) -> Vec<String> {
+    scheduler
+        .schedule()
+        .scheduled
+        .iter()
+        .map(|group| group.request_id.clone())
+        .collect()
+}
+
+#[test]
+fn priority_policy_serves_lower_priorities_first() {
+    let priorities = [0, -1, 5, -1];
+    let mut fcfs = scheduler(Arc::new(Fcfs));
+    let mut priority = scheduler(Arc::new(Priority));
+    for (id, p) in priorities.into_iter().enumerate() {
+        fcfs.add_sequence(group(id, 8, p));
+        priority.add_sequence(group(id, 8, p));
+    }
+    assert_eq!(
+        scheduled_ids(&mut fcfs),
+        ["test-0", "test-1", "test-2", "test-3"]
+    );
+    assert_eq!(
+        scheduled_ids(&mut priority),
+        ["test-1", "test-3", "test-0", "test-2"]
+    );
+    assert_eq!(priority.snapshot().policy, "priority");
+}
+
+/// Shortest prompt first, at most two prompts per step.
+struct ShortestFirst;
+
+impl SchedulingPolicy for ShortestFirst {
+    fn name(&self) -> &str {
+        "sjf"
+    }
+
+    fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering {
+        a.get_prompt_len().cmp(&b.get_prompt_len())
+    }
+
+    fn admit(&self, _seq_group: &SequenceGroup, scheduled: &VecDeque<Arc<SequenceGroup>>) -> bool {
+        scheduled.len() < 2
+    }
+}
+
+#[test]
+fn custom_policies_order_and_compose_batches() {
+    let mut scheduler = scheduler(Arc::new(ShortestFirst));
+    for (id, prompt_len) in [40, 8, 24].into_iter().enumerate() {
+        scheduler.add_sequence(group(id, prompt_len, 0));
+    }
+    assert_eq!(scheduled_ids(&mut scheduler), ["test-1", "test-2"]);
+    // The group left out of the batch keeps its place in the queue.
+    let waiting = scheduler.snapshot().waiting;
+    assert_eq!(waiting.len(), 1);
+    assert_eq!(waiting[0].request_id, "test-0");
+}
+
+#[test]
+fn victims_are_selected_by_the_policy() {
+    // 63 of the 64 blocks are full, the next token of each group needs a block.
+    let prompt_lens = [20, 20, 20, 3].map(|blocks| blocks * BLOCK_SIZE);
+    let run = |policy: Arc<dyn SchedulingPolicy>| {
+        let mut scheduler = scheduler(policy);
+        for (id, prompt_len) in prompt_lens.into_iter().enumerate() {
+            scheduler.add_sequence(group(id, prompt_len, 0));
+        }
+        assert_eq!(scheduled_ids(&mut scheduler).len(), 4);
+        let running = scheduled_ids(&mut scheduler);
+        let waiting = scheduler
+            .snapshot()
+            .waiting
+            .into_iter()
+            .map(|group| group.request_id)
+            .collect::<Vec<_>>();
+        (running, waiting)
+    };
+    // FCFS preempts the newest group.
+    let (running, waiting) = run(Arc::new(Fcfs));
+    assert_eq!(running, ["test-0", "test-1", "test-2"]);
+    assert_eq!(waiting, ["test-3"]);
+
+    /// Preempts the first group served after the one that needs a block.
+    struct PreemptNext;
+
+    impl SchedulingPolicy for PreemptNext {
+        fn name(&self) -> &str {
+            "preempt-next"
+        }
+
+        fn compare(&self, a: &SequenceGroup, b: &SequenceGroup) -> Ordering {
+            Fcfs.compare(a, b)
+        }
+
+        fn select_victim(&self, _running: &VecDeque<Arc<SequenceGroup>>) -> usize {
+            0
+        }
+    }
+    let (running, waiting) = run(Arc::new(PreemptNext));
+    assert_eq!(running, ["test-0", "test-1", "test-3"]);
+    assert_eq!(waiting, ["test-2"]);
+}
+
+fn tenant_group(id: usize, prompt_len: usize, tenant: &str) -> SequenceGroup {
+    let mut group = sequence_group(id, prompt_len, BLOCK_SIZE);
+    group.sampling_params.tenant = Some(tenant.to_string());
+    group
+}
+
+#[test]
+fn fair_share_keeps_a_flooding_tenant_from_starving_the_others() {
+    // Tenant a floods long prompts before tenant b sends two short ones.
+    let groups = || {
+        (0..4)
+            .map(|id| tenant_group(id, 256, "a"))
+            .chain((4..6).map(|id| tenant_group(id, 64, "b")))
+    };
+    let budget = Some(256 + 2 * 64);
+    let mut fcfs = budget_scheduler(Arc::new(Fcfs), budget);
+    let mut fair = budget_scheduler(Arc::new(FairShare::new(256)), budget);
+    for group in groups() {
+        fcfs.add_sequence(group);
+    }
+    for group in groups() {
+        fair.add_sequence(group);
+    }
+    assert_eq!(scheduled_ids(&mut fcfs), ["test-0"]);
+    assert_eq!(scheduled_ids(&mut fair), ["test-0", "test-4", "test-5"]);
+}
+
+#[test]
+fn fair_share_serves_the_tenants_with_the_least_in_flight_first() {
+    let mut scheduler = scheduler(Arc::new(FairShare::new(256)));
+    scheduler.add_sequence(tenant_group(0, 400, "a"));
+    assert_eq!(scheduled_ids(&mut scheduler), ["test-0"]);
+
+    // a arrived first, but has 400 tokens in flight.
+    scheduler.add_sequence(tenant_group(1, 64, "a"));
+    scheduler.add_sequence(tenant_group(2, 64, "b"));
+    assert_eq!(scheduled_ids(&mut scheduler), ["test-2", "test-1"]);
+    assert_eq!(scheduler.snapshot().policy, "fair");
+}
@@ -0,0 +1,19 @@
+use candle_vllm::{
+    openai::pipelines::{llm_engine::LLMEngine, pipeline::DefaultPipeline, ModulePipeline},
+    scheduler::{
+        sequence::{Sequence, SequenceGroup},
+        Scheduler,
+    },
+};
+
+fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+
+#[test]
+fn engine_state_is_send_and_sync() {
+    assert_send_sync::<Sequence>();
+    assert_send_sync::<SequenceGroup>();
+    assert_send_sync::<Scheduler>();
+    assert_send_sync::<DefaultPipeline>();
+    assert_send_sync::<dyn ModulePipeline>();
+    assert_send_sync::<LLMEngine>();
+}
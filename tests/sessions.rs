@@ -0,0 +1,153 @@
+use candle_vllm::openai::hooks::{EngineObserver, StepEvent};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t11 t3 t50 t22 t7 t19 t28";
+const NEXT_MESSAGE: &str = "t31 t8 t44 t12 t60";
+const MAX_TOKENS: usize = 12;
+
+/// Records the request ids of the prompt steps.
+#[derive(Default)]
+struct Prefills(Mutex<Vec<String>>);
+
+impl EngineObserver for Prefills {
+    fn on_step(&self, step: &StepEvent) {
+        if step.is_prompt {
+            self.0.lock().unwrap().extend(step.request_ids.clone());
+        }
+    }
+}
+
+/// The prompt of the next turn: `prompt`, the answer to it and a new message.
+fn next_turn(prompt: &str, answer: &[usize]) -> String {
+    let answer = answer
+        .iter()
+        .map(|token| format!("t{token}"))
+        .collect::<Vec<_>>();
+    format!("{prompt} {} {NEXT_MESSAGE}", answer.join(" "))
+}
+
+#[test]
+fn next_turn_continues_the_cached_turn() {
+    let mut expected = TinyEngine::new(16);
+    let mut engine = TinyEngine::with_sessions(TinyEngine::cache_config(16), 4);
+    let prefills = Arc::new(Prefills::default());
+    engine.add_observer(prefills.clone());
+    engine.conversation_id = Some("chat".to_string());
+
+    let mut prompt = PROMPT.to_string();
+    for turn in 0..3 {
+        let encoding = engine.encode(&prompt);
+        let answer = engine.generate(&[encoding.clone()], MAX_TOKENS).remove(0);
+        assert_eq!(answer, expected.generate(&[encoding], MAX_TOKENS)[0]);
+        let snapshot = engine.scheduler_snapshot();
+        assert_eq!(snapshot.num_sessions, 1);
+        assert!(snapshot.num_free_gpu_blocks < snapshot.num_gpu_blocks);
+        // Only the first turn is prefilled, the others are written by their first decode step.
+        assert_eq!(*prefills.0.lock().unwrap(), ["tiny-0"], "turn {turn}");
+        prompt = next_turn(&prompt, &answer);
+    }
+}
+
+#[test]
+fn diverging_prompt_only_reuses_the_common_prefix() {
+    let mut expected = TinyEngine::new(16);
+    let mut engine = TinyEngine::with_sessions(TinyEngine::cache_config(16), 4);
+    engine.conversation_id = Some("chat".to_string());
+    let first = engine.encode(PROMPT);
+    engine.generate(&[first], MAX_TOKENS);
+
+    // The history was edited from the 8th token on.
+    let kept = PROMPT.split_whitespace().take(7).collect::<Vec<_>>();
+    let edited = engine.encode(&format!("{} t61 t62 t63", kept.join(" ")));
+    assert_eq!(
+        engine.generate(&[edited.clone()], MAX_TOKENS),
+        expected.generate(&[edited], MAX_TOKENS)
+    );
+    assert_eq!(engine.scheduler_snapshot().num_sessions, 1);
+}
+
+#[test]
+fn sessions_give_way_to_requests() {
+    let mut cache_config = TinyEngine::cache_config(16);
+    cache_config.num_gpu_blocks = Some(8);
+    let mut engine = TinyEngine::with_sessions(cache_config, 2);
+    let prompt = engine.encode(PROMPT);
+    for conversation_id in ["a", "b", "c"] {
+        engine.conversation_id = Some(conversation_id.to_string());
+        engine.generate(&[prompt.clone()], MAX_TOKENS);
+    }
+    // The least recently used conversation is dropped past `max_sessions`.
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_sessions, 2);
+    assert_eq!(snapshot.num_free_gpu_blocks, 8 - 2 * 2);
+
+    // A request needing the blocks of the sessions evicts them.
+    engine.conversation_id = None;
+    let long_prompt = (0..100)
+        .map(|i| format!("t{}", 3 + i % 61))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let long_prompt = engine.encode(&long_prompt);
+    assert!(!engine.generate(&[long_prompt], 4)[0].is_empty());
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_sessions, 0);
+    assert_eq!(snapshot.num_free_gpu_blocks, 8);
+}
+
+#[test]
+fn retained_sessions_are_evicted_last() {
+    let mut engine = TinyEngine::with_sessions(TinyEngine::cache_config(16), 2);
+    let prefills = Arc::new(Prefills::default());
+    engine.add_observer(prefills.clone());
+    let prompt = engine.encode(PROMPT);
+    let mut answer = vec![];
+    for conversation_id in ["a", "b", "c"] {
+        engine.conversation_id = Some(conversation_id.to_string());
+        engine.kv_cache_retention = (conversation_id == "a").then_some(60.0);
+        answer = engine.generate(&[prompt.clone()], MAX_TOKENS).remove(0);
+    }
+    assert_eq!(engine.scheduler_snapshot().num_sessions, 2);
+
+    // "b" was evicted past `max_sessions` instead of "a", whose next turn is not prefilled.
+    engine.conversation_id = Some("a".to_string());
+    engine.kv_cache_retention = None;
+    let next = engine.encode(&next_turn(PROMPT, &answer));
+    engine.generate(&[next], MAX_TOKENS);
+    assert_eq!(*prefills.0.lock().unwrap(), ["tiny-0", "tiny-1", "tiny-2"]);
+}
+
+#[test]
+fn sessions_are_dropped_when_their_retention_ends() {
+    let mut engine = TinyEngine::with_sessions(TinyEngine::cache_config(16), 4);
+    let num_gpu_blocks = engine.scheduler_snapshot().num_gpu_blocks;
+    let prompt = engine.encode(PROMPT);
+    engine.conversation_id = Some("chat".to_string());
+    engine.kv_cache_retention = Some(0.05);
+    engine.generate(&[prompt.clone()], MAX_TOKENS);
+    assert_eq!(engine.scheduler_snapshot().num_sessions, 1);
+
+    // The next scheduler step frees the blocks of the session.
+    std::thread::sleep(Duration::from_millis(60));
+    engine.conversation_id = None;
+    engine.kv_cache_retention = None;
+    engine.generate(&[prompt], 1);
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_sessions, 0);
+    assert_eq!(snapshot.num_free_gpu_blocks, num_gpu_blocks);
+}
+
+#[test]
+fn zero_retention_frees_the_turn_at_once() {
+    let mut engine = TinyEngine::with_sessions(TinyEngine::cache_config(16), 4);
+    engine.conversation_id = Some("chat".to_string());
+    engine.kv_cache_retention = Some(0.0);
+    let prompt = engine.encode(PROMPT);
+    engine.generate(&[prompt], MAX_TOKENS);
+    let snapshot = engine.scheduler_snapshot();
+    assert_eq!(snapshot.num_sessions, 0);
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+}
@@ -0,0 +1,73 @@
+use candle_core::DType;
+use candle_vllm::{
+    openai::pipelines::llm_engine::SleepLevel, scheduler::cache_engine::CacheConfig,
+};
+
+mod common;
+use common::{cache_config, tiny_model::TinyEngine};
+
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const MAX_TOKENS: usize = 12;
+
+#[test]
+fn generation_resumes_after_waking_up() {
+    for level in [SleepLevel::KvCache, SleepLevel::Weights] {
+        let mut engine = TinyEngine::new(8);
+        let prompt = engine.encode(PROMPT);
+        let expected = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+        engine.sleep(level).unwrap();
+        engine.wake().unwrap();
+        assert_eq!(
+            engine.generate(&[prompt], MAX_TOKENS),
+            expected,
+            "{level:?}"
+        );
+    }
+}
+
+#[test]
+fn requests_are_rejected_while_sleeping() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.sleep(SleepLevel::Weights).unwrap();
+    // Sleeping again at a lower level keeps the weights released.
+    engine.sleep(SleepLevel::KvCache).unwrap();
+    let err = engine
+        .try_generate(std::slice::from_ref(&prompt), MAX_TOKENS)
+        .unwrap_err();
+    assert!(err.to_string().contains("sleeping"), "{err}");
+    engine.wake().unwrap();
+    // Waking an engine that is awake does nothing.
+    engine.wake().unwrap();
+    assert_eq!(
+        engine.generate(&[prompt], MAX_TOKENS)[0].len(),
+        MAX_TOKENS + 1
+    );
+}
+
+#[test]
+fn engine_does_not_sleep_with_requests_in_flight() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.submit(std::slice::from_ref(&prompt), MAX_TOKENS);
+    let err = engine.sleep(SleepLevel::KvCache).unwrap_err();
+    assert!(err.to_string().contains("in flight"), "{err}");
+    engine.generate(&[prompt], MAX_TOKENS);
+    engine.sleep(SleepLevel::KvCache).unwrap();
+}
+
+#[test]
+fn lazily_grown_cache_wakes_up_with_its_first_chunk() {
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        dtype: DType::F32,
+        gpu_growth_blocks: Some(2),
+        ..cache_config(8)
+    });
+    let prompt = engine.encode(PROMPT);
+    let expected = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+    assert!(engine.scheduler_snapshot().num_allocated_gpu_blocks > 2);
+    engine.sleep(SleepLevel::KvCache).unwrap();
+    engine.wake().unwrap();
+    assert_eq!(engine.scheduler_snapshot().num_allocated_gpu_blocks, 2);
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), expected);
+}
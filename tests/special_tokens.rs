@@ -0,0 +1,111 @@
+use candle_vllm::openai::special_tokens::SpecialTokens;
+use tokenizers::Tokenizer;
+
+const HELLO: u32 = 1;
+const WORLD: u32 = 2;
+const EOT: u32 = 3;
+const IM_END: u32 = 4;
+
+// Word level tokenizer over "<unk>", "hello" and "world", with "<|eot_id|>" and "<|im_end|>" as
+// special tokens and "<|fim|>" as an added token which is not special.
+fn tokenizer() -> Tokenizer {
+    let added = |id: u32, content: &str, special: bool| {
+        serde_json::json!({
+            "id": id,
+            "content": content,
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": false,
+            "special": special,
+        })
+    };
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            added(EOT, "<|eot_id|>", true),
+            added(IM_END, "<|im_end|>", true),
+            added(5, "<|fim|>", false),
+        ],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {
+            "type": "WordLevel",
+            "vocab": {
+                "<unk>": 0, "hello": 1, "world": 2, "<|eot_id|>": 3, "<|im_end|>": 4, "<|fim|>": 5,
+            },
+            "unk_token": "<unk>",
+        },
+    });
+    Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+}
+
+#[test]
+fn special_tokens_come_from_the_tokenizer() {
+    let special_tokens = SpecialTokens::from_tokenizer(&tokenizer());
+    assert!(special_tokens.is_special(EOT));
+    assert_eq!(special_tokens.text(IM_END), Some("<|im_end|>"));
+    assert!(!special_tokens.is_special(HELLO));
+    // Added tokens are not all special.
+    assert!(!special_tokens.is_special(5));
+}
+
+#[test]
+fn special_tokens_are_skipped_or_spaced_out() {
+    let tokenizer = tokenizer();
+    let special_tokens = SpecialTokens::from_tokenizer(&tokenizer);
+    let decode = |tokens: &[u32], skip, spaces| {
+        special_tokens
+            .decode(&tokenizer, tokens, skip, spaces)
+            .unwrap()
+    };
+    let tokens = [HELLO, EOT, WORLD, IM_END];
+    assert_eq!(decode(&tokens, true, true), "hello world");
+    assert_eq!(decode(&[EOT, IM_END], true, true), "");
+    assert_eq!(
+        decode(&tokens, false, true),
+        "hello <|eot_id|> world <|im_end|>"
+    );
+    assert_eq!(
+        decode(&tokens, false, false),
+        "hello<|eot_id|>world<|im_end|>"
+    );
+}
+
+#[test]
+fn streamed_deltas_add_up_to_the_text() {
+    let tokenizer = tokenizer();
+    let special_tokens = SpecialTokens::from_tokenizer(&tokenizer);
+    let tokens = [HELLO, EOT, WORLD, IM_END];
+    for (skip, spaces) in [(false, true), (false, false)] {
+        let streamed = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, &token)| {
+                let previous = i.checked_sub(1).map(|i| tokens[i]);
+                let text = tokenizer.decode(&[token], false).unwrap();
+                special_tokens.delta(previous, token, &text, skip, spaces)
+            })
+            .collect::<String>();
+        assert_eq!(
+            streamed,
+            special_tokens
+                .decode(&tokenizer, &tokens, skip, spaces)
+                .unwrap()
+        );
+    }
+
+    // Skipped special tokens never reach the client.
+    assert_eq!(
+        special_tokens.delta(Some(HELLO), EOT, "<|eot_id|>", true, true),
+        ""
+    );
+    assert_eq!(
+        special_tokens.delta(Some(EOT), WORLD, "world", true, true),
+        "world"
+    );
+}
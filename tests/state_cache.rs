@@ -0,0 +1,115 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::scheduler::{
+    block_engine::{AllocStatus, BlockEngine},
+    state_cache::{RecurrentStateConfig, RecurrentStateInput, StateCache},
+};
+
+mod common;
+use common::sequence_group;
+
+/// Recurrent layers 1 and 3, each keeping a `(2,)` and a `(2, 2)` state per sequence.
+fn state_config() -> RecurrentStateConfig {
+    RecurrentStateConfig {
+        layers: vec![1, 3],
+        state_shapes: vec![vec![2], vec![2, 2]],
+        dtype: DType::F32,
+    }
+}
+
+fn states(value: f32, num_seqs: usize) -> Vec<Tensor> {
+    vec![
+        Tensor::full(value, (num_seqs, 2), &Device::Cpu).unwrap(),
+        Tensor::full(value, (num_seqs, 2, 2), &Device::Cpu).unwrap(),
+    ]
+}
+
+fn values(states: &[Tensor]) -> Vec<Vec<f32>> {
+    states
+        .iter()
+        .map(|state| state.flatten_all().unwrap().to_vec1().unwrap())
+        .collect()
+}
+
+#[test]
+fn sequences_hold_a_state_slot_until_they_are_freed() {
+    let mut block_engine = BlockEngine::new(16, 8, 8).with_state_slots(2);
+    let first = sequence_group(0, 8, 16);
+    block_engine.allocate(&first);
+    block_engine.allocate(&sequence_group(1, 8, 16));
+    assert_eq!(block_engine.get_state_slot(0), Some(0));
+    assert_eq!(block_engine.get_state_slot(1), Some(1));
+    assert_eq!(block_engine.take_new_state_slots(), [0, 1]);
+    assert!(block_engine.take_new_state_slots().is_empty());
+
+    // Blocks are left, slots are not.
+    let third = sequence_group(2, 8, 16);
+    assert!(matches!(
+        block_engine.can_allocate(&third),
+        AllocStatus::Later
+    ));
+
+    block_engine.free_sequence(&first.get_seqs()[&0]);
+    assert_eq!(block_engine.get_state_slot(0), None);
+    assert!(matches!(block_engine.can_allocate(&third), AllocStatus::Ok));
+    block_engine.allocate(&third);
+    assert_eq!(block_engine.get_state_slot(2), Some(0));
+    // The slot is zeroed again for its new sequence.
+    assert_eq!(block_engine.take_new_state_slots(), [0]);
+}
+
+#[test]
+fn states_are_written_once_the_step_is_committed() {
+    let cache = StateCache::new(state_config(), 4, &Device::Cpu).unwrap();
+    let step = RecurrentStateInput::new(cache.clone(), vec![2, 0]);
+    assert_eq!(values(&step.read(1).unwrap()), values(&states(0., 2)));
+    assert!(step.read(2).is_err());
+
+    step.write(1, states(1., 2));
+    step.write(3, states(3., 2));
+    assert_eq!(
+        values(&cache.read(1, &[2]).unwrap()),
+        values(&states(0., 1))
+    );
+    step.commit().unwrap();
+    assert_eq!(
+        values(&cache.read(1, &[2]).unwrap()),
+        values(&states(1., 1))
+    );
+    assert_eq!(
+        values(&cache.read(3, &[0]).unwrap()),
+        values(&states(3., 1))
+    );
+    // The other slots are untouched.
+    assert_eq!(
+        values(&cache.read(1, &[1, 3]).unwrap()),
+        values(&states(0., 2))
+    );
+
+    // A step that is not committed leaves the states as they were.
+    let failed = RecurrentStateInput::new(cache.clone(), vec![2]);
+    failed.write(1, states(7., 1));
+    drop(failed);
+    assert_eq!(
+        values(&cache.read(1, &[2]).unwrap()),
+        values(&states(1., 1))
+    );
+
+    cache.reset(&[2]).unwrap();
+    assert_eq!(
+        values(&cache.read(1, &[2]).unwrap()),
+        values(&states(0., 1))
+    );
+    assert_eq!(
+        values(&cache.read(3, &[0]).unwrap()),
+        values(&states(3., 1))
+    );
+}
+
+#[test]
+fn states_of_the_wrong_shape_are_rejected() {
+    let cache = StateCache::new(state_config(), 4, &Device::Cpu).unwrap();
+    assert!(cache.write(1, &[0, 1], &states(1., 1)).is_err());
+    assert!(cache.write(1, &[0], &states(1., 1)[..1]).is_err());
+    assert!(cache.write(1, &[0], &states(1., 1)).is_ok());
+    assert_eq!(state_config().slot_bytes(), 2 * (2 + 4) * 4);
+}
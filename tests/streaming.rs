@@ -0,0 +1,214 @@
+use axum::response::{sse::Sse, IntoResponse};
+use candle_vllm::{
+    openai::{
+        requests::StreamOptions,
+        responses::{ChatCompletionChunk, Choice, ChoiceData},
+        streaming::{ChatResponse, Streamer},
+    },
+    scheduler::{Scheduler, SchedulerConfig},
+};
+use futures::executor::block_on;
+
+mod common;
+use common::{cache_config, sequence_group};
+
+fn chunk(content: &str) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: "cmpl-0".to_string(),
+        choices: vec![Choice {
+            delta: ChoiceData {
+                content: Some(content.to_string()),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: None,
+            index: 0,
+            logprobs: None,
+            moderation: Vec::new(),
+            token_ids: None,
+        }],
+        created: 0,
+        model: "tiny".to_string(),
+        object: "chat.completion.chunk",
+        system_fingerprint: None,
+        usage: None,
+        prompt_token_ids: None,
+    }
+}
+
+/// Data of the events of the stream receiving `responses`, after which the engine drops it.
+fn stream(responses: Vec<ChatResponse>) -> Vec<String> {
+    stream_with_options(responses, StreamOptions::default())
+}
+
+fn stream_with_options(responses: Vec<ChatResponse>, options: StreamOptions) -> Vec<String> {
+    events(responses, |receiver| {
+        Streamer::new(receiver, false, 5, options)
+    })
+}
+
+fn events(
+    responses: Vec<ChatResponse>,
+    streamer: impl FnOnce(flume::Receiver<ChatResponse>) -> Streamer,
+) -> Vec<String> {
+    let (sender, receiver) = flume::unbounded();
+    for response in responses {
+        sender.send(response).unwrap();
+    }
+    drop(sender);
+    let streamer = streamer(receiver);
+    let body = Sse::new(streamer).into_response().into_body();
+    let body = block_on(axum::body::to_bytes(body, usize::MAX)).unwrap();
+    String::from_utf8(body.to_vec())
+        .unwrap()
+        .split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn stream_ends_with_done() {
+    let events = stream(vec![
+        ChatResponse::Chunk(chunk("Hello")),
+        ChatResponse::Done,
+        ChatResponse::Chunk(chunk("ignored")),
+    ]);
+    assert_eq!(events.len(), 2);
+    let first: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+    assert_eq!(first["choices"][0]["delta"]["content"], "Hello");
+    assert_eq!(events[1], "[DONE]");
+}
+
+#[test]
+fn engine_errors_end_the_stream_with_an_error_frame() {
+    let events = stream(vec![
+        ChatResponse::Chunk(chunk("Hello")),
+        ChatResponse::ModelError("out of memory".to_string()),
+        ChatResponse::Chunk(chunk("ignored")),
+    ]);
+    assert_eq!(events.len(), 3);
+    let error: serde_json::Value = serde_json::from_str(&events[1]).unwrap();
+    assert_eq!(error["error"]["message"], "out of memory");
+    assert_eq!(error["error"]["type"], "server_error");
+    assert_eq!(events[2], "[DONE]");
+}
+
+#[test]
+fn streams_dropped_by_the_engine_end_with_an_error_frame() {
+    for responses in [vec![], vec![ChatResponse::Chunk(chunk("Hello"))]] {
+        let events = stream(responses);
+        let error: serde_json::Value = serde_json::from_str(&events[events.len() - 2]).unwrap();
+        assert_eq!(error["error"]["type"], "server_error");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+    }
+}
+
+fn json(event: &str) -> serde_json::Value {
+    serde_json::from_str(event).unwrap()
+}
+
+fn tokens() -> Vec<ChatResponse> {
+    vec![
+        ChatResponse::Chunk(chunk("Hello")),
+        ChatResponse::Chunk(chunk(" world")),
+        ChatResponse::Done,
+    ]
+}
+
+#[test]
+fn usage_is_sent_in_a_last_chunk() {
+    let events = stream_with_options(
+        tokens(),
+        StreamOptions {
+            include_usage: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(events.len(), 4);
+    assert!(json(&events[0]).get("usage").is_none());
+    let usage = json(&events[2]);
+    assert_eq!(usage["choices"].as_array().unwrap().len(), 0);
+    assert_eq!(usage["id"], "cmpl-0");
+    assert_eq!(usage["usage"]["prompt_tokens"], 5);
+    assert_eq!(usage["usage"]["completion_tokens"], 2);
+    assert_eq!(usage["usage"]["total_tokens"], 7);
+    assert_eq!(events[3], "[DONE]");
+}
+
+#[test]
+fn prompt_token_ids_are_sent_with_the_first_chunk() {
+    let events = events(tokens(), |receiver| {
+        Streamer::new(receiver, false, 5, StreamOptions::default())
+            .with_prompt_token_ids(Some(vec![1, 5, 9, 17, 33]))
+    });
+    assert_eq!(
+        json(&events[0])["prompt_token_ids"],
+        json("[1, 5, 9, 17, 33]")
+    );
+    assert!(json(&events[1]).get("prompt_token_ids").is_none());
+}
+
+#[test]
+fn continuous_usage_is_sent_with_every_chunk() {
+    let events = stream_with_options(
+        tokens(),
+        StreamOptions {
+            continuous_usage_stats: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(events.len(), 3);
+    assert_eq!(json(&events[0])["usage"]["completion_tokens"], 1);
+    assert_eq!(json(&events[1])["usage"]["completion_tokens"], 2);
+    assert_eq!(json(&events[1])["usage"]["total_tokens"], 7);
+}
+
+#[test]
+fn chunks_carry_the_full_text_on_request() {
+    let events = stream_with_options(
+        tokens(),
+        StreamOptions {
+            full_text: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(json(&events[0])["choices"][0]["delta"]["content"], "Hello");
+    assert_eq!(
+        json(&events[1])["choices"][0]["delta"]["content"],
+        "Hello world"
+    );
+
+    let events = stream(tokens());
+    assert_eq!(json(&events[1])["choices"][0]["delta"]["content"], " world");
+}
+
+#[test]
+fn abort_all_frees_every_group() {
+    let block_size = 16;
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig {
+            max_num_seqs: 2,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
+        &cache_config(block_size),
+    );
+    scheduler.add_sequence(sequence_group(0, 40, block_size));
+    scheduler.add_sequence(sequence_group(1, 8, block_size));
+    scheduler.schedule();
+    let snapshot = scheduler.snapshot();
+    assert_eq!((snapshot.running.len(), snapshot.waiting.len()), (1, 1));
+
+    let aborted = scheduler.abort_all();
+    assert_eq!(aborted.len(), 2);
+    assert!(aborted.iter().all(|group| group.is_finished()));
+    assert!(!scheduler.has_unfinished_sequences());
+    let snapshot = scheduler.snapshot();
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+}
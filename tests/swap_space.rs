@@ -0,0 +1,114 @@
+use candle_vllm::{
+    backend::parse_cpu_list,
+    openai::responses::ChatCompletionChunk,
+    scheduler::cache_engine::{verify_swap_space, CacheConfig, SwapAffinity},
+};
+use std::{collections::BTreeMap, str::FromStr};
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = [
+    "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60",
+    "t3 t1 t4 t1 t5 t9 t2 t6 t5 t3",
+];
+const MAX_TOKENS: usize = 24;
+
+/// Text streamed for each choice, by index.
+fn choice_texts(chunks: &[ChatCompletionChunk]) -> BTreeMap<usize, String> {
+    let mut texts = BTreeMap::<usize, String>::new();
+    for choice in chunks.iter().flat_map(|chunk| &chunk.choices) {
+        let text = texts.entry(choice.index).or_default();
+        text.push_str(choice.delta.content.as_deref().unwrap_or_default());
+    }
+    texts
+}
+
+#[test]
+fn swap_space_has_to_fit_in_host_memory() {
+    assert!(verify_swap_space(4096, 16384).is_ok());
+    // Allowed with a warning.
+    assert!(verify_swap_space(8192, 16384).is_ok());
+    assert!(verify_swap_space(12288, 16384).is_err());
+}
+
+#[test]
+fn preempted_groups_are_swapped_out_and_back_in() {
+    let generate = |engine: &mut TinyEngine| {
+        let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+        engine
+            .stream(&prompts, 2, MAX_TOKENS)
+            .into_iter()
+            .map(|chunks| choice_texts(&chunks))
+            .collect::<Vec<_>>()
+    };
+    let mut engine = TinyEngine::new(8);
+    let expected = generate(&mut engine);
+    assert_eq!(engine.scheduler_snapshot().num_swapped_out_blocks, 0);
+
+    // Each request takes 9 blocks by the end, both do not fit at once.
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        num_gpu_blocks: Some(12),
+        ..TinyEngine::cache_config(8)
+    });
+    assert_eq!(generate(&mut engine), expected);
+    let stats = engine.scheduler_snapshot().cache_stats();
+    assert!(stats.num_swapped_out_blocks > 0);
+    assert_eq!(stats.num_swapped_in_blocks, stats.num_swapped_out_blocks);
+    assert_eq!(stats.num_free_cpu_blocks, stats.num_cpu_blocks);
+}
+
+#[test]
+fn swap_affinity_is_parsed() {
+    assert_eq!(
+        parse_cpu_list("0-3,8,10-11\n"),
+        Some(vec![0, 1, 2, 3, 8, 10, 11])
+    );
+    assert_eq!(parse_cpu_list(""), None);
+    assert_eq!(parse_cpu_list("3-1"), None);
+    assert_eq!(SwapAffinity::from_str("auto").unwrap(), SwapAffinity::Auto);
+    assert_eq!(
+        SwapAffinity::from_str("node:1").unwrap(),
+        SwapAffinity::Node(1)
+    );
+    assert_eq!(
+        SwapAffinity::from_str("0-1,4").unwrap(),
+        SwapAffinity::Cpus(vec![0, 1, 4])
+    );
+    assert!(SwapAffinity::from_str("node:x").is_err());
+    assert!(SwapAffinity::from_str("socket0").is_err());
+
+    let with_affinity = |cpus| CacheConfig {
+        swap_affinity: Some(SwapAffinity::Cpus(cpus)),
+        ..TinyEngine::cache_config(8)
+    };
+    assert!(with_affinity(vec![0]).verify_args().is_ok());
+    assert!(with_affinity(vec![]).verify_args().is_err());
+    assert!(with_affinity(vec![1 << 20]).verify_args().is_err());
+}
+
+#[test]
+fn swaps_bound_to_cpus_give_the_same_output() {
+    let generate = |engine: &mut TinyEngine| {
+        let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+        engine
+            .stream(&prompts, 2, MAX_TOKENS)
+            .into_iter()
+            .map(|chunks| choice_texts(&chunks))
+            .collect::<Vec<_>>()
+    };
+    let expected = generate(&mut TinyEngine::new(8));
+
+    // The CPU has no NUMA node to find, `auto` leaves the swaps unbound.
+    for swap_affinity in [SwapAffinity::Cpus(vec![0]), SwapAffinity::Auto] {
+        let mut engine = TinyEngine::with_cache_config(CacheConfig {
+            num_gpu_blocks: Some(12),
+            swap_affinity: Some(swap_affinity),
+            ..TinyEngine::cache_config(8)
+        });
+        assert_eq!(generate(&mut engine), expected);
+        let stats = engine.scheduler_snapshot().cache_stats();
+        assert!(stats.num_swapped_out_blocks > 0);
+        assert_eq!(stats.num_swapped_in_blocks, stats.num_swapped_out_blocks);
+    }
+}
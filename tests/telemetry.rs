@@ -0,0 +1,191 @@
+use axum::{extract::State, routing::post, Json, Router};
+use candle_vllm::telemetry::{trace_id, OtlpExporter, TelemetryConfig};
+use serde_json::Value;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 8;
+
+fn config(endpoint: &str, trace_sample_ratio: f64) -> TelemetryConfig {
+    TelemetryConfig {
+        endpoint: endpoint.to_string(),
+        trace_sample_ratio,
+        export_interval: Duration::from_secs(10),
+        resource: vec![("service.name".to_string(), "tiny".to_string())],
+    }
+}
+
+/// Exporter to `endpoint` observing the generation of `PROMPTS`, with the outputs and the
+/// prompt lengths.
+fn observe_to(
+    endpoint: &str,
+    trace_sample_ratio: f64,
+) -> (Arc<OtlpExporter>, Vec<Vec<usize>>, usize) {
+    let mut engine = TinyEngine::new(16);
+    let exporter = Arc::new(OtlpExporter::new(config(endpoint, trace_sample_ratio)).unwrap());
+    engine.add_observer(exporter.clone());
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let outputs = engine.generate(&prompts, MAX_TOKENS);
+    let prompt_tokens = prompts.iter().map(|prompt| prompt.len()).sum();
+    (exporter, outputs, prompt_tokens)
+}
+
+fn observe(trace_sample_ratio: f64) -> (Arc<OtlpExporter>, Vec<Vec<usize>>, usize) {
+    observe_to("http://127.0.0.1:4318", trace_sample_ratio)
+}
+
+fn metric<'a>(payload: &'a Value, name: &str) -> &'a Value {
+    payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|metric| metric["name"] == name)
+        .unwrap_or_else(|| panic!("no metric {name}"))
+}
+
+fn counter(payload: &Value, name: &str) -> u64 {
+    metric(payload, name)["sum"]["dataPoints"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|point| point["asInt"].as_str().unwrap().parse::<u64>().unwrap())
+        .sum()
+}
+
+#[test]
+fn metrics_aggregate_the_steps_and_requests_of_the_engine() {
+    let (exporter, outputs, prompt_tokens) = observe(1.0);
+    let payload = exporter.metrics_payload();
+    assert_eq!(
+        payload["resourceMetrics"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+        "tiny"
+    );
+    assert_eq!(counter(&payload, "candle_vllm.requests"), 2);
+    assert_eq!(counter(&payload, "candle_vllm.requests.aborted"), 0);
+    assert_eq!(
+        counter(&payload, "candle_vllm.prompt_tokens"),
+        prompt_tokens as u64
+    );
+    assert_eq!(
+        counter(&payload, "candle_vllm.generation_tokens"),
+        outputs.iter().map(Vec::len).sum::<usize>() as u64
+    );
+    assert!(counter(&payload, "candle_vllm.steps") >= MAX_TOKENS as u64);
+    let ttft =
+        &metric(&payload, "candle_vllm.request.time_to_first_token")["histogram"]["dataPoints"][0];
+    assert_eq!(ttft["count"], "2");
+    let buckets = ttft["bucketCounts"].as_array().unwrap();
+    assert_eq!(
+        buckets.len(),
+        ttft["explicitBounds"].as_array().unwrap().len() + 1
+    );
+    assert_eq!(
+        buckets
+            .iter()
+            .map(|count| count.as_str().unwrap().parse::<u64>().unwrap())
+            .sum::<u64>(),
+        2
+    );
+}
+
+#[test]
+fn each_request_is_traced_with_its_stages() {
+    let (exporter, _, _) = observe(1.0);
+    let payload = exporter.take_traces_payload().unwrap();
+    let spans = payload["resourceSpans"][0]["scopeSpans"][0]["spans"]
+        .as_array()
+        .unwrap();
+    for request_id in ["tiny-0", "tiny-1"] {
+        let trace = spans
+            .iter()
+            .filter(|span| span["traceId"] == trace_id(request_id))
+            .collect::<Vec<_>>();
+        let names = trace
+            .iter()
+            .map(|span| span["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["request", "queue", "prefill", "decode"]);
+        let root = trace[0];
+        assert!(root.get("parentSpanId").is_none());
+        for stage in &trace[1..] {
+            assert_eq!(stage["parentSpanId"], root["spanId"]);
+            let nanos =
+                |span: &Value, field: &str| span[field].as_str().unwrap().parse::<u128>().unwrap();
+            assert!(nanos(stage, "startTimeUnixNano") >= nanos(root, "startTimeUnixNano"));
+            assert!(nanos(stage, "endTimeUnixNano") <= nanos(root, "endTimeUnixNano"));
+        }
+    }
+    // The spans are exported once.
+    assert!(exporter.take_traces_payload().is_none());
+}
+
+#[test]
+fn unsampled_requests_are_not_traced() {
+    let (exporter, _, _) = observe(0.0);
+    assert!(exporter.take_traces_payload().is_none());
+    assert_eq!(
+        counter(&exporter.metrics_payload(), "candle_vllm.requests"),
+        2
+    );
+}
+
+#[test]
+fn invalid_configurations_are_refused() {
+    for (endpoint, ratio) in [
+        ("https://collector:4318", 1.0),
+        ("not a url", 1.0),
+        ("http://collector:4318", 1.5),
+    ] {
+        assert!(OtlpExporter::new(config(endpoint, ratio)).is_err());
+    }
+}
+
+#[test]
+fn signals_are_posted_to_the_collector() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+    let (exporter, _, _) = observe_to(&endpoint, 1.0);
+
+    type Received = Arc<Mutex<Vec<(String, Value)>>>;
+    let received = Received::default();
+    let record = |path: &'static str| {
+        post(
+            move |State(received): State<Received>, Json(body): Json<Value>| async move {
+                received.lock().unwrap().push((path.to_string(), body));
+            },
+        )
+    };
+    let collector = Router::new()
+        .route("/v1/metrics", record("/v1/metrics"))
+        .route("/v1/traces", record("/v1/traces"))
+        .with_state(received.clone());
+
+    Runtime::new().unwrap().block_on(async {
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+        exporter.export().await.unwrap();
+        // The spans were sent, only the metrics are sent again.
+        exporter.export().await.unwrap();
+
+        let unreachable = OtlpExporter::new(config("http://127.0.0.1:1", 1.0)).unwrap();
+        assert!(unreachable.export().await.is_err());
+    });
+    let received = received.lock().unwrap();
+    let paths = received
+        .iter()
+        .map(|(path, _)| path.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, ["/v1/metrics", "/v1/traces", "/v1/metrics"]);
+    let spans = received[1].1["resourceSpans"][0]["scopeSpans"][0]["spans"]
+        .as_array()
+        .unwrap();
+    assert_eq!(spans.len(), 8);
+}
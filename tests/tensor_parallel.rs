@@ -0,0 +1,212 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::backend::{AllReduceAlgorithm, CUSTOM_ALL_REDUCE_MAX_SIZE};
+use candle_vllm::openai::{
+    models::{llama::LlamaConfig, Config},
+    pipelines::{
+        tensor_parallel::{check_tensor_parallel, Shard},
+        weights::{load_safetensors, load_safetensors_shard},
+        worker_process::WorkerProcess,
+    },
+};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+
+/// The config of Llama 3 8B, 8 KV heads for 32 attention heads.
+fn llama3_8b() -> Config {
+    let config: LlamaConfig = serde_json::from_value(serde_json::json!({
+        "hidden_size": 4096,
+        "intermediate_size": 14336,
+        "vocab_size": 128256,
+        "num_hidden_layers": 32,
+        "num_attention_heads": 32,
+        "num_key_value_heads": 8,
+        "rms_norm_eps": 1e-5,
+        "bos_token_id": 128000,
+        "eos_token_id": 128001,
+        "max_position_embeddings": 8192
+    }))
+    .unwrap();
+    config.into_config(false, DType::BF16)
+}
+
+#[test]
+fn projections_are_split_along_their_heads() {
+    let shard = Shard {
+        rank: 1,
+        world_size: 4,
+    };
+    for name in ["q_proj", "k_proj", "v_proj", "gate_proj", "up_proj"] {
+        let name = format!("model.layers.3.self_attn.{name}.weight");
+        assert_eq!(shard.dim(&name), Some(0), "{name}");
+    }
+    assert_eq!(shard.dim("model.layers.0.self_attn.o_proj.weight"), Some(1));
+    assert_eq!(shard.dim("model.layers.0.mlp.down_proj.weight"), Some(1));
+    for name in [
+        "model.embed_tokens.weight",
+        "model.norm.weight",
+        "lm_head.weight",
+        "model.layers.0.input_layernorm.weight",
+    ] {
+        assert_eq!(shard.dim(name), None, "{name}");
+    }
+    assert_eq!(shard.range(4096), 1024..2048);
+    // A single rank holds the whole model.
+    assert_eq!(
+        Shard::default().dim("model.layers.0.self_attn.q_proj.weight"),
+        None
+    );
+}
+
+#[test]
+fn local_config_keeps_the_head_size() {
+    let config = llama3_8b();
+    let local = Shard {
+        rank: 3,
+        world_size: 8,
+    }
+    .local_config(&config);
+    assert_eq!(local.num_attention_heads, 4);
+    assert_eq!(local.num_key_value_heads, 1);
+    assert_eq!(local.intermediate_size, 14336 / 8);
+    assert_eq!(local.get_head_size(), config.get_head_size());
+    assert_eq!(local.num_hidden_layers, config.num_hidden_layers);
+}
+
+#[test]
+fn models_that_cannot_be_split_are_rejected() {
+    let config = llama3_8b();
+    check_tensor_parallel("llama3", &config, 1).unwrap();
+    check_tensor_parallel("phi3", &config, 1).unwrap();
+    let err = check_tensor_parallel("phi3", &config, 2).unwrap_err();
+    assert!(err.to_string().contains("not supported"), "{err}");
+    // 8 KV heads over 16 GPUs.
+    let err = check_tensor_parallel("llama3", &config, 16).unwrap_err();
+    assert!(err.to_string().contains("KV heads"), "{err}");
+}
+
+#[test]
+fn shards_of_the_ranks_make_up_the_weights() {
+    let dir = std::env::temp_dir().join(format!(
+        "candle-vllm-tensor-parallel-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let arange = |rows: usize, cols: usize| {
+        Tensor::arange(0f32, (rows * cols) as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((rows, cols))
+            .unwrap()
+    };
+    let weights = HashMap::from([
+        (
+            "model.layers.0.self_attn.q_proj.weight".to_string(),
+            arange(8, 6),
+        ),
+        (
+            "model.layers.0.self_attn.o_proj.weight".to_string(),
+            arange(6, 8),
+        ),
+        ("model.norm.weight".to_string(), arange(1, 6)),
+    ]);
+    let file = dir.join("model.safetensors");
+    candle_core::safetensors::save(&weights, &file).unwrap();
+    let files = vec![file];
+    let full = load_safetensors(&files, DType::F32, &Device::Cpu, 1 << 20).unwrap();
+
+    let world_size = 2;
+    // Buffers of a single row and of whole tensors.
+    for buffer_size in [4, 1 << 20] {
+        let shards = (0..world_size)
+            .map(|rank| {
+                let shard = Shard { rank, world_size };
+                load_safetensors_shard(&files, DType::F32, &Device::Cpu, buffer_size, shard)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for (name, dim) in [
+            ("model.layers.0.self_attn.q_proj.weight", 0),
+            ("model.layers.0.self_attn.o_proj.weight", 1),
+        ] {
+            let parts = shards.iter().map(|s| &s[name]).collect::<Vec<_>>();
+            assert_eq!(parts[0].dim(dim).unwrap(), full[name].dim(dim).unwrap() / 2);
+            let joined = Tensor::cat(&parts, dim).unwrap();
+            assert_eq!(
+                joined.to_vec2::<f32>().unwrap(),
+                full[name].to_vec2::<f32>().unwrap(),
+                "{name} with a buffer of {buffer_size} bytes"
+            );
+        }
+        for shard in &shards {
+            assert_eq!(
+                shard["model.norm.weight"].to_vec2::<f32>().unwrap(),
+                full["model.norm.weight"].to_vec2::<f32>().unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+fn small_messages_take_the_custom_all_reduce() {
+    // A decode step of 8 tokens of Llama 3 8B in bf16.
+    let decode = 8 * 4096 * 2;
+    assert_eq!(
+        AllReduceAlgorithm::select(decode, 2),
+        Some(AllReduceAlgorithm::OneShot)
+    );
+    assert_eq!(
+        AllReduceAlgorithm::select(decode, 8),
+        Some(AllReduceAlgorithm::OneShot)
+    );
+    // Two ranks read as much in one shot as in two, more of them read less in two.
+    let batch = 256 * 4096 * 2;
+    assert_eq!(
+        AllReduceAlgorithm::select(batch, 2),
+        Some(AllReduceAlgorithm::OneShot)
+    );
+    assert_eq!(
+        AllReduceAlgorithm::select(batch, 4),
+        Some(AllReduceAlgorithm::TwoShot)
+    );
+    // Prefills go through NCCL, as do single ranks and too many of them.
+    assert_eq!(
+        AllReduceAlgorithm::select(CUSTOM_ALL_REDUCE_MAX_SIZE + 2, 4),
+        None
+    );
+    assert_eq!(AllReduceAlgorithm::select(decode, 1), None);
+    assert_eq!(AllReduceAlgorithm::select(decode, 16), None);
+}
+
+#[test]
+fn only_the_expected_workers_with_the_secret_are_accepted() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connect = |line: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "{line}").unwrap();
+        stream
+    };
+    let connected = |rank: usize, secret: &str| {
+        format!(
+            r#"{{"message":{{"Connected":{{"rank":{rank},"secret":"{secret}"}}}},"has_tensors":false}}"#
+        )
+    };
+    let clients = std::thread::spawn(move || {
+        // A port scan, a probe, the wrong secret, a rank of no other node and one connected
+        // twice are dropped.
+        drop(TcpStream::connect(addr).unwrap());
+        let mut streams = vec![connect("GET / HTTP/1.1")];
+        streams.push(connect(&connected(1, "guess")));
+        streams.push(connect(
+            r#"{"message":{"Connected":{"rank":1,"secret":null}},"has_tensors":false}"#,
+        ));
+        streams.push(connect(&connected(5, "secret")));
+        streams.push(connect(&connected(1, "secret")));
+        streams.push(connect(&connected(1, "secret")));
+        streams.push(connect(&connected(2, "secret")));
+        streams
+    });
+    let workers = WorkerProcess::accept_remote(&listener, 1..3, "secret").unwrap();
+    assert_eq!(workers.len(), 2);
+    drop(clients.join().unwrap());
+}
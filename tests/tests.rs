@@ -1,19 +1,26 @@
 use axum::{
     http::{self, Method},
-    routing::post,
+    routing::{get, post},
     Router,
 };
 use candle_core::{DType, Device};
 use candle_vllm::{
     get_model_loader,
     openai::{
-        openai_server::chat_completions, pipelines::llm_engine::LLMEngine, responses::APIError,
+        batches::BatchStore,
+        openai_server::{chat_completions, debug_scheduler},
+        pipelines::{
+            llm_engine::LLMEngine, weights::DEFAULT_WEIGHT_BUFFER_MEM, worker::WorkerConfig,
+        },
+        responses::APIError,
+        sampling_params::MAX_TOP_LOGPROBS,
+        tokenizer_pool::TokenizerPool,
         OpenAIServerData,
     },
     scheduler::{cache_engine::CacheConfig, SchedulerConfig},
     ModelSelected,
 };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use tokio::sync::Notify;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 #[tokio::main]
@@ -33,28 +40,71 @@ async fn test_llama() -> Result<(), APIError> {
         Some(std::env::var("TESTS_HF_TOKEN").unwrap()),
         None,
     )?;
-    let model = loader.load_model(paths, DType::F16, Device::Cpu)?;
+    let model = loader.load_model(paths, DType::F16, Device::Cpu, DEFAULT_WEIGHT_BUFFER_MEM)?;
     let finish_notify = Arc::new(Notify::new());
     let llm_engine = LLMEngine::new(
         model.0,
-        SchedulerConfig { max_num_seqs: 256 },
+        SchedulerConfig {
+            max_num_seqs: 256,
+            max_num_prefill_tokens: None,
+            long_prefill_token_threshold: None,
+            max_long_prefills: 1,
+            prompt_lookup: None,
+            batch_invariant: false,
+            max_sessions: 0,
+            compaction_blocks: None,
+        },
         CacheConfig {
             block_size: 16,
             num_gpu_blocks: None,
             num_cpu_blocks: None,
             fully_init: false,
             dtype: DType::F16,
+            gpu_growth_blocks: None,
+            idle_shrink_after: None,
+            attention_sinks: None,
+            num_state_slots: None,
+            swap_affinity: None,
         },
+        WorkerConfig::default(),
         Arc::new(Notify::new()),
         finish_notify.clone(),
     )?;
+    let (scheduler_trace, scheduler_limits, aborted_requests, tokenizer, admissions) = {
+        let engine = llm_engine.lock().await;
+        (
+            engine.scheduler_trace.clone(),
+            engine.scheduler_limits.clone(),
+            engine.aborted_requests.clone(),
+            engine.get_pipeline().tokenizer().tokenizer().clone(),
+            engine.admission_queue(),
+        )
+    };
 
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
+        pipeline_config: std::sync::RwLock::new(model.1),
         model: llm_engine,
         device: Device::Cpu,
         record_conversation: false,
         finish_notify: finish_notify.clone(),
+        scheduler_trace,
+        tokenizer_pool: TokenizerPool::new(tokenizer, 2)?,
+        scheduler_limits,
+        aborted_requests,
+        admin_token: None,
+        log_filter: None,
+        batches: BatchStore::new(4),
+        watermark: None,
+        served_model_names: vec![],
+        control_vectors: vec![],
+        max_waiting_requests: None,
+        cap_max_tokens_to_free_blocks: false,
+        response_cache: None,
+        reject_unknown_fields: false,
+        max_logprobs: MAX_TOP_LOGPROBS,
+        logit_dumps: None,
+        admissions,
+        api_keys: HashSet::new(),
     };
 
     let allow_origin = AllowOrigin::any();
@@ -66,6 +116,7 @@ async fn test_llama() -> Result<(), APIError> {
     let app = Router::new()
         .layer(cors_layer)
         .route("/v1/chat/completions", post(chat_completions))
+        .route("/debug/scheduler", get(debug_scheduler))
         .with_state(Arc::new(server_data));
 
     let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:2000"))
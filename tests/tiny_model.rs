@@ -0,0 +1,149 @@
+use candle_core::DType;
+use candle_vllm::{
+    openai::responses::ChatCompletionChunk,
+    scheduler::cache_engine::{CacheConfig, SUPPORTED_BLOCK_SIZES},
+};
+
+mod common;
+use common::{cache_config, tiny_model::TinyEngine};
+
+const PROMPT_A: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t60";
+const PROMPT_B: &str = "t11 t3 t50 t22 t19 t44 t6 t23 t31 t58";
+const MAX_TOKENS: usize = 24;
+
+// Greedy outputs of the seeded tiny llama. `max_tokens` yields one extra token, and B stops at EOS.
+const GOLDEN_A: [usize; 25] = [
+    45, 3, 46, 18, 49, 41, 23, 44, 32, 32, 23, 44, 44, 23, 44, 63, 60, 45, 0, 16, 47, 1, 58, 0, 45,
+];
+const GOLDEN_B: [usize; 18] = [
+    38, 34, 46, 45, 41, 17, 6, 34, 18, 6, 13, 35, 51, 8, 43, 39, 43, 39,
+];
+
+#[test]
+fn greedy_generation_matches_golden_tokens() {
+    let mut engine = TinyEngine::new(16);
+    let a = engine.encode(PROMPT_A);
+    let b = engine.encode(PROMPT_B);
+    assert_eq!(a.get_ids(), [5, 9, 17, 33, 40, 41, 7, 8, 12, 60]);
+    assert_eq!(engine.generate(&[a], MAX_TOKENS), [GOLDEN_A.to_vec()]);
+    assert_eq!(engine.generate(&[b], MAX_TOKENS), [GOLDEN_B.to_vec()]);
+}
+
+#[test]
+fn output_does_not_depend_on_block_size() {
+    // 10 prompt tokens and 25 generated ones span several blocks of every supported size.
+    for block_size in SUPPORTED_BLOCK_SIZES {
+        let mut engine = TinyEngine::new(block_size);
+        let a = engine.encode(PROMPT_A);
+        assert_eq!(
+            engine.generate(&[a], MAX_TOKENS),
+            [GOLDEN_A.to_vec()],
+            "block size {block_size}"
+        );
+    }
+}
+
+#[test]
+fn batched_generation_matches_single_requests() {
+    let mut engine = TinyEngine::new(8);
+    let a = engine.encode(PROMPT_A);
+    let b = engine.encode(PROMPT_B);
+    assert_eq!(
+        engine.generate(&[a, b], MAX_TOKENS),
+        [GOLDEN_A.to_vec(), GOLDEN_B.to_vec()]
+    );
+}
+
+#[test]
+fn paged_decoding_matches_prefill_recompute() {
+    let mut engine = TinyEngine::new(8);
+    // Each decoded token must be what a fresh prefill of the prompt and the tokens before it
+    // predicts, so reading the keys and values back from the paged cache loses nothing.
+    for (i, &token) in GOLDEN_A.iter().enumerate() {
+        // Tokens below 3 are special and do not round-trip through the word level vocabulary.
+        if GOLDEN_A[..i].iter().any(|&t| t < 3) {
+            break;
+        }
+        let prefix = GOLDEN_A[..i]
+            .iter()
+            .map(|t| format!("t{t}"))
+            .collect::<Vec<_>>();
+        let prompt = engine.encode(&format!("{PROMPT_A} {}", prefix.join(" ")));
+        let generated = engine.generate(&[prompt], 1);
+        assert_eq!(generated[0][0], token, "position {i}");
+    }
+}
+
+#[test]
+fn lazily_grown_cache_matches_golden_tokens_and_shrinks_when_idle() {
+    let mut engine = TinyEngine::with_cache_config(CacheConfig {
+        dtype: DType::F32,
+        gpu_growth_blocks: Some(2),
+        ..cache_config(8)
+    });
+    assert_eq!(engine.scheduler_snapshot().num_allocated_gpu_blocks, 2);
+    assert!(!engine.shrink_idle_cache());
+
+    let a = engine.encode(PROMPT_A);
+    let b = engine.encode(PROMPT_B);
+    assert_eq!(
+        engine.generate(&[a.clone(), b], MAX_TOKENS),
+        [GOLDEN_A.to_vec(), GOLDEN_B.to_vec()]
+    );
+    let snapshot = engine.scheduler_snapshot();
+    // 35 and 28 tokens in blocks of 8, grown 2 blocks at a time.
+    assert_eq!(snapshot.num_allocated_gpu_blocks, 10);
+    assert_eq!(snapshot.num_free_gpu_blocks, snapshot.num_gpu_blocks);
+
+    assert!(engine.shrink_idle_cache());
+    assert_eq!(engine.scheduler_snapshot().num_allocated_gpu_blocks, 2);
+    assert_eq!(engine.generate(&[a], MAX_TOKENS), [GOLDEN_A.to_vec()]);
+}
+
+/// The text and finish reason of each of the `n` choices of a stream, checking that the finish
+/// reason of a choice comes with its last chunk.
+fn collect_choices(chunks: &[ChatCompletionChunk], n: usize) -> Vec<(String, String)> {
+    let mut choices = vec![(String::new(), None); n];
+    for chunk in chunks {
+        assert_eq!(chunk.choices.len(), 1);
+        let choice = &chunk.choices[0];
+        let (text, finish_reason) = &mut choices[choice.index];
+        assert!(
+            finish_reason.is_none(),
+            "chunk for choice {} after its finish reason",
+            choice.index
+        );
+        text.push_str(choice.delta.content.as_deref().unwrap_or_default());
+        *finish_reason = choice.finish_reason.clone();
+    }
+    choices
+        .into_iter()
+        .map(|(text, finish_reason)| (text, finish_reason.expect("choice never finished")))
+        .collect()
+}
+
+#[test]
+fn parallel_choices_stream_with_their_own_index() {
+    let mut engine = TinyEngine::new(8);
+    let a = engine.encode(PROMPT_A);
+    let b = engine.encode(PROMPT_B);
+    let single = engine
+        .stream(&[a.clone(), b.clone()], 1, MAX_TOKENS)
+        .iter()
+        .map(|chunks| collect_choices(chunks, 1).remove(0))
+        .collect::<Vec<_>>();
+    assert_eq!(single[0].1, "length");
+    assert_eq!(single[1].1, "stop");
+
+    // Every choice is the greedy output, each streamed under its own index with its own finish
+    // reason, while the choices of both requests are decoded in one batch.
+    let parallel = engine.stream(&[a, b], 3, MAX_TOKENS);
+    for (chunks, expected) in parallel.iter().zip(&single) {
+        let first_step = chunks[..3]
+            .iter()
+            .map(|chunk| chunk.choices[0].index)
+            .collect::<Vec<_>>();
+        assert_eq!(first_step, [0, 1, 2]);
+        assert_eq!(collect_choices(chunks, 3), vec![expected.clone(); 3]);
+    }
+}
@@ -0,0 +1,39 @@
+use candle_vllm::tls::TlsFiles;
+use std::path::PathBuf;
+use tokio::runtime::Runtime;
+
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("candle-vllm-tls-{}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn missing_tls_files_are_named_in_the_error() {
+    let key = temp_file("missing.key", "");
+    let files = TlsFiles {
+        cert: PathBuf::from("/nonexistent/cert.pem"),
+        key: key.clone(),
+    };
+    let err = Runtime::new().unwrap().block_on(files.load()).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("TLS certificate /nonexistent/cert.pem"),
+        "{err}"
+    );
+    std::fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn invalid_certificates_are_rejected() {
+    let cert = temp_file("invalid.pem", "not a certificate");
+    let key = temp_file("invalid.key", "not a key");
+    let files = TlsFiles {
+        cert: cert.clone(),
+        key: key.clone(),
+    };
+    let err = Runtime::new().unwrap().block_on(files.load()).unwrap_err();
+    assert!(err.to_string().contains("Invalid TLS certificate"), "{err}");
+    std::fs::remove_file(cert).unwrap();
+    std::fs::remove_file(key).unwrap();
+}
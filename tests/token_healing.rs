@@ -0,0 +1,58 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::openai::logits_processor::mask_logits;
+
+mod common;
+use common::tiny_model::TinyEngine;
+
+// Ends with `t6`, which `t60` ... `t63` extend.
+const PROMPT: &str = "t5 t9 t17 t33 t40 t41 t7 t8 t12 t6";
+const HEALED: [usize; 5] = [6, 60, 61, 62, 63];
+const MAX_TOKENS: usize = 12;
+
+#[test]
+fn masks_all_but_allowed_logits() {
+    let logits = Tensor::new(&[0.5f32, 3.0, -1.0, 2.0], &Device::Cpu).unwrap();
+    let masked = mask_logits(&logits, &[0, 2, 9]).unwrap();
+    assert_eq!(
+        masked.to_vec1::<f32>().unwrap(),
+        [0.5, f32::NEG_INFINITY, -1.0, f32::NEG_INFINITY]
+    );
+    assert_eq!(masked.argmax(0).unwrap().to_scalar::<u32>().unwrap(), 0);
+}
+
+#[test]
+fn first_token_extends_the_removed_prompt_token() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.token_healing = true;
+    let healed = engine.generate(&[prompt], MAX_TOKENS).remove(0);
+    assert!(HEALED.contains(&healed[0]), "{healed:?}");
+
+    // The rest is what the prompt ending in the healed token generates.
+    engine.token_healing = false;
+    let healed_prompt = engine.encode(&PROMPT.replace(" t6", &format!(" t{}", healed[0])));
+    let generated = engine.generate(&[healed_prompt], MAX_TOKENS - 1).remove(0);
+    assert_eq!(generated, healed[1..]);
+}
+
+#[test]
+fn streamed_text_does_not_repeat_the_prompt() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode(PROMPT);
+    engine.token_healing = true;
+    let first = engine
+        .generate(std::slice::from_ref(&prompt), MAX_TOKENS)
+        .remove(0)[0];
+    let chunks = engine.stream(&[prompt], 1, MAX_TOKENS).remove(0);
+    let text = chunks[0].choices[0].delta.content.clone().unwrap();
+    assert_eq!(text, format!("t{first}").strip_prefix("t6").unwrap());
+}
+
+#[test]
+fn short_prompts_are_not_healed() {
+    let mut engine = TinyEngine::new(8);
+    let prompt = engine.encode("t5 t6");
+    let plain = engine.generate(std::slice::from_ref(&prompt), MAX_TOKENS);
+    engine.token_healing = true;
+    assert_eq!(engine.generate(&[prompt], MAX_TOKENS), plain);
+}
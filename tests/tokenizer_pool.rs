@@ -0,0 +1,49 @@
+use futures::{executor::block_on, future::join_all};
+use tokenizers::Tokenizer;
+
+use candle_vllm::openai::tokenizer_pool::TokenizerPool;
+
+mod common;
+use common::tiny_model::tiny_llama_dir;
+
+fn tokenizer() -> Tokenizer {
+    Tokenizer::from_file(tiny_llama_dir().join("tokenizer.json")).unwrap()
+}
+
+#[test]
+fn needs_a_thread() {
+    assert!(TokenizerPool::new(tokenizer(), 0).is_err());
+}
+
+#[test]
+fn encodes_concurrent_prompts_like_the_tokenizer() {
+    let tokenizer = tokenizer();
+    let pool = TokenizerPool::new(tokenizer.clone(), 3).unwrap();
+    // Prompts of increasing length, the long ones finish last.
+    let prompts = (1..=32)
+        .map(|len| {
+            (0..len * 64)
+                .map(|i| format!("t{}", 3 + (i * 7 + len) % 61))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>();
+    let encodings = block_on(join_all(
+        prompts.iter().map(|prompt| pool.encode(prompt.clone())),
+    ));
+    for (prompt, encoding) in prompts.iter().zip(encodings) {
+        let expected = tokenizer.encode(prompt.as_str(), false).unwrap();
+        assert_eq!(encoding.unwrap().get_ids(), expected.get_ids());
+    }
+}
+
+#[test]
+fn decodes_on_the_pool() {
+    let pool = TokenizerPool::new(tokenizer(), 1).unwrap();
+    let ids = block_on(pool.encode("t5 t9 t17".to_string()))
+        .unwrap()
+        .get_ids()
+        .to_vec();
+    assert_eq!(ids, [5, 9, 17]);
+    assert_eq!(block_on(pool.decode(ids)).unwrap(), "t5 t9 t17");
+}
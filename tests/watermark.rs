@@ -0,0 +1,137 @@
+use axum::{
+    extract::{Json, State},
+    response::IntoResponse,
+};
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::{
+    hooks::{LogitsContext, LogitsProcessor},
+    openai_server::detect_watermark,
+    requests::WatermarkDetectRequest,
+    watermark::{Watermark, WatermarkConfig, DEFAULT_Z_THRESHOLD},
+};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+mod common;
+use common::tiny_model::{TinyEngine, VOCAB_SIZE};
+
+const PROMPTS: [&str; 2] = ["t5 t9 t17 t33 t40", "t11 t3 t50 t22"];
+const MAX_TOKENS: usize = 48;
+
+fn watermark(key: u64) -> Watermark {
+    Watermark::new(WatermarkConfig {
+        key,
+        gamma: 0.25,
+        delta: 100.0,
+    })
+    .unwrap()
+}
+
+fn green_tokens(watermark: &Watermark, previous: u32, vocab_size: u32) -> Vec<u32> {
+    (0..vocab_size)
+        .filter(|&token| watermark.is_green(previous, token))
+        .collect()
+}
+
+#[test]
+fn green_lists_depend_on_the_key_and_previous_token() {
+    let vocab_size = 32000;
+    let watermark = watermark(7);
+    let green = green_tokens(&watermark, 5, vocab_size);
+    let fraction = green.len() as f64 / vocab_size as f64;
+    assert!((fraction - 0.25).abs() < 0.01, "{fraction}");
+    assert_ne!(green, green_tokens(&watermark, 6, vocab_size));
+    assert_ne!(green, green_tokens(&self::watermark(8), 5, vocab_size));
+
+    for (gamma, delta) in [(0.0, 2.0), (1.0, 2.0), (0.5, 0.0), (0.5, f32::NAN)] {
+        assert!(Watermark::new(WatermarkConfig {
+            key: 7,
+            gamma,
+            delta
+        })
+        .is_err());
+    }
+}
+
+#[test]
+fn processor_biases_the_green_tokens() {
+    let watermark = watermark(7);
+    let logits = Tensor::zeros(VOCAB_SIZE, DType::F32, &Device::Cpu).unwrap();
+    let context = LogitsContext {
+        request_id: "a",
+        prompt: &[5, 9],
+        generated: &[17],
+    };
+    let biased = watermark
+        .process(&context, &logits)
+        .unwrap()
+        .to_vec1::<f32>()
+        .unwrap();
+    for (token, logit) in biased.into_iter().enumerate() {
+        let expected = if watermark.is_green(17, token as u32) {
+            100.0
+        } else {
+            0.0
+        };
+        assert_eq!(logit, expected);
+    }
+}
+
+#[test]
+fn generated_text_is_detected_with_the_key_only() {
+    let mut engine = TinyEngine::new(16);
+    let prompts = PROMPTS.map(|prompt| engine.encode(prompt));
+    let plain = engine.generate(&prompts, MAX_TOKENS);
+    engine.add_logits_processor(Arc::new(watermark(7)));
+    let marked = engine.generate(&prompts, MAX_TOKENS);
+
+    for (plain, marked) in plain.iter().zip(&marked) {
+        let plain = plain.iter().map(|&token| token as u32).collect::<Vec<_>>();
+        let marked = marked.iter().map(|&token| token as u32).collect::<Vec<_>>();
+        let detection = watermark(7).detect(&marked);
+        assert!(detection.num_tokens_scored > 10, "{detection:?}");
+        assert_eq!(detection.num_green_tokens, detection.num_tokens_scored);
+        assert!(detection.is_watermarked(DEFAULT_Z_THRESHOLD));
+        assert!(!watermark(8)
+            .detect(&marked)
+            .is_watermarked(DEFAULT_Z_THRESHOLD));
+        assert!(!watermark(7)
+            .detect(&plain)
+            .is_watermarked(DEFAULT_Z_THRESHOLD));
+    }
+}
+
+#[test]
+fn detect_endpoint_needs_a_watermark() {
+    let engine = TinyEngine::new(16);
+    let data_without = Arc::new(engine.server_data(None));
+    let mut data = engine.server_data(None);
+    let text = (0..40)
+        .map(|i| format!("t{}", 5 + i))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let request = WatermarkDetectRequest {
+        text,
+        z_threshold: None,
+    };
+    Runtime::new().unwrap().block_on(async {
+        let response = detect_watermark(State(data_without), Json(request.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), 404);
+
+        data.watermark = Some(watermark(7));
+        let response = detect_watermark(State(Arc::new(data)), Json(request))
+            .await
+            .into_response();
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["num_tokens_scored"], 39);
+        assert_eq!(body["z_threshold"], DEFAULT_Z_THRESHOLD);
+        assert_eq!(body["watermarked"], false);
+    });
+}
@@ -0,0 +1,92 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::pipelines::weights::{load_safetensors, MAX_WEIGHT_LOADERS};
+use std::{collections::HashMap, path::PathBuf};
+
+fn write_shards(dir: &str) -> (Vec<PathBuf>, HashMap<String, Tensor>) {
+    let dir = std::env::temp_dir().join(format!("{dir}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let arange = |n: usize| Tensor::arange(0f32, n as f32, &Device::Cpu).unwrap();
+    let shards = [
+        vec![
+            ("embed", arange(7 * 6).reshape((7, 6)).unwrap()),
+            ("norm", arange(6)),
+        ],
+        vec![
+            ("proj", arange(5 * 3 * 4).reshape((5, 3, 4)).unwrap()),
+            ("scale", Tensor::new(0.5f32, &Device::Cpu).unwrap()),
+            (
+                "empty",
+                Tensor::zeros((0, 4), DType::F32, &Device::Cpu).unwrap(),
+            ),
+        ],
+    ];
+    let mut files = Vec::new();
+    let mut expected = HashMap::new();
+    for (i, shard) in shards.into_iter().enumerate() {
+        let shard: HashMap<_, _> = shard.into_iter().collect();
+        let file = dir.join(format!("model-{i}.safetensors"));
+        candle_core::safetensors::save(&shard, &file).unwrap();
+        files.push(file);
+        expected.extend(shard.into_iter().map(|(name, t)| (name.to_string(), t)));
+    }
+    (files, expected)
+}
+
+#[test]
+fn weights_are_uploaded_in_slices_of_the_buffer() {
+    let (mut files, expected) = write_shards("candle-vllm-weight-streaming");
+    // Sharded checkpoints list a shard once per tensor.
+    files.push(files[0].clone());
+    for dtype in [DType::F32, DType::BF16] {
+        // Slices of several rows, of a single row, and whole tensors.
+        for buffer_size in [64, 1, 1 << 20] {
+            let tensors = load_safetensors(&files, dtype, &Device::Cpu, buffer_size).unwrap();
+            assert_eq!(tensors.len(), expected.len());
+            for (name, tensor) in &expected {
+                let loaded = &tensors[name];
+                assert_eq!(loaded.dtype(), dtype);
+                assert_eq!(loaded.dims(), tensor.dims(), "{name}");
+                let values = |t: &Tensor| {
+                    let t = t.to_dtype(DType::F32).unwrap().flatten_all().unwrap();
+                    t.to_vec1::<f32>().unwrap()
+                };
+                assert_eq!(
+                    values(loaded),
+                    values(tensor),
+                    "{name} with a buffer of {buffer_size} bytes"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn shards_are_loaded_in_parallel_into_one_map() {
+    let dir =
+        std::env::temp_dir().join(format!("candle-vllm-weight-shards-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // More shards than loaders, each with a tensor of its own.
+    let num_shards = 3 * MAX_WEIGHT_LOADERS;
+    let files = (0..num_shards)
+        .map(|i| {
+            let tensor = Tensor::full(i as f32, (3, 2), &Device::Cpu).unwrap();
+            let file = dir.join(format!("model-{i}.safetensors"));
+            candle_core::safetensors::save(&HashMap::from([(format!("w{i}"), tensor)]), &file)
+                .unwrap();
+            file
+        })
+        .collect::<Vec<_>>();
+    let tensors = load_safetensors(&files, DType::F32, &Device::Cpu, 16).unwrap();
+    assert_eq!(tensors.len(), num_shards);
+    for i in 0..num_shards {
+        let values = tensors[&format!("w{i}")].flatten_all().unwrap();
+        assert_eq!(values.to_vec1::<f32>().unwrap(), [i as f32; 6]);
+    }
+
+    // A shard that fails to load fails the whole model.
+    let corrupt = dir.join("corrupt.safetensors");
+    std::fs::write(&corrupt, b"not a safetensors file").unwrap();
+    let mut files = files;
+    files.insert(num_shards / 2, corrupt);
+    assert!(load_safetensors(&files, DType::F32, &Device::Cpu, 16).is_err());
+}
@@ -0,0 +1,123 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::openai::pipelines::integrity::{
+    check_safetensors_size, hub_digest, sha256_file, verify_weights, WeightManifest,
+};
+use std::{collections::HashMap, path::PathBuf};
+
+fn write_shards(name: &str) -> (PathBuf, Vec<PathBuf>) {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let arange = |n: usize| Tensor::arange(0f32, n as f32, &Device::Cpu).unwrap();
+    let files = [arange(24), arange(10)]
+        .into_iter()
+        .enumerate()
+        .map(|(i, tensor)| {
+            let file = dir.join(format!("model-{i}.safetensors"));
+            let tensors = HashMap::from([(format!("weight-{i}"), tensor)]);
+            candle_core::safetensors::save(&tensors, &file).unwrap();
+            file
+        })
+        .collect();
+    (dir, files)
+}
+
+fn manifest(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| {
+            let name = file.file_name().unwrap().to_str().unwrap();
+            format!("{}  {name}\n", sha256_file(file).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn manifests_are_json_or_sha256sum_output() {
+    let digest = "ab".repeat(32);
+    let json = WeightManifest::parse(&format!(
+        r#"{{"model-0.safetensors": "{}"}}"#,
+        digest.to_uppercase()
+    ))
+    .unwrap();
+    let sums = WeightManifest::parse(&format!("{digest} *weights/model-0.safetensors\n")).unwrap();
+    assert_eq!(json, sums);
+    let file = PathBuf::from("/models/model-0.safetensors");
+    assert_eq!(json.digest(&file), Some(digest.as_str()));
+    assert_eq!(json.digest(&PathBuf::from("model-1.safetensors")), None);
+
+    assert!(WeightManifest::parse("abc  model-0.safetensors").is_err());
+    assert!(WeightManifest::parse("model-0.safetensors").is_err());
+}
+
+#[test]
+fn shards_matching_the_manifest_load() {
+    let (dir, files) = write_shards("candle-vllm-weights-integrity");
+    let manifest = WeightManifest::parse(&manifest(&files)).unwrap();
+    // Sharded checkpoints list a shard once per tensor.
+    let mut listed = files.clone();
+    listed.push(files[0].clone());
+    verify_weights(&listed, Some(&manifest)).unwrap();
+    // Without a manifest nor a hub digest, only the sizes are checked.
+    verify_weights(&files, None).unwrap();
+
+    // Every shard has to be listed.
+    let partial = WeightManifest::parse(&manifest(&files[..1])).unwrap();
+    let err = verify_weights(&files, Some(&partial)).unwrap_err();
+    assert!(err.to_string().contains("not listed"), "{err}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupted_and_truncated_shards_are_rejected() {
+    let (dir, files) = write_shards("candle-vllm-weights-corrupted");
+    let manifest = WeightManifest::parse(&manifest(&files)).unwrap();
+
+    // A flipped byte in the data keeps the size.
+    let mut bytes = std::fs::read(&files[0]).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&files[0], &bytes).unwrap();
+    check_safetensors_size(&files[0]).unwrap();
+    let err = verify_weights(&files, Some(&manifest)).unwrap_err();
+    assert!(err.to_string().contains("SHA-256"), "{err}");
+
+    // A truncated shard fails on its size, without a digest.
+    std::fs::write(&files[1], &std::fs::read(&files[1]).unwrap()[..40]).unwrap();
+    let err = check_safetensors_size(&files[1]).unwrap_err();
+    assert!(err.to_string().contains("corrupted"), "{err}");
+    assert!(verify_weights(&files[1..], None).is_err());
+    std::fs::write(&files[1], [1, 0]).unwrap();
+    assert!(check_safetensors_size(&files[1]).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn hub_downloads_are_checked_against_their_blob() {
+    let (dir, files) = write_shards("candle-vllm-weights-hub");
+    // The layout of the hf-hub cache: snapshots link to blobs named after their etag.
+    let blobs = dir.join("blobs");
+    let snapshot = dir.join("snapshots").join("main");
+    std::fs::create_dir_all(&blobs).unwrap();
+    std::fs::create_dir_all(&snapshot).unwrap();
+    let link = |file: &PathBuf, etag: &str| {
+        std::fs::rename(file, blobs.join(etag)).unwrap();
+        let linked = snapshot.join(file.file_name().unwrap());
+        std::os::unix::fs::symlink(format!("../../blobs/{etag}"), &linked).unwrap();
+        linked
+    };
+    let digest = sha256_file(&files[0]).unwrap();
+    let good = link(&files[0], &digest);
+    assert_eq!(hub_digest(&good), Some(digest));
+    verify_weights(&[good.clone()], None).unwrap();
+
+    let bad = link(&files[1], &"0".repeat(64));
+    let err = verify_weights(&[good, bad], None).unwrap_err();
+    assert!(err.to_string().contains("corrupted"), "{err}");
+    // Small files are not in LFS, their etag is a git hash.
+    let config = dir.join("config.json");
+    std::fs::write(blobs.join("a".repeat(40)), "{}").unwrap();
+    std::os::unix::fs::symlink(blobs.join("a".repeat(40)), &config).unwrap();
+    assert_eq!(hub_digest(&config), None);
+    std::fs::remove_dir_all(dir).unwrap();
+}